@@ -0,0 +1,138 @@
+// Benchmarks for the relay hot path: telnet parsing and escaping, CP437
+// conversion, forwarding through a running proxy, and the client map under
+// contention. Run them with Criterion, optionally with a filter on the names:
+//
+//     cargo bench
+//     cargo bench -- cp437
+//
+// Criterion reports time per iteration, and throughput where a benchmark
+// handles a known number of bytes. Save a baseline before a change and
+// compare against it after:
+//
+//     cargo bench -- --save-baseline before
+//     cargo bench -- --baseline before
+
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use triserver::codec::{self, cp437_to_utf8, utf8_to_cp437, Cp437Encoder, Parser};
+use triserver::config::LogLevel;
+use triserver::harness::{self, idle_connection, ScriptedBackend, Step, TestClient, TestServer};
+use triserver::SharedClientMap;
+
+const CHUNK: usize = 4096;
+
+// Plain text, as most menus are.
+fn text() -> Vec<u8> {
+    b"Welcome to the board! Type a command and press Enter.\r\n".iter().copied().cycle().take(CHUNK).collect()
+}
+
+// ANSI art: colour escapes and box-drawing characters above 0x7f.
+fn ansi_art() -> Vec<u8> {
+    b"\x1b[1;34m\xc9\xcd\xcd\xcd\xbb\x1b[0m \xb0\xb1\xb2\xdb ".iter().copied().cycle().take(CHUNK).collect()
+}
+
+// Data with a command every few bytes, as at the start of a session or in a
+// binary transfer where IAC is doubled.
+fn command_heavy() -> Vec<u8> {
+    [b'a', 255, 251, 1, b'b', 255, 255, b'c', 255, 250, 24, 1, 255, 240].iter().copied().cycle().take(CHUNK).collect()
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iac_scan");
+    group.throughput(Throughput::Bytes(CHUNK as u64));
+    for (name, input) in [("text", text()), ("ansi_art", ansi_art()), ("command_heavy", command_heavy())] {
+        let mut parser = Parser::new();
+        group.bench_function(format!("parse/{}", name), |b| b.iter(|| parser.feed(black_box(&input))));
+        group.bench_function(format!("escape/{}", name), |b| b.iter(|| codec::escape(black_box(&input))));
+    }
+    group.finish();
+}
+
+fn cp437(c: &mut Criterion) {
+    let art = ansi_art();
+    let unicode = cp437_to_utf8(&art);
+    let mut group = c.benchmark_group("cp437");
+    group.throughput(Throughput::Bytes(CHUNK as u64));
+    group.bench_function("to_utf8", |b| b.iter(|| cp437_to_utf8(black_box(&art))));
+    group.bench_function("from_utf8", |b| b.iter(|| utf8_to_cp437(black_box(&unicode))));
+    let mut encoder = Cp437Encoder::new();
+    group.bench_function("streaming_encoder", |b| b.iter(|| encoder.encode(black_box(unicode.as_bytes()))));
+    group.finish();
+}
+
+// A backend sends `size` bytes through the proxy to one client. Each
+// iteration is a fresh session, so this includes connecting.
+fn forwarding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forwarding");
+    // Each iteration takes milliseconds, so fewer of them will do.
+    group.sample_size(20);
+    for size in [4 * 1024, 64 * 1024] {
+        let mut payload = text().into_iter().cycle().take(size).collect::<Vec<u8>>();
+        payload.extend_from_slice(b"\r\nEND");
+        let backend = ScriptedBackend::start(vec![Step::Send(payload), Step::Sleep(Duration::from_millis(50))]).unwrap();
+        let mut config = harness::config(backend.address());
+        // Session log lines would drown out the results.
+        config.log.verbosity = Some(LogLevel::Warn);
+        let server = TestServer::start(config).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(size.to_string(), |b| b.iter(|| {
+            let mut client = TestClient::connect(server.address()).unwrap();
+            client.expect("\r\nEND").unwrap();
+        }));
+        server.stop();
+    }
+    group.finish();
+}
+
+// Each thread inserts, looks up and removes entries while the others do the
+// same, as sessions starting and ending under load do.
+fn client_map(c: &mut Criterion) {
+    let filled = || {
+        let map = SharedClientMap::new();
+        for i in 0..1000u32 {
+            let (client_id, connection) = idle_connection(IpAddr::V4(Ipv4Addr::from(i)));
+            map.insert(client_id, connection);
+        }
+        map
+    };
+    let mut group = c.benchmark_group("client_map");
+    for threads in [1, 4, 16] {
+        group.bench_function(format!("insert_get_remove/{}", threads), |b| b.iter_custom(|iterations| {
+            let map = filled();
+            let barrier = Arc::new(Barrier::new(threads + 1));
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    let map = map.clone();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        for _ in 0..iterations {
+                            let (client_id, connection) = idle_connection(IpAddr::V4(Ipv4Addr::LOCALHOST));
+                            map.insert(client_id, connection);
+                            black_box(map.get(client_id));
+                            map.remove(client_id);
+                        }
+                    })
+                })
+                .collect();
+            barrier.wait();
+            let started = Instant::now();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            started.elapsed()
+        }));
+    }
+    let map = filled();
+    group.bench_function("values_1000", |b| b.iter(|| map.values()));
+    group.finish();
+}
+
+criterion_group!(benches, parsing, cp437, forwarding, client_map);
+criterion_main!(benches);
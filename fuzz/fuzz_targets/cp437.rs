@@ -0,0 +1,29 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use triserver::codec::{cp437_to_utf8, utf8_to_cp437, Cp437Encoder, Utf8Sanitizer};
+
+fuzz_target!(|input: &[u8]| {
+    // Every CP437 byte has a character, so the round trip is exact.
+    assert_eq!(utf8_to_cp437(&cp437_to_utf8(input)), input);
+
+    // Arbitrary bytes as "UTF-8" from a client, split at every point the
+    // first byte picks, must encode the same as in one read.
+    let Some((&split, input)) = input.split_first() else { return };
+    let whole = Cp437Encoder::new().encode(input);
+    let mut encoder = Cp437Encoder::new();
+    let chunked: Vec<u8> = input.chunks(split as usize + 1).flat_map(|chunk| encoder.encode(chunk)).collect();
+    assert_eq!(whole, chunked);
+
+    // Backend output through the sanitizer is always valid UTF-8 with no C1
+    // controls (ASCII only in ASCII mode), however it is split.
+    for ascii in [false, true] {
+        let whole = Utf8Sanitizer::new(ascii).sanitize(input);
+        let mut sanitizer = Utf8Sanitizer::new(ascii);
+        let chunked: Vec<u8> = input.chunks(split as usize + 1).flat_map(|chunk| sanitizer.sanitize(chunk)).collect();
+        assert_eq!(whole, chunked);
+        let text = std::str::from_utf8(&whole).unwrap();
+        assert!(!text.chars().any(|c| matches!(c, '\u{80}'..='\u{9f}')));
+        assert!(!ascii || text.is_ascii());
+    }
+});
@@ -0,0 +1,39 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use triserver::codec::{Frame, Parser};
+
+// The first byte picks how the rest is split into reads. However it is split,
+// it has to parse the same as in one read.
+fuzz_target!(|input: &[u8]| {
+    let Some((&split, input)) = input.split_first() else { return };
+    let whole = Parser::new().feed(input);
+
+    let mut parser = Parser::new();
+    let mut chunked = Vec::new();
+    for chunk in input.chunks(split as usize + 1) {
+        chunked.extend(parser.feed(chunk));
+    }
+    assert_eq!(normalize(&whole), normalize(&chunked));
+});
+
+// Joins adjacent data frames, which depend on where the reads ended.
+fn normalize(frames: &[Frame]) -> Vec<String> {
+    let mut normalized = Vec::new();
+    let mut data = Vec::new();
+    for frame in frames {
+        match frame {
+            Frame::Data(bytes) => data.extend_from_slice(bytes),
+            other => {
+                if !data.is_empty() {
+                    normalized.push(format!("{:?}", std::mem::take(&mut data)));
+                }
+                normalized.push(format!("{:?}", other));
+            }
+        }
+    }
+    if !data.is_empty() {
+        normalized.push(format!("{:?}", data));
+    }
+    normalized
+}
@@ -0,0 +1,214 @@
+// The [access] ban and allow lists: addresses and networks, one to a line
+// with # comments, kept in files so that other tools (a fail2ban action, a
+// cron job) can manage them just by editing the file. The directories they
+// are in are watched, and a file is read again whenever it changes, however
+// it was changed (written in place, or replaced by a rename); a missing file
+// is an empty list until it turns up.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crossbeam_channel::unbounded;
+use notify::{PollWatcher, RecursiveMode, Watcher};
+
+use crate::cidr::Cidr;
+use crate::config::AccessConfig;
+use crate::log;
+
+// How often the files are looked at where their changes can't be watched for.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    Banned,
+    NotAllowed,
+}
+
+#[derive(Default)]
+struct Lists {
+    banned: Vec<Cidr>,
+    allowed: Vec<Cidr>,
+}
+
+// Shared between the client manager, which checks callers against the
+// lists, and the thread that keeps them up to date.
+#[derive(Clone, Default)]
+pub struct AccessLists {
+    inner: Arc<RwLock<Lists>>,
+}
+
+impl AccessLists {
+    // Ban file entries win; an allow file only limits who gets in when it
+    // lists anyone.
+    pub fn refuses(&self, ip: IpAddr) -> Option<Refusal> {
+        let lists = self.inner.read().unwrap();
+        if lists.banned.iter().any(|block| block.contains(ip)) {
+            Some(Refusal::Banned)
+        } else if !lists.allowed.is_empty() && !lists.allowed.iter().any(|block| block.contains(ip)) {
+            Some(Refusal::NotAllowed)
+        } else {
+            None
+        }
+    }
+
+    // How many entries the ban and allow lists have.
+    pub fn len(&self) -> (usize, usize) {
+        let lists = self.inner.read().unwrap();
+        (lists.banned.len(), lists.allowed.len())
+    }
+}
+
+// The entries in a list file, and a "line N: ..." for each line that isn't one.
+pub fn read(path: &Path) -> io::Result<(Vec<Cidr>, Vec<String>)> {
+    let contents = fs::read_to_string(path)?;
+    let (mut entries, mut problems) = (Vec::new(), Vec::new());
+    for (index, line) in contents.lines().enumerate() {
+        let entry = line.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.parse() {
+            Ok(block) => entries.push(block),
+            Err(error) => problems.push(format!("line {}: {}", index + 1, error)),
+        }
+    }
+    Ok((entries, problems))
+}
+
+// Reads the lists now, then keeps them up to date.
+pub fn launch_access_lists(config: &AccessConfig) -> AccessLists {
+    let lists = AccessLists::default();
+    let mut files: Vec<ListFile> = [(&config.ban_file, Refusal::Banned), (&config.allow_file, Refusal::NotAllowed)]
+        .into_iter()
+        .filter_map(|(path, list)| Some(ListFile { path: path.clone()?, list, stamp: None }))
+        .collect();
+    for file in &mut files {
+        if !file.path.exists() {
+            log!(Server, Warn, "{} doesn't exist yet; it is read once it does", file.path.display());
+        }
+        file.refresh(&lists);
+    }
+    let (sender, events) = unbounded();
+    let mut watcher: Box<dyn Watcher + Send> = match notify::recommended_watcher(sender.clone()) {
+        Ok(watcher) => Box::new(watcher),
+        Err(error) => {
+            log!(Server, Warn, "Unable to watch for changes to the access lists ({}); looking at them every {} seconds instead",
+                 error, POLL_INTERVAL.as_secs());
+            match PollWatcher::new(sender, notify::Config::default().with_poll_interval(POLL_INTERVAL)) {
+                Ok(watcher) => Box::new(watcher),
+                Err(error) => {
+                    log!(Server, Warn, "Unable to look for changes to the access lists: {}", error);
+                    return lists;
+                }
+            }
+        }
+    };
+    // Their directories rather than the files, which may not exist yet and
+    // may be replaced rather than written to.
+    for file in &files {
+        let directory = file.path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if let Err(error) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+            log!(Server, Warn, "Unable to watch {} for changes to {}: {}", directory.display(), file.path.display(), error);
+        }
+    }
+    let shared = lists.clone();
+    let _ = thread::spawn(move || {
+        // Kept for as long as the events are wanted.
+        let _watcher = watcher;
+        for event in events {
+            let Ok(event) = event else { continue };
+            for file in &mut files {
+                if event.paths.iter().any(|path| path.file_name() == file.path.file_name()) {
+                    file.refresh(&shared);
+                }
+            }
+        }
+    });
+    lists
+}
+
+struct ListFile {
+    path: PathBuf,
+    list: Refusal,
+    // When it was last changed and how long it was, as of the last read.
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl ListFile {
+    fn refresh(&mut self, lists: &AccessLists) {
+        let stamp = fs::metadata(&self.path).ok().map(|metadata| (metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), metadata.len()));
+        if stamp == self.stamp {
+            return;
+        }
+        let entries = match stamp {
+            None => {
+                log!(Server, Warn, "{} is gone; its list is empty until it's back", self.path.display());
+                Vec::new()
+            }
+            Some(_) => match read(&self.path) {
+                Ok((entries, problems)) => {
+                    for problem in problems {
+                        log!(Server, Warn, "Skipped {}, {}", self.path.display(), problem);
+                    }
+                    log!(Server, Info, "Read {} entries from {}", entries.len(), self.path.display());
+                    entries
+                }
+                // Half written, perhaps; it is tried again on the next change.
+                Err(error) => {
+                    log!(Server, Warn, "Unable to read {}: {}", self.path.display(), error);
+                    return;
+                }
+            },
+        };
+        self.stamp = stamp;
+        let mut inner = lists.inner.write().unwrap();
+        match self.list {
+            Refusal::Banned => inner.banned = entries,
+            Refusal::NotAllowed => inner.allowed = entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn wait_until(what: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !what() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn reads_a_list_again_when_it_is_written_or_replaced() {
+        let directory = std::env::temp_dir().join(format!("triserver-access-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let ban_file = directory.join("banned.txt");
+        let config = AccessConfig { ban_file: Some(ban_file.clone()), allow_file: None };
+        let lists = launch_access_lists(&config);
+        let caller = IpAddr::from([192, 0, 2, 7]);
+        assert_eq!(lists.refuses(caller), None);
+
+        fs::write(&ban_file, "192.0.2.0/24  # the whole network\n").unwrap();
+        assert!(wait_until(|| lists.refuses(caller) == Some(Refusal::Banned)));
+        let replacement = directory.join("banned.txt.new");
+        fs::write(&replacement, "198.51.100.1\n").unwrap();
+        fs::rename(&replacement, &ban_file).unwrap();
+        assert!(wait_until(|| lists.refuses(caller).is_none()));
+        assert_eq!(lists.len(), (1, 0));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
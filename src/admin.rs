@@ -0,0 +1,612 @@
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::chaos;
+use crate::clock::{format_timestamp, now_timestamp};
+use crate::config::{AdminConfig, BackendConfig, LogLevel, Subsystem};
+use crate::handover;
+use crate::live::Change;
+use crate::log;
+use crate::pool;
+use crate::resources;
+use crate::sysop::ConsoleMessage;
+use crate::version;
+use crate::{ClientConnection, ServerContext, SessionControl};
+
+// How long the command line client waits for each reply line.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a connection may sit without sending a line, before and after it
+// has authenticated. The events stream and the sysop console wait on the
+// server rather than the admin, so neither is timed out.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// Wrong passwords before the connection is closed.
+const MAX_AUTH_FAILURES: u32 = 3;
+
+const HELP: &str = "help                 this list
+status               version, uptime and counts of sessions and bans
+who                  list connected sessions
+label <client-id> [text]
+                     nickname a session for who, or clear it
+note <ip> [text]     keep a note about an address (shown by who), or clear it
+notes                list address notes
+kick <client-id>     disconnect a session
+negotiated <client-id>
+                     how the caller's telnet settled: binary, echo, TTYPE, NAWS, charset
+broadcast <message>  show every connected caller a message
+bans                 list active bans
+ban <ip> [minutes]   ban an address and disconnect its sessions
+unban <ip>           lift a ban and forget the address's strikes
+drain <backend>      send a pool's new callers elsewhere, leaving its sessions be
+undrain <backend>    let a drained backend take new callers again
+drains               list draining backends and the sessions left on each
+backends             list backends and the sessions on each
+backend add <name> <key>=<value> ...
+                     add a backend: host=, port=, socket=, telnet=, output=,
+                     input=, sndloc=, logout=, redial_window=
+backend set <name> <key>=<value> ...
+                     change a backend's settings for new sessions
+backend remove <name>
+                     remove a backend no pool or route uses
+pool join <pool> <backend> [weight]
+                     add a backend to a pool
+pool leave <pool> <backend>
+                     take a backend out of a pool, leaving its sessions be
+log                  list how much each subsystem logs
+log <subsystem> <level>
+                     set negotiation, relay, manager, admin or server to off,
+                     error, warn, info, debug or trace
+chaos <client-id> ...
+                     set a session's faults: latency=<ms> drop=<one in n>
+                     disconnect=<s> partial=on|off, or off (needs [chaos])
+upgrade              hand the listeners to a freshly started copy of the server
+shutdown <seconds>   warn callers, turn new ones away for the last minute, then stop
+shutdown cancel      call off a scheduled shutdown
+events               stream session events until the connection is closed
+console              ring when a caller pages the sysop; chat <client-id> to chat
+                     with a caller, /end to stop
+version              show the build and how long the server has been up
+quit                 close this admin connection";
+
+// Line-based control socket. Every reply ends with a line that is either
+// "OK" or "ERR <message>" so scripts can tell where a response stops.
+pub fn launch_admin_server(config: &AdminConfig, context: ServerContext) {
+    let listener = match handover::bind("admin", &config.address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            log!(Server, Error, "Unable to bind admin interface on {}: {}", config.address, error);
+            return;
+        }
+    };
+    log!(Server, Info, "Admin Interface Listening on: {}", config.address);
+    let password = config.password.clone();
+    let _ = thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let context = context.clone();
+            let password = password.clone();
+            let _ = thread::spawn(move || {
+                let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+                log!(Admin, Info, "Admin connection from {}", peer);
+                let _ = handle_admin_connection(stream, &peer, &context, password.as_deref());
+                log!(Admin, Info, "Admin connection from {} closed", peer);
+            });
+        }
+    });
+}
+
+fn handle_admin_connection(stream: TcpStream, peer: &str, context: &ServerContext, password: Option<&str>) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut authenticated = password.is_none();
+    let mut failures = 0;
+    writer.set_read_timeout(Some(if authenticated { IDLE_TIMEOUT } else { AUTH_TIMEOUT }))?;
+    writeln!(writer, "TriServer admin interface{}", if authenticated { "" } else { " - auth required" })?;
+
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = words.collect();
+
+        if !authenticated {
+            match (command, arguments.as_slice(), password) {
+                // Compared without stopping at the first difference, so the
+                // time taken doesn't give away how much of it was right.
+                ("auth", [given], Some(expected)) if bool::from(given.as_bytes().ct_eq(expected.as_bytes())) => {
+                    authenticated = true;
+                    writer.set_read_timeout(Some(IDLE_TIMEOUT))?;
+                    writeln!(writer, "OK")?;
+                }
+                ("auth", ..) => {
+                    failures += 1;
+                    if failures >= MAX_AUTH_FAILURES {
+                        log!(Admin, Warn, "Closing admin connection from {} after {} wrong passwords", peer, failures);
+                        writeln!(writer, "ERR too many wrong passwords")?;
+                        break;
+                    }
+                    writeln!(writer, "ERR wrong password")?;
+                }
+                _ => writeln!(writer, "ERR authentication required")?,
+            }
+            continue;
+        }
+
+        if command != "auth" {
+            log!(Admin, Debug, "Admin command from {}: {}", peer, line);
+        }
+        if command == "quit" {
+            writeln!(writer, "OK")?;
+            break;
+        }
+        if command == "events" {
+            // Runs until the admin hangs up and the next write fails.
+            writer.set_read_timeout(None)?;
+            for event in context.events.subscribe() {
+                writeln!(writer, "{} {}", now_timestamp(), event)?;
+            }
+            break;
+        }
+        if command == "console" {
+            writer.set_read_timeout(None)?;
+            return console(&mut lines, &writer, peer, context);
+        }
+        let reply = run_command(command, &arguments, context);
+        match reply {
+            Ok(output) => {
+                for line in output {
+                    writeln!(writer, "{}", line)?;
+                }
+                writeln!(writer, "OK")?;
+            }
+            Err(message) => writeln!(writer, "ERR {}", message)?,
+        }
+    }
+    Ok(())
+}
+
+// Rings when a caller pages the sysop and chats with callers, taking other
+// commands in between, until the admin quits or hangs up.
+fn console(lines: &mut Lines<BufReader<TcpStream>>, writer: &TcpStream, peer: &str, context: &ServerContext) -> io::Result<()> {
+    let (console, messages) = context.sysop.attach();
+    // The session being chatted with, until either side ends it.
+    let chatting: Arc<Mutex<Option<Uuid>>> = Arc::default();
+    // Everything is written from the one thread, so a page never lands in
+    // the middle of a command's reply.
+    let mut out = writer.try_clone()?;
+    let ended = chatting.clone();
+    let printer = thread::spawn(move || {
+        for message in messages {
+            match &message {
+                ConsoleMessage::Detached => break,
+                ConsoleMessage::Ended { client_id, .. } => {
+                    let mut chatting = ended.lock().unwrap();
+                    if *chatting == Some(*client_id) {
+                        *chatting = None;
+                    }
+                }
+                _ => {}
+            }
+            if writeln!(out, "{}", message).is_err() {
+                break;
+            }
+        }
+    });
+    let reply = |line: &str| {
+        let _ = console.send(ConsoleMessage::Reply(line.to_string()));
+    };
+    reply("Sysop console: pages from callers ring here. chat <client-id> answers one, or breaks in on anyone; \
+           /end stops a chat. Other commands work as usual; quit leaves.");
+    log!(Admin, Info, "Sysop console attached from {}", peer);
+
+    let mut result = Ok(());
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        };
+        let current = *chatting.lock().unwrap();
+        if let Some(client_id) = current {
+            let client = context.clients.get(client_id);
+            if line.trim() == "/end" {
+                if let Some(client) = &client {
+                    let _ = client.control.send(SessionControl::ChatEnd);
+                }
+                *chatting.lock().unwrap() = None;
+                reply("*** Chat ended");
+            } else if let Some(client) = client.filter(|client| client.control.send(SessionControl::ChatLine { text: line.clone() }).is_ok()) {
+                log!(Admin, Debug, span = client.span(), "Sysop said: {}", line);
+            } else {
+                *chatting.lock().unwrap() = None;
+                reply("*** The caller has hung up");
+            }
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = words.collect();
+        log!(Admin, Debug, "Console command from {}: {}", peer, line);
+        match (command, arguments.as_slice()) {
+            ("quit", []) => {
+                reply("OK");
+                break;
+            }
+            ("chat", [id]) => {
+                let client = id.parse().ok().and_then(|client_id| context.clients.get(client_id));
+                match client {
+                    Some(client) if client.control.send(SessionControl::ChatStart { console: console.clone() }).is_ok() => {
+                        log!(Admin, Info, span = client.span(), "Sysop chat started from {}", peer);
+                        *chatting.lock().unwrap() = Some(client.client_id);
+                        reply(&format!("*** Chatting with {}; what you type goes to them, /end stops", describe_caller(&client)));
+                    }
+                    _ => reply(&format!("ERR no session {}", id)),
+                }
+            }
+            _ => match run_command(command, &arguments, context) {
+                Ok(output) => {
+                    output.iter().for_each(|line| reply(line));
+                    reply("OK");
+                }
+                Err(message) => reply(&format!("ERR {}", message)),
+            },
+        }
+    }
+    if let Some(client) = chatting.lock().unwrap().take().and_then(|client_id| context.clients.get(client_id)) {
+        let _ = client.control.send(SessionControl::ChatEnd);
+    }
+    let _ = console.send(ConsoleMessage::Detached);
+    let _ = printer.join();
+    log!(Admin, Info, "Sysop console from {} detached", peer);
+    result
+}
+
+// The caller's user name if they logged in, and where they're calling from.
+fn describe_caller(client: &ClientConnection) -> String {
+    match &client.username {
+        Some(username) => format!("{} ({}, node {})", username, client.ip_addr, client.node),
+        None => format!("{} (node {})", client.ip_addr, client.node),
+    }
+}
+
+fn run_command(command: &str, arguments: &[&str], context: &ServerContext) -> Result<Vec<String>, String> {
+    match (command, arguments) {
+        ("help", _) => Ok(HELP.lines().map(String::from).collect()),
+        ("status", []) => {
+            let stats = context.manager.stats()?;
+            let mut lines = vec![
+                version::describe(),
+                format!("uptime:   {}s", context.started.elapsed().as_secs()),
+                format!("sessions: {}", stats.sessions),
+                format!("callers:  {} accepted, {} refused, {} turned away busy", stats.accepted, stats.refused, stats.turned_away),
+                format!("manager:  {} messages queued", stats.queued),
+                format!("held:     {}", context.held_sessions.len()),
+                format!("bans:     {}", context.bans.list().len()),
+                format!("faults:   {}", context.faults.describe()),
+            ];
+            if let Some(access) = &context.access {
+                let (banned, allowed) = access.len();
+                lines.push(format!("access:   {} banned, {} allowed", banned, allowed));
+            }
+            if let Some(usage) = resources::current() {
+                lines.push(format!("process:  {}", usage.describe()));
+            }
+            lines.push(String::from("backends:"));
+            for backend in &context.live.current().backends {
+                let faults = context.faults.backend(&backend.name);
+                let last = match &faults.last {
+                    Some((detail, at)) => format!("; last {} UTC: {}", format_timestamp(*at), detail),
+                    None => String::new(),
+                };
+                lines.push(format!("  {:<16} {} connect failures, {} timeouts, {} drops{}", backend.name, faults.connect_failures,
+                                   faults.timeouts, faults.drops, last));
+            }
+            if let Some(remaining) = context.shutdown.remaining() {
+                lines.push(format!("shutdown: in {}s", remaining.as_secs()));
+            }
+            // The figures above are this worker's; the stats file covers them all.
+            #[cfg(target_os = "linux")]
+            if let Some(workers) = context.config.workers.as_ref().filter(|workers| workers.count > 1) {
+                lines.extend(crate::workers::read_stats(&workers.stats_file));
+            }
+            Ok(lines)
+        }
+        ("who", []) => {
+            let mut clients = context.manager.list_clients()?;
+            clients.sort_by_key(|client| client.connected_at);
+            Ok(clients.iter()
+                .map(|client| {
+                    let line = format!("{}  {:<40} {:<16} {:<16} {:<11} {:>8}s", client.client_id, client.ip_addr,
+                                       client.backend.as_deref().unwrap_or("-"), client.username.as_deref().unwrap_or("-"),
+                                       client.state, client.connected_at.elapsed().as_secs());
+                    match client.label.clone().or_else(|| context.notes.get(client.ip_addr)) {
+                        Some(label) => format!("{}  {}", line, label),
+                        None => line,
+                    }
+                })
+                .collect())
+        }
+        ("label", [id, text @ ..]) => {
+            let client_id: Uuid = id.parse().map_err(|_| format!("invalid client id '{}'", id))?;
+            let label = Some(text.join(" ")).filter(|label| !label.is_empty());
+            let reply = match &label {
+                Some(label) => format!("{} labelled '{}'", client_id, label),
+                None => format!("{} label cleared", client_id),
+            };
+            if !context.clients.update(client_id, |client| client.label = label) {
+                return Err(format!("no session {}", client_id));
+            }
+            Ok(vec![reply])
+        }
+        ("note", [ip, text @ ..]) => {
+            let ip_addr: IpAddr = ip.parse().map_err(|_| format!("invalid address '{}'", ip))?;
+            let note = Some(text.join(" ")).filter(|note| !note.is_empty());
+            let reply = match &note {
+                Some(note) => format!("noted {}: {}", ip_addr, note),
+                None => format!("note on {} cleared", ip_addr),
+            };
+            context.notes.set(ip_addr, note).map_err(|error| format!("unable to save notes: {}", error))?;
+            Ok(vec![reply])
+        }
+        ("notes", []) => Ok(context.notes.list().iter()
+            .map(|(ip_addr, note)| format!("{:<40} {}", ip_addr, note))
+            .collect()),
+        ("negotiated", [id]) => {
+            let client_id: Uuid = id.parse().map_err(|_| format!("invalid client id '{}'", id))?;
+            let client = context.clients.get(client_id).ok_or_else(|| format!("no session {}", client_id))?;
+            Ok(vec![client.negotiation.unwrap_or_else(|| String::from("not settled yet"))])
+        }
+        ("kick", [id]) => {
+            let client_id: Uuid = id.parse().map_err(|_| format!("invalid client id '{}'", id))?;
+            let client = context.manager.disconnect(client_id, "You have been disconnected by the sysop.")?
+                .ok_or_else(|| format!("no session {}", client_id))?;
+            log!(Admin, Info, span = client.span(), "kicked from the admin interface");
+            Ok(vec![format!("kicked {}", client_id)])
+        }
+        ("ban", [ip, minutes @ ..]) if minutes.len() <= 1 => {
+            let ip_addr: IpAddr = ip.parse().map_err(|_| format!("invalid address '{}'", ip))?;
+            let duration = match minutes {
+                [minutes] => Some(minutes.parse::<u64>().ok().and_then(|minutes| minutes.checked_mul(60)).map(Duration::from_secs)
+                    .ok_or_else(|| format!("invalid duration '{}'", minutes))?),
+                _ => None,
+            };
+            let ban = context.bans.ban(ip_addr, duration, "banned by the sysop").ok_or_else(|| String::from("invalid duration"))?;
+            log!(Admin, Info, "Banned {} for {} seconds from the admin interface", ip_addr, ban.remaining().as_secs());
+            let mut kicked = 0;
+            for client in context.manager.list_clients()?.into_iter().filter(|client| client.ip_addr == ip_addr) {
+                if context.manager.disconnect(client.client_id, "You have been banned by the sysop.")?.is_some() {
+                    kicked += 1;
+                }
+            }
+            Ok(vec![format!("banned {} for {}s, {} session(s) disconnected", ip_addr, ban.remaining().as_secs(), kicked)])
+        }
+        ("broadcast", message) if !message.is_empty() => {
+            let message = message.join(" ");
+            let sessions = context.manager.broadcast(&message)?;
+            log!(Admin, Info, "Broadcast to {} session(s) from the admin interface: {}", sessions, message);
+            Ok(vec![format!("sent to {} session(s)", sessions)])
+        }
+        ("chaos", [id, faults @ ..]) if !faults.is_empty() => {
+            let defaults = context.config.chaos.clone().ok_or_else(|| String::from("chaos mode is off; add a [chaos] section to use it"))?;
+            let client_id: Uuid = id.parse().map_err(|_| format!("invalid client id '{}'", id))?;
+            let client = context.clients.get(client_id).ok_or_else(|| format!("no session {}", client_id))?;
+            let faults = chaos::parse(faults, defaults)?;
+            let description = chaos::describe(&faults);
+            let _ = client.control.send(SessionControl::Chaos(faults));
+            Ok(vec![format!("{}: {}", client_id, description)])
+        }
+        ("upgrade", []) => {
+            if context.config.workers.as_ref().is_some_and(|workers| workers.count > 1) {
+                return Err(String::from("upgrades are not supported with [workers]; restart the server instead"));
+            }
+            handover::request();
+            Ok(vec![String::from("upgrade requested, see the server log")])
+        }
+        ("shutdown", ["cancel"]) => {
+            if !context.shutdown.cancel() {
+                return Err(String::from("no shutdown is scheduled"));
+            }
+            log!(Admin, Info, "Scheduled shutdown cancelled from the admin interface");
+            Ok(vec![String::from("shutdown cancelled")])
+        }
+        ("shutdown", [seconds]) => {
+            if context.config.workers.as_ref().is_some_and(|workers| workers.count > 1) {
+                return Err(String::from("scheduled shutdowns are not supported with [workers]; stop the supervisor instead"));
+            }
+            let seconds: u64 = seconds.parse().map_err(|_| format!("invalid delay '{}'", seconds))?;
+            if !context.shutdown.schedule(Duration::from_secs(seconds)) {
+                return Err(format!("invalid delay '{}'", seconds));
+            }
+            log!(Admin, Info, "Shutdown in {} seconds scheduled from the admin interface", seconds);
+            Ok(vec![format!("shutting down in {}s", seconds)])
+        }
+        ("bans", []) => Ok(context.bans.list().iter()
+            .map(|ban| format!("{:<40} {:>8}s  strikes: {}  {}", ban.ip_addr, ban.remaining().as_secs(), ban.strikes, ban.reason))
+            .collect()),
+        ("unban", [ip]) => {
+            let ip_addr: IpAddr = ip.parse().map_err(|_| format!("invalid address '{}'", ip))?;
+            if context.bans.remove(ip_addr) {
+                log!(Admin, Info, "Ban on {} lifted from the admin interface", ip_addr);
+                Ok(vec![format!("unbanned {}", ip_addr)])
+            } else {
+                Err(format!("{} is not banned", ip_addr))
+            }
+        }
+        ("drain", [backend]) => {
+            if !context.live.current().pools.iter().any(|pool| pool.members.iter().any(|member| member.backend == *backend)) {
+                return Err(format!("{} is not a member of any pool", backend));
+            }
+            if !context.pools.drain(backend) {
+                return Err(format!("{} is already draining", backend));
+            }
+            log!(Admin, Info, "Backend {} draining from the admin interface", backend);
+            let reply = match sessions_on(context, backend) {
+                0 => format!("draining {}, no sessions left", backend),
+                sessions => format!("draining {}, {} session(s) left", backend, sessions),
+            };
+            pool::report_if_drained(context, backend);
+            Ok(vec![reply])
+        }
+        ("undrain", [backend]) => {
+            if !context.pools.undrain(backend) {
+                return Err(format!("{} is not draining", backend));
+            }
+            log!(Admin, Info, "Backend {} taking new callers again from the admin interface", backend);
+            Ok(vec![format!("{} takes new callers again", backend)])
+        }
+        ("drains", []) => Ok(context.pools.draining().iter()
+            .map(|backend| match sessions_on(context, backend) {
+                0 => format!("{:<16} drained", backend),
+                sessions => format!("{:<16} {} session(s) left", backend, sessions),
+            })
+            .collect()),
+        ("backends", []) => Ok(context.live.current().backends.iter()
+            .map(|backend| format!("{:<16} {:<32} {} session(s)", backend.name, backend.address(), sessions_on(context, &backend.name)))
+            .collect()),
+        ("backend", ["add", name, settings @ ..]) if !settings.is_empty() => {
+            let change = Change::Add { name: name.to_string(), settings: parse_settings(settings)? };
+            let saved = change_backends(context, &change)?;
+            log!(Admin, Info, "Backend {} added from the admin interface", name);
+            Ok(vec![with_saved(format!("added {}", name), saved)])
+        }
+        ("backend", ["set", name, settings @ ..]) if !settings.is_empty() => {
+            let change = Change::Set { name: name.to_string(), settings: parse_settings(settings)? };
+            let saved = change_backends(context, &change)?;
+            log!(Admin, Info, "Backend {} changed from the admin interface", name);
+            Ok(vec![with_saved(format!("changed {}; sessions already on it are unaffected", name), saved)])
+        }
+        ("backend", ["remove", name]) => {
+            let saved = change_backends(context, &Change::Remove { name: name.to_string() })?;
+            context.pools.undrain(name);
+            log!(Admin, Info, "Backend {} removed from the admin interface", name);
+            let reply = match sessions_on(context, name) {
+                0 => format!("removed {}", name),
+                sessions => format!("removed {}; its {} session(s) stay connected", name, sessions),
+            };
+            Ok(vec![with_saved(reply, saved)])
+        }
+        ("pool", ["join", pool, backend, weight @ ..]) if weight.len() <= 1 => {
+            let weight = match weight {
+                [weight] => weight.parse::<u64>().ok().filter(|&weight| weight > 0).ok_or_else(|| format!("invalid weight '{}'", weight))?,
+                _ => 1,
+            };
+            let saved = change_backends(context, &Change::Join { pool: pool.to_string(), backend: backend.to_string(), weight })?;
+            log!(Admin, Info, "Backend {} joined pool {} from the admin interface", backend, pool);
+            Ok(vec![with_saved(format!("{} joined {} with weight {}", backend, pool, weight), saved)])
+        }
+        ("pool", ["leave", pool, backend]) => {
+            let saved = change_backends(context, &Change::Leave { pool: pool.to_string(), backend: backend.to_string() })?;
+            log!(Admin, Info, "Backend {} left pool {} from the admin interface", backend, pool);
+            Ok(vec![with_saved(format!("{} left {}", backend, pool), saved)])
+        }
+        ("log", []) => Ok(Subsystem::ALL.iter()
+            .map(|subsystem| format!("{:<12} {}", subsystem.name(), log::level(*subsystem).name()))
+            .collect()),
+        ("log", [subsystem, level]) => {
+            if context.config.workers.as_ref().is_some_and(|workers| workers.count > 1) {
+                return Err(String::from("log levels can't be changed at runtime with [workers]; set them in [log] and restart"));
+            }
+            let subsystem: Subsystem = subsystem.parse()?;
+            let level: LogLevel = level.parse()?;
+            log::set(subsystem, level);
+            log!(Admin, Info, "{} logging set to {} from the admin interface", subsystem.name(), level.name());
+            Ok(vec![format!("{} logs at {}", subsystem.name(), level.name())])
+        }
+        ("version", []) => Ok(vec![
+            version::describe(),
+            format!("up {}s", context.started.elapsed().as_secs()),
+        ]),
+        _ => Err(format!("unknown command '{}', try 'help'", command)),
+    }
+}
+
+fn sessions_on(context: &ServerContext, backend: &str) -> usize {
+    context.clients.values().iter().filter(|client| client.backend.as_deref() == Some(backend)).count()
+}
+
+// Each worker has its own copy of the config, so a change made through one
+// would leave the others behind.
+fn change_backends(context: &ServerContext, change: &Change) -> Result<bool, String> {
+    if context.config.workers.as_ref().is_some_and(|workers| workers.count > 1) {
+        return Err(String::from("backends can't be changed at runtime with [workers]; edit the config file and restart"));
+    }
+    context.live.apply(change)
+}
+
+fn with_saved(reply: String, saved: bool) -> String {
+    if saved {
+        format!("{} (saved to the config file)", reply)
+    } else {
+        reply
+    }
+}
+
+// `key=value` words as TOML settings. A quoted value may have spaces in it,
+// which split it over several words.
+fn parse_settings(words: &[&str]) -> Result<Vec<(String, String)>, String> {
+    let mut settings = Vec::new();
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        let (key, value) = word.split_once('=').ok_or_else(|| format!("expected key=value, found '{}'", word))?;
+        let mut value = value.to_string();
+        if value.starts_with('"') {
+            while value.len() < 2 || !value.ends_with('"') {
+                let next = words.next().ok_or_else(|| format!("{}: unterminated quote", key))?;
+                value.push(' ');
+                value.push_str(next);
+            }
+        }
+        settings.push((key.to_string(), BackendConfig::setting(key, &value)?));
+    }
+    Ok(settings)
+}
+
+// Runs one command against a running server's admin interface and returns its
+// output, for the `TriServer status|who|kick|ban` subcommands.
+pub fn send_command(config: &AdminConfig, command: &str) -> Result<Vec<String>, String> {
+    let stream = TcpStream::connect(&config.address)
+        .map_err(|error| format!("unable to reach the admin interface on {}: {}", config.address, error))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).map_err(|error| error.to_string())?;
+    let mut writer = stream.try_clone().map_err(|error| error.to_string())?;
+    let mut lines = BufReader::new(stream).lines();
+    let mut next_line = || match lines.next() {
+        Some(Ok(line)) => Ok(line),
+        Some(Err(error)) => Err(error.to_string()),
+        None => Err(String::from("the admin interface closed the connection")),
+    };
+
+    // Greeting first, then the auth reply if a password is set.
+    next_line()?;
+    if let Some(password) = &config.password {
+        writeln!(writer, "auth {}", password).map_err(|error| error.to_string())?;
+        let reply = next_line()?;
+        if reply != "OK" {
+            return Err(reply.strip_prefix("ERR ").unwrap_or(&reply).to_string());
+        }
+    }
+    writeln!(writer, "{}", command).map_err(|error| error.to_string())?;
+    let mut output = Vec::new();
+    loop {
+        let line = next_line()?;
+        if line == "OK" {
+            break;
+        }
+        if let Some(message) = line.strip_prefix("ERR ") {
+            return Err(message.to_string());
+        }
+        output.push(line);
+    }
+    let _ = writeln!(writer, "quit");
+    Ok(output)
+}
@@ -0,0 +1,51 @@
+// Which autonomous system a caller's address belongs to, for the [asn] allow
+// and deny rules. The database is an iptoasn.com style table, one
+// "range_start range_end AS_number country description" line per range,
+// tab-separated, IPv4 and IPv6 alike (ip2asn-combined.tsv).
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+pub struct AsnDatabase {
+    // (first, last, AS number), sorted, with addresses as IPv6 so both kinds share one order.
+    ranges: Vec<(u128, u128, u32)>,
+}
+
+impl AsnDatabase {
+    // Lines that don't parse, and ranges no one announces (AS 0), are skipped.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut ranges: Vec<(u128, u128, u32)> = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let first: IpAddr = fields.next()?.trim().parse().ok()?;
+                let last: IpAddr = fields.next()?.trim().parse().ok()?;
+                let asn: u32 = fields.next()?.trim().parse().ok()?;
+                Some((key(first), key(last), asn)).filter(|_| asn != 0)
+            })
+            .collect();
+        ranges.sort_unstable();
+        Ok(Self { ranges })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        let key = key(ip);
+        let index = self.ranges.partition_point(|&(first, _, _)| first <= key).checked_sub(1)?;
+        let (_, last, asn) = self.ranges[index];
+        (key <= last).then_some(asn)
+    }
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
@@ -0,0 +1,218 @@
+// ATASCII for callers on Atari 8-bit terminal programs. Lines end in EOL
+// (0x9b) rather than CR LF, box drawing and blocks become ATASCII's own
+// graphics, ANSI reverse video becomes inverse characters and ANSI cursor
+// moves and screen clears become ATASCII's control codes. The Atari's text
+// screen has no colours, so those are dropped, as are sequences with no
+// ATASCII equivalent. What the caller types is turned back into ASCII,
+// cursor keys into ANSI.
+
+use codepage_437::CP437_CONTROL;
+
+use crate::codec::{transliterate, AnsiScanner, CommandTracker, Scanned};
+
+const EOL: u8 = 0x9b;
+const CURSOR_UP: u8 = 0x1c;
+const CURSOR_DOWN: u8 = 0x1d;
+const CURSOR_LEFT: u8 = 0x1e;
+const CURSOR_RIGHT: u8 = 0x1f;
+const CLEAR: u8 = 0x7d;
+const BACKSPACE: u8 = 0x7e;
+const TAB: u8 = 0x7f;
+const BELL: u8 = 0xfd;
+// Set on a character to show it in inverse video.
+const INVERSE: u8 = 0x80;
+// Further than this a cursor move can't go on a 40 column screen.
+const MAX_MOVE: usize = 40;
+
+// Backend output to ATASCII over a stream of reads, holding on to an escape
+// sequence split between them.
+#[derive(Default)]
+pub struct AtasciiEncoder {
+    scanner: AnsiScanner,
+    inverse: bool,
+    after_return: bool,
+}
+
+impl AtasciiEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        for &byte in input {
+            let byte = match self.scanner.scan(byte) {
+                Some(Scanned::Byte(byte)) => byte,
+                Some(Scanned::Sequence(numbers, command)) => {
+                    self.csi(&numbers, command, &mut out);
+                    continue;
+                }
+                None => continue,
+            };
+            match byte {
+                b'\r' => out.push(EOL),
+                b'\n' if self.after_return => {}
+                b'\n' => out.push(EOL),
+                0x07 => out.push(BELL),
+                0x08 => out.push(CURSOR_LEFT),
+                b'\t' => out.push(TAB),
+                0x0c => out.push(CLEAR),
+                0x20..=0x7e => self.push(ascii(byte), &mut out),
+                0x80..=0xff => self.graphic(CP437_CONTROL.decode(byte), &mut out),
+                _ => {}
+            }
+            self.after_return = byte == b'\r';
+        }
+        out
+    }
+
+    fn csi(&mut self, numbers: &[usize], command: u8, out: &mut Vec<u8>) {
+        let count = numbers[0].clamp(1, MAX_MOVE);
+        match command {
+            b'A' => out.extend(std::iter::repeat_n(CURSOR_UP, count)),
+            b'B' => out.extend(std::iter::repeat_n(CURSOR_DOWN, count)),
+            b'C' => out.extend(std::iter::repeat_n(CURSOR_RIGHT, count)),
+            b'D' => out.extend(std::iter::repeat_n(CURSOR_LEFT, count)),
+            b'J' => out.push(CLEAR),
+            b'm' => {
+                for &number in numbers {
+                    match number {
+                        0 | 27 => self.inverse = false,
+                        7 => self.inverse = true,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The ATASCII graphic for a CP437 one, or the ASCII stand-in when there
+    // is none. A block is an inverse space or half block.
+    fn graphic(&self, c: char, out: &mut Vec<u8>) {
+        let (glyph, inverse) = match c {
+            '\u{2500}' | '\u{2550}' => (0x12, false),
+            '\u{2502}' | '\u{2551}' => (b'|', false),
+            '\u{250c}' | '\u{2552}' | '\u{2553}' | '\u{2554}' => (0x11, false),
+            '\u{2510}' | '\u{2555}' | '\u{2556}' | '\u{2557}' => (0x05, false),
+            '\u{2514}' | '\u{2558}' | '\u{2559}' | '\u{255a}' => (0x1a, false),
+            '\u{2518}' | '\u{255b}' | '\u{255c}' | '\u{255d}' => (0x03, false),
+            '\u{251c}' | '\u{255e}' | '\u{255f}' | '\u{2560}' => (0x01, false),
+            '\u{2524}' | '\u{2561}' | '\u{2562}' | '\u{2563}' => (0x04, false),
+            '\u{252c}' | '\u{2564}' | '\u{2565}' | '\u{2566}' => (0x17, false),
+            '\u{2534}' | '\u{2567}' | '\u{2568}' | '\u{2569}' => (0x18, false),
+            '\u{253c}' | '\u{256a}' | '\u{256b}' | '\u{256c}' => (0x13, false),
+            '\u{2584}' => (0x15, false),
+            '\u{258c}' => (0x19, false),
+            '\u{2588}' => (b' ', true),
+            '\u{2580}' => (0x15, true),
+            '\u{2590}' => (0x19, true),
+            '\u{2665}' => (0x00, false),
+            '\u{2663}' => (0x10, false),
+            '\u{2666}' => (0x60, false),
+            '\u{2660}' => (0x7b, false),
+            _ => {
+                transliterate(c).bytes().for_each(|byte| self.push(ascii(byte), out));
+                return;
+            }
+        };
+        self.push(if inverse { glyph ^ INVERSE } else { glyph }, out);
+    }
+
+    fn push(&self, glyph: u8, out: &mut Vec<u8>) {
+        out.push(if self.inverse { glyph ^ INVERSE } else { glyph });
+    }
+}
+
+// ASCII is ATASCII but for a few places given to card suits and controls.
+fn ascii(byte: u8) -> u8 {
+    match byte {
+        b'`' => b'\'',
+        b'{' => b'(',
+        b'}' => b')',
+        b'~' => b'-',
+        _ => byte,
+    }
+}
+
+// What the caller types back to ASCII. Telnet commands pass untouched.
+#[derive(Default)]
+pub struct AtasciiDecoder {
+    telnet: CommandTracker,
+}
+
+impl AtasciiDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        for &byte in input {
+            if !self.telnet.is_data(byte) {
+                out.push(byte);
+                continue;
+            }
+            match byte {
+                EOL => out.push(b'\r'),
+                BACKSPACE => out.push(0x08),
+                TAB => out.push(b'\t'),
+                CURSOR_UP => out.extend_from_slice(b"\x1b[A"),
+                CURSOR_DOWN => out.extend_from_slice(b"\x1b[B"),
+                CURSOR_RIGHT => out.extend_from_slice(b"\x1b[C"),
+                CURSOR_LEFT => out.extend_from_slice(b"\x1b[D"),
+                // Control and letter keys give the control characters, and
+                // typing in inverse video the plain ones.
+                0x00..=0x1b | 0x20..=0x7c => out.push(byte),
+                0xa0..=0xfc => out.push(byte ^ INVERSE),
+                _ => {}
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ends_lines_in_eol() {
+        let mut encoder = AtasciiEncoder::new();
+        assert_eq!(encoder.encode(b"Hi\r\nthere\n"), b"Hi\x9bthere\x9b");
+        assert_eq!(encoder.encode(b"\x07\t\x08\x0c"), [BELL, TAB, CURSOR_LEFT, CLEAR]);
+        assert_eq!(encoder.encode(b"{~}`"), b"(-)'");
+    }
+
+    #[test]
+    fn turns_ansi_into_control_codes() {
+        let mut encoder = AtasciiEncoder::new();
+        assert_eq!(encoder.encode(b"\x1b[7mA\x1b[0mB"), [b'A' ^ INVERSE, b'B']);
+        assert_eq!(encoder.encode(b"\x1b[2B\x1b[A\x1b[J"), [CURSOR_DOWN, CURSOR_DOWN, CURSOR_UP, CLEAR]);
+        assert_eq!(encoder.encode(b"\x1b[99C"), [CURSOR_RIGHT; MAX_MOVE]);
+        // The screen has no colours.
+        assert_eq!(encoder.encode(b"\x1b[1;31mx\x1b[44m"), b"x");
+        // A sequence split between reads.
+        assert_eq!(encoder.encode(b"\x1b["), []);
+        assert_eq!(encoder.encode(b"7mz\x1b[27m"), [b'z' ^ INVERSE]);
+    }
+
+    #[test]
+    fn draws_cp437_graphics() {
+        let mut encoder = AtasciiEncoder::new();
+        assert_eq!(encoder.encode(b"\xc9\xcd\xbb\xba\xc8\xbc"), [0x11, 0x12, 0x05, b'|', 0x1a, 0x03]);
+        assert_eq!(encoder.encode(b"\xdb\xdf"), [b' ' ^ INVERSE, 0x15 ^ INVERSE]);
+        // In inverse video a block is drawn the other way out.
+        assert_eq!(encoder.encode(b"\x1b[7m\xdb\x82"), [b' ', b'e' ^ INVERSE]);
+    }
+
+    #[test]
+    fn decodes_what_the_caller_types() {
+        let mut decoder = AtasciiDecoder::new();
+        assert_eq!(decoder.decode(&[b'a', EOL, BACKSPACE, TAB]), b"a\r\x08\t");
+        assert_eq!(decoder.decode(&[CURSOR_UP, CURSOR_DOWN, CURSOR_RIGHT, CURSOR_LEFT]), b"\x1b[A\x1b[B\x1b[C\x1b[D");
+        // Typed in inverse video, and with the control key.
+        assert_eq!(decoder.decode(&[b'h' ^ INVERSE, b'I' ^ INVERSE, 0x03]), b"hI\x03");
+        assert_eq!(decoder.decode(b"\xff\xfd\x18x\xfd"), b"\xff\xfd\x18x");
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::AutobanConfig;
+use crate::events::{Event, EventBus};
+
+// How often stale per-address history is swept out of the tracker.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Offense {
+    Reconnect,
+    FailedLogin,
+    NegotiationFlood,
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offense::Reconnect => write!(f, "rapid reconnects"),
+            Offense::FailedLogin => write!(f, "failed logins"),
+            Offense::NegotiationFlood => write!(f, "negotiation flood"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ban {
+    pub ip_addr: IpAddr,
+    pub reason: String,
+    pub until: Instant,
+    // How many times this address has been banned; each ban lasts twice as long.
+    pub strikes: u32,
+}
+
+impl Ban {
+    pub fn remaining(&self) -> Duration {
+        self.until.saturating_duration_since(Instant::now())
+    }
+}
+
+#[derive(Default)]
+struct History {
+    offenses: HashMap<Offense, VecDeque<Instant>>,
+    strikes: u32,
+    last_ban: Option<Instant>,
+}
+
+struct BanListInner {
+    config: AutobanConfig,
+    history: HashMap<IpAddr, History>,
+    bans: HashMap<IpAddr, Ban>,
+    last_sweep: Instant,
+}
+
+// Temporary bans imposed when an address keeps misbehaving. Shared between the
+// manager, session threads and the admin interface.
+#[derive(Clone)]
+pub struct BanList {
+    inner: Arc<Mutex<BanListInner>>,
+    events: EventBus,
+}
+
+impl BanList {
+    pub fn new(config: AutobanConfig, events: EventBus) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(BanListInner {
+                config,
+                history: HashMap::new(),
+                bans: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+            events,
+        }
+    }
+
+    pub fn check(&self, ip_addr: IpAddr) -> Option<Ban> {
+        let mut lock = self.inner.lock().unwrap();
+        match lock.bans.get(&ip_addr) {
+            Some(ban) if ban.until > Instant::now() => Some(ban.clone()),
+            Some(_) => {
+                lock.bans.remove(&ip_addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Records an offense and returns the new ban if it pushed the address over
+    // its threshold.
+    pub fn record(&self, ip_addr: IpAddr, offense: Offense) -> Option<Ban> {
+        let mut lock = self.inner.lock().unwrap();
+        let now = Instant::now();
+        lock.sweep(now);
+
+        let (threshold, window) = lock.config.threshold(offense);
+        if threshold == 0 || lock.bans.contains_key(&ip_addr) {
+            return None;
+        }
+        let base = lock.config.ban_duration;
+        let max = lock.config.max_ban_duration;
+
+        let history = lock.history.entry(ip_addr).or_default();
+        let events = history.offenses.entry(offense).or_default();
+        events.push_back(now);
+        while events.front().is_some_and(|at| now.duration_since(*at) > window) {
+            events.pop_front();
+        }
+        if (events.len() as u32) < threshold {
+            return None;
+        }
+
+        let factor = 1u32.checked_shl(history.strikes).unwrap_or(u32::MAX);
+        // The config keeps max_ban_duration within reach, so this holds.
+        let until = now.checked_add(base.saturating_mul(factor).min(max))?;
+        events.clear();
+        history.strikes += 1;
+        history.last_ban = Some(now);
+        let ban = Ban {
+            ip_addr,
+            reason: offense.to_string(),
+            until,
+            strikes: history.strikes,
+        };
+        lock.bans.insert(ip_addr, ban.clone());
+        drop(lock);
+        self.events.publish(Event::Banned(ban.clone()));
+        Some(ban)
+    }
+
+    // Bans an address by hand, replacing any ban it already has. This counts
+    // as a strike, so a later automatic ban lasts longer. A ban is no longer
+    // than max_ban_duration; None if even that can't be reached from now.
+    pub fn ban(&self, ip_addr: IpAddr, duration: Option<Duration>, reason: &str) -> Option<Ban> {
+        let mut lock = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let duration = duration.unwrap_or(lock.config.ban_duration).min(lock.config.max_ban_duration);
+        let until = now.checked_add(duration)?;
+        let history = lock.history.entry(ip_addr).or_default();
+        history.strikes += 1;
+        history.last_ban = Some(now);
+        let ban = Ban {
+            ip_addr,
+            reason: reason.to_string(),
+            until,
+            strikes: history.strikes,
+        };
+        lock.bans.insert(ip_addr, ban.clone());
+        drop(lock);
+        self.events.publish(Event::Banned(ban.clone()));
+        Some(ban)
+    }
+
+    pub fn list(&self) -> Vec<Ban> {
+        let lock = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let mut bans: Vec<Ban> = lock.bans.values().filter(|ban| ban.until > now).cloned().collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+
+    // Lifts a ban and forgets the address's strikes.
+    pub fn remove(&self, ip_addr: IpAddr) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        lock.history.remove(&ip_addr);
+        lock.bans.remove(&ip_addr).is_some()
+    }
+}
+
+impl BanListInner {
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = now;
+        self.bans.retain(|_, ban| ban.until > now);
+        // Strikes are forgotten once an address has behaved for the longest ban period.
+        let forget_after = self.config.max_ban_duration;
+        let longest_window = self.config.longest_window();
+        self.history.retain(|_, history| {
+            for events in history.offenses.values_mut() {
+                while events.front().is_some_and(|at| now.duration_since(*at) > longest_window) {
+                    events.pop_front();
+                }
+            }
+            if history.last_ban.is_some_and(|at| now.duration_since(at) > forget_after) {
+                history.strikes = 0;
+                history.last_ban = None;
+            }
+            history.strikes > 0 || history.offenses.values().any(|events| !events.is_empty())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn bans(config: AutobanConfig) -> BanList {
+        BanList::new(config, EventBus::default())
+    }
+
+    fn minutes(minutes: u64) -> Duration {
+        Duration::from_secs(60 * minutes)
+    }
+
+    // How long a ban made just after `from` lasts, to the second.
+    fn length(ban: &Ban, from: Instant) -> Duration {
+        Duration::from_secs(ban.until.duration_since(from).as_secs())
+    }
+
+    // Ends a ban as its time running out would, keeping the strikes.
+    fn expire(bans: &BanList, ip_addr: IpAddr) {
+        bans.inner.lock().unwrap().bans.remove(&ip_addr);
+    }
+
+    #[test]
+    fn bans_once_offenses_reach_the_threshold_within_the_window() {
+        let bans = bans(AutobanConfig { reconnects: 3, reconnect_window: Duration::from_millis(100), ..AutobanConfig::default() });
+        assert!(bans.record(ADDRESS, Offense::Reconnect).is_none());
+        assert!(bans.record(ADDRESS, Offense::Reconnect).is_none());
+        // The first two fall out of the window, so two more aren't enough.
+        thread::sleep(Duration::from_millis(150));
+        assert!(bans.record(ADDRESS, Offense::Reconnect).is_none());
+        assert!(bans.record(ADDRESS, Offense::Reconnect).is_none());
+        assert!(bans.record(ADDRESS, Offense::FailedLogin).is_none());
+        assert!(bans.check(ADDRESS).is_none());
+        let ban = bans.record(ADDRESS, Offense::Reconnect).unwrap();
+        assert_eq!((ban.strikes, ban.reason.as_str()), (1, "rapid reconnects"));
+        assert_eq!(bans.check(ADDRESS).map(|ban| ban.strikes), Some(1));
+        // Nothing more is counted against an address while it is banned.
+        assert!(bans.record(ADDRESS, Offense::Reconnect).is_none());
+        assert!(bans.check(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))).is_none());
+    }
+
+    #[test]
+    fn doubles_each_ban_up_to_the_maximum() {
+        let bans = bans(AutobanConfig { reconnects: 1, ban_duration: minutes(1), max_ban_duration: minutes(5), ..AutobanConfig::default() });
+        let mut lengths = Vec::new();
+        for _ in 0..5 {
+            let from = Instant::now();
+            let ban = bans.record(ADDRESS, Offense::Reconnect).unwrap();
+            lengths.push((ban.strikes, length(&ban, from)));
+            expire(&bans, ADDRESS);
+        }
+        assert_eq!(lengths, [(1, minutes(1)), (2, minutes(2)), (3, minutes(4)), (4, minutes(5)), (5, minutes(5))]);
+        // Lifting a ban forgets the strikes.
+        bans.remove(ADDRESS);
+        let from = Instant::now();
+        let ban = bans.record(ADDRESS, Offense::Reconnect).unwrap();
+        assert_eq!((ban.strikes, length(&ban, from)), (1, minutes(1)));
+    }
+
+    #[test]
+    fn holds_a_sysop_ban_to_the_maximum() {
+        let bans = bans(AutobanConfig { ban_duration: minutes(5), max_ban_duration: minutes(60), ..AutobanConfig::default() });
+        let from = Instant::now();
+        assert_eq!(length(&bans.ban(ADDRESS, None, "by hand").unwrap(), from), minutes(5));
+        assert_eq!(length(&bans.ban(ADDRESS, Some(minutes(10)), "by hand").unwrap(), from), minutes(10));
+        assert_eq!(length(&bans.ban(ADDRESS, Some(minutes(24 * 60)), "by hand").unwrap(), from), minutes(60));
+        assert_eq!(length(&bans.ban(ADDRESS, Some(Duration::MAX), "by hand").unwrap(), from), minutes(60));
+        assert_eq!(bans.list().len(), 1);
+        // Past what the clock can hold, nothing is banned and nothing panics.
+        let unbounded = self::bans(AutobanConfig { max_ban_duration: Duration::MAX, ..AutobanConfig::default() });
+        assert!(unbounded.ban(ADDRESS, Some(Duration::MAX), "by hand").is_none());
+        assert!(unbounded.check(ADDRESS).is_none());
+        assert!(unbounded.ban(ADDRESS, None, "by hand").is_some());
+    }
+
+    #[test]
+    fn counts_a_sysop_ban_as_a_strike() {
+        let bans = bans(AutobanConfig { failed_logins: 2, ban_duration: minutes(1), ..AutobanConfig::default() });
+        let ban = bans.ban(ADDRESS, Some(minutes(30)), "by hand").unwrap();
+        assert_eq!((ban.strikes, ban.reason.as_str()), (1, "by hand"));
+        expire(&bans, ADDRESS);
+        assert!(bans.record(ADDRESS, Offense::FailedLogin).is_none());
+        let from = Instant::now();
+        let ban = bans.record(ADDRESS, Offense::FailedLogin).unwrap();
+        assert_eq!((ban.strikes, length(&ban, from)), (2, minutes(2)));
+    }
+
+    #[test]
+    fn publishes_each_ban() {
+        let events = EventBus::default();
+        let received = events.subscribe();
+        let bans = BanList::new(AutobanConfig { negotiation_floods: 1, ..AutobanConfig::default() }, events);
+        bans.record(ADDRESS, Offense::NegotiationFlood).unwrap();
+        match received.try_recv() {
+            Ok(Event::Banned(ban)) => assert_eq!((ban.ip_addr, ban.reason.as_str()), (ADDRESS, "negotiation flood")),
+            other => panic!("expected a ban event, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+// Fault injection on the relay path, so error handling and session resumption
+// can be exercised against a real backend. Only reachable with a [chaos]
+// section; a server real callers use should never have one.
+
+use std::io::{self, ErrorKind, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::config::ChaosConfig;
+use crate::upstream::Upstream;
+
+// Largest piece of a partial write, and the pause that keeps the pieces in
+// separate segments.
+const PARTIAL_WRITE_SIZE: u64 = 8;
+const PARTIAL_WRITE_PAUSE: Duration = Duration::from_millis(1);
+
+// The faults applied to one session.
+pub struct Chaos {
+    faults: ChaosConfig,
+    applied_at: Instant,
+    disconnected: bool,
+    // xorshift64 state; faults only need to look random.
+    state: u64,
+}
+
+impl Chaos {
+    pub fn new(faults: ChaosConfig) -> Self {
+        let (seed, _) = Uuid::new_v4().as_u64_pair();
+        Self { faults, applied_at: Instant::now(), disconnected: false, state: seed | 1 }
+    }
+
+    pub fn set(&mut self, faults: ChaosConfig) {
+        self.faults = faults;
+        self.applied_at = Instant::now();
+        self.disconnected = false;
+    }
+
+    // Delays, then drops bytes from, a chunk about to be relayed.
+    pub fn mangle(&mut self, data: &mut Vec<u8>) {
+        if !self.faults.latency.is_zero() {
+            sleep(self.faults.latency);
+        }
+        if self.faults.drop_one_in > 0 {
+            let one_in = self.faults.drop_one_in as u64;
+            data.retain(|_| !self.next().is_multiple_of(one_in));
+        }
+    }
+
+    // Cuts the backend connection once it is due. The relay then sees the
+    // backend close, as it would for a real drop.
+    pub fn disconnect_if_due(&mut self, upstream: &mut Upstream) -> bool {
+        let due = self.faults.disconnect_after.is_some_and(|after| self.applied_at.elapsed() >= after);
+        if !due || self.disconnected {
+            return false;
+        }
+        self.disconnected = true;
+        let _ = upstream.shutdown(Shutdown::Both);
+        true
+    }
+
+    pub fn write(&mut self, stream: &mut impl Segmented, data: &[u8]) -> io::Result<()> {
+        if !self.faults.partial_writes {
+            return stream.write_all(data);
+        }
+        stream.set_nodelay(true)?;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let size = (self.next() % PARTIAL_WRITE_SIZE + 1).min(rest.len() as u64) as usize;
+            let (piece, after) = rest.split_at(size);
+            stream.write_all(piece)?;
+            rest = after;
+            sleep(PARTIAL_WRITE_PAUSE);
+        }
+        Ok(())
+    }
+
+    // As much of `data` as the stream takes without blocking, in pieces with
+    // partial writes on, returning how much that was.
+    pub fn write_some(&mut self, stream: &mut impl Segmented, data: &[u8]) -> io::Result<usize> {
+        if self.faults.partial_writes {
+            stream.set_nodelay(true)?;
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let size = match self.faults.partial_writes {
+                true => (self.next() % PARTIAL_WRITE_SIZE + 1).min((data.len() - written) as u64) as usize,
+                false => data.len() - written,
+            };
+            match stream.write(&data[written..written + size]) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(taken) => written += taken,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+            if self.faults.partial_writes {
+                sleep(PARTIAL_WRITE_PAUSE);
+            }
+        }
+        Ok(written)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+// A connection partial writes can be made to, whose pieces are kept apart
+// by turning Nagle's algorithm off.
+pub trait Segmented: Write {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()>;
+}
+
+impl Segmented for TcpStream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+// Applies admin "chaos" arguments ("latency=200", "drop=100", "disconnect=30",
+// "partial=on", or "off") on top of `faults`.
+pub fn parse(arguments: &[&str], mut faults: ChaosConfig) -> Result<ChaosConfig, String> {
+    for argument in arguments {
+        if *argument == "off" {
+            faults = ChaosConfig::default();
+            continue;
+        }
+        let (key, value) = argument.split_once('=').ok_or_else(|| format!("expected key=value, found '{}'", argument))?;
+        let number = || value.parse::<u64>().map_err(|_| format!("invalid {} '{}'", key, value));
+        match key {
+            "latency" => faults.latency = Duration::from_millis(number()?),
+            "drop" => faults.drop_one_in = number()?.try_into().map_err(|_| format!("invalid drop '{}'", value))?,
+            "disconnect" => faults.disconnect_after = Some(Duration::from_secs(number()?)).filter(|after| !after.is_zero()),
+            "partial" => faults.partial_writes = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(format!("invalid partial '{}', expected on or off", value)),
+            },
+            _ => return Err(format!("unknown fault '{}'", key)),
+        }
+    }
+    Ok(faults)
+}
+
+pub fn describe(faults: &ChaosConfig) -> String {
+    if *faults == ChaosConfig::default() {
+        return String::from("no faults");
+    }
+    format!("latency {}ms, drop {}, disconnect {}, partial writes {}",
+            faults.latency.as_millis(),
+            if faults.drop_one_in == 0 { String::from("off") } else { format!("1 in {}", faults.drop_one_in) },
+            faults.disconnect_after.map_or(String::from("off"), |after| format!("after {}s", after.as_secs())),
+            if faults.partial_writes { "on" } else { "off" })
+}
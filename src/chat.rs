@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{ChatConfig, ChatService};
+use crate::events::{Event, EventBus};
+use crate::http;
+use crate::json::Object;
+use crate::log;
+use crate::middleware::SessionInfo;
+use crate::version;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Posts short notices like "New caller from 203.0.113.9 connected to
+// karatepizza" to a Discord or Slack channel.
+pub fn launch_chat(config: &ChatConfig, events: &EventBus) {
+    let config = config.clone();
+    let receiver = events.subscribe();
+    let _ = thread::spawn(move || {
+        let mut limiter = RateLimiter::default();
+        for event in receiver {
+            if let Some(message) = message(&config, &event) {
+                if let Some(text) = limiter.admit(message, config.rate_limit) {
+                    deliver(&config, &text);
+                }
+            }
+        }
+    });
+}
+
+fn message(config: &ChatConfig, event: &Event) -> Option<String> {
+    match event {
+        Event::Connected(session) => {
+            let template = config.connect_message.as_ref()?;
+            Some(render(template, &session_values(session)))
+        }
+        Event::Closed { session, duration } => {
+            let template = config.disconnect_message.as_ref()?;
+            let mut values = session_values(session);
+            values.push(("duration", describe_duration(duration.as_secs())));
+            Some(render(template, &values))
+        }
+        Event::Banned(ban) => {
+            let template = config.ban_message.as_ref()?;
+            Some(render(template, &[
+                ("host", ban.ip_addr.to_string()),
+                ("reason", ban.reason.clone()),
+                ("duration", describe_duration(ban.remaining().as_secs())),
+            ]))
+        }
+        _ => None,
+    }
+}
+
+fn session_values(session: &SessionInfo) -> Vec<(&'static str, String)> {
+    vec![
+        ("host", session.ip_addr.to_string()),
+        ("backend", session.backend.clone()),
+        ("user", session.user.as_ref().map_or_else(|| String::from("guest"), |user| user.username.clone())),
+    ]
+}
+
+// Fills in {name} placeholders in one pass, so that braces in a value, such
+// as a caller's user name, are never taken for placeholders themselves.
+// Placeholders with no value are left as they are.
+pub fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut message = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        message.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let placeholder = rest[1..].find('}').map(|end| &rest[1..1 + end]);
+        match placeholder.and_then(|placeholder| values.iter().find(|(name, _)| *name == placeholder)) {
+            Some((name, value)) => {
+                message.push_str(value);
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                message.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    message.push_str(rest);
+    message
+}
+
+fn describe_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[derive(Default)]
+struct RateLimiter {
+    sent: VecDeque<Instant>,
+    dropped: u32,
+}
+
+impl RateLimiter {
+    // Returns the text to post, or None if the per-minute limit is used up.
+    fn admit(&mut self, message: String, limit: u32) -> Option<String> {
+        let now = Instant::now();
+        while self.sent.front().is_some_and(|at| now.duration_since(*at) >= RATE_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() as u32 >= limit {
+            self.dropped += 1;
+            log!(Server, Info, "Chat rate limit reached, dropped: {}", message);
+            return None;
+        }
+        self.sent.push_back(now);
+        let text = match self.dropped {
+            0 => message,
+            dropped => format!("{}\n({} earlier notices were dropped by the rate limit)", message, dropped),
+        };
+        self.dropped = 0;
+        Some(text)
+    }
+}
+
+fn deliver(config: &ChatConfig, text: &str) {
+    let body = match config.service {
+        ChatService::Discord => Object::new().string("content", text),
+        ChatService::Slack => Object::new().string("text", text),
+    }
+    .finish();
+    let headers = [
+        ("Content-Type", String::from("application/json")),
+        ("User-Agent", format!("TriServer/{}", version::VERSION)),
+    ];
+    match http::post(&config.url, &headers, body.as_bytes(), POST_TIMEOUT) {
+        Ok(response) if response.is_success() => {}
+        Ok(response) => log!(Server, Info, "Chat webhook returned {}", response.status),
+        Err(error) => log!(Server, Warn, "Chat webhook failed: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_each_placeholder() {
+        let values = [("host", String::from("203.0.113.9")), ("backend", String::from("main"))];
+        assert_eq!(render("New caller from {host} on {backend}, {host} again", &values), "New caller from 203.0.113.9 on main, 203.0.113.9 again");
+        assert_eq!(render("", &values), "");
+        assert_eq!(render("no placeholders", &values), "no placeholders");
+    }
+
+    #[test]
+    fn leaves_what_isnt_a_placeholder() {
+        let values = [("host", String::from("203.0.113.9"))];
+        assert_eq!(render("{user} from {host}", &values), "{user} from 203.0.113.9");
+        assert_eq!(render("{{host}} {host", &values), "{203.0.113.9} {host");
+        assert_eq!(render("}{}{ {", &values), "}{}{ {");
+    }
+
+    #[test]
+    fn never_fills_in_what_a_value_brings() {
+        let values = [("user", String::from("{host}")), ("host", String::from("203.0.113.9")), ("backend", String::from("{user}"))];
+        assert_eq!(render("{user} from {host} on {backend}", &values), "{host} from 203.0.113.9 on {user}");
+    }
+
+    #[test]
+    fn describes_durations() {
+        assert_eq!(describe_duration(59), "59s");
+        assert_eq!(describe_duration(61), "1m 1s");
+        assert_eq!(describe_duration(3 * 3600 + 120), "3h 2m");
+    }
+}
@@ -0,0 +1,280 @@
+// `TriServer --check`: loads the config and checks everything that can be
+// checked without binding a socket or connecting anywhere.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+use local_ip_address::local_ip;
+
+use crate::access;
+use crate::asn::AsnDatabase;
+use crate::chaos;
+use crate::config::{Config, TlsConfig, DEFAULT_CONFIG_PATH};
+use crate::http::Url;
+
+#[derive(Default)]
+struct Report {
+    warnings: u32,
+    errors: u32,
+}
+
+impl Report {
+    fn ok(&mut self, message: String) {
+        println!("ok    {}", message);
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings += 1;
+        println!("warn  {}", message);
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors += 1;
+        println!("error {}", message);
+    }
+}
+
+// Returns the process exit code: 0 when nothing is wrong, 1 otherwise.
+// Warnings alone don't fail the check.
+pub fn run(config_path: Option<&Path>) -> i32 {
+    let mut report = Report::default();
+    let source = match config_path {
+        Some(path) => path.display().to_string(),
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => String::from(DEFAULT_CONFIG_PATH),
+        None => String::from("built-in defaults"),
+    };
+    let config = match Config::load(config_path) {
+        Ok(config) => {
+            report.ok(format!("config parsed from {}", source));
+            for include in &config.includes {
+                report.ok(format!("config: included {}", include.display()));
+            }
+            for unknown in &config.unknown_keys {
+                report.warn(format!("config: {}, ignored", unknown));
+            }
+            config
+        }
+        Err(error) => {
+            report.error(format!("config {}: {}", source, error));
+            return finish(report);
+        }
+    };
+
+    let mut listeners = Vec::new();
+    let telnet_host = match &config.server.address {
+        Some(address) => Some(address.clone()),
+        None => match local_ip() {
+            Ok(ip) => Some(ip.to_string()),
+            Err(error) => {
+                report.error(format!("server: no address set and the local IP address is unknown: {}", error));
+                None
+            }
+        },
+    };
+    if let Some(workers) = config.workers.as_ref().filter(|workers| workers.count > 1) {
+        if cfg!(target_os = "linux") {
+            report.ok(format!("workers: {} processes will share port {}", workers.count, config.server.port));
+            check_file(&mut report, "workers.stats_file", &workers.stats_file, "will be created on start");
+        } else {
+            report.error(String::from("workers: only supported on Linux"));
+        }
+    }
+    if let Some(chaos) = &config.chaos {
+        report.warn(format!("chaos: faults will be injected into sessions ({}); not for a server real callers use",
+                            chaos::describe(chaos)));
+    }
+    if let Some(user) = &config.server.user {
+        check_account(&mut report, user, config.server.group.as_deref());
+    }
+    if let Some(host) = telnet_host {
+        check_listener(&mut report, &mut listeners, "server", &format!("{}:{}", host, config.server.port));
+    }
+    if let Some(admin) = &config.admin {
+        check_listener(&mut report, &mut listeners, "admin", &admin.address);
+        if admin.password.is_none() && !resolve(&admin.address).is_ok_and(|addresses| addresses.iter().all(|a| a.ip().is_loopback())) {
+            report.warn(format!("admin: {} is reachable off this host and has no password", admin.address));
+        }
+        check_file(&mut report, "admin.notes_file", &admin.notes_file, "will be created when a note is added");
+    }
+    if let Some(http) = &config.http {
+        check_listener(&mut report, &mut listeners, "http", &http.address);
+    }
+    if let Some(finger) = &config.finger {
+        check_listener(&mut report, &mut listeners, "finger", &finger.address);
+    }
+    if let Some(tls) = &config.tls {
+        check_listener(&mut report, &mut listeners, "tls", &tls.address);
+        check_certificate(&mut report, tls);
+    }
+    if config.tls.is_none() && config.routes.iter().any(|route| route.server_name.is_some()) {
+        report.warn(String::from("route: server_name only matches callers on the [tls] listener, and there is none"));
+    }
+
+    for backend in &config.backends {
+        if !backend.speaks_telnet() && !backend.options.is_empty() {
+            report.warn(format!("backend {}: has no telnet layer, so its options are never used", backend.name));
+        }
+        if let Some(identity) = backend.ssh.as_ref().and_then(|ssh| ssh.identity.as_ref()) {
+            if !identity.is_file() {
+                report.error(format!("backend {}: ssh identity {} does not exist", backend.name, identity.display()));
+            }
+        }
+        if let Some(serial) = &backend.serial {
+            match std::fs::metadata(&serial.device) {
+                Ok(_) => report.ok(format!("backend {}: serial device {} exists", backend.name, serial.device.display())),
+                Err(error) => report.error(format!("backend {}: serial device {}: {}", backend.name, serial.device.display(), error)),
+            }
+            continue;
+        }
+        if let Some(socket) = &backend.socket {
+            match std::fs::metadata(socket) {
+                Ok(_) => report.ok(format!("backend {}: socket {} exists", backend.name, socket.display())),
+                Err(error) => report.error(format!("backend {}: socket {}: {}", backend.name, socket.display(), error)),
+            }
+            continue;
+        }
+        match resolve(&(backend.host.as_str(), backend.port)) {
+            Ok(addresses) => report.ok(format!("backend {}: {}:{} resolves to {}", backend.name, backend.host, backend.port, join(&addresses))),
+            Err(error) => report.error(format!("backend {}: cannot resolve {}: {}", backend.name, backend.host, error)),
+        }
+    }
+
+    if let Some(users) = &config.users {
+        check_file(&mut report, "users.database", &users.database, "will be created on first start");
+    }
+    if config.proxy_protocol.as_ref().is_some_and(|proxy_protocol| proxy_protocol.trusted.is_empty()) {
+        report.warn(String::from("proxy_protocol: no trusted addresses, so no header will ever be honoured"));
+    }
+    if let Some(asn) = &config.asn {
+        match AsnDatabase::load(&asn.database) {
+            Ok(database) if database.len() == 0 => report.warn(format!("asn: no ranges found in {}", asn.database.display())),
+            Ok(database) => report.ok(format!("asn: {} ranges in {}", database.len(), asn.database.display())),
+            Err(error) => report.error(format!("asn.database: cannot read {}: {}", asn.database.display(), error)),
+        }
+    }
+    if let Some(access) = &config.access {
+        if access.ban_file.is_none() && access.allow_file.is_none() {
+            report.warn(String::from("access: section is present but neither ban_file nor allow_file is set"));
+        }
+        for (key, path) in [("access.ban_file", &access.ban_file), ("access.allow_file", &access.allow_file)] {
+            let Some(path) = path else {
+                continue;
+            };
+            match access::read(path) {
+                Ok((entries, problems)) => {
+                    report.ok(format!("{}: {} entries in {}", key, entries.len(), path.display()));
+                    for problem in problems {
+                        report.warn(format!("{}: {} {}, skipped", key, path.display(), problem));
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    check_file(&mut report, key, path, "is read once it does");
+                }
+                Err(error) => report.error(format!("{}: cannot read {}: {}", key, path.display(), error)),
+            }
+        }
+    }
+    if let Some(honeypot) = &config.honeypot {
+        check_file(&mut report, "honeypot.log", &honeypot.log, "will be created on the first capture");
+        if honeypot.sources.is_empty() {
+            report.warn(String::from("honeypot: no sources are listed, so no caller will ever see it"));
+        }
+    }
+    if let Some(hooks) = &config.hooks {
+        if hooks.on_connect.is_none() && hooks.on_disconnect.is_none() {
+            report.warn(String::from("hooks: section is present but no commands are set"));
+        }
+    }
+    if let Some(webhook) = &config.webhook {
+        check_url(&mut report, "webhook.url", &webhook.url);
+    }
+    if let Some(chat) = &config.chat {
+        check_url(&mut report, "chat.url", &chat.url);
+    }
+    if let Some(push) = &config.metrics_push {
+        check_url(&mut report, "metrics_push.url", &push.url);
+    }
+
+    finish(report)
+}
+
+fn finish(report: Report) -> i32 {
+    println!("{} error(s), {} warning(s)", report.errors, report.warnings);
+    if report.errors == 0 { 0 } else { 1 }
+}
+
+fn resolve(address: &impl ToSocketAddrs) -> std::io::Result<Vec<SocketAddr>> {
+    address.to_socket_addrs().map(Iterator::collect)
+}
+
+fn join(addresses: &[SocketAddr]) -> String {
+    addresses.iter().map(SocketAddr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+fn check_listener<'a>(report: &mut Report, listeners: &mut Vec<(SocketAddr, &'a str)>, name: &'a str, address: &str) {
+    let addresses = match resolve(&address) {
+        Ok(addresses) if !addresses.is_empty() => addresses,
+        Ok(_) => return report.error(format!("{}: {} has no addresses", name, address)),
+        Err(error) => return report.error(format!("{}: invalid listen address {}: {}", name, address, error)),
+    };
+    // TcpListener::bind takes the first address that works; assume that's the first.
+    let address = addresses[0];
+    let clash = listeners.iter().find(|(other, _)| {
+        other.port() == address.port()
+            && (other.ip() == address.ip() || other.ip().is_unspecified() || address.ip().is_unspecified())
+    });
+    match clash {
+        Some((other, other_name)) => report.error(format!("{}: {} clashes with {} on {}", name, address, other_name, other)),
+        None => report.ok(format!("{}: would listen on {}", name, address)),
+    }
+    listeners.push((address, name));
+}
+
+#[cfg(unix)]
+fn check_account(report: &mut Report, user: &str, group: Option<&str>) {
+    let result = crate::privileges::lookup_user(user)
+        .and_then(|(uid, gid)| Ok((uid, group.map(crate::privileges::lookup_group).transpose()?.unwrap_or(gid))));
+    match result {
+        Ok((uid, gid)) => report.ok(format!("server: would switch to user {} (uid {}, gid {}) after binding", user, uid, gid)),
+        Err(error) => report.error(format!("server.user: {}", error)),
+    }
+}
+
+#[cfg(not(unix))]
+fn check_account(report: &mut Report, _user: &str, _group: Option<&str>) {
+    report.error(String::from("server.user: switching users is only supported on Unix"));
+}
+
+fn check_certificate(report: &mut Report, tls: &TlsConfig) {
+    match crate::tls::TlsAcceptor::new(&tls.certificate, &tls.key) {
+        Ok(_) => report.ok(format!("tls: loaded {} and {}", tls.certificate.display(), tls.key.display())),
+        Err(error) => report.error(format!("tls: {}", error.0)),
+    }
+}
+
+fn check_file(report: &mut Report, key: &str, path: &Path, missing: &str) {
+    if path.is_dir() {
+        return report.error(format!("{}: {} is a directory", key, path.display()));
+    }
+    if path.exists() {
+        return report.ok(format!("{}: {} exists", key, path.display()));
+    }
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if parent.is_dir() {
+        report.ok(format!("{}: {} does not exist yet and {}", key, path.display(), missing));
+    } else {
+        report.error(format!("{}: directory {} does not exist", key, parent.display()));
+    }
+}
+
+fn check_url(report: &mut Report, key: &str, url: &Url) {
+    if url.https && cfg!(not(unix)) {
+        return report.error(format!("{}: https is not supported on this platform", key));
+    }
+    match resolve(&(url.host.as_str(), url.port)) {
+        Ok(addresses) => report.ok(format!("{}: {} resolves to {}", key, url.host, join(&addresses))),
+        // Delivery retries later, so an unresolvable host isn't fatal.
+        Err(error) => report.warn(format!("{}: cannot resolve {}: {}", key, url.host, error)),
+    }
+}
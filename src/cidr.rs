@@ -0,0 +1,118 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An address block such as 192.0.2.0/24. A bare address is a single host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv4 clients on a dual-stack listener show up as ::ffff:a.b.c.d.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        _ => ip,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.trim().parse().map_err(|_| format!("invalid address '{}'", value))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.trim().parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("invalid prefix length in '{}'", value)),
+            },
+            None => max_prefix,
+        };
+        Ok(Cidr { address: canonical(address), prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_addresses_in_the_block() {
+        let block = cidr("192.0.2.0/24");
+        assert!(block.contains(ip("192.0.2.0")));
+        assert!(block.contains(ip("192.0.2.255")));
+        assert!(!block.contains(ip("192.0.3.1")));
+        assert!(!block.contains(ip("2001:db8::1")));
+        let block = cidr("2001:db8::/32");
+        assert!(block.contains(ip("2001:db8:ffff::1")));
+        assert!(!block.contains(ip("2001:db9::1")));
+        assert!(!block.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn takes_a_bare_address_as_one_host() {
+        assert_eq!(cidr("192.0.2.7"), cidr("192.0.2.7/32"));
+        assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7").contains(ip("192.0.2.8")));
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+    }
+
+    #[test]
+    fn handles_the_shortest_and_longest_prefixes() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("10.1.2.3/32").contains(ip("10.1.2.3")));
+        // The bits past the prefix don't matter.
+        assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+    }
+
+    #[test]
+    fn treats_mapped_ipv4_addresses_as_ipv4() {
+        assert!(cidr("192.0.2.0/24").contains(ip("::ffff:192.0.2.1")));
+        assert_eq!(cidr("::ffff:192.0.2.0/24"), Cidr { address: ip("192.0.2.0"), prefix: 24 });
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        assert_eq!("192.0.2.0/33".parse::<Cidr>(), Err(String::from("invalid prefix length in '192.0.2.0/33'")));
+        assert_eq!("2001:db8::/129".parse::<Cidr>(), Err(String::from("invalid prefix length in '2001:db8::/129'")));
+        assert_eq!("192.0.2.0/x".parse::<Cidr>(), Err(String::from("invalid prefix length in '192.0.2.0/x'")));
+        assert_eq!("example.com/24".parse::<Cidr>(), Err(String::from("invalid address 'example.com/24'")));
+    }
+}
@@ -0,0 +1,439 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::clock::{local_midnight, parse_date};
+use crate::config::LogLevel;
+use crate::mock::MockOptions;
+use crate::shutdown::{parse_delay, DEFAULT_DELAY};
+use crate::users::ExportFormat;
+
+pub const USAGE: &str = "Usage:
+    TriServer [--config <path>] [--daemon] [-q | -v | -vv]
+    TriServer [--config <path>] --stdio
+    TriServer [--config <path>] --tui
+    TriServer [--config <path>] stop
+    TriServer [--config <path>] service install|uninstall
+    TriServer --version
+    TriServer [--config <path>] --check
+    TriServer config schema [--example]
+    TriServer [--config <path>] status
+    TriServer [--config <path>] who
+    TriServer [--config <path>] kick <client-id>
+    TriServer [--config <path>] ban <ip> [--duration <minutes>]
+    TriServer [--config <path>] drain <backend>
+    TriServer [--config <path>] undrain <backend>
+    TriServer [--config <path>] upgrade
+    TriServer [--config <path>] shutdown [--in <delay>]
+    TriServer [--config <path>] shutdown cancel
+    TriServer [--config <path>] loadtest [--target <host:port>] [--connections <n>] [--rate <per-second>] [--messages <n>] [--script <path>]
+    TriServer mock-backend [--listen <address>] [--negotiation none|standard|flood] [--banner <text>]
+    TriServer [--config <path>] user add <username> [--backend <name>] [--time-limit <minutes>]
+    TriServer [--config <path>] user remove <username>
+    TriServer [--config <path>] user calls [<username>]
+    TriServer [--config <path>] user export [--format csv|json] [--since <date>] [--until <date>] [--output <path>]";
+
+// The options that take a value, and the command each goes with.
+const VALUE_OPTIONS: &[(&str, &str)] = &[
+    ("--backend", "user add"), ("--time-limit", "user add"), ("--duration", "ban"), ("--in", "shutdown"),
+    ("--target", "loadtest"), ("--connections", "loadtest"), ("--rate", "loadtest"), ("--messages", "loadtest"), ("--script", "loadtest"),
+    ("--listen", "mock-backend"), ("--negotiation", "mock-backend"), ("--banner", "mock-backend"),
+    ("--format", "user export"), ("--since", "user export"), ("--until", "user export"), ("--output", "user export"),
+];
+
+pub struct Args {
+    pub config_path: Option<PathBuf>,
+    pub command: Command,
+    // Warn with -q, debug with -v and trace with -vv, for every subsystem.
+    pub verbosity: Option<LogLevel>,
+}
+
+pub enum Command {
+    Serve(ServeMode),
+    Stop,
+    Service(ServiceCommand),
+    Version,
+    Check,
+    // Prints every config key, or an example file with all of them.
+    ConfigSchema { example: bool },
+    User(UserCommand),
+    Remote(RemoteCommand),
+    LoadTest(LoadTestOptions),
+    MockBackend(MockOptions),
+}
+
+pub enum ServeMode {
+    Foreground,
+    Daemon,
+    // Started by the Windows service control manager.
+    Service,
+    // One of the processes started by a [workers] supervisor.
+    Worker(u32),
+    // A single caller on stdin and stdout, started by inetd or sshd.
+    Stdio,
+    // In the foreground with the sysop console on the terminal.
+    Tui,
+}
+
+pub enum ServiceCommand {
+    Install,
+    Uninstall,
+}
+
+pub enum UserCommand {
+    // The password is asked for when the command runs, not given here.
+    Add {
+        username: String,
+        backend: Option<String>,
+        time_limit: Option<u64>,
+    },
+    Remove {
+        username: String,
+    },
+    Calls {
+        username: Option<String>,
+    },
+    // Calls started from `since` up to before `until`: the start of the first
+    // day and of the day after the last, in local time.
+    Export {
+        format: ExportFormat,
+        since: Option<u64>,
+        until: Option<u64>,
+        // None writes to standard output.
+        output: Option<PathBuf>,
+    },
+}
+
+pub struct LoadTestOptions {
+    // None means the server's own listener from the config.
+    pub target: Option<String>,
+    pub connections: u32,
+    pub rate: u32,
+    pub messages: u32,
+    // Lines to send instead of `messages` generated ones.
+    pub script: Option<PathBuf>,
+}
+
+// Commands sent to a running server over its admin interface.
+pub enum RemoteCommand {
+    Status,
+    Who,
+    Kick {
+        client_id: String,
+    },
+    Ban {
+        ip_addr: String,
+        duration: Option<u64>,
+    },
+    Drain {
+        backend: String,
+    },
+    Undrain {
+        backend: String,
+    },
+    Upgrade,
+    // None cancels a scheduled shutdown.
+    Shutdown {
+        delay: Option<Duration>,
+    },
+}
+
+impl RemoteCommand {
+    // The admin interface line for this command.
+    pub fn line(&self) -> String {
+        match self {
+            RemoteCommand::Status => String::from("status"),
+            RemoteCommand::Who => String::from("who"),
+            RemoteCommand::Kick { client_id } => format!("kick {}", client_id),
+            RemoteCommand::Ban { ip_addr, duration: Some(minutes) } => format!("ban {} {}", ip_addr, minutes),
+            RemoteCommand::Ban { ip_addr, duration: None } => format!("ban {}", ip_addr),
+            RemoteCommand::Drain { backend } => format!("drain {}", backend),
+            RemoteCommand::Undrain { backend } => format!("undrain {}", backend),
+            RemoteCommand::Upgrade => String::from("upgrade"),
+            RemoteCommand::Shutdown { delay: Some(delay) } => format!("shutdown {}", delay.as_secs()),
+            RemoteCommand::Shutdown { delay: None } => String::from("shutdown cancel"),
+        }
+    }
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config_path = None;
+        let mut check = false;
+        let mut daemon = false;
+        let mut service = false;
+        let mut worker = None;
+        let mut stdio = false;
+        let mut tui = false;
+        let mut quiet = false;
+        let mut verbose = 0;
+        let mut example = false;
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" | "-c" => {
+                    config_path = Some(PathBuf::from(args.next().ok_or("--config requires a path")?));
+                }
+                name if VALUE_OPTIONS.iter().any(|(option, _)| *option == name) => {
+                    let value = args.next().ok_or_else(|| format!("{} requires a value", arg))?;
+                    options.push((arg, value));
+                }
+                "--help" | "-h" => return Err(String::new()),
+                "--version" | "-V" => return Ok(Args { config_path, command: Command::Version, verbosity: None }),
+                "--check" => check = true,
+                "--daemon" | "-d" => daemon = true,
+                "--stdio" | "--inetd" => stdio = true,
+                "--tui" => tui = true,
+                "--quiet" | "-q" => quiet = true,
+                "--verbose" | "-v" => verbose += 1,
+                "-vv" => verbose += 2,
+                "--example" => example = true,
+                // Not in the usage text; this is what `service install` registers.
+                "--service" => service = true,
+                // Also internal; added by the [workers] supervisor.
+                "--worker" => {
+                    let index = args.next().ok_or("--worker requires an index")?;
+                    worker = Some(index.parse::<u32>().map_err(|_| format!("invalid worker index '{}'", index))?);
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+                _ => positional.push(arg),
+            }
+        }
+
+        let option = |name: &str| options.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        let count = |name: &str, default: u32| match option(name) {
+            Some(value) => value.parse::<u32>().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid {} '{}'", name, value)),
+            None => Ok(default),
+        };
+        let verbosity = match (quiet, verbose) {
+            (true, 0) => Some(LogLevel::Warn),
+            (true, _) => return Err(String::from("-q cannot be combined with -v")),
+            (false, 0) => None,
+            (false, 1) => Some(LogLevel::Debug),
+            (false, _) => Some(LogLevel::Trace),
+        };
+        let positional: Vec<&str> = positional.iter().map(String::as_str).collect();
+        // The command the words name, leaving out its arguments.
+        let named = match positional.as_slice() {
+            ["user", "add", ..] => "user add",
+            ["user", "export"] => "user export",
+            ["ban", ..] => "ban",
+            ["shutdown"] => "shutdown",
+            ["loadtest"] => "loadtest",
+            ["mock-backend"] => "mock-backend",
+            _ => "",
+        };
+        let misplaced = options.iter()
+            .filter_map(|(name, _)| VALUE_OPTIONS.iter().find(|(option, _)| option == name))
+            .find(|(_, command)| *command != named);
+        if let Some((name, command)) = misplaced {
+            return Err(format!("{} only goes with {}", name, command));
+        }
+        let command = match positional.as_slice() {
+            _ if example && positional != ["config", "schema"] => return Err(String::from("--example only goes with config schema")),
+            [] if check => Command::Check,
+            _ if check => return Err(String::from("--check cannot be combined with a command")),
+            [] if daemon && service => return Err(String::from("--daemon cannot be combined with --service")),
+            [] if worker.is_some() && (daemon || service) => return Err(String::from("--worker cannot be combined with --daemon or --service")),
+            [] if stdio && (daemon || service || worker.is_some()) => {
+                return Err(String::from("--stdio cannot be combined with --daemon, --service or --worker"))
+            }
+            [] if tui && (daemon || service || worker.is_some() || stdio) => {
+                return Err(String::from("--tui cannot be combined with --daemon, --service, --worker or --stdio"))
+            }
+            [] if tui => Command::Serve(ServeMode::Tui),
+            [] if stdio => Command::Serve(ServeMode::Stdio),
+            [] if let Some(index) = worker => Command::Serve(ServeMode::Worker(index)),
+            [] if daemon => Command::Serve(ServeMode::Daemon),
+            [] if service => Command::Serve(ServeMode::Service),
+            [] => Command::Serve(ServeMode::Foreground),
+            _ if worker.is_some() => return Err(String::from("--worker cannot be combined with a command")),
+            _ if stdio => return Err(String::from("--stdio cannot be combined with a command")),
+            _ if tui => return Err(String::from("--tui cannot be combined with a command")),
+            _ if daemon || service => return Err(format!("{} cannot be combined with a command", if daemon { "--daemon" } else { "--service" })),
+            ["stop"] => Command::Stop,
+            ["config", "schema"] => Command::ConfigSchema { example },
+            ["service", "install"] => Command::Service(ServiceCommand::Install),
+            ["service", "uninstall"] => Command::Service(ServiceCommand::Uninstall),
+            ["user", "add", username] => {
+                let time_limit = match option("--time-limit") {
+                    Some(minutes) => Some(minutes.parse().map_err(|_| format!("invalid time limit '{}'", minutes))?),
+                    None => None,
+                };
+                Command::User(UserCommand::Add {
+                    username: username.to_string(),
+                    backend: option("--backend"),
+                    time_limit,
+                })
+            }
+            ["user", "remove", username] => Command::User(UserCommand::Remove { username: username.to_string() }),
+            ["user", "calls"] => Command::User(UserCommand::Calls { username: None }),
+            ["user", "calls", username] => Command::User(UserCommand::Calls { username: Some(username.to_string()) }),
+            ["user", "export"] => {
+                let date = |name: &str| option(name).map(|date| parse_date(&date)).transpose();
+                let (since, until) = (date("--since")?, date("--until")?);
+                if matches!((since, until), (Some(since), Some(until)) if since > until) {
+                    return Err(String::from("--since cannot be after --until"));
+                }
+                // --until names the last day wanted; 30 hours on is the next day, however long the day is.
+                let until = until.map(|until| local_midnight(until + 30 * 3600));
+                Command::User(UserCommand::Export {
+                    format: match option("--format") {
+                        Some(format) => format.parse().map_err(|error| format!("invalid --format: {}", error))?,
+                        None => ExportFormat::Csv,
+                    },
+                    since,
+                    until,
+                    output: option("--output").map(PathBuf::from),
+                })
+            }
+            ["status"] => Command::Remote(RemoteCommand::Status),
+            ["who"] => Command::Remote(RemoteCommand::Who),
+            ["upgrade"] => Command::Remote(RemoteCommand::Upgrade),
+            ["shutdown"] => {
+                let delay = match option("--in") {
+                    Some(delay) => parse_delay(&delay)?,
+                    None => DEFAULT_DELAY,
+                };
+                Command::Remote(RemoteCommand::Shutdown { delay: Some(delay) })
+            }
+            ["shutdown", "cancel"] => Command::Remote(RemoteCommand::Shutdown { delay: None }),
+            ["drain", backend] => Command::Remote(RemoteCommand::Drain { backend: backend.to_string() }),
+            ["undrain", backend] => Command::Remote(RemoteCommand::Undrain { backend: backend.to_string() }),
+            ["kick", client_id] => Command::Remote(RemoteCommand::Kick { client_id: client_id.to_string() }),
+            ["ban", ip_addr] => {
+                let duration = match option("--duration") {
+                    Some(minutes) => Some(minutes.parse().map_err(|_| format!("invalid duration '{}'", minutes))?),
+                    None => None,
+                };
+                Command::Remote(RemoteCommand::Ban { ip_addr: ip_addr.to_string(), duration })
+            }
+            ["loadtest"] => Command::LoadTest(LoadTestOptions {
+                target: option("--target"),
+                connections: count("--connections", 100)?,
+                rate: count("--rate", 10)?,
+                messages: count("--messages", 10)?,
+                script: option("--script").map(PathBuf::from),
+            }),
+            ["mock-backend"] => {
+                let defaults = MockOptions::default();
+                Command::MockBackend(MockOptions {
+                    address: option("--listen").unwrap_or(defaults.address),
+                    negotiation: match option("--negotiation") {
+                        Some(negotiation) => negotiation.parse().map_err(|error| format!("invalid --negotiation: {}", error))?,
+                        None => defaults.negotiation,
+                    },
+                    banner: option("--banner").unwrap_or(defaults.banner),
+                })
+            }
+            _ => return Err(format!("unrecognized command '{}'", positional.join(" "))),
+        };
+
+        Ok(Args { config_path, command, verbosity })
+    }
+}
+
+// Asks for a password on the terminal without echoing it, twice to catch a
+// typo, or reads the first line of standard input when that isn't a
+// terminal, so it never has to go in the arguments or the shell history.
+pub fn read_password() -> Result<String, String> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        let mut line = String::new();
+        stdin.lock().read_line(&mut line).map_err(|error| format!("unable to read the password: {}", error))?;
+        let password = line.trim_end_matches(['\r', '\n']).to_string();
+        if password.is_empty() {
+            return Err(String::from("no password on standard input"));
+        }
+        return Ok(password);
+    }
+    let password = prompt("Password: ")?;
+    if password.is_empty() {
+        return Err(String::from("the password cannot be empty"));
+    }
+    if prompt("Again: ")? != password {
+        return Err(String::from("the passwords did not match"));
+    }
+    Ok(password)
+}
+
+fn prompt(text: &str) -> Result<String, String> {
+    eprint!("{}", text);
+    let _ = io::stderr().flush();
+    let echo = EchoOff::new();
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    drop(echo);
+    eprintln!();
+    read.map_err(|error| format!("unable to read the password: {}", error))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Turns off the terminal's echo until dropped.
+#[cfg(unix)]
+struct EchoOff(Option<libc::termios>);
+
+#[cfg(unix)]
+impl EchoOff {
+    fn new() -> Self {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+        unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+                return EchoOff(None);
+            }
+            let saved = termios.assume_init();
+            let mut quiet = saved;
+            quiet.c_lflag &= !libc::ECHO;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &quiet) != 0 {
+                return EchoOff(None);
+            }
+            EchoOff(Some(saved))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.0 {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, saved) };
+        }
+    }
+}
+
+// Windows consoles echo regardless; the password is still kept out of argv.
+#[cfg(not(unix))]
+struct EchoOff;
+
+#[cfg(not(unix))]
+impl EchoOff {
+    fn new() -> Self {
+        EchoOff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The admin interface line a remote command is sent as.
+    fn remote(args: &[&str]) -> Result<String, String> {
+        match Args::parse(args.iter().map(|arg| arg.to_string()))?.command {
+            Command::Remote(command) => Ok(command.line()),
+            _ => panic!("not a remote command: {:?}", args),
+        }
+    }
+
+    #[test]
+    fn shuts_down_after_a_grace_period_unless_told_otherwise() {
+        assert_eq!(remote(&["shutdown"]), Ok(String::from("shutdown 300")));
+        assert_eq!(remote(&["shutdown", "--in", "90s"]), Ok(String::from("shutdown 90")));
+        assert_eq!(remote(&["shutdown", "--in", "0"]), Ok(String::from("shutdown 0")));
+        assert_eq!(remote(&["shutdown", "cancel"]), Ok(String::from("shutdown cancel")));
+        assert!(remote(&["shutdown", "--in", "soon"]).is_err());
+        assert!(remote(&["shutdown", "--in"]).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// "YYYY-MM-DD HH:MM:SS" in UTC.
+pub fn format_timestamp(unix: u64) -> String {
+    let (year, month, day) = civil_from_days((unix / 86_400) as i64);
+    let seconds = unix % 86_400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// "YYYY-MM-DD".
+pub fn format_date(unix: u64) -> String {
+    let (year, month, day) = civil_from_days((unix / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Seconds east of UTC the system's time zone was at `unix`, from TZ or
+// /etc/localtime. Zero where that isn't known, so local time is UTC.
+#[cfg(unix)]
+// tm_gmtoff is a c_long, only 32 bits on some targets.
+#[allow(clippy::unnecessary_cast)]
+pub fn utc_offset(unix: u64) -> i64 {
+    let Ok(time) = libc::time_t::try_from(unix) else {
+        return 0;
+    };
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    // localtime_r fills in `tm`, or returns null having left it alone.
+    unsafe {
+        if libc::localtime_r(&time, tm.as_mut_ptr()).is_null() {
+            return 0;
+        }
+        tm.assume_init().tm_gmtoff as i64
+    }
+}
+
+#[cfg(not(unix))]
+pub fn utc_offset(_unix: u64) -> i64 {
+    0
+}
+
+// The start of the local day `unix` falls on.
+pub fn local_midnight(unix: u64) -> u64 {
+    let local = unix as i64 + utc_offset(unix);
+    from_local(local - local.rem_euclid(86_400))
+}
+
+// The Unix time of a local time given as seconds since 1970-01-01 00:00 local.
+// The offset is looked up twice, so one that changes in between is used.
+fn from_local(local: i64) -> u64 {
+    let guess = local - utc_offset(local.max(0) as u64);
+    (local - utc_offset(guess.max(0) as u64)).max(0) as u64
+}
+
+pub fn now_timestamp() -> String {
+    format_timestamp(unix_time())
+}
+
+// "YYYY-MM-DDTHH:MM:SS.mmmZ", RFC 3339 to the millisecond, for JSON logs.
+pub fn now_rfc3339() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}Z", format_timestamp(now.as_secs()).replace(' ', "T"), now.subsec_millis())
+}
+
+// The Unix time at the start of a "YYYY-MM-DD" day, in local time.
+pub fn parse_date(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid date '{}', expected e.g. 2024-03-31", value);
+    let parts: Vec<&str> = value.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return Err(invalid());
+    };
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse::<i64>(), month.parse::<u32>(), day.parse::<u32>()) else {
+        return Err(invalid());
+    };
+    if year < 1970 || !(1..=12).contains(&month) || day == 0 || civil_from_days(days_from_civil(year, month, day)) != (year, month, day) {
+        return Err(invalid());
+    }
+    Ok(from_local(days_from_civil(year, month, day) * 86_400))
+}
+
+// Howard Hinnant's days-from-civil algorithm.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Howard Hinnant's days-to-civil algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,674 @@
+// Telnet command parsing and CP437 transcoding for the relay path, kept free
+// of I/O so any byte sequence can be fed to it directly. The targets in fuzz/
+// do exactly that: nothing here may panic, whatever a scanner sends.
+
+use codepage_437::CP437_CONTROL;
+use telnet::{Action, TelnetOption};
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+// Sent by a backend at the end of a prompt.
+pub const GA: u8 = 249;
+pub const EOR: u8 = 239;
+
+// Option names accepted in the config, as telnet RFCs and BBS software
+// spell them.
+const OPTION_NAMES: &[(&str, u8)] = &[
+    ("BINARY", 0),
+    ("ECHO", 1),
+    ("SGA", 3),
+    ("STATUS", 5),
+    ("TIMING-MARK", 6),
+    ("SNDLOC", 23),
+    ("TTYPE", 24),
+    ("EOR", 25),
+    ("NAWS", 31),
+    ("TSPEED", 32),
+    ("LFLOW", 33),
+    ("LINEMODE", 34),
+    ("XDISPLOC", 35),
+    ("ENVIRON", 36),
+    ("NEW-ENVIRON", 39),
+    ("CHARSET", 42),
+    ("MSSP", 70),
+    ("MCCP2", 86),
+    ("GMCP", 201),
+];
+
+// An option's code from its name (any case) or number.
+pub fn option_code(name: &str) -> Option<u8> {
+    name.parse().ok().or_else(|| {
+        OPTION_NAMES.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)).map(|(_, code)| *code)
+    })
+}
+
+// An option's name as the config spells it, or its number.
+pub fn option_name(code: u8) -> String {
+    match OPTION_NAMES.iter().find(|(_, known)| *known == code) {
+        Some((name, _)) => name.to_string(),
+        None => code.to_string(),
+    }
+}
+
+pub fn action_name(action: &Action) -> &'static str {
+    match action {
+        Action::Will => "WILL",
+        Action::Wont => "WONT",
+        Action::Do => "DO",
+        Action::Dont => "DONT",
+    }
+}
+
+// How a command reads in a trace log, such as "DO TTYPE" or
+// "SB TTYPE \"\x00ansi\"". Data has no description.
+pub fn describe(frame: &Frame) -> Option<String> {
+    const SHOWN: usize = 40;
+    let description = match frame {
+        Frame::Data(_) => return None,
+        Frame::Negotiation(action, option) => format!("{} {}", action_name(action), option_name(option.as_byte())),
+        Frame::Subnegotiation(option, payload) => {
+            let shown: String = payload.iter().take(SHOWN).map(|&byte| match byte {
+                b'"' | b'\\' => format!("\\{}", byte as char),
+                0x20..=0x7e => (byte as char).to_string(),
+                _ => format!("\\x{:02x}", byte),
+            }).collect();
+            let more = if payload.len() > SHOWN { format!("... ({} bytes)", payload.len()) } else { String::new() };
+            format!("SB {} \"{}\"{}", option_name(option.as_byte()), shown, more)
+        }
+        Frame::Oversized(option) => format!("SB {} (oversized, dropped)", option_name(option.as_byte())),
+        Frame::Command(command) => match command {
+            239 => String::from("EOR"),
+            241 => String::from("NOP"),
+            242 => String::from("DM"),
+            243 => String::from("BRK"),
+            244 => String::from("IP"),
+            245 => String::from("AO"),
+            246 => String::from("AYT"),
+            247 => String::from("EC"),
+            248 => String::from("EL"),
+            249 => String::from("GA"),
+            _ => format!("command {}", command),
+        },
+    };
+    Some(description)
+}
+
+// The default limit on a subnegotiation's payload; a longer one is dropped.
+pub const MAX_SUBNEGOTIATION: usize = 512;
+
+#[derive(Debug)]
+pub enum Frame {
+    Data(Vec<u8>),
+    Negotiation(Action, TelnetOption),
+    Subnegotiation(TelnetOption, Vec<u8>),
+    // A subnegotiation whose payload ran over the parser's limit, reported
+    // as soon as it does. Nothing more is reported for the command.
+    Oversized(TelnetOption),
+    // Any other command, such as GA or AYT.
+    Command(u8),
+}
+
+#[derive(Clone, Copy, Default)]
+enum State {
+    #[default]
+    Data,
+    Iac,
+    Negotiation(u8),
+    SubnegotiationOption,
+    Subnegotiation(u8),
+    SubnegotiationIac(u8),
+}
+
+// Splits a telnet stream into data and commands. It keeps its place between
+// calls, so a command split across two reads is still recognised.
+pub struct Parser {
+    state: State,
+    payload: Vec<u8>,
+    limit: usize,
+    oversized: bool,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::with_limit(MAX_SUBNEGOTIATION)
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A parser that drops subnegotiations with more than `limit` bytes of payload.
+    pub fn with_limit(limit: usize) -> Self {
+        Self { state: State::Data, payload: Vec::new(), limit, oversized: false }
+    }
+
+    pub fn feed(&mut self, input: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut data = Vec::new();
+        for &byte in input {
+            self.step(byte, &mut frames, &mut data);
+        }
+        if !data.is_empty() {
+            frames.push(Frame::Data(data));
+        }
+        frames
+    }
+
+    fn step(&mut self, byte: u8, frames: &mut Vec<Frame>, data: &mut Vec<u8>) {
+        self.state = match (self.state, byte) {
+            (State::Data, IAC) => State::Iac,
+            (State::Data, _) => {
+                data.push(byte);
+                State::Data
+            }
+            (State::Iac, IAC) => {
+                data.push(IAC);
+                State::Data
+            }
+            (State::Iac, WILL..=DONT) => State::Negotiation(byte),
+            (State::Iac, SB) => State::SubnegotiationOption,
+            (State::Iac, _) => {
+                emit(frames, data, Frame::Command(byte));
+                State::Data
+            }
+            (State::Negotiation(action), _) => {
+                emit(frames, data, Frame::Negotiation(action_from_byte(action), TelnetOption::parse(byte)));
+                State::Data
+            }
+            (State::SubnegotiationOption, _) => {
+                self.payload.clear();
+                self.oversized = false;
+                State::Subnegotiation(byte)
+            }
+            (State::Subnegotiation(option), IAC) => State::SubnegotiationIac(option),
+            (State::Subnegotiation(option), _) => {
+                self.push_payload(option, byte, frames, data);
+                State::Subnegotiation(option)
+            }
+            (State::SubnegotiationIac(option), IAC) => {
+                self.push_payload(option, IAC, frames, data);
+                State::Subnegotiation(option)
+            }
+            (State::SubnegotiationIac(option), SE) => {
+                self.end_subnegotiation(option, frames, data);
+                State::Data
+            }
+            // A command inside a subnegotiation ends it: pass on what arrived
+            // and read the byte as a command of its own.
+            (State::SubnegotiationIac(option), _) => {
+                self.end_subnegotiation(option, frames, data);
+                self.state = State::Iac;
+                return self.step(byte, frames, data);
+            }
+        };
+    }
+
+    // Past the limit the payload is dropped, and the command reported as
+    // oversized straight away rather than once (if ever) it ends.
+    fn push_payload(&mut self, option: u8, byte: u8, frames: &mut Vec<Frame>, data: &mut Vec<u8>) {
+        if self.payload.len() < self.limit {
+            self.payload.push(byte);
+        } else if !self.oversized {
+            self.oversized = true;
+            self.payload.clear();
+            emit(frames, data, Frame::Oversized(TelnetOption::parse(option)));
+        }
+    }
+
+    fn end_subnegotiation(&mut self, option: u8, frames: &mut Vec<Frame>, data: &mut Vec<u8>) {
+        let payload = std::mem::take(&mut self.payload);
+        if !self.oversized {
+            emit(frames, data, Frame::Subnegotiation(TelnetOption::parse(option), payload));
+        }
+    }
+}
+
+// Follows a raw telnet stream a byte at a time to tell data from the bytes
+// of commands, for code that inspects what a caller types without parsing it.
+#[derive(Clone, Copy, Default)]
+pub struct CommandTracker {
+    state: TrackerState,
+}
+
+#[derive(Clone, Copy, Default)]
+enum TrackerState {
+    #[default]
+    Data,
+    Command,
+    Option,
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+impl CommandTracker {
+    // True if the byte is data rather than part of a command.
+    pub fn is_data(&mut self, byte: u8) -> bool {
+        let (next, is_data) = match (self.state, byte) {
+            (TrackerState::Data, IAC) => (TrackerState::Command, false),
+            (TrackerState::Data, _) => (TrackerState::Data, true),
+            (TrackerState::Command, SB) => (TrackerState::Subnegotiation, false),
+            (TrackerState::Command, WILL..=DONT) => (TrackerState::Option, false),
+            // IAC IAC is an escaped 255, which is data.
+            (TrackerState::Command, IAC) => (TrackerState::Data, true),
+            (TrackerState::Command | TrackerState::Option, _) => (TrackerState::Data, false),
+            (TrackerState::Subnegotiation, IAC) => (TrackerState::SubnegotiationCommand, false),
+            (TrackerState::Subnegotiation, _) => (TrackerState::Subnegotiation, false),
+            (TrackerState::SubnegotiationCommand, SE) => (TrackerState::Data, false),
+            (TrackerState::SubnegotiationCommand, _) => (TrackerState::Subnegotiation, false),
+        };
+        self.state = next;
+        is_data
+    }
+}
+
+// Queues a command behind the data that came before it.
+fn emit(frames: &mut Vec<Frame>, data: &mut Vec<u8>, frame: Frame) {
+    if !data.is_empty() {
+        frames.push(Frame::Data(std::mem::take(data)));
+    }
+    frames.push(frame);
+}
+
+fn action_from_byte(byte: u8) -> Action {
+    match byte {
+        WILL => Action::Will,
+        WONT => Action::Wont,
+        DO => Action::Do,
+        _ => Action::Dont,
+    }
+}
+
+// Data as it goes on the wire, with IAC doubled.
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+pub fn negotiation(action: &Action, option: TelnetOption) -> [u8; 3] {
+    [IAC, action.as_byte(), option.as_byte()]
+}
+
+// The whole subnegotiation in one buffer, so it can go out in one write.
+pub fn subnegotiation(option: TelnetOption, payload: &[u8]) -> Vec<u8> {
+    let mut command = vec![IAC, SB, option.as_byte()];
+    command.extend(escape(payload));
+    command.extend([IAC, SE]);
+    command
+}
+
+pub fn cp437_to_utf8(data: &[u8]) -> String {
+    data.iter().map(|&byte| CP437_CONTROL.decode(byte)).collect()
+}
+
+// For terminals that show neither CP437 nor UTF-8: CP437 art is drawn with
+// transliterate's stand-ins, so box drawing comes out as -, | and + and
+// shading as #. Control characters, and so ANSI sequences, pass through.
+pub fn cp437_to_ascii(data: &[u8]) -> Vec<u8> {
+    let mut ascii = Vec::with_capacity(data.len());
+    for &byte in data {
+        match CP437_CONTROL.decode(byte) {
+            c if c.is_ascii() => ascii.push(c as u8),
+            c => ascii.extend_from_slice(transliterate(c).as_bytes()),
+        }
+    }
+    ascii
+}
+
+// Characters CP437 has no place for become '?'.
+pub fn utf8_to_cp437(text: &str) -> Vec<u8> {
+    text.chars().map(|c| CP437_CONTROL.encode(c).unwrap_or(b'?')).collect()
+}
+
+// UTF-8 to CP437 over a stream of reads. A character split between reads is
+// held back until the rest arrives; bytes that aren't UTF-8 become '?'.
+#[derive(Default)]
+pub struct Cp437Encoder {
+    pending: Vec<u8>,
+}
+
+impl Cp437Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(self.pending.len() + input.len());
+        decode_utf8(&mut self.pending, input, |text| match text {
+            Some(text) => encoded.extend(utf8_to_cp437(text)),
+            None => encoded.push(b'?'),
+        });
+        encoded
+    }
+}
+
+// Picks the ANSI control sequences (ESC [ ... final byte) out of output, for
+// translating to character sets with control codes of their own. A sequence
+// split between reads is held on to; other escapes, and sequences too long
+// to be real, are dropped.
+#[derive(Default)]
+pub struct AnsiScanner {
+    sequence: Option<Vec<u8>>,
+}
+
+pub enum Scanned {
+    Byte(u8),
+    // The numbers between ESC [ and the final byte, 0 where one is left out.
+    Sequence(Vec<usize>, u8),
+}
+
+impl AnsiScanner {
+    const MAX_SEQUENCE: usize = 32;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // None while a sequence is being collected.
+    pub fn scan(&mut self, byte: u8) -> Option<Scanned> {
+        let Some(sequence) = self.sequence.as_mut() else {
+            if byte == 0x1b {
+                self.sequence = Some(Vec::new());
+                return None;
+            }
+            return Some(Scanned::Byte(byte));
+        };
+        match byte {
+            _ if sequence.is_empty() && byte != b'[' => self.sequence = None,
+            0x40..=0x7e if !sequence.is_empty() => {
+                let numbers = String::from_utf8_lossy(&sequence[1..]).split(';').map(|number| number.parse().unwrap_or(0)).collect();
+                self.sequence = None;
+                return Some(Scanned::Sequence(numbers, byte));
+            }
+            _ if sequence.len() >= Self::MAX_SEQUENCE => self.sequence = None,
+            _ => sequence.push(byte),
+        }
+        None
+    }
+}
+
+// Cleans up a backend's UTF-8 output over a stream of reads, so a broken
+// backend can't leave the caller's terminal in a state it won't recover from.
+// Invalid bytes become U+FFFD and C1 control characters, which some terminals
+// take as the start of an escape sequence, are dropped. In ASCII mode
+// everything else outside ASCII is transliterated as well.
+#[derive(Default)]
+pub struct Utf8Sanitizer {
+    pending: Vec<u8>,
+    ascii: bool,
+}
+
+impl Utf8Sanitizer {
+    pub fn new(ascii: bool) -> Self {
+        Self { pending: Vec::new(), ascii }
+    }
+
+    pub fn sanitize(&mut self, input: &[u8]) -> Vec<u8> {
+        let ascii = self.ascii;
+        let mut sanitized = String::with_capacity(self.pending.len() + input.len());
+        decode_utf8(&mut self.pending, input, |text| match text {
+            Some(text) => {
+                for c in text.chars().filter(|c| !matches!(c, '\u{80}'..='\u{9f}')) {
+                    match c {
+                        _ if c.is_ascii() || !ascii => sanitized.push(c),
+                        _ => sanitized.push_str(transliterate(c)),
+                    }
+                }
+            }
+            None if ascii => sanitized.push('?'),
+            None => sanitized.push(char::REPLACEMENT_CHARACTER),
+        });
+        sanitized.into_bytes()
+    }
+
+    // A character still cut off at the end of a prompt, which won't be
+    // finished: it's invalid.
+    pub fn flush(&mut self) -> Vec<u8> {
+        match std::mem::take(&mut self.pending) {
+            pending if pending.is_empty() => Vec::new(),
+            _ if self.ascii => b"?".to_vec(),
+            _ => char::REPLACEMENT_CHARACTER.to_string().into_bytes(),
+        }
+    }
+}
+
+// Splits `pending` plus `input` into runs of text and invalid sequences (None),
+// keeping a character that is cut off at the end in `pending` for next time.
+fn decode_utf8(pending: &mut Vec<u8>, input: &[u8], mut emit: impl FnMut(Option<&str>)) {
+    let mut buffer = std::mem::take(pending);
+    buffer.extend_from_slice(input);
+    let mut rest = &buffer[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(text) => {
+                emit(Some(text));
+                return;
+            }
+            Err(error) => {
+                let (valid, after) = rest.split_at(error.valid_up_to());
+                emit(Some(std::str::from_utf8(valid).unwrap_or_default()));
+                match error.error_len() {
+                    Some(length) => {
+                        emit(None);
+                        rest = &after[length..];
+                    }
+                    // Incomplete, not invalid: wait for the next read.
+                    None => {
+                        *pending = after.to_vec();
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A rough ASCII stand-in for a character outside ASCII: lines and corners of box drawing
+// become -, | and +, accented letters lose their accents, and anything
+// without an obvious likeness becomes '?'.
+pub fn transliterate(c: char) -> &'static str {
+    match c {
+        '\u{2500}' | '\u{2501}' | '\u{2504}' | '\u{2505}' | '\u{2508}' | '\u{2509}' | '\u{254c}' | '\u{254d}' | '\u{2550}'
+        | '\u{2574}' | '\u{2576}' | '\u{2578}' | '\u{257a}' | '\u{257c}' | '\u{257e}' => "-",
+        '\u{2502}' | '\u{2503}' | '\u{2506}' | '\u{2507}' | '\u{250a}' | '\u{250b}' | '\u{254e}' | '\u{254f}' | '\u{2551}'
+        | '\u{2575}' | '\u{2577}' | '\u{2579}' | '\u{257b}' | '\u{257d}' | '\u{257f}' => "|",
+        '\u{2500}'..='\u{257f}' => "+",
+        '\u{2580}'..='\u{259f}' | '\u{25a0}' => "#",
+        '\u{a0}' => " ",
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' | '\u{b4}' => "'",
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{ab}' | '\u{bb}' => "\"",
+        '\u{2013}' | '\u{2014}' | '\u{2212}' => "-",
+        '\u{2026}' => "...",
+        '\u{2022}' | '\u{2219}' | '\u{b7}' => ".",
+        '\u{2190}' => "<",
+        '\u{2192}' => ">",
+        '\u{2191}' => "^",
+        '\u{2193}' => "v",
+        'À'..='Å' => "A",
+        'Æ' => "AE",
+        'Ç' => "C",
+        'È'..='Ë' => "E",
+        'Ì'..='Ï' => "I",
+        'Ñ' => "N",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'Ù'..='Ü' => "U",
+        'Ý' => "Y",
+        'ß' => "ss",
+        'à'..='å' => "a",
+        'æ' => "ae",
+        'ç' => "c",
+        'è'..='ë' => "e",
+        'ì'..='ï' => "i",
+        'ñ' => "n",
+        'ò'..='ö' | 'ø' => "o",
+        'ù'..='ü' => "u",
+        'ý' | 'ÿ' => "y",
+        // The rest of CP437's letters and symbols.
+        '\u{a2}' => "c",
+        '\u{a3}' => "L",
+        '\u{a5}' => "Y",
+        '\u{20a7}' => "Pts",
+        '\u{192}' => "f",
+        '\u{aa}' | '\u{3b1}' => "a",
+        '\u{ba}' | '\u{b0}' => "o",
+        '\u{a1}' => "!",
+        '\u{bf}' => "?",
+        '\u{2310}' | '\u{ac}' => "-",
+        '\u{bd}' => "1/2",
+        '\u{bc}' => "1/4",
+        '\u{b1}' => "+/-",
+        '\u{2265}' => ">=",
+        '\u{2264}' => "<=",
+        '\u{f7}' => "/",
+        '\u{2248}' => "~",
+        '\u{221a}' => "v",
+        '\u{207f}' | '\u{2229}' => "n",
+        '\u{b2}' => "2",
+        '\u{221e}' => "oo",
+        '\u{2261}' => "=",
+        '\u{2320}' | '\u{2321}' => "|",
+        '\u{393}' => "G",
+        '\u{3c0}' => "p",
+        '\u{3a3}' => "S",
+        '\u{3c3}' => "s",
+        '\u{b5}' => "u",
+        '\u{3c4}' => "t",
+        '\u{3a6}' | '\u{3c6}' => "f",
+        '\u{398}' | '\u{3a9}' => "O",
+        '\u{3b4}' => "d",
+        '\u{3b5}' => "e",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The frames as text: data as-is, commands as describe has them.
+    fn parse(parser: &mut Parser, input: &[u8]) -> Vec<String> {
+        parser.feed(input).iter().map(|frame| match frame {
+            Frame::Data(data) => format!("data {:?}", String::from_utf8_lossy(data)),
+            frame => describe(frame).unwrap(),
+        }).collect()
+    }
+
+    #[test]
+    fn splits_data_from_commands() {
+        let mut parser = Parser::new();
+        assert_eq!(parse(&mut parser, b"hi\xff\xfd\x18there\xff\xf9"), ["data \"hi\"", "DO TTYPE", "data \"there\"", "GA"]);
+        assert_eq!(parse(&mut parser, b"\xff\xfb\x01\xff\xfc\x03\xff\xfe\x1f"), ["WILL ECHO", "WONT SGA", "DONT NAWS"]);
+    }
+
+    #[test]
+    fn unescapes_a_doubled_iac() {
+        let mut parser = Parser::new();
+        let frames = parser.feed(b"a\xff\xffb");
+        assert!(matches!(&frames[..], [Frame::Data(data)] if data == b"a\xffb"));
+        assert_eq!(escape(b"a\xffb"), b"a\xff\xffb");
+    }
+
+    #[test]
+    fn keeps_its_place_between_reads() {
+        let mut parser = Parser::new();
+        let mut frames = Vec::new();
+        for byte in b"\xff\xfd\x18\xff\xfa\x18\x00ansi\xff\xf0ok" {
+            frames.extend(parse(&mut parser, &[*byte]));
+        }
+        assert_eq!(frames, ["DO TTYPE", "SB TTYPE \"\\x00ansi\"", "data \"o\"", "data \"k\""]);
+        // An IAC at the end of one read escapes the IAC at the start of the next.
+        assert_eq!(parse(&mut parser, b"x\xff"), ["data \"x\""]);
+        assert_eq!(parse(&mut parser, b"\xffy"), ["data \"\u{fffd}y\""]);
+    }
+
+    #[test]
+    fn reads_subnegotiations() {
+        let mut parser = Parser::new();
+        assert_eq!(parse(&mut parser, b"\xff\xfa\x1f\x00\x50\x00\x18\xff\xf0"), ["SB NAWS \"\\x00P\\x00\\x18\""]);
+        // A 255 in the payload is doubled, and an empty payload is still one.
+        let frames = parser.feed(b"\xff\xfa\x1f\x00\xff\xff\x00\x18\xff\xf0\xff\xfa\x18\xff\xf0");
+        assert!(matches!(&frames[..], [Frame::Subnegotiation(_, first), Frame::Subnegotiation(_, second)]
+            if first == b"\x00\xff\x00\x18" && second.is_empty()));
+    }
+
+    #[test]
+    fn ends_a_subnegotiation_cut_short_by_another_command() {
+        let mut parser = Parser::new();
+        assert_eq!(parse(&mut parser, b"\xff\xfa\x18\x00ans\xff\xfd\x01after"), ["SB TTYPE \"\\x00ans\"", "DO ECHO", "data \"after\""]);
+        assert_eq!(parse(&mut parser, b"\xff\xfa\x18\x00vt\xff\xf9"), ["SB TTYPE \"\\x00vt\"", "GA"]);
+    }
+
+    #[test]
+    fn drops_an_oversized_subnegotiation() {
+        let mut parser = Parser::with_limit(4);
+        assert_eq!(parse(&mut parser, b"\xff\xfa\x18abcd\xff\xf0"), ["SB TTYPE \"abcd\""]);
+        // Reported once, when it runs over, and not again when it ends.
+        assert_eq!(parse(&mut parser, b"\xff\xfa\x18abcde"), ["SB TTYPE (oversized, dropped)"]);
+        assert_eq!(parse(&mut parser, b"fgh\xff\xf0next"), ["data \"next\""]);
+        // The limit doesn't carry over to the next one.
+        assert_eq!(parse(&mut parser, b"\xff\xfa\x18ab\xff\xf0"), ["SB TTYPE \"ab\""]);
+    }
+
+    #[test]
+    fn tracks_commands_in_a_raw_stream() {
+        let mut tracker = CommandTracker::default();
+        let data: Vec<u8> = b"a\xff\xfd\x01b\xff\xffc\xff\xfa\x18xy\xff\xf0d\xff\xf9e"
+            .iter().copied().filter(|&byte| tracker.is_data(byte)).collect();
+        assert_eq!(data, b"ab\xffcde");
+    }
+
+    #[test]
+    fn builds_commands() {
+        assert_eq!(negotiation(&Action::Will, TelnetOption::Echo), [IAC, WILL, 1]);
+        assert_eq!(subnegotiation(TelnetOption::TTYPE, b"\x00a\xff"), b"\xff\xfa\x18\x00a\xff\xff\xff\xf0");
+        assert_eq!(option_code("naws"), Some(31));
+        assert_eq!(option_code("200"), Some(200));
+        assert_eq!(option_code("nope"), None);
+        assert_eq!(option_name(42), "CHARSET");
+        assert_eq!(option_name(99), "99");
+    }
+
+    #[test]
+    fn transcodes_cp437() {
+        assert_eq!(cp437_to_utf8(b"\xc9\xcd\xbb \xb0 \x1b[0m"), "╔═╗ ░ \u{1b}[0m");
+        assert_eq!(utf8_to_cp437("╔═╗ ░ 漢"), b"\xc9\xcd\xbb \xb0 ?");
+        assert_eq!(cp437_to_ascii(b"\xc9\xcd\xbb\r\n"), b"+-+\r\n");
+        // A character split between reads comes out whole.
+        let mut encoder = Cp437Encoder::new();
+        let box_corner = "╔".as_bytes();
+        assert_eq!(encoder.encode(&box_corner[..1]), b"");
+        assert_eq!(encoder.encode(&box_corner[1..]), b"\xc9");
+        assert_eq!(encoder.encode(b"\xffok"), b"?ok");
+    }
+
+    #[test]
+    fn sanitizes_utf8() {
+        let mut sanitizer = Utf8Sanitizer::new(false);
+        assert_eq!(sanitizer.sanitize(b"a\xc2\x9bb\xe2\xff"), "ab\u{fffd}\u{fffd}".as_bytes());
+        assert_eq!(sanitizer.sanitize(&"é".as_bytes()[..1]), b"");
+        assert_eq!(sanitizer.flush(), "\u{fffd}".as_bytes());
+        assert_eq!(Utf8Sanitizer::new(true).sanitize(b"\xc3\xa9\xff"), b"e?");
+    }
+
+    #[test]
+    fn picks_out_ansi_sequences() {
+        let mut scanner = AnsiScanner::new();
+        let scanned: Vec<String> = b"a\x1b[1;34mb\x1b[H\x1bxc".iter().filter_map(|&byte| scanner.scan(byte)).map(|scanned| match scanned {
+            Scanned::Byte(byte) => (byte as char).to_string(),
+            Scanned::Sequence(numbers, last) => format!("{:?}{}", numbers, last as char),
+        }).collect();
+        assert_eq!(scanned, ["a", "[1, 34]m", "b", "[0]H", "c"]);
+    }
+}
@@ -0,0 +1,229 @@
+// Changes made to a config file's [[backend]] and [[pool]] tables in place,
+// for backends changed from the admin interface, so the rest of the file and
+// its comments are left as they were.
+
+use super::toml;
+use super::Value;
+
+// A table header in the file: its line and its dotted path.
+struct Header {
+    line: usize,
+    path: String,
+}
+
+// The lines of a config file: edited, then joined back up.
+pub struct Source {
+    lines: Vec<String>,
+}
+
+impl Source {
+    pub fn new(text: &str) -> Self {
+        Self { lines: text.lines().map(String::from).collect() }
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    pub fn add_backend(&mut self, name: &str, settings: &[(String, String)]) {
+        if self.lines.last().is_some_and(|line| !line.trim().is_empty()) {
+            self.lines.push(String::new());
+        }
+        self.lines.push(String::from("[[backend]]"));
+        self.lines.push(format!("name = {}", toml::quote(name)));
+        self.lines.extend(settings.iter().map(|(key, value)| format!("{} = {}", key, value)));
+    }
+
+    // Replaces the keys' lines in the backend's own table, adding any it
+    // doesn't have after its last key.
+    pub fn set_backend(&mut self, name: &str, settings: &[(String, String)]) -> Result<(), String> {
+        let (start, end) = self.find("backend", "name", name).ok_or_else(|| missing("backend", name))?;
+        let own_end = self.headers().iter().find(|header| header.line > start && header.line < end).map_or(end, |header| header.line);
+        for (key, value) in settings {
+            let line = format!("{} = {}", key, value);
+            match (start + 1..own_end).find(|&i| key_of(&self.lines[i]) == Some(key.as_str())) {
+                Some(i) => self.lines[i] = line,
+                None => {
+                    let after = (start..own_end).rev().find(|&i| i == start || key_of(&self.lines[i]).is_some()).unwrap_or(start);
+                    self.lines.insert(after + 1, line);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Takes out the backend's table along with its subtables.
+    pub fn remove_backend(&mut self, name: &str) -> Result<(), String> {
+        let (start, end) = self.find("backend", "name", name).ok_or_else(|| missing("backend", name))?;
+        self.lines.drain(start..end);
+        Ok(())
+    }
+
+    pub fn add_member(&mut self, pool: &str, backend: &str, weight: u64) -> Result<(), String> {
+        let (_, end) = self.find("pool", "name", pool).ok_or_else(|| missing("pool", pool))?;
+        let member = [String::from("[[pool.member]]"), format!("backend = {}", toml::quote(backend)), format!("weight = {}", weight)];
+        self.lines.splice(end..end, member);
+        Ok(())
+    }
+
+    pub fn remove_member(&mut self, pool: &str, backend: &str) -> Result<(), String> {
+        let (start, end) = self.find("pool", "name", pool).ok_or_else(|| missing("pool", pool))?;
+        let headers = self.headers();
+        let members: Vec<usize> = headers.iter()
+            .filter(|header| header.line > start && header.line < end && header.path == "pool.member")
+            .map(|header| header.line)
+            .collect();
+        for member in members {
+            let next = headers.iter().find(|header| header.line > member).map_or(self.lines.len(), |header| header.line);
+            let member_end = self.trim_back(member, next.min(end));
+            if self.value(member + 1, member_end, "backend").as_deref() == Some(backend) {
+                self.lines.drain(member..member_end);
+                return Ok(());
+            }
+        }
+        Err(format!("pool {} has no member {} in the config file", pool, backend))
+    }
+
+    // Every table header, skipping lines inside multi-line arrays.
+    fn headers(&self) -> Vec<Header> {
+        let mut headers = Vec::new();
+        let mut depth = 0;
+        for (i, line) in self.lines.iter().enumerate() {
+            let content = toml::strip_comment(line).trim();
+            if depth > 0 {
+                depth += toml::bracket_depth(content);
+                continue;
+            }
+            if let Some(eq) = toml::find_unquoted(content, '=') {
+                depth = toml::bracket_depth(&content[eq + 1..]);
+                continue;
+            }
+            let path = content.strip_prefix("[[").and_then(|rest| rest.strip_suffix("]]"))
+                .or_else(|| content.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')));
+            if let Some(path) = path {
+                headers.push(Header { line: i, path: path.split('.').map(str::trim).collect::<Vec<_>>().join(".") });
+            }
+        }
+        headers
+    }
+
+    // The lines of the `[[table]]` whose `key` is `value`, with its subtables
+    // but not the comments leading up to whatever follows it.
+    fn find(&self, table: &str, key: &str, value: &str) -> Option<(usize, usize)> {
+        let headers = self.headers();
+        let subtable = format!("{}.", table);
+        for (k, header) in headers.iter().enumerate().filter(|(_, header)| header.path == table) {
+            let next = headers[k + 1..].iter().find(|other| !other.path.starts_with(&subtable)).map_or(self.lines.len(), |other| other.line);
+            let own_end = headers.get(k + 1).map_or(self.lines.len(), |other| other.line);
+            if self.value(header.line + 1, own_end, key).as_deref() == Some(value) {
+                return Some((header.line, self.trim_back(header.line, next)));
+            }
+        }
+        None
+    }
+
+    // Moves `end` back over blank and comment lines, down to just after `start`.
+    fn trim_back(&self, start: usize, mut end: usize) -> usize {
+        while end > start + 1 && toml::strip_comment(&self.lines[end - 1]).trim().is_empty() {
+            end -= 1;
+        }
+        end
+    }
+
+    fn value(&self, start: usize, end: usize, key: &str) -> Option<String> {
+        match toml::parse(&self.lines[start..end].join("\n")) {
+            Ok(Value::Table(entries)) => match entries.get(key) {
+                Some(Value::String(value)) => Some(value.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+// The key a `key = value` line sets.
+fn key_of(line: &str) -> Option<&str> {
+    let content = toml::strip_comment(line);
+    toml::find_unquoted(content, '=').map(|eq| content[..eq].trim()).filter(|key| !key.is_empty())
+}
+
+fn missing(table: &str, name: &str) -> String {
+    format!("there is no {} {} in the config file", table, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"[server]
+port = 9000
+
+# The main board.
+[[backend]]
+name = "node1"
+host = "127.0.0.1"   # local
+port = 2323
+[backend.options]
+TTYPE = "refuse"
+
+# The spare.
+[[backend]]
+name = "node2"
+host = "127.0.0.1"
+port = 2324
+
+[[pool]]
+name = "nodes"
+[[pool.member]]
+backend = "node1"
+weight = 1
+[[pool.member]]
+backend = "node2"
+weight = 2
+"#;
+
+    fn setting(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn sets_keys_in_the_backends_own_table() {
+        let mut source = Source::new(CONFIG);
+        source.set_backend("node1", &[setting("port", "2325"), setting("output", "\"ascii\"")]).unwrap();
+        let expected = CONFIG.replace("port = 2323\n", "port = 2325\noutput = \"ascii\"\n");
+        assert_eq!(source.text(), expected);
+    }
+
+    #[test]
+    fn adds_and_removes_backends_with_their_subtables() {
+        let mut source = Source::new(CONFIG);
+        source.remove_backend("node1").unwrap();
+        assert_eq!(source.text(), CONFIG.replace("[[backend]]\nname = \"node1\"\nhost = \"127.0.0.1\"   # local\nport = 2323\n\
+                                                  [backend.options]\nTTYPE = \"refuse\"\n", ""));
+        source.add_backend("games", &[setting("host", "\"games.example\"")]);
+        assert!(source.text().ends_with("weight = 2\n\n[[backend]]\nname = \"games\"\nhost = \"games.example\"\n"));
+    }
+
+    #[test]
+    fn joins_and_leaves_pools() {
+        let mut source = Source::new(CONFIG);
+        source.remove_member("nodes", "node1").unwrap();
+        source.add_member("nodes", "node3", 5).unwrap();
+        assert!(source.text().ends_with("[[pool]]\nname = \"nodes\"\n[[pool.member]]\nbackend = \"node2\"\nweight = 2\n\
+                                         [[pool.member]]\nbackend = \"node3\"\nweight = 5\n"), "{}", source.text());
+    }
+
+    #[test]
+    fn reports_what_isnt_there() {
+        let mut source = Source::new(CONFIG);
+        assert_eq!(source.set_backend("node3", &[setting("port", "23")]), Err(String::from("there is no backend node3 in the config file")));
+        assert!(source.remove_backend("node3").is_err());
+        assert!(source.add_member("others", "node1", 1).is_err());
+        assert_eq!(source.remove_member("nodes", "node3"), Err(String::from("pool nodes has no member node3 in the config file")));
+        // A name in a comment or another table isn't a backend.
+        assert!(source.remove_backend("nodes").is_err());
+        assert_eq!(source.text(), CONFIG);
+    }
+}
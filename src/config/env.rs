@@ -0,0 +1,226 @@
+// Config keys set from the environment, over whatever the file says, so a
+// container can be configured without a file baked into its image. A
+// variable names the table and the key, with [[table]]s taking an index:
+//
+//   TRISERVER_SERVER_PORT=2323          [server] port = 2323
+//   TRISERVER_PORT=2323                 the same; [server] is the default
+//   TRISERVER_LOG_FORMAT=json           [log] format = "json"
+//   TRISERVER_BACKEND_0_HOST=bbs        host in the first [[backend]]
+//   TRISERVER_BACKEND_HOST=bbs          the same; a left-out index is 0
+//   TRISERVER_BACKEND_ADDR=bbs:2323     host and port together
+//   TRISERVER_POOL_0_MEMBER_1_WEIGHT=2  in the first pool's second member
+//
+// A value is read as TOML if it is one (2323, true, ["a", "b"], "quoted"),
+// and as a plain string otherwise. The index one past the last [[table]]
+// adds one. A key the table doesn't have is an error, naming the variable.
+
+use std::collections::BTreeMap;
+
+use super::schema::{Section, SECTIONS};
+use super::toml;
+use super::{ConfigError, Value};
+
+pub const PREFIX: &str = "TRISERVER_";
+
+// Our own variables that aren't config: those a handover passes on.
+const NOT_CONFIG: &[&str] = &["TRISERVER_LISTEN_FDS", "TRISERVER_READY_FD", "TRISERVER_LOCK_FD"];
+
+// Takes `host:port` for a [[backend]]'s host and port.
+const ADDRESS: &str = "addr";
+
+enum Step {
+    Table(&'static str),
+    Element(&'static str, usize),
+}
+
+// Sets each of our variables' keys in the parsed file.
+pub fn apply(root: &mut BTreeMap<String, Value>, variables: impl IntoIterator<Item = (String, String)>) -> Result<(), ConfigError> {
+    let mut overrides = Vec::new();
+    for (variable, raw) in variables {
+        let Some(name) = variable.strip_prefix(PREFIX).filter(|_| !NOT_CONFIG.contains(&variable.as_str())) else {
+            continue;
+        };
+        let (steps, key) = locate(&name.to_ascii_lowercase())
+            .map_err(|message| ConfigError::Invalid { key: variable.clone(), message })?;
+        overrides.push((steps, key, variable, raw));
+    }
+    // Lowest indexes first, so each new [[table]] comes after the last.
+    overrides.sort_by_key(|(steps, ..)| steps.iter().map(|step| match step {
+        Step::Table(_) => 0,
+        Step::Element(_, index) => *index,
+    }).collect::<Vec<_>>());
+    for (steps, key, variable, raw) in overrides {
+        let table = table_at(root, &steps).map_err(|message| ConfigError::Invalid { key: variable.clone(), message })?;
+        if key == ADDRESS {
+            let (host, port) = raw.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse::<i64>().ok()?)))
+                .ok_or_else(|| ConfigError::Invalid { key: variable, message: format!("expected host:port, found '{}'", raw) })?;
+            table.insert(String::from("host"), Value::String(host.trim_matches(['[', ']']).to_string()));
+            table.insert(String::from("port"), Value::Integer(port));
+            continue;
+        }
+        table.insert(key, toml::parse_value(&raw, 0).unwrap_or(Value::String(raw)));
+    }
+    Ok(())
+}
+
+// Where a variable's name (less the prefix, in lower case) points.
+fn locate(name: &str) -> Result<(Vec<Step>, String), String> {
+    let mut steps = Vec::new();
+    let mut path = Vec::new();
+    // [server] unless the name starts with another table.
+    let mut table = &SECTIONS[0];
+    let mut sections = SECTIONS;
+    let mut rest = name;
+    'descend: loop {
+        for section in sections {
+            let Some(after) = rest.strip_prefix(section.name).and_then(|after| after.strip_prefix('_')) else {
+                continue;
+            };
+            rest = after;
+            if section.repeated {
+                let index = match rest.split_once('_') {
+                    Some((index, after)) if !index.is_empty() && index.bytes().all(|byte| byte.is_ascii_digit()) => {
+                        rest = after;
+                        index.parse().map_err(|_| format!("index {} is too large", index))?
+                    }
+                    _ => 0,
+                };
+                steps.push(Step::Element(section.name, index));
+            } else {
+                steps.push(Step::Table(section.name));
+            }
+            path.push(section.name);
+            table = section;
+            sections = section.sections;
+            continue 'descend;
+        }
+        break;
+    }
+    if steps.is_empty() {
+        steps.push(Step::Table("server"));
+        path.push("server");
+    }
+    if rest.is_empty() {
+        return Err(String::from("no key is named"));
+    }
+    if !has_key(table, rest) {
+        return Err(format!("[{}] has no key {}", path.join("."), rest));
+    }
+    Ok((steps, rest.to_string()))
+}
+
+fn has_key(table: &Section, key: &str) -> bool {
+    table.keys.iter().any(|known| known.name == key) || (table.name == "backend" && key == ADDRESS)
+}
+
+fn table_at<'a>(root: &'a mut BTreeMap<String, Value>, steps: &[Step]) -> Result<&'a mut BTreeMap<String, Value>, String> {
+    let mut table = root;
+    for step in steps {
+        table = match step {
+            Step::Table(name) => match table.entry(name.to_string()).or_insert_with(|| Value::Table(BTreeMap::new())) {
+                Value::Table(inner) => inner,
+                _ => return Err(format!("'{}' is not a table", name)),
+            },
+            Step::Element(name, index) => match table.entry(name.to_string()).or_insert_with(|| Value::Array(Vec::new())) {
+                Value::Array(items) => {
+                    if *index == items.len() {
+                        items.push(Value::Table(BTreeMap::new()));
+                    }
+                    let count = items.len();
+                    match items.get_mut(*index) {
+                        Some(Value::Table(inner)) => inner,
+                        Some(_) => return Err(format!("'{}' is not an array of tables", name)),
+                        None => return Err(format!("there are only {} [[{}]] tables", count, name)),
+                    }
+                }
+                _ => return Err(format!("'{}' is not an array of tables", name)),
+            },
+        };
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(variables: &[(&str, &str)]) -> Result<BTreeMap<String, Value>, ConfigError> {
+        let mut root = BTreeMap::new();
+        apply(&mut root, variables.iter().map(|(name, value)| (name.to_string(), value.to_string())))?;
+        Ok(root)
+    }
+
+    fn table<'a>(root: &'a BTreeMap<String, Value>, name: &str) -> &'a BTreeMap<String, Value> {
+        match root.get(name) {
+            Some(Value::Table(table)) => table,
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    fn element<'a>(root: &'a BTreeMap<String, Value>, name: &str, index: usize) -> &'a BTreeMap<String, Value> {
+        match root.get(name) {
+            Some(Value::Array(items)) => match &items[index] {
+                Value::Table(table) => table,
+                other => panic!("{}[{}] is {:?}", name, index, other),
+            },
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn sets_server_keys_with_or_without_the_table() {
+        let root = applied(&[("TRISERVER_SERVER_PORT", "2323"), ("TRISERVER_ADDRESS", "0.0.0.0")]).unwrap();
+        assert_eq!(table(&root, "server").get("port"), Some(&Value::Integer(2323)));
+        assert_eq!(table(&root, "server").get("address"), Some(&Value::String(String::from("0.0.0.0"))));
+    }
+
+    #[test]
+    fn reads_values_as_toml_when_they_are() {
+        let root = applied(&[("TRISERVER_SERVER_TRACE_NEGOTIATION", "true"), ("TRISERVER_SERVER_USER", "\"true\"")]).unwrap();
+        assert_eq!(table(&root, "server").get("trace_negotiation"), Some(&Value::Boolean(true)));
+        assert_eq!(table(&root, "server").get("user"), Some(&Value::String(String::from("true"))));
+    }
+
+    #[test]
+    fn indexes_repeated_tables_from_zero() {
+        let root = applied(&[("TRISERVER_BACKEND_1_NAME", "other"), ("TRISERVER_BACKEND_0_NAME", "first")]).unwrap();
+        assert_eq!(element(&root, "backend", 0).get("name"), Some(&Value::String(String::from("first"))));
+        assert_eq!(element(&root, "backend", 1).get("name"), Some(&Value::String(String::from("other"))));
+    }
+
+    #[test]
+    fn takes_a_left_out_index_as_zero() {
+        let root = applied(&[("TRISERVER_BACKEND_HOST", "bbs"), ("TRISERVER_BACKEND_SSH_USER", "sysop")]).unwrap();
+        let backend = element(&root, "backend", 0);
+        assert_eq!(backend.get("host"), Some(&Value::String(String::from("bbs"))));
+        assert_eq!(table(backend, "ssh").get("user"), Some(&Value::String(String::from("sysop"))));
+    }
+
+    #[test]
+    fn splits_a_backend_address() {
+        let root = applied(&[("TRISERVER_BACKEND_ADDR", "bbs.example.com:2323")]).unwrap();
+        let backend = element(&root, "backend", 0);
+        assert_eq!(backend.get("host"), Some(&Value::String(String::from("bbs.example.com"))));
+        assert_eq!(backend.get("port"), Some(&Value::Integer(2323)));
+        assert!(applied(&[("TRISERVER_BACKEND_ADDR", "bbs")]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys_naming_the_variable() {
+        let error = applied(&[("TRISERVER_MAX_CLIENTS", "5")]).unwrap_err().to_string();
+        assert_eq!(error, "TRISERVER_MAX_CLIENTS: [server] has no key max_clients");
+        let error = applied(&[("TRISERVER_BACKEND_0_SSH_COLOR", "red")]).unwrap_err().to_string();
+        assert_eq!(error, "TRISERVER_BACKEND_0_SSH_COLOR: [backend.ssh] has no key color");
+    }
+
+    #[test]
+    fn rejects_an_index_past_the_next_table() {
+        assert!(applied(&[("TRISERVER_BACKEND_2_NAME", "far")]).is_err());
+    }
+
+    #[test]
+    fn ignores_other_variables() {
+        let root = applied(&[("HOME", "/root"), ("TRISERVER_LISTEN_FDS", "3,4"), ("TRISERVER_LOCK_FD", "5")]).unwrap();
+        assert!(root.is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+// `include = ["backends/*.toml"]`: other config files read into this one, so
+// that each backend, say, can have a file of its own. Paths are relative to
+// the file that includes them, and * and ? match in the file name, the
+// matches read in name order. An included file looks just like the main one
+// (and may include more); its [[tables]] come after those already read and
+// its [tables] are merged key by key, but no other key can be set twice.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{read_table, ConfigError, Value};
+
+// Reads in the files the main config file (or none) includes, returning them
+// in the order they were read. Keys they set that nothing reads go in `unknown`.
+pub fn expand(root: &mut BTreeMap<String, Value>, path: Option<&Path>, unknown: &mut Vec<String>) -> Result<Vec<PathBuf>, ConfigError> {
+    let mut reading = match path {
+        Some(path) => vec![canonical(path)?],
+        None => Vec::new(),
+    };
+    let mut read = Vec::new();
+    include(root, directory(path), &mut reading, &mut read, unknown)?;
+    Ok(read)
+}
+
+// `base` is the directory the table's file is in, and `reading` that file and
+// those that included it, to catch one that includes itself.
+fn include(table: &mut BTreeMap<String, Value>, base: &Path, reading: &mut Vec<PathBuf>,
+           read: &mut Vec<PathBuf>, unknown: &mut Vec<String>) -> Result<(), ConfigError> {
+    let patterns = match table.remove("include") {
+        None => return Ok(()),
+        Some(Value::String(pattern)) => vec![pattern],
+        Some(Value::Array(items)) => items.into_iter().map(|item| match item {
+            Value::String(pattern) => Ok(pattern),
+            _ => Err(invalid("include", "expected an array of paths")),
+        }).collect::<Result<_, _>>()?,
+        Some(_) => return Err(invalid("include", "expected an array of paths")),
+    };
+    for pattern in patterns {
+        for path in matching(&base.join(&pattern))? {
+            let canonical = canonical(&path)?;
+            if reading.contains(&canonical) {
+                return Err(invalid("include", &format!("{} includes itself", path.display())));
+            }
+            let mut included = read_table(&path, unknown).map_err(|error| within(&path, error))?;
+            reading.push(canonical);
+            include(&mut included, directory(Some(&path)), reading, read, unknown).map_err(|error| within(&path, error))?;
+            reading.pop();
+            merge(table, included, "").map_err(|error| within(&path, error))?;
+            read.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Adds what an included file sets to what has been read before it.
+fn merge(into: &mut BTreeMap<String, Value>, from: BTreeMap<String, Value>, prefix: &str) -> Result<(), ConfigError> {
+    for (key, value) in from {
+        let dotted = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                into.insert(key, value);
+            }
+            (Some(Value::Table(existing)), Value::Table(table)) => merge(existing, table, &dotted)?,
+            (Some(Value::Array(existing)), Value::Array(items)) if tables(existing) && tables(&items) => existing.extend(items),
+            _ => return Err(invalid(&dotted, "is set in more than one file")),
+        }
+    }
+    Ok(())
+}
+
+fn tables(items: &[Value]) -> bool {
+    items.iter().all(|item| matches!(item, Value::Table(_)))
+}
+
+// The path itself, unless its file name has wildcards. Like a shell, a * or ?
+// doesn't match a leading dot.
+fn matching(pattern: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+    let name = pattern.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let parent = directory(Some(pattern));
+    if parent.to_string_lossy().contains(['*', '?']) {
+        return Err(invalid("include", &format!("'{}': wildcards only work in the file name", pattern.display())));
+    }
+    let listed = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+    let entries = fs::read_dir(listed).map_err(|error| ConfigError::Io { path: listed.to_path_buf(), error })?;
+    let name: Vec<char> = name.chars().collect();
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| !kind.is_dir()))
+        .filter(|entry| entry.file_name().to_str().is_some_and(|file| {
+            !file.starts_with('.') && wildcard(&name, &file.chars().collect::<Vec<_>>())
+        }))
+        .map(|entry| parent.join(entry.file_name()))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => wildcard(&pattern[1..], name) || (!name.is_empty() && wildcard(pattern, &name[1..])),
+        (Some('?'), Some(_)) => wildcard(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+// Empty for the current directory.
+fn directory(path: Option<&Path>) -> &Path {
+    path.and_then(Path::parent).unwrap_or(Path::new(""))
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, ConfigError> {
+    fs::canonicalize(path).map_err(|error| ConfigError::Io { path: path.to_path_buf(), error })
+}
+
+// An error in an included file, saying which; a read error already does.
+fn within(path: &Path, error: ConfigError) -> ConfigError {
+    match error {
+        ConfigError::Io { .. } => error,
+        error => ConfigError::Included { path: path.to_path_buf(), error: Box::new(error) },
+    }
+}
+
+fn invalid(key: &str, message: &str) -> ConfigError {
+    ConfigError::Invalid { key: key.to_string(), message: message.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of config files, removed afterwards.
+    struct Scratch(PathBuf);
+
+    // The table read, the files included and the unknown keys found.
+    type Expanded = (BTreeMap<String, Value>, Vec<PathBuf>, Vec<String>);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("triserver-include-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, name: &str, source: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, source).unwrap();
+            path
+        }
+
+        // Reads `name` and what it includes.
+        fn expand(&self, name: &str) -> Result<Expanded, ConfigError> {
+            let path = self.0.join(name);
+            let mut unknown = Vec::new();
+            let mut root = read_table(&path, &mut unknown)?;
+            let read = expand(&mut root, Some(&path), &mut unknown)?;
+            Ok((root, read, unknown))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(root: &BTreeMap<String, Value>) -> Vec<String> {
+        let Some(Value::Array(backends)) = root.get("backend") else { panic!("no backends") };
+        backends.iter().map(|backend| match backend {
+            Value::Table(backend) => format!("{:?}", backend.get("name")),
+            other => panic!("backend {:?}", other),
+        }).collect()
+    }
+
+    #[test]
+    fn reads_included_files_in_name_order() {
+        let scratch = Scratch::new("order");
+        scratch.write("main.toml", "include = [\"backends/*.toml\", \"log.yaml\"]\n[server]\nport = 23\n[[backend]]\nname = \"main\"\n");
+        let b = scratch.write("backends/b.toml", "[[backend]]\nname = \"b\"\nprot = 23\n");
+        let a = scratch.write("backends/a.toml", "[[backend]]\nname = \"a\"\n[server]\nnode_name = \"BBS\"\n");
+        scratch.write("backends/.hidden.toml", "[[backend]]\nname = \"hidden\"\n");
+        scratch.write("backends/notes.txt", "not a config file");
+        let log = scratch.write("log.yaml", "log:\n  relay: debug\n");
+        let (root, read, unknown) = scratch.expand("main.toml").unwrap();
+        assert_eq!(read, [a, b, log]);
+        assert_eq!(names(&root), ["Some(String(\"main\"))", "Some(String(\"a\"))", "Some(String(\"b\"))"]);
+        let Some(Value::Table(server)) = root.get("server") else { panic!("no server table") };
+        assert_eq!(server.get("port"), Some(&Value::Integer(23)));
+        assert_eq!(server.get("node_name"), Some(&Value::String(String::from("BBS"))));
+        assert!(root.contains_key("log"));
+        // Each file's unknown keys are reported with its own lines.
+        assert_eq!(unknown.len(), 1, "{:?}", unknown);
+        assert!(unknown[0].contains("b.toml line 3:") && unknown[0].contains("prot"), "{:?}", unknown);
+    }
+
+    #[test]
+    fn reads_includes_of_includes_relative_to_their_file() {
+        let scratch = Scratch::new("nested");
+        scratch.write("main.toml", "include = \"more/first.toml\"\n");
+        scratch.write("more/first.toml", "include = [\"second.json\"]\n[[backend]]\nname = \"first\"\n");
+        scratch.write("more/second.json", "{\"backend\": [{\"name\": \"second\"}]}");
+        let (root, read, _) = scratch.expand("main.toml").unwrap();
+        assert_eq!(read, [scratch.0.join("more/second.json"), scratch.0.join("more/first.toml")]);
+        assert_eq!(names(&root), ["Some(String(\"first\"))", "Some(String(\"second\"))"]);
+    }
+
+    #[test]
+    fn refuses_a_key_set_twice() {
+        let scratch = Scratch::new("twice");
+        scratch.write("main.toml", "include = [\"other.toml\"]\n[server]\nport = 23\n");
+        let other = scratch.write("other.toml", "[server]\nport = 2323\n");
+        match scratch.expand("main.toml") {
+            Err(ConfigError::Included { path, error }) => {
+                assert_eq!(path, other);
+                assert_eq!(error.to_string(), "server.port: is set in more than one file");
+            }
+            other => panic!("expanded to {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_a_file_that_includes_itself() {
+        let scratch = Scratch::new("loop");
+        scratch.write("main.toml", "include = [\"loop.toml\"]\n");
+        scratch.write("loop.toml", "include = [\"./main.toml\"]\n");
+        let error = scratch.expand("main.toml").unwrap_err().to_string();
+        assert!(error.ends_with("main.toml includes itself"), "{}", error);
+    }
+
+    #[test]
+    fn refuses_bad_includes() {
+        let scratch = Scratch::new("bad");
+        scratch.write("wild.toml", "include = [\"*/*.toml\"]\n");
+        let error = scratch.expand("wild.toml").unwrap_err().to_string();
+        assert!(error.contains("wildcards only work in the file name"), "{}", error);
+        scratch.write("number.toml", "include = 5\n");
+        assert_eq!(scratch.expand("number.toml").unwrap_err().to_string(), "include: expected an array of paths");
+        scratch.write("missing.toml", "include = [\"nowhere.toml\"]\n");
+        assert!(matches!(scratch.expand("missing.toml"), Err(ConfigError::Io { .. })));
+        scratch.write("nowhere.toml", "include = [\"nowhere/*.toml\"]\n");
+        assert!(matches!(scratch.expand("nowhere.toml"), Err(ConfigError::Io { .. })));
+        // But a pattern that matches nothing is fine.
+        scratch.write("empty.toml", "include = [\"*.none\"]\n");
+        assert_eq!(scratch.expand("empty.toml").unwrap().1, Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let matches = |pattern: &str, name: &str| wildcard(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>());
+        assert!(matches("*.toml", "a.toml"));
+        assert!(matches("*.toml", ".toml"));
+        assert!(matches("b?.toml", "b1.toml"));
+        assert!(matches("*a*", "bab"));
+        assert!(!matches("b?.toml", "b.toml"));
+        assert!(!matches("*.toml", "a.toml.bak"));
+    }
+}
@@ -0,0 +1,248 @@
+// JSON config files, read into the same values as TOML ones: objects are
+// tables, and an array of objects is an array of tables. Numbers have to be
+// whole, as the config has no others, and a null is the same as leaving the
+// key out.
+
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use super::{ConfigError, Value};
+
+pub fn parse(source: &str) -> Result<Value, ConfigError> {
+    let mut parser = Parser { chars: source.chars().peekable(), line: 1 };
+    parser.whitespace();
+    let value = match parser.peek() {
+        Some('{') => parser.value()?,
+        _ => return Err(parser.error("expected an object at the top level")),
+    };
+    parser.whitespace();
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value.unwrap_or_else(|| Value::Table(BTreeMap::new())))
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let next = self.chars.next();
+        if next == Some('\n') {
+            self.line += 1;
+        }
+        next
+    }
+
+    fn whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ConfigError> {
+        self.whitespace();
+        match self.next() {
+            Some(found) if found == expected => Ok(()),
+            Some(found) => Err(self.error(&format!("expected '{}', found '{}'", expected, found))),
+            None => Err(self.error(&format!("expected '{}', found the end of the file", expected))),
+        }
+    }
+
+    fn error(&self, message: &str) -> ConfigError {
+        ConfigError::Parse { line: self.line, message: message.to_string() }
+    }
+
+    // None for a null.
+    fn value(&mut self) -> Result<Option<Value>, ConfigError> {
+        self.whitespace();
+        match self.peek() {
+            Some('{') => self.object().map(Some),
+            Some('[') => self.array().map(Some),
+            Some('"') => self.string().map(|string| Some(Value::String(string))),
+            Some('-' | '0'..='9') => self.number().map(Some),
+            Some(letter) if letter.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(letter) = self.peek().filter(char::is_ascii_alphabetic) {
+                    word.push(letter);
+                    self.next();
+                }
+                match word.as_str() {
+                    "true" => Ok(Some(Value::Boolean(true))),
+                    "false" => Ok(Some(Value::Boolean(false))),
+                    "null" => Ok(None),
+                    _ => Err(self.error(&format!("unexpected '{}'", word))),
+                }
+            }
+            Some(found) => Err(self.error(&format!("unexpected '{}'", found))),
+            None => Err(self.error("unexpected end of the file")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ConfigError> {
+        self.expect('{')?;
+        let mut entries = BTreeMap::new();
+        self.whitespace();
+        if self.peek() == Some('}') {
+            self.next();
+            return Ok(Value::Table(entries));
+        }
+        loop {
+            self.whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a quoted key"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            if let Some(value) = self.value()? {
+                if entries.insert(key.clone(), value).is_some() {
+                    return Err(self.error(&format!("duplicate key '{}'", key)));
+                }
+            }
+            self.whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Table(entries)),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ConfigError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(']') {
+            self.next();
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?.ok_or_else(|| self.error("null in an array"))?);
+            self.whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ConfigError> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('u') => string.push(self.unicode_escape()?),
+                    Some(escaped @ ('"' | '\\' | '/')) => string.push(escaped),
+                    _ => return Err(self.error("invalid escape in a string")),
+                },
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(other) => string.push(other),
+            }
+        }
+    }
+
+    // \uXXXX, with a surrogate pair taking two of them.
+    fn unicode_escape(&mut self) -> Result<char, ConfigError> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if self.next() != Some('\\') || self.next() != Some('u') {
+                return Err(self.error("unpaired surrogate in a string"));
+            }
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("unpaired surrogate in a string"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid \\u escape in a string"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ConfigError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next().and_then(|digit| digit.to_digit(16)).ok_or_else(|| self.error("invalid \\u escape in a string"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Value, ConfigError> {
+        let mut number = String::new();
+        while let Some(digit) = self.peek().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+            number.push(digit);
+            self.next();
+        }
+        number.parse().map(Value::Integer).map_err(|_| self.error(&format!("expected a whole number, found '{}'", number)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(source: &str) -> BTreeMap<String, Value> {
+        match parse(source) {
+            Ok(Value::Table(root)) => root,
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    #[test]
+    fn reads_objects_as_tables() {
+        let root = table("{\n  \"server\": {\"port\": 23, \"tls\": false},\n  \"backend\": [{\"name\": \"a\"}, {\"name\": \"b\"}]\n}\n");
+        assert_eq!(root.get("server"), Some(&Value::Table(BTreeMap::from([
+            (String::from("port"), Value::Integer(23)),
+            (String::from("tls"), Value::Boolean(false)),
+        ]))));
+        let Some(Value::Array(backends)) = root.get("backend") else { panic!("no backends") };
+        assert_eq!(backends[1], Value::Table(BTreeMap::from([(String::from("name"), string("b"))])));
+        assert_eq!(table("{}"), BTreeMap::new());
+    }
+
+    #[test]
+    fn leaves_out_nulls() {
+        let root = table("{\"a\": null, \"b\": [], \"c\": -7}");
+        assert_eq!(root.get("a"), None);
+        assert_eq!(root.get("b"), Some(&Value::Array(Vec::new())));
+        assert_eq!(root.get("c"), Some(&Value::Integer(-7)));
+    }
+
+    #[test]
+    fn reads_escapes() {
+        let root = table(r#"{"s": "a\"b\\c\/d\n\t\u00e9\ud83d\ude00"}"#);
+        assert_eq!(root.get("s"), Some(&string("a\"b\\c/d\n\t\u{e9}\u{1f600}")));
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        for (source, line) in [("[1]", 1), ("{\"a\": 1}\nx", 2), ("{\n\"a\": 1.5}", 2), ("{\"a\": 1, \"a\": 2}", 1),
+                               ("{\n\"a\": [1, null]}", 2), ("{\"a\": \"open\n\"}", 2), ("{\"a\": \"\\ud800\"}", 1),
+                               ("{a: 1}", 1), ("{\"a\": 1,\n\n", 3), ("{\"a\": yes}", 1)] {
+            match parse(source) {
+                Err(ConfigError::Parse { line: found, .. }) => assert_eq!(found, line, "{:?}", source),
+                other => panic!("{:?} parsed as {:?}", source, other),
+            }
+        }
+    }
+}
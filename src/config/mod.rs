@@ -0,0 +1,2193 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::bans::Offense;
+use crate::cidr::Cidr;
+use crate::codec;
+use crate::http::Url;
+
+pub mod edit;
+mod env;
+mod include;
+mod json;
+pub mod schema;
+mod toml;
+mod yaml;
+
+pub const DEFAULT_CONFIG_PATH: &str = "triserver.toml";
+
+// What a config file is written in, by its extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Toml,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Toml => "TOML",
+            Format::Json => "JSON",
+            Format::Yaml => "YAML",
+        }
+    }
+}
+
+// A config file's top-level table, parsed as whatever its extension says.
+// Also adds each table and key the schema doesn't know to `unknown`, with
+// the file and, for TOML, the line.
+fn read_table(path: &Path, unknown: &mut Vec<String>) -> Result<BTreeMap<String, Value>, ConfigError> {
+    let source = fs::read_to_string(path).map_err(|error| ConfigError::Io { path: path.to_path_buf(), error })?;
+    let (value, lines) = match Format::of(path) {
+        Format::Toml => toml::parse_located(&source)?,
+        Format::Json => (json::parse(&source)?, toml::Lines::new()),
+        Format::Yaml => (yaml::parse(&source)?, toml::Lines::new()),
+    };
+    let Value::Table(root) = value else {
+        unreachable!("the parsers always return a table");
+    };
+    let mut found: Vec<_> = schema::unknown(&root).into_iter().map(|(located, message)| {
+        // An inline table's keys have their table's line.
+        let line = std::iter::successors(Some(located.as_str()), |path| path.rsplit_once('.').map(|(parent, _)| parent))
+            .find_map(|path| lines.get(path).copied());
+        (line, message)
+    }).collect();
+    found.sort_by_key(|(line, _)| *line);
+    for (line, message) in found {
+        unknown.push(match line {
+            Some(line) => format!("{} line {}: {}", path.display(), line, message),
+            None => format!("{}: {}", path.display(), message),
+        });
+    }
+    Ok(root)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Table(_) => "table",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { line: usize, message: String },
+    Invalid { key: String, message: String },
+    // Something wrong in a file the config file includes.
+    Included { path: PathBuf, error: Box<ConfigError> },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+            ConfigError::Included { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub backends: Vec<BackendConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub pools: Vec<PoolConfig>,
+    pub users: Option<UserStoreConfig>,
+    pub resume: Option<ResumeConfig>,
+    pub honeypot: Option<HoneypotConfig>,
+    pub plugins: Option<PluginsConfig>,
+    pub autoban: Option<AutobanConfig>,
+    pub admin: Option<AdminConfig>,
+    pub hooks: Option<HooksConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub chat: Option<ChatConfig>,
+    pub http: Option<HttpConfig>,
+    pub metrics_push: Option<MetricsPushConfig>,
+    pub daily_stats: Option<DailyStatsConfig>,
+    pub port_mapping: Option<PortMappingConfig>,
+    pub daemon: Option<DaemonConfig>,
+    pub workers: Option<WorkersConfig>,
+    pub chaos: Option<ChaosConfig>,
+    pub tls: Option<TlsConfig>,
+    pub finger: Option<FingerConfig>,
+    pub flood: Option<FloodConfig>,
+    pub negotiation: Option<NegotiationConfig>,
+    pub multisession: Option<MultisessionConfig>,
+    pub escape: Option<EscapeConfig>,
+    pub petscii: Option<CharsetConfig>,
+    pub atascii: Option<CharsetConfig>,
+    pub rip: Option<RipConfig>,
+    pub syncterm: Option<SynctermConfig>,
+    pub dropfile: Option<DropFileConfig>,
+    pub asn: Option<AsnConfig>,
+    pub access: Option<AccessConfig>,
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    pub log: LogConfig,
+    // The file this was loaded from, if any.
+    pub source: Option<PathBuf>,
+    // The files it includes, in the order they were read.
+    pub includes: Vec<PathBuf>,
+    // Tables and keys in those files that nothing reads, as warnings with
+    // where they are.
+    pub unknown_keys: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // None binds to the primary local IP address, as before the config file existed.
+    pub address: Option<String>,
+    pub port: u16,
+    pub duplicate_ip: DuplicatePolicy,
+    // Account to switch to once the listeners are bound, when started as root.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    // Where SIGUSR1 snapshots are appended; None writes them to the log.
+    pub snapshot_file: Option<PathBuf>,
+    // Log every telnet command either side of each session sends.
+    pub trace_negotiation: bool,
+    // This server's name for SNDLOC's {node}; None uses the host name.
+    pub node_name: Option<String>,
+    // Callers who type nothing for this long are warned, then disconnected.
+    pub idle_timeout: Option<Duration>,
+    // How long a write to a caller who takes nothing may wait before the
+    // session is ended.
+    pub write_timeout: Duration,
+    // KiB of output a caller may fall behind by before being disconnected.
+    pub pending_output: usize,
+    // Longest a single call may last, on top of any daily limit from [users].
+    pub session_time_limit: Option<Duration>,
+    // Minutes before a time limit runs out at which the caller is told.
+    pub time_warnings: Vec<u64>,
+    // Shown to each caller on connecting, with its placeholders filled in.
+    pub motd_file: Option<PathBuf>,
+    // Where the caller count is kept so it carries on after a restart.
+    pub callers_file: Option<PathBuf>,
+    // Ask each caller's terminal what it can do before dialing the backend.
+    pub probe_terminal: bool,
+}
+
+// What to do when a caller connects from an address that already has a session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    Allow,
+    Reject,
+    // Disconnect the existing session, e.g. one left behind by a crashed client.
+    Kick,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "allow" => Ok(DuplicatePolicy::Allow),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "kick" => Ok(DuplicatePolicy::Kick),
+            _ => Err(format!("expected one of allow, reject, kick; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BackendConfig {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    // How to answer particular telnet options, by option code. Options not
+    // listed get the built-in answers.
+    pub options: BTreeMap<u8, OptionPolicy>,
+    pub output: OutputFilter,
+    pub input: InputFilter,
+    // Sent when the backend asks for SNDLOC, with {ip} and {port} (the
+    // caller's), {node} and {backend}. Refusing SNDLOC in options sends nothing.
+    pub sndloc: String,
+    // Typed to the backend when the caller drops, so a node isn't left at a
+    // prompt until its own timeout, e.g. "\r\r/G\rY\r".
+    pub logout: Option<String>,
+    // How long to keep re-dialing the backend if it drops mid-session, with
+    // the caller kept on the line. None hangs up at once.
+    pub redial_window: Option<Duration>,
+    // False relays the backend's bytes as they are, for plain TCP services
+    // that IAC interpretation would corrupt.
+    pub telnet: bool,
+    // A local serial device to use instead of dialing host and port.
+    pub serial: Option<SerialConfig>,
+    // A Unix domain socket (a named pipe on Windows) to connect to instead of
+    // dialing host and port.
+    pub socket: Option<PathBuf>,
+    // Log in to host and port over SSH instead of telnet.
+    pub ssh: Option<SshConfig>,
+    // Log in to host and port with rlogin instead of telnet.
+    pub rlogin: Option<RloginConfig>,
+}
+
+impl BackendConfig {
+    pub fn option_policy(&self, option: u8) -> Option<OptionPolicy> {
+        self.options.get(&option).copied()
+    }
+
+    // Raw, serial, SSH and rlogin backends are relayed byte for byte, with no
+    // telnet commands either way.
+    pub fn speaks_telnet(&self) -> bool {
+        self.telnet && self.serial.is_none() && self.ssh.is_none() && self.rlogin.is_none()
+    }
+
+    // Only a TCP connection or a Unix domain socket can be told the caller has
+    // stopped sending and still be read from.
+    pub fn can_half_close(&self) -> bool {
+        self.serial.is_none() && self.ssh.is_none() && (self.socket.is_none() || cfg!(unix))
+    }
+
+    // The keys the admin interface can set while the server runs. The
+    // options, serial, ssh and rlogin tables are only set in the config file.
+    pub const SETTINGS: [&'static str; 9] = ["host", "port", "socket", "telnet", "output", "input", "sndloc", "logout",
+                                             "redial_window"];
+
+    // The settable keys as `key = value` TOML, for changing some of them.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        let mut settings = vec![(String::from("host"), toml::quote(&self.host))];
+        settings.push((String::from("port"), self.port.to_string()));
+        if let Some(socket) = &self.socket {
+            settings.push((String::from("socket"), toml::quote(&socket.display().to_string())));
+        }
+        settings.push((String::from("telnet"), self.telnet.to_string()));
+        settings.push((String::from("output"), toml::quote(self.output.name())));
+        settings.push((String::from("input"), toml::quote(self.input.name())));
+        settings.push((String::from("sndloc"), toml::quote(&self.sndloc)));
+        if let Some(logout) = &self.logout {
+            settings.push((String::from("logout"), toml::quote(logout)));
+        }
+        if let Some(window) = self.redial_window {
+            settings.push((String::from("redial_window"), window.as_secs().to_string()));
+        }
+        settings
+    }
+
+    // Turns a value typed at the admin interface into TOML for `key`. Strings
+    // may be given bare, or quoted to use escapes such as \r.
+    pub fn setting(key: &str, value: &str) -> Result<String, String> {
+        match key {
+            "port" | "redial_window" => value.parse::<u64>().map(|number| number.to_string())
+                .map_err(|_| format!("{}: expected a number, found '{}'", key, value)),
+            "telnet" => value.parse::<bool>().map(|flag| flag.to_string())
+                .map_err(|_| format!("{}: expected true or false, found '{}'", key, value)),
+            _ if BackendConfig::SETTINGS.contains(&key) && value.starts_with('"') => Ok(value.to_string()),
+            _ if BackendConfig::SETTINGS.contains(&key) => Ok(toml::quote(value)),
+            _ => Err(format!("unknown setting '{}'; expected one of {}", key, BackendConfig::SETTINGS.join(", "))),
+        }
+    }
+
+    // Where the backend is, for logs and reports.
+    pub fn address(&self) -> String {
+        match (&self.serial, &self.socket) {
+            (Some(serial), _) => serial.device.display().to_string(),
+            (_, Some(socket)) => socket.display().to_string(),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+// A backend on a local serial port, such as a BBS on real hardware or a retro
+// machine, opened for each caller. A port takes one caller at a time.
+#[derive(Clone, Debug)]
+pub struct SerialConfig {
+    pub device: PathBuf,
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub flow_control: FlowControl,
+}
+
+impl SerialConfig {
+    pub const BAUD_RATES: [u32; 14] = [300, 600, 1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1000000];
+}
+
+// A backend logged in to over SSH, with a PTY requested on the remote end.
+// When the identity isn't taken, the caller is asked for the password.
+#[derive(Clone, Debug)]
+pub struct SshConfig {
+    pub user: Option<String>,
+    pub identity: Option<PathBuf>,
+    pub known_hosts: Option<PathBuf>,
+    pub host_key_checking: HostKeyChecking,
+    // Run instead of a login shell.
+    pub command: Option<String>,
+    // TERM for the remote PTY.
+    pub terminal: String,
+}
+
+// A backend reached with rlogin (RFC 1282), as some BBSes prefer for door
+// connections. The user names take {user}, the caller's triserver user name,
+// empty for callers who didn't log in.
+#[derive(Clone, Debug)]
+pub struct RloginConfig {
+    pub local_user: String,
+    pub remote_user: String,
+    // Terminal type and speed, e.g. "ansi/38400".
+    pub terminal: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostKeyChecking {
+    // Only hosts already in known_hosts.
+    Strict,
+    // Unknown hosts are added on first connection; changed keys are refused.
+    AcceptNew,
+    Off,
+}
+
+impl FromStr for HostKeyChecking {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "strict" => Ok(HostKeyChecking::Strict),
+            "accept-new" => Ok(HostKeyChecking::AcceptNew),
+            "off" => Ok(HostKeyChecking::Off),
+            _ => Err(format!("expected one of strict, accept-new, off; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl FromStr for Parity {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Parity::None),
+            "even" => Ok(Parity::Even),
+            "odd" => Ok(Parity::Odd),
+            _ => Err(format!("expected one of none, even, odd; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowControl {
+    None,
+    // RTS/CTS.
+    Hardware,
+    // XON/XOFF.
+    Software,
+}
+
+impl FromStr for FlowControl {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(FlowControl::None),
+            "hardware" => Ok(FlowControl::Hardware),
+            "software" => Ok(FlowControl::Software),
+            _ => Err(format!("expected one of none, hardware, software; found '{}'", value)),
+        }
+    }
+}
+
+// Sends callers whose terminal reports a matching type or probes as a given
+// class, or who asked the TLS listener for a matching host name, to a
+// backend. Routes are tried in order; callers none match go to the default
+// backend.
+#[derive(Clone, Debug)]
+pub struct RouteConfig {
+    // Matched anywhere in the terminal type, ignoring case. None matches every
+    // caller, including those that report no type.
+    pub terminal: Option<String>,
+    // Matched against what the terminal probe found. None matches every caller.
+    pub terminal_class: Option<TerminalClass>,
+    // Matched against the whole SNI host name, ignoring case. None matches
+    // every caller, including plain telnet ones.
+    pub server_name: Option<String>,
+    pub backend: String,
+}
+
+impl RouteConfig {
+    pub fn matches(&self, terminal: Option<&str>, terminal_class: Option<TerminalClass>, server_name: Option<&str>) -> bool {
+        let server_name_matches = match (&self.server_name, server_name) {
+            (None, _) => true,
+            (Some(pattern), Some(server_name)) => pattern.eq_ignore_ascii_case(server_name),
+            (Some(_), None) => false,
+        };
+        let terminal_matches = match (&self.terminal, terminal) {
+            (None, _) => true,
+            (Some(pattern), Some(terminal)) => terminal.to_ascii_lowercase().contains(&pattern.to_ascii_lowercase()),
+            (Some(_), None) => false,
+        };
+        let class_matches = self.terminal_class.is_none() || self.terminal_class == terminal_class;
+        server_name_matches && terminal_matches && class_matches
+    }
+}
+
+// What the terminal probe made of a caller: no ANSI at all, ANSI showing
+// CP437, or ANSI showing UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TerminalClass {
+    Plain,
+    Ansi,
+    Utf8,
+}
+
+impl TerminalClass {
+    pub fn name(self) -> &'static str {
+        match self {
+            TerminalClass::Plain => "plain",
+            TerminalClass::Ansi => "ansi",
+            TerminalClass::Utf8 => "utf8",
+        }
+    }
+}
+
+impl FromStr for TerminalClass {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [TerminalClass::Plain, TerminalClass::Ansi, TerminalClass::Utf8]
+            .into_iter()
+            .find(|class| class.name() == value)
+            .ok_or_else(|| format!("expected one of plain, ansi, utf8; found '{}'", value))
+    }
+}
+
+// Backends that share callers between them, named in place of a backend by a
+// route or a user's mapping. Callers are shared out in proportion to the
+// members' weights, passing over members whose health probe failed.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub name: String,
+    pub policy: PoolPolicy,
+    pub members: Vec<PoolMember>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PoolPolicy {
+    // Each new caller goes to the next member in turn.
+    RoundRobin,
+    // A caller's address always picks the same member while it is up, so
+    // returning callers find the node-local state they left.
+    IpHash,
+}
+
+impl PoolPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PoolPolicy::RoundRobin => "round-robin",
+            PoolPolicy::IpHash => "ip-hash",
+        }
+    }
+}
+
+impl FromStr for PoolPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(PoolPolicy::RoundRobin),
+            "ip-hash" => Ok(PoolPolicy::IpHash),
+            _ => Err(format!("expected one of round-robin, ip-hash; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolMember {
+    pub backend: String,
+    pub weight: u64,
+}
+
+// What is done to a backend's output before the caller sees it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFilter {
+    // Passed through untouched.
+    Raw,
+    // Checked as UTF-8: invalid sequences are replaced and C1 controls dropped.
+    Utf8,
+    // As Utf8, with everything outside ASCII transliterated.
+    Ascii,
+    // Converted from CP437 to UTF-8.
+    Cp437ToUtf8,
+    // Converted from CP437 to ASCII, art and all.
+    Cp437ToAscii,
+    // Converted from CP437 to UTF-8 for callers whose terminal shows UTF-8,
+    // to ASCII for those the probe found plain, and passed through for the
+    // rest.
+    Cp437Auto,
+}
+
+impl OutputFilter {
+    pub const ALL: [OutputFilter; 6] = [OutputFilter::Raw, OutputFilter::Utf8, OutputFilter::Ascii, OutputFilter::Cp437ToUtf8,
+                                        OutputFilter::Cp437ToAscii, OutputFilter::Cp437Auto];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFilter::Raw => "raw",
+            OutputFilter::Utf8 => "utf8",
+            OutputFilter::Ascii => "ascii",
+            OutputFilter::Cp437ToUtf8 => "cp437-to-utf8",
+            OutputFilter::Cp437ToAscii => "cp437-to-ascii",
+            OutputFilter::Cp437Auto => "cp437-auto",
+        }
+    }
+}
+
+impl FromStr for OutputFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        OutputFilter::ALL
+            .into_iter()
+            .find(|filter| filter.name() == value)
+            .ok_or_else(|| format!("expected one of raw, utf8, ascii, cp437-to-utf8, cp437-to-ascii, cp437-auto; found '{}'", value))
+    }
+}
+
+// What is done to control characters a caller types, other than CR, LF,
+// backspace and delete, before the backend sees them. Telnet commands are
+// left alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFilter {
+    Pass,
+    Strip,
+    // Sent as caret notation, so ESC arrives as the two characters "^[".
+    Escape,
+}
+
+impl InputFilter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputFilter::Pass => "pass",
+            InputFilter::Strip => "strip",
+            InputFilter::Escape => "escape",
+        }
+    }
+}
+
+impl FromStr for InputFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pass" => Ok(InputFilter::Pass),
+            "strip" => Ok(InputFilter::Strip),
+            "escape" => Ok(InputFilter::Escape),
+            _ => Err(format!("expected one of pass, strip, escape; found '{}'", value)),
+        }
+    }
+}
+
+// The answer to a backend asking for a telnet option with WILL or DO.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptionPolicy {
+    Accept,
+    Refuse,
+    // Accept, and ask for the option as soon as the backend is connected
+    // rather than waiting to be asked.
+    Force,
+}
+
+impl FromStr for OptionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "accept" => Ok(OptionPolicy::Accept),
+            "refuse" => Ok(OptionPolicy::Refuse),
+            "force" => Ok(OptionPolicy::Force),
+            _ => Err(format!("expected one of accept, refuse, force; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UserStoreConfig {
+    pub database: PathBuf,
+    // Minutes per day for users without their own limit. None means unlimited.
+    pub default_time_limit: Option<u64>,
+    pub max_login_attempts: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResumeConfig {
+    // Seconds a dropped session's backend is held open for the caller to return.
+    pub grace_period: u64,
+    // Seconds new callers are given to enter a resume code.
+    pub prompt_timeout: u64,
+    // KiB of recent backend output replayed to a resuming client.
+    pub replay_buffer: u64,
+}
+
+// Callers allowed or refused by the autonomous system (network operator)
+// their address belongs to, looked up in `database`. Addresses it doesn't
+// cover, such as private ones, are always let in.
+#[derive(Clone, Debug)]
+pub struct AsnConfig {
+    pub database: PathBuf,
+    // When not empty, only callers from these are let in.
+    pub allow: Vec<u32>,
+    pub deny: Vec<u32>,
+}
+
+impl AsnConfig {
+    pub fn permits(&self, asn: Option<u32>) -> bool {
+        match asn {
+            Some(asn) => !self.deny.contains(&asn) && (self.allow.is_empty() || self.allow.contains(&asn)),
+            None => true,
+        }
+    }
+}
+
+// Callers refused, or the only ones let in, by address, listed in files that
+// are read again whenever they change.
+#[derive(Clone, Debug)]
+pub struct AccessConfig {
+    pub ban_file: Option<PathBuf>,
+    // When it lists anyone, only callers from these are let in.
+    pub allow_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct HoneypotConfig {
+    // Callers from these blocks get the fake backend instead of a real one.
+    pub sources: Vec<Cidr>,
+    pub log: PathBuf,
+    pub banner: String,
+}
+
+impl HoneypotConfig {
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        self.sources.iter().any(|source| source.contains(ip))
+    }
+}
+
+// WebAssembly modules that see, and may change or end, every session's
+// traffic: each *.wasm file in `directory`, in name order.
+#[derive(Clone, Debug)]
+pub struct PluginsConfig {
+    pub directory: PathBuf,
+    // Instructions a plugin may run on each call before it is stopped.
+    pub fuel: u64,
+    // KiB of memory each plugin may have for each session.
+    pub memory: u64,
+}
+
+// Thresholds are "this many offenses within the window"; zero disables that offense.
+#[derive(Clone, Debug)]
+pub struct AutobanConfig {
+    pub reconnects: u32,
+    pub reconnect_window: Duration,
+    pub failed_logins: u32,
+    pub failed_login_window: Duration,
+    pub negotiation_floods: u32,
+    pub negotiation_flood_window: Duration,
+    // Telnet commands per second from a client that count as a flood.
+    pub negotiation_rate: u32,
+    // First ban length; each further ban of the same address doubles it.
+    pub ban_duration: Duration,
+    pub max_ban_duration: Duration,
+}
+
+impl AutobanConfig {
+    pub fn disabled() -> Self {
+        Self {
+            reconnects: 0,
+            failed_logins: 0,
+            negotiation_floods: 0,
+            ..AutobanConfig::default()
+        }
+    }
+
+    pub fn threshold(&self, offense: Offense) -> (u32, Duration) {
+        match offense {
+            Offense::Reconnect => (self.reconnects, self.reconnect_window),
+            Offense::FailedLogin => (self.failed_logins, self.failed_login_window),
+            Offense::NegotiationFlood => (self.negotiation_floods, self.negotiation_flood_window),
+        }
+    }
+
+    pub fn longest_window(&self) -> Duration {
+        self.reconnect_window.max(self.failed_login_window).max(self.negotiation_flood_window)
+    }
+}
+
+impl Default for AutobanConfig {
+    fn default() -> Self {
+        Self {
+            reconnects: 10,
+            reconnect_window: Duration::from_secs(60),
+            failed_logins: 5,
+            failed_login_window: Duration::from_secs(300),
+            negotiation_floods: 1,
+            negotiation_flood_window: Duration::from_secs(3600),
+            negotiation_rate: 100,
+            ban_duration: Duration::from_secs(300),
+            max_ban_duration: Duration::from_secs(86_400),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+    pub address: String,
+    // When set, admin connections must send "auth <password>" first.
+    pub password: Option<String>,
+    // Where notes about caller addresses are kept.
+    pub notes_file: PathBuf,
+    // Write backends added, changed or removed at runtime back to the config
+    // file, so they outlast a restart.
+    pub save_backends: bool,
+}
+
+// Shell commands run when sessions start and end.
+#[derive(Clone, Debug)]
+pub struct HooksConfig {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: Url,
+    // Signs each payload with HMAC-SHA256, sent in X-TriServer-Signature.
+    pub secret: Option<String>,
+    pub events: Vec<WebhookEvent>,
+    // Further attempts after a failed delivery, with doubling delays.
+    pub retries: u32,
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebhookEvent {
+    Connect,
+    Disconnect,
+    Ban,
+    // Each day's [daily_stats] summary.
+    Daily,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 4] = [WebhookEvent::Connect, WebhookEvent::Disconnect, WebhookEvent::Ban, WebhookEvent::Daily];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Connect => "connect",
+            WebhookEvent::Disconnect => "disconnect",
+            WebhookEvent::Ban => "ban",
+            WebhookEvent::Daily => "daily",
+        }
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        WebhookEvent::ALL
+            .into_iter()
+            .find(|event| event.name() == value)
+            .ok_or_else(|| format!("expected one of connect, disconnect, ban, daily; found '{}'", value))
+    }
+}
+
+// Human-readable notices posted to a Discord or Slack incoming webhook.
+#[derive(Clone, Debug)]
+pub struct ChatConfig {
+    pub service: ChatService,
+    pub url: Url,
+    // Templates with {host}, {backend}, {user}, {duration} and {reason}
+    // placeholders; events without a template are not posted.
+    pub connect_message: Option<String>,
+    pub disconnect_message: Option<String>,
+    pub ban_message: Option<String>,
+    // Messages per minute; anything over is dropped.
+    pub rate_limit: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChatService {
+    Discord,
+    Slack,
+}
+
+impl FromStr for ChatService {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "discord" => Ok(ChatService::Discord),
+            "slack" => Ok(ChatService::Slack),
+            _ => Err(format!("expected one of discord, slack; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    pub address: String,
+    // How often each backend is probed for the readiness check.
+    pub backend_check_interval: Duration,
+}
+
+// The /metrics figures sent out on a timer, for a server that can't be
+// scraped.
+#[derive(Clone, Debug)]
+pub struct MetricsPushConfig {
+    pub url: Url,
+    pub format: PushFormat,
+    pub interval: Duration,
+    pub timeout: Duration,
+    // The job and instance labels the figures are pushed under; the
+    // instance defaults to server.node_name, or else the host name.
+    pub job: String,
+    pub instance: Option<String>,
+    // Sent as "Authorization: Bearer ...".
+    pub bearer_token: Option<String>,
+}
+
+// Each day's calls, addresses, peak and bytes, summed up and started again at
+// a local time of day.
+#[derive(Clone, Debug)]
+pub struct DailyStatsConfig {
+    pub rollover: TimeOfDay,
+    // Each day's summary is appended here as a line of JSON.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeOfDay {
+    pub hour: u32,
+    pub minute: u32,
+}
+
+impl TimeOfDay {
+    pub fn seconds(&self) -> u64 {
+        u64::from(self.hour * 3600 + self.minute * 60)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected a 24-hour time such as \"04:30\", found '{}'", value);
+        let (hour, minute) = value.split_once(':').ok_or_else(invalid)?;
+        let hour: u32 = hour.parse().ok().filter(|&hour| hour < 24).ok_or_else(invalid)?;
+        let minute: u32 = minute.parse().ok().filter(|&minute| minute < 60).ok_or_else(invalid)?;
+        Ok(TimeOfDay { hour, minute })
+    }
+}
+
+// Asking the router to forward the telnet port, for a server at home behind
+// NAT.
+#[derive(Clone, Debug)]
+pub struct PortMappingConfig {
+    pub protocol: MappingProtocol,
+    // None is server.port.
+    pub external_port: Option<u16>,
+    // Asked for with each mapping, which is renewed at half of it.
+    pub lifetime: Duration,
+    // The NAT-PMP router; None is the default route's gateway (Linux only).
+    pub gateway: Option<Ipv4Addr>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MappingProtocol {
+    // NAT-PMP, then UPnP if the router doesn't answer it.
+    Auto,
+    NatPmp,
+    Upnp,
+}
+
+impl FromStr for MappingProtocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(MappingProtocol::Auto),
+            "nat-pmp" => Ok(MappingProtocol::NatPmp),
+            "upnp" => Ok(MappingProtocol::Upnp),
+            _ => Err(format!("expected one of auto, nat-pmp, upnp; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PushFormat {
+    // The text format, to a Pushgateway.
+    Pushgateway,
+    // Prometheus remote write, to Prometheus itself or anything that takes it.
+    RemoteWrite,
+}
+
+impl PushFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PushFormat::Pushgateway => "pushgateway",
+            PushFormat::RemoteWrite => "remote_write",
+        }
+    }
+}
+
+impl FromStr for PushFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pushgateway" => Ok(PushFormat::Pushgateway),
+            "remote_write" => Ok(PushFormat::RemoteWrite),
+            _ => Err(format!("expected one of pushgateway, remote_write; found '{}'", value)),
+        }
+    }
+}
+
+// PROXY protocol headers on the telnet listener, from load balancers and
+// tunnels in front of the server.
+#[derive(Clone, Debug)]
+pub struct ProxyProtocolConfig {
+    // Where headers are honoured from; connections from these must send one.
+    pub trusted: Vec<Cidr>,
+    // What to do with a header from anywhere else.
+    pub spoofed: SpoofedHeader,
+}
+
+impl ProxyProtocolConfig {
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|trusted| trusted.contains(ip))
+    }
+}
+
+// The parts of the server whose log lines can be turned up or down apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subsystem {
+    // Telnet commands exchanged with callers and backends.
+    Negotiation,
+    // Sessions: dialing backends, relaying and hanging up.
+    Relay,
+    // The client manager's bookkeeping of who is connected.
+    Manager,
+    Admin,
+    // Everything else: listeners, bans, webhooks, health checks, workers.
+    Server,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [Subsystem::Negotiation, Subsystem::Relay, Subsystem::Manager, Subsystem::Admin, Subsystem::Server];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subsystem::Negotiation => "negotiation",
+            Subsystem::Relay => "relay",
+            Subsystem::Manager => "manager",
+            Subsystem::Admin => "admin",
+            Subsystem::Server => "server",
+        }
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Subsystem::ALL.into_iter().find(|subsystem| subsystem.name() == value)
+            .ok_or_else(|| format!("expected one of negotiation, relay, manager, admin, server; found '{}'", value))
+    }
+}
+
+// Ordered from the fewest lines to the most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("expected one of off, error, warn, info, debug, trace; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    // Lines of text, as they have always been.
+    #[default]
+    Text,
+    // One JSON object per line, for log shippers.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected one of text, json; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogColor {
+    // When stdout is a terminal and NO_COLOR isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for LogColor {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(LogColor::Auto),
+            "always" => Ok(LogColor::Always),
+            "never" => Ok(LogColor::Never),
+            _ => Err(format!("expected one of auto, always, never; found '{}'", value)),
+        }
+    }
+}
+
+// How much each subsystem logs at startup; the admin `log` command changes
+// it from there. Subsystems not listed log at info.
+#[derive(Clone, Debug, Default)]
+pub struct LogConfig {
+    pub levels: BTreeMap<Subsystem, LogLevel>,
+    pub format: LogFormat,
+    pub color: LogColor,
+    // From -q, -v or -vv, for every subsystem in place of the levels above.
+    pub verbosity: Option<LogLevel>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpoofedHeader {
+    // Hang up on the connection.
+    Reject,
+    // Drop the header and carry on with the connection's own address.
+    Ignore,
+}
+
+impl FromStr for SpoofedHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(SpoofedHeader::Reject),
+            "ignore" => Ok(SpoofedHeader::Ignore),
+            _ => Err(format!("expected one of reject, ignore; found '{}'", value)),
+        }
+    }
+}
+
+// Public, read-only list of who is online.
+#[derive(Clone, Debug)]
+pub struct FingerConfig {
+    pub address: String,
+}
+
+// Where `--daemon` writes its PID and output. Without a [daemon] section the
+// defaults are used.
+#[derive(Clone, Debug)]
+pub struct DaemonConfig {
+    pub pid_file: PathBuf,
+    pub log_file: PathBuf,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self { pid_file: PathBuf::from("triserver.pid"), log_file: PathBuf::from("triserver.log") }
+    }
+}
+
+// Runs several copies of the server sharing the telnet port (Linux only).
+#[derive(Clone, Debug)]
+pub struct WorkersConfig {
+    pub count: u32,
+    // Each worker's session and connection counts, one line per worker.
+    pub stats_file: PathBuf,
+}
+
+// Faults injected into relayed traffic, for testing only. The section sets
+// what every session starts with; the admin "chaos" command changes it for one
+// session. Everything defaults to off.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChaosConfig {
+    // Added before each chunk is relayed, in either direction.
+    pub latency: Duration,
+    // Each relayed byte is dropped with a one-in-this chance; zero never drops.
+    pub drop_one_in: u32,
+    // The backend connection is cut this long after the faults are applied.
+    pub disconnect_after: Option<Duration>,
+    // Relayed data is written a few bytes at a time.
+    pub partial_writes: bool,
+}
+
+// Limits on how fast a caller may type (or paste), per session. Zero turns a
+// limit off.
+#[derive(Clone, Debug)]
+pub struct FloodConfig {
+    pub bytes_per_second: u32,
+    // Counted by CR, which ends every line a telnet client sends.
+    pub lines_per_second: u32,
+    pub action: FloodAction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloodAction {
+    // Slow the caller down to the limits.
+    Throttle,
+    // Discard input over the limits and tell the caller why.
+    Warn,
+    Disconnect,
+}
+
+impl FromStr for FloodAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "throttle" => Ok(FloodAction::Throttle),
+            "warn" => Ok(FloodAction::Warn),
+            "disconnect" => Ok(FloodAction::Disconnect),
+            _ => Err(format!("expected one of throttle, warn, disconnect; found '{}'", value)),
+        }
+    }
+}
+
+// Limits on telnet negotiation from either side of a session, which is cut
+// off when one is exceeded. Zero turns a rate off.
+#[derive(Clone, Debug)]
+pub struct NegotiationConfig {
+    // Longest subnegotiation payload accepted, in bytes.
+    pub max_subnegotiation: usize,
+    // Telnet commands per second.
+    pub client_rate: u32,
+    pub backend_rate: u32,
+}
+
+// Lets a caller keep sessions to several backends open at once and switch
+// between them with a hotkey. Output from the sessions in the background is
+// held until the caller switches back.
+#[derive(Clone, Debug)]
+pub struct MultisessionConfig {
+    pub hotkey: ControlKey,
+    pub max_sessions: usize,
+    // KiB of output held per background session; the oldest is dropped past it.
+    pub held_output: usize,
+}
+
+// A key, like telnet's Ctrl-], that brings up a prompt for commands to the
+// proxy itself in the middle of a session.
+#[derive(Clone, Debug)]
+pub struct EscapeConfig {
+    pub key: ControlKey,
+}
+
+// A control key, written in caret notation: "^A" is Ctrl-A.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControlKey(pub u8);
+
+impl FromStr for ControlKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.as_bytes() {
+            [b'^', key @ (b'@'..=b'_' | b'a'..=b'z')] => Ok(ControlKey(key.to_ascii_uppercase() ^ 0x40)),
+            _ => Err(format!("expected a control key such as ^A; found '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for ControlKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "^{}", (self.0 ^ 0x40) as char)
+    }
+}
+
+// Callers on a home computer's own character set, the C64's PETSCII or the
+// Atari's ATASCII, picked out by terminal type or, on a server for them
+// alone, all of them.
+#[derive(Clone, Debug)]
+pub struct CharsetConfig {
+    // Matched anywhere in the terminal type, ignoring case.
+    pub terminals: Vec<String>,
+    pub always: bool,
+}
+
+impl CharsetConfig {
+    fn matches(&self, terminal: Option<&str>) -> bool {
+        let terminal = terminal.map(str::to_ascii_lowercase);
+        self.always || terminal.is_some_and(|terminal| self.terminals.iter().any(|pattern| terminal.contains(&pattern.to_ascii_lowercase())))
+    }
+}
+
+// RIPscrip graphics in backends' output: passed on untouched to callers whose
+// terminal type says they can draw them, and left out for the rest unless
+// strip is off.
+#[derive(Clone, Debug)]
+pub struct RipConfig {
+    // Matched anywhere in the terminal type, ignoring case.
+    pub terminals: Vec<String>,
+    pub strip: bool,
+}
+
+impl RipConfig {
+    pub fn matches(&self, terminal: Option<&str>) -> bool {
+        let terminal = terminal.map(str::to_ascii_lowercase);
+        terminal.is_some_and(|terminal| self.terminals.iter().any(|pattern| terminal.contains(&pattern.to_ascii_lowercase())))
+    }
+}
+
+// Callers on SyncTERM, or another terminal on its CTerm emulation, picked out
+// by terminal type: their extended sequences are passed on untouched both
+// ways, and the backend is told it's talking to SyncTERM.
+#[derive(Clone, Debug)]
+pub struct SynctermConfig {
+    // Matched anywhere in the terminal type, ignoring case.
+    pub terminals: Vec<String>,
+    // Agree to the telnet binary mode SyncTERM asks for.
+    pub binary: bool,
+}
+
+impl SynctermConfig {
+    pub fn matches(&self, terminal: Option<&str>) -> bool {
+        let terminal = terminal.map(str::to_ascii_lowercase);
+        terminal.is_some_and(|terminal| self.terminals.iter().any(|pattern| terminal.contains(&pattern.to_ascii_lowercase())))
+    }
+}
+
+// A door drop file written as each session starts, for a backend that runs a
+// door itself with the proxy in the BBS's place. {node} in the path is the
+// caller's node.
+#[derive(Clone, Debug)]
+pub struct DropFileConfig {
+    pub path: String,
+    pub format: DropFileFormat,
+    // What the door is told the caller's connection runs at.
+    pub baud: u32,
+    pub sysop: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DropFileFormat {
+    DoorSys,
+    Dorinfo,
+}
+
+impl FromStr for DropFileFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "door.sys" => Ok(DropFileFormat::DoorSys),
+            "dorinfo" => Ok(DropFileFormat::Dorinfo),
+            _ => Err(format!("expected door.sys or dorinfo; found '{}'", value)),
+        }
+    }
+}
+
+// What a caller's terminal is translated to and from, in place of the
+// backend's output filter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientCharset {
+    Petscii,
+    Atascii,
+}
+
+impl ClientCharset {
+    pub fn name(self) -> &'static str {
+        match self {
+            ClientCharset::Petscii => "PETSCII",
+            ClientCharset::Atascii => "ATASCII",
+        }
+    }
+}
+
+// Telnet over TLS on a port of its own. Both files are PEM; the certificate
+// file may hold the whole chain. To front several host names with one
+// listener, the certificate has to cover all of them.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub address: String,
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig { address: None, port: 9000, duplicate_ip: DuplicatePolicy::Allow, user: None, group: None,
+                                  snapshot_file: None, trace_negotiation: false, node_name: None, idle_timeout: None,
+                                  write_timeout: Duration::from_secs(30), pending_output: 256,
+                                  session_time_limit: None, time_warnings: vec![30, 10, 1], motd_file: None,
+                                  callers_file: None, probe_terminal: false },
+            backends: vec![BackendConfig {
+                name: String::from("karatepizza"),
+                host: String::from("172.250.225.86"),
+                port: 2727,
+                options: BTreeMap::new(),
+                output: OutputFilter::Raw,
+                input: InputFilter::Pass,
+                sndloc: String::from("{ip}"),
+                logout: None,
+                redial_window: None,
+                telnet: true,
+                serial: None,
+                socket: None,
+                ssh: None,
+                rlogin: None,
+            }],
+            routes: Vec::new(),
+            pools: Vec::new(),
+            users: None,
+            resume: None,
+            honeypot: None,
+            plugins: None,
+            autoban: None,
+            admin: None,
+            hooks: None,
+            webhook: None,
+            chat: None,
+            http: None,
+            metrics_push: None,
+            daily_stats: None,
+            port_mapping: None,
+            daemon: None,
+            workers: None,
+            chaos: None,
+            tls: None,
+            finger: None,
+            flood: None,
+            negotiation: None,
+            multisession: None,
+            escape: None,
+            petscii: None,
+            atascii: None,
+            rip: None,
+            syncterm: None,
+            dropfile: None,
+            asn: None,
+            access: None,
+            proxy_protocol: None,
+            log: LogConfig::default(),
+            source: None,
+            includes: Vec::new(),
+            unknown_keys: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // Loads the given file, or triserver.toml from the working directory if it
+    // exists. Without either the built-in defaults are used. TRISERVER_*
+    // environment variables override either. A file is read as JSON or YAML
+    // by its extension, and as TOML otherwise.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()),
+        };
+        let mut unknown_keys = Vec::new();
+        let mut root = match &path {
+            Some(path) => read_table(path, &mut unknown_keys)?,
+            None => BTreeMap::new(),
+        };
+        let includes = include::expand(&mut root, path.as_deref(), &mut unknown_keys)?;
+        let variables = std::env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+        env::apply(&mut root, variables)?;
+        let mut config = Config::from_table(&Table::new("", &root))?;
+        // Again, now that what it was read from is known.
+        config.source = path;
+        config.includes = includes;
+        config.unknown_keys = unknown_keys;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // A backend from `key = value` TOML settings, as the admin interface adds
+    // them, checked just as one in a config file is.
+    pub fn parse_backend(name: &str, settings: &[(String, String)]) -> Result<BackendConfig, ConfigError> {
+        let mut source = format!("name = {}\n", toml::quote(name));
+        for (key, value) in settings {
+            source.push_str(&format!("{} = {}\n", key, value));
+        }
+        match toml::parse(&source)? {
+            Value::Table(entries) => backend_from_table(&Table::new(&format!("backend.{}", name), &entries)),
+            _ => unreachable!("the parser always returns a table"),
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<Self, ConfigError> {
+        match toml::parse(source)? {
+            Value::Table(root) => Config::from_table(&Table::new("", &root)),
+            _ => unreachable!("the parser always returns a table"),
+        }
+    }
+
+    fn from_table(root: &Table) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+
+        if let Some(server) = root.table("server")? {
+            config.server.address = server.string("address")?;
+            if let Some(port) = server.port("port")? {
+                config.server.port = port;
+            }
+            if let Some(policy) = server.parsed("duplicate_ip")? {
+                config.server.duplicate_ip = policy;
+            }
+            config.server.user = server.string("user")?;
+            config.server.group = server.string("group")?;
+            config.server.snapshot_file = server.string("snapshot_file")?.map(PathBuf::from);
+            config.server.trace_negotiation = server.boolean("trace_negotiation")?.unwrap_or(false);
+            config.server.node_name = server.string("node_name")?;
+            config.server.idle_timeout = server.seconds("idle_timeout")?.filter(|timeout| !timeout.is_zero());
+            if let Some(timeout) = server.seconds("write_timeout")?.filter(|timeout| !timeout.is_zero()) {
+                config.server.write_timeout = timeout;
+            }
+            if let Some(kib) = server.unsigned("pending_output")?.filter(|&kib| kib > 0) {
+                config.server.pending_output = kib as usize;
+            }
+            config.server.session_time_limit = server.unsigned("session_time_limit")?.filter(|&minutes| minutes > 0)
+                .map(|minutes| Duration::from_secs(minutes * 60));
+            if let Some(warnings) = server.unsigned_list("time_warnings")? {
+                config.server.time_warnings = warnings;
+            }
+            config.server.motd_file = server.string("motd_file")?.map(PathBuf::from);
+            config.server.callers_file = server.string("callers_file")?.map(PathBuf::from);
+            config.server.probe_terminal = server.boolean("probe_terminal")?.unwrap_or(false);
+        }
+
+        let backends = root.tables("backend")?;
+        if !backends.is_empty() {
+            config.backends = backends.iter().map(backend_from_table).collect::<Result<_, ConfigError>>()?;
+        }
+        config.routes = root
+            .tables("route")?
+            .iter()
+            .map(|route| {
+                Ok(RouteConfig {
+                    terminal: route.string("terminal")?,
+                    terminal_class: route.parsed("terminal_class")?,
+                    server_name: route.string("server_name")?,
+                    backend: route.required_string("backend")?,
+                })
+            })
+            .collect::<Result<_, ConfigError>>()?;
+        config.pools = root
+            .tables("pool")?
+            .iter()
+            .map(|pool| {
+                Ok(PoolConfig {
+                    name: pool.required_string("name")?,
+                    policy: pool.parsed("policy")?.unwrap_or(PoolPolicy::RoundRobin),
+                    members: pool.tables("member")?
+                        .iter()
+                        .map(|member| {
+                            let weight = member.unsigned("weight")?.unwrap_or(1);
+                            if weight == 0 {
+                                return Err(member.invalid("weight", String::from("must be at least 1")));
+                            }
+                            Ok(PoolMember { backend: member.required_string("backend")?, weight })
+                        })
+                        .collect::<Result<_, ConfigError>>()?,
+                })
+            })
+            .collect::<Result<_, ConfigError>>()?;
+
+        if let Some(users) = root.table("users")? {
+            config.users = Some(UserStoreConfig {
+                database: PathBuf::from(users.string("database")?.unwrap_or_else(|| String::from("triserver.db"))),
+                default_time_limit: users.unsigned("default_time_limit")?,
+                max_login_attempts: users.unsigned("max_login_attempts")?.unwrap_or(3) as u32,
+            });
+        }
+
+        if let Some(resume) = root.table("resume")? {
+            config.resume = Some(ResumeConfig {
+                grace_period: resume.unsigned("grace_period")?.unwrap_or(300),
+                prompt_timeout: resume.unsigned("prompt_timeout")?.unwrap_or(5),
+                replay_buffer: resume.unsigned("replay_buffer")?.unwrap_or(16),
+            });
+        }
+
+        if let Some(honeypot) = root.table("honeypot")? {
+            config.honeypot = Some(HoneypotConfig {
+                sources: honeypot.list("sources")?,
+                log: PathBuf::from(honeypot.string("log")?.unwrap_or_else(|| String::from("honeypot.log"))),
+                banner: honeypot.string("banner")?.unwrap_or_else(|| String::from("BusyBox v1.19.4 built-in shell (ash)")),
+            });
+        }
+
+        if let Some(plugins) = root.table("plugins")? {
+            config.plugins = Some(PluginsConfig {
+                directory: PathBuf::from(plugins.required_string("directory")?),
+                fuel: plugins.unsigned("fuel")?.unwrap_or(1_000_000).max(1),
+                memory: plugins.unsigned("memory")?.unwrap_or(1024),
+            });
+        }
+
+        if let Some(asn) = root.table("asn")? {
+            let numbers = |key| asn.unsigned_list(key).map(|numbers| numbers.unwrap_or_default().into_iter().map(|n| n as u32).collect());
+            config.asn = Some(AsnConfig {
+                database: PathBuf::from(asn.required_string("database")?),
+                allow: numbers("allow")?,
+                deny: numbers("deny")?,
+            });
+        }
+
+        if let Some(access) = root.table("access")? {
+            config.access = Some(AccessConfig {
+                ban_file: access.string("ban_file")?.map(PathBuf::from),
+                allow_file: access.string("allow_file")?.map(PathBuf::from),
+            });
+        }
+
+        if let Some(proxy_protocol) = root.table("proxy_protocol")? {
+            config.proxy_protocol = Some(ProxyProtocolConfig {
+                trusted: proxy_protocol.list("trusted")?,
+                spoofed: proxy_protocol.parsed("spoofed")?.unwrap_or(SpoofedHeader::Reject),
+            });
+        }
+
+        if let Some(log) = root.table("log")? {
+            config.log.format = log.parsed("format")?.unwrap_or_default();
+            config.log.color = log.parsed("color")?.unwrap_or_default();
+            for subsystem in Subsystem::ALL {
+                if let Some(level) = log.parsed(subsystem.name())? {
+                    config.log.levels.insert(subsystem, level);
+                }
+            }
+        }
+
+        if let Some(autoban) = root.table("autoban")? {
+            let defaults = AutobanConfig::default();
+            config.autoban = Some(AutobanConfig {
+                reconnects: autoban.unsigned("reconnects")?.map_or(defaults.reconnects, |n| n as u32),
+                reconnect_window: autoban.seconds("reconnect_window")?.unwrap_or(defaults.reconnect_window),
+                failed_logins: autoban.unsigned("failed_logins")?.map_or(defaults.failed_logins, |n| n as u32),
+                failed_login_window: autoban.seconds("failed_login_window")?.unwrap_or(defaults.failed_login_window),
+                negotiation_floods: autoban.unsigned("negotiation_floods")?.map_or(defaults.negotiation_floods, |n| n as u32),
+                negotiation_flood_window: autoban.seconds("negotiation_flood_window")?.unwrap_or(defaults.negotiation_flood_window),
+                negotiation_rate: autoban.unsigned("negotiation_rate")?.map_or(defaults.negotiation_rate, |n| n as u32),
+                ban_duration: autoban.seconds("ban_duration")?.unwrap_or(defaults.ban_duration),
+                max_ban_duration: autoban.seconds("max_ban_duration")?.unwrap_or(defaults.max_ban_duration),
+            });
+        }
+
+        if let Some(admin) = root.table("admin")? {
+            config.admin = Some(AdminConfig {
+                address: admin.string("address")?.unwrap_or_else(|| String::from("127.0.0.1:9001")),
+                password: admin.string("password")?,
+                notes_file: PathBuf::from(admin.string("notes_file")?.unwrap_or_else(|| String::from("triserver.notes"))),
+                save_backends: admin.boolean("save_backends")?.unwrap_or(false),
+            });
+        }
+
+        if let Some(hooks) = root.table("hooks")? {
+            config.hooks = Some(HooksConfig {
+                on_connect: hooks.string("on_connect")?,
+                on_disconnect: hooks.string("on_disconnect")?,
+            });
+        }
+
+        if let Some(webhook) = root.table("webhook")? {
+            let events = webhook.list("events")?;
+            config.webhook = Some(WebhookConfig {
+                url: webhook.required("url")?,
+                secret: webhook.string("secret")?,
+                events: if events.is_empty() { WebhookEvent::ALL.to_vec() } else { events },
+                retries: webhook.unsigned("retries")?.map_or(3, |n| n as u32),
+                timeout: webhook.seconds("timeout")?.unwrap_or(Duration::from_secs(10)),
+            });
+        }
+
+        if let Some(chat) = root.table("chat")? {
+            config.chat = Some(ChatConfig {
+                service: chat.required("service")?,
+                url: chat.required("url")?,
+                // An empty template turns the default connect notice off.
+                connect_message: Some(chat.string("connect_message")?
+                    .unwrap_or_else(|| String::from("New caller from {host} connected to {backend}")))
+                    .filter(|message| !message.is_empty()),
+                disconnect_message: chat.string("disconnect_message")?,
+                ban_message: chat.string("ban_message")?,
+                rate_limit: chat.unsigned("rate_limit")?.map_or(10, |n| n as u32),
+            });
+        }
+
+        if let Some(http) = root.table("http")? {
+            config.http = Some(HttpConfig {
+                address: http.string("address")?.unwrap_or_else(|| String::from("127.0.0.1:9080")),
+                backend_check_interval: http.seconds("backend_check_interval")?.unwrap_or(Duration::from_secs(60)),
+            });
+        }
+
+        if let Some(push) = root.table("metrics_push")? {
+            if push.unsigned("interval")? == Some(0) {
+                return Err(push.invalid("interval", String::from("must be at least 1")));
+            }
+            config.metrics_push = Some(MetricsPushConfig {
+                url: push.required("url")?,
+                format: push.parsed("format")?.unwrap_or(PushFormat::Pushgateway),
+                interval: push.seconds("interval")?.unwrap_or(Duration::from_secs(15)),
+                timeout: push.seconds("timeout")?.unwrap_or(Duration::from_secs(10)),
+                job: push.string("job")?.unwrap_or_else(|| String::from("triserver")),
+                instance: push.string("instance")?,
+                bearer_token: push.string("bearer_token")?,
+            });
+        }
+
+        if let Some(daily) = root.table("daily_stats")? {
+            config.daily_stats = Some(DailyStatsConfig {
+                rollover: daily.parsed("rollover")?.unwrap_or(TimeOfDay { hour: 0, minute: 0 }),
+                file: daily.string("file")?.map(PathBuf::from),
+            });
+        }
+
+        if let Some(mapping) = root.table("port_mapping")? {
+            if mapping.unsigned("lifetime")?.is_some_and(|lifetime| lifetime < 120) {
+                return Err(mapping.invalid("lifetime", String::from("must be at least 120")));
+            }
+            let gateway = match mapping.string("gateway")? {
+                Some(gateway) => Some(gateway.parse().map_err(|_| mapping.invalid("gateway", format!("expected an IPv4 address, found '{}'", gateway)))?),
+                None => None,
+            };
+            config.port_mapping = Some(PortMappingConfig {
+                protocol: mapping.parsed("protocol")?.unwrap_or(MappingProtocol::Auto),
+                external_port: mapping.port("external_port")?,
+                lifetime: mapping.seconds("lifetime")?.unwrap_or(Duration::from_secs(3600)),
+                gateway,
+            });
+        }
+
+        if let Some(finger) = root.table("finger")? {
+            config.finger = Some(FingerConfig {
+                address: finger.string("address")?.unwrap_or_else(|| String::from("0.0.0.0:79")),
+            });
+        }
+
+        if let Some(flood) = root.table("flood")? {
+            config.flood = Some(FloodConfig {
+                bytes_per_second: flood.unsigned("bytes_per_second")?.map_or(2000, |n| n as u32),
+                lines_per_second: flood.unsigned("lines_per_second")?.map_or(20, |n| n as u32),
+                action: flood.parsed("action")?.unwrap_or(FloodAction::Throttle),
+            });
+        }
+
+        if let Some(negotiation) = root.table("negotiation")? {
+            config.negotiation = Some(NegotiationConfig {
+                max_subnegotiation: negotiation.unsigned("max_subnegotiation")?.map_or(codec::MAX_SUBNEGOTIATION, |n| n as usize),
+                client_rate: negotiation.unsigned("client_rate")?.map_or(100, |n| n as u32),
+                backend_rate: negotiation.unsigned("backend_rate")?.map_or(1000, |n| n as u32),
+            });
+        }
+
+        if let Some(multisession) = root.table("multisession")? {
+            config.multisession = Some(MultisessionConfig {
+                hotkey: multisession.parsed("hotkey")?.unwrap_or(ControlKey(0x01)),
+                max_sessions: multisession.unsigned("max_sessions")?.map_or(4, |n| n as usize).max(1),
+                held_output: multisession.unsigned("held_output")?.map_or(64, |n| n as usize),
+            });
+        }
+
+        if let Some(escape) = root.table("escape")? {
+            config.escape = Some(EscapeConfig { key: escape.parsed("key")?.unwrap_or(ControlKey(0x1d)) });
+        }
+
+        if let Some(petscii) = root.table("petscii")? {
+            config.petscii = Some(CharsetConfig {
+                terminals: petscii.strings("terminals")?.unwrap_or_else(|| {
+                    ["c64", "c128", "commodore", "petscii"].map(String::from).to_vec()
+                }),
+                always: petscii.boolean("always")?.unwrap_or(false),
+            });
+        }
+
+        if let Some(atascii) = root.table("atascii")? {
+            config.atascii = Some(CharsetConfig {
+                terminals: atascii.strings("terminals")?.unwrap_or_else(|| ["atari", "atascii"].map(String::from).to_vec()),
+                always: atascii.boolean("always")?.unwrap_or(false),
+            });
+        }
+
+        if let Some(rip) = root.table("rip")? {
+            config.rip = Some(RipConfig {
+                terminals: rip.strings("terminals")?.unwrap_or_else(|| vec![String::from("rip")]),
+                strip: rip.boolean("strip")?.unwrap_or(true),
+            });
+        }
+
+        if let Some(syncterm) = root.table("syncterm")? {
+            config.syncterm = Some(SynctermConfig {
+                terminals: syncterm.strings("terminals")?.unwrap_or_else(|| ["syncterm", "cterm"].map(String::from).to_vec()),
+                binary: syncterm.boolean("binary")?.unwrap_or(true),
+            });
+        }
+
+        if let Some(dropfile) = root.table("dropfile")? {
+            config.dropfile = Some(DropFileConfig {
+                path: dropfile.required_string("path")?,
+                format: dropfile.parsed("format")?.unwrap_or(DropFileFormat::DoorSys),
+                baud: dropfile.unsigned("baud")?.map_or(38_400, |baud| baud as u32),
+                sysop: dropfile.string("sysop")?.unwrap_or_else(|| String::from("Sysop")),
+            });
+        }
+
+        if let Some(daemon) = root.table("daemon")? {
+            let defaults = DaemonConfig::default();
+            config.daemon = Some(DaemonConfig {
+                pid_file: daemon.string("pid_file")?.map_or(defaults.pid_file, PathBuf::from),
+                log_file: daemon.string("log_file")?.map_or(defaults.log_file, PathBuf::from),
+            });
+        }
+
+        if let Some(workers) = root.table("workers")? {
+            config.workers = Some(WorkersConfig {
+                count: workers.unsigned("count")?.map_or(1, |n| n as u32),
+                stats_file: PathBuf::from(workers.string("stats_file")?.unwrap_or_else(|| String::from("triserver.stats"))),
+            });
+        }
+
+        if let Some(chaos) = root.table("chaos")? {
+            config.chaos = Some(ChaosConfig {
+                latency: Duration::from_millis(chaos.unsigned("latency")?.unwrap_or(0)),
+                drop_one_in: chaos.unsigned("drop_one_in")?.map_or(0, |n| n as u32),
+                disconnect_after: chaos.seconds("disconnect_after")?,
+                partial_writes: chaos.boolean("partial_writes")?.unwrap_or(false),
+            });
+        }
+
+        if let Some(tls) = root.table("tls")? {
+            config.tls = Some(TlsConfig {
+                address: tls.string("address")?.unwrap_or_else(|| String::from("0.0.0.0:992")),
+                certificate: PathBuf::from(tls.required_string("certificate")?),
+                key: PathBuf::from(tls.required_string("key")?),
+            });
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (i, backend) in self.backends.iter().enumerate() {
+            if self.backends[..i].iter().any(|other| other.name == backend.name) {
+                return Err(ConfigError::Invalid {
+                    key: format!("backend.{}", backend.name),
+                    message: String::from("duplicate backend name"),
+                });
+            }
+            let transports = [backend.serial.is_some(), backend.socket.is_some(), backend.ssh.is_some(), backend.rlogin.is_some()];
+            if transports.iter().filter(|&&set| set).count() > 1 {
+                return Err(ConfigError::Invalid {
+                    key: format!("backend.{}", backend.name),
+                    message: String::from("can only have one of serial, socket, ssh and rlogin"),
+                });
+            }
+        }
+        let format = self.source.as_deref().map_or(Format::Toml, Format::of);
+        if self.admin.as_ref().is_some_and(|admin| admin.save_backends) && format != Format::Toml {
+            return Err(ConfigError::Invalid {
+                key: String::from("admin.save_backends"),
+                message: format!("changes can only be saved to a TOML config file, not {}", format.name()),
+            });
+        }
+        if self.server.group.is_some() && self.server.user.is_none() {
+            return Err(ConfigError::Invalid {
+                key: String::from("server.group"),
+                message: String::from("only used together with server.user"),
+            });
+        }
+        for (i, pool) in self.pools.iter().enumerate() {
+            if self.backend(&pool.name).is_some() || self.pools[..i].iter().any(|other| other.name == pool.name) {
+                return Err(ConfigError::Invalid {
+                    key: format!("pool.{}", pool.name),
+                    message: String::from("duplicate backend or pool name"),
+                });
+            }
+            if pool.members.is_empty() {
+                return Err(ConfigError::Invalid {
+                    key: format!("pool.{}", pool.name),
+                    message: String::from("has no members"),
+                });
+            }
+            if let Some(member) = pool.members.iter().find(|member| self.backend(&member.backend).is_none()) {
+                return Err(ConfigError::Invalid {
+                    key: format!("pool.{}.member", pool.name),
+                    message: format!("no backend named '{}'", member.backend),
+                });
+            }
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            if self.backend(&route.backend).is_none() && self.pool(&route.backend).is_none() {
+                return Err(ConfigError::Invalid {
+                    key: format!("route[{}].backend", i),
+                    message: format!("no backend or pool named '{}'", route.backend),
+                });
+            }
+        }
+        // A ban's end has to be somewhere an Instant can reach.
+        if self.autoban.as_ref().is_some_and(|autoban| Instant::now().checked_add(autoban.max_ban_duration).is_none()) {
+            return Err(ConfigError::Invalid {
+                key: String::from("autoban.max_ban_duration"),
+                message: String::from("too long"),
+            });
+        }
+        if let Some(workers) = &self.workers {
+            if workers.count == 0 {
+                return Err(ConfigError::Invalid {
+                    key: String::from("workers.count"),
+                    message: String::from("must be at least 1"),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // The first configured backend is the default for unauthenticated callers
+    // and users without a mapping.
+    pub fn default_backend(&self) -> &BackendConfig {
+        &self.backends[0]
+    }
+
+    // The backend or pool of the first route matching the caller's terminal
+    // type and class and TLS host name.
+    pub fn route(&self, terminal: Option<&str>, terminal_class: Option<TerminalClass>, server_name: Option<&str>) -> Option<&str> {
+        self.routes.iter()
+            .find(|route| route.matches(terminal, terminal_class, server_name))
+            .map(|route| route.backend.as_str())
+    }
+
+    // The character set a caller with this terminal type is translated to, if
+    // not the backend's own.
+    pub fn client_charset(&self, terminal: Option<&str>) -> Option<ClientCharset> {
+        [(&self.petscii, ClientCharset::Petscii), (&self.atascii, ClientCharset::Atascii)]
+            .into_iter()
+            .find(|(charset, _)| charset.as_ref().is_some_and(|charset| charset.matches(terminal)))
+            .map(|(_, charset)| charset)
+    }
+
+    // Whether a caller's terminal type has to be asked for to pick their character set.
+    pub fn charset_by_terminal(&self) -> bool {
+        [&self.petscii, &self.atascii].into_iter().flatten().any(|charset| !charset.always)
+    }
+
+    pub fn backend(&self, name: &str) -> Option<&BackendConfig> {
+        self.backends.iter().find(|backend| backend.name == name)
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&PoolConfig> {
+        self.pools.iter().find(|pool| pool.name == name)
+    }
+}
+
+fn backend_from_table(backend: &Table) -> Result<BackendConfig, ConfigError> {
+    let serial = match backend.table("serial")? {
+        Some(serial) => Some(serial.serial()?),
+        None => None,
+    };
+    let ssh = match backend.table("ssh")? {
+        Some(ssh) => Some(SshConfig {
+            user: ssh.string("user")?,
+            identity: ssh.string("identity")?.map(PathBuf::from),
+            known_hosts: ssh.string("known_hosts")?.map(PathBuf::from),
+            host_key_checking: ssh.parsed("host_key_checking")?.unwrap_or(HostKeyChecking::AcceptNew),
+            command: ssh.string("command")?,
+            terminal: ssh.string("terminal")?.unwrap_or_else(|| String::from("ansi")),
+        }),
+        None => None,
+    };
+    let rlogin = match backend.table("rlogin")? {
+        Some(rlogin) => Some(RloginConfig {
+            local_user: rlogin.string("local_user")?.unwrap_or_else(|| String::from("{user}")),
+            remote_user: rlogin.string("remote_user")?.unwrap_or_else(|| String::from("{user}")),
+            terminal: rlogin.string("terminal")?.unwrap_or_else(|| String::from("ansi/38400")),
+        }),
+        None => None,
+    };
+    let socket = backend.string("socket")?.map(PathBuf::from);
+    let default_port = match (&ssh, &rlogin) {
+        (Some(_), _) => 22,
+        (_, Some(_)) => 513,
+        _ => 23,
+    };
+    Ok(BackendConfig {
+        name: backend.required_string("name")?,
+        // A serial or socket backend has no host to dial.
+        host: match (&serial, &socket) {
+            (None, None) => backend.required_string("host")?,
+            _ => backend.string("host")?.unwrap_or_default(),
+        },
+        port: backend.port("port")?.unwrap_or(default_port),
+        options: match backend.table("options")? {
+            Some(options) => options.option_policies()?,
+            None => BTreeMap::new(),
+        },
+        output: backend.parsed("output")?.unwrap_or(OutputFilter::Raw),
+        input: backend.parsed("input")?.unwrap_or(InputFilter::Pass),
+        sndloc: backend.string("sndloc")?.unwrap_or_else(|| String::from("{ip}")),
+        logout: backend.string("logout")?,
+        redial_window: backend.seconds("redial_window")?.filter(|window| !window.is_zero()),
+        telnet: backend.boolean("telnet")?.unwrap_or(true),
+        serial,
+        socket,
+        ssh,
+        rlogin,
+    })
+}
+
+// Typed accessors over a parsed table that report the full key path on error.
+struct Table<'a> {
+    path: String,
+    entries: &'a BTreeMap<String, Value>,
+}
+
+impl<'a> Table<'a> {
+    fn new(path: &str, entries: &'a BTreeMap<String, Value>) -> Self {
+        Self { path: path.to_string(), entries }
+    }
+
+    fn key(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn invalid(&self, key: &str, message: String) -> ConfigError {
+        ConfigError::Invalid { key: self.key(key), message }
+    }
+
+    fn expected(&self, key: &str, expected: &str, found: &Value) -> ConfigError {
+        self.invalid(key, format!("expected {}, found {}", expected, found.type_name()))
+    }
+
+    fn string(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(other) => Err(self.expected(key, "string", other)),
+        }
+    }
+
+    fn parsed<T: FromStr<Err = String>>(&self, key: &str) -> Result<Option<T>, ConfigError> {
+        match self.string(key)? {
+            Some(value) => value.parse().map(Some).map_err(|message| self.invalid(key, message)),
+            None => Ok(None),
+        }
+    }
+
+    fn required<T: FromStr<Err = String>>(&self, key: &str) -> Result<T, ConfigError> {
+        self.parsed(key)?.ok_or_else(|| self.invalid(key, String::from("missing required key")))
+    }
+
+    fn list<T: FromStr<Err = String>>(&self, key: &str) -> Result<Vec<T>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(value) => value.parse().map_err(|message| self.invalid(key, message)),
+                    other => Err(self.expected(key, "array of strings", other)),
+                })
+                .collect(),
+            Some(other) => Err(self.expected(key, "array of strings", other)),
+        }
+    }
+
+    fn required_string(&self, key: &str) -> Result<String, ConfigError> {
+        self.string(key)?.ok_or_else(|| self.invalid(key, String::from("missing required key")))
+    }
+
+    fn integer(&self, key: &str) -> Result<Option<i64>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(value)) => Ok(Some(*value)),
+            Some(other) => Err(self.expected(key, "integer", other)),
+        }
+    }
+
+    fn boolean(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Boolean(value)) => Ok(Some(*value)),
+            Some(other) => Err(self.expected(key, "boolean", other)),
+        }
+    }
+
+    fn unsigned(&self, key: &str) -> Result<Option<u64>, ConfigError> {
+        match self.integer(key)? {
+            Some(value) if value < 0 => Err(self.invalid(key, format!("must not be negative, found {}", value))),
+            value => Ok(value.map(|value| value as u64)),
+        }
+    }
+
+    fn strings(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(value) => Ok(value.clone()),
+                    other => Err(self.expected(key, "array of strings", other)),
+                })
+                .collect::<Result<_, _>>()
+                .map(Some),
+            Some(other) => Err(self.expected(key, "array of strings", other)),
+        }
+    }
+
+    fn unsigned_list(&self, key: &str) -> Result<Option<Vec<u64>>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::Integer(value) if *value >= 0 => Ok(*value as u64),
+                    Value::Integer(value) => Err(self.invalid(key, format!("must not be negative, found {}", value))),
+                    other => Err(self.expected(key, "array of integers", other)),
+                })
+                .collect::<Result<_, _>>()
+                .map(Some),
+            Some(other) => Err(self.expected(key, "array of integers", other)),
+        }
+    }
+
+    fn seconds(&self, key: &str) -> Result<Option<Duration>, ConfigError> {
+        Ok(self.unsigned(key)?.map(Duration::from_secs))
+    }
+
+    fn port(&self, key: &str) -> Result<Option<u16>, ConfigError> {
+        match self.integer(key)? {
+            Some(value) => match u16::try_from(value) {
+                Ok(port) if port > 0 => Ok(Some(port)),
+                _ => Err(self.invalid(key, format!("invalid port {}", value))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Every key is a telnet option name or number, every value a policy.
+    fn serial(&self) -> Result<SerialConfig, ConfigError> {
+        let baud = self.unsigned("baud")?.unwrap_or(9600);
+        if !SerialConfig::BAUD_RATES.iter().any(|&rate| rate as u64 == baud) {
+            return Err(self.invalid("baud", format!("unsupported baud rate {}", baud)));
+        }
+        let data_bits = self.unsigned("data_bits")?.unwrap_or(8);
+        if !(5..=8).contains(&data_bits) {
+            return Err(self.invalid("data_bits", format!("must be between 5 and 8, found {}", data_bits)));
+        }
+        let stop_bits = self.unsigned("stop_bits")?.unwrap_or(1);
+        if !(1..=2).contains(&stop_bits) {
+            return Err(self.invalid("stop_bits", format!("must be 1 or 2, found {}", stop_bits)));
+        }
+        Ok(SerialConfig {
+            device: PathBuf::from(self.required_string("device")?),
+            baud: baud as u32,
+            data_bits: data_bits as u8,
+            parity: self.parsed("parity")?.unwrap_or(Parity::None),
+            stop_bits: stop_bits as u8,
+            flow_control: self.parsed("flow_control")?.unwrap_or(FlowControl::None),
+        })
+    }
+
+    fn option_policies(&self) -> Result<BTreeMap<u8, OptionPolicy>, ConfigError> {
+        let mut policies = BTreeMap::new();
+        for key in self.entries.keys() {
+            let option = codec::option_code(key).ok_or_else(|| self.invalid(key, String::from("unknown telnet option")))?;
+            policies.insert(option, self.required(key)?);
+        }
+        Ok(policies)
+    }
+
+    fn table(&self, key: &str) -> Result<Option<Table<'a>>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Table(entries)) => Ok(Some(Table::new(&self.key(key), entries))),
+            Some(other) => Err(self.expected(key, "table", other)),
+        }
+    }
+
+    fn tables(&self, key: &str) -> Result<Vec<Table<'a>>, ConfigError> {
+        match self.entries.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| match item {
+                    Value::Table(entries) => Ok(Table::new(&format!("{}[{}]", self.key(key), i), entries)),
+                    other => Err(self.expected(key, "array of tables", other)),
+                })
+                .collect(),
+            Some(other) => Err(self.expected(key, "array of tables", other)),
+        }
+    }
+}
@@ -0,0 +1,478 @@
+// Every table and key the config file takes, with its type, default and what
+// it's for, as `TriServer config schema` prints them and as the example file
+// it writes. The environment overrides find their tables here too. A key
+// read in mod.rs belongs here as well, with the same default.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::Value;
+
+pub struct Section {
+    pub name: &'static str,
+    // A [[table]], which there can be several of.
+    pub repeated: bool,
+    pub description: &'static str,
+    pub keys: &'static [Key],
+    pub sections: &'static [Section],
+}
+
+pub struct Key {
+    pub name: &'static str,
+    pub kind: &'static str,
+    // What it is when left out, in words; empty when it's simply unset.
+    pub default: &'static str,
+    // A value for the example file, as TOML.
+    pub example: &'static str,
+    pub description: &'static str,
+}
+
+const fn key(name: &'static str, kind: &'static str, default: &'static str, example: &'static str,
+             description: &'static str) -> Key {
+    Key { name, kind, default, example, description }
+}
+
+const fn table(name: &'static str, description: &'static str, keys: &'static [Key]) -> Section {
+    Section { name, repeated: false, description, keys, sections: &[] }
+}
+
+const LOG_LEVEL: &str = "off, error, warn, info, debug or trace";
+
+// Keys outside any table, which come before the first one.
+pub const TOP_LEVEL: &[Key] = &[
+    key("include", "array of paths", "none", "[\"backends/*.toml\"]",
+        "More config files read in, relative to this one; * and ? match in file names."),
+];
+
+pub const SECTIONS: &[Section] = &[
+    table("server", "The telnet listener, and what every session shares.", &[
+        key("address", "string", "the primary local IP address", "\"0.0.0.0\"", "Address the telnet listener binds to."),
+        key("port", "port", "9000", "9000", "Port the telnet listener binds to."),
+        key("duplicate_ip", "allow, reject or kick", "allow", "\"allow\"",
+            "What happens when an address that already has a session connects again. Sessions held for resume don't count."),
+        key("user", "string", "", "\"nobody\"", "Account to switch to after binding, when started as root."),
+        key("group", "string", "the user's primary group", "\"nogroup\"", "Group to switch to, together with user."),
+        key("snapshot_file", "path", "the log", "\"triserver.snapshot\"", "Where SIGUSR1 snapshots are appended."),
+        key("trace_negotiation", "boolean", "false", "true", "Log every telnet command, both directions, per session."),
+        key("node_name", "string", "the host name", "\"node1\"", "SNDLOC's {node}, and the instance metrics are pushed as."),
+        key("idle_timeout", "seconds", "none", "900", "Time without typing before a caller is disconnected, warned a minute before."),
+        key("write_timeout", "seconds", "30", "30", "Time a caller may take nothing written to them before the session ends."),
+        key("pending_output", "KiB", "256", "256", "Output a caller may fall behind by before being disconnected."),
+        key("session_time_limit", "minutes", "none", "120", "Time limit per call, on top of any daily [users] limit."),
+        key("time_warnings", "array of minutes", "[30, 10, 1]", "[30, 10, 1]", "Minutes left at which callers are told, with either time limit."),
+        key("motd_file", "path", "none", "\"motd.ans\"",
+            "Shown to callers on connecting, filling in {caller_number}, {node}, {clients_online}, {client_ip} and {time}."),
+        key("callers_file", "path", "none, counting from 1 at each start", "\"triserver.callers\"",
+            "Where {caller_number}'s count is kept across restarts."),
+        key("probe_terminal", "boolean", "false", "true",
+            "Asks each caller's terminal whether it does ANSI and UTF-8 before dialing; [[route]] terminal_class implies it."),
+    ]),
+    Section {
+        name: "backend",
+        repeated: true,
+        description: "A system callers are relayed to. The first one is the default.",
+        keys: &[
+            key("name", "string", "required", "\"karatepizza\"", "What routes, pools, users and the admin interface call it."),
+            key("host", "string", "required, unless socket or serial is set", "\"172.250.225.86\"", "Host to dial."),
+            key("port", "port", "23, or 22 with ssh and 513 with rlogin", "2727", "Port to dial."),
+            key("socket", "path", "", "\"/run/bbs/telnet.sock\"", "A Unix domain socket (a named pipe on Windows) to dial instead."),
+            key("telnet", "boolean", "true", "false", "Whether it speaks telnet; false relays raw bytes, with no IAC handling."),
+            key("output", "raw, utf8, ascii, cp437-to-utf8, cp437-to-ascii or cp437-auto", "raw", "\"utf8\"", "How its output is translated for callers."),
+            key("input", "pass, strip or escape", "pass", "\"strip\"", "What happens to control characters callers type."),
+            key("sndloc", "string", "{ip}", "\"{ip}:{port} via triserver node {node}\"", "The SNDLOC location sent to it."),
+            key("logout", "string", "", "\"\\r\\r/G\\rY\\r\"", "Typed to it when a caller drops, to free the node."),
+            key("redial_window", "seconds", "none", "60", "How long it is re-dialed for when it drops mid-session."),
+        ],
+        sections: &[
+            table("options", "How its telnet option requests are answered, by option name or number.", &[
+                key("TTYPE", "accept, refuse or force", "the built-in answer", "\"refuse\"",
+                    "Any option, such as BINARY, ECHO, SGA, TTYPE, SNDLOC or NAWS: accept agrees, refuse declines, \
+                     force also asks for it once connected."),
+            ]),
+            table("serial", "A local serial port to use instead of host and port.", &[
+                key("device", "path", "required", "\"/dev/ttyUSB0\"", "The serial device."),
+                key("baud", "integer", "9600", "9600", "Line speed."),
+                key("data_bits", "integer", "8", "8", "5 to 8."),
+                key("parity", "none, even or odd", "none", "\"none\"", "Parity."),
+                key("stop_bits", "integer", "1", "1", "1 or 2."),
+                key("flow_control", "none, hardware or software", "none", "\"none\"", "RTS/CTS or XON/XOFF flow control."),
+            ]),
+            table("ssh", "Log in over SSH.", &[
+                key("user", "string", "the server's own user", "\"guest\"", "User to log in as."),
+                key("identity", "path", "", "\"/etc/triserver/id_ed25519\"", "Private key to log in with."),
+                key("known_hosts", "path", "~/.ssh/known_hosts", "\"/etc/triserver/known_hosts\"", "Known hosts file."),
+                key("host_key_checking", "strict, accept-new or off", "accept-new", "\"accept-new\"", "How host keys are checked."),
+                key("command", "string", "a login shell", "\"/usr/local/bin/bbs\"", "Command run on the remote end."),
+                key("terminal", "string", "ansi", "\"ansi\"", "TERM on the remote end."),
+            ]),
+            table("rlogin", "Log in with rlogin.", &[
+                key("local_user", "string", "{user}", "\"{user}\"", "Local user name sent; {user} is the caller's."),
+                key("remote_user", "string", "{user}", "\"{user}\"", "Remote user name sent."),
+                key("terminal", "string", "ansi/38400", "\"ansi/38400\"", "Terminal type and speed sent."),
+            ]),
+        ],
+    },
+    Section {
+        name: "route",
+        repeated: true,
+        description: "Picks a backend or pool by terminal type or class or TLS host name; tried in order.",
+        keys: &[
+            key("terminal", "string", "any", "\"syncterm\"", "Matched anywhere in the caller's terminal type, ignoring case."),
+            key("terminal_class", "plain, ansi or utf8", "any", "\"utf8\"", "What the terminal probe found the caller's terminal to be."),
+            key("server_name", "string", "any", "\"bbs.example.com\"", "Matched against the whole host name a [tls] caller asked for."),
+            key("backend", "string", "required", "\"karatepizza\"", "The backend or pool callers are sent to."),
+        ],
+        sections: &[],
+    },
+    Section {
+        name: "pool",
+        repeated: true,
+        description: "Several nodes of one system; routes and users may name it instead of a backend.",
+        keys: &[
+            key("name", "string", "required", "\"nodes\"", "What routes and users call it."),
+            key("policy", "round-robin or ip-hash", "round-robin", "\"round-robin\"",
+                "round-robin spreads callers by weight; ip-hash sends an address to the same member each time."),
+        ],
+        sections: &[Section {
+            name: "member",
+            repeated: true,
+            description: "A backend in the pool.",
+            keys: &[
+                key("backend", "string", "required", "\"node1\"", "The backend."),
+                key("weight", "integer", "1", "1", "Its share of callers."),
+            ],
+            sections: &[],
+        }],
+    },
+    table("users", "Ask callers for a user name and password before connecting.", &[
+        key("database", "path", "triserver.db", "\"triserver.db\"", "The SQLite user database."),
+        key("default_time_limit", "minutes", "unlimited", "60", "Minutes per day for users without their own limit."),
+        key("max_login_attempts", "integer", "3", "3", "Tries before a caller is hung up on."),
+    ]),
+    table("resume", "Hold a dropped caller's backend session so they can resume it.", &[
+        key("grace_period", "seconds", "300", "300", "How long a session is held."),
+        key("prompt_timeout", "seconds", "5", "5", "Time new callers get to enter a resume code."),
+        key("replay_buffer", "KiB", "16", "16", "Recent output replayed on resuming."),
+    ]),
+    table("honeypot", "Send flagged sources to a fake shell that logs what they type.", &[
+        key("sources", "array of addresses and networks", "none", "[\"198.51.100.0/24\"]", "Who is sent there."),
+        key("log", "path", "honeypot.log", "\"honeypot.log\"", "Where what they type is logged."),
+        key("banner", "string", "BusyBox v1.19.4 built-in shell (ash)", "\"BusyBox v1.19.4 built-in shell (ash)\"",
+            "The shell's greeting."),
+    ]),
+    table("asn", "Let callers in or not by the network their address belongs to.", &[
+        key("database", "path", "required", "\"ip2asn-combined.tsv\"", "ip2asn-combined.tsv from iptoasn.com."),
+        key("allow", "array of integers", "everyone", "[7922, 701]", "Only these networks get in."),
+        key("deny", "array of integers", "none", "[14061, 16276]", "These networks are refused."),
+    ]),
+    table("access", "Refuse callers, or let only some in, by address lists kept in files.", &[
+        key("ban_file", "path", "none", "\"banned.txt\"", "Addresses and networks refused, one to a line."),
+        key("allow_file", "path", "none", "\"allowed.txt\"", "When it lists anyone, only these get in."),
+    ]),
+    table("proxy_protocol", "Take callers' addresses from a PROXY protocol header on the telnet listener.", &[
+        key("trusted", "array of addresses and networks", "none", "[\"10.0.0.5\"]", "Where headers are honoured from; these must send one."),
+        key("spoofed", "reject or ignore", "reject", "\"reject\"", "What happens to a header from anywhere else."),
+    ]),
+    table("plugins", "Run WebAssembly modules that see, and may change or end, every session's traffic.", &[
+        key("directory", "path", "required", "\"plugins\"", "Every *.wasm file in it is loaded, in name order."),
+        key("fuel", "integer", "1000000", "250000", "Instructions a plugin may run on each call before it's stopped."),
+        key("memory", "KiB", "1024", "256", "Memory each plugin may have in each session."),
+    ]),
+    table("log", "How much each subsystem logs, and how.", &[
+        key("negotiation", LOG_LEVEL, "info", "\"info\"", "Telnet commands exchanged with callers and backends."),
+        key("relay", LOG_LEVEL, "info", "\"info\"", "Sessions: dialing, relaying, hanging up."),
+        key("manager", LOG_LEVEL, "info", "\"info\"", "The bookkeeping of who is connected."),
+        key("admin", LOG_LEVEL, "info", "\"info\"", "Admin connections; debug logs each command."),
+        key("server", LOG_LEVEL, "info", "\"info\"", "Everything else: listeners, health checks, hooks."),
+        key("format", "text or json", "text", "\"text\"", "json writes one object per line, for log shippers."),
+        key("color", "auto, always or never", "auto", "\"auto\"", "Colors text lines by level; auto does in a terminal without NO_COLOR."),
+    ]),
+    table("autoban", "Temporary bans for abusive addresses; each repeat ban doubles.", &[
+        key("reconnects", "integer", "10", "10", "Connections within reconnect_window that earn a ban."),
+        key("reconnect_window", "seconds", "60", "60", "Window for reconnects."),
+        key("failed_logins", "integer", "5", "5", "Failed logins or wrong resume codes within failed_login_window that earn a ban."),
+        key("failed_login_window", "seconds", "300", "300", "Window for failed logins."),
+        key("negotiation_floods", "integer", "1", "1", "Negotiation floods within negotiation_flood_window that earn a ban."),
+        key("negotiation_flood_window", "seconds", "3600", "3600", "Window for negotiation floods."),
+        key("negotiation_rate", "integer", "100", "100", "Telnet commands per second before a session is cut off."),
+        key("ban_duration", "seconds", "300", "300", "Length of a first ban."),
+        key("max_ban_duration", "seconds", "86400", "86400", "Longest a repeat ban grows to."),
+    ]),
+    table("admin", "The line-based admin socket.", &[
+        key("address", "string", "127.0.0.1:9001", "\"127.0.0.1:9001\"", "Address it listens on."),
+        key("password", "string", "none", "\"change-me\"", "Asked for before any command."),
+        key("notes_file", "path", "triserver.notes", "\"triserver.notes\"", "Where notes from the note command are kept."),
+        key("save_backends", "boolean", "false", "true", "Write backend and pool changes back to a TOML config file."),
+    ]),
+    table("hooks", "Shell commands run in the background as sessions start and end.", &[
+        key("on_connect", "string", "", "\"./connected.sh\"", "Run when a session starts."),
+        key("on_disconnect", "string", "", "\"./accounting.sh\"", "Run when a session ends."),
+    ]),
+    table("webhook", "POST a JSON payload for each event.", &[
+        key("url", "URL", "required", "\"https://example.com/triserver\"", "Where payloads are posted."),
+        key("secret", "string", "", "\"change-me\"", "Signs each payload with HMAC-SHA256, in X-TriServer-Signature."),
+        key("events", "array of connect, disconnect, ban and daily", "all of them", "[\"connect\", \"disconnect\", \"ban\"]",
+            "The events posted."),
+        key("retries", "integer", "3", "3", "Further attempts after a failure, with doubling delays."),
+        key("timeout", "seconds", "10", "10", "Time allowed for each attempt."),
+    ]),
+    table("chat", "Post notices to a Discord or Slack incoming webhook.", &[
+        key("service", "discord or slack", "required", "\"discord\"", "Which one it is."),
+        key("url", "URL", "required", "\"https://discord.com/api/webhooks/...\"", "The incoming webhook."),
+        key("connect_message", "string", "New caller from {host} connected to {backend}",
+            "\"New caller from {host} connected to {backend}\"", "Posted when a caller connects; empty posts nothing."),
+        key("disconnect_message", "string", "", "\"{user} left {backend} after {duration}\"", "Posted when a caller leaves."),
+        key("ban_message", "string", "", "\"Banned {host} for {duration}: {reason}\"", "Posted when an address is banned."),
+        key("rate_limit", "integer", "10", "10", "Messages per minute; extra notices are dropped."),
+    ]),
+    table("http", "Health checks, /info and /metrics over HTTP.", &[
+        key("address", "string", "127.0.0.1:9080", "\"127.0.0.1:9080\"", "Address it listens on."),
+        key("backend_check_interval", "seconds", "60", "60", "Time between backend probes for /readyz."),
+    ]),
+    table("metrics_push", "Push the /metrics figures on a timer, for a server that can't be scraped.", &[
+        key("url", "URL", "required", "\"http://pushgateway.example.net:9091\"", "The Pushgateway or remote write endpoint."),
+        key("format", "pushgateway or remote_write", "pushgateway", "\"pushgateway\"", "What the endpoint takes."),
+        key("interval", "seconds", "15", "15", "Time between pushes."),
+        key("timeout", "seconds", "10", "10", "Time allowed for each push."),
+        key("job", "string", "triserver", "\"triserver\"", "The job label."),
+        key("instance", "string", "server.node_name, or else the host name", "\"node1\"", "The instance label."),
+        key("bearer_token", "string", "", "\"...\"", "Sent as \"Authorization: Bearer ...\"."),
+    ]),
+    table("daily_stats", "Sum up each day's calls, addresses, peak and bytes, as BBS packages do nightly.", &[
+        key("rollover", "HH:MM", "00:00", "\"04:00\"", "Local time the day's figures are reported and started again."),
+        key("file", "path", "none", "\"triserver.daily\"", "Each day's summary is appended here as a line of JSON."),
+    ]),
+    table("port_mapping", "Ask the router to forward the telnet port, by NAT-PMP or UPnP, for a server at home behind NAT.", &[
+        key("protocol", "auto, nat-pmp or upnp", "auto", "\"auto\"", "How to ask; auto tries NAT-PMP, then UPnP."),
+        key("external_port", "port", "server.port", "23", "The port asked for on the router's public address."),
+        key("lifetime", "seconds", "3600", "3600", "How long each mapping is asked for; it's renewed at half of it."),
+        key("gateway", "IPv4 address", "the default route's gateway", "\"192.168.1.1\"", "The router NAT-PMP asks (needed off Linux)."),
+    ]),
+    table("finger", "A read-only \"who's online\" port.", &[
+        key("address", "string", "0.0.0.0:79", "\"0.0.0.0:79\"", "Address it listens on."),
+    ]),
+    table("flood", "Limit how fast each caller may type or paste; zero turns a limit off.", &[
+        key("bytes_per_second", "integer", "2000", "2000", "Bytes per second."),
+        key("lines_per_second", "integer", "20", "20", "Lines per second."),
+        key("action", "throttle, warn or disconnect", "throttle", "\"throttle\"", "What happens to a caller over the limits."),
+    ]),
+    table("negotiation", "Cut off a session when either side overdoes telnet negotiation; zero turns a rate off.", &[
+        key("max_subnegotiation", "bytes", "512", "512", "Largest subnegotiation payload."),
+        key("client_rate", "integer", "100", "100", "Telnet commands per second from a caller; the lower of this and [autoban] negotiation_rate applies."),
+        key("backend_rate", "integer", "1000", "1000", "Telnet commands per second from a backend."),
+    ]),
+    table("multisession", "Let callers keep sessions to several backends open at once.", &[
+        key("hotkey", "control key", "^A", "\"^A\"", "Followed by a backend's number, switches to it."),
+        key("max_sessions", "integer", "4", "4", "Sessions a caller may have open."),
+        key("held_output", "KiB", "64", "64", "Output held per background session; the oldest is dropped."),
+    ]),
+    table("escape", "An escape key that brings up a prompt for the proxy itself.", &[
+        key("key", "control key", "^]", "\"^]\"", "The key."),
+    ]),
+    table("petscii", "Talk PETSCII to callers on Commodore 64 and 128 terminals.", &[
+        key("terminals", "array of strings", "[\"c64\", \"c128\", \"commodore\", \"petscii\"]", "[\"c64\", \"ccgms\"]",
+            "Matched anywhere in a caller's terminal type, ignoring case."),
+        key("always", "boolean", "false", "false", "Every caller gets PETSCII, for a server of their own."),
+    ]),
+    table("atascii", "Talk ATASCII to callers on Atari 8-bit terminal programs.", &[
+        key("terminals", "array of strings", "[\"atari\", \"atascii\"]", "[\"atari\"]",
+            "Matched anywhere in a caller's terminal type, ignoring case."),
+        key("always", "boolean", "false", "false", "Every caller gets ATASCII, for a server of their own."),
+    ]),
+    table("rip", "Pass RIPscrip graphics on untouched to terminals that draw them.", &[
+        key("terminals", "array of strings", "[\"rip\"]", "[\"rip\", \"ripterm\"]",
+            "Matched anywhere in a caller's terminal type, ignoring case."),
+        key("strip", "boolean", "true", "true", "Leaves RIPscrip out for every other caller."),
+    ]),
+    table("syncterm", "Pass SyncTERM's extended sequences on untouched, and tell backends it's SyncTERM.", &[
+        key("terminals", "array of strings", "[\"syncterm\", \"cterm\"]", "[\"syncterm\"]",
+            "Matched anywhere in a caller's terminal type, ignoring case."),
+        key("binary", "boolean", "true", "true", "Agrees to the telnet binary mode SyncTERM asks for."),
+    ]),
+    table("dropfile", "Write a door drop file as each session starts.", &[
+        key("path", "string", "required", "\"/bbs/node{node}/DOOR.SYS\"", "Where; {node} is the caller's node."),
+        key("format", "door.sys or dorinfo", "door.sys", "\"dorinfo\"", "DOOR.SYS or DORINFO1.DEF."),
+        key("baud", "integer", "38400", "38400", "The connection speed the door is told."),
+        key("sysop", "string", "Sysop", "\"Rick Greer\"", "The sysop's name."),
+    ]),
+    table("daemon", "Where --daemon writes its PID and output.", &[
+        key("pid_file", "path", "triserver.pid", "\"triserver.pid\"", "The PID file."),
+        key("log_file", "path", "triserver.log", "\"triserver.log\"", "The log; the Windows service uses it too."),
+    ]),
+    table("workers", "Several server processes sharing the telnet port (Linux only).", &[
+        key("count", "integer", "1", "4", "Processes to run."),
+        key("stats_file", "path", "triserver.stats", "\"triserver.stats\"", "One line of counts per worker."),
+    ]),
+    table("tls", "Also accept telnet over TLS.", &[
+        key("address", "string", "0.0.0.0:992", "\"0.0.0.0:992\"", "Address it listens on."),
+        key("certificate", "path", "required", "\"/etc/triserver/fullchain.pem\"", "PEM certificate, which may include the chain."),
+        key("key", "path", "required", "\"/etc/triserver/privkey.pem\"", "PEM private key."),
+    ]),
+    table("chaos", "Testing only: inject faults into every session's relayed traffic.", &[
+        key("latency", "milliseconds", "0", "200", "Added before each relayed chunk."),
+        key("drop_one_in", "integer", "0 (never)", "1000", "Drop each relayed byte with a 1 in this many chance."),
+        key("disconnect_after", "seconds", "never", "60", "Cut the backend connection after this long."),
+        key("partial_writes", "boolean", "false", "true", "Write relayed data a few bytes at a time."),
+    ]),
+];
+
+// Every table and key, for reading.
+// The tables and keys in a parsed file that aren't in the schema, most likely
+// typos, each by its path as toml::Lines has it and with what's wrong.
+pub fn unknown(root: &BTreeMap<String, Value>) -> Vec<(String, String)> {
+    let mut found = Vec::new();
+    unknown_in(root, TOP_LEVEL, SECTIONS, "", "", &mut found);
+    found
+}
+
+fn unknown_in(table: &BTreeMap<String, Value>, keys: &[Key], sections: &[Section], name: &str, located: &str,
+              found: &mut Vec<(String, String)>) {
+    for (key, value) in table {
+        let inner_name = if name.is_empty() { key.clone() } else { format!("{}.{}", name, key) };
+        let inner_located = if located.is_empty() { key.clone() } else { format!("{}.{}", located, key) };
+        if let Some(section) = sections.iter().find(|section| section.name == key) {
+            // A table of the wrong shape is an error when the config is read.
+            match value {
+                Value::Array(items) if section.repeated => {
+                    for (index, item) in items.iter().enumerate() {
+                        if let Value::Table(inner) = item {
+                            unknown_in(inner, section.keys, section.sections, &inner_name, &format!("{}.{}", inner_located, index), found);
+                        }
+                    }
+                }
+                Value::Table(inner) if !section.repeated => unknown_in(inner, section.keys, section.sections, &inner_name, &inner_located, found),
+                _ => {}
+            }
+        } else if !keys.iter().any(|known| known.name == key) {
+            let message = match value {
+                Value::Table(_) | Value::Array(_) if name.is_empty() => format!("there is no [{}] table", key),
+                _ if name.is_empty() => format!("there is no top-level key {}", key),
+                _ => format!("[{}] has no key {}", name, key),
+            };
+            found.push((inner_located, message));
+        }
+    }
+}
+
+pub fn describe() -> String {
+    let mut out = String::from("(top level)\n    Keys before the first table.\n");
+    describe_keys(&mut out, TOP_LEVEL);
+    let _ = writeln!(out);
+    for section in SECTIONS {
+        describe_section(&mut out, section, "", "TRISERVER_");
+    }
+    out.push_str("Each table's keys can also be set from the environment under the name shown,\n\
+                  with the key in capitals. An <INDEX> left out is 0, and a [[backend]]'s ADDR\n\
+                  sets its host and port as host:port.\n");
+    out
+}
+
+fn describe_section(out: &mut String, section: &Section, parent: &str, variable: &str) {
+    let path = format!("{}{}", parent, section.name);
+    let header = if section.repeated { format!("[[{}]]", path) } else { format!("[{}]", path) };
+    let variable = format!("{}{}_{}", variable, section.name.to_ascii_uppercase(), if section.repeated { "<INDEX>_" } else { "" });
+    let _ = writeln!(out, "{}\n    {}\n    Environment: {}<KEY>", header, section.description, variable);
+    describe_keys(out, section.keys);
+    let _ = writeln!(out);
+    for inner in section.sections {
+        describe_section(out, inner, &format!("{}.", path), &variable);
+    }
+}
+
+fn describe_keys(out: &mut String, keys: &[Key]) {
+    for key in keys {
+        let default = match key.default {
+            "" => String::new(),
+            default if default.starts_with("required") => format!("; {}", default),
+            default => format!("; default: {}", default),
+        };
+        let _ = writeln!(out, "  {} ({}{})\n      {}", key.name, key.kind, default, key.description);
+    }
+}
+
+// A config file with every key commented out, set to its example.
+pub fn example() -> String {
+    let mut out = String::from("# Every key TriServer reads, commented out. Uncomment a table's header along\n\
+                                # with the keys you set in it; leave the rest out for their defaults.\n\n");
+    example_keys(&mut out, TOP_LEVEL);
+    let _ = writeln!(out);
+    for section in SECTIONS {
+        example_section(&mut out, section, "");
+    }
+    out
+}
+
+fn example_section(out: &mut String, section: &Section, parent: &str) {
+    let path = format!("{}{}", parent, section.name);
+    let header = if section.repeated { format!("[[{}]]", path) } else { format!("[{}]", path) };
+    let _ = writeln!(out, "# {}\n# {}", section.description, header);
+    example_keys(out, section.keys);
+    let _ = writeln!(out);
+    for inner in section.sections {
+        example_section(out, inner, &format!("{}.", path));
+    }
+}
+
+fn example_keys(out: &mut String, keys: &[Key]) {
+    for key in keys {
+        let default = match key.default {
+            "" => String::new(),
+            default if default.starts_with("required") => format!(" {}{}.", default[..1].to_uppercase(), &default[1..]),
+            default => format!(" Default: {}.", default),
+        };
+        let _ = writeln!(out, "#   {} ({}){}\n# {} = {}", key.description, key.kind, default, key.name, key.example);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::toml;
+
+    fn unknown_in(source: &str) -> Vec<String> {
+        match toml::parse(source) {
+            Ok(Value::Table(root)) => unknown(&root).into_iter().map(|(_, message)| message).collect(),
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    #[test]
+    fn knows_every_key_in_the_example() {
+        // Uncommenting every header and key, but not the descriptions.
+        let source: String = example().lines()
+            .filter_map(|line| line.strip_prefix("# "))
+            .filter(|line| line.starts_with('[') || line.split_once(" = ").is_some_and(|(key, _)| !key.contains(' ')))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert!(source.contains("[[backend]]\n"));
+        assert_eq!(unknown_in(&source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn finds_unknown_keys_and_tables() {
+        let found = unknown_in("typo = 1\n[server]\nadress = \"x\"\n[bogus]\n[[backend]]\nname = \"a\"\n[[backend]]\nhots = \"b\"\n\
+                                [backend.ssh]\nusr = \"me\"\n[[pool]]\n[[pool.member]]\nwieght = 2\n");
+        assert_eq!(found, ["[backend] has no key hots", "[backend.ssh] has no key usr", "there is no [bogus] table",
+                           "[pool.member] has no key wieght", "[server] has no key adress", "there is no top-level key typo"]);
+    }
+
+    #[test]
+    fn leaves_free_form_tables_alone() {
+        assert_eq!(unknown_in("[[backend]]\nname = \"a\"\n[backend.options]\nTTYPE = \"refuse\"\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn gives_the_path_of_each_unknown_key() {
+        let Ok(Value::Table(root)) = toml::parse("[[backend]]\nname = \"a\"\n[[backend]]\nhots = \"b\"\n") else { panic!() };
+        assert_eq!(unknown(&root), [(String::from("backend.1.hots"), String::from("[backend] has no key hots"))]);
+    }
+
+    #[test]
+    fn describes_every_table() {
+        let description = describe();
+        for section in SECTIONS {
+            assert!(description.contains(&format!("[{}]", section.name)), "{} missing", section.name);
+        }
+        assert!(description.contains("Environment: TRISERVER_BACKEND_<INDEX>_SSH_<KEY>"));
+    }
+}
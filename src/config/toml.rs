@@ -0,0 +1,417 @@
+// Small TOML subset parser: tables, arrays of tables, strings, integers,
+// booleans, inline tables and (possibly multi-line) arrays. That covers
+// everything the config file uses; floats, dates and multi-line strings are
+// refused.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{ConfigError, Value};
+
+// The line each table and key was set on, by its dotted path with the index
+// of each array element in it, as in `backend.0.host`.
+pub type Lines = HashMap<String, usize>;
+
+pub fn parse(source: &str) -> Result<Value, ConfigError> {
+    parse_located(source).map(|(value, _)| value)
+}
+
+pub fn parse_located(source: &str) -> Result<(Value, Lines), ConfigError> {
+    let mut root = BTreeMap::new();
+    let mut lines_of = Lines::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut lines = source.lines().enumerate().peekable();
+
+    while let Some((index, raw_line)) = lines.next() {
+        let line_number = index + 1;
+        let mut line = strip_comment(raw_line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]")) {
+            let path = parse_key_path(header, line_number)?;
+            let (last, parents) = path.split_last().unwrap();
+            let parent = table_at(&mut root, parents, line_number)?;
+            let entry = parent.entry(last.clone()).or_insert_with(|| Value::Array(Vec::new()));
+            match entry {
+                Value::Array(items) => items.push(Value::Table(BTreeMap::new())),
+                _ => return Err(error(line_number, format!("'{}' is not an array of tables", header.trim()))),
+            }
+            lines_of.insert(located(&root, &path), line_number);
+            current = path;
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let path = parse_key_path(header, line_number)?;
+            table_at(&mut root, &path, line_number)?;
+            lines_of.insert(located(&root, &path), line_number);
+            current = path;
+            continue;
+        }
+
+        let Some(eq) = find_unquoted(&line, '=') else {
+            return Err(error(line_number, format!("expected 'key = value', found '{}'", line)));
+        };
+
+        // Arrays may continue over several lines until the brackets balance.
+        while bracket_depth(&line[eq + 1..]) > 0 {
+            match lines.next() {
+                Some((_, next)) => {
+                    line.push(' ');
+                    line.push_str(strip_comment(next).trim());
+                }
+                None => return Err(error(line_number, String::from("unterminated array"))),
+            }
+        }
+
+        let key_path = parse_key_path(&line[..eq], line_number)?;
+        let value = parse_value(line[eq + 1..].trim(), line_number)?;
+        let (key, parents) = key_path.split_last().unwrap();
+        let mut full_path = current.clone();
+        full_path.extend_from_slice(parents);
+        let table = table_at(&mut root, &full_path, line_number)?;
+        if table.insert(key.clone(), value).is_some() {
+            return Err(error(line_number, format!("duplicate key '{}'", key)));
+        }
+        full_path.push(key.clone());
+        lines_of.insert(located(&root, &full_path), line_number);
+    }
+
+    Ok((Value::Table(root), lines_of))
+}
+
+// A path as Lines has it, with the index of the last element of each array
+// of tables on the way.
+fn located(root: &BTreeMap<String, Value>, path: &[String]) -> String {
+    let mut parts = Vec::new();
+    let mut table = Some(root);
+    for segment in path {
+        parts.push(segment.clone());
+        table = match table.and_then(|table| table.get(segment)) {
+            Some(Value::Table(inner)) => Some(inner),
+            Some(Value::Array(items)) if !items.is_empty() => {
+                parts.push((items.len() - 1).to_string());
+                match items.last() {
+                    Some(Value::Table(inner)) => Some(inner),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+    }
+    parts.join(".")
+}
+
+fn error(line: usize, message: String) -> ConfigError {
+    ConfigError::Parse { line, message }
+}
+
+// Walks (and creates) nested tables. When a path segment is an array of
+// tables the last element is used, matching TOML semantics.
+fn table_at<'a>(root: &'a mut BTreeMap<String, Value>, path: &[String], line: usize) -> Result<&'a mut BTreeMap<String, Value>, ConfigError> {
+    let mut table = root;
+    for segment in path {
+        let entry = table.entry(segment.clone()).or_insert_with(|| Value::Table(BTreeMap::new()));
+        table = match entry {
+            Value::Table(inner) => inner,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(inner)) => inner,
+                _ => return Err(error(line, format!("'{}' is not a table", segment))),
+            },
+            _ => return Err(error(line, format!("'{}' is not a table", segment))),
+        };
+    }
+    Ok(table)
+}
+
+fn parse_key_path(raw: &str, line: usize) -> Result<Vec<String>, ConfigError> {
+    let mut path = Vec::new();
+    for part in raw.split('.') {
+        let part = part.trim();
+        let key = if let Some(quoted) = part.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+            quoted.to_string()
+        } else if !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            part.to_string()
+        } else {
+            return Err(error(line, format!("invalid key '{}'", raw.trim())));
+        };
+        path.push(key);
+    }
+    Ok(path)
+}
+
+pub fn parse_value(raw: &str, line: usize) -> Result<Value, ConfigError> {
+    let (value, rest) = parse_partial(raw, line)?;
+    if !rest.trim().is_empty() {
+        return Err(error(line, format!("unexpected trailing characters '{}'", rest.trim())));
+    }
+    Ok(value)
+}
+
+fn parse_partial(raw: &str, line: usize) -> Result<(Value, &str), ConfigError> {
+    let raw = raw.trim_start();
+    if let Some(rest) = raw.strip_prefix('"') {
+        return parse_basic_string(rest, line);
+    }
+    if let Some(rest) = raw.strip_prefix('\'') {
+        return match rest.find('\'') {
+            Some(end) => Ok((Value::String(rest[..end].to_string()), &rest[end + 1..])),
+            None => Err(error(line, String::from("unterminated string"))),
+        };
+    }
+    if let Some(mut rest) = raw.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_partial(rest, line)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err(error(line, String::from("expected ',' or ']' in array")));
+            }
+        }
+    }
+    if let Some(mut rest) = raw.strip_prefix('{') {
+        let mut table = BTreeMap::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('}').filter(|_| table.is_empty()) {
+                return Ok((Value::Table(table), after));
+            }
+            let eq = find_unquoted(rest, '=').ok_or_else(|| error(line, String::from("expected 'key = value' in inline table")))?;
+            let path = parse_key_path(&rest[..eq], line)?;
+            let (value, after) = parse_partial(&rest[eq + 1..], line)?;
+            let (key, parents) = path.split_last().unwrap();
+            if table_at(&mut table, parents, line)?.insert(key.clone(), value).is_some() {
+                return Err(error(line, format!("duplicate key '{}'", key)));
+            }
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('}') {
+                return Ok((Value::Table(table), after));
+            } else {
+                return Err(error(line, String::from("expected ',' or '}' in inline table")));
+            }
+        }
+    }
+
+    let end = raw.find(|c: char| c == ',' || c == ']' || c == '}' || c.is_whitespace()).unwrap_or(raw.len());
+    let (token, rest) = raw.split_at(end);
+    let value = match token {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => match token.replace('_', "").parse::<i64>() {
+            Ok(number) => Value::Integer(number),
+            Err(_) => return Err(error(line, format!("invalid value '{}'; only strings, whole numbers, booleans, arrays and inline tables are read", token))),
+        },
+    };
+    Ok((value, rest))
+}
+
+fn parse_basic_string(raw: &str, line: usize) -> Result<(Value, &str), ConfigError> {
+    let mut value = String::new();
+    let mut chars = raw.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Value::String(value), &raw[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 'r')) => value.push('\r'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, 'e')) => value.push('\x1b'),
+                Some((_, 'u')) => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                        Some(decoded) => value.push(decoded),
+                        None => return Err(error(line, format!("invalid unicode escape '\\u{}'", hex))),
+                    }
+                }
+                _ => return Err(error(line, String::from("invalid escape sequence"))),
+            },
+            _ => value.push(c),
+        }
+    }
+    Err(error(line, String::from("unterminated string")))
+}
+
+pub fn strip_comment(line: &str) -> &str {
+    match find_unquoted(line, '#') {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+pub fn find_unquoted(line: &str, needle: char) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == needle => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+pub fn bracket_depth(value: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    for c in value.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            },
+        }
+    }
+    depth
+}
+
+// A basic string that parses back to `value`, for writing keys out.
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\x1b' => quoted.push_str("\\e"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(source: &str) -> BTreeMap<String, Value> {
+        match parse(source) {
+            Ok(Value::Table(root)) => root,
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    #[test]
+    fn reads_tables_and_arrays_of_tables() {
+        let root = table("top = 1\n[server]\nport = 23 # telnet\n\n[[backend]]\nname = \"a\"\n[[backend]]\nname = \"b\"\n");
+        assert_eq!(root.get("top"), Some(&Value::Integer(1)));
+        let Some(Value::Table(server)) = root.get("server") else { panic!("no server table") };
+        assert_eq!(server.get("port"), Some(&Value::Integer(23)));
+        let Some(Value::Array(backends)) = root.get("backend") else { panic!("no backends") };
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[1], Value::Table(BTreeMap::from([(String::from("name"), string("b"))])));
+    }
+
+    #[test]
+    fn reads_dotted_keys_and_headers() {
+        let root = table("[backend.options]\nTTYPE = \"refuse\"\n[log]\nlevels.relay = \"debug\"\n");
+        let Some(Value::Table(backend)) = root.get("backend") else { panic!("no backend table") };
+        assert_eq!(backend.get("options"), Some(&Value::Table(BTreeMap::from([(String::from("TTYPE"), string("refuse"))]))));
+        let Some(Value::Table(log)) = root.get("log") else { panic!("no log table") };
+        assert_eq!(log.get("levels"), Some(&Value::Table(BTreeMap::from([(String::from("relay"), string("debug"))]))));
+    }
+
+    #[test]
+    fn reads_values() {
+        assert_eq!(parse_value("\"a\\tb\\u00e9\\e\"", 1).unwrap(), string("a\tb\u{e9}\x1b"));
+        assert_eq!(parse_value("'C:\\path'", 1).unwrap(), string("C:\\path"));
+        assert_eq!(parse_value("1_000", 1).unwrap(), Value::Integer(1000));
+        assert_eq!(parse_value("-5", 1).unwrap(), Value::Integer(-5));
+        assert_eq!(parse_value("false", 1).unwrap(), Value::Boolean(false));
+        assert_eq!(parse_value("[1, [\"x\"], ]", 1).unwrap(),
+                   Value::Array(vec![Value::Integer(1), Value::Array(vec![string("x")])]));
+    }
+
+    #[test]
+    fn continues_arrays_over_lines() {
+        let root = table("list = [\n  \"a\", # first\n  \"b]\",\n]\nafter = 2\n");
+        assert_eq!(root.get("list"), Some(&Value::Array(vec![string("a"), string("b]")])));
+        assert_eq!(root.get("after"), Some(&Value::Integer(2)));
+    }
+
+    #[test]
+    fn keeps_hashes_in_strings() {
+        let root = table("color = \"#ff0000\" # red\n");
+        assert_eq!(root.get("color"), Some(&string("#ff0000")));
+    }
+
+    #[test]
+    fn reads_inline_tables() {
+        let root = table("options = { TTYPE = \"refuse\", \"NAWS\" = \"accept\" }\nmembers = [{ backend = \"a\", weight = 2 }, {}]\n");
+        assert_eq!(root.get("options"), Some(&Value::Table(BTreeMap::from([
+            (String::from("NAWS"), string("accept")),
+            (String::from("TTYPE"), string("refuse")),
+        ]))));
+        assert_eq!(root.get("members"), Some(&Value::Array(vec![
+            Value::Table(BTreeMap::from([(String::from("backend"), string("a")), (String::from("weight"), Value::Integer(2))])),
+            Value::Table(BTreeMap::new()),
+        ])));
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        for (source, line) in [("a = 1\nb\n", 2), ("a = 1\na = 2\n", 2), ("x = \"open\n", 1), ("list = [1,\n", 1),
+                               ("speed = 1.5\n", 1), ("t = { a = 1\n", 1), ("t = { a = 1, a = 2 }\n", 1), ("bad key = 1\n", 1)] {
+            match parse(source) {
+                Err(ConfigError::Parse { line: found, .. }) => assert_eq!(found, line, "{:?}", source),
+                other => panic!("{:?} parsed as {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn says_which_values_it_reads() {
+        let error = parse("speed = 1.5\n").unwrap_err().to_string();
+        assert!(error.contains("only strings, whole numbers, booleans, arrays and inline tables"), "{}", error);
+    }
+
+    #[test]
+    fn records_the_line_of_each_table_and_key() {
+        let (_, lines) = parse_located("[server]\nport = 23\n\n[[backend]]\nname = \"a\"\n[[backend]]\nname = \"b\"\nssh.user = \"x\"\n").unwrap();
+        assert_eq!(lines.get("server"), Some(&1));
+        assert_eq!(lines.get("server.port"), Some(&2));
+        assert_eq!(lines.get("backend.0"), Some(&4));
+        assert_eq!(lines.get("backend.1.name"), Some(&7));
+        assert_eq!(lines.get("backend.1.ssh.user"), Some(&8));
+    }
+
+    #[test]
+    fn quotes_strings_that_parse_back() {
+        for value in ["plain", "with \"quotes\"", "back\\slash", "\r\n\t\x1b", "\u{7}bell", "caf\u{e9}"] {
+            assert_eq!(parse_value(&quote(value), 1).unwrap(), string(value));
+        }
+    }
+}
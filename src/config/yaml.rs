@@ -0,0 +1,296 @@
+// YAML config files: the block style of mappings and sequences that config
+// files are written in, read into the same values as TOML ones. A sequence
+// of mappings is an array of tables. Scalars are quoted or plain strings,
+// whole numbers, true and false, and [flow, sequences] of them; a null (~,
+// null or nothing at all) is the same as leaving the key out. Anchors, tags,
+// block scalars and flow mappings aren't read.
+
+use std::collections::BTreeMap;
+
+use super::{ConfigError, Value};
+
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+pub fn parse(source: &str) -> Result<Value, ConfigError> {
+    let mut lines = Vec::new();
+    for (index, raw) in source.lines().enumerate() {
+        let without_comment = strip_comment(raw).trim_end();
+        let text = without_comment.trim_start();
+        if text.is_empty() || text == "---" {
+            continue;
+        }
+        if raw.starts_with('\t') {
+            return Err(error(index + 1, "tabs can't indent YAML"));
+        }
+        lines.push(Line { number: index + 1, indent: without_comment.len() - text.len(), text });
+    }
+    let mut parser = Parser { lines, position: 0 };
+    if parser.lines.is_empty() {
+        return Ok(Value::Table(BTreeMap::new()));
+    }
+    if is_item(parser.lines[0].text) {
+        return Err(error(parser.lines[0].number, "expected a mapping at the top level"));
+    }
+    let indent = parser.lines[0].indent;
+    let root = parser.mapping(indent)?;
+    if let Some(line) = parser.lines.get(parser.position) {
+        return Err(error(line.number, "unexpected indentation"));
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    lines: Vec<Line<'a>>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    // The block under a key or item, if the next line is indented past it
+    // (or, for a key, starts a sequence at the key's own indent).
+    fn block(&mut self, parent: usize, key: bool) -> Result<Option<Value>, ConfigError> {
+        let Some(next) = self.lines.get(self.position) else {
+            return Ok(None);
+        };
+        match (next.indent, is_item(next.text)) {
+            (indent, true) if indent > parent || (key && indent == parent) => self.sequence(indent).map(Some),
+            (indent, false) if indent > parent => self.mapping(indent).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Value, ConfigError> {
+        let mut entries = BTreeMap::new();
+        while let Some(line) = self.lines.get(self.position).filter(|line| line.indent == indent && !is_item(line.text)) {
+            let (number, text) = (line.number, line.text);
+            let (key, rest) = split_key(text).ok_or_else(|| error(number, &format!("expected 'key: value', found '{}'", text)))?;
+            self.position += 1;
+            let value = match rest {
+                "" => self.block(indent, true)?,
+                rest => scalar(rest, number)?,
+            };
+            if entries.contains_key(&key) {
+                return Err(error(number, &format!("duplicate key '{}'", key)));
+            }
+            if let Some(value) = value {
+                entries.insert(key, value);
+            }
+        }
+        match self.lines.get(self.position) {
+            Some(line) if line.indent > indent => Err(error(line.number, "unexpected indentation")),
+            _ => Ok(Value::Table(entries)),
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Value, ConfigError> {
+        let mut items = Vec::new();
+        while let Some(line) = self.lines.get(self.position).filter(|line| line.indent == indent && is_item(line.text)) {
+            let (number, text) = (line.number, line.text);
+            let rest = text[1..].trim_start();
+            let item = if rest.is_empty() {
+                self.position += 1;
+                self.block(indent, false)?
+            } else if split_key(rest).is_some() {
+                // "- key: value" starts a mapping at the key, which the
+                // lines after it line up with.
+                let inner = indent + (text.len() - rest.len());
+                self.lines[self.position] = Line { number, indent: inner, text: rest };
+                Some(self.mapping(inner)?)
+            } else {
+                self.position += 1;
+                scalar(rest, number)?
+            };
+            items.push(item.ok_or_else(|| error(number, "null in a sequence"))?);
+        }
+        match self.lines.get(self.position) {
+            Some(line) if line.indent > indent => Err(error(line.number, "unexpected indentation")),
+            _ => Ok(Value::Array(items)),
+        }
+    }
+}
+
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+// "key: value", the key maybe quoted.
+fn split_key(text: &str) -> Option<(String, &str)> {
+    let colon = find_unquoted(text, ':').filter(|&colon| text[colon + 1..].is_empty() || text[colon + 1..].starts_with(' '))?;
+    let key = text[..colon].trim();
+    let key = match unquote(key) {
+        Some(quoted) => quoted.ok()?,
+        None if key.is_empty() => return None,
+        None => key.to_string(),
+    };
+    Some((key, text[colon + 1..].trim()))
+}
+
+// None for a null.
+fn scalar(text: &str, line: usize) -> Result<Option<Value>, ConfigError> {
+    if let Some(quoted) = unquote(text) {
+        return quoted.map(|string| Some(Value::String(string))).map_err(|message| error(line, &message));
+    }
+    if let Some(inside) = text.strip_prefix('[') {
+        let inside = inside.strip_suffix(']').ok_or_else(|| error(line, "unterminated [sequence]"))?;
+        let mut items = Vec::new();
+        for item in split_flow(inside).into_iter().filter(|item| !item.is_empty()) {
+            items.push(scalar(item, line)?.ok_or_else(|| error(line, "null in a sequence"))?);
+        }
+        return Ok(Some(Value::Array(items)));
+    }
+    if text.starts_with(['{', '&', '*', '!', '|', '>']) {
+        return Err(error(line, &format!("'{}' isn't read in config files", &text[..1])));
+    }
+    Ok(match text {
+        "~" | "null" | "Null" | "NULL" => None,
+        "true" | "True" | "TRUE" => Some(Value::Boolean(true)),
+        "false" | "False" | "FALSE" => Some(Value::Boolean(false)),
+        _ => Some(text.parse().map(Value::Integer).unwrap_or_else(|_| Value::String(text.to_string()))),
+    })
+}
+
+// "double" with backslash escapes, or 'single' with '' for a quote; None when
+// the text isn't quoted.
+fn unquote(text: &str) -> Option<Result<String, String>> {
+    if let Some(inside) = text.strip_prefix('"') {
+        let inside = inside.strip_suffix('"').filter(|_| text.len() > 1);
+        return Some(inside.ok_or_else(|| String::from("unterminated string")).and_then(|inside| {
+            let mut string = String::new();
+            let mut chars = inside.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    string.push(c);
+                    continue;
+                }
+                string.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(escaped @ ('"' | '\\' | '/' | ' ')) => escaped,
+                    _ => return Err(String::from("invalid escape in a string")),
+                });
+            }
+            Ok(string)
+        }));
+    }
+    if let Some(inside) = text.strip_prefix('\'') {
+        let inside = inside.strip_suffix('\'').filter(|_| text.len() > 1);
+        return Some(inside.map(|inside| inside.replace("''", "'")).ok_or_else(|| String::from("unterminated string")));
+    }
+    None
+}
+
+fn strip_comment(line: &str) -> &str {
+    match find_unquoted(line, '#') {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+// Where `needle` is outside any quoted scalar. As a quote only opens one at
+// the start of a scalar, "Bob's BBS" has none; and a # only starts a comment
+// after a space.
+fn find_unquoted(text: &str, needle: char) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && q == '"' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if (c == '"' || c == '\'') && (previous.is_whitespace() || matches!(previous, '[' | ',' | ':')) => quote = Some(c),
+            None if c == needle && (needle != '#' || previous.is_whitespace()) => return Some(i),
+            None => {}
+        }
+        previous = c;
+    }
+    None
+}
+
+// The items of a flow sequence, split at the commas outside quotes.
+fn split_flow(inside: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut rest = inside;
+    while let Some(comma) = find_unquoted(rest, ',') {
+        items.push(rest[..comma].trim());
+        rest = &rest[comma + 1..];
+    }
+    items.push(rest.trim());
+    items
+}
+
+fn error(line: usize, message: &str) -> ConfigError {
+    ConfigError::Parse { line, message: message.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(source: &str) -> BTreeMap<String, Value> {
+        match parse(source) {
+            Ok(Value::Table(root)) => root,
+            other => panic!("parsed {:?}", other),
+        }
+    }
+
+    fn string(value: &str) -> Value {
+        Value::String(value.to_string())
+    }
+
+    #[test]
+    fn reads_mappings_and_sequences_of_mappings() {
+        let root = table("---\nserver:\n  port: 23 # telnet\n  name: Bob's BBS\nbackend:\n- name: a\n  port: 2323\n-\n  name: b\n");
+        assert_eq!(root.get("server"), Some(&Value::Table(BTreeMap::from([
+            (String::from("name"), string("Bob's BBS")),
+            (String::from("port"), Value::Integer(23)),
+        ]))));
+        assert_eq!(root.get("backend"), Some(&Value::Array(vec![
+            Value::Table(BTreeMap::from([(String::from("name"), string("a")), (String::from("port"), Value::Integer(2323))])),
+            Value::Table(BTreeMap::from([(String::from("name"), string("b"))])),
+        ])));
+        assert_eq!(table("# nothing here\n"), BTreeMap::new());
+    }
+
+    #[test]
+    fn reads_scalars() {
+        let root = table("a: \"tab\\there # not a comment\"\nb: 'it''s'\nc: True\nd: -3\ne: [1, \"x, y\", z]\nf: ~\ng:\n\"h k\": '#ff0000'\n");
+        assert_eq!(root.get("a"), Some(&string("tab\there # not a comment")));
+        assert_eq!(root.get("b"), Some(&string("it's")));
+        assert_eq!(root.get("c"), Some(&Value::Boolean(true)));
+        assert_eq!(root.get("d"), Some(&Value::Integer(-3)));
+        assert_eq!(root.get("e"), Some(&Value::Array(vec![Value::Integer(1), string("x, y"), string("z")])));
+        assert_eq!(root.get("f"), None);
+        assert_eq!(root.get("g"), None);
+        assert_eq!(root.get("h k"), Some(&string("#ff0000")));
+    }
+
+    #[test]
+    fn reads_block_sequences_of_scalars() {
+        let root = table("include:\n  - a.yaml\n  - 'b c.yaml'\nlisten:\n- 23\n");
+        assert_eq!(root.get("include"), Some(&Value::Array(vec![string("a.yaml"), string("b c.yaml")])));
+        assert_eq!(root.get("listen"), Some(&Value::Array(vec![Value::Integer(23)])));
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        for (source, line) in [("- a\n", 1), ("a: 1\n\tb: 2\n", 2), ("a: 1\n  b: 2\n", 2), ("a: 1\na: 2\n", 2),
+                               ("a:\n  - ~\n", 2), ("a: \"open\n", 1), ("a: [1, 2\n", 1), ("a: &anchor 1\n", 1),
+                               ("a: |\n  text\n", 1), ("just text\n", 1), ("a:\n  b: 1\n c: 2\n", 3)] {
+            match parse(source) {
+                Err(ConfigError::Parse { line: found, .. }) => assert_eq!(found, line, "{:?}", source),
+                other => panic!("{:?} parsed as {:?}", source, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Background mode for classic init systems: fork, detach from the terminal,
+// send output to a log file and leave a PID file for `TriServer stop`.
+
+use std::ffi::c_int;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::DaemonConfig;
+use crate::instance;
+use crate::log;
+
+const SIGTERM: c_int = 15;
+const EPERM: i32 = 1;
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+extern "C" {
+    fn fork() -> c_int;
+    fn setsid() -> c_int;
+    fn dup2(old: c_int, new: c_int) -> c_int;
+    fn kill(pid: c_int, signal: c_int) -> c_int;
+    fn _exit(status: c_int) -> !;
+}
+
+// Must be called before any threads are started; only the calling thread
+// survives a fork. Returns in the background process, which has taken over
+// the locked PID file.
+pub fn daemonize(config: &DaemonConfig) -> io::Result<()> {
+    // Opened up front so a bad path is reported on the terminal.
+    let log = OpenOptions::new().create(true).append(true).open(&config.log_file)?;
+    let null = File::open("/dev/null")?;
+
+    unsafe {
+        fork_and_exit_parent()?;
+        if setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Forking again means the daemon is not a session leader and can never
+        // pick up a controlling terminal.
+        fork_and_exit_parent()?;
+    }
+    instance::write_pid()?;
+    log!(Server, Info, "TriServer running in the background as PID {}, logging to {}", std::process::id(), config.log_file.display());
+
+    unsafe {
+        dup2(null.as_raw_fd(), 0);
+        dup2(log.as_raw_fd(), 1);
+        dup2(log.as_raw_fd(), 2);
+    }
+    Ok(())
+}
+
+// Points the PID file at the process that took over after an upgrade.
+pub fn replace_pid(config: &DaemonConfig, pid: u32) -> io::Result<()> {
+    fs::write(&config.pid_file, format!("{}\n", pid))
+}
+
+unsafe fn fork_and_exit_parent() -> io::Result<()> {
+    match fork() {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => _exit(0),
+    }
+}
+
+// Sends SIGTERM to the process in the PID file and waits for it to exit.
+pub fn stop(config: &DaemonConfig) -> Result<i32, String> {
+    let pid = read_pid(&config.pid_file)?;
+    if unsafe { kill(pid, SIGTERM) } == -1 {
+        let error = io::Error::last_os_error();
+        let _ = fs::remove_file(&config.pid_file);
+        return Err(format!("unable to signal PID {}: {}", pid, error));
+    }
+    let started = Instant::now();
+    while is_alive(pid) {
+        if started.elapsed() >= STOP_TIMEOUT {
+            return Err(format!("PID {} is still running after {} seconds", pid, STOP_TIMEOUT.as_secs()));
+        }
+        sleep(Duration::from_millis(100));
+    }
+    let _ = fs::remove_file(&config.pid_file);
+    Ok(pid)
+}
+
+fn read_pid(path: &Path) -> Result<c_int, String> {
+    let contents = fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    // Zero or a negative number would signal a whole process group.
+    contents.trim().parse().ok().filter(|pid| *pid > 0).ok_or_else(|| format!("{}: not a PID", path.display()))
+}
+
+fn is_alive(pid: c_int) -> bool {
+    // EPERM means the process exists but belongs to someone else.
+    let result = unsafe { kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(EPERM)
+}
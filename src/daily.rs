@@ -0,0 +1,135 @@
+// Each day's figures, as BBS packages give in their nightly stats: calls,
+// the addresses they came from, the most callers connected at once and the
+// bytes relayed each way. At [daily_stats] rollover, in local time, the day's
+// figures are logged, published as a `daily` event for the webhook and the
+// admin events stream, and appended to the file if one is set, then started
+// again from zero. The first day after a start only counts from then.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::clock::{format_date, unix_time, utc_offset};
+use crate::config::{DailyStatsConfig, TimeOfDay};
+use crate::events::Event;
+use crate::json::Object;
+use crate::log;
+use crate::ServerContext;
+
+// How often the event bus and the client map are looked at.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct DailySummary {
+    // The local date the figures belong to.
+    pub date: String,
+    // When counting began: the previous rollover, or the server starting.
+    pub since: u64,
+    pub calls: u64,
+    pub unique_ips: u64,
+    pub peak_sessions: u64,
+    pub to_backend: u64,
+    pub to_client: u64,
+}
+
+impl DailySummary {
+    pub fn fields(&self, object: Object) -> Object {
+        object
+            .string("date", &self.date)
+            .number("since", self.since)
+            .number("calls", self.calls)
+            .number("unique_ips", self.unique_ips)
+            .number("peak_sessions", self.peak_sessions)
+            .number("to_backend", self.to_backend)
+            .number("to_client", self.to_client)
+    }
+}
+
+#[derive(Default)]
+struct Day {
+    calls: u64,
+    addresses: HashSet<IpAddr>,
+    peak_sessions: u64,
+    to_backend: u64,
+    to_client: u64,
+}
+
+impl Day {
+    fn count(&mut self, event: &Event) {
+        match event {
+            Event::Connected(session) => {
+                self.calls += 1;
+                self.addresses.insert(session.ip_addr);
+            }
+            Event::BytesRelayed { to_backend, to_client, .. } => {
+                self.to_backend += to_backend;
+                self.to_client += to_client;
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn launch_daily_stats(config: &DailyStatsConfig, context: ServerContext) {
+    let config = config.clone();
+    let receiver = context.events.subscribe();
+    let _ = thread::spawn(move || {
+        let mut day = Day::default();
+        let mut since = unix_time();
+        let (mut rollover, mut date) = next_rollover(since, config.rollover);
+        loop {
+            for event in receiver.try_iter() {
+                day.count(&event);
+            }
+            let sessions = context.clients.len() as u64;
+            day.peak_sessions = day.peak_sessions.max(sessions);
+            let now = unix_time();
+            if now >= rollover {
+                let summary = DailySummary {
+                    date,
+                    since,
+                    calls: day.calls,
+                    unique_ips: day.addresses.len() as u64,
+                    peak_sessions: day.peak_sessions,
+                    to_backend: day.to_backend,
+                    to_client: day.to_client,
+                };
+                report(&summary, &config, &context);
+                day = Day { peak_sessions: sessions, ..Day::default() };
+                since = now;
+                (rollover, date) = next_rollover(now, config.rollover);
+            }
+            sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+// The next time it's `at` locally, and the local date the day it closes
+// began on: a rollover at 04:00 on the 2nd closes the 1st.
+fn next_rollover(now: u64, at: TimeOfDay) -> (u64, String) {
+    let local = now as i64 + utc_offset(now);
+    let mut target = local - local.rem_euclid(86_400) + at.seconds() as i64;
+    if target <= local {
+        target += 86_400;
+    }
+    // The offset at the rollover itself, in case the clocks change before it.
+    let utc = target - utc_offset((target - utc_offset(now)) as u64);
+    (utc as u64, format_date((target - 86_400) as u64))
+}
+
+fn report(summary: &DailySummary, config: &DailyStatsConfig, context: &ServerContext) {
+    log!(Server, Info, "Daily stats for {}: {} call(s) from {} address(es), at most {} connected at once, {} bytes to backends, {} bytes to callers",
+         summary.date, summary.calls, summary.unique_ips, summary.peak_sessions, summary.to_backend, summary.to_client);
+    context.events.publish(Event::Daily(summary.clone()));
+    let Some(path) = &config.file else {
+        return;
+    };
+    let line = format!("{}\n", summary.fields(Object::new()).finish());
+    if let Err(error) = OpenOptions::new().create(true).append(true).open(path).and_then(|mut file| file.write_all(line.as_bytes())) {
+        log!(Server, Warn, "Unable to append the daily stats to {}: {}", path.display(), error);
+    }
+}
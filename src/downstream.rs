@@ -0,0 +1,133 @@
+// A session's connection to its caller, non-blocking so the relay can poll
+// it. What the caller's send buffer won't take is kept and sent as room
+// comes, on every read and write after, so a slow caller doesn't hold the
+// relay up. Up to server.pending_output may be kept; a caller who falls
+// further behind than that, one who takes nothing of it for
+// server.write_timeout, and one whose connection has gone (a broken pipe or
+// a reset) get an error back, which ends the session the usual way.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::chaos::Segmented;
+use crate::negotiated::CallerNegotiation;
+
+const WRITE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct Downstream {
+    stream: TcpStream,
+    write_timeout: Duration,
+    pending_limit: usize,
+    // Written, but not yet taken by the caller's send buffer.
+    pending: Vec<u8>,
+    // When the caller last took something of `pending`.
+    progress_at: Instant,
+    overflowed: bool,
+    // What the caller's telnet client has agreed to, from all they send.
+    // Boxed, as a stream is handed about by value when a session is resumed.
+    negotiation: Box<CallerNegotiation>,
+}
+
+impl Downstream {
+    pub fn new(stream: TcpStream, write_timeout: Duration, pending_limit: usize) -> Self {
+        Self { stream, write_timeout, pending_limit, pending: Vec::new(), progress_at: Instant::now(), overflowed: false,
+               negotiation: Box::new(CallerNegotiation::new()) }
+    }
+
+    pub fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.stream.peek(buffer)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    pub fn negotiation(&mut self) -> &mut CallerNegotiation {
+        &mut self.negotiation
+    }
+
+    // Whether a write failed for the caller having fallen too far behind.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    // Sends what the caller's send buffer takes of `pending`.
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(written) => {
+                    self.pending.drain(..written);
+                    self.progress_at = Instant::now();
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    fn stalled(&self) -> io::Result<()> {
+        if self.pending.is_empty() || self.progress_at.elapsed() < self.write_timeout {
+            return Ok(());
+        }
+        let taken = format!("the caller took nothing for {} seconds", self.write_timeout.as_secs());
+        Err(io::Error::new(ErrorKind::TimedOut, taken))
+    }
+
+    fn overflow(&self) -> io::Error {
+        io::Error::other(format!("more than {} KiB of output was waiting for the caller", self.pending_limit / 1024))
+    }
+}
+
+impl Read for Downstream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        // A connection that has gone shows up on the read as well.
+        let _ = self.drain();
+        let read = self.stream.read(buffer)?;
+        self.negotiation.feed(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+impl Write for Downstream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.overflowed {
+            return Err(self.overflow());
+        }
+        self.stalled()?;
+        if self.pending.is_empty() {
+            self.progress_at = Instant::now();
+        }
+        self.pending.extend_from_slice(data);
+        self.drain()?;
+        if self.pending.len() > self.pending_limit {
+            // The caller is about to be let go; none of it is worth sending.
+            self.pending = Vec::new();
+            self.overflowed = true;
+            return Err(self.overflow());
+        }
+        Ok(data.len())
+    }
+
+    // Waits until everything written has been taken.
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            self.drain()?;
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+            self.stalled()?;
+            sleep(WRITE_POLL_INTERVAL);
+        }
+    }
+}
+
+impl Segmented for Downstream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+}
@@ -0,0 +1,76 @@
+// Door drop files, for a backend that runs a door itself with the proxy in
+// the BBS's place: DOOR.SYS in the 52-line GAP layout or DORINFO1.DEF,
+// written to [dropfile] path as each session starts. A door learns the
+// caller's node, name, address (as where they are calling from), baud and
+// time left from it; what only a real BBS would know, such as upload counts,
+// is filled in with harmless values. Lines end in CR LF, as DOS doors expect.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::chat;
+use crate::clock::now_timestamp;
+use crate::config::{DropFileConfig, DropFileFormat};
+
+// What a door is told is left of a call with no time limit: a day.
+const UNLIMITED_MINUTES: u64 = 1440;
+
+pub struct Caller<'a> {
+    pub node: u32,
+    pub name: &'a str,
+    pub ip_addr: IpAddr,
+    pub ansi: bool,
+    // None when the call has no time limit.
+    pub remaining: Option<Duration>,
+}
+
+// Writes the file, returning where it went.
+pub fn write(config: &DropFileConfig, bbs_name: &str, caller: &Caller) -> io::Result<PathBuf> {
+    let path = PathBuf::from(chat::render(&config.path, &[("node", caller.node.to_string())]));
+    let lines = match config.format {
+        DropFileFormat::DoorSys => door_sys(config, caller),
+        DropFileFormat::Dorinfo => dorinfo(config, bbs_name, caller),
+    };
+    fs::write(&path, lines.iter().map(|line| format!("{}\r\n", line)).collect::<String>())?;
+    Ok(path)
+}
+
+fn door_sys(config: &DropFileConfig, caller: &Caller) -> Vec<String> {
+    let seconds = caller.remaining.map_or(UNLIMITED_MINUTES * 60, |remaining| remaining.as_secs());
+    // "YYYY-MM-DD HH:MM:SS", of which DOOR.SYS wants MM/DD/YY and HH:MM.
+    let now = now_timestamp();
+    let date = format!("{}/{}/{}", &now[5..7], &now[8..10], &now[2..4]);
+    let time = now[11..16].to_string();
+    let baud = config.baud.to_string();
+    [
+        "COM1:", &baud, "8", &caller.node.to_string(), &baud, "Y", "N", "Y", "Y", caller.name, &caller.ip_addr.to_string(),
+        "", "", "", "10", "1", &date, &seconds.to_string(), &(seconds / 60).to_string(),
+        if caller.ansi { "GR" } else { "NG" }, "24", "N", "", "", "12/31/99", "1", "Z", "0", "0", "0", "999999",
+        "01/01/70", "", "", &config.sysop, caller.name, "00:00", "Y", "N", "N", "7", "0", &date, &time, &time,
+        "999", "0", "0", "0", "", "0", "0",
+    ].map(String::from).to_vec()
+}
+
+fn dorinfo(config: &DropFileConfig, bbs_name: &str, caller: &Caller) -> Vec<String> {
+    let minutes = caller.remaining.map_or(UNLIMITED_MINUTES, |remaining| remaining.as_secs() / 60);
+    let (sysop_first, sysop_last) = config.sysop.split_once(' ').unwrap_or((&config.sysop, ""));
+    let (first, last) = caller.name.split_once(' ').unwrap_or((caller.name, ""));
+    vec![
+        bbs_name.to_string(),
+        sysop_first.to_string(),
+        sysop_last.to_string(),
+        String::from("COM1"),
+        format!("{} BAUD,N,8,1", config.baud),
+        String::from("0"),
+        first.to_string(),
+        last.to_string(),
+        caller.ip_addr.to_string(),
+        String::from(if caller.ansi { "1" } else { "0" }),
+        String::from("10"),
+        minutes.to_string(),
+        String::from("-1"),
+    ]
+}
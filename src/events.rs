@@ -0,0 +1,151 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use telnet::TelnetOption;
+use uuid::Uuid;
+
+use crate::bans::Ban;
+use crate::daily::DailySummary;
+use crate::log;
+use crate::middleware::SessionInfo;
+
+// Events a subscriber may fall behind by. One further behind than this is
+// dropped, so that a stuck subscriber can't hold up publishers or grow
+// without limit.
+const SUBSCRIBER_QUEUE: usize = 4096;
+
+// Things that happen to sessions, for anything that wants to react to them
+// without being wired into the relay or the client manager.
+#[derive(Clone, Debug)]
+pub enum Event {
+    // The backend was dialed and the relay is starting.
+    Connected(SessionInfo),
+    // The proxy answered a negotiation from the backend.
+    Negotiated { client_id: Uuid, action: &'static str, option: TelnetOption },
+    // Bytes forwarded since the previous report for this session.
+    BytesRelayed { client_id: Uuid, to_backend: u64, to_client: u64 },
+    Closed { session: SessionInfo, duration: Duration },
+    Banned(Ban),
+    Error { client_id: Uuid, ip_addr: IpAddr, message: String },
+    // The last session on a draining backend has closed.
+    Drained { backend: String },
+    // A day's figures, at its [daily_stats] rollover.
+    Daily(DailySummary),
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Connected(_) => "connected",
+            Event::Negotiated { .. } => "negotiated",
+            Event::BytesRelayed { .. } => "bytes",
+            Event::Closed { .. } => "closed",
+            Event::Banned(_) => "banned",
+            Event::Error { .. } => "error",
+            Event::Drained { .. } => "drained",
+            Event::Daily(_) => "daily",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<10} ", self.name())?;
+        match self {
+            Event::Connected(session) => write!(f, "{} {} -> {}", session.client_id, session.ip_addr, session.backend),
+            Event::Negotiated { client_id, action, option } => write!(f, "{} {} {:?}", client_id, action, option),
+            Event::BytesRelayed { client_id, to_backend, to_client } => {
+                write!(f, "{} to backend: {} to client: {}", client_id, to_backend, to_client)
+            }
+            Event::Closed { session, duration } => {
+                write!(f, "{} {} after {}s", session.client_id, session.ip_addr, duration.as_secs())
+            }
+            Event::Banned(ban) => write!(f, "{} for {}s: {}", ban.ip_addr, ban.remaining().as_secs(), ban.reason),
+            Event::Error { client_id, ip_addr, message } => write!(f, "{} {} {}", client_id, ip_addr, message),
+            Event::Drained { backend } => write!(f, "{}", backend),
+            Event::Daily(summary) => write!(f, "{} {} calls from {} addresses, peak {}, {} bytes to backends, {} to callers", summary.date,
+                                            summary.calls, summary.unique_ips, summary.peak_sessions, summary.to_backend, summary.to_client),
+        }
+    }
+}
+
+// Fans each published event out to every subscriber. Subscribers that have
+// gone away, or fallen SUBSCRIBER_QUEUE behind, are dropped on the next
+// publish.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = bounded(SUBSCRIBER_QUEUE);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    // Events waiting to be taken by each subscriber.
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.subscribers.lock().unwrap().iter().map(Sender::len).collect()
+    }
+
+    pub fn publish(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log!(Server, Warn, "Dropped an event subscriber that fell {} events behind", SUBSCRIBER_QUEUE);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drained() -> Event {
+        Event::Drained { backend: String::from("main") }
+    }
+
+    #[test]
+    fn delivers_to_every_subscriber() {
+        let events = EventBus::default();
+        let (one, other) = (events.subscribe(), events.subscribe());
+        events.publish(drained());
+        assert_eq!(one.try_recv().unwrap().name(), "drained");
+        assert_eq!(other.try_recv().unwrap().name(), "drained");
+    }
+
+    #[test]
+    fn drops_subscribers_that_have_gone_away() {
+        let events = EventBus::default();
+        let kept = events.subscribe();
+        drop(events.subscribe());
+        events.publish(drained());
+        assert_eq!(events.queue_depths(), vec![1]);
+        assert!(kept.try_recv().is_ok());
+    }
+
+    #[test]
+    fn drops_a_subscriber_that_falls_too_far_behind() {
+        let events = EventBus::default();
+        let stuck = events.subscribe();
+        let keeping_up = events.subscribe();
+        for _ in 0..SUBSCRIBER_QUEUE {
+            events.publish(drained());
+            assert!(keeping_up.try_recv().is_ok());
+        }
+        assert_eq!(events.queue_depths(), vec![SUBSCRIBER_QUEUE, 0]);
+        events.publish(drained());
+        assert_eq!(events.queue_depths(), vec![1]);
+        // What it had queued is still there, and then it's over.
+        assert_eq!(stuck.iter().count(), SUBSCRIBER_QUEUE);
+        assert!(keeping_up.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,102 @@
+// What goes wrong in sessions. A session reports each fault to the client
+// manager instead of logging it where it happened, so faults are logged,
+// counted and published as events in one place, and whatever is decided
+// about them later has one place to go. Faults with a backend are also
+// counted against it, with the last one kept, so whether it's the BBS or the
+// proxy that's down shows in the admin status.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::clock::unix_time;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    // A backend couldn't be dialed, or re-dialed.
+    Connect,
+    // A backend didn't answer a dial in time.
+    Timeout,
+    // Writing to the caller or to a backend failed.
+    Write,
+    // A backend went over the [negotiation] limits.
+    Negotiation,
+    // The connection to a backend was lost mid-session: reading from it
+    // failed, or one with a redial_window hung up.
+    Lost,
+    // A caller fell further behind the output than server.pending_output.
+    Overflow,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 6] = [Fault::Connect, Fault::Timeout, Fault::Write, Fault::Negotiation, Fault::Lost, Fault::Overflow];
+
+    // The fault a failed dial is.
+    pub fn dialing(error: &io::Error) -> Fault {
+        match error.kind() {
+            io::ErrorKind::TimedOut => Fault::Timeout,
+            _ => Fault::Connect,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::Connect => "connect",
+            Fault::Timeout => "timeout",
+            Fault::Write => "write",
+            Fault::Negotiation => "negotiation",
+            Fault::Lost => "lost",
+            Fault::Overflow => "overflow",
+        }
+    }
+}
+
+// One backend's faults since startup. A session cut short by the backend,
+// however it happened, is a drop.
+#[derive(Clone, Debug, Default)]
+pub struct BackendFaults {
+    pub connect_failures: u64,
+    pub timeouts: u64,
+    pub drops: u64,
+    // What the last one was, and when (Unix time).
+    pub last: Option<(String, u64)>,
+}
+
+// How many of each fault the manager has seen since startup.
+#[derive(Clone, Default)]
+pub struct FaultCounts {
+    counts: Arc<[AtomicU64; Fault::ALL.len()]>,
+    backends: Arc<Mutex<BTreeMap<String, BackendFaults>>>,
+}
+
+impl FaultCounts {
+    pub fn count(&self, fault: Fault, backend: Option<&str>, detail: &str) {
+        self.counts[fault as usize].fetch_add(1, Ordering::Relaxed);
+        let Some(backend) = backend else {
+            return;
+        };
+        let mut backends = self.backends.lock().unwrap();
+        let faults = backends.entry(backend.to_string()).or_default();
+        match fault {
+            Fault::Connect => faults.connect_failures += 1,
+            Fault::Timeout => faults.timeouts += 1,
+            Fault::Write | Fault::Negotiation | Fault::Lost | Fault::Overflow => faults.drops += 1,
+        }
+        faults.last = Some((detail.to_string(), unix_time()));
+    }
+
+    pub fn get(&self, fault: Fault) -> u64 {
+        self.counts[fault as usize].load(Ordering::Relaxed)
+    }
+
+    // All zeros for a backend that hasn't had any.
+    pub fn backend(&self, name: &str) -> BackendFaults {
+        self.backends.lock().unwrap().get(name).cloned().unwrap_or_default()
+    }
+
+    // As the admin status shows them: "connect 2, timeout 0, ...".
+    pub fn describe(&self) -> String {
+        Fault::ALL.iter().map(|&fault| format!("{} {}", fault.name(), self.get(fault))).collect::<Vec<_>>().join(", ")
+    }
+}
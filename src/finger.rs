@@ -0,0 +1,78 @@
+// Read-only "who's online" listener for other retro services and scripts. It
+// answers a finger query (or nothing at all, for a plain `nc`) with the
+// callers currently connected and hangs up. No addresses, client ids or sysop
+// labels are shown, since anyone can ask.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::FingerConfig;
+use crate::handover;
+use crate::log;
+use crate::version;
+use crate::ServerContext;
+
+// How long a client gets to send its query before the full list is sent.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn launch_finger_server(config: &FingerConfig, context: ServerContext) {
+    let listener = match handover::bind("finger", &config.address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            log!(Server, Error, "Unable to bind finger listener on {}: {}", config.address, error);
+            return;
+        }
+    };
+    log!(Server, Info, "Finger Interface Listening on: {}", config.address);
+    let _ = thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let context = context.clone();
+            let _ = thread::spawn(move || {
+                let _ = answer(stream, &context);
+            });
+        }
+    });
+}
+
+fn answer(stream: TcpStream, context: &ServerContext) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut query = String::new();
+    let _ = BufReader::new(stream).read_line(&mut query);
+    // "/W" asks for verbose output, which is all there is anyway.
+    let query = query.trim().trim_start_matches("/W").trim();
+
+    let mut clients = context.clients.values();
+    clients.sort_by_key(|client| client.connected_at);
+    let online: Vec<_> = clients.iter().filter(|client| client.backend.is_some()).collect();
+    let mut lines = vec![
+        format!("TriServer {} - {} caller(s) online, up {}", version::VERSION, online.len(),
+                describe_duration(context.started.elapsed())),
+        String::new(),
+    ];
+    let matching: Vec<_> = online.iter()
+        .filter(|client| query.is_empty() || client.username.as_deref().is_some_and(|username| username.eq_ignore_ascii_case(query)))
+        .collect();
+    if matching.is_empty() && !query.is_empty() {
+        lines.push(format!("{} is not online.", query));
+    } else {
+        lines.push(format!("{:<16} {:<16} {}", "User", "System", "On for"));
+        for client in matching {
+            lines.push(format!("{:<16} {:<16} {}", client.username.as_deref().unwrap_or("(guest)"),
+                               client.backend.as_deref().unwrap_or_default(), describe_duration(client.connected_at.elapsed())));
+        }
+    }
+    let reply: String = lines.iter().map(|line| format!("{}\r\n", line)).collect();
+    writer.write_all(reply.as_bytes())
+}
+
+fn describe_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    if minutes >= 24 * 60 {
+        format!("{}d {}h", minutes / (24 * 60), minutes / 60 % 24)
+    } else {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    }
+}
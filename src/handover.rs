@@ -0,0 +1,202 @@
+// Zero-downtime upgrades. On SIGUSR2 or the admin "upgrade" command the server
+// starts a fresh copy of its executable and passes it the listening sockets.
+// Once the new process reports ready, the old one stops accepting and exits
+// after its remaining sessions finish. The sockets are never closed, so
+// callers connecting during the upgrade aren't refused.
+//
+// Listening sockets are passed as inherited file descriptors named in
+// TRISERVER_LISTEN_FDS ("telnet=3,admin=4"). The new process writes a byte
+// to the TRISERVER_READY_FD pipe once it is accepting. The locked PID file
+// goes along in TRISERVER_LOCK_FD.
+
+use std::io;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(unix)]
+use std::sync::{Mutex, OnceLock};
+
+use crate::log;
+
+const LISTEN_FDS_VAR: &str = "TRISERVER_LISTEN_FDS";
+const READY_FD_VAR: &str = "TRISERVER_READY_FD";
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Raw descriptors of every listener bound through `bind`, by name.
+#[cfg(unix)]
+static LISTENERS: Mutex<Vec<(&'static str, i32)>> = Mutex::new(Vec::new());
+#[cfg(unix)]
+static INHERITED: OnceLock<Mutex<Vec<(String, i32)>>> = OnceLock::new();
+
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// True once per request.
+pub fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+// Binds a listener, or takes over the one the previous process passed down
+// under the same name.
+#[cfg(unix)]
+pub fn bind(name: &'static str, address: &str) -> io::Result<TcpListener> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    let inherited = INHERITED.get_or_init(|| {
+        let fds = std::env::var(LISTEN_FDS_VAR).unwrap_or_default();
+        // Hooks and later upgrades shouldn't see our copy.
+        std::env::remove_var(LISTEN_FDS_VAR);
+        Mutex::new(fds.split(',')
+            .filter_map(|entry| entry.split_once('='))
+            .filter_map(|(name, fd)| Some((name.to_string(), fd.parse().ok()?)))
+            .collect())
+    });
+    let fd = {
+        let mut inherited = inherited.lock().unwrap();
+        let position = inherited.iter().position(|(inherited_name, _)| inherited_name == name);
+        position.map(|i| inherited.remove(i).1)
+    };
+    let listener = match fd {
+        Some(fd) => {
+            log!(Server, Info, "Took over the {} listener from the previous process", name);
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            set_close_on_exec(fd, true);
+            listener
+        }
+        None => TcpListener::bind(address)?,
+    };
+    LISTENERS.lock().unwrap().push((name, listener.as_raw_fd()));
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+pub fn bind(_name: &'static str, address: &str) -> io::Result<TcpListener> {
+    TcpListener::bind(address)
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::c_int;
+
+    pub const F_GETFD: c_int = 1;
+    pub const F_SETFD: c_int = 2;
+    pub const FD_CLOEXEC: c_int = 1;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+    pub const SIGUSR2: c_int = 31;
+    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")))]
+    pub const SIGUSR2: c_int = 12;
+
+    extern "C" {
+        pub fn fcntl(fd: c_int, command: c_int, ...) -> c_int;
+        pub fn pipe(fds: *mut c_int) -> c_int;
+        pub fn signal(signal: c_int, handler: usize) -> usize;
+    }
+}
+
+#[cfg(unix)]
+pub fn set_close_on_exec(fd: i32, close: bool) {
+    unsafe {
+        let flags = sys::fcntl(fd, sys::F_GETFD);
+        let flags = if close { flags | sys::FD_CLOEXEC } else { flags & !sys::FD_CLOEXEC };
+        sys::fcntl(fd, sys::F_SETFD, flags);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigusr2(_signal: std::ffi::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// SIGUSR2 asks for an upgrade, as with nginx.
+pub fn install_signal_handler() {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGUSR2, on_sigusr2 as extern "C" fn(std::ffi::c_int) as usize);
+    }
+}
+
+// Starts the new process with our listeners and waits for it to report ready.
+// Returns its PID.
+#[cfg(unix)]
+pub fn spawn_successor(timeout: std::time::Duration) -> Result<u32, String> {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+    use std::process::Command;
+
+    let executable = std::env::current_exe().map_err(|error| error.to_string())?;
+    // The new process inherits our place in the background.
+    let args: Vec<String> = std::env::args().skip(1).filter(|arg| arg != "--daemon" && arg != "-d").collect();
+
+    let mut fds = [0; 2];
+    if unsafe { sys::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(format!("pipe: {}", io::Error::last_os_error()));
+    }
+    let mut ready = unsafe { File::from_raw_fd(fds[0]) };
+    let ready_writer = unsafe { File::from_raw_fd(fds[1]) };
+    set_close_on_exec(fds[0], true);
+
+    let listeners = LISTENERS.lock().unwrap().clone();
+    let lock = crate::instance::raw_fd();
+    for fd in listeners.iter().map(|(_, fd)| fd).chain(&lock) {
+        set_close_on_exec(*fd, false);
+    }
+    let mut command = Command::new(&executable);
+    command.args(&args)
+        .env(LISTEN_FDS_VAR, listeners.iter().map(|(name, fd)| format!("{}={}", name, fd)).collect::<Vec<_>>().join(","))
+        .env(READY_FD_VAR, fds[1].to_string());
+    if let Some(fd) = lock {
+        command.env(crate::instance::LOCK_FD_VAR, fd.to_string());
+    }
+    let spawned = command.spawn();
+    for fd in listeners.iter().map(|(_, fd)| fd).chain(&lock) {
+        set_close_on_exec(*fd, true);
+    }
+    drop(ready_writer);
+    let mut child = spawned.map_err(|error| format!("unable to start {}: {}", executable.display(), error))?;
+
+    // Read on another thread so a new process that hangs can be given up on.
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let _ = std::thread::spawn(move || {
+        let mut byte = [0u8; 1];
+        let _ = sender.send(matches!(ready.read(&mut byte), Ok(1)));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(true) => {
+            let pid = child.id();
+            // It stays our child until we exit; reap it if it goes first so
+            // `TriServer stop` doesn't wait on a zombie.
+            let _ = std::thread::spawn(move || child.wait());
+            Ok(pid)
+        }
+        Ok(false) => {
+            let _ = child.wait();
+            Err(format!("PID {} exited before it was ready", child.id()))
+        }
+        Err(_) => {
+            // Otherwise both processes would go on accepting.
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(format!("PID {} was not ready within {} seconds", child.id(), timeout.as_secs()))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn spawn_successor(_timeout: std::time::Duration) -> Result<u32, String> {
+    Err(String::from("upgrades without downtime are only supported on Unix"))
+}
+
+// Called by the new process once it is accepting callers.
+pub fn report_ready() {
+    #[cfg(unix)]
+    if let Some(fd) = std::env::var(READY_FD_VAR).ok().and_then(|fd| fd.parse::<i32>().ok()) {
+        use std::io::Write;
+        use std::os::fd::FromRawFd;
+        std::env::remove_var(READY_FD_VAR);
+        let mut pipe = unsafe { std::fs::File::from_raw_fd(fd) };
+        let _ = pipe.write_all(b"1");
+    }
+}
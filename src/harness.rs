@@ -0,0 +1,349 @@
+// In-process servers, backends and clients for end-to-end tests. A test
+// starts a ScriptedBackend (or mock::start), a TestServer pointed at it, and
+// drives TestClients through scripts of sends and expected replies. All of
+// them bind ephemeral ports on 127.0.0.1, so tests can run side by side.
+
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver};
+use uuid::Uuid;
+
+use crate::cli::ServeMode;
+use crate::config::{BackendConfig, ChaosConfig, Config, InputFilter, OutputFilter};
+use crate::lifecycle::SessionState;
+use crate::log;
+use crate::users::UserStore;
+use crate::{run_server, ClientConnection, SessionControl, SharedClientMap};
+
+// How long an Expect step waits before the script fails.
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// One step of a backend or client script.
+#[derive(Clone, Debug)]
+pub enum Step {
+    Send(Vec<u8>),
+    // Reads until these bytes have arrived; anything before them is skipped.
+    Expect(Vec<u8>),
+    // Reads until the other side closes.
+    ExpectClosed,
+    Sleep(Duration),
+    Close,
+}
+
+impl Step {
+    pub fn send(data: impl AsRef<[u8]>) -> Self {
+        Step::Send(data.as_ref().to_vec())
+    }
+
+    pub fn expect(data: impl AsRef<[u8]>) -> Self {
+        Step::Expect(data.as_ref().to_vec())
+    }
+}
+
+// A config that relays to `backend` and leaves every optional feature off.
+pub fn config(backend: SocketAddr) -> Config {
+    let mut config = Config::default();
+    config.server.address = Some(String::from("127.0.0.1"));
+    config.backends = vec![BackendConfig {
+        name: String::from("test"),
+        host: backend.ip().to_string(),
+        port: backend.port(),
+        options: BTreeMap::new(),
+        output: OutputFilter::Raw,
+        input: InputFilter::Pass,
+        sndloc: String::from("{ip}"),
+        logout: None,
+        redial_window: None,
+        telnet: true,
+        serial: None,
+        socket: None,
+        ssh: None,
+        rlogin: None,
+    }];
+    config
+}
+
+// A client map entry with no session behind it, for exercising the map on
+// its own. Controls sent to it go nowhere.
+pub fn idle_connection(ip_addr: IpAddr) -> (Uuid, ClientConnection) {
+    let client_id = Uuid::new_v4();
+    let (control, _) = unbounded();
+    (client_id, ClientConnection { client_id, listener: "telnet", ip_addr, control, node: 1, connected_at: Instant::now(), backend: None,
+                                   username: None, terminal: None, terminal_class: None, negotiation: None, label: None, state: SessionState::Active, state_since: Instant::now(),
+                                   held: false })
+}
+
+// The proxy running on a background thread until `stop` or drop.
+pub struct TestServer {
+    address: SocketAddr,
+    clients: SharedClientMap,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start(config: Config) -> io::Result<Self> {
+        Self::start_with_users(config, None)
+    }
+
+    pub fn start_with_users(config: Config, user_store: Option<Arc<UserStore>>) -> io::Result<Self> {
+        log::configure(&config);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let clients = SharedClientMap::new();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let clients = clients.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                run_server(listener, clients, Arc::new(config), user_store, ServeMode::Foreground,
+                           || !stopping.load(Ordering::SeqCst))
+            })
+        };
+        Ok(Self { address, clients, stopping, thread: Some(thread) })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn sessions(&self) -> usize {
+        self.clients.len()
+    }
+
+    // Sessions whose caller dropped and which are held for [resume].
+    pub fn held_sessions(&self) -> usize {
+        self.clients.values().iter().filter(|client| client.held).count()
+    }
+
+    // Waits up to STEP_TIMEOUT for the session count to reach `count`.
+    pub fn wait_for_sessions(&self, count: usize) -> Result<(), String> {
+        wait_for(count, "sessions", || self.sessions())
+    }
+
+    // Waits up to STEP_TIMEOUT for the held session count to reach `count`.
+    pub fn wait_for_held(&self, count: usize) -> Result<(), String> {
+        wait_for(count, "held sessions", || self.held_sessions())
+    }
+
+    // Gives every current session these faults, as the admin "chaos"
+    // command does for one; no [chaos] section is needed. Returns how many
+    // sessions were told.
+    pub fn inject(&self, faults: ChaosConfig) -> usize {
+        let clients = self.clients.values();
+        for client in &clients {
+            let _ = client.control.send(SessionControl::Chaos(faults.clone()));
+        }
+        clients.len()
+    }
+
+    // Shuts down as the real server does, disconnecting every session, and
+    // returns once it has finished.
+    pub fn stop(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+fn wait_for(count: usize, what: &str, current: impl Fn() -> usize) -> Result<(), String> {
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    while current() != count {
+        if Instant::now() >= deadline {
+            return Err(format!("expected {} {}, found {}", count, what, current()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+// A backend that runs the same script for every connection and reports how
+// each run went.
+pub struct ScriptedBackend {
+    address: SocketAddr,
+    results: Receiver<Result<(), String>>,
+}
+
+impl ScriptedBackend {
+    pub fn start(script: Vec<Step>) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let (sender, results) = unbounded();
+        let _ = thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let script = script.clone();
+                let sender = sender.clone();
+                let _ = thread::spawn(move || {
+                    let mut peer = Peer { stream, received: Vec::new() };
+                    let _ = sender.send(peer.run(&script));
+                });
+            }
+        });
+        Ok(Self { address, results })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // The outcome of the next connection's script.
+    pub fn finished(&self, timeout: Duration) -> Result<(), String> {
+        self.results.recv_timeout(timeout).map_err(|_| String::from("no backend connection finished in time"))?
+    }
+}
+
+// A caller connected to a TestServer.
+pub struct TestClient {
+    peer: Peer,
+}
+
+impl TestClient {
+    pub fn connect(address: SocketAddr) -> io::Result<Self> {
+        Ok(Self { peer: Peer { stream: TcpStream::connect(address)?, received: Vec::new() } })
+    }
+
+    pub fn run(&mut self, script: &[Step]) -> Result<(), String> {
+        self.peer.run(script)
+    }
+
+    pub fn send(&mut self, data: impl AsRef<[u8]>) -> Result<(), String> {
+        self.peer.run(&[Step::send(data)])
+    }
+
+    // Returns everything received up to and including `data`.
+    pub fn expect(&mut self, data: impl AsRef<[u8]>) -> Result<Vec<u8>, String> {
+        self.peer.expect(data.as_ref())
+    }
+
+    // Returns everything received before the server closed the connection.
+    pub fn expect_closed(&mut self) -> Result<Vec<u8>, String> {
+        self.peer.expect_closed()
+    }
+
+    // Drops the connection with a reset rather than a FIN, as a caller whose
+    // line went does.
+    pub fn reset(self) -> io::Result<()> {
+        set_option(&self.peer.stream, SocketOption::Linger(0))
+    }
+
+    // Keeps what waits for this client to read small, so the server's writes
+    // back up soon after it stops reading, as they do to a slow line.
+    pub fn shrink_receive_buffer(&self) -> io::Result<()> {
+        set_option(&self.peer.stream, SocketOption::ReceiveBuffer(4096))
+    }
+}
+
+enum SocketOption {
+    // SO_LINGER, in seconds: 0 has closing reset the connection.
+    Linger(i32),
+    // SO_RCVBUF, in bytes.
+    ReceiveBuffer(i32),
+}
+
+#[cfg(unix)]
+fn set_option(stream: &TcpStream, option: SocketOption) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    fn set<T>(stream: &TcpStream, name: libc::c_int, value: T) -> io::Result<()> {
+        let set = unsafe {
+            libc::setsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, name, (&value as *const T).cast(), std::mem::size_of::<T>() as libc::socklen_t)
+        };
+        match set {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+    match option {
+        SocketOption::Linger(seconds) => set(stream, libc::SO_LINGER, libc::linger { l_onoff: 1, l_linger: seconds }),
+        SocketOption::ReceiveBuffer(size) => set(stream, libc::SO_RCVBUF, size),
+    }
+}
+
+// Elsewhere the socket is left as it is.
+#[cfg(not(unix))]
+fn set_option(_: &TcpStream, _: SocketOption) -> io::Result<()> {
+    Ok(())
+}
+
+struct Peer {
+    stream: TcpStream,
+    // Read but not yet matched by an Expect step.
+    received: Vec<u8>,
+}
+
+impl Peer {
+    fn run(&mut self, script: &[Step]) -> Result<(), String> {
+        for (i, step) in script.iter().enumerate() {
+            let result = match step {
+                Step::Send(data) => self.stream.write_all(data).map_err(|error| error.to_string()),
+                Step::Expect(data) => self.expect(data).map(drop),
+                Step::ExpectClosed => self.expect_closed().map(drop),
+                Step::Sleep(duration) => {
+                    thread::sleep(*duration);
+                    Ok(())
+                }
+                Step::Close => {
+                    let _ = self.stream.shutdown(std::net::Shutdown::Both);
+                    Ok(())
+                }
+            };
+            result.map_err(|error| format!("step {} ({:?}): {}", i + 1, step, error))?;
+        }
+        Ok(())
+    }
+
+    fn expect(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let deadline = Instant::now() + STEP_TIMEOUT;
+        loop {
+            if let Some(position) = self.received.windows(data.len()).position(|window| window == data) {
+                let matched = self.received.drain(..position + data.len()).collect();
+                return Ok(matched);
+            }
+            match self.read(deadline)? {
+                0 => return Err(format!("closed; received {:?}", String::from_utf8_lossy(&self.received))),
+                _ => continue,
+            }
+        }
+    }
+
+    fn expect_closed(&mut self) -> Result<Vec<u8>, String> {
+        let deadline = Instant::now() + STEP_TIMEOUT;
+        while self.read(deadline)? > 0 {}
+        Ok(std::mem::take(&mut self.received))
+    }
+
+    fn read(&mut self, deadline: Instant) -> Result<usize, String> {
+        let remaining = deadline.checked_duration_since(Instant::now()).filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| format!("timed out; received {:?}", String::from_utf8_lossy(&self.received)))?;
+        self.stream.set_read_timeout(Some(remaining)).map_err(|error| error.to_string())?;
+        let mut buffer = [0u8; 1024];
+        match self.stream.read(&mut buffer) {
+            Ok(size) => {
+                self.received.extend_from_slice(&buffer[..size]);
+                Ok(size)
+            }
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(format!("timed out; received {:?}", String::from_utf8_lossy(&self.received)))
+            }
+            Err(error) if error.kind() == ErrorKind::ConnectionReset => Ok(0),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::BackendConfig;
+use crate::live::LiveConfig;
+use crate::local::LocalSocket;
+use crate::log;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct BackendStatus {
+    pub name: String,
+    // None until the first probe has finished.
+    pub healthy: Option<bool>,
+    pub checked_at: Option<Instant>,
+    pub last_error: Option<String>,
+}
+
+// What readiness checks need to know: whether the telnet listener is up and
+// whether the backends answered their last probe.
+#[derive(Clone, Default)]
+pub struct Health {
+    listening: Arc<AtomicBool>,
+    backends: Arc<Mutex<Vec<BackendStatus>>>,
+}
+
+impl Health {
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    pub fn backends(&self) -> Vec<BackendStatus> {
+        self.backends.lock().unwrap().clone()
+    }
+
+    // Whether the backend's last probe failed. Backends that haven't been
+    // probed, or aren't probed at all, aren't down.
+    pub fn is_down(&self, name: &str) -> bool {
+        self.backends.lock().unwrap().iter().any(|status| status.name == name && status.healthy == Some(false))
+    }
+
+    pub fn any_backend_healthy(&self) -> bool {
+        self.backends.lock().unwrap().iter().any(|status| status.healthy == Some(true))
+    }
+
+    // Opens and immediately closes a TCP or socket connection to each backend
+    // on an interval, or checks a serial backend's device is there (opening it
+    // would take it from a caller). The first round runs straight away. Each
+    // round probes the backends as they are then, so ones added from the admin
+    // interface are picked up and removed ones are dropped.
+    pub fn launch_probes(&self, live: LiveConfig, interval: Duration) {
+        let health = self.clone();
+        let _ = thread::spawn(move || loop {
+            let config = live.current();
+            health.track(&config.backends);
+            for backend in &config.backends {
+                let result = match (&backend.serial, &backend.socket) {
+                    (Some(serial), _) => probe_device(&serial.device),
+                    (_, Some(socket)) => LocalSocket::probe(socket).map_err(|error| error.to_string()),
+                    _ => probe(&backend.host, backend.port),
+                };
+                let mut statuses = health.backends.lock().unwrap();
+                let Some(status) = statuses.iter_mut().find(|status| status.name == backend.name) else {
+                    continue;
+                };
+                if status.healthy.is_some_and(|healthy| healthy != result.is_ok()) {
+                    match &result {
+                        Ok(()) => log!(Server, Info, "Backend {} is reachable again", backend.name),
+                        Err(error) => log!(Server, Warn, "Backend {} is unreachable: {}", backend.name, error),
+                    }
+                }
+                status.healthy = Some(result.is_ok());
+                status.checked_at = Some(Instant::now());
+                if let Err(error) = result {
+                    status.last_error = Some(error);
+                }
+            }
+            sleep(interval);
+        });
+    }
+
+    // Keeps the statuses of backends still there, in the order they are now.
+    fn track(&self, backends: &[BackendConfig]) {
+        let mut statuses = self.backends.lock().unwrap();
+        *statuses = backends
+            .iter()
+            .map(|backend| {
+                statuses.iter().find(|status| status.name == backend.name).cloned().unwrap_or(BackendStatus {
+                    name: backend.name.clone(),
+                    healthy: None,
+                    checked_at: None,
+                    last_error: None,
+                })
+            })
+            .collect();
+    }
+}
+
+fn probe(host: &str, port: u16) -> Result<(), String> {
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|error| error.to_string())?
+        .next()
+        .ok_or_else(|| String::from("no address"))?;
+    TcpStream::connect_timeout(&address, PROBE_TIMEOUT).map(drop).map_err(|error| error.to_string())
+}
+
+fn probe_device(device: &Path) -> Result<(), String> {
+    std::fs::metadata(device).map(drop).map_err(|error| error.to_string())
+}
@@ -0,0 +1,125 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::clock::now_timestamp;
+use crate::config::HoneypotConfig;
+use crate::downstream::Downstream;
+use crate::log;
+
+const SESSION_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_LINE_LENGTH: usize = 512;
+const MAX_LINES: usize = 50;
+
+// A fake login and shell that records everything the caller sends. Flagged
+// sources never reach a real backend.
+pub fn run(stream: &mut Downstream, client_id: Uuid, ip_addr: IpAddr, config: &HoneypotConfig) {
+    let log = |kind: &str, data: &[u8]| {
+        if let Err(error) = append(&config.log, client_id, ip_addr, kind, data) {
+            log!(Server, Warn, "Unable to write honeypot log {}: {}", config.log.display(), error);
+        }
+    };
+
+    log("connect", b"");
+    let deadline = Instant::now() + SESSION_TIMEOUT;
+    let mut reader = RawLineReader::default();
+    let mut lines = 0;
+
+    let _ = stream.write_all(format!("\r\n{}\r\n\r\nlogin: ", config.banner).as_bytes());
+    let mut stage = Stage::Login;
+    while lines < MAX_LINES {
+        let Some(line) = reader.read_line(stream, deadline) else {
+            break;
+        };
+        lines += 1;
+        let reply: &[u8] = match stage {
+            Stage::Login => {
+                log("login", &line);
+                stage = Stage::Password;
+                b"Password: "
+            }
+            Stage::Password => {
+                log("password", &line);
+                stage = Stage::Shell;
+                b"\r\n$ "
+            }
+            Stage::Shell => {
+                log("command", &line);
+                if line.trim_ascii() == b"exit" {
+                    break;
+                }
+                b"sh: command not found\r\n$ "
+            }
+        };
+        if stream.write_all(reply).is_err() {
+            break;
+        }
+    }
+    log("disconnect", b"");
+}
+
+enum Stage {
+    Login,
+    Password,
+    Shell,
+}
+
+fn append(path: &Path, client_id: Uuid, ip_addr: IpAddr, kind: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {} {} {} {}", now_timestamp(), ip_addr, client_id, kind, escape(data))
+}
+
+// Printable ASCII is kept, everything else (including telnet commands) is
+// written as \xNN so probes can be reconstructed byte for byte.
+fn escape(data: &[u8]) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for byte in data {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7e => escaped.push(*byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+#[derive(Default)]
+struct RawLineReader {
+    pending: Vec<u8>,
+}
+
+impl RawLineReader {
+    // Returns the bytes up to the next CR or LF, or whatever was received when
+    // the caller hangs up or the line grows too long.
+    fn read_line(&mut self, stream: &mut Downstream, deadline: Instant) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 256];
+        loop {
+            if let Some(end) = self.pending.iter().position(|b| *b == b'\r' || *b == b'\n') {
+                let line = self.pending[..end].to_vec();
+                let skip = self.pending[end + 1..].iter().take_while(|b| **b == b'\n' || **b == 0).count();
+                self.pending.drain(..end + 1 + skip);
+                return Some(line);
+            }
+            if self.pending.len() >= MAX_LINE_LENGTH {
+                return Some(self.pending.split_off(0));
+            }
+            match stream.read(&mut buffer) {
+                Ok(0) => return (!self.pending.is_empty()).then(|| self.pending.split_off(0)),
+                Ok(read) => self.pending.extend_from_slice(&buffer[..read]),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return None;
+                    }
+                    sleep(Duration::from_millis(10));
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::config::HooksConfig;
+use crate::events::{Event, EventBus};
+use crate::log;
+use crate::middleware::SessionInfo;
+use crate::span::Span;
+
+// Runs the configured shell commands as sessions start and end. Details are
+// passed in TRISERVER_* environment variables; commands run in the background
+// so a slow script never holds up the relay.
+pub fn launch_command_hooks(config: &HooksConfig, events: &EventBus) {
+    let config = config.clone();
+    let receiver = events.subscribe();
+    let _ = thread::spawn(move || {
+        for event in receiver {
+            match event {
+                Event::Connected(session) => {
+                    if let Some(command) = &config.on_connect {
+                        run("connect", command, &session, None);
+                    }
+                }
+                Event::Closed { session, duration } => {
+                    if let Some(command) = &config.on_disconnect {
+                        run("disconnect", command, &session, Some(duration.as_secs()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+fn run(event: &'static str, command: &str, session: &SessionInfo, duration: Option<u64>) {
+    let mut process = shell(command);
+    process
+        .env("TRISERVER_EVENT", event)
+        .env("TRISERVER_CLIENT_ID", session.client_id.to_string())
+        .env("TRISERVER_SESSION", Span::short_id(session.client_id))
+        .env("TRISERVER_LISTENER", session.listener)
+        .env("TRISERVER_IP", session.ip_addr.to_string())
+        .env("TRISERVER_BACKEND", &session.backend)
+        .env("TRISERVER_USER", session.user.as_ref().map_or("", |user| user.username.as_str()))
+        .stdin(Stdio::null());
+    if let Some(duration) = duration {
+        process.env("TRISERVER_DURATION", duration.to_string());
+    }
+
+    match process.spawn() {
+        Ok(mut child) => {
+            let session = session.clone();
+            let _ = thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => log!(Server, Warn, span = session.span(), "{} hook exited with {}", event, status),
+                Ok(_) => {}
+                Err(error) => log!(Server, Warn, span = session.span(), "Unable to wait for {} hook: {}", event, error),
+            });
+        }
+        Err(error) => log!(Server, Warn, span = session.span(), "Unable to run {} hook: {}", event, error),
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut process = Command::new("cmd");
+    process.arg("/C").arg(command);
+    process
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut process = Command::new("sh");
+    process.arg("-c").arg(command);
+    process
+}
@@ -0,0 +1,157 @@
+// Just enough of an HTTP/1.1 client to deliver webhook posts and push metrics,
+// and to talk to a router's UPnP service.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::tls::TlsStream;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Url {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    // Path and query, always starting with '/'.
+    pub path: String,
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (https, rest) = if let Some(rest) = value.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = value.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("expected an http:// or https:// URL, found '{}'", value));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        // IPv6 literals are bracketed: http://[::1]:8080/
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => return Err(format!("unterminated IPv6 address in '{}'", value)),
+            },
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("invalid port in '{}'", value))?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in '{}'", value));
+        }
+        Ok(Url { https, host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{}://{}:{}{}", scheme, self.host, self.port, self.path)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+pub fn post(url: &Url, headers: &[(&str, String)], body: &[u8], timeout: Duration) -> Result<Response, String> {
+    send("POST", url, headers, body, timeout)
+}
+
+pub fn get(url: &Url, headers: &[(&str, String)], timeout: Duration) -> Result<Response, String> {
+    send("GET", url, headers, &[], timeout)
+}
+
+fn send(method: &str, url: &Url, headers: &[(&str, String)], body: &[u8], timeout: Duration) -> Result<Response, String> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(|error| format!("{}: {}", url.host, error))?
+        .next()
+        .ok_or_else(|| format!("{}: no address", url.host))?;
+    let tcp = TcpStream::connect_timeout(&address, timeout).map_err(|error| format!("{}: {}", address, error))?;
+    tcp.set_read_timeout(Some(timeout)).map_err(|error| error.to_string())?;
+    tcp.set_write_timeout(Some(timeout)).map_err(|error| error.to_string())?;
+
+    let default_port = if url.https { 443 } else { 80 };
+    let host = if url.port == default_port { url.host.clone() } else { format!("{}:{}", url.host, url.port) };
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n", method, url.path, host,
+                              body.len());
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+
+    let response = if url.https {
+        exchange(TlsStream::connect(tcp, &url.host).map_err(|error| error.to_string())?, &request)?
+    } else {
+        exchange(tcp, &request)?
+    };
+    parse_response(&response)
+}
+
+fn exchange(mut stream: impl Read + Write, request: &[u8]) -> Result<Vec<u8>, String> {
+    stream.write_all(request).map_err(|error| error.to_string())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).map_err(|error| error.to_string())?;
+    Ok(response)
+}
+
+fn parse_response(response: &[u8]) -> Result<Response, String> {
+    let end = response.windows(4).position(|window| window == b"\r\n\r\n");
+    let head = String::from_utf8_lossy(&response[..end.unwrap_or(response.len())]);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("malformed response '{}'", status_line))?;
+    let body = end.map_or(&[][..], |end| &response[end + 4..]);
+    let chunked = head.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = if chunked { dechunk(body) } else { body.to_vec() };
+    Ok(Response { status, body })
+}
+
+// What a chunked body holds, up to the last chunk or as far as it got.
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(end) = data.windows(2).position(|window| window == b"\r\n") {
+        let size = String::from_utf8_lossy(&data[..end]);
+        let Ok(size) = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16) else {
+            break;
+        };
+        data = &data[end + 2..];
+        if size == 0 {
+            break;
+        }
+        body.extend_from_slice(&data[..size.min(data.len())]);
+        data = data.get(size + 2..).unwrap_or_default();
+    }
+    body
+}
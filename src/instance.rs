@@ -0,0 +1,81 @@
+// One server to a PID file. A server writes its PID to [daemon] pid_file and
+// holds an exclusive lock on the file for as long as it runs, so a second one
+// started against the same config is turned away before it binds a port or
+// touches anything else, told which PID has it. A file left by a crash isn't
+// locked and is simply taken over. --stdio servers don't take the lock, nor
+// do [workers] processes, which their supervisor holds it for; an upgrade
+// passes the locked file on to the new process with the listeners.
+
+use std::env;
+use std::ffi::c_int;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+
+use crate::handover::set_close_on_exec;
+
+pub const LOCK_FD_VAR: &str = "TRISERVER_LOCK_FD";
+const LOCK_EX: c_int = 2;
+const LOCK_NB: c_int = 4;
+
+extern "C" {
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+}
+
+// Kept open, and so locked, until the process exits.
+static LOCKED: Mutex<Option<File>> = Mutex::new(None);
+
+pub enum LockError {
+    // Another server has the file.
+    Running(String),
+    // The file couldn't be opened or locked at all.
+    Io(String),
+}
+
+// Locks the PID file and writes our PID in it, or takes the locked file over
+// from the process we are upgrading.
+pub fn lock(path: &Path) -> Result<(), LockError> {
+    if let Some(fd) = env::var(LOCK_FD_VAR).ok().and_then(|fd| fd.parse::<i32>().ok()) {
+        // Hooks and later upgrades shouldn't see our copy.
+        env::remove_var(LOCK_FD_VAR);
+        set_close_on_exec(fd, true);
+        *LOCKED.lock().unwrap() = Some(unsafe { File::from_raw_fd(fd) });
+        return Ok(());
+    }
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+        .map_err(|error| LockError::Io(format!("{}: {}", path.display(), error)))?;
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } == -1 {
+        let error = io::Error::last_os_error();
+        if error.kind() != ErrorKind::WouldBlock {
+            return Err(LockError::Io(format!("unable to lock {}: {}", path.display(), error)));
+        }
+        let mut contents = String::new();
+        let _ = file.read_to_string(&mut contents);
+        return Err(LockError::Running(match contents.trim() {
+            "" => format!("another TriServer has {} locked", path.display()),
+            pid => format!("already running as PID {} (see {})", pid, path.display()),
+        }));
+    }
+    *LOCKED.lock().unwrap() = Some(file);
+    write_pid().map_err(|error| LockError::Io(format!("{}: {}", path.display(), error)))
+}
+
+// Puts this process's PID in the locked file, as --daemon does once it has
+// forked.
+pub fn write_pid() -> io::Result<()> {
+    let mut locked = LOCKED.lock().unwrap();
+    let Some(file) = locked.as_mut() else {
+        return Ok(());
+    };
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", process::id())
+}
+
+// For passing the lock on to the process taking over.
+pub fn raw_fd() -> Option<i32> {
+    LOCKED.lock().unwrap().as_ref().map(AsRawFd::as_raw_fd)
+}
@@ -0,0 +1,983 @@
+use std::collections::{HashMap};
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::process::exit;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError, bounded};
+use local_ip_address::local_ip;
+
+use cli::ServeMode;
+use access::{AccessLists, Refusal, launch_access_lists};
+use asn::AsnDatabase;
+use bans::{BanList, Offense};
+use chat::launch_chat;
+use config::{AutobanConfig, ChaosConfig, Config, DuplicatePolicy, TerminalClass, TlsConfig};
+use events::{Event, EventBus};
+use faults::{Fault, FaultCounts};
+use finger::launch_finger_server;
+use health::Health;
+use hooks::launch_command_hooks;
+use lifecycle::SessionState;
+use live::LiveConfig;
+use metrics::Metrics;
+use middleware::MiddlewareChain;
+use motd::CallerCount;
+use notes::Notes;
+use plugins::Plugins;
+use pool::Pools;
+use queries::{ManagerHandle, ManagerStats};
+use relays::RelayThreads;
+use resume::HeldSessions;
+use session::create_client_connection;
+use shutdown::ScheduledShutdown;
+use span::Span;
+use sysop::{ConsoleMessage, Sysop};
+use systemd::Watchdog;
+use users::UserStore;
+use web::launch_http_server;
+use webhook::launch_webhooks;
+
+pub mod admin;
+mod access;
+mod asn;
+mod atascii;
+mod bans;
+mod chaos;
+mod chat;
+pub mod check;
+mod cidr;
+pub mod cli;
+pub mod codec;
+mod clock;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+mod daily;
+mod downstream;
+mod dropfile;
+mod events;
+mod faults;
+mod finger;
+mod honeypot;
+pub mod harness;
+mod handover;
+mod health;
+mod hooks;
+mod http;
+#[cfg(unix)]
+mod instance;
+mod json;
+mod lifecycle;
+mod live;
+pub mod loadtest;
+mod local;
+pub mod log;
+mod login;
+mod metrics;
+mod middleware;
+mod motd;
+mod negotiated;
+pub mod mock;
+mod notes;
+mod panics;
+mod petscii;
+mod plugins;
+#[cfg(unix)]
+mod privileges;
+mod pool;
+mod portmap;
+mod proxy_protocol;
+mod push;
+mod queries;
+mod relays;
+mod resources;
+mod resume;
+mod rip;
+mod rlogin;
+mod serial;
+mod session;
+mod sha256;
+mod shutdown;
+mod slots;
+mod snapshot;
+mod span;
+mod ssh;
+#[cfg(windows)]
+pub mod service;
+#[cfg(unix)]
+mod stdio;
+mod syncterm;
+mod sysop;
+mod systemd;
+mod telnets;
+mod tls;
+#[cfg(target_os = "linux")]
+mod tui;
+mod upstream;
+pub mod users;
+pub mod version;
+mod web;
+mod webhook;
+#[cfg(target_os = "linux")]
+pub mod workers;
+
+// How often the listener is polled for new callers.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How long a new process gets to start up and take over during an upgrade.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(30);
+// How long sessions are given to close when the server stops.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+// Messages the client manager may fall behind by. New callers past that are
+// turned away rather than queued; sessions wait for room.
+const MANAGER_QUEUE: usize = 1024;
+
+// Callers turned away since startup for the client manager being MANAGER_QUEUE behind.
+static TURNED_AWAY: AtomicU64 = AtomicU64::new(0);
+
+pub enum ClientManagerMessage {
+    Connect {
+        stream: TcpStream,
+        // "telnet", "tls" or "stdio", for the session's log lines.
+        listener: &'static str,
+        forwarded: Option<Forwarded>,
+    },
+    ConnectionClosed {
+        client_id: Uuid
+    },
+    // The caller dropped and the session is held for them to resume.
+    Held {
+        client_id: Uuid,
+    },
+    // The session has moved on to another step of its life.
+    Transition {
+        client_id: Uuid,
+        state: SessionState,
+        at: Instant,
+    },
+    // Something went wrong in the session, for the manager to log and count.
+    Error {
+        client_id: Uuid,
+        kind: Fault,
+        // The backend it was with, if any.
+        backend: Option<String>,
+        detail: String,
+    },
+    Reattached {
+        client_id: Uuid,
+        ip_addr: IpAddr,
+    },
+    Started {
+        client_id: Uuid,
+        backend: String,
+        username: Option<String>,
+        terminal: Option<String>,
+        terminal_class: Option<TerminalClass>,
+    },
+    // How the caller's telnet settled, once it has.
+    Negotiated {
+        client_id: Uuid,
+        summary: String,
+    },
+    // Questions from the admin interface, through a ManagerHandle.
+    ListClients {
+        reply: Sender<Vec<ClientConnection>>,
+    },
+    GetStats {
+        reply: Sender<ManagerStats>,
+    },
+    Disconnect {
+        client_id: Uuid,
+        reason: String,
+        reply: Sender<Option<ClientConnection>>,
+    },
+    Broadcast {
+        message: String,
+        reply: Sender<usize>,
+    },
+    // The server has stopped; the manager thread exits once the last session has closed.
+    Shutdown,
+}
+
+// Who a caller really is when relayed to us by the TLS listener, whose
+// sockets are loopback ones, or by a trusted proxy's PROXY header.
+#[derive(Clone, Debug)]
+pub struct Forwarded {
+    pub ip_addr: IpAddr,
+    pub port: u16,
+    // The host name asked for with SNI, if any.
+    pub server_name: Option<String>,
+}
+
+// Which call a session is: the caller's number, counted in
+// server.callers_file or from this start, and the lowest node free.
+#[derive(Clone, Copy, Debug)]
+pub struct Call {
+    pub number: u64,
+    pub node: u32,
+}
+
+// Instructions from the manager to a running session thread.
+pub enum SessionControl {
+    Disconnect {
+        reason: String
+    },
+    // Replaces the faults injected into this session (only with [chaos]).
+    Chaos(ChaosConfig),
+    // A line to show the caller, such as a shutdown warning.
+    Notice {
+        message: String
+    },
+    // The sysop has answered at this console; the caller chats until /end.
+    ChatStart {
+        console: Sender<ConsoleMessage>
+    },
+    ChatLine {
+        text: String
+    },
+    ChatEnd,
+    // Copies what the caller is shown to the console, until it stops watching.
+    Spy {
+        viewer: Sender<Vec<u8>>
+    },
+}
+
+#[derive(Clone)]
+pub struct ClientConnection {
+    client_id: Uuid,
+    listener: &'static str,
+    ip_addr: IpAddr,
+    control: Sender<SessionControl>,
+    node: u32,
+    connected_at: Instant,
+    // Both None until the session has logged in and reached its backend.
+    backend: Option<String>,
+    username: Option<String>,
+    // Between the caller dropping and resuming, when there is nobody on the line.
+    held: bool,
+    // What the caller's TTYPE reported, when it was asked.
+    terminal: Option<String>,
+    // What the terminal probe found, when there was one.
+    terminal_class: Option<TerminalClass>,
+    // How the caller's telnet settled, once it has: binary, echo, TTYPE, NAWS and charset.
+    negotiation: Option<String>,
+    // A nickname the sysop gave this session from the admin interface.
+    label: Option<String>,
+    state: SessionState,
+    state_since: Instant,
+}
+
+impl ClientConnection {
+    fn span(&self) -> Span<'_> {
+        Span { client_id: self.client_id, listener: self.listener, ip_addr: self.ip_addr, backend: self.backend.as_deref() }
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedClientMap {
+    inner: Arc<Mutex<SharedMapInner>>,
+}
+
+struct SharedMapInner {
+    data: HashMap<uuid::Uuid, ClientConnection>,
+}
+
+impl SharedClientMap {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(SharedMapInner {
+                data: HashMap::new(),
+            }))
+        }
+    }
+
+    pub fn insert(&self, key: uuid::Uuid, value: ClientConnection) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.data.insert(key, value);
+    }
+
+    pub fn get(&self, key: uuid::Uuid) -> Option<ClientConnection> {
+        let lock = self.inner.lock().unwrap();
+        lock.data.get(&key).cloned()
+    }
+
+    // Changes an entry in place, so concurrent updates to other fields aren't
+    // lost. False if there is no such entry.
+    pub fn update(&self, key: uuid::Uuid, change: impl FnOnce(&mut ClientConnection)) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        lock.data.get_mut(&key).map(change).is_some()
+    }
+
+    pub fn remove(&self, key: uuid::Uuid) {
+        let mut lock = self.inner.lock().unwrap();
+        let _ = lock.data.remove(&key);
+    }
+
+    pub fn values(&self) -> Vec<ClientConnection> {
+        let lock = self.inner.lock().unwrap();
+        lock.data.values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        let lock = self.inner.lock().unwrap();
+        lock.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SharedClientMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+// Everything a client connection thread needs from the server.
+#[derive(Clone)]
+pub struct ServerContext {
+    pub config: Arc<Config>,
+    pub user_store: Option<Arc<UserStore>>,
+    pub held_sessions: HeldSessions,
+    pub bans: BanList,
+    pub middleware: MiddlewareChain,
+    pub events: EventBus,
+    pub health: Health,
+    pub pools: Pools,
+    // The config new sessions start with, as changed from the admin interface.
+    pub live: LiveConfig,
+    pub notes: Notes,
+    pub shutdown: ScheduledShutdown,
+    pub started: Instant,
+    pub clients: SharedClientMap,
+    pub faults: FaultCounts,
+    pub metrics: Metrics,
+    // For asking the client manager about sessions.
+    pub manager: ManagerHandle,
+    // Loaded from asn.database, when there's an [asn] section.
+    pub asn: Option<Arc<AsnDatabase>>,
+    // Kept up to date from the [access] files, when there's an [access] section.
+    pub access: Option<AccessLists>,
+    pub sysop: Sysop,
+    // The session threads running, to check the client map against.
+    pub relays: RelayThreads,
+}
+
+#[derive(Clone)]
+pub struct ClientManager {
+    receiver: Receiver<ClientManagerMessage>,
+    clients: SharedClientMap,
+    context: ServerContext,
+}
+
+impl ClientManager {
+    pub fn new(receiver: Receiver<ClientManagerMessage>, context: ServerContext) -> Self {
+        Self {
+            receiver,
+            clients: context.clients.clone(),
+            context,
+        }
+    }
+
+    pub fn receive(&self) -> Result<ClientManagerMessage, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    // Moves a session on to `state` as of `at`, if that's a step it can take
+    // from where it is, timing the step for the metrics. None if there's no
+    // such session.
+    fn transition(&self, client_id: Uuid, state: SessionState, at: Instant) -> Option<ClientConnection> {
+        let mut left = None;
+        self.clients.update(client_id, |client_connection| {
+            if client_connection.state.allows(state) {
+                left = Some((client_connection.state, at.saturating_duration_since(client_connection.state_since)));
+                client_connection.state = state;
+                client_connection.state_since = at;
+            }
+        });
+        let client_connection = self.clients.get(client_id)?;
+        let Some((previous, took)) = left else {
+            log!(Manager, Warn, span = client_connection.span(), "Ignored a move from {} to {}", client_connection.state, state);
+            return Some(client_connection);
+        };
+        log!(Manager, Debug, span = client_connection.span(), "now {}", state);
+        let metrics = &self.context.metrics;
+        match (previous, state, &client_connection.backend) {
+            (SessionState::DialingBackend, SessionState::Negotiating, Some(backend)) => metrics.observe_connect(backend, took),
+            (SessionState::Negotiating, SessionState::Active, Some(backend)) => metrics.observe_first_output(backend, took),
+            (_, SessionState::Closed, _) => metrics.observe_session(at.saturating_duration_since(client_connection.connected_at)),
+            _ => {}
+        }
+        Some(client_connection)
+    }
+}
+
+// Accepts callers until `running` returns false, then asks every session to
+// finish and gives them a moment to go.
+pub fn serve(config: Arc<Config>, user_store: Option<Arc<UserStore>>, mode: ServeMode, running: impl Fn() -> bool) {
+    log!(Server, Info, "{}", version::describe());
+    if !matches!(mode, ServeMode::Worker(_)) {
+        lock_instance(&config, matches!(mode, ServeMode::Daemon));
+    }
+    let tcp_listener = start_telnet_server(&config, matches!(mode, ServeMode::Worker(_)));
+    run_server(tcp_listener, SharedClientMap::new(), config, user_store, mode, running);
+}
+
+// `serve` on an already bound, non-blocking listener, tracking sessions in
+// `clients`.
+pub fn run_server(tcp_listener: TcpListener, clients: SharedClientMap, config: Arc<Config>, user_store: Option<Arc<UserStore>>,
+                  mode: ServeMode, running: impl Fn() -> bool) {
+    let daemon = matches!(mode, ServeMode::Daemon);
+    let worker = match mode {
+        ServeMode::Worker(index) => Some(index),
+        _ => None,
+    };
+    if daemon {
+        start_daemon(&config);
+    }
+    let (client_manager_tx, client_manager_rx) = bounded(MANAGER_QUEUE);
+    let context = start_context(config, user_store, clients, &client_manager_tx);
+    context.health.set_listening();
+    if let Some(index) = worker {
+        start_worker(&context, index);
+    }
+    // With several workers only the first serves these, as they'd clash on the port.
+    if worker.unwrap_or(0) == 0 {
+        if let Some(admin) = &context.config.admin {
+            admin::launch_admin_server(admin, context.clone());
+        }
+        if let Some(http) = &context.config.http {
+            context.health.launch_probes(context.live.clone(), http.backend_check_interval);
+            launch_http_server(http, context.clone());
+        }
+        if let Some(finger) = &context.config.finger {
+            launch_finger_server(finger, context.clone());
+        }
+        if let Some(tls) = &context.config.tls {
+            launch_tls_listener(tls, client_manager_tx.clone());
+        }
+        if let Some(mapping) = &context.config.port_mapping {
+            portmap::launch_port_mapping(mapping, context.config.server.port);
+        }
+    }
+    if let Some(user) = &context.config.server.user {
+        drop_privileges(user, context.config.server.group.as_deref());
+    }
+    let clients = context.clients.clone();
+    let config = context.config.clone();
+    let scheduled_shutdown = context.shutdown.clone();
+    scheduled_shutdown.launch_countdown(clients.clone());
+    snapshot::launch_snapshots(context.clone(), client_manager_tx.clone());
+    resources::launch_monitor();
+    relays::launch_watch(context.clone());
+    if let Some(user_store) = &context.user_store {
+        users::launch_heartbeat(user_store.clone());
+    }
+    if let Some(push) = &context.config.metrics_push {
+        push::launch_metrics_push(push, context.clone());
+    }
+    if let Some(daily) = &context.config.daily_stats {
+        daily::launch_daily_stats(daily, context.clone());
+    }
+    #[cfg(target_os = "linux")]
+    let console = matches!(mode, ServeMode::Tui).then(|| start_console(&context));
+    // --tui is refused on starting up.
+    #[cfg(not(target_os = "linux"))]
+    let console: Option<std::convert::Infallible> = None;
+    let _ = launch_client_manager(client_manager_tx.clone(), client_manager_rx, context);
+    systemd::notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+    handover::report_ready();
+    handover::install_signal_handler();
+
+    let proxy_protocol = config.proxy_protocol.clone().map(Arc::new);
+    while running() && !scheduled_shutdown.is_due() {
+        if handover::take_request() {
+            if worker.is_some() {
+                log!(Server, Warn, "Upgrades are not supported with [workers]; restart the server instead");
+            } else if console.is_some() {
+                log!(Server, Warn, "Upgrades are not supported with --tui; restart the server instead");
+            } else if hand_over(&config, daemon) {
+                drain(&clients);
+                let _ = client_manager_tx.send(ClientManagerMessage::Shutdown);
+                return;
+            }
+        }
+        match tcp_listener.accept() {
+            Ok((stream, _)) => match &proxy_protocol {
+                Some(proxy_protocol) => proxy_protocol::launch_accept(stream, proxy_protocol.clone(), client_manager_tx.clone()),
+                None => {
+                    stream.set_nonblocking(true).expect("Error setting stream to non-blocking");
+                    connect(&client_manager_tx, stream, "telnet", None);
+                }
+            },
+            Err(error) if error.kind() == ErrorKind::WouldBlock => sleep(ACCEPT_POLL_INTERVAL),
+            Err(_) => {}
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(console) = console {
+        console.close();
+    }
+    shut_down(&clients);
+    let _ = client_manager_tx.send(ClientManagerMessage::Shutdown);
+}
+
+// Hands a new caller to the client manager, or turns them away if it is
+// too far behind to take them.
+pub(crate) fn connect(client_manager_tx: &Sender<ClientManagerMessage>, stream: TcpStream, listener: &'static str, forwarded: Option<Forwarded>) {
+    let Err(TrySendError::Full(ClientManagerMessage::Connect { mut stream, forwarded, .. })) =
+        client_manager_tx.try_send(ClientManagerMessage::Connect { stream, listener, forwarded }) else {
+        return;
+    };
+    TURNED_AWAY.fetch_add(1, Ordering::Relaxed);
+    let peer = forwarded.map(|forwarded| forwarded.ip_addr.to_string())
+        .or_else(|| stream.peer_addr().ok().map(|peer| peer.ip().to_string()))
+        .unwrap_or_else(|| String::from("an unknown address"));
+    log!(Server, Warn, "Refused connection from {}: the client manager is {} messages behind", peer, MANAGER_QUEUE);
+    let _ = stream.write_all(b"The system is busy, please try again in a moment.\r\n");
+}
+
+pub(crate) fn turned_away() -> u64 {
+    TURNED_AWAY.load(Ordering::Relaxed)
+}
+
+// Everything sessions share, with the event consumers it feeds started.
+fn start_context(config: Arc<Config>, user_store: Option<Arc<UserStore>>, clients: SharedClientMap,
+                 client_manager_tx: &Sender<ClientManagerMessage>) -> ServerContext {
+    let events = EventBus::default();
+    log::launch_event_log(&events);
+    if let Some(webhook) = &config.webhook {
+        launch_webhooks(webhook, &events);
+    }
+    if let Some(chat) = &config.chat {
+        launch_chat(chat, &events);
+    }
+    if let Some(hooks) = &config.hooks {
+        launch_command_hooks(hooks, &events);
+    }
+    let bans = BanList::new(config.autoban.clone().unwrap_or_else(AutobanConfig::disabled), events.clone());
+    let plugins = config.plugins.as_ref().map(|plugins| Arc::new(Plugins::load(plugins)));
+    let live = LiveConfig::new(config.clone());
+    let middleware = MiddlewareChain::standard(&config, &live, user_store.clone(), &bans, plugins);
+    if let Some(chaos) = &config.chaos {
+        log!(Server, Info, "Chaos mode is on, injecting faults into sessions ({}); not for real callers", chaos::describe(chaos));
+    }
+    let notes = config.admin.as_ref().map_or_else(Notes::default, |admin| Notes::load(&admin.notes_file));
+    let asn = config.asn.as_ref().and_then(|asn| match AsnDatabase::load(&asn.database) {
+        Ok(database) => {
+            log!(Server, Info, "Loaded {} ranges from {}", database.len(), asn.database.display());
+            Some(Arc::new(database))
+        }
+        Err(error) => {
+            log!(Server, Warn, "Unable to load {}, [asn] rules are off: {}", asn.database.display(), error);
+            None
+        }
+    });
+    let access = config.access.as_ref().map(launch_access_lists);
+    ServerContext { config, user_store, held_sessions: HeldSessions::default(), bans, middleware, events,
+                    health: Health::default(), pools: Pools::default(), live, notes, shutdown: ScheduledShutdown::default(),
+                    started: Instant::now(), clients, faults: FaultCounts::default(), metrics: Metrics::default(),
+                    manager: ManagerHandle::new(client_manager_tx.clone()), asn, access,
+                    sysop: Sysop::default(), relays: RelayThreads::default() }
+}
+
+// One session over stdin and stdout, then exit: for inetd/xinetd, or as an
+// sshd ForceCommand. Nothing listens, so the admin, HTTP, finger and TLS
+// interfaces stay off; the session itself runs as it would for a listener.
+#[cfg(unix)]
+pub fn serve_stdio(config: Arc<Config>, user_store: Option<Arc<UserStore>>) {
+    let log_file = config.daemon.as_ref().map(|daemon| daemon.log_file.clone());
+    let (stream, forwarded, bridge) = match stdio::bridge(log_file.as_deref()) {
+        Ok(bridged) => bridged,
+        Err(error) => {
+            eprintln!("Unable to serve on stdin and stdout: {}", error);
+            exit(1);
+        }
+    };
+    log!(Server, Info, "{}", version::describe());
+    log!(Server, Info, "Serving {} on stdin and stdout", forwarded.ip_addr);
+    if let Some(user) = &config.server.user {
+        drop_privileges(user, config.server.group.as_deref());
+    }
+    let (client_manager_tx, client_manager_rx) = bounded(MANAGER_QUEUE);
+    let context = start_context(config, user_store, SharedClientMap::new(), &client_manager_tx);
+    let client_manager = launch_client_manager(client_manager_tx.clone(), client_manager_rx, context);
+    stream.set_nonblocking(true).expect("Error setting stream to non-blocking");
+    connect(&client_manager_tx, stream, "stdio", Some(forwarded));
+    // The manager finishes once the session has, or straight away if it refused the caller.
+    let _ = client_manager_tx.send(ClientManagerMessage::Shutdown);
+    let _ = client_manager.join();
+    bridge.finish();
+}
+
+#[cfg(not(unix))]
+pub fn serve_stdio(_config: Arc<Config>, _user_store: Option<Arc<UserStore>>) {
+    eprintln!("--stdio is only supported on Unix.");
+    exit(1);
+}
+
+fn hand_over(config: &Config, daemon: bool) -> bool {
+    log!(Server, Info, "Upgrade requested, starting a new process to take over the listeners");
+    match handover::spawn_successor(HANDOVER_TIMEOUT) {
+        Ok(pid) => {
+            log!(Server, Info, "PID {} has taken over the listeners", pid);
+            if daemon {
+                update_pid_file(config, pid);
+            }
+            true
+        }
+        Err(error) => {
+            log!(Server, Warn, "Upgrade failed, carrying on: {}", error);
+            false
+        }
+    }
+}
+
+// After a handover new callers go to the new process; this one just waits
+// for its own sessions to end.
+fn drain(clients: &SharedClientMap) {
+    let mut reported = None;
+    while !clients.is_empty() {
+        if reported != Some(clients.len()) {
+            reported = Some(clients.len());
+            log!(Server, Info, "Draining, {} session(s) still connected", clients.len());
+        }
+        sleep(Duration::from_secs(1));
+    }
+    log!(Server, Info, "All sessions have finished, exiting");
+}
+
+fn shut_down(clients: &SharedClientMap) {
+    systemd::notify("STOPPING=1");
+    log!(Server, Info, "Shutting down, disconnecting {} session(s)", clients.len());
+    for client_connection in clients.values() {
+        let _ = client_connection.control.send(SessionControl::Disconnect { reason: String::from("The server is shutting down.") });
+    }
+    let started = Instant::now();
+    while !clients.is_empty() && started.elapsed() < SHUTDOWN_GRACE {
+        sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn start_worker(context: &ServerContext, index: u32) {
+    workers::exit_with_supervisor();
+    if let Some(workers) = &context.config.workers {
+        workers::launch_stats_writer(workers.stats_file.clone(), index, context.clients.clone(), &context.events);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_worker(_context: &ServerContext, _index: u32) {}
+
+#[cfg(target_os = "linux")]
+fn bind_shared(address: &str) -> std::io::Result<TcpListener> {
+    workers::bind_shared(address)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_shared(_address: &str) -> std::io::Result<TcpListener> {
+    Err(std::io::Error::new(ErrorKind::Unsupported, "SO_REUSEPORT workers are only supported on Linux"))
+}
+
+#[cfg(unix)]
+fn drop_privileges(user: &str, group: Option<&str>) {
+    match privileges::drop_to(user, group) {
+        Ok(()) => log!(Server, Info, "Running as user {}", user),
+        Err(error) => {
+            // Carrying on as root would defeat the point of the setting.
+            eprintln!("Unable to switch to user {}: {}", user, error);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_user: &str, _group: Option<&str>) {
+    eprintln!("server.user is only supported on Unix.");
+    exit(1);
+}
+
+#[cfg(target_os = "linux")]
+fn start_console(context: &ServerContext) -> tui::Console {
+    match tui::launch(context.clone()) {
+        Ok(console) => console,
+        Err(error) => {
+            eprintln!("Unable to start the console: {}", error);
+            exit(1);
+        }
+    }
+}
+
+// Before anything is bound or written, so a second server against the same
+// config goes no further. --daemon needs its PID file for `stop`; in the
+// foreground, one that can't be written is only warned about.
+#[cfg(unix)]
+pub fn lock_instance(config: &Config, daemon: bool) {
+    let pid_file = config.daemon.clone().unwrap_or_default().pid_file;
+    match instance::lock(&pid_file) {
+        Ok(()) => {}
+        Err(instance::LockError::Io(error)) if !daemon => {
+            log!(Server, Warn, "No PID file, so nothing stops a second server starting with this config: {}", error);
+        }
+        Err(instance::LockError::Running(error) | instance::LockError::Io(error)) => {
+            eprintln!("Unable to start: {}", error);
+            exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn lock_instance(_config: &Config, _daemon: bool) {}
+
+#[cfg(unix)]
+pub fn start_daemon(config: &Config) {
+    if let Err(error) = daemon::daemonize(&config.daemon.clone().unwrap_or_default()) {
+        eprintln!("Unable to start in the background: {}", error);
+        exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn start_daemon(_config: &Config) {
+    eprintln!("--daemon is only supported on Unix.");
+    exit(1);
+}
+
+fn launch_tls_listener(config: &TlsConfig, client_manager_tx: Sender<ClientManagerMessage>) {
+    telnets::launch_tls_listener(config, client_manager_tx);
+}
+
+#[cfg(unix)]
+fn update_pid_file(config: &Config, pid: u32) {
+    let daemon_config = config.daemon.clone().unwrap_or_default();
+    if let Err(error) = daemon::replace_pid(&daemon_config, pid) {
+        log!(Server, Warn, "Unable to update {}: {}", daemon_config.pid_file.display(), error);
+    }
+}
+
+#[cfg(not(unix))]
+fn update_pid_file(_config: &Config, _pid: u32) {}
+
+// Workers bind the port together with SO_REUSEPORT instead.
+fn start_telnet_server(config: &Config, shared: bool) -> TcpListener {
+    let host = match &config.server.address {
+        Some(address) => address.clone(),
+        None => {
+            let local_ip_address = local_ip().unwrap();
+            log!(Server, Info, "{}", local_ip_address);
+            local_ip_address.to_string()
+        }
+    };
+    let address = format!("{}:{}", host, config.server.port);
+    let listener = match if shared { bind_shared(&address) } else { handover::bind("telnet", &address) } {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Unable to listen on {}: {}", address, error);
+            exit(1);
+        }
+    };
+    listener.set_nonblocking(true).expect("Cannot set non-blocking");
+    log!(Server, Info, "Telnet Server Listening on: {}", address);
+    listener
+}
+
+fn launch_client_manager(sender: Sender<ClientManagerMessage>, receiver: Receiver<ClientManagerMessage>,
+                         context: ServerContext) -> thread::JoinHandle<()> {
+    let client_manager = ClientManager::new(receiver, context);
+    thread::spawn(
+        move || {
+            let mut watchdog = Watchdog::from_env();
+            let mut stopping = false;
+            let (mut connects, mut accepted) = (0, 0);
+            let mut callers = CallerCount::load(client_manager.context.config.server.callers_file.as_deref());
+            // Sessions that outlast the shutdown grace still report to us when they close.
+            while !(stopping && client_manager.clients.is_empty()) {
+                if let Some(watchdog) = watchdog.as_mut() {
+                    watchdog.tick();
+                }
+                if let Ok(client_manager_message) = client_manager.receive() {
+                    match client_manager_message {
+                        ClientManagerMessage::Connect { mut stream, listener, forwarded } => {
+                            log!(Manager, Debug, "TCP Connect event received");
+                            connects += 1;
+                            let peer = match &forwarded {
+                                Some(forwarded) => forwarded.ip_addr,
+                                None => match stream.peer_addr() {
+                                    Ok(peer) => peer.ip(),
+                                    // Gone again before it could be looked at.
+                                    Err(error) => {
+                                        log!(Manager, Debug, "Dropped a connection with no peer address: {}", error);
+                                        continue;
+                                    }
+                                },
+                            };
+                            if client_manager.context.shutdown.is_refusing_callers() {
+                                let _ = stream.write_all(b"The system is about to go down for maintenance. Please call back later.\r\n");
+                                continue;
+                            }
+                            let bans = &client_manager.context.bans;
+                            if let Some(ban) = bans.check(peer) {
+                                log!(Manager, Info, "Refused connection from banned address {} ({})", peer, ban.reason);
+                                let _ = stream.write_all(format!("You are temporarily banned ({}). Try again in {} minutes.\r\n",
+                                                                 ban.reason, ban.remaining().as_secs().div_ceil(60)).as_bytes());
+                                continue;
+                            }
+                            if let Some(refusal) = client_manager.context.access.as_ref().and_then(|access| access.refuses(peer)) {
+                                log!(Manager, Info, "Refused connection from {} ({})", peer, match refusal {
+                                    Refusal::Banned => "in the ban file",
+                                    Refusal::NotAllowed => "not in the allow file",
+                                });
+                                let _ = stream.write_all(b"Connections from your address are not accepted.\r\n");
+                                continue;
+                            }
+                            if let (Some(database), Some(rules)) = (&client_manager.context.asn, &client_manager.context.config.asn) {
+                                let asn = database.lookup(peer);
+                                if !rules.permits(asn) {
+                                    log!(Manager, Info, "Refused connection from {} in AS{}", peer, asn.unwrap_or_default());
+                                    let _ = stream.write_all(b"Connections from your network are not accepted.\r\n");
+                                    continue;
+                                }
+                            }
+                            if let Some(ban) = bans.record(peer, Offense::Reconnect) {
+                                log!(Server, Warn, "Auto-banned {} for {} seconds: {} (strike {})", peer, ban.remaining().as_secs(), ban.reason, ban.strikes);
+                                let _ = stream.write_all(b"Too many connections, please try again later.\r\n");
+                                continue;
+                            }
+                            // A held session is waiting for this caller to come back with its code, not competing with them.
+                            let existing: Vec<ClientConnection> = client_manager.clients.values().into_iter()
+                                .filter(|client_connection| client_connection.ip_addr == peer && !client_connection.held)
+                                .collect();
+                            if !existing.is_empty() {
+                                match client_manager.context.config.server.duplicate_ip {
+                                    DuplicatePolicy::Allow => {}
+                                    DuplicatePolicy::Reject => {
+                                        log!(Manager, Info, "Rejected duplicate connection from {}", peer);
+                                        let _ = stream.write_all(b"Only one connection per address is allowed.\r\n");
+                                        continue;
+                                    }
+                                    DuplicatePolicy::Kick => {
+                                        for client_connection in existing {
+                                            log!(Manager, Info, span = client_connection.span(), "Kicked for a new connection from {}", peer);
+                                            let _ = client_connection.control.send(SessionControl::Disconnect {
+                                                reason: String::from("You have connected from another session."),
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                            let client_manager_sender = sender.clone();
+                            let client_id = Uuid::new_v4();
+                            // New callers get the backends as the admin interface last left them.
+                            let mut context = client_manager.context.clone();
+                            context.config = context.live.current();
+                            let nodes: Vec<u32> = client_manager.clients.values().iter().map(|client_connection| client_connection.node).collect();
+                            let call = Call { number: callers.next(), node: (1..).find(|node| !nodes.contains(node)).unwrap_or_default() };
+                            let client_connection = match create_client_connection(client_id, stream, listener, forwarded, call, client_manager_sender,
+                                                                                   context) {
+                                Ok(client_connection) => client_connection,
+                                Err(error) => {
+                                    log!(Manager, Warn, "Unable to start a session for {}: {}", peer, error);
+                                    continue;
+                                }
+                            };
+                            log!(Manager, Info, span = client_connection.span(), "Client Connection created - Client ID: {}", client_id);
+                            log!(Manager, Debug, span = client_connection.span(), "Inserted into Client Map");
+                            client_manager.clients.insert(client_id, client_connection);
+                            accepted += 1;
+                        }
+                        ClientManagerMessage::ConnectionClosed { client_id } => {
+                            match client_manager.transition(client_id, SessionState::Closed, Instant::now()) {
+                                Some(client_connection) => {
+                                    client_manager.clients.remove(client_id);
+                                    log!(Manager, Debug, span = client_connection.span(), "removed from client map.");
+                                    if let Some(backend) = &client_connection.backend {
+                                        pool::report_if_drained(&client_manager.context, backend);
+                                    }
+                                }
+                                None => log!(Manager, Warn, "No Client Mapping Data for Client ID: {}", client_id),
+                            };
+                        }
+                        ClientManagerMessage::Held { client_id } => {
+                            client_manager.clients.update(client_id, |client_connection| client_connection.held = true);
+                        }
+                        ClientManagerMessage::Transition { client_id, state, at } => {
+                            if client_manager.transition(client_id, state, at).is_none() {
+                                log!(Manager, Warn, "No Client Mapping Data for Client ID: {}", client_id);
+                            }
+                        }
+                        ClientManagerMessage::Error { client_id, kind, backend, detail } => {
+                            client_manager.context.faults.count(kind, backend.as_deref(), &detail);
+                            match client_manager.clients.get(client_id) {
+                                Some(client_connection) => {
+                                    match kind {
+                                        Fault::Lost | Fault::Overflow => log!(Relay, Warn, span = client_connection.span(), "{}", detail),
+                                        _ => log!(Relay, Error, span = client_connection.span(), "{}", detail),
+                                    }
+                                    client_manager.context.events.publish(Event::Error { client_id, ip_addr: client_connection.ip_addr, message: detail });
+                                }
+                                None => log!(Manager, Warn, "No Client Mapping Data for Client ID: {} ({} fault: {})", client_id, kind.name(), detail),
+                            }
+                        }
+                        ClientManagerMessage::Reattached { client_id, ip_addr } => {
+                            if client_manager.clients.update(client_id, |client_connection| {
+                                client_connection.ip_addr = ip_addr;
+                                client_connection.held = false;
+                            }) {
+                                if let Some(client_connection) = client_manager.clients.get(client_id) {
+                                    log!(Manager, Info, span = client_connection.span(), "reattached");
+                                }
+                            }
+                        }
+                        ClientManagerMessage::Started { client_id, backend, username, terminal, terminal_class } => {
+                            let mut previous = None;
+                            client_manager.clients.update(client_id, |client_connection| {
+                                previous = client_connection.backend.replace(backend);
+                                client_connection.username = username;
+                                client_connection.terminal = terminal;
+                                client_connection.terminal_class = terminal_class;
+                            });
+                            // A caller with [multisession] may have switched away from it.
+                            if let Some(previous) = previous {
+                                pool::report_if_drained(&client_manager.context, &previous);
+                            }
+                        }
+                        ClientManagerMessage::Negotiated { client_id, summary } => {
+                            client_manager.clients.update(client_id, |client_connection| client_connection.negotiation = Some(summary));
+                        }
+                        ClientManagerMessage::ListClients { reply } => {
+                            let _ = reply.send(client_manager.clients.values());
+                        }
+                        ClientManagerMessage::GetStats { reply } => {
+                            let _ = reply.send(ManagerStats { sessions: client_manager.clients.len(), accepted, refused: connects - accepted,
+                                                              turned_away: turned_away(), queued: client_manager.receiver.len() });
+                        }
+                        ClientManagerMessage::Disconnect { client_id, reason, reply } => {
+                            let client_connection = client_manager.clients.get(client_id);
+                            if let Some(client_connection) = &client_connection {
+                                let _ = client_connection.control.send(SessionControl::Disconnect { reason });
+                            }
+                            let _ = reply.send(client_connection);
+                        }
+                        ClientManagerMessage::Broadcast { message, reply } => {
+                            let clients = client_manager.clients.values();
+                            for client_connection in &clients {
+                                let _ = client_connection.control.send(SessionControl::Notice { message: message.clone() });
+                            }
+                            let _ = reply.send(clients.len());
+                        }
+                        ClientManagerMessage::Shutdown => stopping = true,
+                    }
+                }
+                sleep(Duration::from_nanos(10))
+            }
+        }
+    )
+}
@@ -0,0 +1,53 @@
+// Where each session is in its life, kept in the client map so `who` and
+// snapshots show a session that is stuck, and where. Sessions report each
+// step to the client manager, which only lets them move forward:
+//
+//   accepted -> validating -> dialing -> negotiating -> active -> draining -> closed
+//
+// The one way back is from negotiating or active to dialing, while a backend
+// that dropped is re-dialed. Nothing leaves closed, so a session can only
+// close once.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SessionState {
+    // The manager has let the caller in and started the session.
+    Accepted,
+    // Prompts, login and the middleware's say on whether the call goes ahead.
+    Validating,
+    DialingBackend,
+    // The backend is connected but nothing has been relayed yet.
+    Negotiating,
+    Active,
+    // The caller has stopped sending, or the session is winding down.
+    Draining,
+    Closed,
+}
+
+impl SessionState {
+    pub const ALL: [SessionState; 7] = [SessionState::Accepted, SessionState::Validating, SessionState::DialingBackend,
+                                        SessionState::Negotiating, SessionState::Active, SessionState::Draining, SessionState::Closed];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SessionState::Accepted => "accepted",
+            SessionState::Validating => "validating",
+            SessionState::DialingBackend => "dialing",
+            SessionState::Negotiating => "negotiating",
+            SessionState::Active => "active",
+            SessionState::Draining => "draining",
+            SessionState::Closed => "closed",
+        }
+    }
+
+    pub fn allows(&self, next: SessionState) -> bool {
+        next > *self || (next == SessionState::DialingBackend && matches!(self, SessionState::Negotiating | SessionState::Active))
+    }
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,247 @@
+// The config as changed from the admin interface while the server runs:
+// backends added, changed and removed, and pool members joined and left.
+// Sessions keep the config they started with; new callers get the latest.
+// With [admin] save_backends, each change is written to the config file too.
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{edit::Source, BackendConfig, Config, PoolConfig, PoolMember};
+
+pub enum Change {
+    Add { name: String, settings: Vec<(String, String)> },
+    Set { name: String, settings: Vec<(String, String)> },
+    Remove { name: String },
+    Join { pool: String, backend: String, weight: u64 },
+    Leave { pool: String, backend: String },
+}
+
+#[derive(Clone)]
+pub struct LiveConfig {
+    current: Arc<Mutex<Arc<Config>>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { current: Arc::new(Mutex::new(config)) }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.current.lock().unwrap().clone()
+    }
+
+    // Checks the changed config as a loaded one would be, saves it if asked
+    // to, and only then puts it in place. Returns whether it was saved.
+    pub fn apply(&self, change: &Change) -> Result<bool, String> {
+        let mut current = self.current.lock().unwrap();
+        let mut config = Config::clone(&current);
+        match change {
+            Change::Add { name, settings } => {
+                if config.backend(name).is_some() || config.pool(name).is_some() {
+                    return Err(format!("there is already a backend or pool named {}", name));
+                }
+                config.backends.push(Config::parse_backend(name, settings).map_err(|error| error.to_string())?);
+            }
+            Change::Set { name, settings } => {
+                let index = backend_index(&config, name)?;
+                let old = &config.backends[index];
+                let mut merged = old.settings();
+                for (key, value) in settings {
+                    match merged.iter_mut().find(|(merged_key, _)| merged_key == key) {
+                        Some(setting) => setting.1 = value.clone(),
+                        None => merged.push((key.clone(), value.clone())),
+                    }
+                }
+                let backend = Config::parse_backend(name, &merged).map_err(|error| error.to_string())?;
+                config.backends[index] = BackendConfig {
+                    options: old.options.clone(),
+                    serial: old.serial.clone(),
+                    ssh: old.ssh.clone(),
+                    rlogin: old.rlogin.clone(),
+                    ..backend
+                };
+            }
+            Change::Remove { name } => {
+                let index = backend_index(&config, name)?;
+                if config.backends.len() == 1 {
+                    return Err(String::from("the last backend can't be removed"));
+                }
+                if let Some(pool) = config.pools.iter().find(|pool| pool.members.iter().any(|member| member.backend == *name)) {
+                    return Err(format!("{} is a member of pool {}; take it out with 'pool leave' first", name, pool.name));
+                }
+                if let Some(i) = config.routes.iter().position(|route| route.backend == *name) {
+                    return Err(format!("route[{}] sends callers to {}; change the config file first", i, name));
+                }
+                config.backends.remove(index);
+            }
+            Change::Join { pool, backend, weight } => {
+                backend_index(&config, backend)?;
+                let members = &mut pool_mut(&mut config, pool)?.members;
+                if members.iter().any(|member| member.backend == *backend) {
+                    return Err(format!("{} is already a member of pool {}", backend, pool));
+                }
+                members.push(PoolMember { backend: backend.clone(), weight: *weight });
+            }
+            Change::Leave { pool, backend } => {
+                let members = &mut pool_mut(&mut config, pool)?.members;
+                let index = members.iter().position(|member| member.backend == *backend)
+                    .ok_or_else(|| format!("{} is not a member of pool {}", backend, pool))?;
+                if members.len() == 1 {
+                    return Err(format!("{} is the last member of pool {}", backend, pool));
+                }
+                members.remove(index);
+            }
+        }
+        config.validate().map_err(|error| error.to_string())?;
+
+        let saved = config.admin.as_ref().is_some_and(|admin| admin.save_backends);
+        if saved {
+            save(&config, change)?;
+        }
+        *current = Arc::new(config);
+        Ok(saved)
+    }
+}
+
+fn backend_index(config: &Config, name: &str) -> Result<usize, String> {
+    config.backends.iter().position(|backend| backend.name == name).ok_or_else(|| format!("no backend named {}", name))
+}
+
+fn pool_mut<'a>(config: &'a mut Config, name: &str) -> Result<&'a mut PoolConfig, String> {
+    config.pools.iter_mut().find(|pool| pool.name == name).ok_or_else(|| format!("no pool named {}", name))
+}
+
+// Makes the same change to the config file, replacing it in one step so a
+// crash never leaves half a file.
+fn save(config: &Config, change: &Change) -> Result<(), String> {
+    let path = config.source.as_ref().ok_or_else(|| String::from("there is no config file to save to"))?;
+    let text = fs::read_to_string(path).map_err(|error| format!("unable to read {}: {}", path.display(), error))?;
+    let mut source = Source::new(&text);
+    match change {
+        Change::Add { name, settings } => source.add_backend(name, settings),
+        Change::Set { name, settings } => source.set_backend(name, settings)?,
+        Change::Remove { name } => source.remove_backend(name)?,
+        Change::Join { pool, backend, weight } => source.add_member(pool, backend, *weight)?,
+        Change::Leave { pool, backend } => source.remove_member(pool, backend)?,
+    }
+    let staging = path.with_extension("toml.new");
+    fs::write(&staging, source.text()).and_then(|()| fs::rename(&staging, path))
+        .map_err(|error| format!("unable to save {}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AdminConfig;
+    use std::path::PathBuf;
+
+    const CONFIG: &str = r#"# Two nodes behind one pool.
+[[backend]]
+name = "node1"
+host = "127.0.0.1"
+port = 2323
+
+[[backend]]
+name = "node2"
+host = "127.0.0.1"
+port = 2324   # the spare
+
+[[pool]]
+name = "nodes"
+[[pool.member]]
+backend = "node1"
+weight = 1
+"#;
+
+    fn settings(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), BackendConfig::setting(key, value).unwrap())).collect()
+    }
+
+    fn live() -> LiveConfig {
+        LiveConfig::new(Arc::new(Config::parse(CONFIG).unwrap()))
+    }
+
+    fn names(config: &Config) -> Vec<&str> {
+        config.backends.iter().map(|backend| backend.name.as_str()).collect()
+    }
+
+    #[test]
+    fn changes_leave_sessions_with_the_config_they_started_with() {
+        let live = live();
+        // What a session already connected holds on to.
+        let attached = live.current();
+
+        let added = Change::Add { name: String::from("games"), settings: settings(&[("host", "games.example"), ("port", "23")]) };
+        assert_eq!(live.apply(&added), Ok(false));
+        let changed = Change::Set { name: String::from("node2"), settings: settings(&[("port", "2325"), ("output", "ascii")]) };
+        assert_eq!(live.apply(&changed), Ok(false));
+        assert_eq!(live.apply(&Change::Remove { name: String::from("games") }), Ok(false));
+        assert_eq!(live.apply(&Change::Remove { name: String::from("node2") }), Ok(false));
+
+        assert_eq!(names(&attached), ["node1", "node2"]);
+        assert_eq!(attached.backend("node2").unwrap().port, 2324);
+        assert_eq!(names(&live.current()), ["node1"]);
+    }
+
+    #[test]
+    fn changing_a_backend_keeps_what_wasnt_given() {
+        let live = live();
+        let change = Change::Set { name: String::from("node2"), settings: settings(&[("port", "2325")]) };
+        live.apply(&change).unwrap();
+        let current = live.current();
+        let node2 = current.backend("node2").unwrap();
+        assert_eq!((node2.host.as_str(), node2.port), ("127.0.0.1", 2325));
+    }
+
+    #[test]
+    fn refuses_edits_that_would_break_the_config() {
+        let live = live();
+        let before = live.current();
+        let refused = [
+            Change::Add { name: String::from("node1"), settings: settings(&[("host", "elsewhere")]) },
+            Change::Add { name: String::from("nodes"), settings: settings(&[("host", "elsewhere")]) },
+            Change::Add { name: String::from("broken"), settings: vec![(String::from("port"), String::from("\"many\""))] },
+            Change::Set { name: String::from("node3"), settings: settings(&[("port", "23")]) },
+            Change::Remove { name: String::from("node1") },
+            Change::Remove { name: String::from("node3") },
+            Change::Join { pool: String::from("nodes"), backend: String::from("node1"), weight: 1 },
+            Change::Join { pool: String::from("nodes"), backend: String::from("node3"), weight: 1 },
+            Change::Join { pool: String::from("others"), backend: String::from("node2"), weight: 1 },
+            Change::Leave { pool: String::from("nodes"), backend: String::from("node1") },
+            Change::Leave { pool: String::from("nodes"), backend: String::from("node2") },
+        ];
+        for change in &refused {
+            assert!(live.apply(change).is_err());
+        }
+        assert!(Arc::ptr_eq(&before, &live.current()));
+
+        // The last backend can't go either.
+        live.apply(&Change::Join { pool: String::from("nodes"), backend: String::from("node2"), weight: 1 }).unwrap();
+        live.apply(&Change::Leave { pool: String::from("nodes"), backend: String::from("node1") }).unwrap();
+        live.apply(&Change::Remove { name: String::from("node1") }).unwrap();
+        assert_eq!(live.apply(&Change::Remove { name: String::from("node2") }), Err(String::from("the last backend can't be removed")));
+    }
+
+    #[test]
+    fn saves_changes_to_the_config_file() {
+        let path = std::env::temp_dir().join(format!("triserver-live-{}.toml", std::process::id()));
+        fs::write(&path, CONFIG).unwrap();
+        let mut config = Config::parse(CONFIG).unwrap();
+        config.source = Some(path.clone());
+        config.admin = Some(AdminConfig { address: String::from("127.0.0.1:0"), password: None, notes_file: PathBuf::from("notes"),
+                                          save_backends: true });
+        let live = LiveConfig::new(Arc::new(config));
+
+        live.apply(&Change::Add { name: String::from("games"), settings: settings(&[("host", "games.example")]) }).unwrap();
+        live.apply(&Change::Set { name: String::from("node2"), settings: settings(&[("port", "2325")]) }).unwrap();
+        live.apply(&Change::Join { pool: String::from("nodes"), backend: String::from("node2"), weight: 3 }).unwrap();
+        // Refused, so the file is left as it was.
+        assert!(live.apply(&Change::Remove { name: String::from("node2") }).is_err());
+
+        let saved = Config::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(names(&saved), ["node1", "node2", "games"]);
+        assert_eq!(saved.backend("node2").unwrap().port, 2325);
+        assert_eq!(saved.pool("nodes").unwrap().members.len(), 2);
+    }
+}
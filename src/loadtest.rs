@@ -0,0 +1,206 @@
+// `TriServer loadtest`: opens many telnet clients against a server, each of
+// which negotiates, sends scripted lines and times the replies. Meant for
+// checking the proxy's own capacity, so it reports percentiles and an
+// error breakdown rather than per-connection detail.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::unbounded;
+use telnet::{Action, Event as TelnetEvent, Telnet, TelnetError, TelnetOption};
+
+use crate::cli::LoadTestOptions;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to keep answering negotiation after connecting before the script starts.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+// Pause between scripted lines, roughly a fast typist.
+const THINK_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Outcome {
+    connect: Option<Duration>,
+    replies: Vec<Duration>,
+    error: Option<&'static str>,
+}
+
+pub fn run(target: &str, options: &LoadTestOptions) -> i32 {
+    let address = match target.to_socket_addrs().map(|mut addresses| addresses.next()) {
+        Ok(Some(address)) => address,
+        Ok(None) | Err(_) => {
+            eprintln!("Unable to resolve {}", target);
+            return 1;
+        }
+    };
+    let script = match &options.script {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => contents.lines().map(|line| format!("{}\r\n", line)).collect(),
+            Err(error) => {
+                eprintln!("Unable to read {}: {}", path.display(), error);
+                return 1;
+            }
+        },
+        None => (1..=options.messages).map(|i| format!("loadtest line {}\r\n", i)).collect::<Vec<_>>(),
+    };
+
+    println!("Opening {} connections to {} at {} per second, {} lines each",
+             options.connections, address, options.rate, script.len());
+    let started = Instant::now();
+    let (sender, receiver) = unbounded();
+    let spacing = Duration::from_secs(1) / options.rate.max(1);
+    for _ in 0..options.connections {
+        let sender = sender.clone();
+        let script = script.clone();
+        let _ = thread::spawn(move || {
+            let _ = sender.send(run_client(address, &script));
+        });
+        sleep(spacing);
+    }
+    drop(sender);
+    let outcomes: Vec<Outcome> = receiver.iter().collect();
+    report(&outcomes, started.elapsed());
+    if outcomes.iter().any(|outcome| outcome.error.is_some()) { 1 } else { 0 }
+}
+
+fn run_client(address: SocketAddr, script: &[String]) -> Outcome {
+    let mut outcome = Outcome::default();
+    let connecting = Instant::now();
+    let stream = match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+        Ok(stream) => stream,
+        Err(_) => {
+            outcome.error = Some("connect failed");
+            return outcome;
+        }
+    };
+    outcome.connect = Some(connecting.elapsed());
+    let Ok(probe) = stream.try_clone() else {
+        outcome.error = Some("read failed");
+        return outcome;
+    };
+    let mut telnet = Telnet::from_stream(Box::new(stream), 256);
+
+    // Answer whatever the server opens with; a prompt or banner is fine too.
+    let settle_until = Instant::now() + SETTLE_TIME;
+    while let Some(remaining) = settle_until.checked_duration_since(Instant::now()) {
+        match read(&mut telnet, &probe, remaining) {
+            Ok(_) => {}
+            Err(error) => {
+                outcome.error = Some(error);
+                return outcome;
+            }
+        }
+    }
+
+    for line in script {
+        // Drop the tail of the previous reply so it isn't taken for this one.
+        loop {
+            match telnet.read_nonblocking() {
+                Ok(TelnetEvent::NoData) => break,
+                Ok(TelnetEvent::Error(TelnetError::InternalQueueErr)) if peer_closed(&probe) => {
+                    outcome.error = Some("closed by server");
+                    return outcome;
+                }
+                Ok(TelnetEvent::Error(TelnetError::InternalQueueErr)) => break,
+                Ok(_) => {}
+                Err(_) => {
+                    outcome.error = Some("read failed");
+                    return outcome;
+                }
+            }
+        }
+        let sent = Instant::now();
+        if telnet.write(line.as_bytes()).is_err() {
+            outcome.error = Some("write failed");
+            return outcome;
+        }
+        loop {
+            let Some(remaining) = (sent + REPLY_TIMEOUT).checked_duration_since(Instant::now()) else {
+                outcome.error = Some("reply timed out");
+                return outcome;
+            };
+            match read(&mut telnet, &probe, remaining) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(error) => {
+                    outcome.error = Some(error);
+                    return outcome;
+                }
+            }
+        }
+        outcome.replies.push(sent.elapsed());
+        sleep(THINK_TIME);
+    }
+    outcome
+}
+
+// Reads one event, answering negotiation. True if it carried data.
+fn read(telnet: &mut Telnet, probe: &TcpStream, timeout: Duration) -> Result<bool, &'static str> {
+    match telnet.read_timeout(timeout) {
+        Ok(TelnetEvent::Data(_)) => Ok(true),
+        Ok(TelnetEvent::Negotiation(action, option)) => {
+            let reply = match (action, &option) {
+                // Let the server echo and drop go-aheads, as a real terminal would.
+                (Action::Will, TelnetOption::Echo | TelnetOption::SuppressGoAhead) => Action::Do,
+                (Action::Will, _) => Action::Dont,
+                (Action::Do, TelnetOption::SuppressGoAhead) => Action::Will,
+                (Action::Do, _) => Action::Wont,
+                (Action::Wont | Action::Dont, _) => return Ok(false),
+            };
+            telnet.negotiate(&reply, option).map_err(|_| "write failed")?;
+            Ok(false)
+        }
+        Ok(TelnetEvent::Error(TelnetError::InternalQueueErr)) if peer_closed(probe) => Err("closed by server"),
+        Ok(_) => Ok(false),
+        Err(_) => Err("read failed"),
+    }
+}
+
+// The telnet crate reports both a closed connection and a read holding only
+// part of a command as an empty event queue. Peeking at the socket tells them
+// apart.
+pub fn peer_closed(stream: &TcpStream) -> bool {
+    let _ = stream.set_nonblocking(true);
+    let closed = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(error) => error.kind() != ErrorKind::WouldBlock,
+    };
+    let _ = stream.set_nonblocking(false);
+    closed
+}
+
+fn report(outcomes: &[Outcome], elapsed: Duration) {
+    let total = outcomes.len().max(1) as f64;
+    let completed = outcomes.iter().filter(|outcome| outcome.error.is_none()).count();
+    println!();
+    println!("Finished in {:.1}s", elapsed.as_secs_f64());
+    println!("connections: {}  completed: {} ({:.1}%)", outcomes.len(), completed, completed as f64 * 100.0 / total);
+
+    let mut errors: BTreeMap<&str, usize> = BTreeMap::new();
+    for error in outcomes.iter().filter_map(|outcome| outcome.error) {
+        *errors.entry(error).or_default() += 1;
+    }
+    for (error, count) in &errors {
+        println!("  {:<18} {:>6} ({:.1}%)", error, count, *count as f64 * 100.0 / total);
+    }
+
+    print_latencies("connect", outcomes.iter().filter_map(|outcome| outcome.connect).collect());
+    print_latencies("reply", outcomes.iter().flat_map(|outcome| outcome.replies.iter().copied()).collect());
+}
+
+fn print_latencies(name: &str, mut samples: Vec<Duration>) {
+    if samples.is_empty() {
+        println!("{:<8} no samples", name);
+        return;
+    }
+    samples.sort();
+    let percentile = |p: usize| samples[((samples.len() - 1) * p).div_ceil(100)];
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    println!("{:<8} n={:<7} p50 {:>8.2}ms  p90 {:>8.2}ms  p99 {:>8.2}ms  max {:>8.2}ms",
+             name, samples.len(), ms(percentile(50)), ms(percentile(90)), ms(percentile(99)), ms(samples[samples.len() - 1]));
+}
@@ -0,0 +1,152 @@
+// Backends on the same host reached without TCP: a Unix domain socket, or a
+// named pipe such as \\.\pipe\bbs on Windows. Either carries telnet, or raw
+// bytes with telnet = false, just as a TCP connection would.
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+
+pub struct LocalSocket {
+    inner: sys::Inner,
+}
+
+impl LocalSocket {
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        sys::connect(path).map(|inner| Self { inner })
+    }
+
+    // Connects and hangs up again. A named pipe can't be probed without taking
+    // up one of its instances, so for one this only checks it exists.
+    pub fn probe(path: &Path) -> io::Result<()> {
+        sys::probe(path)
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        sys::shutdown(&mut self.inner, how)
+    }
+}
+
+impl Read for LocalSocket {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        sys::read(&mut self.inner, buffer)
+    }
+}
+
+impl Write for LocalSocket {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        sys::write(&mut self.inner, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        sys::flush(&mut self.inner)
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+
+    pub type Inner = UnixStream;
+
+    pub fn connect(path: &Path) -> io::Result<Inner> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    }
+
+    pub fn probe(path: &Path) -> io::Result<()> {
+        UnixStream::connect(path).map(drop)
+    }
+
+    pub fn shutdown(stream: &mut Inner, how: Shutdown) -> io::Result<()> {
+        stream.shutdown(how)
+    }
+
+    pub fn read(stream: &mut Inner, buffer: &mut [u8]) -> io::Result<usize> {
+        stream.read(buffer)
+    }
+
+    pub fn write(stream: &mut Inner, data: &[u8]) -> io::Result<usize> {
+        stream.write(data)
+    }
+
+    pub fn flush(stream: &mut Inner) -> io::Result<()> {
+        stream.flush()
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::c_void;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::Shutdown;
+    use std::os::windows::io::AsRawHandle;
+    use std::path::Path;
+    use std::ptr;
+
+    const ERROR_BROKEN_PIPE: i32 = 109;
+
+    // None once closed.
+    pub type Inner = Option<File>;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn PeekNamedPipe(pipe: *mut c_void, buffer: *mut c_void, size: u32, read: *mut u32, available: *mut u32,
+                         left: *mut u32) -> i32;
+    }
+
+    pub fn connect(path: &Path) -> io::Result<Inner> {
+        OpenOptions::new().read(true).write(true).open(path).map(Some)
+    }
+
+    pub fn probe(path: &Path) -> io::Result<()> {
+        std::fs::metadata(path).map(drop)
+    }
+
+    // A pipe has no half-close, so only shutting down both ways does anything:
+    // the pipe instance is given back at once for the next connection.
+    pub fn shutdown(pipe: &mut Inner, how: Shutdown) -> io::Result<()> {
+        if how == Shutdown::Both {
+            *pipe = None;
+        }
+        Ok(())
+    }
+
+    fn open(pipe: &mut Inner) -> io::Result<&mut File> {
+        pipe.as_mut().ok_or_else(|| io::Error::from(ErrorKind::NotConnected))
+    }
+
+    // Pipe handles can't be made non-blocking, so a read only goes ahead once
+    // there is something to read, as the relay expects.
+    pub fn read(pipe: &mut Inner, buffer: &mut [u8]) -> io::Result<usize> {
+        let pipe = open(pipe)?;
+        let mut available = 0u32;
+        let peeked = unsafe {
+            PeekNamedPipe(pipe.as_raw_handle(), ptr::null_mut(), 0, ptr::null_mut(), &mut available, ptr::null_mut())
+        };
+        if peeked == 0 {
+            let error = io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(ERROR_BROKEN_PIPE) => Ok(0),
+                _ => Err(error),
+            };
+        }
+        if available == 0 {
+            return Err(io::Error::from(ErrorKind::WouldBlock));
+        }
+        let size = buffer.len().min(available as usize);
+        pipe.read(&mut buffer[..size])
+    }
+
+    pub fn write(pipe: &mut Inner, data: &[u8]) -> io::Result<usize> {
+        open(pipe)?.write(data)
+    }
+
+    pub fn flush(pipe: &mut Inner) -> io::Result<()> {
+        open(pipe)?.flush()
+    }
+}
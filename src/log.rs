@@ -0,0 +1,215 @@
+// How much each subsystem logs, set from [log] at startup and changed from
+// the admin interface while the server runs, so one noisy area can be turned
+// up for an investigation without restarting or flooding the rest. Lines go
+// to stdout like every other, and so to the log file when daemonized, or to
+// the console's log pane while `--tui` has the terminal.
+//
+// With format = "json" each line is a JSON object instead, and the session
+// events the admin interface streams are logged as objects of their own, so
+// a log shipper can pick out fields without parsing text. The field names
+// (ts, level, subsystem, event, client_id, ip, backend, bytes, message, ...)
+// are kept stable.
+//
+// Text lines in a terminal are colored by level: errors red, warnings yellow
+// and debug and trace lines dim. -q, -v and -vv set every subsystem to warn,
+// debug or trace, over what [log] says.
+
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crossbeam_channel::Sender;
+
+use crate::clock::now_rfc3339;
+use crate::config::{Config, LogColor, LogFormat, LogLevel, Subsystem};
+use crate::events::{Event, EventBus};
+use crate::json::Object;
+use crate::span::Span;
+
+static LEVELS: [AtomicU8; Subsystem::ALL.len()] = [const { AtomicU8::new(LogLevel::Info as u8) }; Subsystem::ALL.len()];
+static JSON: AtomicBool = AtomicBool::new(false);
+static COLOR: AtomicU8 = AtomicU8::new(LogColor::Auto as u8);
+// Where lines go in place of stdout, while something else has the terminal.
+static CAPTURE: Mutex<Option<Sender<String>>> = Mutex::new(None);
+
+// Logs a line for a subsystem at a level, if the subsystem is logging that
+// much. A session's line names its span, which starts the line as text and
+// becomes fields in JSON:
+// `log!(Relay, Info, span = session.span(), "connected to {}", backend.name)`.
+#[macro_export]
+macro_rules! log {
+    ($subsystem:ident, $level:ident, span = $span:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::config::Subsystem::$subsystem, $crate::config::LogLevel::$level) {
+            $crate::log::write($crate::config::Subsystem::$subsystem, $crate::config::LogLevel::$level, Some($span),
+                               format_args!($($arg)*));
+        }
+    };
+    ($subsystem:ident, $level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::config::Subsystem::$subsystem, $crate::config::LogLevel::$level) {
+            $crate::log::write($crate::config::Subsystem::$subsystem, $crate::config::LogLevel::$level, None,
+                               format_args!($($arg)*));
+        }
+    };
+}
+
+// Puts the config's levels and format in place. server.trace_negotiation
+// stands for negotiation = "trace".
+pub fn configure(config: &Config) {
+    for subsystem in Subsystem::ALL {
+        let level = config.log.levels.get(&subsystem).copied().unwrap_or(LogLevel::Info);
+        set(subsystem, config.log.verbosity.unwrap_or(level));
+    }
+    if config.server.trace_negotiation && config.log.verbosity.is_none() {
+        set(Subsystem::Negotiation, LogLevel::Trace);
+    }
+    JSON.store(config.log.format == LogFormat::Json, Ordering::Relaxed);
+    COLOR.store(config.log.color as u8, Ordering::Relaxed);
+}
+
+pub fn set(subsystem: Subsystem, level: LogLevel) {
+    LEVELS[subsystem as usize].store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level(subsystem: Subsystem) -> LogLevel {
+    let stored = LEVELS[subsystem as usize].load(Ordering::Relaxed);
+    [LogLevel::Off, LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace]
+        .into_iter()
+        .find(|level| *level as u8 == stored)
+        .unwrap_or(LogLevel::Info)
+}
+
+pub fn enabled(subsystem: Subsystem, level: LogLevel) -> bool {
+    level != LogLevel::Off && level <= self::level(subsystem)
+}
+
+// Sends lines to `sender` from now on, or to stdout again with None.
+pub fn capture(sender: Option<Sender<String>>) {
+    *CAPTURE.lock().unwrap() = sender;
+}
+
+pub fn write(subsystem: Subsystem, level: LogLevel, span: Option<Span>, message: fmt::Arguments) {
+    if !JSON.load(Ordering::Relaxed) {
+        match span {
+            Some(span) => emit(Some(level), format!("{} {}", span, message)),
+            None => emit(Some(level), message.to_string()),
+        }
+        return;
+    }
+    let mut object = header(subsystem, level, "log");
+    if let Some(span) = span {
+        object = object
+            .string("client_id", &span.client_id.to_string())
+            .string("session", &Span::short_id(span.client_id))
+            .string("listener", span.listener)
+            .string("ip", &span.ip_addr.to_string())
+            .optional_string("backend", span.backend);
+    }
+    emit(None, object.string("message", &message.to_string()).finish());
+}
+
+// A text line comes with its level, to be colored by.
+fn emit(level: Option<LogLevel>, line: String) {
+    if let Some(sender) = CAPTURE.lock().unwrap().as_ref() {
+        let _ = sender.send(line);
+        return;
+    }
+    let color = match level {
+        Some(LogLevel::Error) => "\x1b[31m",
+        Some(LogLevel::Warn) => "\x1b[33m",
+        Some(LogLevel::Debug | LogLevel::Trace) => "\x1b[2m",
+        _ => "",
+    };
+    if color.is_empty() || !colored() {
+        println!("{}", line);
+    } else {
+        println!("{}{}\x1b[0m", color, line);
+    }
+}
+
+// Looked at for each line in auto, as --daemon points stdout at the log file
+// after startup.
+fn colored() -> bool {
+    match COLOR.load(Ordering::Relaxed) {
+        color if color == LogColor::Always as u8 => true,
+        color if color == LogColor::Never as u8 => false,
+        _ => env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && io::stdout().is_terminal(),
+    }
+}
+
+// With format = "json", logs the session events too, each at the level and
+// under the subsystem it belongs to.
+pub fn launch_event_log(events: &EventBus) {
+    if !JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    let receiver = events.subscribe();
+    let _ = thread::spawn(move || {
+        for event in receiver {
+            let (subsystem, level) = match &event {
+                Event::Negotiated { .. } => (Subsystem::Negotiation, LogLevel::Debug),
+                Event::BytesRelayed { .. } => (Subsystem::Relay, LogLevel::Debug),
+                Event::Connected(_) | Event::Closed { .. } => (Subsystem::Relay, LogLevel::Info),
+                Event::Error { .. } => (Subsystem::Relay, LogLevel::Error),
+                Event::Banned(_) => (Subsystem::Server, LogLevel::Warn),
+                Event::Drained { .. } | Event::Daily(_) => (Subsystem::Server, LogLevel::Info),
+            };
+            if enabled(subsystem, level) {
+                emit(None, event_object(header(subsystem, level, event.name()), &event).finish());
+            }
+        }
+    });
+}
+
+fn header(subsystem: Subsystem, level: LogLevel, event: &str) -> Object {
+    Object::new()
+        .string("ts", &now_rfc3339())
+        .string("level", level.name())
+        .string("subsystem", subsystem.name())
+        .string("event", event)
+}
+
+fn event_object(object: Object, event: &Event) -> Object {
+    match event {
+        Event::Connected(session) => object
+            .string("client_id", &session.client_id.to_string())
+            .string("session", &Span::short_id(session.client_id))
+            .string("listener", session.listener)
+            .string("ip", &session.ip_addr.to_string())
+            .string("backend", &session.backend)
+            .optional_string("user", session.user.as_ref().map(|user| user.username.as_str())),
+        Event::Negotiated { client_id, action, option } => object
+            .string("client_id", &client_id.to_string())
+            .string("session", &Span::short_id(*client_id))
+            .string("action", action)
+            .string("option", &format!("{:?}", option)),
+        Event::BytesRelayed { client_id, to_backend, to_client } => object
+            .string("client_id", &client_id.to_string())
+            .string("session", &Span::short_id(*client_id))
+            .number("bytes", to_backend + to_client)
+            .number("to_backend", *to_backend)
+            .number("to_client", *to_client),
+        Event::Closed { session, duration } => object
+            .string("client_id", &session.client_id.to_string())
+            .string("session", &Span::short_id(session.client_id))
+            .string("listener", session.listener)
+            .string("ip", &session.ip_addr.to_string())
+            .string("backend", &session.backend)
+            .optional_string("user", session.user.as_ref().map(|user| user.username.as_str()))
+            .number("duration", duration.as_secs()),
+        Event::Banned(ban) => object
+            .string("ip", &ban.ip_addr.to_string())
+            .string("reason", &ban.reason)
+            .number("duration", ban.remaining().as_secs())
+            .number("strikes", ban.strikes as u64),
+        Event::Error { client_id, ip_addr, message } => object
+            .string("client_id", &client_id.to_string())
+            .string("session", &Span::short_id(*client_id))
+            .string("ip", &ip_addr.to_string())
+            .string("message", message),
+        Event::Drained { backend } => object.string("backend", backend),
+        Event::Daily(summary) => summary.fields(object),
+    }
+}
@@ -0,0 +1,364 @@
+use std::io::{ErrorKind, Read, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use telnet::{Action, TelnetOption};
+
+use crate::codec::{self, Frame, Parser};
+use crate::config::TerminalClass;
+use crate::downstream::Downstream;
+use crate::log;
+use crate::users::{User, UserStore};
+
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const DO: u8 = 253;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TTYPE: u8 = 24;
+const TTYPE_IS: u8 = 0;
+const TTYPE_SEND: u8 = 1;
+
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+// Terminals answer at once; anything silent this long won't say.
+const TERMINAL_TYPE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_FIELD_LENGTH: usize = 64;
+// Terminal types that do ANSI, for terminals that don't answer the probe.
+const ANSI_TERMINALS: &[&str] = &["ansi", "vt1", "vt2", "vt3", "vt4", "xterm", "linux", "screen", "syncterm", "cterm",
+                                  "netrunner", "putty", "qodem"];
+// Terminal types that show UTF-8, for terminals that neither report MTTS nor
+// were probed.
+const UTF8_TERMINALS: &[&str] = &["utf-8", "utf8", "xterm", "rxvt", "kitty", "alacritty", "tmux"];
+// The MTTS bit for a terminal that shows UTF-8.
+const MTTS_UTF8: u32 = 4;
+
+pub enum PromptError {
+    TimedOut,
+    Disconnected,
+}
+
+// Line input on the raw client stream, used for everything the proxy asks
+// the caller before a backend is dialed.
+pub struct Prompt {
+    echo_negotiated: bool,
+    // Swallow the LF or NUL that telnet sends after a CR.
+    skip_after_cr: bool,
+}
+
+impl Prompt {
+    pub fn new() -> Self {
+        Self { echo_negotiated: false, skip_after_cr: false }
+    }
+
+    pub fn read_line(&mut self, stream: &mut Downstream, text: &str, echo: bool, deadline: Instant) -> Result<String, PromptError> {
+        if !self.echo_negotiated {
+            // We echo input ourselves so passwords can be hidden.
+            write(stream, &[IAC, WILL, ECHO, IAC, WILL, SUPPRESS_GO_AHEAD])?;
+            self.echo_negotiated = true;
+        }
+        write(stream, text.as_bytes())?;
+
+        let mut line = String::new();
+        loop {
+            let byte = read_byte(stream, deadline)?;
+            if self.skip_after_cr {
+                self.skip_after_cr = false;
+                if byte == b'\n' || byte == 0 {
+                    continue;
+                }
+            }
+            match byte {
+                IAC => skip_command(stream, deadline)?,
+                b'\r' | b'\n' => {
+                    self.skip_after_cr = byte == b'\r';
+                    // Usually the LF is already waiting; consume it now so it
+                    // is not relayed to the backend after the last prompt.
+                    let mut next = [0u8; 1];
+                    if self.skip_after_cr && matches!(stream.peek(&mut next), Ok(1) if next[0] == b'\n' || next[0] == 0) {
+                        let _ = stream.read(&mut next);
+                        self.skip_after_cr = false;
+                    }
+                    write(stream, b"\r\n")?;
+                    return Ok(line);
+                }
+                0x08 | 0x7f => {
+                    let erased = line.pop().is_some();
+                    if erased && echo {
+                        write(stream, b"\x08 \x08")?;
+                    }
+                }
+                0x20..=0x7e if line.len() < MAX_FIELD_LENGTH => {
+                    line.push(byte as char);
+                    if echo {
+                        write(stream, &[byte])?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Prompts for a username and password. Returns None when the caller runs out
+// of attempts, times out or hangs up. `on_failure` is told about each bad
+// attempt and returns false to stop prompting.
+pub fn login(stream: &mut Downstream, prompt: &mut Prompt, store: &UserStore, max_attempts: u32,
+             mut on_failure: impl FnMut() -> bool) -> Option<User> {
+    let deadline = Instant::now() + LOGIN_TIMEOUT;
+    for attempt in 1..=max_attempts {
+        let (username, password) = match read_credentials(stream, prompt, deadline) {
+            Ok(credentials) => credentials,
+            Err(PromptError::TimedOut) => {
+                let _ = stream.write_all(b"\r\nLogin timed out.\r\n");
+                return None;
+            }
+            Err(PromptError::Disconnected) => return None,
+        };
+        if username.is_empty() {
+            continue;
+        }
+
+        match store.authenticate(&username, &password) {
+            Ok(Some(user)) => {
+                stream.write_all(format!("Welcome, {}.\r\n", user.username).as_bytes()).ok()?;
+                return Some(user);
+            }
+            Ok(None) => {
+                log!(Relay, Warn, "Failed login for user '{}' (attempt {} of {})", username, attempt, max_attempts);
+                stream.write_all(b"Invalid username or password.\r\n").ok()?;
+                if !on_failure() {
+                    return None;
+                }
+            }
+            Err(error) => {
+                log!(Relay, Warn, "User store error during login: {}", error);
+                stream.write_all(b"Login is currently unavailable.\r\n").ok()?;
+                return None;
+            }
+        }
+    }
+    let _ = stream.write_all(b"Too many failed attempts. Goodbye.\r\n");
+    None
+}
+
+fn read_credentials(stream: &mut Downstream, prompt: &mut Prompt, deadline: Instant) -> Result<(String, String), PromptError> {
+    let username = prompt.read_line(stream, "\r\nUsername: ", true, deadline)?;
+    if username.is_empty() {
+        return Ok((username, String::new()));
+    }
+    let password = prompt.read_line(stream, "Password: ", false, deadline)?;
+    Ok((username, password))
+}
+
+// Offers to resume a dropped session. An empty line or no answer within the
+// timeout continues with a fresh session.
+pub fn read_resume_code(stream: &mut Downstream, prompt: &mut Prompt, timeout: Duration) -> Result<Option<String>, PromptError> {
+    let text = "\r\nPress ENTER to continue, or type your resume code: ";
+    match prompt.read_line(stream, text, true, Instant::now() + timeout) {
+        Ok(code) if code.trim().is_empty() => Ok(None),
+        Ok(code) => Ok(Some(code)),
+        Err(PromptError::TimedOut) => {
+            write(stream, b"\r\n")?;
+            Ok(None)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// Asks the caller's terminal for its type (RFC 1091). None if it refuses or
+// doesn't answer in time. Anything typed meanwhile is dropped.
+pub fn read_terminal_type(stream: &mut Downstream) -> Result<Option<String>, PromptError> {
+    write(stream, &[IAC, DO, TTYPE])?;
+    let deadline = Instant::now() + TERMINAL_TYPE_TIMEOUT;
+    let mut parser = Parser::new();
+    loop {
+        let byte = match read_byte(stream, deadline) {
+            Ok(byte) => byte,
+            Err(PromptError::TimedOut) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        for frame in parser.feed(&[byte]) {
+            match frame {
+                Frame::Negotiation(Action::Will, TelnetOption::TTYPE) => {
+                    write(stream, &codec::subnegotiation(TelnetOption::TTYPE, &[TTYPE_SEND]))?;
+                }
+                Frame::Negotiation(Action::Wont, TelnetOption::TTYPE) => return Ok(None),
+                Frame::Subnegotiation(TelnetOption::TTYPE, payload) => {
+                    if let [TTYPE_IS, name @ ..] = payload.as_slice() {
+                        return Ok(Some(String::from_utf8_lossy(name).trim().to_string()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Asks a terminal that has reported its type for the next ones on its list
+// (RFC 1091 cycling), looking for the "MTTS <bits>" that clients following
+// the MUD Terminal Type Standard report third. None if the list ends or stops
+// being answered first.
+pub fn read_mtts(stream: &mut Downstream, reported: &str) -> Result<Option<u32>, PromptError> {
+    let deadline = Instant::now() + TERMINAL_TYPE_TIMEOUT;
+    let mut parser = Parser::new();
+    let mut previous = reported.to_string();
+    for _ in 0..2 {
+        write(stream, &codec::subnegotiation(TelnetOption::TTYPE, &[TTYPE_SEND]))?;
+        let name = 'reply: loop {
+            let byte = match read_byte(stream, deadline) {
+                Ok(byte) => byte,
+                Err(PromptError::TimedOut) => return Ok(None),
+                Err(error) => return Err(error),
+            };
+            for frame in parser.feed(&[byte]) {
+                if let Frame::Subnegotiation(TelnetOption::TTYPE, payload) = frame {
+                    if let [TTYPE_IS, name @ ..] = payload.as_slice() {
+                        break 'reply String::from_utf8_lossy(name).trim().to_string();
+                    }
+                }
+            }
+        };
+        if let Some(bits) = name.strip_prefix("MTTS ") {
+            return Ok(bits.trim().parse().ok());
+        }
+        // A terminal with nothing more to report repeats the last type.
+        if name == previous {
+            return Ok(None);
+        }
+        previous = name;
+    }
+    Ok(None)
+}
+
+// Whether the caller's terminal shows UTF-8 rather than CP437: as its MTTS
+// bits say, else as the probe found, else by what its type is known to do.
+// A terminal nothing can be told about is taken for CP437, as BBS callers'
+// mostly are.
+pub fn shows_utf8(mtts: Option<u32>, terminal_class: Option<TerminalClass>, terminal_type: Option<&str>) -> bool {
+    match (mtts, terminal_class) {
+        (Some(bits), _) => bits & MTTS_UTF8 != 0,
+        (None, Some(TerminalClass::Utf8)) => true,
+        (None, Some(TerminalClass::Ansi)) => false,
+        (None, Some(TerminalClass::Plain) | None) => terminal_type.map(str::to_ascii_lowercase)
+            .is_some_and(|name| UTF8_TERMINALS.iter().any(|utf8| name.contains(utf8))),
+    }
+}
+
+// Agrees to binary mode both ways, which SyncTERM asks for as it connects and
+// needs for 8-bit art and file transfers. Having asked, it takes these as
+// the answer and doesn't reply.
+pub fn agree_binary(stream: &mut Downstream) -> Result<(), PromptError> {
+    write(stream, &[IAC, WILL, BINARY, IAC, DO, BINARY])
+}
+
+// Sorts the caller's terminal into plain, ANSI or UTF-8 by asking it: for its
+// device attributes (DA) and where its cursor is (DSR 6), which only an ANSI
+// terminal answers, then, after one two-byte UTF-8 character, where its
+// cursor is again. A UTF-8 terminal has moved one column; a CP437 one has
+// drawn two characters. The character is rubbed out afterwards. A terminal
+// that doesn't answer is plain, unless its type names an ANSI one. Anything
+// typed meanwhile is dropped.
+pub fn probe_terminal(stream: &mut Downstream, terminal_type: Option<&str>) -> Result<TerminalClass, PromptError> {
+    let mut parser = Parser::new();
+    let mut reply = Vec::new();
+    write(stream, b"\x1b[c\x1b[6n")?;
+    let Some(before) = read_cursor_column(stream, &mut parser, &mut reply)? else {
+        let attributes = reply.windows(3).any(|window| window == b"\x1b[?") && reply.ends_with(b"c");
+        let named = terminal_type.map(str::to_ascii_lowercase)
+            .is_some_and(|name| ANSI_TERMINALS.iter().any(|ansi| name.contains(ansi)));
+        return Ok(if attributes || named { TerminalClass::Ansi } else { TerminalClass::Plain });
+    };
+    reply.clear();
+    write(stream, "\u{e9}\x1b[6n".as_bytes())?;
+    let after = read_cursor_column(stream, &mut parser, &mut reply)?;
+    write(stream, format!("\x1b[{}G\x1b[K", before).as_bytes())?;
+    Ok(match after {
+        Some(after) if after == before + 1 => TerminalClass::Utf8,
+        _ => TerminalClass::Ansi,
+    })
+}
+
+// The column of the next cursor position report, None if none comes in time.
+// What the terminal sent is left in reply.
+fn read_cursor_column(stream: &mut Downstream, parser: &mut Parser, reply: &mut Vec<u8>) -> Result<Option<u32>, PromptError> {
+    let deadline = Instant::now() + TERMINAL_TYPE_TIMEOUT;
+    loop {
+        let byte = match read_byte(stream, deadline) {
+            Ok(byte) => byte,
+            Err(PromptError::TimedOut) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        for frame in parser.feed(&[byte]) {
+            if let Frame::Data(data) = frame {
+                reply.extend_from_slice(&data);
+            }
+        }
+        if reply.ends_with(b"R") {
+            if let Some(column) = cursor_column(reply) {
+                return Ok(Some(column));
+            }
+        }
+    }
+}
+
+// The column of an ESC [ row ; column R report at the end of reply.
+fn cursor_column(reply: &[u8]) -> Option<u32> {
+    let start = reply.windows(2).rposition(|window| window == b"\x1b[")? + 2;
+    let report = std::str::from_utf8(&reply[start..reply.len() - 1]).ok()?;
+    let (row, column) = report.split_once(';')?;
+    row.parse::<u32>().ok()?;
+    column.parse().ok()
+}
+
+fn write(stream: &mut Downstream, bytes: &[u8]) -> Result<(), PromptError> {
+    stream.write_all(bytes).map_err(|_| PromptError::Disconnected)
+}
+
+fn read_byte(stream: &mut Downstream, deadline: Instant) -> Result<u8, PromptError> {
+    let mut byte = [0u8; 1];
+    loop {
+        match stream.read(&mut byte) {
+            Ok(0) => return Err(PromptError::Disconnected),
+            Ok(_) => return Ok(byte[0]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(PromptError::TimedOut);
+                }
+                sleep(Duration::from_millis(10));
+            }
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return Err(PromptError::Disconnected),
+        }
+    }
+}
+
+// Negotiation from the client while prompting is not answered; the backend
+// negotiates for itself once the relay starts.
+fn skip_command(stream: &mut Downstream, deadline: Instant) -> Result<(), PromptError> {
+    match read_byte(stream, deadline)? {
+        SB => {
+            let mut previous = 0;
+            loop {
+                let byte = read_byte(stream, deadline)?;
+                if previous == IAC && byte == SE {
+                    return Ok(());
+                }
+                previous = if previous == IAC && byte == IAC { 0 } else { byte };
+            }
+        }
+        251..=254 => {
+            read_byte(stream, deadline)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -1,302 +1,319 @@
-use std::collections::{HashMap};
-use std::io::{Read, Write};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::{thread};
-use std::str::FromStr;
-use std::thread::sleep;
-use std::time::Duration;
-use uuid::Uuid;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::process::exit;
+use std::sync::Arc;
 
-use codepage_437::{CP437_CONTROL, FromCp437};
-use crossbeam_channel::{Receiver, Sender, TryRecvError, unbounded};
-use telnet::{Telnet, Event as TelnetEvent, TelnetOption, Action, TelnetError};
 use local_ip_address::local_ip;
 
-pub enum ClientManagerMessage {
-    Connect {
-        stream: TcpStream
-    },
-    ConnectionClosed {
-        client_id: Uuid
-    },
-}
-
-#[derive(Clone)]
-pub struct ClientConnection {
-    client_id: Uuid,
-    ip_addr: IpAddr,
-}
+use triserver::cli::{self, Args, Command, LoadTestOptions, RemoteCommand, ServeMode, ServiceCommand, UserCommand};
+use triserver::config::{schema, Config};
+use triserver::users::{self, UserStore};
+use triserver::{admin, check, loadtest, log, mock, serve, serve_stdio, version};
+#[cfg(unix)]
+use triserver::daemon;
+#[cfg(windows)]
+use triserver::{config, service};
+#[cfg(target_os = "linux")]
+use triserver::{lock_instance, start_daemon, workers};
 
-#[derive(Clone)]
-pub struct SharedClientMap {
-    inner: Arc<Mutex<SharedMapInner>>,
-}
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}", message);
+            }
+            eprintln!("{}", cli::USAGE);
+            exit(2);
+        }
+    };
+    if let Command::Version = args.command {
+        println!("{}", version::describe());
+        return;
+    }
+    if let Command::ConfigSchema { example } = args.command {
+        print!("{}", if example { schema::example() } else { schema::describe() });
+        return;
+    }
+    if let Command::Check = args.command {
+        exit(check::run(args.config_path.as_deref()));
+    }
+    if let Command::MockBackend(options) = args.command {
+        exit(mock::run(options));
+    }
+    let config = match Config::load(args.config_path.as_deref()) {
+        Ok(mut config) => {
+            config.log.verbosity = args.verbosity;
+            Arc::new(config)
+        }
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            exit(1);
+        }
+    };
+    if let Command::Remote(command) = &args.command {
+        exit(run_remote_command(&config, command));
+    }
+    if let Command::LoadTest(options) = &args.command {
+        exit(run_load_test(&config, options));
+    }
+    if let Command::Stop = args.command {
+        exit(stop_daemon(&config));
+    }
+    if let Command::Service(command) = &args.command {
+        exit(manage_service(args.config_path.as_deref(), command));
+    }
+    // The service control manager starts services in System32; relative paths
+    // in the config are meant relative to the config file.
+    #[cfg(windows)]
+    if let (Command::Serve(ServeMode::Service), Some(directory)) = (&args.command, args.config_path.as_deref().and_then(Path::parent)) {
+        let _ = env::set_current_dir(directory);
+    }
+    let user_store = config.users.as_ref().map(|users| {
+        match UserStore::open(&users.database, users.default_time_limit) {
+            Ok(store) => Arc::new(store),
+            Err(error) => {
+                eprintln!("Unable to open user database {}: {}", users.database.display(), error);
+                exit(1);
+            }
+        }
+    });
 
-struct SharedMapInner {
-    data: HashMap<uuid::Uuid, ClientConnection>,
-}
+    let mode = match args.command {
+        Command::Serve(mode) => mode,
+        Command::Version | Command::Check | Command::ConfigSchema { .. } | Command::MockBackend(_) | Command::Remote(_) | Command::LoadTest(_) | Command::Stop
+        | Command::Service(_) => {
+            unreachable!("handled before the user store is opened")
+        }
+        Command::User(command) => match user_store {
+            Some(store) => exit(run_user_command(&store, command)),
+            None => {
+                eprintln!("The user store is not enabled; add a [users] section to the config file.");
+                exit(1);
+            }
+        },
+    };
 
-impl SharedClientMap {
-    pub fn new() -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(SharedMapInner {
-                data: HashMap::new(),
-            }))
+    log::configure(&config);
+    for unknown in &config.unknown_keys {
+        log!(Server, Warn, "Config: {}, ignored", unknown);
+    }
+    let worker_count = config.workers.as_ref().map_or(1, |workers| workers.count);
+    match mode {
+        ServeMode::Foreground | ServeMode::Daemon if worker_count > 1 => {
+            supervise_workers(&config, matches!(mode, ServeMode::Daemon))
         }
+        ServeMode::Tui if worker_count > 1 => {
+            eprintln!("--tui doesn't work with [workers]; use the admin console instead.");
+            exit(1);
+        }
+        #[cfg(not(target_os = "linux"))]
+        ServeMode::Tui => {
+            eprintln!("--tui is only supported on Linux.");
+            exit(1);
+        }
+        ServeMode::Service => run_as_service(config, user_store),
+        ServeMode::Stdio => serve_stdio(config, user_store),
+        mode => serve(config, user_store, mode, || true),
     }
+}
 
-    pub fn insert(&self, key: uuid::Uuid, value: ClientConnection) {
-        let mut lock = self.inner.lock().unwrap();
-        lock.data.insert(key, value);
-    }
 
-    pub fn get(&self, key: uuid::Uuid) -> Option<ClientConnection> {
-        let lock = self.inner.lock().unwrap();
-        lock.data.get(&key).cloned()
+#[cfg(windows)]
+fn run_as_service(config: Arc<Config>, user_store: Option<Arc<UserStore>>) {
+    let log_file = config.daemon.clone().unwrap_or_default().log_file;
+    if let Err(error) = service::redirect_output(&log_file) {
+        eprintln!("Unable to open {}: {}", log_file.display(), error);
     }
-
-    pub fn remove(&self, key: uuid::Uuid) {
-        let mut lock = self.inner.lock().unwrap();
-        let _ = lock.data.remove(&key);
+    if let Err(error) = service::run(move || serve(config, user_store, ServeMode::Service, || !service::stop_requested())) {
+        eprintln!("{}", error);
+        exit(1);
     }
+}
 
-    pub fn len(&self) -> usize {
-        let lock = self.inner.lock().unwrap();
-        lock.data.len()
-    }
+#[cfg(not(windows))]
+fn run_as_service(_config: Arc<Config>, _user_store: Option<Arc<UserStore>>) {
+    eprintln!("--service is only supported on Windows.");
+    exit(1);
 }
 
+#[cfg(windows)]
+fn manage_service(config_path: Option<&Path>, command: &ServiceCommand) -> i32 {
+    let result = match command {
+        ServiceCommand::Install => {
+            // The service starts in System32, so it needs the full path.
+            let config_path = config_path.unwrap_or(Path::new(config::DEFAULT_CONFIG_PATH));
+            match config_path.canonicalize() {
+                Ok(config_path) => service::install(&config_path).map(|()| "Installed the TriServer service"),
+                Err(error) => Err(format!("{}: {}", config_path.display(), error)),
+            }
+        }
+        ServiceCommand::Uninstall => service::uninstall().map(|()| "Removed the TriServer service"),
+    };
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            1
+        }
+    }
+}
 
-#[derive(Clone)]
-pub struct ClientManager {
-    receiver: Receiver<ClientManagerMessage>,
-    clients: SharedClientMap,
+#[cfg(not(windows))]
+fn manage_service(_config_path: Option<&Path>, _command: &ServiceCommand) -> i32 {
+    eprintln!("Services are only supported on Windows; use --daemon or a systemd unit instead.");
+    1
 }
 
-impl ClientManager {
-    pub fn new(receiver: Receiver<ClientManagerMessage>) -> Self {
-        Self {
-            receiver,
-            clients: SharedClientMap::new(),
-        }
+#[cfg(target_os = "linux")]
+fn supervise_workers(config: &Config, daemon: bool) {
+    lock_instance(config, daemon);
+    if daemon {
+        start_daemon(config);
     }
+    let workers = config.workers.clone().expect("only called with a [workers] section");
+    log!(Server, Info, "{}", version::describe());
+    log!(Server, Info, "Starting {} workers on port {}", workers.count, config.server.port);
+    workers::supervise(workers.count, &workers.stats_file);
+}
 
-    pub fn receive(&self) -> Result<ClientManagerMessage, TryRecvError> {
-        return self.receiver.try_recv();
+#[cfg(not(target_os = "linux"))]
+fn supervise_workers(_config: &Config, _daemon: bool) {
+    eprintln!("[workers] is only supported on Linux.");
+    exit(1);
+}
+
+#[cfg(unix)]
+fn stop_daemon(config: &Config) -> i32 {
+    match daemon::stop(&config.daemon.clone().unwrap_or_default()) {
+        Ok(pid) => {
+            println!("Stopped TriServer (PID {})", pid);
+            0
+        }
+        Err(error) => {
+            eprintln!("Unable to stop TriServer: {}", error);
+            1
+        }
     }
 }
 
-fn main() {
-    let tcp_listener = start_telnet_server();
-    let (client_manager_tx, client_manager_rx) = unbounded();
-    launch_client_manager(client_manager_tx.clone(), client_manager_rx);
+#[cfg(not(unix))]
+fn stop_daemon(_config: &Config) -> i32 {
+    eprintln!("stop is only supported on Unix.");
+    1
+}
 
-    for stream in tcp_listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                stream.set_nonblocking(true).expect("Error setting stream to non-blocking");
-                client_manager_tx.try_send(ClientManagerMessage::Connect { stream }).unwrap()
+fn run_remote_command(config: &Config, command: &RemoteCommand) -> i32 {
+    let Some(admin_config) = &config.admin else {
+        eprintln!("The admin interface is not enabled; add an [admin] section to the config file.");
+        return 1;
+    };
+    match admin::send_command(admin_config, &command.line()) {
+        Ok(output) => {
+            for line in output {
+                println!("{}", line);
             }
-            _ => {}
+            0
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            1
         }
     }
 }
 
-fn start_telnet_server() -> TcpListener {
-    let local_ip_address = local_ip().unwrap();
-    println!("{}", local_ip_address);
-    let address = local_ip_address.to_string() + ":9000";
-    let listener = TcpListener::bind(address.clone()).unwrap();
-    listener.set_nonblocking(true).expect("Cannot set non-blocking");
-    println!("Telnet Server Listening on: {}", address);
-    listener
+fn run_load_test(config: &Config, options: &LoadTestOptions) -> i32 {
+    let target = match &options.target {
+        Some(target) => target.clone(),
+        None => {
+            let host = match &config.server.address {
+                Some(address) => address.clone(),
+                None => local_ip().map_or_else(|_| String::from("127.0.0.1"), |ip| ip.to_string()),
+            };
+            format!("{}:{}", host, config.server.port)
+        }
+    };
+    loadtest::run(&target, options)
 }
 
-fn launch_client_manager(sender: Sender<ClientManagerMessage>, receiver: Receiver<ClientManagerMessage>) {
-    let client_manager = ClientManager::new(receiver);
-    let _ = thread::spawn(
-        move || {
-            loop {
-                match client_manager.receive() {
-                    Ok(client_manager_message) => {
-                        match client_manager_message {
-                            ClientManagerMessage::Connect { stream } => {
-                                println!("TCP Connect event received");
-                                // TODO Log connection
-                                // TODO: Validate IP address before connecting to server
-                                let client_manager_sender = sender.clone();
-                                let client_id = Uuid::new_v4();
-                                let client_connection = create_client_connection(client_id, stream, client_manager_sender);
-                                println!("Client Connection created - Client ID: {} | Client IP Address: {}", client_id, client_connection.ip_addr);
-                                if client_id == client_connection.client_id {
-                                    let _ = client_manager.clients.insert(client_id, client_connection);
-                                    println!("Inserted Client ID: {} to into Client Map", client_id)
-                                }
-                            }
-                            ClientManagerMessage::ConnectionClosed { client_id } => {
-                                // TODO Log message received from client
-                                match client_manager.clients.get(client_id) {
-                                    Some(client_connection) => {
-                                        if client_id == client_connection.client_id {
-                                            let _ = client_manager.clients.remove(client_id);
-                                            println!("Client ID: {} removed from client map.", client_id);
-                                        }
-                                    }
-                                    _ => { println!("No Client Mapping Data for Client ID: {}", client_id) }
-                                };
-                            }
-                        }
-                    }
-                    _ => {}
+fn run_user_command(store: &UserStore, command: UserCommand) -> i32 {
+    match command {
+        UserCommand::Add { username, backend, time_limit } => {
+            let password = match cli::read_password() {
+                Ok(password) => password,
+                Err(error) => {
+                    eprintln!("Unable to add user {}: {}", username, error);
+                    return 1;
+                }
+            };
+            match store.add_user(&username, &password, backend.as_deref(), time_limit) {
+                Ok(()) => {
+                    println!("Added user {}", username);
+                    0
+                }
+                Err(error) => {
+                    eprintln!("Unable to add user {}: {}", username, error);
+                    1
                 }
-                sleep(Duration::from_nanos(10))
             }
         }
-    );
-}
-
-fn create_client_connection(client_id: uuid::Uuid, stream: TcpStream, client_manager_tx: Sender<ClientManagerMessage>) -> ClientConnection {
-    let ip_addr = stream.peer_addr().unwrap().ip();
-    let client_connection = ClientConnection { client_id, ip_addr };
-    let mut _stream = stream.try_clone().expect("clone failed...");
-    let _ = thread::spawn(
-        move || {
-            let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::from_str("172.250.225.86")
-                .expect("Invalid address")), 2727);
-            let buffer_size = 256;
-            let mut telnet = Telnet::connect_timeout(&address, buffer_size, Duration::from_secs(10))
-                .expect("Couldn't connect to the server...");
-            let client_id = client_id;
-            println!("Client ID: {} connected to Telnet Server", client_id);
-            loop {
-                const MESSAGE_SIZE: usize = 1;
-                let mut rx_bytes = [0u8; MESSAGE_SIZE];
-                match _stream.read(&mut rx_bytes) {
-                    Ok(bytes_read) => {
-                        if bytes_read > 0 {
-                            telnet.write(&rx_bytes).unwrap();
-                        }
-                    }
-                    _ => {}
+        UserCommand::Remove { username } => match store.remove_user(&username) {
+            Ok(true) => {
+                println!("Removed user {}", username);
+                0
+            }
+            Ok(false) => {
+                eprintln!("No such user {}", username);
+                1
+            }
+            Err(error) => {
+                eprintln!("Unable to remove user {}: {}", username, error);
+                1
+            }
+        },
+        UserCommand::Calls { username } => match store.calls(username.as_deref(), 50) {
+            Ok(calls) => {
+                for call in calls {
+                    let duration = call.duration.map(|secs| format!("{}m{:02}s", secs / 60, secs % 60));
+                    println!("{:>6} {:<16} {:<40} {:<16} {:>12} {}",
+                             call.id, call.username, call.ip_addr, call.backend, call.started_at,
+                             duration.unwrap_or_else(|| String::from("online")));
                 }
-
-                let event = telnet.read_nonblocking().expect("Telnet Read Error");
-                match event {
-                    TelnetEvent::Data(buffer) => {
-                        // let response = String::from_cp437(buffer.into_vec(), &CP437_CONTROL);
-                        // _stream.write_all(response.as_bytes()).expect("TCP Stream Write All Error");
-                        _stream.write_all(&buffer).expect("TCP Stream Write All Error");
-                        _stream.flush().expect("TCP Stream Flush Error");
-                    }
-                    TelnetEvent::Negotiation(action, option) => {
-                        // Action Do, Option: SNDLOC
-                        // Action Will, Option: Echo -- default is to not echo
-                        // Action Will, Option: SuppressGoAhead -- default is not to supress go ahead
-                        // Action Do, Option: TransmitBinary --- default is to not transmit binary
-                        // Action Do, Option: TTYPE
-
-                        match option {
-                            TelnetOption::SNDLOC => {
-                                match action {
-                                    Action::Do => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-
-                                        let location = String::from(ip_addr.to_string());
-                                        telnet.subnegotiate(TelnetOption::SNDLOC, &location.as_bytes().to_vec()).unwrap();
-                                    }
-                                    Action::Dont => {
-                                        telnet.negotiate(&Action::Wont, option).unwrap();
-                                    }
-                                    Action::Will => {
-                                        telnet.negotiate(&Action::Dont, option).unwrap();
-                                    }
-                                    Action::Wont => {
-                                        telnet.negotiate(&Action::Dont, option).unwrap();
-                                    }
-                                }
-                            }
-                            TelnetOption::Echo => {
-                                match action {
-                                    Action::Do => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-                                    }
-                                    Action::Dont => {
-                                        telnet.negotiate(&Action::Wont, option).unwrap();
-                                    }
-                                    Action::Will => {
-                                        telnet.negotiate(&Action::Do, option).unwrap();
-                                    }
-                                    Action::Wont => {
-                                        telnet.negotiate(&Action::Dont, option).unwrap();
-                                    }
-                                }
-                            }
-                            TelnetOption::SuppressGoAhead => {
-                                match action {
-                                    Action::Do => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-                                    }
-                                    Action::Dont => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-                                    }
-                                    Action::Will => {
-                                        telnet.negotiate(&Action::Do, option).unwrap();
-                                    }
-                                    Action::Wont => {
-                                        telnet.negotiate(&Action::Do, option).unwrap();
-                                    }
-                                }
-                            }
-                            TelnetOption::TransmitBinary => {
-                                match action {
-                                    Action::Do => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-                                    }
-                                    Action::Dont => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-                                    }
-                                    Action::Will => {
-                                        telnet.negotiate(&Action::Do, option).unwrap();
-                                    }
-                                    Action::Wont => {
-                                        telnet.negotiate(&Action::Do, option).unwrap();
-                                    }
-                                }
-                            }
-                            TelnetOption::TTYPE => {
-                                match action {
-                                    Action::Do => {
-                                        telnet.negotiate(&Action::Will, option).unwrap();
-                                        // TODO: Send actual terminal type
-                                        let terminal_type = String::from("ansi-bbs");
-                                        telnet.subnegotiate(TelnetOption::TTYPE, &terminal_type.as_bytes().to_vec()).unwrap();
-                                    }
-                                    _ => ()
-                                }
-                            }
-                            _ => ()
-                        }
-                    }
-                    TelnetEvent::Error(error) => {
-                        match error {
-                            TelnetError::InternalQueueErr => {
-                                println!("Internal Queue Error Occurred for Client ID: {}. Transmitting close message.", client_id);
-                                break;
-                            }
-                            _ => {}
-                        }
-                    }
-                    TelnetEvent::UnknownIAC(_) => {}
-                    TelnetEvent::Subnegotiation(_, _) => {}
-                    TelnetEvent::TimedOut => { println!("Timed out") }
-                    TelnetEvent::NoData => {}
+                0
+            }
+            Err(error) => {
+                eprintln!("Unable to read call history: {}", error);
+                1
+            }
+        },
+        UserCommand::Export { format, since, until, output } => {
+            let calls = match store.calls_between(since, until) {
+                Ok(calls) => calls,
+                Err(error) => {
+                    eprintln!("Unable to read call history: {}", error);
+                    return 1;
+                }
+            };
+            let written = match &output {
+                Some(path) => File::create(path).and_then(|file| users::export_calls(&calls, format, &mut BufWriter::new(file))),
+                None => users::export_calls(&calls, format, &mut io::stdout().lock()),
+            };
+            match (written, &output) {
+                (Ok(()), Some(path)) => {
+                    println!("Exported {} call(s) to {}", calls.len(), path.display());
+                    0
+                }
+                (Ok(()), None) => 0,
+                (Err(error), _) => {
+                    eprintln!("Unable to export call history: {}", error);
+                    1
                 }
-                sleep(Duration::from_nanos(10))
             }
-            client_manager_tx.try_send(ClientManagerMessage::ConnectionClosed { client_id }).unwrap();
-            println!("Client ID: {} - Telnet Connection Closed", client_id)
         }
-    );
-    client_connection
+    }
 }
@@ -0,0 +1,223 @@
+// Figures for a Prometheus scrape of the HTTP interface's /metrics. How long
+// backends take to connect and to start relaying, how long callers' typing
+// waits on a backend that isn't taking it, and how long sessions last, are
+// histograms, so a slow backend shows up as numbers rather than as callers
+// saying it feels laggy; the client manager records most of them as sessions
+// move from one state to the next, and sessions their own stalls. The
+// sessions connected now, by listener and by backend, are counted from the
+// client map at each scrape, alongside the map's size, the session threads
+// running and the client manager's queue. The backends' failures, and the
+// callers let go for falling too far behind, come from the fault counts; the
+// callers turned away for the client manager being too far behind are
+// counted where they're turned away. The server's own threads, memory and
+// file descriptors are read at each scrape too.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::faults::Fault;
+use crate::resources;
+use crate::ServerContext;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// Upper bounds in seconds: the few milliseconds of a local backend up to the
+// connect timeout, and a quick look in up to a long evening online.
+const CONNECT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const SESSION_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0];
+// A backend catching up on a paste, to one that has stopped reading.
+const STALL_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    // One count per bound, of observations at or below it and above the one before.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        if let Some(i) = self.bounds.iter().position(|&bound| seconds <= bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, self.count);
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+struct Histograms {
+    // By backend name.
+    connect: BTreeMap<String, Histogram>,
+    first_output: BTreeMap<String, Histogram>,
+    stall: BTreeMap<String, Histogram>,
+    session: Histogram,
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    histograms: Arc<Mutex<Histograms>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let histograms = Histograms { connect: BTreeMap::new(), first_output: BTreeMap::new(), stall: BTreeMap::new(),
+                                     session: Histogram::new(SESSION_BUCKETS) };
+        Self { histograms: Arc::new(Mutex::new(histograms)) }
+    }
+}
+
+impl Metrics {
+    // From starting to dial a backend to being connected to it.
+    pub fn observe_connect(&self, backend: &str, took: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.connect.entry(backend.to_string()).or_insert_with(|| Histogram::new(CONNECT_BUCKETS)).observe(took);
+    }
+
+    // From being connected to a backend to relaying the first data it sent.
+    // That takes in the opening negotiation, but doesn't end with it: a
+    // backend that waits for the caller to type adds that wait too.
+    pub fn observe_first_output(&self, backend: &str, took: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.first_output.entry(backend.to_string()).or_insert_with(|| Histogram::new(CONNECT_BUCKETS)).observe(took);
+    }
+
+    // How long what a caller typed waited on a backend's full window.
+    pub fn observe_stall(&self, backend: &str, took: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.stall.entry(backend.to_string()).or_insert_with(|| Histogram::new(STALL_BUCKETS)).observe(took);
+    }
+
+    pub fn observe_session(&self, lasted: Duration) {
+        self.histograms.lock().unwrap().session.observe(lasted);
+    }
+
+    fn render(&self, out: &mut String) {
+        let histograms = self.histograms.lock().unwrap();
+        header(out, "triserver_backend_connect_seconds", "histogram", "Time taken to connect to a backend.");
+        for (backend, histogram) in &histograms.connect {
+            histogram.render(out, "triserver_backend_connect_seconds", &format!("backend=\"{}\",", escape(backend)));
+        }
+        header(out, "triserver_backend_first_output_seconds", "histogram", "Time from connecting to a backend to relaying the first data it sent.");
+        for (backend, histogram) in &histograms.first_output {
+            histogram.render(out, "triserver_backend_first_output_seconds", &format!("backend=\"{}\",", escape(backend)));
+        }
+        header(out, "triserver_backend_stall_seconds", "histogram", "Time a caller's typing waited on a backend that wasn't taking it.");
+        for (backend, histogram) in &histograms.stall {
+            histogram.render(out, "triserver_backend_stall_seconds", &format!("backend=\"{}\",", escape(backend)));
+        }
+        header(out, "triserver_session_duration_seconds", "histogram", "How long sessions lasted.");
+        histograms.session.render(out, "triserver_session_duration_seconds", "");
+    }
+}
+
+// Everything, in the Prometheus text format.
+pub fn render(context: &ServerContext) -> String {
+    let mut out = String::new();
+    sessions(&mut out, context);
+    manager(&mut out, context);
+    failures(&mut out, context);
+    process(&mut out);
+    context.metrics.render(&mut out);
+    out
+}
+
+// Every listener and backend is listed, with the ones nobody is using at 0.
+fn sessions(out: &mut String, context: &ServerContext) {
+    let config = context.live.current();
+    let clients = context.clients.values();
+    let mut listeners = BTreeMap::from([("telnet", config.server.port.to_string())]);
+    if let Some(tls) = &config.tls {
+        listeners.insert("tls", tls.address.rsplit(':').next().unwrap_or_default().to_string());
+    }
+    for client in &clients {
+        listeners.entry(client.listener).or_default();
+    }
+    header(out, "triserver_listener_sessions", "gauge", "Sessions connected now, by the listener they came in on.");
+    for (listener, port) in listeners {
+        let count = clients.iter().filter(|client| client.listener == listener).count();
+        let _ = writeln!(out, "triserver_listener_sessions{{listener=\"{}\",port=\"{}\"}} {}", listener, port, count);
+    }
+    header(out, "triserver_backend_sessions", "gauge", "Sessions connected now, by the backend they are on.");
+    for backend in &config.backends {
+        let count = clients.iter().filter(|client| client.backend.as_deref() == Some(backend.name.as_str())).count();
+        let _ = writeln!(out, "triserver_backend_sessions{{backend=\"{}\"}} {}", escape(&backend.name), count);
+    }
+}
+
+// Figures that should move together; the warning when they don't is logged
+// by the relay watch.
+fn manager(out: &mut String, context: &ServerContext) {
+    let gauges = [("triserver_manager_queue_depth", "Messages waiting for the client manager.", context.manager.queued()),
+                  ("triserver_client_map_sessions", "Sessions in the client map.", context.clients.len()),
+                  ("triserver_relay_threads", "Session threads running.", context.relays.count())];
+    for (name, help, value) in gauges {
+        header(out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+}
+
+fn failures(out: &mut String, context: &ServerContext) {
+    let config = context.live.current();
+    header(out, "triserver_backend_failures_total", "counter", "Backend connect failures, connect timeouts and sessions cut short by the backend.");
+    for backend in &config.backends {
+        let faults = context.faults.backend(&backend.name);
+        for (kind, count) in [("connect", faults.connect_failures), ("timeout", faults.timeouts), ("drop", faults.drops)] {
+            let _ = writeln!(out, "triserver_backend_failures_total{{backend=\"{}\",kind=\"{}\"}} {}", escape(&backend.name), kind, count);
+        }
+    }
+    header(out, "triserver_callers_turned_away_total", "counter", "Callers turned away for the client manager being too far behind to take them.");
+    let _ = writeln!(out, "triserver_callers_turned_away_total {}", crate::turned_away());
+    header(out, "triserver_output_overflows_total", "counter", "Callers disconnected for falling further behind the output than server.pending_output.");
+    let _ = writeln!(out, "triserver_output_overflows_total {}", context.faults.get(Fault::Overflow));
+}
+
+// The server's own threads, memory and file descriptors, where there are
+// figures for them.
+fn process(out: &mut String) {
+    let Some(usage) = resources::current() else {
+        return;
+    };
+    let mut gauges = vec![("triserver_threads", "Threads the server is running.", usage.threads),
+                          ("process_resident_memory_bytes", "Resident memory size in bytes.", usage.resident_bytes),
+                          ("process_open_fds", "Number of open file descriptors.", usage.open_fds)];
+    if let Some(max) = usage.max_fds {
+        gauges.push(("process_max_fds", "Maximum number of open file descriptors.", max));
+    }
+    for (name, help, value) in gauges {
+        header(out, name, "gauge", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// A label value, with its backslashes, quotes and newlines escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,772 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::atascii::{AtasciiDecoder, AtasciiEncoder};
+use crate::bans::{BanList, Offense};
+use crate::codec::{self, CommandTracker, Frame, Parser, Utf8Sanitizer};
+use crate::config::{ClientCharset, Config, FloodAction, FloodConfig, InputFilter, LogLevel, OutputFilter, Subsystem, TerminalClass};
+use crate::plugins::{PluginSession, Plugins};
+use crate::live::LiveConfig;
+use crate::log;
+use crate::petscii::{PetsciiDecoder, PetsciiEncoder};
+use crate::rip::RipScanner;
+use crate::session;
+use crate::syncterm::SequenceScanner;
+use crate::span::Span;
+use crate::users::{User, UserStore};
+
+// What a relay layer knows about the session it is attached to.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub client_id: Uuid,
+    pub listener: &'static str,
+    // Updated when a held session is resumed from another address.
+    pub ip_addr: IpAddr,
+    pub backend: String,
+    pub user: Option<User>,
+    // The output filter the caller picked at the escape prompt, in place of the backend's.
+    pub encoding: Option<OutputFilter>,
+    // What server.probe_terminal found, when the terminal was probed.
+    pub terminal_class: Option<TerminalClass>,
+    // Whether the caller's terminal was judged to show UTF-8, for cp437-auto.
+    pub utf8: bool,
+    // The caller's own character set, when it isn't ASCII's.
+    pub charset: Option<ClientCharset>,
+    // Whether the caller's terminal type says it draws RIPscrip.
+    pub rip: bool,
+    // Whether the caller is on SyncTERM, by [syncterm].
+    pub syncterm: bool,
+}
+
+impl SessionInfo {
+    pub fn span(&self) -> Span<'_> {
+        Span { client_id: self.client_id, listener: self.listener, ip_addr: self.ip_addr, backend: Some(&self.backend) }
+    }
+}
+
+pub enum Flow {
+    Continue,
+    // Ends the session; the reason is shown to the caller.
+    Disconnect(String),
+    // Carries on after showing the caller a message.
+    Warn(String),
+    // Carries on, but nothing more is read from the caller until then.
+    Throttle(Instant),
+}
+
+// One layer of the relay path. Each session gets its own instance, so layers
+// can keep per-connection state. Data hooks may rewrite or empty the buffer.
+pub trait ConnectionMiddleware: Send {
+    fn on_connect(&mut self, _session: &SessionInfo) -> Flow {
+        Flow::Continue
+    }
+
+    fn on_client_data(&mut self, _session: &SessionInfo, _data: &mut Vec<u8>) -> Flow {
+        Flow::Continue
+    }
+
+    fn on_backend_data(&mut self, _session: &SessionInfo, _data: &mut Vec<u8>) -> Flow {
+        Flow::Continue
+    }
+
+    // The backend has ended a prompt with GA or EOR, and the caller is to see
+    // it now rather than when more output comes: `data` is what the layers
+    // behind have let go of, to which a layer adds whatever it holds back.
+    fn on_backend_prompt(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        if data.is_empty() {
+            return Flow::Continue;
+        }
+        self.on_backend_data(session, data)
+    }
+
+    fn on_close(&mut self, _session: &SessionInfo) {}
+}
+
+type Factory = Box<dyn Fn() -> Box<dyn ConnectionMiddleware> + Send + Sync>;
+
+// The ordered list of layers, assembled once at startup.
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    factories: Arc<Vec<Factory>>,
+}
+
+impl MiddlewareChain {
+    pub fn new(factories: Vec<Factory>) -> Self {
+        Self { factories: Arc::new(factories) }
+    }
+
+    // The layers every server runs, in order, depending on which features
+    // are configured. The filters are the backends' as each session starts,
+    // which the admin interface may have changed since.
+    pub fn standard(config: &Config, live: &LiveConfig, user_store: Option<Arc<UserStore>>, bans: &BanList,
+                    plugins: Option<Arc<Plugins>>) -> Self {
+        let mut factories: Vec<Factory> = Vec::new();
+        factories.push(Box::new(|| Box::new(NegotiationTrace { parser: Parser::new() })));
+        // Closest to the caller, so the others only ever see ASCII.
+        factories.push(Box::new(|| Box::new(CharsetTranslation { translators: None })));
+        // Ahead of the other layers, so backend output is filtered after they have all seen it.
+        let output_live = live.clone();
+        let rip = config.rip.as_ref().map(|rip| rip.strip);
+        let syncterm = config.syncterm.is_some();
+        factories.push(Box::new(move || {
+            let filters = output_live.current().backends.iter()
+                .filter(|backend| backend.output != OutputFilter::Raw)
+                .map(|backend| (backend.name.clone(), backend.output))
+                .collect();
+            Box::new(OutputFiltering { filters, picked_for: None, output: None, rip: rip.as_ref().map(|strip| (RipScanner::new(), *strip)),
+                                       sequences: syncterm.then(SequenceScanner::new) })
+        }));
+        let input_live = live.clone();
+        factories.push(Box::new(move || {
+            let filters = input_live.current().backends.iter()
+                .filter(|backend| backend.input != InputFilter::Pass)
+                .map(|backend| (backend.name.clone(), backend.input))
+                .collect();
+            Box::new(InputFiltering { filters, backend: None, filter: InputFilter::Pass, telnet: CommandTracker::default() })
+        }));
+        // After the filters, so plugins see what the backend is sent and the caller's terminal is.
+        if let Some(plugins) = plugins {
+            factories.push(Box::new(move || Box::new(PluginFilter { plugins: plugins.clone(), session: None })));
+        }
+        if let Some(store) = user_store {
+            factories.push(Box::new(move || Box::new(CallAccounting { store: store.clone(), call: None })));
+        }
+        if let Some(flood) = &config.flood {
+            let flood = flood.clone();
+            factories.push(Box::new(move || Box::new(FloodGuard { config: flood.clone(), rate: InputRate::default(), warned: false })));
+        }
+        if config.autoban.is_some() || config.negotiation.is_some() {
+            let bans = bans.clone();
+            // The stricter of the two rates, where both are set.
+            let rates = [config.autoban.as_ref().map(|autoban| autoban.negotiation_rate),
+                         config.negotiation.as_ref().map(|negotiation| negotiation.client_rate)];
+            let limit = rates.into_iter().flatten().filter(|&rate| rate > 0).min().unwrap_or(0);
+            let max_subnegotiation = config.negotiation.as_ref().map(|negotiation| negotiation.max_subnegotiation);
+            factories.push(Box::new(move || {
+                Box::new(NegotiationGuard { bans: bans.clone(), limit, oversized: max_subnegotiation.is_some(),
+                                            parser: max_subnegotiation.map_or_else(Parser::new, Parser::with_limit), rate: CommandRate::default() })
+            }));
+        }
+        Self::new(factories)
+    }
+
+    pub fn start(&self) -> Pipeline {
+        Pipeline {
+            layers: self.factories.iter().map(|factory| factory()).collect(),
+            connected: 0,
+        }
+    }
+}
+
+// The layers of one session. Client data runs through them in order and
+// backend data in reverse, so the first layer is closest to the caller.
+pub struct Pipeline {
+    layers: Vec<Box<dyn ConnectionMiddleware>>,
+    // Layers whose on_connect ran; only those see on_close.
+    connected: usize,
+}
+
+impl Pipeline {
+    pub fn on_connect(&mut self, session: &SessionInfo) -> Flow {
+        for layer in &mut self.layers {
+            let flow = layer.on_connect(session);
+            self.connected += 1;
+            if let Flow::Disconnect(_) = flow {
+                return flow;
+            }
+        }
+        Flow::Continue
+    }
+
+    // A warning or throttle from any layer is passed on once every layer has run.
+    pub fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        let mut flow = Flow::Continue;
+        for layer in &mut self.layers {
+            match layer.on_client_data(session, data) {
+                Flow::Disconnect(reason) => return Flow::Disconnect(reason),
+                Flow::Warn(message) => flow = Flow::Warn(message),
+                Flow::Throttle(until) => flow = Flow::Throttle(until),
+                Flow::Continue => {}
+            }
+            if data.is_empty() {
+                break;
+            }
+        }
+        flow
+    }
+
+    pub fn on_backend_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        let mut flow = Flow::Continue;
+        for layer in self.layers.iter_mut().rev() {
+            match layer.on_backend_data(session, data) {
+                Flow::Disconnect(reason) => return Flow::Disconnect(reason),
+                Flow::Warn(message) => flow = Flow::Warn(message),
+                // Only the caller's input is held back.
+                Flow::Throttle(_) | Flow::Continue => {}
+            }
+            if data.is_empty() {
+                break;
+            }
+        }
+        flow
+    }
+
+    // Every layer runs, empty-handed or not, as each may be holding output.
+    pub fn on_backend_prompt(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        let mut flow = Flow::Continue;
+        for layer in self.layers.iter_mut().rev() {
+            match layer.on_backend_prompt(session, data) {
+                Flow::Disconnect(reason) => return Flow::Disconnect(reason),
+                Flow::Warn(message) => flow = Flow::Warn(message),
+                // Only the caller's input is held back.
+                Flow::Throttle(_) | Flow::Continue => {}
+            }
+        }
+        flow
+    }
+
+    pub fn on_close(&mut self, session: &SessionInfo) {
+        for layer in self.layers[..self.connected].iter_mut().rev() {
+            layer.on_close(session);
+        }
+        self.connected = 0;
+    }
+}
+
+// Records each logged-in call in the user store for time limits and history.
+struct CallAccounting {
+    store: Arc<UserStore>,
+    call: Option<(i64, Instant)>,
+}
+
+impl ConnectionMiddleware for CallAccounting {
+    fn on_connect(&mut self, session: &SessionInfo) -> Flow {
+        if let Some(user) = &session.user {
+            match self.store.record_call_start(user, session.client_id, session.ip_addr, &session.backend) {
+                Ok(call_id) => self.call = Some((call_id, Instant::now())),
+                Err(error) => log!(Relay, Warn, "Unable to record call for {}: {}", user.username, error),
+            }
+        }
+        Flow::Continue
+    }
+
+    fn on_close(&mut self, session: &SessionInfo) {
+        if let Some((call_id, started)) = self.call.take() {
+            if let Err(error) = self.store.record_call_end(call_id, started.elapsed()) {
+                log!(Relay, Warn, span = session.span(), "Unable to record call end: {}", error);
+            }
+        }
+    }
+}
+
+// Applies the session's backend output filter, or the one the caller picked.
+// With [rip], RIPscrip goes round the filter to callers who draw it, and is
+// taken out for the rest when it's to be stripped. With [syncterm], SyncTERM
+// callers' extended sequences go round both.
+struct OutputFiltering {
+    filters: BTreeMap<String, OutputFilter>,
+    // What the filter was picked for, which changes when a [multisession]
+    // caller switches or the caller picks another.
+    picked_for: Option<(String, Option<OutputFilter>)>,
+    output: Option<Output>,
+    rip: Option<(RipScanner, bool)>,
+    sequences: Option<SequenceScanner>,
+}
+
+enum Output {
+    Sanitize(Utf8Sanitizer),
+    Cp437ToUtf8,
+    Cp437ToAscii,
+}
+
+impl ConnectionMiddleware for OutputFiltering {
+    fn on_backend_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        let Some(scanner) = self.sequences.as_mut().filter(|_| session.syncterm) else {
+            self.pass_on(session, data);
+            return Flow::Continue;
+        };
+        let mut output = Vec::with_capacity(data.len());
+        for (sequence, mut run) in scanner.scan(data) {
+            if !sequence {
+                self.pass_on(session, &mut run);
+            }
+            output.extend(run);
+        }
+        *data = output;
+        Flow::Continue
+    }
+
+    // A start of RIPscrip held back is text after all, and the filter's
+    // character cut off is broken.
+    fn on_backend_prompt(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        self.on_backend_data(session, data);
+        let mut held = self.rip.as_mut().map_or_else(Vec::new, |(scanner, _)| scanner.flush());
+        self.filter(session, &mut held);
+        data.extend(held);
+        if let Some(Output::Sanitize(sanitizer)) = &mut self.output {
+            data.extend(sanitizer.flush());
+        }
+        Flow::Continue
+    }
+}
+
+impl OutputFiltering {
+    // RIPscrip round the filter or out, as [rip] has it, and the rest through it.
+    fn pass_on(&mut self, session: &SessionInfo, data: &mut Vec<u8>) {
+        let Some((scanner, strip)) = self.rip.as_mut() else {
+            self.filter(session, data);
+            return;
+        };
+        let strip = *strip;
+        let mut output = Vec::with_capacity(data.len());
+        for (rip, mut run) in scanner.scan(data) {
+            match rip {
+                true if session.rip => output.extend(run),
+                true if strip => {}
+                _ => {
+                    self.filter(session, &mut run);
+                    output.extend(run);
+                }
+            }
+        }
+        *data = output;
+    }
+
+    fn filter(&mut self, session: &SessionInfo, data: &mut Vec<u8>) {
+        let picking = (session.backend.clone(), session.encoding);
+        if session.charset.is_some() {
+            return;
+        }
+        if self.picked_for.as_ref() != Some(&picking) {
+            self.output = match session.encoding.or_else(|| self.filters.get(&session.backend).copied()) {
+                Some(OutputFilter::Utf8) => Some(Output::Sanitize(Utf8Sanitizer::new(false))),
+                Some(OutputFilter::Ascii) => Some(Output::Sanitize(Utf8Sanitizer::new(true))),
+                Some(OutputFilter::Cp437ToUtf8) => Some(Output::Cp437ToUtf8),
+                Some(OutputFilter::Cp437ToAscii) => Some(Output::Cp437ToAscii),
+                Some(OutputFilter::Cp437Auto) if session.utf8 => Some(Output::Cp437ToUtf8),
+                Some(OutputFilter::Cp437Auto) if session.terminal_class == Some(TerminalClass::Plain) => Some(Output::Cp437ToAscii),
+                Some(OutputFilter::Cp437Auto) => None,
+                Some(OutputFilter::Raw) | None => None,
+            };
+            self.picked_for = Some(picking);
+        }
+        match &mut self.output {
+            Some(Output::Sanitize(sanitizer)) => *data = sanitizer.sanitize(data),
+            Some(Output::Cp437ToUtf8) => *data = codec::cp437_to_utf8(data).into_bytes(),
+            Some(Output::Cp437ToAscii) => *data = codec::cp437_to_ascii(data),
+            None => {}
+        }
+    }
+}
+
+// Translates for callers with a character set of their own, whatever the
+// backend's output filter.
+struct CharsetTranslation {
+    translators: Option<Translators>,
+}
+
+enum Translators {
+    Petscii(PetsciiEncoder, PetsciiDecoder),
+    Atascii(AtasciiEncoder, AtasciiDecoder),
+}
+
+impl CharsetTranslation {
+    fn translators(&mut self, charset: ClientCharset) -> &mut Translators {
+        self.translators.get_or_insert_with(|| match charset {
+            ClientCharset::Petscii => Translators::Petscii(PetsciiEncoder::new(), PetsciiDecoder::new()),
+            ClientCharset::Atascii => Translators::Atascii(AtasciiEncoder::new(), AtasciiDecoder::new()),
+        })
+    }
+}
+
+impl ConnectionMiddleware for CharsetTranslation {
+    fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        if let Some(charset) = session.charset {
+            *data = match self.translators(charset) {
+                Translators::Petscii(_, decoder) => decoder.decode(data),
+                Translators::Atascii(_, decoder) => decoder.decode(data),
+            };
+        }
+        Flow::Continue
+    }
+
+    fn on_backend_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        if let Some(charset) = session.charset {
+            *data = match self.translators(charset) {
+                Translators::Petscii(encoder, _) => encoder.encode(data),
+                Translators::Atascii(encoder, _) => encoder.encode(data),
+            };
+        }
+        Flow::Continue
+    }
+}
+
+// Applies the session's backend input filter to control characters.
+struct InputFiltering {
+    filters: BTreeMap<String, InputFilter>,
+    // The backend the filter was picked for, which changes when a
+    // [multisession] caller switches.
+    backend: Option<String>,
+    filter: InputFilter,
+    telnet: CommandTracker,
+}
+
+impl ConnectionMiddleware for InputFiltering {
+    fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        if self.backend.as_deref() != Some(session.backend.as_str()) {
+            self.filter = self.filters.get(&session.backend).copied().unwrap_or(InputFilter::Pass);
+            self.backend = Some(session.backend.clone());
+        }
+        let mut filtered = Vec::with_capacity(data.len());
+        for &byte in data.iter() {
+            // Telnet commands, whose option codes are often control characters, get through untouched.
+            let is_data = self.telnet.is_data(byte);
+            // SyncTERM's answers to the backend's queries start with ESC.
+            let answering = session.syncterm && byte == 0x1b;
+            if self.filter == InputFilter::Pass || !is_data || answering || byte >= 0x20 || matches!(byte, b'\r' | b'\n' | 0x08 | 0x7f) {
+                filtered.push(byte);
+            } else if self.filter == InputFilter::Escape {
+                filtered.extend([b'^', byte ^ 0x40]);
+            }
+        }
+        *data = filtered;
+        Flow::Continue
+    }
+}
+
+// Logs the telnet commands a caller sends while negotiation is logged at
+// trace. Commands the proxy and backend exchange are logged by the session.
+struct NegotiationTrace {
+    parser: Parser,
+}
+
+impl ConnectionMiddleware for NegotiationTrace {
+    fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        if !log::enabled(Subsystem::Negotiation, LogLevel::Trace) {
+            return Flow::Continue;
+        }
+        for frame in self.parser.feed(data) {
+            session::trace_frame(session.span(), "client->proxy", &frame);
+        }
+        Flow::Continue
+    }
+}
+
+// Holds callers to the [flood] limits.
+struct FloodGuard {
+    config: FloodConfig,
+    rate: InputRate,
+    // Warned during the current window already.
+    warned: bool,
+}
+
+impl ConnectionMiddleware for FloodGuard {
+    fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        if self.rate.record(data) {
+            self.warned = false;
+        }
+        if !self.rate.exceeds(&self.config) {
+            return Flow::Continue;
+        }
+        match self.config.action {
+            // The caller's input waits out the window, so it runs no faster than the limits while the relay carries on.
+            FloodAction::Throttle => Flow::Throttle(self.rate.window_end()),
+            FloodAction::Warn => {
+                data.clear();
+                if std::mem::replace(&mut self.warned, true) {
+                    return Flow::Continue;
+                }
+                log!(Relay, Warn, span = session.span(), "Input flood, discarding input");
+                Flow::Warn(String::from("You are typing too fast; some of your input was discarded."))
+            }
+            FloodAction::Disconnect => Flow::Disconnect(String::from("Too much input, disconnecting.")),
+        }
+    }
+}
+
+// Bytes and lines from the client over one-second windows.
+#[derive(Default)]
+struct InputRate {
+    window_start: Option<Instant>,
+    bytes: u32,
+    lines: u32,
+}
+
+impl InputRate {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    // True if this started a new window.
+    fn record(&mut self, data: &[u8]) -> bool {
+        let now = Instant::now();
+        let started = self.window_start.is_none_or(|start| now.duration_since(start) >= Self::WINDOW);
+        if started {
+            self.window_start = Some(now);
+            self.bytes = 0;
+            self.lines = 0;
+        }
+        self.bytes = self.bytes.saturating_add(data.len() as u32);
+        self.lines = self.lines.saturating_add(data.iter().filter(|&&byte| byte == b'\r').count() as u32);
+        started
+    }
+
+    fn exceeds(&self, config: &FloodConfig) -> bool {
+        (config.bytes_per_second > 0 && self.bytes > config.bytes_per_second)
+            || (config.lines_per_second > 0 && self.lines > config.lines_per_second)
+    }
+
+    fn window_end(&self) -> Instant {
+        self.window_start.map_or_else(Instant::now, |start| start + Self::WINDOW)
+    }
+}
+
+// Disconnects and strikes callers that flood the backend with negotiation:
+// more telnet commands a second than [autoban] negotiation_rate or
+// [negotiation] client_rate allow, or with [negotiation], a subnegotiation
+// longer than max_subnegotiation.
+struct NegotiationGuard {
+    bans: BanList,
+    // Zero for no limit on the rate.
+    limit: u32,
+    oversized: bool,
+    parser: Parser,
+    rate: CommandRate,
+}
+
+impl ConnectionMiddleware for NegotiationGuard {
+    fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        for frame in self.parser.feed(data) {
+            let exceeded = match frame {
+                Frame::Data(_) => false,
+                Frame::Oversized(_) if self.oversized => true,
+                _ => self.limit > 0 && self.rate.exceeded(self.limit),
+            };
+            if exceeded {
+                if let Some(ban) = self.bans.record(session.ip_addr, Offense::NegotiationFlood) {
+                    log!(Server, Warn, "Auto-banned {} for {} seconds: {} (strike {})", session.ip_addr, ban.remaining().as_secs(), ban.reason, ban.strikes);
+                }
+                return Flow::Disconnect(String::from("Too much negotiation, disconnecting."));
+            }
+        }
+        Flow::Continue
+    }
+}
+
+// Runs the session's own copies of the [plugins], started as it connects.
+struct PluginFilter {
+    plugins: Arc<Plugins>,
+    session: Option<PluginSession>,
+}
+
+impl PluginFilter {
+    fn flow(vetoed: Result<(), String>) -> Flow {
+        match vetoed {
+            Ok(()) => Flow::Continue,
+            Err(_) => Flow::Disconnect(String::from("Disconnected by a filter.")),
+        }
+    }
+}
+
+impl ConnectionMiddleware for PluginFilter {
+    fn on_connect(&mut self, session: &SessionInfo) -> Flow {
+        let plugins = self.session.insert(self.plugins.start(session.span()));
+        Self::flow(plugins.on_connect(session.span(), &session.ip_addr.to_string(), &session.backend))
+    }
+
+    fn on_client_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        match &mut self.session {
+            Some(plugins) => Self::flow(plugins.on_client_data(session.span(), data)),
+            None => Flow::Continue,
+        }
+    }
+
+    fn on_backend_data(&mut self, session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+        match &mut self.session {
+            Some(plugins) => Self::flow(plugins.on_backend_data(session.span(), data)),
+            None => Flow::Continue,
+        }
+    }
+
+    fn on_close(&mut self, session: &SessionInfo) {
+        if let Some(mut plugins) = self.session.take() {
+            plugins.on_close(session.span());
+        }
+    }
+}
+
+// Counts telnet commands over one-second windows.
+#[derive(Default)]
+pub struct CommandRate {
+    window_start: Option<Instant>,
+    commands: u32,
+}
+
+impl CommandRate {
+    // Counts one command; true once there have been more than `limit` this window.
+    pub fn exceeded(&mut self, limit: u32) -> bool {
+        let now = Instant::now();
+        if self.window_start.is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1)) {
+            self.window_start = Some(now);
+            self.commands = 0;
+        }
+        self.commands += 1;
+        self.commands > limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+    // A layer's name, what it returns, and whether it empties the data.
+    type Layer = (&'static str, fn() -> Flow, bool);
+
+    // Tags what passes through it with its name, and notes each call.
+    struct Recorder {
+        name: &'static str,
+        calls: Calls,
+        // What each hook but on_backend_prompt returns.
+        flow: fn() -> Flow,
+        empties: bool,
+    }
+
+    impl Recorder {
+        fn record(&self, hook: &str) {
+            self.calls.lock().unwrap().push(format!("{} {}", self.name, hook));
+        }
+
+        fn pass(&self, hook: &str, data: &mut Vec<u8>) -> Flow {
+            self.record(hook);
+            match self.empties {
+                true => data.clear(),
+                false => data.extend(self.name.as_bytes()),
+            }
+            (self.flow)()
+        }
+    }
+
+    impl ConnectionMiddleware for Recorder {
+        fn on_connect(&mut self, _session: &SessionInfo) -> Flow {
+            self.record("connect");
+            (self.flow)()
+        }
+
+        fn on_client_data(&mut self, _session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+            self.pass("client", data)
+        }
+
+        fn on_backend_data(&mut self, _session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+            self.pass("backend", data)
+        }
+
+        fn on_backend_prompt(&mut self, _session: &SessionInfo, data: &mut Vec<u8>) -> Flow {
+            self.pass("prompt", data);
+            Flow::Continue
+        }
+
+        fn on_close(&mut self, _session: &SessionInfo) {
+            self.record("close");
+        }
+    }
+
+    fn session() -> SessionInfo {
+        SessionInfo { client_id: Uuid::nil(), listener: "telnet", ip_addr: IpAddr::V4(Ipv4Addr::LOCALHOST), backend: String::from("main"),
+                      user: None, encoding: None, terminal_class: None, utf8: false, charset: None, rip: false, syncterm: false }
+    }
+
+    fn chain(layers: &[Layer]) -> (Pipeline, Calls) {
+        let calls = Calls::default();
+        let factories = layers.iter().map(|&(name, flow, empties)| {
+            let calls = calls.clone();
+            Box::new(move || Box::new(Recorder { name, calls: calls.clone(), flow, empties }) as Box<dyn ConnectionMiddleware>) as Factory
+        }).collect();
+        (MiddlewareChain::new(factories).start(), calls)
+    }
+
+    fn taken(calls: &Calls) -> Vec<String> {
+        std::mem::take(&mut *calls.lock().unwrap())
+    }
+
+    #[test]
+    fn client_data_runs_in_order_and_backend_data_in_reverse() {
+        let (mut pipeline, calls) = chain(&[("a", || Flow::Continue, false), ("b", || Flow::Continue, false), ("c", || Flow::Continue, false)]);
+        let session = session();
+        assert!(matches!(pipeline.on_connect(&session), Flow::Continue));
+        assert_eq!(taken(&calls), ["a connect", "b connect", "c connect"]);
+
+        let mut data = b">".to_vec();
+        assert!(matches!(pipeline.on_client_data(&session, &mut data), Flow::Continue));
+        assert_eq!(data, b">abc");
+        let mut data = b"<".to_vec();
+        assert!(matches!(pipeline.on_backend_data(&session, &mut data), Flow::Continue));
+        assert_eq!(data, b"<cba");
+        assert_eq!(taken(&calls), ["a client", "b client", "c client", "c backend", "b backend", "a backend"]);
+
+        pipeline.on_close(&session);
+        assert_eq!(taken(&calls), ["c close", "b close", "a close"]);
+    }
+
+    #[test]
+    fn a_layer_that_empties_the_data_ends_the_pass() {
+        let (mut pipeline, calls) = chain(&[("a", || Flow::Continue, false), ("b", || Flow::Continue, true), ("c", || Flow::Continue, false)]);
+        let session = session();
+        let mut data = b">".to_vec();
+        pipeline.on_client_data(&session, &mut data);
+        assert!(data.is_empty());
+        let mut data = b"<".to_vec();
+        pipeline.on_backend_data(&session, &mut data);
+        assert!(data.is_empty());
+        assert_eq!(taken(&calls), ["a client", "b client", "c backend", "b backend"]);
+    }
+
+    #[test]
+    fn prompts_reach_every_layer_even_when_empty() {
+        let (mut pipeline, calls) = chain(&[("a", || Flow::Continue, false), ("b", || Flow::Continue, true), ("c", || Flow::Continue, false)]);
+        let mut data = b"<".to_vec();
+        pipeline.on_backend_prompt(&session(), &mut data);
+        assert_eq!(data, b"a");
+        assert_eq!(taken(&calls), ["c prompt", "b prompt", "a prompt"]);
+    }
+
+    #[test]
+    fn a_disconnect_stops_the_chain_and_a_warning_waits_for_it() {
+        let (mut pipeline, calls) = chain(&[("a", || Flow::Warn(String::from("slow down")), false), ("b", || Flow::Continue, false)]);
+        let mut data = b">".to_vec();
+        assert!(matches!(pipeline.on_client_data(&session(), &mut data), Flow::Warn(message) if message == "slow down"));
+        assert_eq!(data, b">ab");
+        assert_eq!(taken(&calls), ["a client", "b client"]);
+
+        let (mut pipeline, calls) = chain(&[("a", || Flow::Continue, false), ("b", || Flow::Disconnect(String::from("bye")), false),
+                                               ("c", || Flow::Continue, false)]);
+        let mut data = b"<".to_vec();
+        assert!(matches!(pipeline.on_backend_data(&session(), &mut data), Flow::Disconnect(reason) if reason == "bye"));
+        assert_eq!(taken(&calls), ["c backend", "b backend"]);
+    }
+
+    #[test]
+    fn only_layers_that_connected_are_closed() {
+        let (mut pipeline, calls) = chain(&[("a", || Flow::Continue, false), ("b", || Flow::Disconnect(String::from("no")), false),
+                                               ("c", || Flow::Continue, false)]);
+        let session = session();
+        assert!(matches!(pipeline.on_connect(&session), Flow::Disconnect(_)));
+        pipeline.on_close(&session);
+        assert_eq!(taken(&calls), ["a connect", "b connect", "b close", "a close"]);
+        // Closing twice closes nothing more.
+        pipeline.on_close(&session);
+        assert!(taken(&calls).is_empty());
+    }
+
+    #[test]
+    fn throttles_stay_on_the_callers_side() {
+        let (mut pipeline, _) = chain(&[("a", || Flow::Throttle(Instant::now()), false)]);
+        let session = session();
+        assert!(matches!(pipeline.on_client_data(&session, &mut b">".to_vec()), Flow::Throttle(_)));
+        assert!(matches!(pipeline.on_backend_data(&session, &mut b"<".to_vec()), Flow::Continue));
+    }
+}
@@ -0,0 +1,188 @@
+// A small telnet server standing in for a BBS during development:
+// `TriServer mock-backend`, or `mock::start` for an in-process backend on an
+// ephemeral port. It shows a banner and a menu, then echoes lines back.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use telnet::{Action, Event as TelnetEvent, Telnet, TelnetError, TelnetOption};
+
+use crate::clock::now_timestamp;
+use crate::loadtest::peer_closed;
+
+const SESSION_TIMEOUT: Duration = Duration::from_secs(600);
+const MAX_LINE_LENGTH: usize = 512;
+// Option requests sent by `MockNegotiation::Flood`, enough to span many reads.
+const FLOOD_COUNT: usize = 500;
+const MENU: &str = "\r\n  E) Echo a line back\r\n  T) Show the time\r\n  Q) Log off\r\n\r\nCommand: ";
+
+// What the backend asks for when a caller connects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MockNegotiation {
+    // Plain text only.
+    None,
+    // WILL ECHO, WILL SUPPRESS-GO-AHEAD and DO TTYPE, like most BBS software.
+    Standard,
+    // A burst of option requests, to exercise the proxy's handling of a noisy backend.
+    Flood,
+}
+
+impl std::str::FromStr for MockNegotiation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(MockNegotiation::None),
+            "standard" => Ok(MockNegotiation::Standard),
+            "flood" => Ok(MockNegotiation::Flood),
+            _ => Err(format!("expected one of none, standard, flood; found '{}'", value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MockOptions {
+    pub address: String,
+    pub negotiation: MockNegotiation,
+    pub banner: String,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            address: String::from("127.0.0.1:2323"),
+            negotiation: MockNegotiation::Standard,
+            banner: String::from("Welcome to the TriServer mock BBS"),
+        }
+    }
+}
+
+// Binds and serves callers on a background thread. Returns the bound
+// address, so an address with port 0 picks a free port.
+pub fn start(options: MockOptions) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(&options.address)?;
+    let address = listener.local_addr()?;
+    let _ = thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let options = options.clone();
+            let _ = thread::spawn(move || serve(stream, &options));
+        }
+    });
+    Ok(address)
+}
+
+// Runs `TriServer mock-backend` until the process is stopped.
+pub fn run(options: MockOptions) -> i32 {
+    let negotiation = options.negotiation;
+    match start(options) {
+        Ok(address) => {
+            println!("Mock backend listening on {} (negotiation: {:?})", address, negotiation);
+            loop {
+                thread::park();
+            }
+        }
+        Err(error) => {
+            eprintln!("Unable to start the mock backend: {}", error);
+            1
+        }
+    }
+}
+
+fn serve(stream: TcpStream, options: &MockOptions) {
+    let peer = stream.peer_addr().map_or_else(|_| String::from("unknown"), |peer| peer.to_string());
+    println!("Mock backend: {} connected", peer);
+    let Ok(probe) = stream.try_clone() else {
+        return;
+    };
+    let mut telnet = Telnet::from_stream(Box::new(stream), 256);
+    let result = session(&mut telnet, &probe, options);
+    match result {
+        Ok(()) => println!("Mock backend: {} logged off", peer),
+        Err(error) => println!("Mock backend: {} dropped: {}", peer, error),
+    }
+}
+
+fn session(telnet: &mut Telnet, mut probe: &TcpStream, options: &MockOptions) -> io::Result<()> {
+    match options.negotiation {
+        MockNegotiation::None => {}
+        MockNegotiation::Standard => {
+            negotiate(telnet, Action::Will, TelnetOption::Echo)?;
+            negotiate(telnet, Action::Will, TelnetOption::SuppressGoAhead)?;
+            negotiate(telnet, Action::Do, TelnetOption::TTYPE)?;
+        }
+        MockNegotiation::Flood => {
+            for i in 0..FLOOD_COUNT {
+                let action = if i % 2 == 0 { Action::Will } else { Action::Wont };
+                negotiate(telnet, action, TelnetOption::Echo)?;
+            }
+        }
+    }
+    telnet.write(format!("\r\n{}\r\n{}", options.banner, MENU).as_bytes())?;
+
+    let mut line = Vec::new();
+    let mut echo_next = false;
+    loop {
+        let data = match telnet.read_timeout(SESSION_TIMEOUT)? {
+            TelnetEvent::Data(data) => data,
+            TelnetEvent::Negotiation(Action::Will, TelnetOption::TTYPE) => {
+                // IAC SB TTYPE SEND IAC SE, in one write as BBS software sends it;
+                // Telnet::subnegotiate writes it in three pieces.
+                probe.write_all(&[255, 250, 24, 1, 255, 240])?;
+                continue;
+            }
+            TelnetEvent::Subnegotiation(TelnetOption::TTYPE, data) => {
+                // The first byte is IS.
+                println!("Mock backend: terminal type {}", String::from_utf8_lossy(data.get(1..).unwrap_or_default()));
+                continue;
+            }
+            TelnetEvent::TimedOut => return Err(io::Error::new(io::ErrorKind::TimedOut, "idle")),
+            TelnetEvent::Error(TelnetError::InternalQueueErr) if peer_closed(probe) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed"));
+            }
+            _ => continue,
+        };
+        for byte in data.iter().copied() {
+            if byte != b'\r' && byte != b'\n' {
+                if line.len() < MAX_LINE_LENGTH {
+                    line.push(byte);
+                }
+                continue;
+            }
+            if byte == b'\n' && line.is_empty() {
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line).trim().to_string();
+            line.clear();
+            let reply = if echo_next {
+                echo_next = false;
+                format!("\r\nYou said: {}\r\n{}", text, MENU)
+            } else {
+                match text.to_ascii_uppercase().as_str() {
+                    "E" => {
+                        echo_next = true;
+                        String::from("\r\nType a line: ")
+                    }
+                    "T" => format!("\r\nIt is {}\r\n{}", now_timestamp(), MENU),
+                    "Q" => {
+                        telnet.write(b"\r\nGoodbye!\r\n")?;
+                        return Ok(());
+                    }
+                    "" => String::from(MENU),
+                    // Anything else is echoed too, so load tests get a reply to every line.
+                    _ => format!("\r\n{}\r\n{}", text, MENU),
+                }
+            };
+            telnet.write(reply.as_bytes())?;
+        }
+    }
+}
+
+fn negotiate(telnet: &mut Telnet, action: Action, option: TelnetOption) -> io::Result<()> {
+    telnet.negotiate(&action, option).map_err(telnet_error)
+}
+
+fn telnet_error(error: TelnetError) -> io::Error {
+    io::Error::other(format!("{:?}", error))
+}
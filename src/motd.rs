@@ -0,0 +1,94 @@
+// The message of the day from server.motd_file, shown to each caller on
+// connecting, and the placeholders it and broadcast messages are filled in
+// with for each caller: {caller_number}, {node}, {clients_online},
+// {client_ip} and {time} (UTC). The file is read for every caller, so it can
+// be changed while the server runs.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::clock::now_timestamp;
+use crate::log;
+use crate::{Call, SharedClientMap};
+
+// Every caller let in, counted in server.callers_file when there is one.
+pub struct CallerCount {
+    path: Option<PathBuf>,
+    count: u64,
+}
+
+impl CallerCount {
+    // A file that isn't there yet starts the count from nothing.
+    pub fn load(path: Option<&Path>) -> Self {
+        let count = path.map_or(0, |path| match fs::read_to_string(path) {
+            Ok(contents) => contents.trim().parse().unwrap_or_else(|_| {
+                log!(Server, Warn, "{} doesn't hold a caller count; counting from 0", path.display());
+                0
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => 0,
+            Err(error) => {
+                log!(Server, Warn, "Unable to read {}, counting callers from 0: {}", path.display(), error);
+                0
+            }
+        });
+        Self { path: path.map(Path::to_path_buf), count }
+    }
+
+    // The next caller's number.
+    pub fn next(&mut self) -> u64 {
+        self.count += 1;
+        if let Some(path) = &self.path {
+            if let Err(error) = fs::write(path, format!("{}\n", self.count)) {
+                log!(Server, Warn, "Unable to save the caller count to {}: {}", path.display(), error);
+            }
+        }
+        self.count
+    }
+}
+
+pub fn values(call: Call, client_id: Uuid, ip_addr: IpAddr, clients: &SharedClientMap) -> Vec<(&'static str, String)> {
+    // A session's thread starts before the manager has put it in the map.
+    let online = clients.len() + usize::from(clients.get(client_id).is_none());
+    vec![
+        ("caller_number", call.number.to_string()),
+        ("node", call.node.to_string()),
+        ("clients_online", online.to_string()),
+        ("client_ip", ip_addr.to_string()),
+        ("time", now_timestamp()),
+    ]
+}
+
+// The file is sent as it is, CP437 art and all, bar the placeholders and
+// bare line feeds, which become CR LF.
+pub fn show(stream: &mut impl Write, path: &Path, values: &[(&str, String)]) -> io::Result<()> {
+    let text = match fs::read(path) {
+        Ok(text) => text,
+        Err(error) => {
+            log!(Relay, Warn, "Unable to read {}: {}", path.display(), error);
+            return Ok(());
+        }
+    };
+    let mut out = Vec::with_capacity(text.len());
+    let mut rest = &text[..];
+    while let Some(&byte) = rest.first() {
+        let placeholder = values.iter().find(|(name, _)| {
+            rest.strip_prefix(b"{").and_then(|after| after.strip_prefix(name.as_bytes())).is_some_and(|after| after.starts_with(b"}"))
+        });
+        match (placeholder, byte) {
+            (Some((name, value)), _) => {
+                out.extend_from_slice(value.as_bytes());
+                rest = &rest[name.len() + 2..];
+                continue;
+            }
+            (None, b'\n') if !out.ends_with(b"\r") => out.extend_from_slice(b"\r\n"),
+            (None, byte) => out.push(byte),
+        }
+        rest = &rest[1..];
+    }
+    stream.write_all(&out)
+}
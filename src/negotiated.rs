@@ -0,0 +1,101 @@
+// What a caller's telnet client settled on with the proxy, for answering
+// "did their client ever agree to binary?" after the fact. The proxy ends the
+// caller's telnet itself, offering echo and asking for the terminal type as
+// it logs them in, so everything the caller answers is picked out of what
+// they send from the moment they connect. Once a session is relaying and the
+// caller has said nothing new for a couple of seconds, the summary is given
+// once; later changes, such as a resized window, aren't followed.
+
+use std::time::{Duration, Instant};
+
+use telnet::Action;
+
+use crate::codec::{Frame, Parser};
+
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+const BINARY: u8 = 0;
+const ECHO: u8 = 1;
+const TTYPE: u8 = 24;
+const NAWS: u8 = 31;
+const TTYPE_IS: u8 = 0;
+
+pub struct CallerNegotiation {
+    parser: Parser,
+    // Whether the caller will send binary (WILL BINARY) and have it sent (DO BINARY).
+    binary_from: Option<bool>,
+    binary_to: Option<bool>,
+    // Whether the caller let the proxy echo (DO ECHO).
+    echo: Option<bool>,
+    // The first terminal type given; MTTS cycles on through others.
+    terminal_type: Option<String>,
+    window: Option<(u16, u16)>,
+    changed_at: Instant,
+    reported: bool,
+}
+
+impl CallerNegotiation {
+    pub fn new() -> Self {
+        Self { parser: Parser::new(), binary_from: None, binary_to: None, echo: None, terminal_type: None, window: None,
+               changed_at: Instant::now(), reported: false }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.reported {
+            return;
+        }
+        for frame in self.parser.feed(data) {
+            match frame {
+                Frame::Negotiation(action, option) => {
+                    let agreed = matches!(action, Action::Will | Action::Do);
+                    match (action, option.as_byte()) {
+                        (Action::Will | Action::Wont, BINARY) => self.binary_from = Some(agreed),
+                        (Action::Do | Action::Dont, BINARY) => self.binary_to = Some(agreed),
+                        (Action::Do | Action::Dont, ECHO) => self.echo = Some(agreed),
+                        _ => continue,
+                    }
+                }
+                Frame::Subnegotiation(option, payload) => match (option.as_byte(), payload.as_slice()) {
+                    (TTYPE, [TTYPE_IS, name @ ..]) if self.terminal_type.is_none() => {
+                        self.terminal_type = Some(String::from_utf8_lossy(name).trim().to_string());
+                    }
+                    (NAWS, &[width_high, width_low, height_high, height_low]) => {
+                        self.window = Some((u16::from_be_bytes([width_high, width_low]), u16::from_be_bytes([height_high, height_low])));
+                    }
+                    _ => continue,
+                },
+                _ => continue,
+            }
+            self.changed_at = Instant::now();
+        }
+    }
+
+    // The summary, the first time this is asked once the caller has been
+    // quiet for long enough, with `charset` for what the proxy translates to.
+    pub fn settled(&mut self, charset: &str) -> Option<String> {
+        if self.reported || self.changed_at.elapsed() < SETTLE_TIME {
+            return None;
+        }
+        self.reported = true;
+        let binary = match (self.binary_from, self.binary_to) {
+            (Some(true), Some(true)) => "binary both ways",
+            (Some(true), _) => "binary from the caller only",
+            (_, Some(true)) => "binary to the caller only",
+            (None, None) => "binary never negotiated",
+            _ => "binary refused",
+        };
+        let echo = match self.echo {
+            Some(true) => "proxy echo",
+            Some(false) => "echo refused",
+            None => "echo never negotiated",
+        };
+        let terminal_type = match self.terminal_type.as_deref() {
+            Some(name) => format!("TTYPE {}", name),
+            None => String::from("no TTYPE"),
+        };
+        let window = match self.window {
+            Some((width, height)) => format!("NAWS {}x{}", width, height),
+            None => String::from("no NAWS"),
+        };
+        Some(format!("{}, {}, {}, {}, charset {}", binary, echo, terminal_type, window, charset))
+    }
+}
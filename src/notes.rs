@@ -0,0 +1,67 @@
+// Sysop notes about caller addresses, set from the admin interface. They are
+// kept in a plain text file, one "<address> <note>" line each, so they
+// survive restarts and can be edited by hand.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::log;
+
+#[derive(Clone, Default)]
+pub struct Notes {
+    // None keeps notes in memory only, as without an [admin] section.
+    path: Option<PathBuf>,
+    entries: Arc<Mutex<BTreeMap<IpAddr, String>>>,
+}
+
+impl Notes {
+    pub fn load(path: &Path) -> Self {
+        let entries = match fs::read_to_string(path) {
+            Ok(contents) => parse(&contents),
+            Err(error) if error.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => {
+                log!(Server, Warn, "Unable to read notes from {}: {}", path.display(), error);
+                BTreeMap::new()
+            }
+        };
+        Self { path: Some(path.to_path_buf()), entries: Arc::new(Mutex::new(entries)) }
+    }
+
+    pub fn get(&self, ip_addr: IpAddr) -> Option<String> {
+        self.entries.lock().unwrap().get(&ip_addr).cloned()
+    }
+
+    pub fn list(&self) -> Vec<(IpAddr, String)> {
+        self.entries.lock().unwrap().iter().map(|(ip_addr, note)| (*ip_addr, note.clone())).collect()
+    }
+
+    // Sets or, with None, removes an address's note and rewrites the file.
+    pub fn set(&self, ip_addr: IpAddr, note: Option<String>) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match note {
+            Some(note) => entries.insert(ip_addr, note),
+            None => entries.remove(&ip_addr),
+        };
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents: String = entries.iter().map(|(ip_addr, note)| format!("{} {}\n", ip_addr, note)).collect();
+        // Written aside and renamed over, so a crash can't leave half a file.
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+// Lines that don't start with an address are skipped.
+fn parse(contents: &str) -> BTreeMap<IpAddr, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().split_once(' '))
+        .filter_map(|(ip_addr, note)| Some((ip_addr.parse().ok()?, note.trim().to_string())))
+        .collect()
+}
@@ -0,0 +1,88 @@
+// Keeps a panic in one session, from a bug in a library say, from taking
+// anything else with it. The session's thread catches it and gets the panic
+// message along with a backtrace to log under the session's span, instead of
+// the usual report on stderr, which is lost once daemonized. Panics anywhere
+// else are reported as they always were.
+
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+static HOOK: Once = Once::new();
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static CAUGHT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Runs `body`, returning what it panicked with and where, then the backtrace.
+pub fn catch<T>(body: impl FnOnce() -> T) -> Result<T, String> {
+    HOOK.call_once(|| {
+        let report = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.with(Cell::get) {
+                let caught = format!("{}\n{}", info, Backtrace::force_capture());
+                CAUGHT.with(|cell| *cell.borrow_mut() = Some(caught));
+            } else {
+                report(info);
+            }
+        }));
+    });
+    // Within another catch, that one goes on catching after this one.
+    let outer = CATCHING.with(|cell| cell.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(body));
+    CATCHING.with(|cell| cell.set(outer));
+    result.map_err(|_| CAUGHT.with(|cell| cell.borrow_mut().take()).unwrap_or_else(|| String::from("panicked")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn passes_on_what_the_body_returns() {
+        assert_eq!(catch(|| 42), Ok(42));
+        assert_eq!(catch(|| Err::<(), _>("refused")), Ok(Err("refused")));
+    }
+
+    #[test]
+    fn gives_back_the_message_and_where_it_panicked() {
+        let line = line!() + 1;
+        let caught = catch(|| panic!("backend sent {} bytes", 3)).unwrap_err();
+        let mut lines = caught.lines();
+        assert!(lines.next().is_some_and(|first| first.starts_with(&format!("panicked at {}:{}:", file!(), line))), "{}", caught);
+        assert_eq!(lines.next(), Some("backend sent 3 bytes"));
+        // Then the backtrace.
+        assert!(lines.next().is_some());
+        // Taken, so the next catch doesn't see it.
+        assert_eq!(catch(|| ()), Ok(()));
+    }
+
+    #[test]
+    fn catches_panics_without_a_message() {
+        let caught = catch(|| std::panic::panic_any(7u8)).unwrap_err();
+        assert!(caught.starts_with(&format!("panicked at {}:", file!())), "{}", caught);
+    }
+
+    #[test]
+    fn goes_on_catching_after_a_catch_within() {
+        let caught = catch(|| {
+            assert!(catch(|| panic!("inner")).unwrap_err().contains("inner"));
+            panic!("outer")
+        });
+        assert!(caught.unwrap_err().contains("outer"));
+    }
+
+    #[test]
+    fn catches_on_each_thread_apart() {
+        let threads: Vec<_> = (0..4).map(|i| thread::spawn(move || catch(|| if i % 2 == 0 { panic!("thread {}", i) } else { i })))
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert!(results[0].as_ref().unwrap_err().contains("thread 0"));
+        assert_eq!(results[1], Ok(1));
+        assert!(results[2].as_ref().unwrap_err().contains("thread 2"));
+        assert_eq!(results[3], Ok(3));
+    }
+}
@@ -0,0 +1,308 @@
+// PETSCII for callers on Commodore 64 and 128 terminals. A backend's ASCII
+// and CP437 output is shown in the C64's lower case character set: letters
+// have their case swapped into PETSCII's places, box drawing becomes the
+// matching PETSCII graphics, and ANSI sequences become the control codes
+// for the same colours, cursor moves and screen clears where there are any.
+// Sequences with no PETSCII equivalent, and background colours, are dropped.
+// What the caller types is turned back into ASCII, cursor keys into ANSI.
+
+use codepage_437::CP437_CONTROL;
+
+use crate::codec::{transliterate, AnsiScanner, CommandTracker, Scanned};
+
+const WHITE: u8 = 0x05;
+const RETURN: u8 = 0x0d;
+const LOWER_CASE: u8 = 0x0e;
+const CURSOR_DOWN: u8 = 0x11;
+const REVERSE_ON: u8 = 0x12;
+const HOME: u8 = 0x13;
+const DELETE: u8 = 0x14;
+const CURSOR_RIGHT: u8 = 0x1d;
+const CURSOR_UP: u8 = 0x91;
+const REVERSE_OFF: u8 = 0x92;
+const CLEAR: u8 = 0x93;
+const CURSOR_LEFT: u8 = 0x9d;
+// Further than this a cursor move can't go on a 40 column screen.
+const MAX_MOVE: usize = 40;
+
+// The colour codes for ANSI's eight, normal and bold.
+const COLORS: [[u8; 2]; 8] = [
+    [0x90, 0x97], // black, dark grey
+    [0x1c, 0x96], // red, light red
+    [0x1e, 0x99], // green, light green
+    [0x95, 0x9e], // brown, yellow
+    [0x1f, 0x9a], // blue, light blue
+    [0x9c, 0x9c], // purple
+    [0x9f, 0x9f], // cyan
+    [0x9b, WHITE], // light grey, white
+];
+
+// Backend output to PETSCII over a stream of reads, holding on to an escape
+// sequence split between them.
+pub struct PetsciiEncoder {
+    started: bool,
+    scanner: AnsiScanner,
+    foreground: usize,
+    bold: bool,
+    reverse: bool,
+    after_return: bool,
+}
+
+impl Default for PetsciiEncoder {
+    fn default() -> Self {
+        Self { started: false, scanner: AnsiScanner::new(), foreground: 7, bold: false, reverse: false, after_return: false }
+    }
+}
+
+impl PetsciiEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() + 1);
+        if !self.started {
+            out.push(LOWER_CASE);
+            self.started = true;
+        }
+        for &byte in input {
+            let byte = match self.scanner.scan(byte) {
+                Some(Scanned::Byte(byte)) => byte,
+                Some(Scanned::Sequence(numbers, command)) => {
+                    self.csi(&numbers, command, &mut out);
+                    continue;
+                }
+                None => continue,
+            };
+            match byte {
+                b'\r' => out.push(RETURN),
+                b'\n' if self.after_return => {}
+                b'\n' => out.push(RETURN),
+                0x07 => out.push(byte),
+                0x08 => out.push(CURSOR_LEFT),
+                0x0c => out.push(CLEAR),
+                0x20..=0x7e => push_ascii(byte, &mut out),
+                0x80..=0xff => self.graphic(CP437_CONTROL.decode(byte), &mut out),
+                _ => {}
+            }
+            self.after_return = byte == b'\r';
+        }
+        out
+    }
+
+    fn csi(&mut self, numbers: &[usize], command: u8, out: &mut Vec<u8>) {
+        let count = numbers[0].clamp(1, MAX_MOVE);
+        match command {
+            b'A' => out.extend(std::iter::repeat_n(CURSOR_UP, count)),
+            b'B' => out.extend(std::iter::repeat_n(CURSOR_DOWN, count)),
+            b'C' => out.extend(std::iter::repeat_n(CURSOR_RIGHT, count)),
+            b'D' => out.extend(std::iter::repeat_n(CURSOR_LEFT, count)),
+            b'J' => out.push(CLEAR),
+            b'H' | b'f' => {
+                let row = numbers[0].clamp(1, MAX_MOVE);
+                let column = numbers.get(1).copied().unwrap_or(1).clamp(1, MAX_MOVE);
+                out.push(HOME);
+                out.extend(std::iter::repeat_n(CURSOR_DOWN, row - 1));
+                out.extend(std::iter::repeat_n(CURSOR_RIGHT, column - 1));
+            }
+            b'm' => self.select_graphic_rendition(numbers, out),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, numbers: &[usize], out: &mut Vec<u8>) {
+        let color = (self.foreground, self.bold);
+        for &number in numbers {
+            match number {
+                0 => {
+                    (self.foreground, self.bold) = (7, false);
+                    if self.reverse {
+                        out.push(REVERSE_OFF);
+                        self.reverse = false;
+                    }
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 if !self.reverse => {
+                    out.push(REVERSE_ON);
+                    self.reverse = true;
+                }
+                27 if self.reverse => {
+                    out.push(REVERSE_OFF);
+                    self.reverse = false;
+                }
+                30..=37 => self.foreground = number - 30,
+                39 => self.foreground = 7,
+                _ => {}
+            }
+        }
+        if (self.foreground, self.bold) != color {
+            out.push(COLORS[self.foreground][usize::from(self.bold)]);
+        }
+    }
+
+    // The PETSCII graphic for a CP437 one, or the ASCII stand-in when there
+    // is none.
+    fn graphic(&self, c: char, out: &mut Vec<u8>) {
+        let glyph = match c {
+            '\u{2500}' | '\u{2550}' => 0xc0,
+            '\u{2502}' | '\u{2551}' => 0xdd,
+            '\u{250c}' | '\u{2552}' | '\u{2553}' | '\u{2554}' => 0xb0,
+            '\u{2510}' | '\u{2555}' | '\u{2556}' | '\u{2557}' => 0xae,
+            '\u{2514}' | '\u{2558}' | '\u{2559}' | '\u{255a}' => 0xad,
+            '\u{2518}' | '\u{255b}' | '\u{255c}' | '\u{255d}' => 0xbd,
+            '\u{251c}' | '\u{255e}' | '\u{255f}' | '\u{2560}' => 0xab,
+            '\u{2524}' | '\u{2561}' | '\u{2562}' | '\u{2563}' => 0xb3,
+            '\u{252c}' | '\u{2564}' | '\u{2565}' | '\u{2566}' => 0xb2,
+            '\u{2534}' | '\u{2567}' | '\u{2568}' | '\u{2569}' => 0xb1,
+            '\u{253c}' | '\u{256a}' | '\u{256b}' | '\u{256c}' => 0xdb,
+            '\u{2591}' | '\u{2592}' | '\u{2593}' => 0xa6,
+            '\u{2584}' => 0xa2,
+            '\u{258c}' => 0xa1,
+            // The rest are the reverse of a space or a half block.
+            '\u{2588}' => return self.reversed(b' ', out),
+            '\u{2580}' => return self.reversed(0xa2, out),
+            '\u{2590}' => return self.reversed(0xa1, out),
+            _ => {
+                transliterate(c).bytes().for_each(|byte| push_ascii(byte, out));
+                return;
+            }
+        };
+        out.push(glyph);
+    }
+
+    fn reversed(&self, glyph: u8, out: &mut Vec<u8>) {
+        if self.reverse {
+            out.extend([REVERSE_OFF, glyph, REVERSE_ON]);
+        } else {
+            out.extend([REVERSE_ON, glyph, REVERSE_OFF]);
+        }
+    }
+}
+
+fn push_ascii(byte: u8, out: &mut Vec<u8>) {
+    out.push(match byte {
+        b'a'..=b'z' => byte - 0x20,
+        b'A'..=b'Z' => byte + 0x80,
+        // PETSCII has a pound sign, arrows and graphics in these places.
+        b'\\' => b'/',
+        b'_' => 0xa4,
+        b'`' => b'\'',
+        b'{' => b'(',
+        b'}' => b')',
+        b'|' => 0xdd,
+        b'~' => b'-',
+        _ => byte,
+    });
+}
+
+// What the caller types back to ASCII. Telnet commands pass untouched.
+#[derive(Default)]
+pub struct PetsciiDecoder {
+    telnet: CommandTracker,
+}
+
+impl PetsciiDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn decode(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        for &byte in input {
+            if !self.telnet.is_data(byte) {
+                out.push(byte);
+                continue;
+            }
+            match byte {
+                RETURN => out.push(b'\r'),
+                DELETE => out.push(0x08),
+                CURSOR_UP => out.extend_from_slice(b"\x1b[A"),
+                CURSOR_DOWN => out.extend_from_slice(b"\x1b[B"),
+                CURSOR_RIGHT => out.extend_from_slice(b"\x1b[C"),
+                CURSOR_LEFT => out.extend_from_slice(b"\x1b[D"),
+                0x41..=0x5a => out.push(byte + 0x20),
+                0x61..=0x7a => out.push(byte - 0x20),
+                0xc1..=0xda => out.push(byte - 0x80),
+                // The pound key, up arrow and left arrow.
+                0x5c => out.push(b'\\'),
+                0x5e => out.push(b'^'),
+                0x5f => out.push(b'_'),
+                // RUN/STOP, like Ctrl-C, and ESC on terminals that have one.
+                0x03 | 0x1b => out.push(byte),
+                0x20..=0x40 | 0x5b | 0x5d => out.push(byte),
+                _ => {}
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An encoder past the switch to lower case it starts with.
+    fn started() -> PetsciiEncoder {
+        let mut encoder = PetsciiEncoder::new();
+        encoder.encode(b"");
+        encoder
+    }
+
+    #[test]
+    fn switches_to_lower_case_and_swaps_letters() {
+        let mut encoder = PetsciiEncoder::new();
+        assert_eq!(encoder.encode(b"Hi"), [LOWER_CASE, 0xc8, 0x49]);
+        assert_eq!(encoder.encode(b"a_Z"), [0x41, 0xa4, 0xda]);
+    }
+
+    #[test]
+    fn ends_lines_in_one_return() {
+        let mut encoder = started();
+        assert_eq!(encoder.encode(b"a\r\nb\nc\r"), [0x41, RETURN, 0x42, RETURN, 0x43, RETURN]);
+        // The LF after a CR at the end of the last read.
+        assert_eq!(encoder.encode(b"\nd"), [0x44]);
+    }
+
+    #[test]
+    fn turns_ansi_into_control_codes() {
+        let mut encoder = started();
+        assert_eq!(encoder.encode(b"\x1b[1;31mX\x1b[0m"), [0x96, 0xd8, 0x9b]);
+        // Setting the colour it already has sends nothing.
+        assert_eq!(encoder.encode(b"\x1b[37m\x1b[31m\x1b[31m"), [0x1c]);
+        assert_eq!(encoder.encode(b"\x1b[7mA\x1b[27m"), [REVERSE_ON, 0xc1, REVERSE_OFF]);
+        assert_eq!(encoder.encode(b"\x1b[2A\x1b[C\x1b[2J"), [CURSOR_UP, CURSOR_UP, CURSOR_RIGHT, CLEAR]);
+        assert_eq!(encoder.encode(b"\x1b[3;2H"), [HOME, CURSOR_DOWN, CURSOR_DOWN, CURSOR_RIGHT]);
+        assert_eq!(encoder.encode(b"\x1b[99D"), [CURSOR_LEFT; MAX_MOVE]);
+        // Background colours and sequences it has no code for are dropped.
+        assert_eq!(encoder.encode(b"\x1b[44m\x1b[6n\x1b[?25l"), []);
+    }
+
+    #[test]
+    fn holds_on_to_a_sequence_split_between_reads() {
+        let mut encoder = started();
+        assert_eq!(encoder.encode(b"\x1b[3"), []);
+        assert_eq!(encoder.encode(b"1mA"), [0x1c, 0xc1]);
+    }
+
+    #[test]
+    fn draws_cp437_graphics() {
+        let mut encoder = started();
+        assert_eq!(encoder.encode(b"\xc9\xcd\xbb\xba\xb0"), [0xb0, 0xc0, 0xae, 0xdd, 0xa6]);
+        assert_eq!(encoder.encode(b"\xdb"), [REVERSE_ON, b' ', REVERSE_OFF]);
+        // In reverse video a full block is a plain space.
+        assert_eq!(encoder.encode(b"\x1b[7m\xdb"), [REVERSE_ON, REVERSE_OFF, b' ', REVERSE_ON]);
+        // Letters with accents lose them.
+        assert_eq!(encoder.encode(b"\x1b[0m\x82"), [REVERSE_OFF, 0x45]);
+    }
+
+    #[test]
+    fn decodes_what_the_caller_types() {
+        let mut decoder = PetsciiDecoder::new();
+        assert_eq!(decoder.decode(b"\xc8I\x0d\x14"), b"Hi\r\x08");
+        assert_eq!(decoder.decode(&[CURSOR_UP, CURSOR_DOWN, CURSOR_RIGHT, CURSOR_LEFT]), b"\x1b[A\x1b[B\x1b[C\x1b[D");
+        assert_eq!(decoder.decode(b"1+\x5c\x03"), b"1+\\\x03");
+        // Telnet commands pass as they are, colour keys are dropped.
+        assert_eq!(decoder.decode(b"\xff\xfb\x01a\x1c"), b"\xff\xfb\x01A");
+    }
+}
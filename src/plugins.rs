@@ -0,0 +1,500 @@
+// Traffic filter plugins: WebAssembly modules from [plugins] directory that
+// each session runs a copy of its own, so a plugin can keep state per
+// connection. The server calls a module's hooks with what the caller and
+// backend send, and the module may pass it on changed or end the session.
+//
+// A module exports `memory` and `alloc(len) -> ptr`, and any of
+//
+//     on_connect(address_ptr, address_len, backend_ptr, backend_len) -> i32
+//     on_client_data(ptr, len) -> i32
+//     on_backend_data(ptr, len) -> i32
+//     on_close()
+//
+// Before each hook the server asks alloc for room for what it is handing
+// over, which need only last the call. A hook returns 0 to carry on and
+// anything else to end the session. It may import triserver.replace(ptr, len)
+// to have other bytes passed on in place of those it was given, and
+// triserver.log(ptr, len) for a line in the server's log.
+//
+// Modules are compiled and run by Wasmtime. Nothing a module does can take
+// the server down with it: each call runs on a budget of fuel, so a loop
+// that never ends is stopped, and each copy's memory is capped. Either ends
+// the call with a trap.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use wasmtime::{bail, format_err, Caller, Config, Engine, Extern, ExternType, FuncType, Instance, Linker, Module, Store, StoreLimits,
+               StoreLimitsBuilder, ValType, WasmParams};
+
+use crate::config::PluginsConfig;
+use crate::log;
+use crate::span::Span;
+
+const PAGE_KIB: u64 = 64;
+// The most a plugin may hand back in place of what it was given.
+const MAX_REPLACEMENT: usize = 64 * 1024;
+
+// The hooks a module may export, with how many i32s each takes and gives back.
+const HOOKS: [(&str, usize, usize); 5] = [
+    ("alloc", 1, 1),
+    ("on_connect", 4, 1),
+    ("on_client_data", 2, 1),
+    ("on_backend_data", 2, 1),
+    ("on_close", 0, 0),
+];
+
+pub struct Plugins {
+    // The host functions, and the engine the modules were compiled for.
+    linker: Linker<Calls>,
+    plugins: Vec<Plugin>,
+    fuel: u64,
+    max_pages: u32,
+}
+
+struct Plugin {
+    name: String,
+    module: Module,
+}
+
+impl Plugins {
+    // A module that doesn't load is logged and left out, as is a directory
+    // that can't be read.
+    pub fn load(config: &PluginsConfig) -> Self {
+        let linker = linker(&engine());
+        let mut plugins = Vec::new();
+        match wasm_files(&config.directory) {
+            Ok(files) => {
+                for (name, path) in files {
+                    match fs::read(&path).map_err(|error| error.to_string()).and_then(|bytes| load_module(linker.engine(), &bytes)) {
+                        Ok(module) => {
+                            log!(Server, Info, "Loaded plugin {}", name);
+                            plugins.push(Plugin { name, module });
+                        }
+                        Err(error) => log!(Server, Warn, "Unable to load plugin {}: {}", path.display(), error),
+                    }
+                }
+            }
+            Err(error) => log!(Server, Warn, "Unable to read plugins from {}: {}", config.directory.display(), error),
+        }
+        let max_pages = config.memory.div_ceil(PAGE_KIB).min(u32::MAX as u64) as u32;
+        Self { linker, plugins, fuel: config.fuel, max_pages }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.plugins.iter().map(|plugin| plugin.name.as_str())
+    }
+
+    // Each session's own copies of the plugins. One that doesn't start, as
+    // when its start function traps, is logged and left out.
+    pub fn start(&self, span: Span<'_>) -> PluginSession {
+        let mut running = Vec::new();
+        for plugin in &self.plugins {
+            let limits = StoreLimitsBuilder::new().memory_size((self.max_pages as usize).saturating_mul(PAGE_KIB as usize * 1024)).build();
+            let mut store = Store::new(self.linker.engine(), Calls { limits, replacement: None, logged: Vec::new() });
+            store.limiter(|calls| &mut calls.limits);
+            let instance = store.set_fuel(self.fuel).and_then(|()| self.linker.instantiate(&mut store, &plugin.module));
+            log_lines(&mut store, &plugin.name, span);
+            match instance {
+                Ok(instance) => running.push(Running { name: plugin.name.clone(), store, instance }),
+                Err(error) => log!(Relay, Warn, span = span, "Plugin {} didn't start: {}", plugin.name, error),
+            }
+        }
+        PluginSession { running, fuel: self.fuel }
+    }
+}
+
+fn engine() -> Engine {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    // A trap is logged as what went wrong, without where in the module.
+    config.wasm_backtrace_max_frames(None);
+    Engine::new(&config).expect("Cannot set up the WebAssembly engine")
+}
+
+fn linker(engine: &Engine) -> Linker<Calls> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap("triserver", "replace", |mut caller: Caller<'_, Calls>, ptr: i32, len: i32| {
+        if len as u32 as usize > MAX_REPLACEMENT {
+            bail!("replaced the data with more than {} bytes", MAX_REPLACEMENT);
+        }
+        caller.data_mut().replacement = Some(read(&mut caller, ptr, len)?);
+        Ok(())
+    }).expect("Cannot define triserver.replace");
+    linker.func_wrap("triserver", "log", |mut caller: Caller<'_, Calls>, ptr: i32, len: i32| {
+        let line = String::from_utf8_lossy(&read(&mut caller, ptr, len)?).into_owned();
+        caller.data_mut().logged.push(line);
+        Ok(())
+    }).expect("Cannot define triserver.log");
+    linker
+}
+
+// The .wasm files in `directory` by name, with the name they stand for.
+fn wasm_files(directory: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "wasm") && path.is_file() {
+            let name = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+            files.push((name, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn load_module(engine: &Engine, bytes: &[u8]) -> Result<Module, String> {
+    let module = Module::from_binary(engine, bytes).map_err(|error| error.to_string())?;
+    if !matches!(module.get_export("memory"), Some(ExternType::Memory(_))) {
+        return Err(String::from("it doesn't export its memory"));
+    }
+    let function = |name| module.get_export(name).and_then(|ty| ty.func().cloned());
+    if function("alloc").is_none() {
+        return Err(String::from("it doesn't export alloc"));
+    }
+    for (name, params, results) in HOOKS {
+        if function(name).is_some_and(|ty| !takes_i32s(&ty, params, results)) {
+            return Err(format!("it exports {} with the wrong type", name));
+        }
+    }
+    Ok(module)
+}
+
+fn takes_i32s(ty: &FuncType, params: usize, results: usize) -> bool {
+    let i32s = |types: Vec<ValType>, count| types.len() == count && types.iter().all(|ty| matches!(ty, ValType::I32));
+    i32s(ty.params().collect(), params) && i32s(ty.results().collect(), results)
+}
+
+pub struct PluginSession {
+    running: Vec<Running>,
+    fuel: u64,
+}
+
+struct Running {
+    name: String,
+    store: Store<Calls>,
+    instance: Instance,
+}
+
+// What a hook gave back.
+enum Answer {
+    Continue,
+    Veto,
+}
+
+impl PluginSession {
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    // Err with the plugin's name when one ends the session.
+    pub fn on_connect(&mut self, span: Span<'_>, address: &str, backend: &str) -> Result<(), String> {
+        self.each(span, "on_connect", false, |instance, store| {
+            let address_ptr = hand_over(instance, store, address.as_bytes())?;
+            let backend_ptr = hand_over(instance, store, backend.as_bytes())?;
+            hook(instance, store, "on_connect", (address_ptr, address.len() as i32, backend_ptr, backend.len() as i32))
+        })
+    }
+
+    // What the caller typed, in plugin order; `data` is left as the plugins
+    // passed it on.
+    pub fn on_client_data(&mut self, span: Span<'_>, data: &mut Vec<u8>) -> Result<(), String> {
+        self.filter(span, "on_client_data", false, data)
+    }
+
+    // What the backend sent, in reverse plugin order.
+    pub fn on_backend_data(&mut self, span: Span<'_>, data: &mut Vec<u8>) -> Result<(), String> {
+        self.filter(span, "on_backend_data", true, data)
+    }
+
+    pub fn on_close(&mut self, span: Span<'_>) {
+        let _ = self.each(span, "on_close", false, |instance, store| {
+            instance.get_typed_func::<(), ()>(&mut *store, "on_close")?.call(&mut *store, ())?;
+            Ok(Answer::Continue)
+        });
+    }
+
+    fn filter(&mut self, span: Span<'_>, name: &str, reverse: bool, data: &mut Vec<u8>) -> Result<(), String> {
+        self.each(span, name, reverse, |instance, store| {
+            let ptr = hand_over(instance, store, data)?;
+            let answer = hook(instance, store, name, (ptr, data.len() as i32))?;
+            if let Some(replacement) = store.data_mut().replacement.take() {
+                *data = replacement;
+            }
+            Ok(answer)
+        })
+    }
+
+    // Runs `call` for each plugin that has `hook`. One that traps is left out
+    // from then on, and the rest carry on without it.
+    fn each(&mut self, span: Span<'_>, hook: &str, reverse: bool,
+            mut call: impl FnMut(&Instance, &mut Store<Calls>) -> wasmtime::Result<Answer>) -> Result<(), String> {
+        let mut order: Vec<usize> = (0..self.running.len()).collect();
+        if reverse {
+            order.reverse();
+        }
+        let mut trapped = Vec::new();
+        let mut vetoed = None;
+        for index in order {
+            let Running { name, store, instance } = &mut self.running[index];
+            if instance.get_func(&mut *store, hook).is_none() {
+                continue;
+            }
+            store.data_mut().replacement = None;
+            let result = store.set_fuel(self.fuel).and_then(|()| call(instance, store));
+            log_lines(store, name, span);
+            match result {
+                Ok(Answer::Continue) => {}
+                Ok(Answer::Veto) => {
+                    log!(Relay, Info, span = span, "Plugin {} ended the session from {}", name, hook);
+                    vetoed = Some(name.clone());
+                    break;
+                }
+                Err(trap) => {
+                    log!(Relay, Warn, span = span, "Plugin {} trapped in {} and is off for this session: {}", name, hook, trap);
+                    trapped.push(index);
+                }
+            }
+        }
+        trapped.sort_unstable();
+        for index in trapped.into_iter().rev() {
+            self.running.remove(index);
+        }
+        vetoed.map_or(Ok(()), Err)
+    }
+}
+
+// Copies `bytes` into room the plugin allocated for them.
+fn hand_over(instance: &Instance, store: &mut Store<Calls>, bytes: &[u8]) -> wasmtime::Result<i32> {
+    let ptr = instance.get_typed_func::<i32, i32>(&mut *store, "alloc")?.call(&mut *store, bytes.len() as i32)?;
+    let memory = instance.get_memory(&mut *store, "memory").ok_or_else(|| format_err!("it doesn't export its memory"))?;
+    memory.write(&mut *store, ptr as u32 as usize, bytes).map_err(|_| format_err!("alloc returned room outside memory"))?;
+    Ok(ptr)
+}
+
+fn hook(instance: &Instance, store: &mut Store<Calls>, name: &str, args: impl WasmParams) -> wasmtime::Result<Answer> {
+    match instance.get_typed_func::<_, i32>(&mut *store, name)?.call(&mut *store, args)? {
+        0 => Ok(Answer::Continue),
+        _ => Ok(Answer::Veto),
+    }
+}
+
+// A plugin copy's own state, besides its instance: its memory cap, and what
+// the host functions were given during a call.
+struct Calls {
+    limits: StoreLimits,
+    replacement: Option<Vec<u8>>,
+    // Lines for the server's log, written once the call returns.
+    logged: Vec<String>,
+}
+
+// The `len` bytes at `ptr` in the calling plugin's memory.
+fn read(caller: &mut Caller<'_, Calls>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    let memory = caller.get_export("memory").and_then(Extern::into_memory).ok_or_else(|| format_err!("it doesn't export its memory"))?;
+    memory.data(&*caller).get(ptr..ptr.saturating_add(len)).map(<[u8]>::to_vec).ok_or_else(|| format_err!("out of bounds memory access"))
+}
+
+fn log_lines(store: &mut Store<Calls>, name: &str, span: Span<'_>) {
+    for line in store.data_mut().logged.drain(..) {
+        log!(Relay, Info, span = span, "Plugin {}: {}", name, line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    const SPAN: Span<'static> = Span { client_id: Uuid::nil(), listener: "telnet", ip_addr: IpAddr::V4(Ipv4Addr::LOCALHOST), backend: None };
+
+    // What it takes to be a plugin, with `hooks` exported: each is given the
+    // locals $i and $byte, and alloc hands out room from 1024 on until the
+    // next hook runs.
+    fn plugin(hooks: &[(&str, &str)]) -> String {
+        let hooks: String = hooks.iter().map(|(name, body)| {
+            let ty = match *name {
+                "on_connect" => "(param $address i32) (param $address_len i32) (param $backend i32) (param $backend_len i32) (result i32)",
+                "on_close" => "",
+                _ => "(param $ptr i32) (param $len i32) (result i32)",
+            };
+            format!("(func (export \"{}\") {} (local $i i32) (local $byte i32) (global.set $next (i32.const 1024)) {})", name, ty, body)
+        }).collect();
+        format!(r#"(module
+            (import "triserver" "replace" (func $replace (param i32 i32)))
+            (import "triserver" "log" (func $log (param i32 i32)))
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 1024))
+            (func (export "alloc") (param $len i32) (result i32)
+                (global.get $next)
+                (global.set $next (i32.add (global.get $next) (local.get $len))))
+            {})"#, hooks)
+    }
+
+    // Upper-cases the letters it's given, a byte at a time.
+    const UPPER: &str = "
+        (block $done (loop $next_byte
+            (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+            (local.set $byte (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+            (i32.store8 (i32.add (local.get $ptr) (local.get $i))
+                (select (i32.sub (local.get $byte) (i32.const 32)) (local.get $byte)
+                        (i32.lt_u (i32.sub (local.get $byte) (i32.const 97)) (i32.const 26))))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $next_byte)))
+        (call $replace (local.get $ptr) (local.get $len))
+        (i32.const 0)";
+
+    // Ends the session when what it's given starts with an X.
+    const VETO_X: &str = "(i32.and (i32.ne (local.get $len) (i32.const 0)) (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 88)))";
+
+    const SPIN: &str = "(loop $forever (br $forever)) (i32.const 0)";
+
+    fn plugins(modules: &[(&str, String)]) -> Plugins {
+        let linker = linker(&engine());
+        let plugins = modules.iter().map(|(name, text)| Plugin { name: name.to_string(), module: load_module(linker.engine(), &wasm(text)).unwrap() }).collect();
+        Plugins { linker, plugins, fuel: 10_000, max_pages: 16 }
+    }
+
+    fn wasm(text: &str) -> Vec<u8> {
+        wat::parse_str(text).unwrap()
+    }
+
+    fn client_data(session: &mut PluginSession, data: &str) -> Result<String, String> {
+        let mut data = data.as_bytes().to_vec();
+        session.on_client_data(SPAN, &mut data)?;
+        Ok(String::from_utf8(data).unwrap())
+    }
+
+    #[test]
+    fn changes_what_is_passed_on() {
+        let plugins = plugins(&[("upper", plugin(&[("on_client_data", UPPER)]))]);
+        let mut session = plugins.start(SPAN);
+        assert_eq!(client_data(&mut session, "hello, World 1"), Ok(String::from("HELLO, WORLD 1")));
+        let mut data = b"from the backend".to_vec();
+        assert_eq!(session.on_backend_data(SPAN, &mut data), Ok(()));
+        assert_eq!(data, b"from the backend");
+    }
+
+    #[test]
+    fn ends_the_session_when_a_plugin_says_so() {
+        let plugins = plugins(&[("veto", plugin(&[("on_client_data", VETO_X)]))]);
+        let mut session = plugins.start(SPAN);
+        assert_eq!(client_data(&mut session, "hello"), Ok(String::from("hello")));
+        assert_eq!(client_data(&mut session, ""), Ok(String::new()));
+        assert_eq!(client_data(&mut session, "Xyzzy"), Err(String::from("veto")));
+        assert_eq!(client_data(&mut session, "XY"), Err(String::from("veto")));
+    }
+
+    #[test]
+    fn runs_backend_data_through_in_reverse() {
+        let plugins = plugins(&[
+            ("a", plugin(&[("on_client_data", VETO_X), ("on_backend_data", VETO_X)])),
+            ("b", plugin(&[("on_client_data", UPPER), ("on_backend_data", UPPER)])),
+        ]);
+        let mut session = plugins.start(SPAN);
+        // a sees the x before b upper-cases it, going to the backend, and after coming from it.
+        assert_eq!(client_data(&mut session, "xyzzy"), Ok(String::from("XYZZY")));
+        assert_eq!(session.on_backend_data(SPAN, &mut b"xyzzy".to_vec()), Err(String::from("a")));
+    }
+
+    #[test]
+    fn tells_plugins_who_is_connecting() {
+        // Keeps the address at 0 and refuses backends starting with an X.
+        let on_connect = "
+            (memory.copy (i32.const 0) (local.get $address) (local.get $address_len))
+            (i32.and (i32.ne (local.get $backend_len) (i32.const 0)) (i32.eq (i32.load8_u (local.get $backend)) (i32.const 88)))";
+        let plugins = plugins(&[("connect", plugin(&[("on_connect", on_connect)]))]);
+        let mut session = plugins.start(SPAN);
+        assert_eq!(session.on_connect(SPAN, "203.0.113.5", "bbs"), Ok(()));
+        let Running { store, instance, .. } = &mut session.running[0];
+        let memory = instance.get_memory(&mut *store, "memory").unwrap();
+        assert_eq!(&memory.data(&*store)[..11], b"203.0.113.5");
+        assert_eq!(plugins.start(SPAN).on_connect(SPAN, "203.0.113.5", "Xenix"), Err(String::from("connect")));
+    }
+
+    #[test]
+    fn keeps_each_sessions_state_apart() {
+        // Ends the session on the third call.
+        let plugins = plugins(&[("count", String::from(r#"(module
+            (import "triserver" "replace" (func (param i32 i32)))
+            (memory (export "memory") 1)
+            (global $count (mut i32) (i32.const 0))
+            (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "on_client_data") (param i32 i32) (result i32)
+                (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                (i32.gt_u (global.get $count) (i32.const 2))))"#))]);
+        let mut first = plugins.start(SPAN);
+        let mut second = plugins.start(SPAN);
+        assert_eq!(client_data(&mut first, "a"), Ok(String::from("a")));
+        assert_eq!(client_data(&mut first, "b"), Ok(String::from("b")));
+        assert_eq!(client_data(&mut second, "c"), Ok(String::from("c")));
+        assert_eq!(client_data(&mut first, "d"), Err(String::from("count")));
+        assert_eq!(client_data(&mut second, "e"), Ok(String::from("e")));
+    }
+
+    #[test]
+    fn leaves_out_a_plugin_that_runs_out_of_fuel() {
+        let plugins = plugins(&[("spin", plugin(&[("on_client_data", SPIN)])), ("upper", plugin(&[("on_client_data", UPPER)]))]);
+        let mut session = plugins.start(SPAN);
+        assert_eq!(client_data(&mut session, "hello"), Ok(String::from("HELLO")));
+        assert_eq!(session.running.len(), 1);
+        assert_eq!(client_data(&mut session, "again"), Ok(String::from("AGAIN")));
+        // Only for this session.
+        assert_eq!(plugins.start(SPAN).running.len(), 2);
+    }
+
+    #[test]
+    fn leaves_out_a_plugin_that_traps() {
+        let out_of_bounds = "(drop (i32.load (i32.const -1))) (i32.const 0)";
+        let oversized = format!("(call $replace (i32.const 0) (i32.const {})) (i32.const 0)", MAX_REPLACEMENT + 1);
+        let plugins = plugins(&[("out-of-bounds", plugin(&[("on_client_data", out_of_bounds)])),
+                                ("oversized", plugin(&[("on_client_data", &oversized)]))]);
+        let mut session = plugins.start(SPAN);
+        assert_eq!(client_data(&mut session, "hello"), Ok(String::from("hello")));
+        assert!(session.is_empty());
+    }
+
+    #[test]
+    fn caps_each_plugins_memory() {
+        // Ends the session if it could grow its memory by `pages` 64 KiB pages.
+        let grow = |pages: u32| format!("(i32.ne (memory.grow (i32.const {})) (i32.const -1))", pages);
+        let plugins = plugins(&[("grow", plugin(&[("on_client_data", &grow(16)), ("on_backend_data", &grow(15))]))]);
+        let mut session = plugins.start(SPAN);
+        assert_eq!(client_data(&mut session, "hello"), Ok(String::from("hello")));
+        assert_eq!(session.on_backend_data(SPAN, &mut b"hello".to_vec()), Err(String::from("grow")));
+    }
+
+    #[test]
+    fn refuses_modules_without_the_plugin_interface() {
+        let error = |text: &str| load_module(&engine(), &wasm(text)).err();
+        assert_eq!(error(r#"(module (func (export "alloc") (param i32) (result i32) (local.get 0)))"#).as_deref(),
+                   Some("it doesn't export its memory"));
+        assert_eq!(error(r#"(module (memory (export "memory") 1))"#).as_deref(), Some("it doesn't export alloc"));
+        assert_eq!(error(r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (local.get 0))
+            (func (export "on_client_data") (param i32) (result i32) (local.get 0)))"#).as_deref(),
+                   Some("it exports on_client_data with the wrong type"));
+    }
+
+    #[test]
+    fn loads_every_module_in_the_directory_by_name() {
+        let directory = std::env::temp_dir().join(format!("triserver-plugins-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("nested.wasm")).unwrap();
+        fs::write(directory.join("b.wasm"), wasm(&plugin(&[("on_client_data", UPPER)]))).unwrap();
+        fs::write(directory.join("a.wasm"), wasm(&plugin(&[]))).unwrap();
+        fs::write(directory.join("broken.wasm"), b"\0asm\x01\0\0\0\x01").unwrap();
+        fs::write(directory.join("README"), "not a plugin").unwrap();
+        let loaded = Plugins::load(&PluginsConfig { directory: directory.clone(), fuel: 1000, memory: 100 });
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(loaded.names().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(loaded.max_pages, 2);
+        let missing = Plugins::load(&PluginsConfig { directory, fuel: 1000, memory: 100 });
+        assert_eq!(missing.names().count(), 0);
+    }
+}
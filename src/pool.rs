@@ -0,0 +1,214 @@
+// Picks the backend for a caller sent to a pool. With round-robin, members
+// take turns in proportion to their weights, spread out rather than in runs
+// (smooth weighted round-robin, as nginx does it), so with 80/20 every fifth
+// caller goes to the small node. With ip-hash, each member scores the caller's
+// address and the highest weighted score wins (rendezvous hashing), so an
+// address keeps its member across restarts, and only the callers of a member
+// that goes down are moved. Members whose last health probe failed sit out
+// until they recover, unless every member is down. Members being drained for
+// maintenance sit out regardless, while their callers stay connected.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{BackendConfig, Config, PoolConfig, PoolPolicy};
+use crate::events::Event;
+use crate::health::Health;
+use crate::log;
+use crate::sha256;
+use crate::ServerContext;
+
+#[derive(Clone, Default)]
+pub struct Pools {
+    // Each round-robin pool's running totals, by member.
+    rounds: Arc<Mutex<HashMap<String, Vec<i64>>>>,
+    // Backends taking no new callers from their pools.
+    draining: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Pools {
+    // The backend a new caller for `name` goes to: the backend itself, or the
+    // chosen member if it names a pool. None if every member is draining.
+    pub fn pick<'a>(&self, config: &'a Config, health: &Health, name: &str, ip_addr: IpAddr) -> Option<&'a BackendConfig> {
+        let Some(pool) = config.pool(name) else {
+            return config.backend(name);
+        };
+        let open: Vec<bool> = pool.members.iter().map(|member| !self.is_draining(&member.backend)).collect();
+        if !open.contains(&true) {
+            return None;
+        }
+        let mut up: Vec<bool> = pool.members.iter().zip(&open).map(|(member, &open)| open && !health.is_down(&member.backend)).collect();
+        if !up.contains(&true) {
+            up = open;
+        }
+        let chosen = match pool.policy {
+            PoolPolicy::RoundRobin => self.next(pool, &up),
+            PoolPolicy::IpHash => hash(pool, &up, ip_addr),
+        };
+        config.backend(&pool.members[chosen].backend)
+    }
+
+    // Returns false if the backend was already draining.
+    pub fn drain(&self, backend: &str) -> bool {
+        self.draining.lock().unwrap().insert(backend.to_string())
+    }
+
+    // Returns false if the backend wasn't draining.
+    pub fn undrain(&self, backend: &str) -> bool {
+        self.draining.lock().unwrap().remove(backend)
+    }
+
+    pub fn is_draining(&self, backend: &str) -> bool {
+        self.draining.lock().unwrap().contains(backend)
+    }
+
+    pub fn draining(&self) -> Vec<String> {
+        self.draining.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn next(&self, pool: &PoolConfig, up: &[bool]) -> usize {
+        let mut rounds = self.rounds.lock().unwrap();
+        let current = rounds.entry(pool.name.clone()).or_default();
+        // Members joined or left from the admin interface: start over.
+        if current.len() != pool.members.len() {
+            *current = vec![0; pool.members.len()];
+        }
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (i, member) in pool.members.iter().enumerate().filter(|(i, _)| up[*i]) {
+            current[i] += member.weight as i64;
+            total += member.weight as i64;
+            if best.is_none_or(|best| current[i] > current[best]) {
+                best = Some(i);
+            }
+        }
+        let chosen = best.unwrap_or(0);
+        current[chosen] -= total;
+        chosen
+    }
+}
+
+// Once the last session on a draining backend has gone it can be taken down,
+// which the admin interface's events stream and `drains` show.
+pub fn report_if_drained(context: &ServerContext, backend: &str) {
+    if context.pools.is_draining(backend) && !context.clients.values().iter().any(|client| client.backend.as_deref() == Some(backend)) {
+        log!(Server, Info, "Backend {} is drained, no sessions left", backend);
+        context.events.publish(Event::Drained { backend: backend.to_string() });
+    }
+}
+
+fn hash(pool: &PoolConfig, up: &[bool], ip_addr: IpAddr) -> usize {
+    let score = |backend: &str, weight: u64| {
+        let digest = sha256::digest(format!("{}/{}", ip_addr, backend).as_bytes());
+        let value = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        // Uniform in (0, 1), so the logarithm is finite and negative.
+        let uniform = (value as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+        weight as f64 / -uniform.ln()
+    };
+    pool.members.iter().enumerate()
+        .filter(|(i, _)| up[*i])
+        .map(|(i, member)| (i, score(&member.backend, member.weight)))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn config(policy: &str, weights: &[u64]) -> Config {
+        let mut source = format!("[[pool]]\nname = \"nodes\"\npolicy = \"{}\"\n", policy);
+        for (i, weight) in weights.iter().enumerate() {
+            source += &format!("[[pool.member]]\nbackend = \"node{}\"\nweight = {}\n", i + 1, weight);
+        }
+        for i in 1..=weights.len() {
+            source += &format!("[[backend]]\nname = \"node{}\"\nhost = \"127.0.0.1\"\nport = {}\n", i, 2000 + i);
+        }
+        Config::parse(&source).unwrap()
+    }
+
+    fn address(i: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0xc000_0200 + i))
+    }
+
+    fn picks(pools: &Pools, config: &Config, count: u32) -> Vec<String> {
+        (0..count).map(|i| pools.pick(config, &Health::default(), "nodes", address(i)).unwrap().name.clone()).collect()
+    }
+
+    #[test]
+    fn takes_turns_in_proportion_to_the_weights() {
+        let config = config("round-robin", &[80, 20]);
+        let pools = Pools::default();
+        // Spread out: every fifth caller goes to the small node.
+        let expected: Vec<&str> = ["node1", "node1", "node2", "node1", "node1"].repeat(4);
+        assert_eq!(picks(&pools, &config, 20), expected);
+    }
+
+    #[test]
+    fn passes_over_members_that_are_down() {
+        let config = config("round-robin", &[1, 1, 1]);
+        let pool = config.pool("nodes").unwrap();
+        let pools = Pools::default();
+        let chosen: Vec<usize> = (0..4).map(|_| pools.next(pool, &[true, false, true])).collect();
+        assert_eq!(chosen, [0, 2, 0, 2]);
+        let chosen: Vec<usize> = (0..100).map(|i| hash(pool, &[false, true, true], address(i))).collect();
+        assert!(!chosen.contains(&0));
+    }
+
+    #[test]
+    fn keeps_an_address_on_its_member() {
+        let config = config("ip-hash", &[1, 1, 1]);
+        let first = picks(&Pools::default(), &config, 50);
+        assert_eq!(picks(&Pools::default(), &config, 50), first);
+        // Every member gets some.
+        for member in ["node1", "node2", "node3"] {
+            assert!(first.iter().any(|chosen| chosen == member), "{:?}", first);
+        }
+    }
+
+    #[test]
+    fn moves_only_the_callers_of_a_member_that_goes_down() {
+        let config = config("ip-hash", &[1, 1, 1]);
+        let pool = config.pool("nodes").unwrap();
+        for i in 0..200 {
+            let before = hash(pool, &[true, true, true], address(i));
+            let after = hash(pool, &[true, false, true], address(i));
+            if before != 1 {
+                assert_eq!(before, after, "{}", address(i));
+            }
+        }
+    }
+
+    #[test]
+    fn hashes_in_proportion_to_the_weights() {
+        let config = config("ip-hash", &[3, 1]);
+        let chosen = picks(&Pools::default(), &config, 4000);
+        let small = chosen.iter().filter(|chosen| *chosen == "node2").count();
+        assert!((800..1200).contains(&small), "{} of 4000", small);
+    }
+
+    #[test]
+    fn leaves_out_members_being_drained() {
+        let config = config("round-robin", &[1, 1]);
+        let pools = Pools::default();
+        assert!(pools.drain("node1"));
+        assert!(!pools.drain("node1"));
+        assert_eq!(picks(&pools, &config, 3), ["node2", "node2", "node2"]);
+        assert!(pools.drain("node2"));
+        assert!(pools.pick(&config, &Health::default(), "nodes", address(0)).is_none());
+        assert_eq!(pools.draining(), ["node1", "node2"]);
+        assert!(pools.undrain("node1"));
+        assert!(!pools.undrain("node1"));
+        assert_eq!(picks(&pools, &config, 2), ["node1", "node1"]);
+    }
+
+    #[test]
+    fn picks_a_backend_named_directly() {
+        let config = config("round-robin", &[1]);
+        let pools = Pools::default();
+        assert_eq!(pools.pick(&config, &Health::default(), "node1", address(0)).map(|backend| backend.port), Some(2001));
+        assert!(pools.pick(&config, &Health::default(), "nowhere", address(0)).is_none());
+    }
+}
@@ -0,0 +1,487 @@
+// Asking a home router to forward the telnet port here, for a server behind
+// NAT on a consumer connection. NAT-PMP (RFC 6886), which Apple and most open
+// router firmware speak, is a couple of UDP packets to the gateway; UPnP IGD
+// finds the router by SSDP multicast, reads its description for the WAN
+// connection service and calls AddPortMapping on it over SOAP. Mappings are
+// asked for with a lifetime and renewed at half of it, so one left behind by
+// a server that has stopped lapses on its own. A router that wants them
+// permanent (UPnP error 725) gets that, and they're renewed all the same, in
+// case it restarts and forgets them.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::config::{MappingProtocol, PortMappingConfig};
+use crate::http::{self, Url};
+use crate::log;
+use crate::version;
+
+const NAT_PMP_PORT: u16 = 5351;
+// RFC 6886's retransmission, cut short: 250ms, doubling, four times.
+const NAT_PMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+const NAT_PMP_TRIES: u32 = 4;
+const SSDP_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SEARCH_TIME: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// The WAN connection services AddPortMapping is called on, best first.
+const SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// How long after a failure the router is looked for again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const SHORTEST_RENEWAL: Duration = Duration::from_secs(60);
+
+enum Router {
+    NatPmp(Ipv4Addr),
+    Upnp(UpnpGateway),
+}
+
+struct UpnpGateway {
+    control: Url,
+    service: &'static str,
+    // This machine's address on the router's network.
+    local_ip: IpAddr,
+}
+
+struct Mapping {
+    via: &'static str,
+    external: SocketAddr,
+    // Zero for a permanent one.
+    lifetime: Duration,
+}
+
+// A UPnP action the router turned down.
+struct Fault {
+    code: Option<u32>,
+    description: String,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} (UPnP error {})", self.description, code),
+            None => write!(f, "{}", self.description),
+        }
+    }
+}
+
+impl From<String> for Fault {
+    fn from(description: String) -> Self {
+        Self { code: None, description }
+    }
+}
+
+pub fn launch_port_mapping(config: &PortMappingConfig, internal_port: u16) {
+    let config = config.clone();
+    let external_port = config.external_port.unwrap_or(internal_port);
+    let _ = thread::spawn(move || {
+        let mut router = None;
+        // The outside address last logged, and whether the last try failed,
+        // so only changes are logged.
+        let mut reachable_at = None;
+        let mut failing = false;
+        loop {
+            let wait = match map(&config, &mut router, internal_port, external_port) {
+                Ok(mapping) => {
+                    if reachable_at != Some(mapping.external) {
+                        let lifetime = match mapping.lifetime.as_secs() {
+                            0 => String::from("permanently"),
+                            seconds => format!("for {}s", seconds),
+                        };
+                        log!(Server, Info, "The router forwards {} to port {} {} ({})", mapping.external, internal_port, lifetime,
+                             mapping.via);
+                        reachable_at = Some(mapping.external);
+                    } else {
+                        log!(Server, Debug, "Renewed the router's mapping of {} ({})", mapping.external, mapping.via);
+                    }
+                    failing = false;
+                    let lifetime = if mapping.lifetime.is_zero() { config.lifetime } else { mapping.lifetime };
+                    (lifetime / 2).max(SHORTEST_RENEWAL)
+                }
+                Err(error) => {
+                    if !failing {
+                        log!(Server, Warn, "Couldn't map port {} on the router: {}; trying again every {}s", external_port, error,
+                             RETRY_INTERVAL.as_secs());
+                        failing = true;
+                    }
+                    router = None;
+                    reachable_at = None;
+                    RETRY_INTERVAL
+                }
+            };
+            sleep(wait);
+        }
+    });
+}
+
+fn map(config: &PortMappingConfig, router: &mut Option<Router>, internal_port: u16, external_port: u16) -> Result<Mapping, String> {
+    let router = match router {
+        Some(router) => router,
+        None => router.insert(find_router(config)?),
+    };
+    match router {
+        Router::NatPmp(gateway) => nat_pmp_map(*gateway, internal_port, external_port, config.lifetime),
+        Router::Upnp(gateway) => upnp_map(gateway, internal_port, external_port, config.lifetime).map_err(|fault| fault.to_string()),
+    }
+}
+
+fn find_router(config: &PortMappingConfig) -> Result<Router, String> {
+    let nat_pmp = || {
+        let gateway = config.gateway.or_else(default_gateway).ok_or("no gateway given, and no default route to find one by")?;
+        nat_pmp_external_address(gateway)?;
+        Ok::<_, String>(gateway)
+    };
+    match config.protocol {
+        MappingProtocol::NatPmp => nat_pmp().map(Router::NatPmp).map_err(|error| format!("NAT-PMP: {}", error)),
+        MappingProtocol::Upnp => upnp_discover().map(Router::Upnp).map_err(|error| format!("UPnP: {}", error)),
+        MappingProtocol::Auto => match nat_pmp() {
+            Ok(gateway) => Ok(Router::NatPmp(gateway)),
+            Err(nat_pmp_error) => upnp_discover()
+                .map(Router::Upnp)
+                .map_err(|upnp_error| format!("NAT-PMP: {}; UPnP: {}", nat_pmp_error, upnp_error)),
+        },
+    }
+}
+
+// The gateway of the default IPv4 route, from the kernel's routing table.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16).ok().filter(|&gateway| gateway != 0),
+            _ => None,
+        }
+    })
+    // Written out as the address's bytes in memory order.
+    .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+// Sends a NAT-PMP request until it's answered, giving back the answer once
+// it's been checked for success.
+fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8], length: usize) -> Result<Vec<u8>, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|error| error.to_string())?;
+    socket.connect((gateway, NAT_PMP_PORT)).map_err(|error| format!("{}: {}", gateway, error))?;
+    let mut wait = NAT_PMP_FIRST_WAIT;
+    for _ in 0..NAT_PMP_TRIES {
+        socket.send(request).map_err(|error| format!("{}: {}", gateway, error))?;
+        socket.set_read_timeout(Some(wait)).map_err(|error| error.to_string())?;
+        let mut response = [0; 16];
+        match socket.recv(&mut response) {
+            Ok(received) => {
+                if let Some(answer) = nat_pmp_answer(request, &response[..received], length) {
+                    return answer.map(<[u8]>::to_vec).map_err(|error| format!("{} refused: {}", gateway, error));
+                }
+            }
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            // An ICMP port unreachable, from a router that doesn't speak it.
+            Err(error) if error.kind() == ErrorKind::ConnectionRefused => return Err(format!("{} doesn't take NAT-PMP", gateway)),
+            Err(error) => return Err(format!("{}: {}", gateway, error)),
+        }
+        wait *= 2;
+    }
+    Err(format!("{} didn't answer", gateway))
+}
+
+// The response, if `response` is one to `request` at least `length` long,
+// once it's been checked for success. Anything else is ignored.
+fn nat_pmp_answer<'a>(request: &[u8], response: &'a [u8], length: usize) -> Option<Result<&'a [u8], &'static str>> {
+    // The response's opcode is the request's plus 128.
+    if response.len() < length || response[0] != 0 || response[1] != request[1] + 128 {
+        return None;
+    }
+    Some(match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(response),
+        result => Err(describe_result(result)),
+    })
+}
+
+fn describe_result(result: u16) -> &'static str {
+    match result {
+        1 => "unsupported version",
+        2 => "not authorized, perhaps turned off",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result code",
+    }
+}
+
+fn nat_pmp_external_address(gateway: Ipv4Addr) -> Result<Ipv4Addr, String> {
+    let response = nat_pmp_request(gateway, &[0, 0], 12)?;
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+}
+
+fn nat_pmp_map(gateway: Ipv4Addr, internal_port: u16, external_port: u16, lifetime: Duration) -> Result<Mapping, String> {
+    let address = nat_pmp_external_address(gateway)?;
+    let response = nat_pmp_request(gateway, &nat_pmp_map_request(internal_port, external_port, lifetime), 16)?;
+    Ok(nat_pmp_mapping(address, &response))
+}
+
+fn nat_pmp_map_request(internal_port: u16, external_port: u16, lifetime: Duration) -> Vec<u8> {
+    // Opcode 2 maps TCP.
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs().min(u32::MAX as u64) as u32).to_be_bytes());
+    request
+}
+
+fn nat_pmp_mapping(address: Ipv4Addr, response: &[u8]) -> Mapping {
+    // The router may give a different port from the one asked for.
+    let port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Mapping { via: "NAT-PMP", external: SocketAddr::new(IpAddr::V4(address), port), lifetime: Duration::from_secs(lifetime.into()) }
+}
+
+// Looks for an Internet gateway device by SSDP and takes the first one whose
+// description has a WAN connection service.
+fn upnp_discover() -> Result<UpnpGateway, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|error| error.to_string())?;
+    let search = format!("M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+                          ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nUSER-AGENT: TriServer/{}\r\n\r\n",
+                         version::VERSION);
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS).map_err(|error| format!("searching: {}", error))?;
+    let deadline = Instant::now() + SEARCH_TIME;
+    let mut last_error = String::from("no gateway answered the search");
+    let mut tried = Vec::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+        socket.set_read_timeout(Some(left)).map_err(|error| error.to_string())?;
+        let mut buffer = [0; 2048];
+        let received = match socket.recv_from(&mut buffer) {
+            Ok((received, _)) => received,
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(error) => return Err(error.to_string()),
+        };
+        let answer = String::from_utf8_lossy(&buffer[..received]);
+        let Some(location) = header(&answer, "location") else {
+            continue;
+        };
+        // Devices answer more than once.
+        if tried.contains(&location) {
+            continue;
+        }
+        tried.push(location.clone());
+        match upnp_describe(&location) {
+            Ok(gateway) => return Ok(gateway),
+            Err(error) => last_error = format!("{}: {}", location, error),
+        }
+    }
+    Err(last_error)
+}
+
+fn header(message: &str, name: &str) -> Option<String> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+    })
+}
+
+fn upnp_describe(location: &str) -> Result<UpnpGateway, String> {
+    let url: Url = location.parse()?;
+    let response = http::get(&url, &[("User-Agent", format!("TriServer/{}", version::VERSION))], HTTP_TIMEOUT)?;
+    if !response.is_success() {
+        return Err(format!("the description answered {}", response.status));
+    }
+    let (service, control) = wan_service(&String::from_utf8_lossy(&response.body), url)?;
+    let local_ip = local_ip_towards(&control.host, control.port)?;
+    Ok(UpnpGateway { control, service, local_ip })
+}
+
+// The best WAN connection service in a device description, and the URL its
+// actions are posted to.
+fn wan_service(description: &str, url: Url) -> Result<(&'static str, Url), String> {
+    let (service, control) = SERVICES
+        .iter()
+        .find_map(|&wanted| {
+            let block = description.split("<service>").skip(1).find(|block| element(block, "serviceType") == Some(wanted))?;
+            Some((wanted, element(block, "controlURL")?))
+        })
+        .ok_or("no WAN connection service in its description")?;
+    let base = match element(description, "URLBase") {
+        Some(base) => base.parse()?,
+        None => url,
+    };
+    let control = if control.starts_with("http://") {
+        control.parse()?
+    } else {
+        Url { path: format!("/{}", control.trim_start_matches('/')), ..base }
+    };
+    Ok((service, control))
+}
+
+// The address this machine reaches the router from, which the mapping points at.
+fn local_ip_towards(host: &str, port: u16) -> Result<IpAddr, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|error| error.to_string())?;
+    socket.connect((host, port)).and_then(|()| socket.local_addr()).map(|address| address.ip()).map_err(|error| format!("{}: {}", host, error))
+}
+
+// The text of the first <name> element, outside any namespace prefix.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(xml[start..end].trim())
+}
+
+fn soap(gateway: &UpnpGateway, action: &str, arguments: &str) -> Result<String, Fault> {
+    let body = format!("<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+                        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+                        <u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body></s:Envelope>\r\n",
+                       action = action, service = gateway.service, arguments = arguments);
+    let headers = [
+        ("Content-Type", String::from("text/xml; charset=\"utf-8\"")),
+        ("SOAPAction", format!("\"{}#{}\"", gateway.service, action)),
+        ("User-Agent", format!("TriServer/{}", version::VERSION)),
+    ];
+    let response = http::post(&gateway.control, &headers, body.as_bytes(), HTTP_TIMEOUT)?;
+    let text = String::from_utf8_lossy(&response.body).into_owned();
+    if response.is_success() {
+        return Ok(text);
+    }
+    Err(fault(action, response.status, &text))
+}
+
+fn fault(action: &str, status: u16, text: &str) -> Fault {
+    Fault {
+        code: element(text, "errorCode").and_then(|code| code.parse().ok()),
+        description: element(text, "errorDescription").map_or_else(|| format!("{} answered {}", action, status), String::from),
+    }
+}
+
+fn upnp_map(gateway: &UpnpGateway, internal_port: u16, external_port: u16, lifetime: Duration) -> Result<Mapping, Fault> {
+    let add = |lifetime: u64| {
+        let arguments = format!("<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>TCP</NewProtocol>\
+                                 <NewInternalPort>{}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled>\
+                                 <NewPortMappingDescription>TriServer</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+                                external_port, internal_port, gateway.local_ip, lifetime);
+        soap(gateway, "AddPortMapping", &arguments)
+    };
+    let lifetime = match add(lifetime.as_secs()) {
+        Ok(_) => lifetime,
+        // OnlyPermanentLeasesSupported
+        Err(Fault { code: Some(725), .. }) => {
+            add(0)?;
+            Duration::ZERO
+        }
+        Err(fault) => return Err(fault),
+    };
+    let answer = soap(gateway, "GetExternalIPAddress", "")?;
+    let address = element(&answer, "NewExternalIPAddress")
+        .and_then(|address| address.parse().ok())
+        .ok_or_else(|| String::from("the router didn't say what its external address is"))?;
+    Ok(Mapping { via: "UPnP", external: SocketAddr::new(address, external_port), lifetime })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers as RFC 6886 lays them out: the external address, a mapping of
+    // 9000 to 9001 for an hour, and the same refused as not authorized.
+    const ADDRESS_RESPONSE: [u8; 12] = [0x00, 0x80, 0x00, 0x00, 0x00, 0x01, 0x51, 0x80, 0xcb, 0x00, 0x71, 0x07];
+    const MAPPING_RESPONSE: [u8; 16] = [0x00, 0x82, 0x00, 0x00, 0x00, 0x01, 0x51, 0x84, 0x23, 0x28, 0x23, 0x29, 0x00, 0x00, 0x0e, 0x10];
+    const REFUSED_RESPONSE: [u8; 16] = [0x00, 0x82, 0x00, 0x02, 0x00, 0x01, 0x51, 0x84, 0x23, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device><deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+<controlURL>/ctl/L3F</controlURL>
+</service></serviceList>
+<deviceList><device><deviceList><device>
+<serviceList><service>
+<serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+<controlURL>/ctl/PPP</controlURL>
+</service><service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service></serviceList>
+</device></deviceList></device></deviceList>
+</device></root>"#;
+
+    fn url(text: &str) -> Url {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn encodes_a_mapping_request() {
+        assert_eq!(nat_pmp_map_request(9000, 9001, Duration::from_secs(3600)),
+                   [0x00, 0x02, 0x00, 0x00, 0x23, 0x28, 0x23, 0x29, 0x00, 0x00, 0x0e, 0x10]);
+        // Lifetimes past what the field holds are cut to the most it does.
+        assert_eq!(nat_pmp_map_request(23, 23, Duration::MAX)[8..], [0xff; 4]);
+    }
+
+    #[test]
+    fn parses_answers() {
+        let address = nat_pmp_answer(&[0, 0], &ADDRESS_RESPONSE, 12).unwrap().unwrap();
+        assert_eq!(Ipv4Addr::new(address[8], address[9], address[10], address[11]), Ipv4Addr::new(203, 0, 113, 7));
+
+        let request = nat_pmp_map_request(9000, 9001, Duration::from_secs(3600));
+        let response = nat_pmp_answer(&request, &MAPPING_RESPONSE, 16).unwrap().unwrap();
+        let mapping = nat_pmp_mapping(Ipv4Addr::new(203, 0, 113, 7), response);
+        assert_eq!(mapping.external, "203.0.113.7:9001".parse().unwrap());
+        assert_eq!(mapping.lifetime, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn reports_a_refusal() {
+        let request = nat_pmp_map_request(9000, 9000, Duration::from_secs(3600));
+        assert_eq!(nat_pmp_answer(&request, &REFUSED_RESPONSE, 16), Some(Err("not authorized, perhaps turned off")));
+        assert_eq!(describe_result(99), "unknown result code");
+    }
+
+    #[test]
+    fn waits_past_what_doesnt_answer_the_request() {
+        let request = nat_pmp_map_request(9000, 9000, Duration::from_secs(3600));
+        // Cut short.
+        assert_eq!(nat_pmp_answer(&request, &MAPPING_RESPONSE[..12], 16), None);
+        assert_eq!(nat_pmp_answer(&request, &[], 16), None);
+        // The answer to another request.
+        assert_eq!(nat_pmp_answer(&request, &ADDRESS_RESPONSE, 12), None);
+        assert_eq!(nat_pmp_answer(&[0, 0], &MAPPING_RESPONSE, 12), None);
+        // Another version.
+        let mut newer = MAPPING_RESPONSE;
+        newer[0] = 2;
+        assert_eq!(nat_pmp_answer(&request, &newer, 16), None);
+    }
+
+    #[test]
+    fn finds_the_wan_connection_service() {
+        let (service, control) = wan_service(DESCRIPTION, url("http://192.168.1.1:5000/rootDesc.xml")).unwrap();
+        assert_eq!(service, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        assert_eq!(control, url("http://192.168.1.1:5000/ctl/IPConn"));
+
+        let based = DESCRIPTION.replace("<device>", "<URLBase>http://192.168.1.254:49000/</URLBase><device>");
+        assert_eq!(wan_service(&based, url("http://192.168.1.1:5000/rootDesc.xml")).unwrap().1, url("http://192.168.1.254:49000/ctl/IPConn"));
+
+        let without = DESCRIPTION.replace("WANIPConnection", "WANIPv6FirewallControl").replace("WANPPPConnection", "WANCommonInterfaceConfig");
+        assert!(wan_service(&without, url("http://192.168.1.1:5000/rootDesc.xml")).is_err());
+    }
+
+    #[test]
+    fn reads_search_answers_and_faults() {
+        let answer = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(header(answer, "location").as_deref(), Some("http://192.168.1.1:5000/rootDesc.xml"));
+        assert_eq!(header(answer, "server"), None);
+
+        let body = "<s:Envelope><s:Body><s:Fault><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+                    <errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                    </UPnPError></detail></s:Fault></s:Body></s:Envelope>";
+        assert_eq!(fault("AddPortMapping", 500, body).to_string(), "OnlyPermanentLeasesSupported (UPnP error 725)");
+        assert_eq!(fault("AddPortMapping", 500, "").to_string(), "AddPortMapping answered 500");
+    }
+}
@@ -0,0 +1,83 @@
+// Switching from root to an unprivileged account once the listeners are bound,
+// so the server can own port 23 without running as root.
+
+use std::ffi::{c_char, c_int, CString};
+
+#[repr(C)]
+struct Passwd {
+    pw_name: *const c_char,
+    pw_passwd: *const c_char,
+    pw_uid: u32,
+    pw_gid: u32,
+    // The remaining fields differ between platforms and aren't needed.
+}
+
+#[repr(C)]
+struct Group {
+    gr_name: *const c_char,
+    gr_passwd: *const c_char,
+    gr_gid: u32,
+}
+
+extern "C" {
+    fn getpwnam(name: *const c_char) -> *const Passwd;
+    fn getgrnam(name: *const c_char) -> *const Group;
+    fn getuid() -> u32;
+    fn setuid(uid: u32) -> c_int;
+    fn setgid(gid: u32) -> c_int;
+    #[cfg(target_os = "linux")]
+    fn setgroups(size: usize, list: *const u32) -> c_int;
+    #[cfg(not(target_os = "linux"))]
+    fn setgroups(size: c_int, list: *const u32) -> c_int;
+}
+
+// Returns the uid and primary gid of a user.
+pub fn lookup_user(name: &str) -> Result<(u32, u32), String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid user name '{}'", name))?;
+    let entry = unsafe { getpwnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no such user '{}'", name));
+    }
+    Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) })
+}
+
+pub fn lookup_group(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid group name '{}'", name))?;
+    let entry = unsafe { getgrnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no such group '{}'", name));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+// Drops to the given user, and to its primary group unless another is named.
+// Supplementary groups are cleared.
+pub fn drop_to(user: &str, group: Option<&str>) -> Result<(), String> {
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid,
+    };
+    if unsafe { getuid() } == uid {
+        return Ok(());
+    }
+    if unsafe { getuid() } != 0 {
+        return Err(format!("switching to user '{}' requires starting as root", user));
+    }
+    unsafe {
+        if setgroups(1, &gid) != 0 {
+            return Err(format!("setgroups: {}", std::io::Error::last_os_error()));
+        }
+        if setgid(gid) != 0 {
+            return Err(format!("setgid({}): {}", gid, std::io::Error::last_os_error()));
+        }
+        if setuid(uid) != 0 {
+            return Err(format!("setuid({}): {}", uid, std::io::Error::last_os_error()));
+        }
+        // If root can still be regained, the drop didn't take.
+        if setuid(0) == 0 {
+            return Err(String::from("still able to regain root after setuid"));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+// The PROXY protocol header (version 1 or 2) that load balancers and tunnels
+// such as HAProxy put in front of a connection to say who the caller really
+// is. Only connections from [proxy_protocol] trusted addresses are read for
+// one, and they must start with it; a header from anywhere else is spoofed.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Sender;
+
+use crate::config::{ProxyProtocolConfig, SpoofedHeader};
+use crate::log;
+use crate::slots::Slots;
+use crate::{connect, ClientManagerMessage, Forwarded};
+
+// How long a trusted proxy gets to send its header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// How long an untrusted caller's first bytes are waited for, to see whether
+// they are a header. Telnet callers often wait to be spoken to, so it's short.
+const SPOOF_TIMEOUT: Duration = Duration::from_millis(250);
+// Trusted connections having their header read at once, each on a thread
+// that may wait out HEADER_TIMEOUT. Past this, new ones are turned away.
+static READING: Slots = Slots::new(64);
+// Untrusted callers being checked for a spoofed header at once, each for up
+// to SPOOF_TIMEOUT. Past this, new ones are let in unchecked; their header
+// would not have been honoured anyway.
+static CHECKING: Slots = Slots::new(256);
+// The longest version 1 header, CRLF included.
+const MAX_V1_HEADER: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// Starts `accept` on a thread of its own, unless the connection's slots are
+// all taken by others still waiting on theirs.
+pub fn launch_accept(mut stream: TcpStream, config: Arc<ProxyProtocolConfig>, client_manager_tx: Sender<ClientManagerMessage>) {
+    let Ok(peer) = stream.peer_addr() else {
+        return;
+    };
+    let trusted = config.trusts(peer.ip());
+    let Some(slot) = (if trusted { &READING } else { &CHECKING }).take() else {
+        if trusted {
+            log!(Server, Warn, "Refused connection from {}: {} connections are already sending PROXY headers", peer, READING.max());
+            let _ = stream.write_all(b"Too many connections, please try again later.\r\n");
+        } else {
+            log!(Server, Debug, "Not checking {} for a PROXY header: {} callers already are", peer, CHECKING.max());
+            if stream.set_nonblocking(true).is_ok() {
+                connect(&client_manager_tx, stream, "telnet", None);
+            }
+        }
+        return;
+    };
+    let _ = thread::spawn(move || {
+        let _slot = slot;
+        accept(stream, &config, &client_manager_tx);
+    });
+}
+
+// Reads the header, if any, of a freshly accepted connection and hands it to
+// the client manager. A trusted proxy's header may take a moment.
+fn accept(mut stream: TcpStream, config: &ProxyProtocolConfig, client_manager_tx: &Sender<ClientManagerMessage>) {
+    let Ok(peer) = stream.peer_addr() else {
+        return;
+    };
+    let mut forwarded = None;
+    if config.trusts(peer.ip()) {
+        match read_header(&mut stream) {
+            Ok(Some(source)) => forwarded = Some(Forwarded { ip_addr: source.ip(), port: source.port(), server_name: None }),
+            // The proxy's own health checks.
+            Ok(None) => {}
+            Err(error) => {
+                log!(Server, Warn, "Bad PROXY header from {}: {}", peer, error);
+                return;
+            }
+        }
+    } else if starts_with_header(&stream) {
+        match config.spoofed {
+            SpoofedHeader::Reject => {
+                log!(Server, Warn, "Refused connection from {}: PROXY header from an untrusted address", peer);
+                return;
+            }
+            SpoofedHeader::Ignore => {
+                log!(Server, Warn, "Ignoring PROXY header from untrusted address {}", peer);
+                let _ = read_header(&mut stream);
+            }
+        }
+    }
+    if stream.set_nonblocking(true).is_ok() {
+        connect(client_manager_tx, stream, "telnet", forwarded);
+    }
+}
+
+// Whether the caller's first bytes look like a header. They are waited for
+// up to SPOOF_TIMEOUT; a header split over packets that slow, or sent after
+// the caller has been waiting for a banner, isn't caught.
+fn starts_with_header(stream: &TcpStream) -> bool {
+    let deadline = Instant::now() + SPOOF_TIMEOUT;
+    let mut start = [0u8; 12];
+    let found = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            break false;
+        }
+        let peeked = match stream.peek(&mut start) {
+            Ok(0) | Err(_) => break false,
+            Ok(peeked) => peeked,
+        };
+        let start = &start[..peeked];
+        let prefix = peeked.min(6);
+        if start[..prefix] != b"PROXY "[..prefix] && !V2_SIGNATURE.starts_with(start) {
+            break false;
+        }
+        if peeked >= 6 {
+            break true;
+        }
+        // The rest of the start is still coming.
+        sleep(Duration::from_millis(10));
+    };
+    let _ = stream.set_read_timeout(None);
+    found
+}
+
+// The caller's address from the header; None when the proxy says it's
+// connecting on its own behalf (v1 UNKNOWN, v2 LOCAL).
+fn read_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    stream.set_read_timeout(Some(HEADER_TIMEOUT))?;
+    parse_header(stream)
+}
+
+fn parse_header(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start[..6])?;
+    if &start[..6] == b"PROXY " {
+        return read_v1(stream);
+    }
+    stream.read_exact(&mut start[6..])?;
+    if start == V2_SIGNATURE {
+        return read_v2(stream);
+    }
+    Err(invalid("no PROXY header"))
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 23\r\n", after "PROXY ".
+fn read_v1(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 6 >= MAX_V1_HEADER {
+            return Err(invalid("header too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line[..line.len() - 2]).into_owned();
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("unrecognised header")),
+    }
+}
+
+// The binary header, after its signature.
+fn read_v2(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    const LOCAL: u8 = 0x20;
+    const PROXY: u8 = 0x21;
+    const TCP4: u8 = 0x11;
+    const TCP6: u8 = 0x21;
+    let mut fixed = [0u8; 4];
+    stream.read_exact(&mut fixed)?;
+    let mut rest = vec![0u8; u16::from_be_bytes([fixed[2], fixed[3]]) as usize];
+    stream.read_exact(&mut rest)?;
+    match (fixed[0], fixed[1]) {
+        (LOCAL, _) => Ok(None),
+        (PROXY, TCP4) if rest.len() >= 12 => {
+            let ip = Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([rest[8], rest[9]]))))
+        }
+        (PROXY, TCP6) if rest.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&rest[..16]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), u16::from_be_bytes([rest[32], rest[33]]))))
+        }
+        (PROXY, TCP4 | TCP6) => Err(invalid("addresses cut short")),
+        // Other families (UDP, unix sockets) say nothing useful about a telnet caller.
+        (PROXY, _) => Ok(None),
+        _ => Err(invalid("unsupported version or command")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        parse_header(&mut &header[..])
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn reads_v1_headers() {
+        let source = parsed(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 23\r\n").unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        let source = parsed(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 23\r\n").unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(parsed(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert_eq!(parsed(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn reads_v2_headers() {
+        let tcp4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 23];
+        assert_eq!(parsed(&v2(0x21, 0x11, &tcp4)).unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        let mut tcp6 = [0u8; 36];
+        tcp6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        tcp6[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        assert_eq!(parsed(&v2(0x21, 0x21, &tcp6)).unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        // LOCAL, from the proxy's health checks, with or without addresses.
+        assert_eq!(parsed(&v2(0x20, 0x00, &[])).unwrap(), None);
+        assert_eq!(parsed(&v2(0x20, 0x11, &tcp4)).unwrap(), None);
+        // A UDP source says nothing about a telnet caller.
+        assert_eq!(parsed(&v2(0x21, 0x12, &tcp4)).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        for header in [&b"PROXY TCP4 192.0.2.1"[..], b"PROXY", b"\r\n\r\n\0\r\nQU"] {
+            assert_eq!(parsed(header).unwrap_err().kind(), ErrorKind::UnexpectedEof, "{:?}", header);
+        }
+        let mut short = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 23]);
+        short.truncate(short.len() - 1);
+        assert_eq!(parsed(&short).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        // The length says 12 bytes of addresses, but a TCP6 header needs 36.
+        assert_eq!(parsed(&v2(0x21, 0x21, &[0; 12])).unwrap_err().to_string(), "addresses cut short");
+    }
+
+    #[test]
+    fn rejects_oversized_and_malformed_headers() {
+        let mut oversized = b"PROXY TCP4 ".to_vec();
+        oversized.extend_from_slice(&[b'1'; MAX_V1_HEADER]);
+        oversized.extend_from_slice(b"\r\n");
+        assert_eq!(parsed(&oversized).unwrap_err().to_string(), "header too long");
+        assert_eq!(parsed(b"PROXY TCP4 nowhere 198.51.100.1 1 23\r\n").unwrap_err().to_string(), "bad source address");
+        assert_eq!(parsed(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 23\r\n").unwrap_err().to_string(), "bad source port");
+        assert_eq!(parsed(b"PROXY TCP4 192.0.2.1\r\n").unwrap_err().to_string(), "unrecognised header");
+        assert_eq!(parsed(&v2(0x11, 0x11, &[])).unwrap_err().to_string(), "unsupported version or command");
+        assert_eq!(parsed(b"GET / HTTP/1.1\r\n").unwrap_err().to_string(), "no PROXY header");
+    }
+}
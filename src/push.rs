@@ -0,0 +1,183 @@
+// The /metrics figures sent out on a timer, for a server Prometheus can't
+// reach to scrape, such as one on a home connection behind NAT. They go to a
+// Pushgateway in the text format, grouped by job and instance, or by remote
+// write: each sample as its own series in a protobuf WriteRequest, compressed
+// with Snappy. Both encodings are written out here. The Snappy is literals
+// only, which any reader takes but which is no smaller than the input.
+
+use std::thread;
+use std::thread::sleep;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{MetricsPushConfig, PushFormat};
+use crate::http;
+use crate::log;
+use crate::metrics;
+use crate::session::host_name;
+use crate::version;
+use crate::ServerContext;
+
+// Name and value pairs, as remote write sends them.
+type Labels = Vec<(String, String)>;
+
+pub fn launch_metrics_push(config: &MetricsPushConfig, context: ServerContext) {
+    let config = config.clone();
+    let instance = config.instance.clone().or_else(|| context.config.server.node_name.clone()).unwrap_or_else(host_name);
+    let _ = thread::spawn(move || {
+        // Only the first failure in a row is logged, and the recovery.
+        let mut failing = false;
+        loop {
+            match push(&config, &instance, &metrics::render(&context)) {
+                Ok(()) if failing => {
+                    log!(Server, Info, "Pushing metrics to {} again", config.url);
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(error) if !failing => {
+                    log!(Server, Warn, "Couldn't push metrics to {}: {}; trying again every {}s", config.url, error,
+                         config.interval.as_secs());
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+            sleep(config.interval);
+        }
+    });
+}
+
+fn push(config: &MetricsPushConfig, instance: &str, text: &str) -> Result<(), String> {
+    let mut headers = vec![("User-Agent", format!("TriServer/{}", version::VERSION))];
+    if let Some(token) = &config.bearer_token {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+    let mut url = config.url.clone();
+    let body = match config.format {
+        PushFormat::Pushgateway => {
+            headers.push(("Content-Type", String::from(metrics::CONTENT_TYPE)));
+            url.path = format!("{}/metrics/job/{}/instance/{}", url.path.trim_end_matches('/'), path_segment(&config.job),
+                               path_segment(instance));
+            text.as_bytes().to_vec()
+        }
+        PushFormat::RemoteWrite => {
+            headers.push(("Content-Type", String::from("application/x-protobuf")));
+            headers.push(("Content-Encoding", String::from("snappy")));
+            headers.push(("X-Prometheus-Remote-Write-Version", String::from("0.1.0")));
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+            snappy(&write_request(text, &config.job, instance, timestamp))
+        }
+    };
+    let response = http::post(&url, &headers, &body, config.timeout)?;
+    if response.is_success() {
+        Ok(())
+    } else {
+        Err(format!("answered {}", response.status))
+    }
+}
+
+// A job or instance label in a Pushgateway URL.
+fn path_segment(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(byte).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+// Every sample in the text format, labelled with the job and instance and
+// stamped with the time (Unix milliseconds).
+fn write_request(text: &str, job: &str, instance: &str, timestamp: u64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for line in text.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let Some((name, mut labels, value)) = parse_sample(line) else {
+            continue;
+        };
+        labels.push((String::from("__name__"), name));
+        labels.push((String::from("job"), job.to_string()));
+        labels.push((String::from("instance"), instance.to_string()));
+        // Remote write wants them sorted, and an empty one is no label at all.
+        labels.retain(|(_, value)| !value.is_empty());
+        labels.sort();
+
+        let mut series = Vec::new();
+        for (name, value) in &labels {
+            let mut label = Vec::new();
+            length_delimited(&mut label, 1, name.as_bytes());
+            length_delimited(&mut label, 2, value.as_bytes());
+            length_delimited(&mut series, 1, &label);
+        }
+        let mut sample = vec![1 << 3 | 1];
+        sample.extend_from_slice(&value.to_le_bytes());
+        sample.push(2 << 3);
+        varint(&mut sample, timestamp);
+        length_delimited(&mut series, 2, &sample);
+        length_delimited(&mut request, 1, &series);
+    }
+    request
+}
+
+// `name{label="value",...} 1.5`, as metrics::render writes them.
+fn parse_sample(line: &str) -> Option<(String, Labels, f64)> {
+    let name_end = line.find(['{', ' '])?;
+    let name = line[..name_end].to_string();
+    let mut labels = Vec::new();
+    let mut rest = &line[name_end..];
+    if let Some(mut inside) = rest.strip_prefix('{') {
+        loop {
+            if let Some(after) = inside.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (label, after) = inside.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        other => value.push(other),
+                    },
+                    (_, other) => value.push(other),
+                }
+            };
+            labels.push((label.to_string(), value));
+            inside = after[end + 1..].strip_prefix(',').unwrap_or(&after[end + 1..]);
+        }
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some((name, labels, value))
+}
+
+fn length_delimited(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    out.push(field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// The Snappy block format: the length, then the data as literals of up to
+// 64 KiB, each tagged with its length less one.
+fn snappy(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    varint(&mut out, data.len() as u64);
+    for chunk in data.chunks(65536) {
+        let length = chunk.len() - 1;
+        if length < 60 {
+            out.push((length as u8) << 2);
+        } else if length < 256 {
+            out.push(60 << 2);
+            out.push(length as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(length as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
@@ -0,0 +1,66 @@
+// Questions for the client manager, sent over its channel like everything
+// else it hears about and answered on a reply channel made for each one, so
+// the admin interface sees sessions as the manager does, in order with the
+// connects and closes around them.
+
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Sender};
+use uuid::Uuid;
+
+use crate::{ClientConnection, ClientManagerMessage};
+
+// How long an answer is waited for before the manager is taken to be stuck.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ManagerStats {
+    pub sessions: usize,
+    // Callers since startup, and those turned away before a session started.
+    pub accepted: u64,
+    pub refused: u64,
+    // Callers turned away for the manager being too far behind, and the
+    // messages it has yet to get to.
+    pub turned_away: u64,
+    pub queued: usize,
+}
+
+#[derive(Clone)]
+pub struct ManagerHandle {
+    sender: Sender<ClientManagerMessage>,
+}
+
+impl ManagerHandle {
+    pub fn new(sender: Sender<ClientManagerMessage>) -> Self {
+        Self { sender }
+    }
+
+    pub fn list_clients(&self) -> Result<Vec<ClientConnection>, String> {
+        self.ask(|reply| ClientManagerMessage::ListClients { reply })
+    }
+
+    pub fn stats(&self) -> Result<ManagerStats, String> {
+        self.ask(|reply| ClientManagerMessage::GetStats { reply })
+    }
+
+    // The session told to hang up, if there was one.
+    pub fn disconnect(&self, client_id: Uuid, reason: &str) -> Result<Option<ClientConnection>, String> {
+        self.ask(|reply| ClientManagerMessage::Disconnect { client_id, reason: reason.to_string(), reply })
+    }
+
+    // Shows every session the message; returns how many there were.
+    pub fn broadcast(&self, message: &str) -> Result<usize, String> {
+        self.ask(|reply| ClientManagerMessage::Broadcast { message: message.to_string(), reply })
+    }
+
+    // Messages waiting for the manager.
+    pub fn queued(&self) -> usize {
+        self.sender.len()
+    }
+
+    fn ask<T>(&self, question: impl FnOnce(Sender<T>) -> ClientManagerMessage) -> Result<T, String> {
+        let (reply, answer) = bounded(1);
+        self.sender.send(question(reply)).map_err(|_| String::from("the client manager has stopped"))?;
+        answer.recv_timeout(REPLY_TIMEOUT).map_err(|_| String::from("the client manager did not answer"))
+    }
+}
@@ -0,0 +1,71 @@
+// Session threads running now, counted apart from the client map so the two
+// can be checked against each other. Every session has both, a thread and a
+// map entry, from just after it's accepted until it has closed; a map entry
+// with no thread left is a session that died without saying so, and a thread
+// with no entry is one the manager has lost track of. Either shows up as the
+// two counts drifting apart, which is checked every little while. They can
+// differ for a moment as a session starts and ends, so only a difference
+// that lasts from one check to the next is warned about.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::log;
+use crate::ServerContext;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Default)]
+pub struct RelayThreads {
+    running: Arc<AtomicUsize>,
+}
+
+impl RelayThreads {
+    // Counts the calling thread in until the guard is dropped, however the thread ends.
+    pub fn enter(&self) -> RelayGuard {
+        self.running.fetch_add(1, Ordering::Relaxed);
+        RelayGuard { running: self.running.clone() }
+    }
+
+    pub fn count(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+}
+
+pub struct RelayGuard {
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for RelayGuard {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn launch_watch(context: ServerContext) {
+    let _ = thread::spawn(move || {
+        // The difference at the last check, and the one last warned about.
+        let mut last = 0;
+        let mut warned = 0;
+        loop {
+            let (sessions, threads) = (context.clients.len() as isize, context.relays.count() as isize);
+            let difference = sessions - threads;
+            if difference != 0 && difference == last && difference != warned {
+                match difference {
+                    missing if missing > 0 => log!(Manager, Warn, "{} session(s) in the client map have no relay thread ({} sessions, {} threads)",
+                                                   missing, sessions, threads),
+                    extra => log!(Manager, Warn, "{} relay thread(s) have no session in the client map ({} sessions, {} threads)",
+                                  -extra, sessions, threads),
+                }
+                warned = difference;
+            } else if difference == 0 {
+                warned = 0;
+            }
+            last = difference;
+            sleep(CHECK_INTERVAL);
+        }
+    });
+}
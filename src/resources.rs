@@ -0,0 +1,78 @@
+// The server's own threads, memory and file descriptors, for /metrics and the
+// admin status. Every session takes a thread and a few descriptors, so
+// running out of descriptors is how a busy server falls over; they're
+// checked every little while and a warning is logged well before the limit.
+// Only Linux has these figures to give.
+
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::log;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Warned about at this share of the limit, and again only once back under
+// the lower one.
+const FD_WARNING: f64 = 0.8;
+const FD_REARM: f64 = 0.7;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Usage {
+    pub threads: u64,
+    pub resident_bytes: u64,
+    pub open_fds: u64,
+    // The soft limit, if there is one.
+    pub max_fds: Option<u64>,
+}
+
+impl Usage {
+    // As the admin status shows it.
+    pub fn describe(&self) -> String {
+        let fds = match self.max_fds {
+            Some(max) => format!("{} of {}", self.open_fds, max),
+            None => self.open_fds.to_string(),
+        };
+        format!("{} threads, {:.1} MB resident, {} file descriptors", self.threads, self.resident_bytes as f64 / 1_048_576.0, fds)
+    }
+}
+
+pub fn launch_monitor() {
+    let _ = thread::spawn(|| {
+        let mut warned = false;
+        loop {
+            if let Some((usage, max)) = current().and_then(|usage| usage.max_fds.map(|max| (usage, max))) {
+                let share = usage.open_fds as f64 / max as f64;
+                if !warned && share >= FD_WARNING {
+                    log!(Server, Warn, "{} of {} file descriptors in use; raise the limit (ulimit -n, or LimitNOFILE= under systemd) \
+                                        before callers are turned away", usage.open_fds, max);
+                    warned = true;
+                } else if warned && share < FD_REARM {
+                    warned = false;
+                }
+            }
+            sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+pub fn current() -> Option<Usage> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let field = |name: &str| status.lines()
+        .find_map(|line| line.strip_prefix(name))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|value| value.parse::<u64>().ok());
+    let open_fds = std::fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+    // "Max open files            1024                 524288               files"
+    let limits = std::fs::read_to_string("/proc/self/limits").unwrap_or_default();
+    let max_fds = limits.lines()
+        .find_map(|line| line.strip_prefix("Max open files"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|soft| soft.parse().ok());
+    Some(Usage { threads: field("Threads:")?, resident_bytes: field("VmRSS:")? * 1024, open_fds, max_fds })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current() -> Option<Usage> {
+    None
+}
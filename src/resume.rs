@@ -0,0 +1,96 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::Sender;
+use uuid::Uuid;
+
+use crate::downstream::Downstream;
+
+// No 0/O or 1/I so codes can be read back off a screen.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub struct Reattach {
+    pub stream: Downstream,
+    pub ip_addr: IpAddr,
+}
+
+// Sessions whose client dropped and whose backend is being held open for the
+// grace period, keyed by resume code.
+#[derive(Clone, Default)]
+pub struct HeldSessions {
+    inner: Arc<Mutex<HashMap<String, Sender<Reattach>>>>,
+}
+
+impl HeldSessions {
+    pub fn hold(&self, code: &str, sender: Sender<Reattach>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.insert(code.to_string(), sender);
+    }
+
+    // Takes the session out of the list, whether it was still there: it isn't
+    // once a caller has taken it to reattach, and then that caller has it.
+    pub fn release(&self, code: &str) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        lock.remove(code).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    // Hands the stream to the held session. The stream is given back if the
+    // code is unknown or the session expired in the meantime.
+    pub fn reattach(&self, code: &str, reattach: Reattach) -> Result<(), Reattach> {
+        let sender = {
+            let mut lock = self.inner.lock().unwrap();
+            lock.remove(&normalize(code))
+        };
+        match sender {
+            Some(sender) => sender.send(reattach).map_err(|error| error.into_inner()),
+            None => Err(reattach),
+        }
+    }
+}
+
+pub fn generate_code() -> String {
+    let bytes = Uuid::new_v4().into_bytes();
+    let code: String = bytes[..8].iter().map(|b| CODE_ALPHABET[(*b as usize) % CODE_ALPHABET.len()] as char).collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+fn normalize(code: &str) -> String {
+    let code: String = code.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_uppercase()).collect();
+    if code.len() == 8 {
+        format!("{}-{}", &code[..4], &code[4..])
+    } else {
+        code
+    }
+}
+
+// The most recent backend output of a session, replayed to a client that
+// resumes it so they see their screen again.
+pub struct ReplayBuffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self { data: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + bytes.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.data.extend(bytes);
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.iter().copied().collect()
+    }
+}
@@ -0,0 +1,186 @@
+// RIPscrip, the vector graphics some BBSes send to terminals that can draw
+// them, picked out of a backend's output so it can reach those terminals as
+// it was sent and be kept from the rest. A RIPscrip line starts with "!|" at
+// the start of a line and runs to its end, a backslash at the end carrying it
+// onto the next; the ESC [ ! query and ESC [ 1 ! and ESC [ 2 ! switches that
+// go with it count too.
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum State {
+    #[default]
+    LineStart,
+    Text,
+    // "!" at the start of a line, which may be RIPscrip.
+    Bang,
+    Rip,
+    // A backslash inside RIPscrip, escaping what follows.
+    RipEscaped,
+    // The CR ending a line of RIPscrip, whose LF belongs to it too.
+    RipEnd,
+    // ESC, ESC [ and any digits after, which may be a RIPscrip switch.
+    Escape,
+}
+
+#[derive(Default)]
+pub struct RipScanner {
+    state: State,
+    // What may yet turn out to be RIPscrip.
+    held: Vec<u8>,
+}
+
+impl RipScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The output in runs of RIPscrip (true) and everything else (false). A
+    // possible start of RIPscrip at the end is held back for the next read.
+    pub fn scan(&mut self, input: &[u8]) -> Vec<(bool, Vec<u8>)> {
+        let mut runs: Vec<(bool, Vec<u8>)> = Vec::new();
+        let mut push = |rip: bool, bytes: &[u8]| match runs.last_mut() {
+            Some((last, run)) if *last == rip => run.extend_from_slice(bytes),
+            _ if bytes.is_empty() => {}
+            _ => runs.push((rip, bytes.to_vec())),
+        };
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Rip, b'\\') => {
+                    push(true, &[byte]);
+                    State::RipEscaped
+                }
+                (State::Rip, b'\r') => {
+                    push(true, &[byte]);
+                    State::RipEnd
+                }
+                (State::Rip | State::RipEnd, b'\n') => {
+                    push(true, &[byte]);
+                    State::LineStart
+                }
+                // The CR of an escaped CR LF, which carries on to the LF.
+                (State::RipEscaped, b'\r') => {
+                    push(true, &[byte]);
+                    State::RipEscaped
+                }
+                (State::Rip | State::RipEscaped, _) => {
+                    push(true, &[byte]);
+                    State::Rip
+                }
+                (State::Bang, b'|') => {
+                    push(true, &std::mem::take(&mut self.held));
+                    push(true, &[byte]);
+                    State::Rip
+                }
+                (State::Escape, b'[') if self.held == [0x1b] => {
+                    self.held.push(byte);
+                    State::Escape
+                }
+                (State::Escape, b'0'..=b'9') if self.held.len() >= 2 => {
+                    self.held.push(byte);
+                    State::Escape
+                }
+                (State::Escape, b'!') if self.held.len() >= 2 => {
+                    push(true, &std::mem::take(&mut self.held));
+                    push(true, &[byte]);
+                    State::Text
+                }
+                (State::Bang | State::Escape, _) => {
+                    // Not RIPscrip after all.
+                    push(false, &std::mem::take(&mut self.held));
+                    self.state = State::Text;
+                    self.text(byte, &mut push)
+                }
+                (State::RipEnd, _) => {
+                    self.state = State::LineStart;
+                    self.text(byte, &mut push)
+                }
+                (State::LineStart | State::Text, _) => self.text(byte, &mut push),
+            };
+        }
+        runs
+    }
+
+    // What's held back, given up on as text at the end of a prompt.
+    pub fn flush(&mut self) -> Vec<u8> {
+        if !self.held.is_empty() {
+            self.state = State::Text;
+        }
+        std::mem::take(&mut self.held)
+    }
+
+    fn text(&mut self, byte: u8, push: &mut impl FnMut(bool, &[u8])) -> State {
+        match (self.state, byte) {
+            (_, 0x1b) => {
+                self.held.push(byte);
+                State::Escape
+            }
+            (State::LineStart, b'!') => {
+                self.held.push(byte);
+                State::Bang
+            }
+            (_, b'\r' | b'\n') => {
+                push(false, &[byte]);
+                State::LineStart
+            }
+            // The NUL of a telnet CR NUL.
+            (State::LineStart, 0) => {
+                push(false, &[byte]);
+                State::LineStart
+            }
+            _ => {
+                push(false, &[byte]);
+                State::Text
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(scanner: &mut RipScanner, input: &[u8]) -> Vec<(bool, String)> {
+        scanner.scan(input).into_iter().map(|(rip, bytes)| (rip, String::from_utf8_lossy(&bytes).into_owned())).collect()
+    }
+
+    fn runs(expected: &[(bool, &str)]) -> Vec<(bool, String)> {
+        expected.iter().map(|&(rip, text)| (rip, text.to_string())).collect()
+    }
+
+    #[test]
+    fn picks_out_ripscrip_lines() {
+        let mut scanner = RipScanner::new();
+        assert_eq!(scan(&mut scanner, b"hello\r\n!|L00001212\r\nafter"),
+                   runs(&[(false, "hello\r\n"), (true, "!|L00001212\r\n"), (false, "after")]));
+    }
+
+    #[test]
+    fn leaves_bangs_that_are_not_ripscrip() {
+        let mut scanner = RipScanner::new();
+        assert_eq!(scan(&mut scanner, b"!hi a!|b\r\n!\r\n"), runs(&[(false, "!hi a!|b\r\n!\r\n")]));
+    }
+
+    #[test]
+    fn carries_an_escaped_line_end_onto_the_next() {
+        let mut scanner = RipScanner::new();
+        assert_eq!(scan(&mut scanner, b"!|L0\\\r\nmore\r\nx"), runs(&[(true, "!|L0\\\r\nmore\r\n"), (false, "x")]));
+    }
+
+    #[test]
+    fn picks_out_the_ripscrip_escapes() {
+        let mut scanner = RipScanner::new();
+        assert_eq!(scan(&mut scanner, b"a\x1b[1!b\x1b[0m\x1b[!"),
+                   runs(&[(false, "a"), (true, "\x1b[1!"), (false, "b\x1b[0m"), (true, "\x1b[!")]));
+    }
+
+    #[test]
+    fn holds_back_a_possible_start_for_the_next_read() {
+        let mut scanner = RipScanner::new();
+        assert_eq!(scan(&mut scanner, b"x\r\n!"), runs(&[(false, "x\r\n")]));
+        assert_eq!(scan(&mut scanner, b"|1K\r\n\x1b[2"), runs(&[(true, "!|1K\r\n")]));
+        assert_eq!(scan(&mut scanner, b"!"), runs(&[(true, "\x1b[2!")]));
+        // At the end of a prompt it's given up on as text.
+        assert_eq!(scan(&mut scanner, b"\r\n!"), runs(&[(false, "\r\n")]));
+        assert_eq!(scanner.flush(), b"!");
+        assert_eq!(scan(&mut scanner, b"|x"), runs(&[(false, "|x")]));
+    }
+}
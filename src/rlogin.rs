@@ -0,0 +1,43 @@
+// The client side of rlogin (RFC 1282). Once the server has accepted the
+// opening handshake the connection carries the session's bytes as they are.
+// The server's out-of-band control bytes, such as window size requests, are
+// left unanswered, as BBSes taking rlogin door connections don't rely on them.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::chat;
+use crate::config::RloginConfig;
+
+// How long the server gets to accept or refuse the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// The most of a refusal message that is read.
+const MAX_REFUSAL: usize = 256;
+
+// Sends the user names and terminal, then waits for the server's answer: a
+// single zero byte, or a message saying why it refused.
+pub fn handshake(stream: &mut TcpStream, rlogin: &RloginConfig, user: &str) -> io::Result<()> {
+    let values = [("user", user.to_string())];
+    let mut opening = vec![0];
+    for field in [&rlogin.local_user, &rlogin.remote_user, &rlogin.terminal] {
+        opening.extend_from_slice(chat::render(field, &values).as_bytes());
+        opening.push(0);
+    }
+    stream.write_all(&opening)?;
+
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut answer = [0u8; 1];
+    stream.read_exact(&mut answer)?;
+    if answer[0] != 0 {
+        // The refusal runs to the end of the line, or until the server hangs up.
+        let mut refusal = Vec::new();
+        let mut byte = [0u8; 1];
+        while refusal.len() < MAX_REFUSAL && matches!(stream.read(&mut byte), Ok(1)) && byte[0] != b'\n' {
+            refusal.push(byte[0]);
+        }
+        let refusal = String::from_utf8_lossy(&refusal).trim().to_string();
+        return Err(io::Error::new(ErrorKind::PermissionDenied, format!("rlogin refused: {}", refusal)));
+    }
+    stream.set_read_timeout(None)
+}
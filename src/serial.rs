@@ -0,0 +1,155 @@
+// Serial port backends: the device is opened and set up for each caller, and
+// its bytes are relayed as they are, with no telnet layer. Only one caller at
+// a time can have a port; the next is told it is busy.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serialport::{ClearBuffer, DataBits, StopBits};
+
+use crate::config::{FlowControl, Parity, SerialConfig};
+
+// Devices open in this process. The port's exclusive mode stops anyone else.
+static IN_USE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+pub struct SerialPort {
+    // None once closed, after which reads see the device as gone.
+    port: Option<Box<dyn serialport::SerialPort>>,
+    device: PathBuf,
+}
+
+impl SerialPort {
+    pub fn open(config: &SerialConfig) -> io::Result<Self> {
+        {
+            let mut in_use = IN_USE.lock().unwrap();
+            if in_use.contains(&config.device) {
+                return Err(io::Error::new(ErrorKind::ResourceBusy, "serial port in use by another caller"));
+            }
+            in_use.push(config.device.clone());
+        }
+        match open(config) {
+            Ok(port) => Ok(Self { port: Some(port), device: config.device.clone() }),
+            Err(error) => {
+                release(&config.device);
+                Err(error.into())
+            }
+        }
+    }
+
+    // Lets the device go, so it can be opened again.
+    pub fn close(&mut self) {
+        if self.port.take().is_some() {
+            release(&self.device);
+        }
+    }
+}
+
+impl Drop for SerialPort {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn release(device: &Path) {
+    IN_USE.lock().unwrap().retain(|open| open != device);
+}
+
+// Opens the device with the configured framing. With no timeout, a read with
+// nothing waiting or a write the device can't take yet fails at once, as the
+// relay expects of a backend.
+fn open(config: &SerialConfig) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+    let data_bits = match config.data_bits {
+        5 => DataBits::Five,
+        6 => DataBits::Six,
+        7 => DataBits::Seven,
+        _ => DataBits::Eight,
+    };
+    let parity = match config.parity {
+        Parity::None => serialport::Parity::None,
+        Parity::Even => serialport::Parity::Even,
+        Parity::Odd => serialport::Parity::Odd,
+    };
+    let flow_control = match config.flow_control {
+        FlowControl::None => serialport::FlowControl::None,
+        FlowControl::Hardware => serialport::FlowControl::Hardware,
+        FlowControl::Software => serialport::FlowControl::Software,
+    };
+    let port = serialport::new(config.device.to_string_lossy(), config.baud)
+        .data_bits(data_bits)
+        .parity(parity)
+        .stop_bits(if config.stop_bits == 2 { StopBits::Two } else { StopBits::One })
+        .flow_control(flow_control)
+        .timeout(Duration::ZERO)
+        .open()?;
+    // Whatever the device sent before the caller arrived isn't theirs.
+    port.clear(ClearBuffer::All)?;
+    Ok(port)
+}
+
+// The port's timeout is how it says it isn't ready.
+fn not_ready(error: io::Error) -> io::Error {
+    match error.kind() {
+        ErrorKind::TimedOut => io::Error::from(ErrorKind::WouldBlock),
+        _ => error,
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.port.as_mut() {
+            Some(port) => port.read(buffer).map_err(not_ready),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.port.as_mut() {
+            Some(port) => port.write(data).map_err(not_ready),
+            None => Err(io::Error::from(ErrorKind::NotConnected)),
+        }
+    }
+
+    // What was written is with the device already; waiting for it to go out
+    // on the line would hold up the relay.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A pseudo-terminal stands in for the device.
+#[cfg(all(test, unix))]
+mod tests {
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    #[test]
+    fn relays_both_ways_and_takes_one_caller_at_a_time() {
+        let (mut controller, mut device) = (0, 0);
+        let mut name = [0 as libc::c_char; 64];
+        assert_eq!(unsafe { libc::openpty(&mut controller, &mut device, name.as_mut_ptr(), std::ptr::null(), std::ptr::null()) }, 0);
+        let mut controller = unsafe { File::from_raw_fd(controller) };
+        let device_path = PathBuf::from(unsafe { CStr::from_ptr(name.as_ptr()) }.to_str().unwrap());
+        let config = SerialConfig { device: device_path, baud: 9600, data_bits: 8, parity: Parity::None, stop_bits: 1, flow_control: FlowControl::None };
+        let mut port = SerialPort::open(&config).unwrap();
+        assert_eq!(SerialPort::open(&config).err().map(|error| error.kind()), Some(ErrorKind::ResourceBusy));
+        let mut buffer = [0; 16];
+        assert_eq!(port.read(&mut buffer).err().map(|error| error.kind()), Some(ErrorKind::WouldBlock));
+        controller.write_all(b"READY.").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let read = port.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"READY.");
+        port.write_all(b"LOAD").unwrap();
+        let read = controller.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..read], b"LOAD");
+        port.close();
+        unsafe { libc::close(device) };
+        SerialPort::open(&config).unwrap();
+    }
+}
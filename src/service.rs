@@ -0,0 +1,173 @@
+// Running as a Windows service: registration with the service control
+// manager, the dispatcher entry point, stop handling and Event Log messages.
+
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::os::windows::io::IntoRawHandle;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, select};
+use windows_service::service::{ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+                               ServiceStartType, ServiceState, ServiceStatus, ServiceType};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::Console::{SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE};
+use windows_sys::Win32::System::EventLog::{DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+                                           EVENTLOG_INFORMATION_TYPE, REPORT_EVENT_TYPE};
+
+const SERVICE_NAME: &str = "TriServer";
+const DISPLAY_NAME: &str = "TriServer telnet proxy";
+
+// How long the service control manager is told a stop may take.
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static SERVE: Mutex<Option<Box<dyn FnOnce() + Send>>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(Some(0)).collect()
+}
+
+// The crate's own messages leave out what Windows said.
+fn describe(error: windows_service::Error) -> String {
+    match error {
+        windows_service::Error::Winapi(error) => error.to_string(),
+        error => error.to_string(),
+    }
+}
+
+// Registers the service to start automatically, running this executable with
+// the given config file.
+pub fn install(config_path: &Path) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+        .map_err(|error| format!("unable to open the service control manager: {}", describe(error)))?;
+    let service = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(DISPLAY_NAME),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(|error| error.to_string())?,
+        launch_arguments: vec![OsString::from("--config"), config_path.as_os_str().to_owned(), OsString::from("--service")],
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    manager.create_service(&service, ServiceAccess::empty())
+        .map(|_| ())
+        .map_err(|error| format!("unable to create the service: {}", describe(error)))
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|error| format!("unable to open the service control manager: {}", describe(error)))?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)
+        .map_err(|error| format!("unable to open the service: {}", describe(error)))?;
+    service.delete().map_err(|error| format!("unable to delete the service: {}", describe(error)))
+}
+
+// Services have no console, so output goes to the log file instead.
+pub fn redirect_output(log_file: &Path) -> std::io::Result<()> {
+    let log = OpenOptions::new().create(true).append(true).open(log_file)?;
+    let handle = log.into_raw_handle();
+    unsafe {
+        SetStdHandle(STD_OUTPUT_HANDLE, handle as HANDLE);
+        SetStdHandle(STD_ERROR_HANDLE, handle as HANDLE);
+    }
+    Ok(())
+}
+
+// Hands the process to the service control manager. `serve` runs on the
+// service thread and should return once stop_requested() turns true.
+pub fn run(serve: impl FnOnce() + Send + 'static) -> Result<(), String> {
+    *SERVE.lock().unwrap() = Some(Box::new(serve));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|error| {
+        format!("unable to start the service dispatcher (was this started by the service control manager?): {}", describe(error))
+    })
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let (stop, stopping) = bounded(1);
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            STOP_REQUESTED.store(true, Ordering::SeqCst);
+            let _ = stop.try_send(());
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(status) => status,
+        Err(error) => {
+            report_event(EVENTLOG_ERROR_TYPE, &format!("Unable to register the service control handler: {}", describe(error)));
+            return;
+        }
+    };
+    set_status(status, ServiceState::StartPending, Duration::ZERO);
+
+    // Serving goes on its own thread so that this one, which holds the status
+    // handle, can say the service is stopping as soon as it's asked to.
+    let serve = SERVE.lock().unwrap().take();
+    let (done, served) = bounded(1);
+    thread::spawn(move || {
+        if let Some(serve) = serve {
+            serve();
+        }
+        let _ = done.send(());
+    });
+    set_status(status, ServiceState::Running, Duration::ZERO);
+    report_event(EVENTLOG_INFORMATION_TYPE, &format!("{} started", crate::version::describe()));
+    select! {
+        recv(stopping) -> _ => {
+            set_status(status, ServiceState::StopPending, STOP_WAIT_HINT);
+            let _ = served.recv();
+        }
+        recv(served) -> _ => {}
+    }
+    report_event(EVENTLOG_INFORMATION_TYPE, "TriServer stopped");
+    set_status(status, ServiceState::Stopped, Duration::ZERO);
+}
+
+fn set_status(status: ServiceStatusHandle, state: ServiceState, wait_hint: Duration) {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    let _ = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    });
+}
+
+// Writes a message to the Application event log under the TriServer source.
+fn report_event(event_type: REPORT_EVENT_TYPE, message: &str) {
+    let message = wide(message);
+    let strings = [message.as_ptr()];
+    unsafe {
+        let log = RegisterEventSourceW(ptr::null(), wide(SERVICE_NAME).as_ptr());
+        if log == 0 {
+            return;
+        }
+        ReportEventW(log, event_type, 0, 0, ptr::null_mut(), 1, 0, strings.as_ptr(), ptr::null());
+        DeregisterEventSource(log);
+    }
+}
@@ -0,0 +1,1928 @@
+use std::cell::Cell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use telnet::{TelnetOption, Action};
+
+use crate::bans::Offense;
+use crate::chaos::{self, Chaos};
+use crate::chat;
+use crate::codec::{self, CommandTracker, Frame, Parser};
+use crate::downstream::Downstream;
+use crate::dropfile;
+use crate::config::{BackendConfig, ClientCharset, Config, ControlKey, LogLevel, MultisessionConfig, NegotiationConfig, OptionPolicy, OutputFilter, Subsystem,
+                    TerminalClass};
+use crate::events::{Event, EventBus};
+use crate::faults::Fault;
+use crate::honeypot;
+use crate::lifecycle::SessionState;
+use crate::local::LocalSocket;
+use crate::log;
+use crate::login::{self, Prompt, PromptError};
+use crate::metrics::Metrics;
+use crate::middleware::{CommandRate, Flow, Pipeline, SessionInfo};
+use crate::motd;
+use crate::panics;
+use crate::resume::{self, Reattach, ReplayBuffer};
+use crate::rlogin;
+use crate::serial::SerialPort;
+use crate::span::Span;
+use crate::sysop::ConsoleMessage;
+use crate::ssh::SshSession;
+use crate::upstream::Upstream;
+use crate::users::User;
+use crate::{Call, ClientConnection, ClientManagerMessage, Forwarded, ServerContext, SessionControl};
+
+const BYTES_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// How long a backend gets to finish its output once the caller stops sending.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// How long closing waits for the caller to take the last of the output.
+const HANG_UP_TIMEOUT: Duration = Duration::from_secs(2);
+const CARRIER_LOST: &[u8] = b"\r\nNO CARRIER\r\n";
+// Waits between attempts to re-dial a backend that dropped, doubling each time.
+const FIRST_REDIAL: Duration = Duration::from_secs(1);
+const MAX_REDIAL: Duration = Duration::from_secs(16);
+// Output held for a caller at the escape prompt without [multisession].
+const HELD_OUTPUT: usize = 64 * 1024;
+const ESCAPE_PROMPT: &[u8] = b"\r\ntriserver> ";
+// How long before server.idle_timeout a caller is warned, at most half the timeout.
+const IDLE_WARNING: Duration = Duration::from_secs(60);
+// How long a negotiation answer or logout waits on a backend's full window.
+const COMMAND_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn create_client_connection(client_id: uuid::Uuid, stream: TcpStream, listener: &'static str, forwarded: Option<Forwarded>,
+                                call: Call, client_manager_tx: Sender<ClientManagerMessage>, context: ServerContext) -> io::Result<ClientConnection> {
+    let (ip_addr, port, server_name) = match forwarded {
+        Some(forwarded) => (forwarded.ip_addr, forwarded.port, forwarded.server_name),
+        None => {
+            let peer = stream.peer_addr()?;
+            (peer.ip(), peer.port(), None)
+        }
+    };
+    let (control_tx, control_rx) = unbounded();
+    let client_connection = ClientConnection { client_id, listener, ip_addr, control: control_tx, node: call.node, connected_at: Instant::now(),
+                                               backend: None, username: None, terminal: None, terminal_class: None, negotiation: None,
+                                               label: None, state: SessionState::Accepted, state_since: Instant::now(), held: false };
+    let downstream = Downstream::new(stream.try_clone()?, context.config.server.write_timeout,
+                                     context.config.server.pending_output * 1024);
+    let _ = thread::spawn(
+        move || {
+            let _relay = context.relays.enter();
+            let reporter = Reporter { client_id, sender: client_manager_tx, state: Cell::new(SessionState::Accepted),
+                                     closed: Cell::new(false) };
+            let caller = Caller { client_id, listener, ip_addr, port, server_name, call };
+            let caught = panics::catch(|| serve(downstream, &caller, control_rx, &reporter, &context));
+            if let Err(report) = caught {
+                log!(Relay, Error, span = caller.span(), "Session thread panicked, hanging up: {}", report);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            reporter.close();
+        }
+    );
+    Ok(client_connection)
+}
+
+// Who a session is for, as they connected.
+struct Caller {
+    client_id: uuid::Uuid,
+    listener: &'static str,
+    ip_addr: IpAddr,
+    port: u16,
+    // The host name a [tls] caller asked for (SNI).
+    server_name: Option<String>,
+    call: Call,
+}
+
+impl Caller {
+    // Until there is a backend; the session's own span takes over from there.
+    fn span(&self) -> Span<'_> {
+        Span { client_id: self.client_id, listener: self.listener, ip_addr: self.ip_addr, backend: None }
+    }
+}
+
+// A stage before the relay ended the session, having told the caller why,
+// logged it and reported the session closed.
+struct Ended;
+
+// The session from the caller connecting until they are hung up on: the
+// stages that pick and dial their backend, then the relay.
+fn serve(mut stream: Downstream, caller: &Caller, control_rx: Receiver<SessionControl>, reporter: &Reporter,
+         context: &ServerContext) -> Result<(), Ended> {
+    let config = &context.config;
+    let (client_id, ip_addr, span) = (caller.client_id, caller.ip_addr, caller.span());
+    let mut prompt = Prompt::new();
+    reporter.state(SessionState::Validating);
+
+    if let Some(honeypot) = config.honeypot.as_ref().filter(|honeypot| honeypot.matches(ip_addr)) {
+        log!(Relay, Info, span = span, "routed to the honeypot");
+        honeypot::run(&mut stream, client_id, ip_addr, honeypot);
+        reporter.close();
+        log!(Relay, Info, span = span, "Honeypot session closed");
+        return Ok(());
+    }
+
+    if let Some(motd_file) = &config.server.motd_file {
+        let _ = motd::show(&mut stream, motd_file, &motd::values(caller.call, client_id, ip_addr, &context.clients));
+    }
+
+    let terminal = negotiate_terminal(&mut stream, config, reporter, span)?;
+    let mut stream = offer_resume(stream, &mut prompt, caller, reporter, context)?;
+    let user = log_in(&mut stream, &mut prompt, caller, reporter, context)?;
+    let backend = pick_backend(&mut stream, user.as_ref(), &terminal, caller, reporter, context)?;
+    let deadline = time_limit(&mut stream, user.as_ref(), span, reporter, context)?;
+    let time_warnings = deadline.as_ref()
+        .map(|deadline| TimeWarnings::new(&config.server.time_warnings, deadline.at.saturating_duration_since(Instant::now())));
+
+    let session = SessionInfo { client_id, listener: caller.listener, ip_addr, backend: backend.name.clone(), user, encoding: None,
+                                terminal_class: terminal.class, utf8: terminal.utf8, charset: terminal.charset, rip: terminal.rip,
+                                syncterm: terminal.syncterm };
+    let mut pipeline = context.middleware.start();
+    if let Flow::Disconnect(reason) = pipeline.on_connect(&session) {
+        let _ = stream.write_all(format!("{}\r\n", reason).as_bytes());
+        pipeline.on_close(&session);
+        reporter.close();
+        log!(Relay, Info, span = session.span(), "Refused: {}", reason);
+        return Err(Ended);
+    }
+
+    let dialer = Dialer {
+        config,
+        ip_addr,
+        port: caller.port,
+        node: config.server.node_name.clone().unwrap_or_else(host_name),
+        user_name: session.user.as_ref().map_or_else(String::new, |user| user.username.clone()),
+        answered_type: if terminal.syncterm { "syncterm" } else { "ansi-bbs" },
+    };
+    if let Some(dropfile) = &config.dropfile {
+        let name = session.user.as_ref().map_or("Guest", |user| user.username.as_str());
+        let caller = dropfile::Caller {
+            node: caller.call.node,
+            name,
+            ip_addr,
+            ansi: terminal.class != Some(TerminalClass::Plain) && terminal.charset.is_none(),
+            remaining: deadline.as_ref().map(|deadline| deadline.at.saturating_duration_since(Instant::now())),
+        };
+        match dropfile::write(dropfile, &dialer.node, &caller) {
+            Ok(path) => log!(Relay, Debug, span = session.span(), "wrote drop file {}", path.display()),
+            Err(error) => log!(Relay, Warn, span = session.span(), "Unable to write the drop file: {}", error),
+        }
+    }
+    reporter.state(SessionState::DialingBackend);
+    let line = match dialer.open(backend, traced(&session)) {
+        Ok(line) => line,
+        Err(error) => {
+            let _ = stream.write_all(format!("Unable to reach {} right now, please try again later.\r\n", backend.name).as_bytes());
+            reporter.fault(Fault::dialing(&error), Some(&backend.name), format!("Unable to connect to {}: {}", backend.name, error));
+            pipeline.on_close(&session);
+            reporter.close();
+            return Err(Ended);
+        }
+    };
+    log!(Relay, Info, span = session.span(), "connected to Telnet Server {}", backend.name);
+    context.events.publish(Event::Connected(session.clone()));
+    reporter.started(&session, terminal.terminal_type.as_deref());
+    reporter.state(SessionState::Negotiating);
+
+    let resume_code = resume::generate_code();
+    if let Some(resume) = &config.resume {
+        let _ = stream.write_all(format!("Your resume code is {}. If you are disconnected, reconnect within {} and enter it to pick up where you left off.\r\n",
+                                         resume_code, describe_seconds(resume.grace_period)).as_bytes());
+    }
+    let (reattach_tx, reattach_rx) = unbounded();
+    let mut relay = Relay {
+        context,
+        config,
+        reporter,
+        control_rx,
+        call: caller.call,
+        session,
+        terminal_type: terminal.terminal_type,
+        pipeline,
+        dialer,
+        lines: vec![line],
+        active: 0,
+        switch_to: None,
+        client: Some(stream),
+        started: Instant::now(),
+        held_until: None,
+        draining_until: None,
+        reading_after: None,
+        relayed: RelayCounter::default(),
+        redial: None,
+        keys: LocalKeys::default(),
+        idle: config.server.idle_timeout.map(IdleTimer::new),
+        deadline,
+        time_warnings,
+        held_output: config.multisession.as_ref().map_or(HELD_OUTPUT, |multisession| multisession.held_output * 1024),
+        chaos: Chaos::new(config.chaos.clone().unwrap_or_default()),
+        chat: None,
+        spy: None,
+        resume_code,
+        reattach_tx,
+        reattach_rx,
+        replay: ReplayBuffer::new(config.resume.as_ref().map_or(0, |resume| resume.replay_buffer as usize * 1024)),
+    };
+    relay.run();
+    relay.finish();
+    Ok(())
+}
+
+// What is known of the caller's terminal, as far as anything configured needs it.
+struct Terminal {
+    terminal_type: Option<String>,
+    // What server.probe_terminal found.
+    class: Option<TerminalClass>,
+    charset: Option<ClientCharset>,
+    // Whether it draws RIPscrip, by [rip].
+    rip: bool,
+    // Whether it is SyncTERM, by [syncterm].
+    syncterm: bool,
+    // Whether it shows UTF-8 rather than CP437, for cp437-auto.
+    utf8: bool,
+}
+
+// The negotiation before a backend is picked: the terminal type and its MTTS
+// bits, the probe, and SyncTERM's binary mode, each only when it is needed.
+fn negotiate_terminal(stream: &mut Downstream, config: &Config, reporter: &Reporter, span: Span) -> Result<Terminal, Ended> {
+    let disconnected = |_: PromptError| {
+        reporter.close();
+        log!(Relay, Info, span = span, "Disconnected before session start");
+        Ended
+    };
+    let probing = config.server.probe_terminal || config.routes.iter().any(|route| route.terminal_class.is_some());
+    // Whether CP437 output is to be converted for this caller is decided once, up front.
+    let detecting = config.backends.iter().any(|backend| backend.output == OutputFilter::Cp437Auto);
+    let mut terminal_type = None;
+    if probing || detecting || config.charset_by_terminal() || config.rip.is_some() || config.syncterm.is_some() || config.routes.iter().any(|route| route.terminal.is_some()) {
+        terminal_type = login::read_terminal_type(stream).map_err(disconnected)?;
+        log!(Relay, Debug, span = span, "terminal type: {}", terminal_type.as_deref().unwrap_or("not reported"));
+    }
+    let mut mtts = None;
+    if let Some(reported) = terminal_type.as_deref().filter(|_| detecting) {
+        mtts = login::read_mtts(stream, reported).map_err(disconnected)?;
+    }
+    let mut class = None;
+    if probing {
+        let probed = login::probe_terminal(stream, terminal_type.as_deref()).map_err(disconnected)?;
+        log!(Relay, Debug, span = span, "terminal probed as {}", probed.name());
+        class = Some(probed);
+    }
+    let charset = config.client_charset(terminal_type.as_deref());
+    if let Some(charset) = charset {
+        log!(Relay, Debug, span = span, "translating to and from {}", charset.name());
+    }
+    let rip = config.rip.as_ref().is_some_and(|rip| rip.matches(terminal_type.as_deref()));
+    if rip {
+        log!(Relay, Debug, span = span, "passing RIPscrip on");
+    }
+    let syncterm = config.syncterm.as_ref().filter(|syncterm| syncterm.matches(terminal_type.as_deref()));
+    if let Some(syncterm) = syncterm {
+        log!(Relay, Debug, span = span, "passing SyncTERM's sequences on");
+        if syncterm.binary {
+            login::agree_binary(stream).map_err(disconnected)?;
+        }
+    }
+    let utf8 = detecting && login::shows_utf8(mtts, class, terminal_type.as_deref());
+    if detecting {
+        log!(Relay, Debug, span = span, "terminal shows {}{}", if utf8 { "UTF-8" } else { "CP437" },
+             mtts.map_or_else(String::new, |bits| format!(" (MTTS {})", bits)));
+    }
+    Ok(Terminal { terminal_type, class, charset, rip, syncterm: syncterm.is_some(), utf8 })
+}
+
+// With [resume], asks for a code to go back to a held session. A good one
+// hands the stream over to it; a wrong one counts like a failed login.
+fn offer_resume(mut stream: Downstream, prompt: &mut Prompt, caller: &Caller, reporter: &Reporter,
+                context: &ServerContext) -> Result<Downstream, Ended> {
+    let Some(resume) = &context.config.resume else {
+        return Ok(stream);
+    };
+    let (ip_addr, span) = (caller.ip_addr, caller.span());
+    match login::read_resume_code(&mut stream, prompt, Duration::from_secs(resume.prompt_timeout)) {
+        Ok(Some(code)) => match context.held_sessions.reattach(&code, Reattach { stream, ip_addr }) {
+            Ok(()) => {
+                // The held session now owns the stream and keeps its own client id.
+                log!(Relay, Info, span = span, "handed over to a held session");
+                reporter.close();
+                Err(Ended)
+            }
+            Err(reattach) => {
+                let mut stream = reattach.stream;
+                // A wrong code counts like a failed login, so codes can't be guessed at leisure.
+                if let Some(ban) = context.bans.record(ip_addr, Offense::FailedLogin) {
+                    log!(Server, Warn, "Auto-banned {} for {} seconds: {} (strike {})", ip_addr, ban.remaining().as_secs(), ban.reason, ban.strikes);
+                    let _ = stream.write_all(b"Unknown or expired resume code.\r\n");
+                    reporter.close();
+                    log!(Relay, Info, span = span, "Resume failed, connection closed");
+                    return Err(Ended);
+                }
+                let _ = stream.write_all(b"Unknown or expired resume code, starting a new session.\r\n");
+                Ok(stream)
+            }
+        },
+        Ok(None) => Ok(stream),
+        Err(PromptError::TimedOut) | Err(PromptError::Disconnected) => {
+            reporter.close();
+            log!(Relay, Info, span = span, "Disconnected before session start");
+            Err(Ended)
+        }
+    }
+}
+
+// With [users], asks for a username and password; None without.
+fn log_in(stream: &mut Downstream, prompt: &mut Prompt, caller: &Caller, reporter: &Reporter,
+          context: &ServerContext) -> Result<Option<User>, Ended> {
+    let (Some(store), Some(users)) = (&context.user_store, &context.config.users) else {
+        return Ok(None);
+    };
+    let (ip_addr, span) = (caller.ip_addr, caller.span());
+    let on_failure = || match context.bans.record(ip_addr, Offense::FailedLogin) {
+        Some(ban) => {
+            log!(Server, Warn, "Auto-banned {} for {} seconds: {} (strike {})", ip_addr, ban.remaining().as_secs(), ban.reason, ban.strikes);
+            false
+        }
+        None => true,
+    };
+    match login::login(stream, prompt, store, users.max_login_attempts, on_failure) {
+        Some(authenticated) => {
+            log!(Relay, Info, span = span, "logged in as {}", authenticated.username);
+            Ok(Some(authenticated))
+        }
+        None => {
+            reporter.close();
+            log!(Relay, Info, span = span, "Login failed, connection closed");
+            Err(Ended)
+        }
+    }
+}
+
+// The user's own backend, or the first route matching the caller, or the
+// default; a pool named by either gives one of its members.
+fn pick_backend<'a>(stream: &mut Downstream, user: Option<&User>, terminal: &Terminal, caller: &Caller, reporter: &Reporter,
+                    context: &'a ServerContext) -> Result<&'a BackendConfig, Ended> {
+    let config: &'a Config = &context.config;
+    let span = caller.span();
+    let known = |name: &&str| config.backend(name).is_some() || config.pool(name).is_some();
+    let mapped = user.and_then(|user| user.backend.as_deref());
+    if let Some(name) = mapped.filter(|name| !known(name)) {
+        log!(Relay, Warn, span = span, "Unknown backend '{}' mapped, using default", name);
+    }
+    let Some(name) = mapped.filter(known).or_else(|| config.route(terminal.terminal_type.as_deref(), terminal.class, caller.server_name.as_deref())) else {
+        return Ok(config.default_backend());
+    };
+    match context.pools.pick(config, &context.health, name, caller.ip_addr) {
+        Some(backend) => Ok(backend),
+        None => {
+            let _ = stream.write_all(b"Every node is down for maintenance, please try again later.\r\n");
+            reporter.close();
+            log!(Relay, Warn, span = span, "Every member of pool {} is draining", name);
+            Err(Ended)
+        }
+    }
+}
+
+// When a call has to end by.
+struct Deadline {
+    at: Instant,
+    // Whether it's server.session_time_limit, rather than the user's time for the day.
+    call_limited: bool,
+}
+
+// The call ends at the user's daily limit or at server.session_time_limit,
+// whichever comes first; a user with no time left today isn't put through.
+fn time_limit(stream: &mut Downstream, user: Option<&User>, span: Span, reporter: &Reporter,
+              context: &ServerContext) -> Result<Option<Deadline>, Ended> {
+    let mut deadline = None;
+    if let (Some(store), Some(user)) = (&context.user_store, user) {
+        match store.time_remaining(user) {
+            Ok(Some(remaining)) if remaining.is_zero() => {
+                let _ = stream.write_all(b"You have no time remaining today. Goodbye.\r\n");
+                reporter.close();
+                log!(Relay, Info, span = span, "No time remaining for {}", user.username);
+                return Err(Ended);
+            }
+            Ok(Some(remaining)) => {
+                let _ = stream.write_all(format!("You have {} minutes remaining today.\r\n", remaining.as_secs() / 60).as_bytes());
+                deadline = Some(Deadline { at: Instant::now() + remaining, call_limited: false });
+            }
+            Ok(None) => {}
+            Err(error) => log!(Relay, Warn, "Unable to read time remaining for {}: {}", user.username, error),
+        }
+    }
+    if let Some(limit) = context.config.server.session_time_limit {
+        let end = Instant::now() + limit;
+        if deadline.as_ref().is_none_or(|deadline| end < deadline.at) {
+            let _ = stream.write_all(format!("You have {} minutes for this call.\r\n", limit.as_secs() / 60).as_bytes());
+            deadline = Some(Deadline { at: end, call_limited: true });
+        }
+    }
+    Ok(deadline)
+}
+
+// What opening a line to a backend takes, the same for each of a session's lines.
+struct Dialer<'a> {
+    config: &'a Config,
+    ip_addr: IpAddr,
+    port: u16,
+    node: String,
+    // The caller's user name, empty if they didn't log in.
+    user_name: String,
+    // What backends asking for a terminal type are told: BBSes turn their SyncTERM extras on for "syncterm".
+    answered_type: &'static str,
+}
+
+impl<'a> Dialer<'a> {
+    fn parser(&self) -> Parser {
+        self.config.negotiation.as_ref().map_or_else(Parser::new, |limits| Parser::with_limit(limits.max_subnegotiation))
+    }
+
+    // The SNDLOC payload for `backend`.
+    fn location(&self, backend: &BackendConfig) -> String {
+        chat::render(&backend.sndloc, &[
+            ("ip", self.ip_addr.to_string()),
+            ("port", self.port.to_string()),
+            ("node", self.node.clone()),
+            ("backend", backend.name.clone()),
+        ])
+    }
+
+    fn open(&self, backend: &'a BackendConfig, trace: Option<Span>) -> io::Result<Line<'a>> {
+        Line::open(backend, self.parser(), self.location(backend), self.answered_type, &self.user_name, trace)
+    }
+}
+
+// How a stage of a pass of the relay went.
+enum Pass {
+    // On to the next stage.
+    Next,
+    // Straight on to the next pass.
+    Again,
+    // The session is over.
+    End,
+}
+
+// Runs a stage of the relay loop, going on as it says.
+macro_rules! stage {
+    ($pass:expr) => {
+        match $pass {
+            Pass::Next => {}
+            Pass::Again => continue,
+            Pass::End => break,
+        }
+    };
+}
+
+// A session once its first backend is dialed: the relay between the caller
+// and their lines, until one side or the other ends it.
+struct Relay<'a> {
+    context: &'a ServerContext,
+    config: &'a Config,
+    reporter: &'a Reporter,
+    control_rx: Receiver<SessionControl>,
+    call: Call,
+    session: SessionInfo,
+    terminal_type: Option<String>,
+    pipeline: Pipeline,
+    dialer: Dialer<'a>,
+    // The session's backend connections: only ever one without [multisession].
+    lines: Vec<Line<'a>>,
+    // The line the caller is talking to.
+    active: usize,
+    // The line to make active at the top of the next pass.
+    switch_to: Option<usize>,
+    // None while the client has dropped and the backend is being held.
+    client: Option<Downstream>,
+    started: Instant,
+    held_until: Option<Instant>,
+    // Once the client has stopped sending (TCP half-close), when the backend must be done by.
+    draining_until: Option<Instant>,
+    // While a [flood] throttle holds the caller back, when their input is read again.
+    reading_after: Option<Instant>,
+    relayed: RelayCounter,
+    redial: Option<Redial>,
+    keys: LocalKeys,
+    idle: Option<IdleTimer>,
+    deadline: Option<Deadline>,
+    time_warnings: Option<TimeWarnings>,
+    held_output: usize,
+    chaos: Chaos,
+    // The console the sysop is chatting from, while they are.
+    chat: Option<Sender<ConsoleMessage>>,
+    // Where a copy of the caller's output goes while the sysop spies on the session.
+    spy: Option<Sender<Vec<u8>>>,
+    resume_code: String,
+    reattach_tx: Sender<Reattach>,
+    reattach_rx: Receiver<Reattach>,
+    replay: ReplayBuffer,
+}
+
+impl<'a> Relay<'a> {
+    fn run(&mut self) {
+        loop {
+            self.relayed.report(&self.context.events, self.session.client_id, false);
+            self.switch_line();
+            stage!(self.release_held());
+            stage!(self.take_control());
+            let backend = self.lines[self.active].backend;
+            if self.chaos.disconnect_if_due(&mut self.lines[self.active].upstream) {
+                log!(Relay, Info, span = self.session.span(), "Chaos: cut the connection to {}", backend.name);
+            }
+            stage!(self.check_time());
+            self.report_negotiated();
+            stage!(self.check_idle());
+            self.serve_background();
+            stage!(match self.client {
+                Some(_) => self.read_caller(),
+                None => self.await_caller(),
+            });
+            stage!(self.poll_redial());
+            let received = match self.receive() {
+                Ok(received) => received,
+                Err(Pass::End) => break,
+                Err(_) => continue,
+            };
+            stage!(self.filter_output(received));
+            sleep(Duration::from_nanos(10))
+        }
+    }
+
+    // Backends are let go before waiting on the caller, so a serial port is free for the next one.
+    fn finish(mut self) {
+        let (client_id, reporter) = (self.session.client_id, self.reporter);
+        if let Some(console) = self.chat {
+            let _ = console.send(ConsoleMessage::Ended { client_id, caller: caller_label(&self.session, self.call) });
+        }
+        reporter.state(SessionState::Draining);
+        drop(self.lines);
+        // A caller who took the session to resume it as it ended is told it's gone, not just dropped.
+        if !self.context.held_sessions.release(&self.resume_code) && self.held_until.is_some() {
+            if let Ok(reattach) = self.reattach_rx.recv() {
+                let mut stream = reattach.stream;
+                let _ = stream.write_all(b"\r\nThe session has ended.\r\n");
+                self.client = Some(stream);
+            }
+        }
+        let mut pipeline = self.pipeline;
+        pipeline.on_close(&self.session);
+        self.relayed.report(&self.context.events, client_id, true);
+        self.context.events.publish(Event::Closed { session: self.session.clone(), duration: self.started.elapsed() });
+        reporter.close();
+        log!(Relay, Info, span = self.session.span(), "Telnet Connection Closed");
+        if let Some(stream) = self.client {
+            hang_up(stream);
+        }
+    }
+
+    // Writes to the caller, unless they have dropped.
+    fn tell(&mut self, message: &[u8]) {
+        if let Some(stream) = self.client.as_mut() {
+            let _ = stream.write_all(message);
+        }
+    }
+
+    fn disconnect(&mut self, reason: &str) -> Pass {
+        self.tell(format!("\r\n{}\r\n", reason).as_bytes());
+        log!(Relay, Info, span = self.session.span(), "Disconnected: {}", reason);
+        Pass::End
+    }
+
+    fn log_out_all(&mut self) {
+        let span = self.session.span();
+        self.lines.iter_mut().for_each(|line| log_out(line, span));
+    }
+
+    fn switch_line(&mut self) {
+        let Some(index) = self.switch_to.take() else {
+            return;
+        };
+        self.active = index;
+        let backend = self.lines[index].backend;
+        let name = &backend.name;
+        self.session.backend = name.clone();
+        // An output filter picked at the escape prompt was for the backend left behind.
+        self.session.encoding = None;
+        log!(Relay, Info, span = self.session.span(), "switched to {}", name);
+        self.reporter.started(&self.session, self.terminal_type.as_deref());
+        self.tell(format!("\r\n[Switched to {}]\r\n", name).as_bytes());
+    }
+
+    // Output held while the caller was on another line, at the escape prompt or chatting.
+    fn release_held(&mut self) -> Pass {
+        if self.keys.is_holding() || self.lines[self.active].held.is_empty() {
+            return Pass::Next;
+        }
+        let mut held = std::mem::take(&mut self.lines[self.active].held);
+        if let Flow::Disconnect(reason) = self.pipeline.on_backend_data(&self.session, &mut held) {
+            return self.disconnect(&reason);
+        }
+        self.replay.push(&held);
+        self.tell(&held);
+        show_spy(&mut self.spy, &held);
+        Pass::Next
+    }
+
+    // What the manager, the admin interface or the sysop's console asked of the session.
+    fn take_control(&mut self) -> Pass {
+        let client_id = self.session.client_id;
+        match self.control_rx.try_recv() {
+            Ok(SessionControl::Disconnect { reason }) => return self.disconnect(&reason),
+            Ok(SessionControl::Notice { message }) => {
+                let message = chat::render(&message, &motd::values(self.call, client_id, self.session.ip_addr, &self.context.clients));
+                self.tell(format!("\r\n{}\r\n", message).as_bytes());
+            }
+            Ok(SessionControl::Chaos(faults)) => {
+                log!(Relay, Info, span = self.session.span(), "Chaos: {}", chaos::describe(&faults));
+                self.chaos.set(faults);
+            }
+            Ok(SessionControl::ChatStart { console }) => {
+                log!(Relay, Info, span = self.session.span(), "sysop chat started");
+                if let Some(previous) = self.chat.replace(console) {
+                    let _ = previous.send(ConsoleMessage::Ended { client_id, caller: caller_label(&self.session, self.call) });
+                }
+                self.keys.start_chat();
+                self.tell(b"\r\n*** The sysop is here to chat. Type /end on a line of its own to go back. ***\r\n");
+            }
+            Ok(SessionControl::ChatLine { text }) => {
+                if self.chat.is_some() {
+                    let line = format!("\r\nSysop: {}\r\n{}", text, self.keys.chat_typed());
+                    self.tell(line.as_bytes());
+                }
+            }
+            Ok(SessionControl::Spy { viewer }) => {
+                log!(Relay, Info, span = self.session.span(), "the sysop is watching");
+                self.spy = Some(viewer);
+            }
+            Ok(SessionControl::ChatEnd) => {
+                if self.chat.take().is_some() {
+                    log!(Relay, Info, span = self.session.span(), "sysop chat ended by the sysop");
+                    self.keys.end_chat();
+                    let backend = self.lines[self.active].backend;
+                    self.tell(format!("\r\n*** The sysop has left the chat; back to {}. ***\r\n", backend.name).as_bytes());
+                }
+            }
+            Err(_) => {}
+        }
+        Pass::Next
+    }
+
+    fn check_time(&mut self) -> Pass {
+        let Some(deadline) = &self.deadline else {
+            return Pass::Next;
+        };
+        let (at, call_limited) = (deadline.at, deadline.call_limited);
+        if Instant::now() >= at {
+            self.tell(if call_limited {
+                b"\r\nYour time for this call is up. Goodbye.\r\n".as_slice()
+            } else {
+                b"\r\nYour time limit for today has been reached. Goodbye.\r\n".as_slice()
+            });
+            log!(Relay, Info, span = self.session.span(), "Time limit reached");
+            return Pass::End;
+        }
+        if let Some(minutes) = self.time_warnings.as_mut().and_then(|warnings| warnings.due(at.saturating_duration_since(Instant::now()))) {
+            let left = if minutes == 1 { String::from("1 minute") } else { format!("{} minutes", minutes) };
+            self.tell(format!("\r\n*** You have {} remaining. ***\r\n", left).as_bytes());
+        }
+        Pass::Next
+    }
+
+    fn report_negotiated(&mut self) {
+        let Some(stream) = self.client.as_mut() else {
+            return;
+        };
+        let charset = self.session.charset.map_or(if self.session.utf8 { "UTF-8" } else { "as sent" }, |charset| charset.name());
+        if let Some(summary) = stream.negotiation().settled(charset) {
+            log!(Relay, Info, span = self.session.span(), "negotiated: {}", summary);
+            self.reporter.negotiated(summary);
+        }
+    }
+
+    // A caller who has dropped or stopped sending isn't expected to type.
+    fn check_idle(&mut self) -> Pass {
+        let (Some(timer), Some(stream), None) = (self.idle.as_mut(), self.client.as_mut(), self.draining_until) else {
+            return Pass::Next;
+        };
+        if timer.is_expired() {
+            let _ = stream.write_all(b"\r\nDisconnected for inactivity.\r\n");
+            log!(Relay, Info, span = self.session.span(), "Idle for {} seconds, disconnected", timer.timeout.as_secs());
+            self.log_out_all();
+            return Pass::End;
+        }
+        if let Some(left) = timer.warning_due() {
+            let _ = stream.write_all(format!("\r\nYou will be disconnected in {} seconds due to inactivity - press any key.\r\n",
+                                             left.as_millis().div_ceil(1000)).as_bytes());
+        }
+        Pass::Next
+    }
+
+    // Lines in the background are kept answered and their output held.
+    fn serve_background(&mut self) {
+        let mut index = 0;
+        while index < self.lines.len() {
+            if index == self.active {
+                index += 1;
+                continue;
+            }
+            let line = &mut self.lines[index];
+            let received = line.send(&mut self.chaos, &self.context.metrics).map_err(Dropped::Unwritable)
+                .and_then(|_| line.receive(None, self.config.negotiation.as_ref(), &self.context.events, self.session.client_id, traced(&self.session)));
+            match received {
+                Ok(received) => {
+                    line.hold(received.into_iter().flat_map(Received::into_data).collect(), self.held_output);
+                    index += 1;
+                }
+                Err(dropped) => {
+                    let backend = line.backend;
+                    let detail = format!("{} (in the background)", dropped.describe(backend));
+                    match dropped.fault() {
+                        Some(kind) => self.reporter.fault(kind, Some(&backend.name), detail),
+                        None => log!(Relay, Info, span = self.session.span(), "{}", detail),
+                    }
+                    self.tell(format!("\r\n[Connection to {} closed]\r\n", backend.name).as_bytes());
+                    self.lines.remove(index);
+                    if index < self.active {
+                        self.active -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Reads what the caller typed, when they may type, and passes it on.
+    fn read_caller(&mut self) -> Pass {
+        let backend = self.lines[self.active].backend;
+        if self.draining_until.is_some_and(|until| Instant::now() >= until) {
+            log!(Relay, Warn, span = self.session.span(), "{} did not finish within {} seconds", backend.name, DRAIN_TIMEOUT.as_secs());
+            return Pass::End;
+        }
+        if self.draining_until.is_some() || self.reading_after.is_some_and(|after| Instant::now() < after) {
+            return Pass::Next;
+        }
+        if self.redial.is_none() && self.lines[self.active].is_stalled() {
+            return self.send_typed();
+        }
+        let Some(stream) = self.client.as_mut() else {
+            return Pass::Next;
+        };
+        const MESSAGE_SIZE: usize = 1;
+        let mut rx_bytes = [0u8; MESSAGE_SIZE];
+        match stream.read(&mut rx_bytes) {
+            Ok(0) => self.caller_stopped(),
+            Ok(_) => self.typed(rx_bytes.to_vec()),
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => Pass::Next,
+            Err(error) => {
+                // Without [resume] the read after a reset sees the end of the stream.
+                self.hold(&format!("connection lost ({})", error));
+                Pass::Next
+            }
+        }
+    }
+
+    // With [resume], keeps the session for the caller to come back to, however
+    // their connection went. Whether it is held.
+    fn hold(&mut self, how: &str) -> bool {
+        let Some(resume) = &self.config.resume else {
+            return false;
+        };
+        log!(Relay, Info, span = self.session.span(), "{}, holding session for {} seconds", how, resume.grace_period);
+        self.context.held_sessions.hold(&self.resume_code, self.reattach_tx.clone());
+        self.reporter.held();
+        self.held_until = Some(Instant::now() + Duration::from_secs(resume.grace_period));
+        self.client = None;
+        true
+    }
+
+    // The caller hung up or half-closed: the session is held for them to
+    // resume, drained, or over.
+    fn caller_stopped(&mut self) -> Pass {
+        let backend = self.lines[self.active].backend;
+        if self.hold("dropped") {
+            return Pass::Next;
+        }
+        if !backend.can_half_close() {
+            // With no half-close to pass on, there is nothing to wait for.
+            log!(Relay, Info, span = self.session.span(), "hung up on {}", backend.name);
+            self.log_out_all();
+            return Pass::End;
+        } else {
+            // Pass the half-close on and keep relaying until the backend is done.
+            log!(Relay, Info, span = self.session.span(), "stopped sending, waiting for {} to finish", backend.name);
+            let span = self.session.span();
+            for line in self.lines.iter_mut() {
+                log_out(line, span);
+                let _ = line.upstream.shutdown(Shutdown::Write);
+            }
+            self.draining_until = Some(Instant::now() + DRAIN_TIMEOUT);
+            self.reporter.state(SessionState::Draining);
+        }
+        Pass::Next
+    }
+
+    // What the caller typed: the keys the proxy itself answers, then the
+    // filter layers, then on to the active line.
+    fn typed(&mut self, mut data: Vec<u8>) -> Pass {
+        if let Some(timer) = self.idle.as_mut() {
+            timer.typed(&data);
+        }
+        let (config, context, client_id) = (self.config, self.context, self.session.client_id);
+        let backend = self.lines[self.active].backend;
+        let Some(stream) = self.client.as_mut() else {
+            return Pass::Next;
+        };
+        let mut echo = Vec::new();
+        let local = self.keys.take(&mut data, config.multisession.as_ref().map(|multisession| multisession.hotkey),
+                                   config.escape.as_ref().map(|escape| escape.key), &mut echo);
+        let _ = stream.write_all(&echo);
+        let target = match local {
+            Some(Local::Switch(switch)) => config.multisession.as_ref()
+                .and_then(|multisession| pick_line(switch, &self.lines, self.active, &config.backends, multisession, stream)),
+            Some(Local::Said(text)) if text.trim() == "/end" => {
+                if let Some(console) = self.chat.take() {
+                    let _ = console.send(ConsoleMessage::Ended { client_id, caller: caller_label(&self.session, self.call) });
+                }
+                log!(Relay, Info, span = self.session.span(), "sysop chat ended by the caller");
+                self.keys.end_chat();
+                let _ = stream.write_all(format!("*** Chat over; back to {}. ***\r\n", backend.name).as_bytes());
+                None
+            }
+            Some(Local::Said(text)) => {
+                if let Some(console) = &self.chat {
+                    let _ = console.send(ConsoleMessage::Said { caller: caller_label(&self.session, self.call), text });
+                }
+                None
+            }
+            Some(Local::Command(command)) => {
+                let deadline = self.deadline.as_ref().map(|deadline| deadline.at);
+                match run_command(&command, &self.lines, self.active, &mut self.session, config, deadline, stream) {
+                    Escaped::Resume => None,
+                    Escaped::Page(reason) => {
+                        let reply = match context.sysop.page(client_id, &caller_label(&self.session, self.call), &reason) {
+                            Ok(()) => {
+                                log!(Relay, Info, span = self.session.span(), "paged the sysop");
+                                "Paging the sysop. If they can chat, they'll break in here."
+                            }
+                            Err(refusal) => refusal,
+                        };
+                        let _ = stream.write_all(format!("{}\r\n", reply).as_bytes());
+                        None
+                    }
+                    Escaped::Switch(target) => Some(target),
+                    Escaped::Quit => {
+                        let _ = stream.write_all(b"Goodbye.\r\n");
+                        log!(Relay, Info, span = self.session.span(), "quit from the escape prompt");
+                        return Pass::End;
+                    }
+                }
+            }
+            None => None,
+        };
+        if let Some(Pass::Again) = target.map(|target| self.switch_or_dial(target)) {
+            return Pass::Again;
+        }
+        match self.pipeline.on_client_data(&self.session, &mut data) {
+            Flow::Disconnect(reason) => return self.disconnect(&reason),
+            Flow::Warn(message) => self.tell(format!("\r\n{}\r\n", message).as_bytes()),
+            Flow::Throttle(until) => self.reading_after = Some(until),
+            Flow::Continue => {}
+        }
+        self.chaos.mangle(&mut data);
+        // Typing is dropped while the backend is being re-dialed.
+        if data.is_empty() || self.redial.is_some() {
+            return Pass::Next;
+        }
+        let line = &mut self.lines[self.active];
+        let outgoing = line.encode(&data);
+        line.unsent.extend(outgoing);
+        if let Pass::End = self.send_typed() {
+            return Pass::End;
+        }
+        self.relayed.to_backend += data.len() as u64;
+        self.reporter.state(SessionState::Active);
+        Pass::Next
+    }
+
+    // Sends what the active line's window takes of what the caller typed.
+    fn send_typed(&mut self) -> Pass {
+        let line = &mut self.lines[self.active];
+        if let Err(error) = line.send(&mut self.chaos, &self.context.metrics) {
+            let backend = line.backend;
+            self.reporter.fault(Fault::Write, Some(&backend.name), format!("Unable to write to {}: {}", backend.name, error));
+            return Pass::End;
+        }
+        Pass::Next
+    }
+
+    // Puts the caller through to `target`, on the line they have to it or a new one.
+    fn switch_or_dial(&mut self, target: &'a BackendConfig) -> Pass {
+        match self.lines.iter().position(|line| line.backend.name == target.name) {
+            // There's no switching away while the backend is being re-dialed.
+            _ if self.redial.is_some() => {
+                let backend = self.lines[self.active].backend;
+                self.tell(format!("\r\n[Still reconnecting to {}]\r\n", backend.name).as_bytes());
+            }
+            Some(index) => self.switch_to = Some(index),
+            None => match self.dialer.open(target, traced(&self.session)) {
+                Ok(line) => {
+                    log!(Relay, Info, span = self.session.span(), "connected to Telnet Server {}", target.name);
+                    self.lines.push(line);
+                    self.switch_to = Some(self.lines.len() - 1);
+                    // Without [multisession] a caller has the one line, so the old one is hung up.
+                    if self.config.multisession.is_none() {
+                        let mut old = self.lines.remove(self.active);
+                        log_out(&mut old, self.session.span());
+                        log!(Relay, Info, span = self.session.span(), "hung up on {}", old.backend.name);
+                        self.switch_to = Some(0);
+                        return Pass::Again;
+                    }
+                }
+                Err(error) => {
+                    self.tell(format!("\r\n[Unable to reach {} right now]\r\n", target.name).as_bytes());
+                    self.reporter.fault(Fault::dialing(&error), Some(&target.name), format!("Unable to connect to {}: {}", target.name, error));
+                }
+            },
+        }
+        Pass::Next
+    }
+
+    // While the caller is away, whether they have come back with their code
+    // or the grace period is over.
+    fn await_caller(&mut self) -> Pass {
+        if let Ok(reattach) = self.reattach_rx.try_recv() {
+            self.resume(reattach);
+        } else if self.held_until.is_some_and(|until| Instant::now() >= until) {
+            // Whichever of this and a resuming caller takes the session out of
+            // the held list has it; one who got there first is on their way.
+            if !self.context.held_sessions.release(&self.resume_code) {
+                if let Ok(reattach) = self.reattach_rx.recv() {
+                    self.resume(reattach);
+                    return Pass::Next;
+                }
+            }
+            self.held_until = None;
+            log!(Relay, Info, span = self.session.span(), "did not return within the grace period");
+            self.log_out_all();
+            return Pass::End;
+        }
+        Pass::Next
+    }
+
+    fn resume(&mut self, reattach: Reattach) {
+        let mut stream = reattach.stream;
+        let _ = stream.write_all(b"\r\nSession resumed.\r\n");
+        let _ = stream.write_all(&self.replay.contents());
+        log!(Relay, Info, span = self.session.span(), "resumed from {}", reattach.ip_addr);
+        self.session.ip_addr = reattach.ip_addr;
+        self.reporter.reattached(reattach.ip_addr);
+        self.client = Some(stream);
+        self.held_until = None;
+    }
+
+    // While the active line's backend is being re-dialed, nothing else is read from it.
+    fn poll_redial(&mut self) -> Pass {
+        let Some(attempts) = self.redial.as_mut() else {
+            return Pass::Next;
+        };
+        let backend = self.lines[self.active].backend;
+        if let Some(dialed) = attempts.poll(backend, &self.dialer.user_name, &self.session) {
+            match dialed {
+                Ok(stream) => {
+                    let line = &mut self.lines[self.active];
+                    line.upstream = stream;
+                    line.parser = self.dialer.parser();
+                    // Typing the old connection never took went with it.
+                    line.unsent.clear();
+                    line.stalled_at = None;
+                    line.last_redial = Some((attempts.until, Instant::now()));
+                    self.redial = None;
+                    self.reporter.state(SessionState::Negotiating);
+                    self.tell(format!("\r\nReconnected to {}.\r\n", backend.name).as_bytes());
+                    log!(Relay, Info, span = self.session.span(), "reconnected to {}", backend.name);
+                }
+                Err(error) if attempts.back_off() => {
+                    log!(Relay, Warn, span = self.session.span(), "Unable to reconnect to {}, retrying: {}", backend.name, error);
+                }
+                Err(error) => {
+                    self.tell(CARRIER_LOST);
+                    self.reporter.fault(Fault::dialing(&error), Some(&backend.name), format!("Unable to reconnect to {}: {}", backend.name, error));
+                    return Pass::End;
+                }
+            }
+        }
+        if self.redial.is_some() {
+            sleep(Duration::from_millis(10));
+            return Pass::Again;
+        }
+        Pass::Next
+    }
+
+    // What the active line's backend sent. When it has gone, the line is
+    // re-dialed if it may be, or the caller is put through to another.
+    fn receive(&mut self) -> Result<Vec<Received>, Pass> {
+        let line = &mut self.lines[self.active];
+        let backend = line.backend;
+        let dropped = match line.receive(Some(&mut self.chaos), self.config.negotiation.as_ref(), &self.context.events,
+                                         self.session.client_id, traced(&self.session)) {
+            Ok(received) => return Ok(received),
+            Err(dropped) => dropped,
+        };
+        if let Some(kind) = dropped.fault() {
+            self.reporter.fault(kind, Some(&backend.name), dropped.describe(backend));
+        }
+        let notice = match dropped {
+            Dropped::HungUp | Dropped::Lost(_) => {
+                // A backend finishing after the caller stopped sending has simply hung up.
+                let until = backend.redial_window.filter(|_| self.draining_until.is_none()).map(|window| match line.last_redial {
+                    Some((until, reconnected)) if reconnected.elapsed() < window => until,
+                    _ => Instant::now() + window,
+                });
+                if let Some(until) = until.filter(|&until| Instant::now() < until) {
+                    // A serial port has to be let go of before it can be opened again.
+                    let _ = line.upstream.shutdown(Shutdown::Both);
+                    self.tell(format!("\r\nConnection to {} lost, retrying...\r\n", backend.name).as_bytes());
+                    // A hang-up is a fault too when the backend is meant to stay up.
+                    if let Dropped::HungUp = dropped {
+                        self.reporter.fault(Fault::Lost, Some(&backend.name), dropped.describe(backend));
+                    }
+                    self.redial = Some(Redial::new(until));
+                    self.reporter.state(SessionState::DialingBackend);
+                    return Err(Pass::Again);
+                }
+                if let Dropped::HungUp = dropped {
+                    log!(Relay, Info, span = self.session.span(), "{}", dropped.describe(backend));
+                }
+                CARRIER_LOST.to_vec()
+            }
+            Dropped::Unruly => format!("\r\nThe connection to {} was closed.\r\n", backend.name).into_bytes(),
+            Dropped::Unwritable(_) => Vec::new(),
+        };
+        self.tell(&notice);
+        // With other lines open, the caller is put through to the next one.
+        if self.lines.len() == 1 || self.draining_until.is_some() {
+            return Err(Pass::End);
+        }
+        self.lines.remove(self.active);
+        self.switch_to = Some(self.active % self.lines.len());
+        Err(Pass::Again)
+    }
+
+    // The backend's output through the filter layers to the caller, or held
+    // while the caller is at the escape prompt or chatting.
+    fn filter_output(&mut self, received: Vec<Received>) -> Pass {
+        for received in received {
+            let prompt = matches!(received, Received::Prompt);
+            let mut data = received.into_data();
+            if self.keys.is_holding() {
+                self.lines[self.active].hold(data, self.held_output);
+                continue;
+            }
+            // A prompt's end has the layers let go of anything they are holding back.
+            let flow = match prompt {
+                true => self.pipeline.on_backend_prompt(&self.session, &mut data),
+                false => self.pipeline.on_backend_data(&self.session, &mut data),
+            };
+            if let Flow::Disconnect(reason) = flow {
+                return self.disconnect(&reason);
+            }
+            if data.is_empty() {
+                continue;
+            }
+            self.replay.push(&data);
+            if let Some(stream) = self.client.as_mut() {
+                if let Err(error) = self.chaos.write(stream, &data) {
+                    let fault = if stream.overflowed() { Fault::Overflow } else { Fault::Write };
+                    self.reporter.fault(fault, None, format!("Unable to write to the client: {}", error));
+                    // What they missed is in the replay buffer for when they resume.
+                    if !self.hold(&format!("unable to write to them ({})", error)) {
+                        self.log_out_all();
+                        return Pass::End;
+                    }
+                } else {
+                    self.relayed.to_client += data.len() as u64;
+                    self.reporter.state(SessionState::Active);
+                }
+            }
+            show_spy(&mut self.spy, &data);
+        }
+        Pass::Next
+    }
+}
+
+// What a session tells the manager about how it's going: each step of its
+// life, its faults, and that it's over, once, however it ended. A manager
+// that has already gone, as at shutdown, is simply not told.
+struct Reporter {
+    client_id: uuid::Uuid,
+    sender: Sender<ClientManagerMessage>,
+    state: Cell<SessionState>,
+    closed: Cell<bool>,
+}
+
+impl Reporter {
+    // Steps it can't take from where it is, like back to active while draining, are left out.
+    fn state(&self, state: SessionState) {
+        if self.state.get().allows(state) {
+            self.state.set(state);
+            let _ = self.sender.send(ClientManagerMessage::Transition { client_id: self.client_id, state, at: Instant::now() });
+        }
+    }
+
+    fn fault(&self, kind: Fault, backend: Option<&str>, detail: String) {
+        let backend = backend.map(String::from);
+        let _ = self.sender.send(ClientManagerMessage::Error { client_id: self.client_id, kind, backend, detail });
+    }
+
+    // The session is through to `session.backend`, first or after a switch.
+    fn started(&self, session: &SessionInfo, terminal: Option<&str>) {
+        let _ = self.sender.send(ClientManagerMessage::Started {
+            client_id: self.client_id,
+            backend: session.backend.clone(),
+            username: session.user.as_ref().map(|user| user.username.clone()),
+            terminal: terminal.map(String::from),
+            terminal_class: session.terminal_class,
+        });
+    }
+
+    fn negotiated(&self, summary: String) {
+        let _ = self.sender.send(ClientManagerMessage::Negotiated { client_id: self.client_id, summary });
+    }
+
+    fn held(&self) {
+        let _ = self.sender.send(ClientManagerMessage::Held { client_id: self.client_id });
+    }
+
+    fn reattached(&self, ip_addr: IpAddr) {
+        let _ = self.sender.send(ClientManagerMessage::Reattached { client_id: self.client_id, ip_addr });
+    }
+
+    fn close(&self) {
+        if !self.closed.replace(true) {
+            let _ = self.sender.send(ClientManagerMessage::ConnectionClosed { client_id: self.client_id });
+        }
+    }
+}
+
+// Re-dialing a backend that dropped mid-session, until the window runs out.
+struct Redial {
+    until: Instant,
+    next_attempt: Instant,
+    wait: Duration,
+    // The attempt under way. Dialing can take seconds, so it has a thread of
+    // its own and the relay keeps serving the caller meanwhile.
+    dialing: Option<Receiver<io::Result<Upstream>>>,
+}
+
+impl Redial {
+    fn new(until: Instant) -> Self {
+        Self { until, next_attempt: Instant::now() + FIRST_REDIAL, wait: FIRST_REDIAL, dialing: None }
+    }
+
+    // Starts an attempt once one is due, and gives its outcome once it has one.
+    fn poll(&mut self, backend: &BackendConfig, user: &str, session: &SessionInfo) -> Option<io::Result<Upstream>> {
+        let Some(dialing) = &self.dialing else {
+            if Instant::now() >= self.next_attempt {
+                let (backend, user, session) = (backend.clone(), user.to_string(), session.clone());
+                let (result_tx, result_rx) = bounded(1);
+                let _ = thread::spawn(move || {
+                    let _ = result_tx.send(connect_backend(&backend, &user, traced(&session)));
+                });
+                self.dialing = Some(result_rx);
+            }
+            return None;
+        };
+        let outcome = match dialing.try_recv() {
+            Ok(outcome) => outcome,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(io::Error::other("the dialing thread stopped")),
+        };
+        self.dialing = None;
+        Some(outcome)
+    }
+
+    // Schedules the next attempt after a failed one; false if the window has run out.
+    fn back_off(&mut self) -> bool {
+        self.wait = (self.wait * 2).min(MAX_REDIAL);
+        self.next_attempt = Instant::now() + self.wait;
+        self.next_attempt < self.until
+    }
+}
+
+// One of a session's backend connections. With [multisession] a caller can
+// hold several, and the output of those in the background is kept in `held`
+// until the caller switches back.
+struct Line<'a> {
+    backend: &'a BackendConfig,
+    upstream: Upstream,
+    parser: Parser,
+    commands: CommandRate,
+    // Picks the caller's telnet commands out of what they type, for a backend without telnet.
+    typed: CommandTracker,
+    // The SNDLOC payload sent to this backend.
+    location: String,
+    // The terminal type given when it asks.
+    terminal_type: &'static str,
+    held: Vec<u8>,
+    // What the caller typed that the backend's window hasn't taken yet, and
+    // since when it hasn't.
+    unsent: Vec<u8>,
+    stalled_at: Option<Instant>,
+    // The last re-dial's window and when it got through. A backend that drops again
+    // before staying up for a whole window doesn't get a fresh one.
+    last_redial: Option<(Instant, Instant)>,
+}
+
+// What a read from a backend brought.
+enum Received {
+    Data(Vec<u8>),
+    // GA or EOR, which ends a prompt.
+    Prompt,
+}
+
+impl Received {
+    fn into_data(self) -> Vec<u8> {
+        match self {
+            Received::Data(data) => data,
+            Received::Prompt => Vec::new(),
+        }
+    }
+}
+
+// Why a line came to an end.
+enum Dropped {
+    // The backend closed the connection.
+    HungUp,
+    // Reading from the backend failed.
+    Lost(String),
+    // It went over the [negotiation] limits.
+    Unruly,
+    Unwritable(io::Error),
+}
+
+impl Dropped {
+    fn describe(&self, backend: &BackendConfig) -> String {
+        match self {
+            Dropped::HungUp => format!("{} closed the connection", backend.name),
+            Dropped::Lost(lost) => lost.clone(),
+            Dropped::Unruly => format!("{} exceeded the negotiation limits", backend.name),
+            Dropped::Unwritable(error) => format!("Unable to write to {}: {}", backend.name, error),
+        }
+    }
+
+    // A backend hanging up is how sessions end, not a fault; one that's to be
+    // re-dialed is reported as lost where the re-dial starts.
+    fn fault(&self) -> Option<Fault> {
+        match self {
+            Dropped::HungUp => None,
+            Dropped::Lost(_) => Some(Fault::Lost),
+            Dropped::Unruly => Some(Fault::Negotiation),
+            Dropped::Unwritable(_) => Some(Fault::Write),
+        }
+    }
+}
+
+impl<'a> Line<'a> {
+    fn open(backend: &'a BackendConfig, parser: Parser, location: String, terminal_type: &'static str, user: &str,
+            trace: Option<Span>) -> io::Result<Self> {
+        let upstream = connect_backend(backend, user, trace)?;
+        Ok(Self { backend, upstream, parser, commands: CommandRate::default(), typed: CommandTracker::default(), location,
+                  terminal_type, held: Vec::new(), unsent: Vec::new(), stalled_at: None, last_redial: None })
+    }
+
+    // What the caller typed, as it goes to the backend.
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.backend.speaks_telnet() {
+            return codec::escape(data);
+        }
+        data.iter().copied().filter(|&byte| self.typed.is_data(byte)).collect()
+    }
+
+    // Sends what the backend's window takes of what the caller typed. While
+    // anything is left, no more is read from the caller, so it backs up to them.
+    fn send(&mut self, chaos: &mut Chaos, metrics: &Metrics) -> io::Result<()> {
+        if self.unsent.is_empty() {
+            return Ok(());
+        }
+        let written = chaos.write_some(&mut self.upstream, &self.unsent)?;
+        self.unsent.drain(..written);
+        match (self.unsent.is_empty(), self.stalled_at) {
+            (true, Some(at)) => {
+                metrics.observe_stall(&self.backend.name, at.elapsed());
+                self.stalled_at = None;
+            }
+            (false, None) => self.stalled_at = Some(Instant::now()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn is_stalled(&self) -> bool {
+        !self.unsent.is_empty()
+    }
+
+    // Keeps output for the caller to see later, dropping the oldest past the limit.
+    fn hold(&mut self, data: Vec<u8>, limit: usize) {
+        self.held.extend(data);
+        let excess = self.held.len().saturating_sub(limit);
+        self.held.drain(..excess);
+    }
+
+    // Reads what the backend has sent and answers its negotiation, returning
+    // the data to pass on to the caller and where its prompts end.
+    fn receive(&mut self, chaos: Option<&mut Chaos>, limits: Option<&NegotiationConfig>, events: &EventBus,
+               client_id: uuid::Uuid, trace: Option<Span>) -> Result<Vec<Received>, Dropped> {
+        let backend = self.backend;
+        let mut buffer = [0u8; 256];
+        let frames = match self.upstream.read(&mut buffer) {
+            Ok(0) => return Err(Dropped::HungUp),
+            Ok(size) => {
+                let mut data = buffer[..size].to_vec();
+                if let Some(chaos) = chaos {
+                    chaos.mangle(&mut data);
+                }
+                if !backend.speaks_telnet() {
+                    return Ok(vec![Received::Data(data)]);
+                }
+                self.parser.feed(&data)
+            }
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => return Ok(Vec::new()),
+            Err(error) => return Err(Dropped::Lost(format!("Unable to read from {}: {}", backend.name, error))),
+        };
+        let mut received = Vec::new();
+        for frame in frames {
+            if let Some(span) = trace {
+                trace_frame(span, "backend->proxy", &frame);
+            }
+            if let Some(limits) = limits {
+                let exceeded = match &frame {
+                    Frame::Data(_) => false,
+                    Frame::Oversized(_) => true,
+                    _ => self.commands.exceeded(limits.backend_rate) && limits.backend_rate > 0,
+                };
+                if exceeded {
+                    return Err(Dropped::Unruly);
+                }
+            }
+            match frame {
+                Frame::Data(data) => received.push(Received::Data(data)),
+                Frame::Command(codec::GA | codec::EOR) => received.push(Received::Prompt),
+                Frame::Negotiation(action, option) => {
+                    events.publish(Event::Negotiated { client_id, action: codec::action_name(&action), option });
+                    negotiate(&mut self.upstream, backend, action, option, &self.location, trace).map_err(Dropped::Unwritable)?;
+                }
+                Frame::Subnegotiation(option, payload) => {
+                    subnegotiate(&mut self.upstream, backend, option, &payload, self.terminal_type, trace).map_err(Dropped::Unwritable)?;
+                }
+                Frame::Oversized(_) | Frame::Command(_) => {}
+            }
+        }
+        Ok(received)
+    }
+}
+
+// Tells the caller as their time limit nears, once at each of
+// server.time_warnings (in minutes).
+struct TimeWarnings {
+    thresholds: Vec<u64>,
+    // The last warning given. Those already passed when the call started are skipped.
+    announced: Option<u64>,
+}
+
+impl TimeWarnings {
+    fn new(thresholds: &[u64], left: Duration) -> Self {
+        let mut warnings = Self { thresholds: thresholds.to_vec(), announced: None };
+        warnings.due(left);
+        warnings
+    }
+
+    // The warning the time left has come down to, if it's not been given yet.
+    fn due(&mut self, left: Duration) -> Option<u64> {
+        let due = self.thresholds.iter().copied()
+            .filter(|&minutes| minutes > 0 && self.announced.is_none_or(|announced| minutes < announced))
+            .filter(|&minutes| left <= Duration::from_secs(minutes * 60))
+            .min()?;
+        self.announced = Some(due);
+        Some(due)
+    }
+}
+
+// Counts down to disconnecting a caller who has stopped typing, with a
+// warning first. Telnet commands, which clients send as keepalives, don't
+// count as typing.
+struct IdleTimer {
+    timeout: Duration,
+    last_typed: Instant,
+    warned: bool,
+    telnet: CommandTracker,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        Self { timeout, last_typed: Instant::now(), warned: false, telnet: CommandTracker::default() }
+    }
+
+    fn typed(&mut self, data: &[u8]) {
+        // Every byte goes through the tracker, so no stopping at the first typed one.
+        let mut typed = false;
+        for &byte in data {
+            typed |= self.telnet.is_data(byte);
+        }
+        if typed {
+            self.last_typed = Instant::now();
+            self.warned = false;
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.last_typed.elapsed() >= self.timeout
+    }
+
+    // The time left, the first time it's down to the warning.
+    fn warning_due(&mut self) -> Option<Duration> {
+        let left = self.timeout.saturating_sub(self.last_typed.elapsed());
+        if self.warned || left > IDLE_WARNING.min(self.timeout / 2) {
+            return None;
+        }
+        self.warned = true;
+        Some(left)
+    }
+}
+
+// What a caller asked for with the multisession hotkey.
+enum Switch {
+    // To the backend at this position in the config.
+    To(usize),
+    Next,
+    List,
+}
+
+// What a caller typed for the proxy itself rather than the backend.
+enum Local {
+    Switch(Switch),
+    // A command line from the escape prompt.
+    Command(String),
+    // A line typed in a sysop chat.
+    Said(String),
+}
+
+// Picks the keys meant for the proxy out of what a caller types: the
+// multisession hotkey and the key pressed after it, and the escape key and
+// the command typed after that.
+#[derive(Default)]
+struct LocalKeys {
+    telnet: CommandTracker,
+    pressed: bool,
+    // What has been typed at the escape prompt, while it's up.
+    prompt: Option<String>,
+    // And of the line being typed in a sysop chat, during one.
+    chat: Option<String>,
+    // A command has just been entered, and the LF or NUL after its CR isn't for the backend.
+    entered: bool,
+}
+
+impl LocalKeys {
+    // At the escape prompt or in a sysop chat, while the backend's output waits.
+    fn is_holding(&self) -> bool {
+        self.prompt.is_some() || self.chat.is_some()
+    }
+
+    fn start_chat(&mut self) {
+        self.prompt = None;
+        self.chat = Some(String::new());
+    }
+
+    fn end_chat(&mut self) {
+        self.chat = None;
+    }
+
+    fn chat_typed(&self) -> &str {
+        self.chat.as_deref().unwrap_or_default()
+    }
+
+    // Takes the keys out of the data. What the caller should see in answer,
+    // the prompt and the echo of what is typed at it, is added to `echo`.
+    fn take(&mut self, data: &mut Vec<u8>, hotkey: Option<ControlKey>, escape: Option<ControlKey>, echo: &mut Vec<u8>) -> Option<Local> {
+        let mut local = None;
+        data.retain(|&byte| {
+            if !self.telnet.is_data(byte) {
+                return true;
+            }
+            if std::mem::take(&mut self.entered) && matches!(byte, b'\n' | 0) {
+                return false;
+            }
+            let key = Some(ControlKey(byte));
+            // Everything typed in a chat is for the sysop; Enter sends the line.
+            if let Some(typed) = self.chat.as_mut() {
+                match byte {
+                    b'\r' => {
+                        local = Some(Local::Said(std::mem::take(typed)));
+                        self.entered = true;
+                        echo.extend_from_slice(b"\r\n");
+                    }
+                    0x08 | 0x7f if typed.pop().is_some() => echo.extend_from_slice(b"\x08 \x08"),
+                    0x20..=0x7e => {
+                        typed.push(byte as char);
+                        echo.push(byte);
+                    }
+                    _ => {}
+                }
+                return false;
+            }
+            if let Some(typed) = self.prompt.as_mut() {
+                match byte {
+                    // Pressed again at the prompt, it's sent on.
+                    _ if key == escape => {
+                        self.prompt = None;
+                        echo.extend_from_slice(b"\r\n");
+                        return true;
+                    }
+                    b'\r' => {
+                        local = Some(Local::Command(std::mem::take(typed)));
+                        self.prompt = None;
+                        self.entered = true;
+                        echo.extend_from_slice(b"\r\n");
+                    }
+                    0x08 | 0x7f if typed.pop().is_some() => echo.extend_from_slice(b"\x08 \x08"),
+                    0x20..=0x7e => {
+                        typed.push(byte as char);
+                        echo.push(byte);
+                    }
+                    _ => {}
+                }
+                return false;
+            }
+            if self.pressed {
+                self.pressed = false;
+                local = match byte {
+                    // Pressed twice, it's sent on.
+                    _ if key == hotkey => return true,
+                    b'1'..=b'9' => Some(Local::Switch(Switch::To((byte - b'1') as usize))),
+                    b'n' | b'N' => Some(Local::Switch(Switch::Next)),
+                    _ => Some(Local::Switch(Switch::List)),
+                };
+                false
+            } else if key == hotkey {
+                self.pressed = true;
+                false
+            } else if key == escape {
+                self.prompt = Some(String::new());
+                echo.extend_from_slice(ESCAPE_PROMPT);
+                false
+            } else {
+                true
+            }
+        });
+        local
+    }
+}
+
+// The backend a multisession switch is to, telling the caller why when
+// there's none.
+fn pick_line<'a>(switch: Switch, lines: &[Line], active: usize, backends: &'a [BackendConfig], multisession: &MultisessionConfig,
+                 stream: &mut Downstream) -> Option<&'a BackendConfig> {
+    let notice = match switch {
+        Switch::To(index) if index < backends.len() => match refuse_switch(&backends[index], lines, active, Some(multisession)) {
+            Some(refusal) => format!("[{}]", refusal),
+            None => return Some(&backends[index]),
+        },
+        Switch::Next if lines.len() > 1 => {
+            let next = &lines[(active + 1) % lines.len()];
+            return backends.iter().find(|backend| backend.name == next.backend.name);
+        }
+        Switch::Next => String::from("[No other sessions open]"),
+        Switch::To(_) | Switch::List => {
+            let mut list = String::new();
+            for (index, backend) in backends.iter().enumerate().take(9) {
+                let state = if backend.name == lines[active].backend.name {
+                    " (active)"
+                } else if lines.iter().any(|line| line.backend.name == backend.name) {
+                    " (open)"
+                } else {
+                    ""
+                };
+                list.push_str(&format!("{}. {}{}\r\n", index + 1, backend.name, state));
+            }
+            let hotkey = multisession.hotkey;
+            format!("{}{} and a number switches systems, {} n the next open one, {} {} sends {}.", list, hotkey, hotkey, hotkey, hotkey, hotkey)
+        }
+    };
+    let _ = stream.write_all(format!("\r\n{}\r\n", notice).as_bytes());
+    None
+}
+
+// Why the caller can't switch to a backend, if they can't.
+fn refuse_switch(backend: &BackendConfig, lines: &[Line], active: usize, multisession: Option<&MultisessionConfig>) -> Option<String> {
+    let is_open = lines.iter().any(|line| line.backend.name == backend.name);
+    if backend.name == lines[active].backend.name {
+        Some(format!("You are on {}", backend.name))
+    } else if multisession.is_some_and(|multisession| !is_open && lines.len() >= multisession.max_sessions) {
+        Some(format!("You already have {} sessions open", lines.len()))
+    } else {
+        None
+    }
+}
+
+// What the relay does after a command from the escape prompt.
+enum Escaped<'a> {
+    Resume,
+    Switch(&'a BackendConfig),
+    // Page the sysop, for this reason if one was given.
+    Page(String),
+    Quit,
+}
+
+// Runs a command typed at the escape prompt, answering the caller.
+fn run_command<'a>(command: &str, lines: &[Line], active: usize, session: &mut SessionInfo, config: &'a Config,
+                   deadline: Option<Instant>, stream: &mut Downstream) -> Escaped<'a> {
+    let backend = lines[active].backend;
+    let (name, argument) = command.trim().split_once(' ').unwrap_or((command.trim(), ""));
+    let argument = argument.trim();
+    let reply = match name.to_ascii_lowercase().as_str() {
+        "" => return Escaped::Resume,
+        "quit" | "exit" => return Escaped::Quit,
+        "status" => {
+            let encoding = session.encoding.unwrap_or(backend.output);
+            let mut status = format!("Connected to {}, output {}{}.", backend.name, encoding.name(),
+                                     if session.encoding.is_some() { " (picked here)" } else { "" });
+            if lines.len() > 1 {
+                let open: Vec<&str> = lines.iter().map(|line| line.backend.name.as_str()).collect();
+                status.push_str(&format!("\r\nOpen sessions: {}.", open.join(", ")));
+            }
+            if let Some(deadline) = deadline {
+                status.push_str(&format!("\r\nTime left: {} minutes.", deadline.saturating_duration_since(Instant::now()).as_secs() / 60));
+            }
+            status
+        }
+        "switch" => {
+            let target = match argument.parse::<usize>() {
+                Ok(number) => number.checked_sub(1).and_then(|index| config.backends.get(index)),
+                Err(_) => config.backend(argument),
+            };
+            match target {
+                Some(target) => match refuse_switch(target, lines, active, config.multisession.as_ref()) {
+                    Some(refusal) => format!("{}.", refusal),
+                    None => return Escaped::Switch(target),
+                },
+                None => {
+                    let names: Vec<String> = config.backends.iter().enumerate().map(|(index, backend)| format!("{} {}", index + 1, backend.name)).collect();
+                    format!("No system '{}'. Systems: {}.", argument, names.join(", "))
+                }
+            }
+        }
+        "encoding" => {
+            let encoding = if argument.is_empty() {
+                // On its own, CP437 translation is turned on or off.
+                match session.encoding.unwrap_or(backend.output) {
+                    OutputFilter::Cp437ToUtf8 => Ok(OutputFilter::Raw),
+                    OutputFilter::Cp437Auto if session.utf8 => Ok(OutputFilter::Raw),
+                    _ => Ok(OutputFilter::Cp437ToUtf8),
+                }
+            } else {
+                argument.parse::<OutputFilter>()
+            };
+            match encoding {
+                Ok(encoding) => {
+                    session.encoding = Some(encoding);
+                    format!("Output is now {}.", encoding.name())
+                }
+                Err(message) => format!("Unknown encoding: {}.", message),
+            }
+        }
+        "page" => return Escaped::Page(argument.to_string()),
+        "help" | "?" => String::from("status             where you are connected\r\n\
+                                      switch <system>    change systems, by name or number\r\n\
+                                      encoding [name]    set the output encoding, or turn CP437 translation on or off\r\n\
+                                      page [reason]      ask the sysop to chat\r\n\
+                                      quit               hang up\r\n\
+                                      Enter on its own goes back to the session."),
+        _ => format!("Unknown command '{}'. Try help.", name),
+    };
+    let _ = stream.write_all(format!("{}\r\n", reply).as_bytes());
+    Escaped::Resume
+}
+
+// Who a caller is to the sysop's console.
+fn caller_label(session: &SessionInfo, call: Call) -> String {
+    match &session.user {
+        Some(user) => format!("{} ({}, node {})", user.username, session.ip_addr, call.node),
+        None => format!("{} (node {})", session.ip_addr, call.node),
+    }
+}
+
+// Types the backend's logout sequence for a caller who has gone.
+fn log_out(line: &mut Line, span: Span) {
+    let Some(logout) = &line.backend.logout else {
+        return;
+    };
+    match write_waiting(&mut line.upstream, &codec::escape(logout.as_bytes())) {
+        Ok(()) => log!(Relay, Debug, span = span, "Logged out of {}", line.backend.name),
+        Err(error) => log!(Relay, Warn, span = span, "Unable to log out of {}: {}", line.backend.name, error),
+    }
+}
+
+// Closes the client connection once everything written to it has been sent.
+// Closing with unread input would reset the connection and could throw the
+// last of the output away, so input is read and dropped until the client
+// closes too, or for a couple of seconds at most.
+fn hang_up(mut stream: Downstream) {
+    let _ = stream.flush();
+    if stream.shutdown(Shutdown::Write).is_err() {
+        return;
+    }
+    let until = Instant::now() + HANG_UP_TIMEOUT;
+    let mut buffer = [0u8; 256];
+    while Instant::now() < until {
+        match stream.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => sleep(Duration::from_millis(10)),
+            Err(_) => return,
+        }
+    }
+}
+
+// `user` is the caller's user name, empty if they didn't log in.
+fn connect_backend(backend: &BackendConfig, user: &str, trace: Option<Span>) -> io::Result<Upstream> {
+    if let Some(serial) = &backend.serial {
+        return SerialPort::open(serial).map(Upstream::Serial);
+    }
+    if let Some(path) = &backend.socket {
+        let mut socket = LocalSocket::connect(path)?;
+        if backend.speaks_telnet() {
+            force_options(&mut socket, backend, trace)?;
+        }
+        return Ok(Upstream::Local(socket));
+    }
+    if let Some(ssh) = &backend.ssh {
+        return SshSession::open(backend, ssh).map(Upstream::Ssh);
+    }
+    let address = (backend.host.as_str(), backend.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(10))?;
+    if let Some(rlogin) = &backend.rlogin {
+        rlogin::handshake(&mut stream, rlogin, user)?;
+    }
+    if backend.speaks_telnet() {
+        force_options(&mut stream, backend, trace)?;
+    }
+    stream.set_nonblocking(true)?;
+    Ok(Upstream::Tcp(stream))
+}
+
+// Copies output to the console spying on the session, until it stops watching.
+fn show_spy(spy: &mut Option<Sender<Vec<u8>>>, data: &[u8]) {
+    if spy.as_ref().is_some_and(|viewer| viewer.send(data.to_vec()).is_err()) {
+        *spy = None;
+    }
+}
+
+// Bytes forwarded in each direction, published on the event bus every few
+// seconds rather than per read.
+#[derive(Default)]
+struct RelayCounter {
+    to_backend: u64,
+    to_client: u64,
+    last_report: Option<Instant>,
+}
+
+impl RelayCounter {
+    fn report(&mut self, events: &EventBus, client_id: uuid::Uuid, force: bool) {
+        let now = Instant::now();
+        let due = self.last_report.is_none_or(|at| now.duration_since(at) >= BYTES_REPORT_INTERVAL);
+        if !(force || due) {
+            return;
+        }
+        self.last_report = Some(now);
+        if self.to_backend > 0 || self.to_client > 0 {
+            events.publish(Event::BytesRelayed { client_id, to_backend: self.to_backend, to_client: self.to_client });
+            self.to_backend = 0;
+            self.to_client = 0;
+        }
+    }
+}
+
+fn describe_seconds(seconds: u64) -> String {
+    if seconds >= 120 {
+        format!("{} minutes", seconds / 60)
+    } else {
+        format!("{} seconds", seconds)
+    }
+}
+
+// Answers a backend's WILL, WONT, DO or DONT, following the backend's option
+// policy when it has one for the option.
+fn negotiate(upstream: &mut Upstream, backend: &BackendConfig, action: Action, option: TelnetOption, location: &str,
+             trace: Option<Span>) -> io::Result<()> {
+    let reply = match backend.option_policy(option.as_byte()) {
+        Some(policy) => Some(policy_answer(policy, &action)),
+        None => default_answer(&action, option),
+    };
+    let Some(reply) = reply else {
+        return Ok(());
+    };
+    send(upstream, &codec::negotiation(&reply, option), trace)?;
+    if let (TelnetOption::SNDLOC, Action::Will) = (option, reply) {
+        send(upstream, &codec::subnegotiation(TelnetOption::SNDLOC, location.as_bytes()), trace)?;
+    }
+    Ok(())
+}
+
+// The machine's host name, for SNDLOC's {node} without server.node_name.
+pub fn host_name() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok().map(|name| name.trim().to_string()).filter(|name| !name.is_empty()))
+        .unwrap_or_else(|| String::from("triserver"))
+}
+
+fn policy_answer(policy: OptionPolicy, action: &Action) -> Action {
+    match (policy, action) {
+        (OptionPolicy::Accept | OptionPolicy::Force, Action::Do) => Action::Will,
+        (OptionPolicy::Accept | OptionPolicy::Force, Action::Will) => Action::Do,
+        (_, Action::Do | Action::Dont) => Action::Wont,
+        (_, Action::Will | Action::Wont) => Action::Dont,
+    }
+}
+
+// The answers used for options a backend has no policy for.
+fn default_answer(action: &Action, option: TelnetOption) -> Option<Action> {
+    match (option, action) {
+        (TelnetOption::SNDLOC, Action::Do) => Some(Action::Will),
+        (TelnetOption::SNDLOC, Action::Dont) => Some(Action::Wont),
+        (TelnetOption::SNDLOC, Action::Will | Action::Wont) => Some(Action::Dont),
+        (TelnetOption::Echo, Action::Do) => Some(Action::Will),
+        (TelnetOption::Echo, Action::Dont) => Some(Action::Wont),
+        (TelnetOption::Echo, Action::Will) => Some(Action::Do),
+        (TelnetOption::Echo, Action::Wont) => Some(Action::Dont),
+        (TelnetOption::SuppressGoAhead | TelnetOption::TransmitBinary, Action::Do | Action::Dont) => Some(Action::Will),
+        (TelnetOption::SuppressGoAhead | TelnetOption::TransmitBinary, Action::Will | Action::Wont) => Some(Action::Do),
+        (TelnetOption::TTYPE, Action::Do) => Some(Action::Will),
+        _ => None,
+    }
+}
+
+// Asks for every option the backend's policy forces, right after connecting.
+fn force_options(upstream: &mut impl Write, backend: &BackendConfig, trace: Option<Span>) -> io::Result<()> {
+    for (&option, _) in backend.options.iter().filter(|(_, policy)| **policy == OptionPolicy::Force) {
+        let option = TelnetOption::parse(option);
+        send(upstream, &codec::negotiation(&Action::Will, option), trace)?;
+        send(upstream, &codec::negotiation(&Action::Do, option), trace)?;
+    }
+    Ok(())
+}
+
+// Answers the backend's TTYPE SEND (RFC 1091) with IS and the terminal type,
+// unless its policy refuses TTYPE.
+fn subnegotiate(upstream: &mut Upstream, backend: &BackendConfig, option: TelnetOption, payload: &[u8], terminal_type: &str,
+                trace: Option<Span>) -> io::Result<()> {
+    const IS: u8 = 0;
+    const SEND: u8 = 1;
+    let refused = backend.option_policy(option.as_byte()) == Some(OptionPolicy::Refuse);
+    if let (TelnetOption::TTYPE, [SEND], false) = (option, payload, refused) {
+        let mut reply = vec![IS];
+        reply.extend_from_slice(terminal_type.as_bytes());
+        send(upstream, &codec::subnegotiation(TelnetOption::TTYPE, &reply), trace)?;
+    }
+    Ok(())
+}
+
+// Writes a telnet command to the backend, logging it when tracing.
+fn send(upstream: &mut impl Write, command: &[u8], trace: Option<Span>) -> io::Result<()> {
+    if let Some(span) = trace {
+        for frame in Parser::new().feed(command) {
+            trace_frame(span, "proxy->backend", &frame);
+        }
+    }
+    write_waiting(upstream, command)
+}
+
+// Writes all of `data` to a backend that may not block, waiting out a full
+// window rather than failing on it.
+fn write_waiting(upstream: &mut impl Write, mut data: &[u8]) -> io::Result<()> {
+    let until = Instant::now() + COMMAND_WRITE_TIMEOUT;
+    while !data.is_empty() {
+        match upstream.write(data) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(written) => data = &data[written..],
+            Err(error) if error.kind() == ErrorKind::WouldBlock && Instant::now() < until => sleep(Duration::from_millis(1)),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => return Err(io::Error::from(ErrorKind::TimedOut)),
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+// The span telnet commands are logged under, while negotiation is logged at trace.
+fn traced(session: &SessionInfo) -> Option<Span<'_>> {
+    log::enabled(Subsystem::Negotiation, LogLevel::Trace).then(|| session.span())
+}
+
+pub fn trace_frame(span: Span, direction: &str, frame: &Frame) {
+    if let Some(description) = codec::describe(frame) {
+        log!(Negotiation, Trace, span = span, "{} {}", direction, description);
+    }
+}
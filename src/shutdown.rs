@@ -0,0 +1,175 @@
+// Shutdowns scheduled ahead of time from the admin interface ("shutdown
+// <seconds>"). Callers are warned at set points of the countdown, new callers
+// are turned away for the final minute, and then the server stops as it
+// would on SIGTERM.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::log;
+use crate::{SessionControl, SharedClientMap};
+
+// Seconds left at which callers are warned, besides when the shutdown is
+// first scheduled.
+const WARNINGS: [u64; 9] = [3600, 1800, 900, 600, 300, 120, 60, 30, 10];
+// New callers are turned away once this little time is left.
+const FINAL_WINDOW: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// For `shutdown` without --in: long enough for callers to be warned and to
+// finish up.
+pub const DEFAULT_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Default)]
+pub struct ScheduledShutdown {
+    at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ScheduledShutdown {
+    // False if the delay is further off than the clock can count to.
+    pub fn schedule(&self, delay: Duration) -> bool {
+        let Some(at) = Instant::now().checked_add(delay) else {
+            return false;
+        };
+        *self.at.lock().unwrap() = Some(at);
+        true
+    }
+
+    // False if no shutdown was scheduled.
+    pub fn cancel(&self) -> bool {
+        self.at.lock().unwrap().take().is_some()
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.lock().unwrap().map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_refusing_callers(&self) -> bool {
+        self.remaining().is_some_and(|remaining| remaining <= FINAL_WINDOW)
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.remaining().is_some_and(|remaining| remaining.is_zero())
+    }
+
+    // Broadcasts the countdown to every session on a background thread.
+    pub fn launch_countdown(&self, clients: SharedClientMap) {
+        let shutdown = self.clone();
+        let _ = thread::spawn(move || {
+            // The shutdown last announced, and the seconds left it was announced with.
+            let mut announced: Option<(Instant, u64)> = None;
+            loop {
+                let at = *shutdown.at.lock().unwrap();
+                match (at, announced) {
+                    (None, Some(_)) => {
+                        broadcast(&clients, "*** The scheduled shutdown has been cancelled. ***");
+                        announced = None;
+                    }
+                    (Some(at), _) => {
+                        let left = at.saturating_duration_since(Instant::now()).as_millis().div_ceil(1000) as u64;
+                        // After the first announcement, only warning points passed since the last one.
+                        let warning = match announced {
+                            Some((announced_at, last)) if announced_at == at => {
+                                WARNINGS.into_iter().filter(|&warning| warning < last && left <= warning).min()
+                            }
+                            _ => Some(left),
+                        };
+                        if let Some(warning) = warning.filter(|&warning| warning > 0) {
+                            broadcast(&clients, &format!("*** The system is going down in {}. Please finish up. ***",
+                                                         describe_seconds(warning)));
+                            announced = Some((at, warning));
+                        }
+                    }
+                    (None, None) => {}
+                }
+                sleep(POLL_INTERVAL);
+            }
+        });
+    }
+}
+
+fn broadcast(clients: &SharedClientMap, message: &str) {
+    log!(Server, Info, "{}", message);
+    for client in clients.values() {
+        let _ = client.control.send(SessionControl::Notice { message: message.to_string() });
+    }
+}
+
+fn describe_seconds(seconds: u64) -> String {
+    match seconds {
+        1 => String::from("1 second"),
+        60 => String::from("1 minute"),
+        3600 => String::from("1 hour"),
+        _ if seconds.is_multiple_of(3600) => format!("{} hours", seconds / 3600),
+        _ if seconds >= 120 => format!("{} minutes", seconds.div_ceil(60)),
+        _ => format!("{} seconds", seconds),
+    }
+}
+
+// "10m", "90s", "1h", or a plain number of minutes.
+pub fn parse_delay(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid delay '{}', expected e.g. 10m, 90s or 1h", value);
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, "m"),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(scale).map(Duration::from_secs).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_delays() {
+        assert_eq!(parse_delay("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_delay("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_delay("10"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_delay("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_delay("0"), Ok(Duration::ZERO));
+        for invalid in ["", "m", "10d", "1.5h", "-1m", "18446744073709551615h"] {
+            assert!(parse_delay(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn schedules_and_cancels() {
+        let shutdown = ScheduledShutdown::default();
+        assert!(!shutdown.cancel());
+        assert!(shutdown.schedule(Duration::from_secs(600)));
+        assert!(shutdown.remaining().is_some_and(|remaining| remaining > Duration::from_secs(590)));
+        assert!(!shutdown.is_refusing_callers());
+        assert!(!shutdown.is_due());
+        assert!(shutdown.schedule(Duration::ZERO));
+        assert!(shutdown.is_refusing_callers());
+        assert!(shutdown.is_due());
+        assert!(shutdown.cancel());
+        assert_eq!(shutdown.remaining(), None);
+    }
+
+    #[test]
+    fn refuses_a_delay_past_what_the_clock_can_hold() {
+        let shutdown = ScheduledShutdown::default();
+        assert!(shutdown.schedule(Duration::from_secs(60)));
+        assert!(!shutdown.schedule(Duration::MAX));
+        // The shutdown already scheduled stands.
+        assert!(shutdown.remaining().is_some_and(|remaining| remaining <= Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn describes_the_time_left() {
+        assert_eq!(describe_seconds(1), "1 second");
+        assert_eq!(describe_seconds(30), "30 seconds");
+        assert_eq!(describe_seconds(300), "5 minutes");
+        assert_eq!(describe_seconds(3600), "1 hour");
+        assert_eq!(describe_seconds(7200), "2 hours");
+    }
+}
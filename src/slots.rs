@@ -0,0 +1,39 @@
+// Caps on connections being set up on a thread of their own before they are
+// sessions, such as one having its PROXY header read or its TLS handshake
+// done. A caller that connects and then says nothing keeps its thread until a
+// timeout, so without a cap a flood of them could start threads without end.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Slots {
+    taken: AtomicUsize,
+    max: usize,
+}
+
+impl Slots {
+    pub const fn new(max: usize) -> Self {
+        Self { taken: AtomicUsize::new(0), max }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // A slot, given back when it is dropped, or None when all are taken.
+    pub fn take(&'static self) -> Option<Slot> {
+        if self.taken.fetch_add(1, Ordering::AcqRel) >= self.max {
+            self.taken.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        Some(Slot(self))
+    }
+}
+
+// Counts a connection out however its thread ends.
+pub struct Slot(&'static Slots);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.taken.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,182 @@
+// A full state dump on SIGUSR1, for looking inside a server that has no admin
+// interface enabled: sessions, backends, running totals, queue depths and
+// threads. It goes to the log, or is appended to server.snapshot_file.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+
+use crate::clock::now_timestamp;
+use crate::events::Event;
+use crate::lifecycle::SessionState;
+use crate::log;
+use crate::version;
+use crate::{ClientManagerMessage, ServerContext};
+
+// How often the request flag is checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Counted from the event bus since startup.
+#[derive(Default)]
+struct Totals {
+    connects: u64,
+    closes: u64,
+    to_backend: u64,
+    to_client: u64,
+    negotiations: u64,
+    bans: u64,
+    errors: u64,
+}
+
+impl Totals {
+    fn count(&mut self, event: &Event) {
+        match event {
+            Event::Connected(_) => self.connects += 1,
+            Event::Closed { .. } => self.closes += 1,
+            Event::BytesRelayed { to_backend, to_client, .. } => {
+                self.to_backend += to_backend;
+                self.to_client += to_client;
+            }
+            Event::Negotiated { .. } => self.negotiations += 1,
+            Event::Banned(_) => self.bans += 1,
+            Event::Error { .. } => self.errors += 1,
+            Event::Drained { .. } | Event::Daily(_) => {}
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sigusr1(_signal: std::ffi::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Installs the SIGUSR1 handler and keeps the totals a snapshot reports.
+pub fn launch_snapshots(context: ServerContext, client_manager_tx: Sender<ClientManagerMessage>) {
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGUSR1, on_sigusr1 as extern "C" fn(std::ffi::c_int) as usize);
+    }
+    let receiver = context.events.subscribe();
+    let _ = thread::spawn(move || {
+        let mut totals = Totals::default();
+        loop {
+            for event in receiver.try_iter() {
+                totals.count(&event);
+            }
+            if REQUESTED.swap(false, Ordering::SeqCst) {
+                write_snapshot(&describe(&context, &totals, client_manager_tx.len()), &context);
+            }
+            sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+fn write_snapshot(lines: &[String], context: &ServerContext) {
+    let Some(path) = &context.config.server.snapshot_file else {
+        for line in lines {
+            log!(Server, Info, "{}", line);
+        }
+        return;
+    };
+    let contents: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    match OpenOptions::new().create(true).append(true).open(path).and_then(|mut file| file.write_all(contents.as_bytes())) {
+        Ok(()) => log!(Server, Info, "Snapshot written to {}", path.display()),
+        Err(error) => log!(Server, Warn, "Unable to write snapshot to {}: {}", path.display(), error),
+    }
+}
+
+fn describe(context: &ServerContext, totals: &Totals, manager_queue: usize) -> Vec<String> {
+    let mut lines = vec![
+        format!("=== Snapshot at {} UTC, {} ===", now_timestamp(), version::describe()),
+        format!("uptime: {}s  pid: {}", context.started.elapsed().as_secs(), std::process::id()),
+    ];
+
+    let mut clients = context.clients.values();
+    clients.sort_by_key(|client| client.connected_at);
+    let states: Vec<String> = SessionState::ALL.iter()
+        .map(|&state| (state, clients.iter().filter(|client| client.state == state).count()))
+        .filter(|&(_, count)| count > 0)
+        .map(|(state, count)| format!("{} {}", count, state))
+        .collect();
+    lines.push(format!("sessions: {} ({}), held: {}", clients.len(), if states.is_empty() { String::from("none") } else { states.join(", ") },
+                       context.held_sessions.len()));
+    for client in &clients {
+        lines.push(format!("  {}  {:<40} {:<16} {:<16} {:>8}s  {} for {}s", client.client_id, client.ip_addr,
+                           client.backend.as_deref().unwrap_or("-"), client.username.as_deref().unwrap_or("-"),
+                           client.connected_at.elapsed().as_secs(), client.state, client.state_since.elapsed().as_secs()));
+    }
+
+    lines.push(String::from("backends:"));
+    let statuses = context.health.backends();
+    for backend in &context.live.current().backends {
+        let status = statuses.iter().find(|status| status.name == backend.name);
+        let health = match status.and_then(|status| status.healthy) {
+            Some(true) => String::from("healthy"),
+            Some(false) => format!("unhealthy ({})", status.and_then(|status| status.last_error.as_deref()).unwrap_or("no answer")),
+            None => String::from("not probed"),
+        };
+        let sessions = clients.iter().filter(|client| client.backend.as_deref() == Some(backend.name.as_str())).count();
+        lines.push(format!("  {:<16} {}  {} session(s), {}", backend.name, backend.address(), sessions, health));
+    }
+
+    lines.push(format!("totals: {} connects, {} closes, {} bytes to backends, {} bytes to clients, {} negotiations, {} bans, {} errors",
+                       totals.connects, totals.closes, totals.to_backend, totals.to_client, totals.negotiations, totals.bans, totals.errors));
+    lines.push(format!("bans: {} active", context.bans.list().len()));
+    let depths = context.events.queue_depths();
+    lines.push(format!("queues: client manager {}, event subscribers {:?}", manager_queue, depths));
+    lines.push(describe_threads());
+    lines.push(String::from("=== End of snapshot ==="));
+    lines
+}
+
+// Thread counts by scheduler state, from /proc.
+#[cfg(target_os = "linux")]
+fn describe_threads() -> String {
+    let mut states = std::collections::BTreeMap::new();
+    let tasks = std::fs::read_dir("/proc/self/task").into_iter().flatten().flatten();
+    for task in tasks {
+        let Ok(stat) = std::fs::read_to_string(task.path().join("stat")) else {
+            continue;
+        };
+        // The state follows the parenthesised command name, which may itself contain spaces.
+        let state = stat.rsplit_once(')').and_then(|(_, rest)| rest.split_whitespace().next()).unwrap_or("?");
+        let name = match state {
+            "R" => "running",
+            "S" => "sleeping",
+            "D" => "waiting on I/O",
+            "T" | "t" => "stopped",
+            "Z" => "zombie",
+            _ => "other",
+        };
+        *states.entry(name).or_insert(0) += 1;
+    }
+    let total: u32 = states.values().sum();
+    let breakdown: Vec<String> = states.iter().map(|(name, count)| format!("{} {}", count, name)).collect();
+    format!("threads: {} ({})", total, breakdown.join(", "))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn describe_threads() -> String {
+    String::from("threads: not available on this platform")
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::c_int;
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+    pub const SIGUSR1: c_int = 30;
+    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")))]
+    pub const SIGUSR1: c_int = 10;
+
+    extern "C" {
+        pub fn signal(signal: c_int, handler: usize) -> usize;
+    }
+}
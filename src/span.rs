@@ -0,0 +1,30 @@
+// What each of a session's log lines starts with, so one caller's trouble can
+// be followed with a single grep: a short correlation id (the first part of
+// the client id), the listener they came in on, their address and, once one
+// is picked, their backend, as in `[3f2a9c1e telnet 203.0.113.5 bbs]`. The
+// full client id is logged once, when the session is created.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Span<'a> {
+    pub client_id: Uuid,
+    pub listener: &'a str,
+    pub ip_addr: IpAddr,
+    pub backend: Option<&'a str>,
+}
+
+impl Span<'_> {
+    pub fn short_id(client_id: Uuid) -> String {
+        client_id.to_string()[..8].to_string()
+    }
+}
+
+impl fmt::Display for Span<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} {} {} {}]", Span::short_id(self.client_id), self.listener, self.ip_addr, self.backend.unwrap_or("-"))
+    }
+}
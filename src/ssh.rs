@@ -0,0 +1,236 @@
+// SSH backends: each caller gets an SSH session of their own to the host,
+// with a PTY requested for the login shell or command, and the channel is
+// relayed like any other backend connection. Nothing is forwarded, so a
+// caller can't reach past the host they were put through to. When the
+// identity doesn't log them in, the caller is asked for the password, as the
+// ssh client would.
+
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ssh2::{Channel, CheckResult, KeyboardInteractivePrompt, KnownHostFileKind, Prompt, Session};
+
+use crate::config::{BackendConfig, HostKeyChecking, SshConfig};
+use crate::log;
+
+// Matches the timeout for dialing a telnet backend.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// As many as the ssh client gives.
+const PASSWORD_TRIES: u32 = 3;
+// A BBS-sized window.
+const COLUMNS: u32 = 80;
+const ROWS: u32 = 24;
+
+pub struct SshSession {
+    session: Session,
+    user: String,
+    terminal: String,
+    command: Option<String>,
+    // Text for the caller ahead of anything from the host, such as the
+    // password prompt.
+    pending: VecDeque<u8>,
+    state: State,
+}
+
+enum State {
+    Password(Asking),
+    Open(Channel),
+    Closed,
+}
+
+// The caller being asked for the password.
+struct Asking {
+    prompt: String,
+    // The host takes it as the answer to a keyboard-interactive prompt
+    // rather than as a password.
+    keyboard_interactive: bool,
+    typed: Vec<u8>,
+    tries_left: u32,
+}
+
+impl SshSession {
+    pub fn open(backend: &BackendConfig, ssh: &SshConfig) -> io::Result<Self> {
+        let address = (backend.host.as_str(), backend.port).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address"))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?);
+        session.set_timeout(CONNECT_TIMEOUT.as_millis() as u32);
+        session.handshake()?;
+        check_host_key(&session, backend, ssh)?;
+
+        // Who the server runs as, as with the ssh client.
+        let user = ssh.user.clone().or_else(|| env::var("USER").ok()).or_else(|| env::var("LOGNAME").ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no user to log in as"))?;
+        // Asking may log straight in, with the "none" method.
+        let methods = session.auth_methods(&user)?.to_string();
+        if let Some(identity) = &ssh.identity {
+            if !session.authenticated() && session.userauth_pubkey_file(&user, None, identity, None).is_err() {
+                log!(Relay, Debug, "{} didn't take the identity {} for {}", backend.name, identity.display(), user);
+            }
+        }
+        let mut ssh_session = Self { session, user, terminal: ssh.terminal.clone(), command: ssh.command.clone(), pending: VecDeque::new(),
+                                     state: State::Closed };
+        if ssh_session.session.authenticated() {
+            ssh_session.start()?;
+        } else if methods.split(',').any(|method| method == "password" || method == "keyboard-interactive") {
+            let prompt = format!("{}@{}'s password: ", ssh_session.user, backend.host);
+            ssh_session.pending.extend(prompt.as_bytes());
+            let keyboard_interactive = !methods.split(',').any(|method| method == "password");
+            ssh_session.state = State::Password(Asking { prompt, keyboard_interactive, typed: Vec::new(), tries_left: PASSWORD_TRIES });
+        } else {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, format!("not logged in, and the host takes only {}", methods)));
+        }
+        Ok(ssh_session)
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+        self.pending.clear();
+        let _ = self.session.disconnect(None, "", None);
+    }
+
+    // Opens the channel once logged in. From then on nothing waits.
+    fn start(&mut self) -> io::Result<()> {
+        let mut channel = self.session.channel_session()?;
+        channel.request_pty(&self.terminal, None, Some((COLUMNS, ROWS, 0, 0)))?;
+        match &self.command {
+            Some(command) => channel.exec(command)?,
+            None => channel.shell()?,
+        }
+        self.session.set_blocking(false);
+        self.state = State::Open(channel);
+        Ok(())
+    }
+
+    // What the caller types at the password prompt, which isn't echoed.
+    fn type_password(&mut self, data: &[u8]) -> io::Result<()> {
+        for &byte in data {
+            // Whatever follows the password on its line is dropped.
+            let State::Password(asking) = &mut self.state else {
+                break;
+            };
+            match byte {
+                b'\r' => self.log_in()?,
+                b'\n' | 0 => {}
+                0x08 | 0x7f => {
+                    asking.typed.pop();
+                }
+                _ => asking.typed.push(byte),
+            }
+        }
+        Ok(())
+    }
+
+    fn log_in(&mut self) -> io::Result<()> {
+        let State::Password(asking) = &mut self.state else {
+            return Ok(());
+        };
+        let password = String::from_utf8_lossy(&std::mem::take(&mut asking.typed)).into_owned();
+        self.pending.extend(b"\r\n");
+        let logged_in = if asking.keyboard_interactive {
+            self.session.userauth_keyboard_interactive(&self.user, &mut Answer(&password))
+        } else {
+            self.session.userauth_password(&self.user, &password)
+        };
+        if logged_in.is_ok() {
+            return self.start();
+        }
+        asking.tries_left -= 1;
+        if asking.tries_left == 0 {
+            self.pending.extend(b"Permission denied.\r\n");
+            self.state = State::Closed;
+        } else {
+            self.pending.extend(b"Permission denied, please try again.\r\n");
+            self.pending.extend(asking.prompt.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SshSession {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Gives the caller's password to every keyboard-interactive prompt.
+struct Answer<'a>(&'a str);
+
+impl KeyboardInteractivePrompt for Answer<'_> {
+    fn prompt(&mut self, _user: &str, _instructions: &str, prompts: &[Prompt]) -> Vec<String> {
+        prompts.iter().map(|_| self.0.to_string()).collect()
+    }
+}
+
+// Checks the host's key against known_hosts, by default the one of the user
+// the server runs as. With accept-new, a host that isn't there is added.
+fn check_host_key(session: &Session, backend: &BackendConfig, ssh: &SshConfig) -> io::Result<()> {
+    if ssh.host_key_checking == HostKeyChecking::Off {
+        return Ok(());
+    }
+    let (key, key_type) = session.host_key().ok_or_else(|| io::Error::other("no host key"))?;
+    let path = ssh.known_hosts.clone().or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh").join("known_hosts")));
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+        known_hosts.read_file(path, KnownHostFileKind::OpenSSH)?;
+    }
+    match known_hosts.check_port(&backend.host, backend.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound if ssh.host_key_checking == HostKeyChecking::AcceptNew => {
+            let host = match backend.port {
+                22 => backend.host.clone(),
+                port => format!("[{}]:{}", backend.host, port),
+            };
+            known_hosts.add(&host, key, "", key_type.into())?;
+            // The key is still taken this time if it can't be kept.
+            if let Some(path) = &path {
+                if let Err(error) = known_hosts.write_file(path, KnownHostFileKind::OpenSSH) {
+                    log!(Relay, Warn, "Unable to add {} to {}: {}", host, path.display(), error);
+                }
+            }
+            Ok(())
+        }
+        CheckResult::NotFound => Err(io::Error::new(ErrorKind::PermissionDenied, "host key not in known_hosts")),
+        CheckResult::Mismatch => Err(io::Error::new(ErrorKind::PermissionDenied, "host key doesn't match known_hosts")),
+        CheckResult::Failure => Err(io::Error::other("unable to check the host key")),
+    }
+}
+
+impl Read for SshSession {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let size = buffer.len().min(self.pending.len());
+            for (slot, byte) in buffer.iter_mut().zip(self.pending.drain(..size)) {
+                *slot = byte;
+            }
+            return Ok(size);
+        }
+        match &mut self.state {
+            State::Password(_) => Err(io::Error::from(ErrorKind::WouldBlock)),
+            State::Open(channel) => channel.read(buffer),
+            State::Closed => Ok(0),
+        }
+    }
+}
+
+impl Write for SshSession {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            State::Password(_) => self.type_password(data).map(|()| data.len()),
+            State::Open(channel) => channel.write(data),
+            State::Closed => Err(io::Error::from(ErrorKind::NotConnected)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            State::Open(channel) => channel.flush(),
+            State::Password(_) | State::Closed => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Serving a single caller over stdin and stdout, for inetd/xinetd or an sshd
+// ForceCommand. The caller is bridged onto a loopback connection, so their
+// session runs just as it would for a caller of the listener. Log output
+// would reach the caller, so it goes to the [daemon] log file or nowhere.
+
+use std::ffi::c_int;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver};
+
+use crate::Forwarded;
+
+// How long the caller's side gets to close once the session has finished with
+// it, as when hanging up on a caller of the listener.
+const HANG_UP_TIMEOUT: Duration = Duration::from_secs(2);
+
+extern "C" {
+    fn dup(fd: c_int) -> c_int;
+    fn dup2(old: c_int, new: c_int) -> c_int;
+}
+
+pub struct Bridge {
+    output: JoinHandle<()>,
+    input_done: Receiver<()>,
+    // Our stdout, when inetd has given us the caller's socket.
+    socket: Option<TcpStream>,
+}
+
+// Takes over stdin and stdout, returning the end of the bridge the session
+// uses and who the caller is. Must be called before any output is written,
+// as stdout is then pointed at `log` (or /dev/null) along with stderr.
+pub fn bridge(log: Option<&Path>) -> io::Result<(TcpStream, Forwarded, Bridge)> {
+    let input = duplicate(0)?;
+    let output = duplicate(1)?;
+    // Under inetd stdin and stdout are the caller's socket; under sshd the
+    // caller's address is only in the environment.
+    let socket = duplicate(output.as_raw_fd()).ok().map(|file| TcpStream::from(OwnedFd::from(file)))
+        .filter(|socket| socket.peer_addr().is_ok());
+    let (ip_addr, port) = match socket.as_ref().and_then(|socket| socket.peer_addr().ok()) {
+        Some(peer) => (peer.ip(), peer.port()),
+        None => ssh_client().unwrap_or((IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)),
+    };
+    let log = match log {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    unsafe {
+        dup2(log.as_raw_fd(), 1);
+        dup2(log.as_raw_fd(), 2);
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let outer = TcpStream::connect(listener.local_addr()?)?;
+    let (stream, _) = listener.accept()?;
+    let (done_tx, input_done) = bounded(1);
+    let to_session = outer.try_clone()?;
+    let _ = thread::spawn(move || {
+        pump_input(input, to_session);
+        let _ = done_tx.send(());
+    });
+    let output = thread::spawn(move || pump_output(outer, output));
+    Ok((stream, Forwarded { ip_addr, port, server_name: None }, Bridge { output, input_done, socket }))
+}
+
+impl Bridge {
+    // Waits for the session's last output to reach the caller.
+    pub fn finish(self) {
+        let _ = self.output.join();
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Write);
+        }
+        let _ = self.input_done.recv_timeout(HANG_UP_TIMEOUT);
+    }
+}
+
+// What the caller sends goes to the session until they close. Once the
+// session has gone their input is still read, so closing doesn't reset the
+// connection and lose the last of the output.
+fn pump_input(mut input: File, mut session: TcpStream) {
+    let mut open = true;
+    let mut buffer = [0u8; 1024];
+    loop {
+        match input.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(size) if open => open = session.write_all(&buffer[..size]).is_ok(),
+            Ok(_) => {}
+        }
+    }
+    let _ = session.shutdown(Shutdown::Write);
+}
+
+fn pump_output(mut session: TcpStream, mut output: File) {
+    let mut buffer = [0u8; 1024];
+    while let Ok(size) = session.read(&mut buffer) {
+        if size == 0 || output.write_all(&buffer[..size]).and_then(|_| output.flush()).is_err() {
+            break;
+        }
+    }
+}
+
+// sshd's SSH_CLIENT is "<address> <port> <local port>".
+fn ssh_client() -> Option<(IpAddr, u16)> {
+    let value = std::env::var("SSH_CLIENT").ok()?;
+    let mut parts = value.split_whitespace();
+    let ip_addr = parts.next()?.parse().ok()?;
+    let port = parts.next()?.parse().ok()?;
+    Some((ip_addr, port))
+}
+
+fn duplicate(fd: c_int) -> io::Result<File> {
+    match unsafe { dup(fd) } {
+        -1 => Err(io::Error::last_os_error()),
+        duplicate => Ok(unsafe { File::from_raw_fd(duplicate) }),
+    }
+}
@@ -0,0 +1,80 @@
+// SyncTERM's extended sequences, picked out of a backend's output so they
+// reach SyncTERM as they were sent, around the output filter and [rip]. That
+// takes in every control sequence, ESC [ with CTerm's private parameters and
+// intermediates for font switching and the like, and every control string up
+// to its ST: DCS for font loading and sixel graphics, APC for SyncTERM's file
+// cache that doors use, OSC for the palette. None of it is held back, so a
+// string may run on over any number of reads.
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum State {
+    #[default]
+    Text,
+    Escape,
+    // ESC [ and what has come of the sequence since.
+    Control,
+    // A control string; true for an OSC, which BEL may end as well as ST.
+    String(bool),
+    // ESC inside a control string, the start of its ST.
+    StringEscape(bool),
+}
+
+#[derive(Default)]
+pub struct SequenceScanner {
+    state: State,
+}
+
+impl SequenceScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The output in runs of sequences (true) and everything else (false).
+    pub fn scan(&mut self, input: &[u8]) -> Vec<(bool, Vec<u8>)> {
+        let mut runs: Vec<(bool, Vec<u8>)> = Vec::new();
+        let mut push = |sequence: bool, byte: u8| match runs.last_mut() {
+            Some((last, run)) if *last == sequence => run.push(byte),
+            _ => runs.push((sequence, vec![byte])),
+        };
+        for &byte in input {
+            match next(self.state, byte) {
+                Some(state) => {
+                    push(true, byte);
+                    self.state = state;
+                }
+                None => {
+                    push(false, byte);
+                    self.state = State::Text;
+                }
+            }
+        }
+        runs
+    }
+}
+
+// The state a byte leaves a sequence in, or None when it's text.
+fn next(state: State, byte: u8) -> Option<State> {
+    Some(match (state, byte) {
+        (State::Escape, b'[') => State::Control,
+        (State::Escape, b'P' | b'_' | b'^' | b'X') => State::String(false),
+        (State::Escape, b']') => State::String(true),
+        // An escape's intermediates, such as a character set designation's,
+        // and its last byte.
+        (State::Escape, 0x20..=0x2f) => State::Escape,
+        (State::Escape, 0x30..=0x7e) => State::Text,
+        (State::Control, 0x20..=0x3f) => State::Control,
+        (State::Control, 0x40..=0x7e) => State::Text,
+        (State::String(osc), ESC) => State::StringEscape(osc),
+        (State::String(true), BEL) => State::Text,
+        (State::String(osc), _) => State::String(osc),
+        (State::StringEscape(_), b'\\') => State::Text,
+        // A string broken off by another escape.
+        (State::StringEscape(_), _) => return next(State::Escape, byte),
+        (_, ESC) => State::Escape,
+        // Anything else breaks off an escape or sequence, and is text.
+        _ => return None,
+    })
+}
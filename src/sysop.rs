@@ -0,0 +1,84 @@
+// Paging the sysop, and the chat that answers a page: a caller types `page`
+// at the escape prompt, and every admin connection running `console` rings
+// and shows who it is. The sysop answers with `chat <client-id>`, and until
+// either side types /end the caller's backend is put on hold while lines
+// typed on the console go to the caller and the caller's to the console.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use uuid::Uuid;
+
+// A caller can't page more often than this.
+const PAGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// What a console is shown.
+pub enum ConsoleMessage {
+    Page { client_id: Uuid, caller: String, reason: String },
+    Said { caller: String, text: String },
+    // The caller typed /end or hung up.
+    Ended { client_id: Uuid, caller: String },
+    // A line of the console's own output, such as a command's reply.
+    Reply(String),
+    // The console is being left; nothing more is written to it.
+    Detached,
+}
+
+impl fmt::Display for ConsoleMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleMessage::Page { client_id, caller, reason } if reason.is_empty() => {
+                write!(f, "\x07*** {} is paging you; chat {} to answer", caller, client_id)
+            }
+            ConsoleMessage::Page { client_id, caller, reason } => {
+                write!(f, "\x07*** {} is paging you: {}; chat {} to answer", caller, reason, client_id)
+            }
+            ConsoleMessage::Said { caller, text } => write!(f, "{}: {}", caller, text),
+            ConsoleMessage::Ended { caller, .. } => write!(f, "*** {} has left the chat", caller),
+            ConsoleMessage::Reply(line) => write!(f, "{}", line),
+            ConsoleMessage::Detached => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SysopInner {
+    consoles: Vec<Sender<ConsoleMessage>>,
+    last_page: HashMap<Uuid, Instant>,
+}
+
+// The attached consoles, shared by the admin interface and the sessions.
+#[derive(Clone, Default)]
+pub struct Sysop {
+    inner: Arc<Mutex<SysopInner>>,
+}
+
+impl Sysop {
+    // A console stays attached until its receiver is dropped.
+    pub fn attach(&self) -> (Sender<ConsoleMessage>, Receiver<ConsoleMessage>) {
+        let (sender, receiver) = unbounded();
+        self.inner.lock().unwrap().consoles.push(sender.clone());
+        (sender, receiver)
+    }
+
+    // Rings every console, telling the caller why not when none could be.
+    pub fn page(&self, client_id: Uuid, caller: &str, reason: &str) -> Result<(), &'static str> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.last_page.retain(|_, at| now.duration_since(*at) < PAGE_INTERVAL);
+        if inner.last_page.contains_key(&client_id) {
+            return Err("You paged the sysop less than a minute ago.");
+        }
+        inner.consoles.retain(|console| {
+            console.send(ConsoleMessage::Page { client_id, caller: caller.to_string(), reason: reason.to_string() }).is_ok()
+        });
+        if inner.consoles.is_empty() {
+            return Err("The sysop isn't at the console right now.");
+        }
+        inner.last_page.insert(client_id, now);
+        Ok(())
+    }
+}
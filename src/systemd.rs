@@ -0,0 +1,68 @@
+// sd_notify for `Type=notify` units: READY=1 once callers can connect, and
+// WATCHDOG=1 from the manager loop when the unit sets WatchdogSec=. Outside
+// systemd NOTIFY_SOCKET is unset and all of this does nothing.
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use crate::log;
+
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(error) = send(&path, state) {
+        log!(Server, Warn, "Unable to notify systemd at {}: {}", path, error);
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    // An '@' prefix means a socket in the abstract namespace.
+    let address = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        _ => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Pings systemd at half the configured watchdog interval.
+pub struct Watchdog {
+    interval: Duration,
+    last_ping: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Option<Self> {
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        // WATCHDOG_PID is set when the unit's main process isn't us, e.g. a wrapper script.
+        if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) {
+            if pid != std::process::id() {
+                return None;
+            }
+        }
+        log!(Server, Info, "systemd watchdog enabled, pinging every {} ms", usec / 2000);
+        Some(Self { interval: Duration::from_micros(usec) / 2, last_ping: None })
+    }
+
+    pub fn tick(&mut self) {
+        if self.last_ping.is_some_and(|at| at.elapsed() < self.interval) {
+            return;
+        }
+        self.last_ping = Some(Instant::now());
+        notify("WATCHDOG=1");
+    }
+}
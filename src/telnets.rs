@@ -0,0 +1,228 @@
+// The [tls] listener: telnet over TLS for clients that support it, such as
+// SyncTERM and NetRunner. Each caller is decrypted here and handed to the
+// client manager over a loopback connection, so sessions run as they do for
+// plain telnet. The caller's address and the host name they asked for (SNI)
+// travel alongside, since the loopback socket's peer is ourselves.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+
+use crate::config::TlsConfig;
+use crate::handover;
+use crate::log;
+use crate::slots::{Slot, Slots};
+use crate::tls::{TlsAcceptor, TlsStream};
+use crate::{connect, ClientManagerMessage, Forwarded};
+
+// A caller that hasn't finished the handshake by then is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Callers in the handshake at once, each on a thread that may wait out
+// HANDSHAKE_TIMEOUT. Past this, new ones are hung up on.
+static HANDSHAKING: Slots = Slots::new(64);
+// How long the relay waits when neither side has anything to send.
+const RELAY_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+pub fn launch_tls_listener(config: &TlsConfig, client_manager_tx: Sender<ClientManagerMessage>) {
+    let acceptor = match TlsAcceptor::new(&config.certificate, &config.key) {
+        Ok(acceptor) => Arc::new(acceptor),
+        Err(error) => {
+            log!(Server, Error, "Unable to start the TLS listener: {}", error);
+            return;
+        }
+    };
+    let loopback = match Loopback::bind() {
+        Ok(loopback) => Arc::new(loopback),
+        Err(error) => {
+            log!(Server, Error, "Unable to start the TLS listener: {}", error);
+            return;
+        }
+    };
+    let listener = match handover::bind("tls", &config.address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            log!(Server, Error, "Unable to bind TLS listener on {}: {}", config.address, error);
+            return;
+        }
+    };
+    log!(Server, Info, "TLS Server Listening on: {}", config.address);
+    let _ = thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let Some(slot) = HANDSHAKING.take() else {
+                let peer = stream.peer_addr().map_or_else(|_| String::from("an unknown address"), |peer| peer.to_string());
+                log!(Server, Warn, "Refused TLS connection from {}: {} callers are already in the handshake", peer, HANDSHAKING.max());
+                continue;
+            };
+            let acceptor = acceptor.clone();
+            let loopback = loopback.clone();
+            let client_manager_tx = client_manager_tx.clone();
+            let _ = thread::spawn(move || serve_caller(stream, slot, &acceptor, &loopback, &client_manager_tx));
+        }
+    });
+}
+
+// Holds `handshake` until the handshake is over, one way or the other.
+fn serve_caller(stream: TcpStream, handshake: Slot, acceptor: &TlsAcceptor, loopback: &Loopback, client_manager_tx: &Sender<ClientManagerMessage>) {
+    let Ok(peer) = stream.peer_addr() else {
+        return;
+    };
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT));
+    let accepted = acceptor.accept(stream);
+    drop(handshake);
+    let mut tls = match accepted {
+        Ok(tls) => tls,
+        Err(error) => {
+            log!(Server, Warn, "TLS handshake with {} failed: {}", peer, error);
+            return;
+        }
+    };
+    let server_name = tls.server_name();
+    log!(Server, Info, "TLS connection from {} for {}", peer, server_name.as_deref().unwrap_or("no host name"));
+    let (near, far) = match loopback.pair() {
+        Ok(pair) => pair,
+        Err(error) => {
+            log!(Server, Warn, "Unable to hand the TLS connection from {} to a session: {}", peer, error);
+            return;
+        }
+    };
+    let ready = [far.set_nonblocking(true), near.set_nonblocking(true), tls.get_ref().set_nonblocking(true)];
+    if let Some(Err(error)) = ready.into_iter().find(Result::is_err) {
+        log!(Server, Warn, "Unable to relay the TLS connection from {}: {}", peer, error);
+        return;
+    }
+    let forwarded = Forwarded { ip_addr: peer.ip(), port: peer.port(), server_name };
+    connect(client_manager_tx, far, "tls", Some(forwarded));
+    relay(&mut tls, near);
+}
+
+// Copies between the caller and their session until either side closes.
+fn relay(tls: &mut TlsStream, mut session: TcpStream) {
+    let mut buffer = [0u8; 4096];
+    loop {
+        let mut idle = true;
+        match tls.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                if write_all(&mut session, &buffer[..size]).is_err() {
+                    break;
+                }
+                idle = false;
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+        match session.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                if write_all(tls, &buffer[..size]).is_err() {
+                    break;
+                }
+                idle = false;
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+        if idle {
+            sleep(RELAY_POLL_INTERVAL);
+        }
+    }
+    let _ = session.shutdown(Shutdown::Both);
+}
+
+// write_all for non-blocking sockets: waits out WouldBlock rather than
+// failing.
+fn write_all(writer: &mut impl Write, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(written) => data = &data[written..],
+            Err(error) if error.kind() == ErrorKind::WouldBlock => sleep(RELAY_POLL_INTERVAL),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+// Hands out connected pairs of sockets over 127.0.0.1.
+struct Loopback {
+    listener: Mutex<TcpListener>,
+    address: SocketAddr,
+}
+
+impl Loopback {
+    fn bind() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        Ok(Self { listener: Mutex::new(listener), address })
+    }
+
+    fn pair(&self) -> io::Result<(TcpStream, TcpStream)> {
+        let listener = self.listener.lock().unwrap();
+        let near = TcpStream::connect(self.address)?;
+        let local = near.local_addr()?;
+        // Anything else on this host could connect to the port too.
+        loop {
+            let (far, peer) = listener.accept()?;
+            if peer == local {
+                return Ok((near, far));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::tests::TestCertificate;
+    use crossbeam_channel::bounded;
+
+    #[test]
+    fn relays_a_decrypted_caller_to_a_session_with_where_they_came_from() {
+        let test = TestCertificate::new("telnets", &["bbs.example"]);
+        let acceptor = TlsAcceptor::new(&test.certificate, &test.key).unwrap();
+        let loopback = Loopback::bind().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client_manager_tx, client_manager_rx) = bounded(1);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_caller(stream, HANDSHAKING.take().unwrap(), &acceptor, &loopback, &client_manager_tx);
+        });
+
+        let tcp = TcpStream::connect(address).unwrap();
+        let caller = tcp.local_addr().unwrap();
+        let mut client = test.connect(tcp, "bbs.example").unwrap();
+        let Ok(ClientManagerMessage::Connect { stream: mut session, listener, forwarded }) = client_manager_rx.recv_timeout(Duration::from_secs(5)) else {
+            panic!("no session was started");
+        };
+        assert_eq!(listener, "tls");
+        let forwarded = forwarded.unwrap();
+        assert_eq!((forwarded.ip_addr, forwarded.port), (caller.ip(), caller.port()));
+        assert_eq!(forwarded.server_name.as_deref(), Some("bbs.example"));
+
+        session.set_nonblocking(false).unwrap();
+        session.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"hello").unwrap();
+        client.flush().unwrap();
+        let mut received = [0u8; 5];
+        session.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"hello");
+        session.write_all(b"Welcome").unwrap();
+        let mut received = [0u8; 7];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"Welcome");
+
+        // The caller hanging up ends the session's connection too.
+        drop(client);
+        let mut rest = Vec::new();
+        session.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        server.join().unwrap();
+    }
+}
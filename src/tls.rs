@@ -0,0 +1,280 @@
+// TLS through rustls: the client side for talking to https endpoints, and the
+// server side for the [tls] listener. As a client, certificates are checked
+// against the system trust store and the host name.
+
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::log;
+
+// Read from the system once, for every https request after.
+static CLIENT_CONFIG: OnceLock<Result<Arc<ClientConfig>, Error>> = OnceLock::new();
+
+#[derive(Clone, Debug)]
+pub struct Error(pub String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tls: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rustls::Error> for Error {
+    fn from(error: rustls::Error) -> Self {
+        Error(error.to_string())
+    }
+}
+
+pub struct TlsStream {
+    stream: Stream,
+}
+
+enum Stream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl TlsStream {
+    pub fn connect(tcp: TcpStream, host: &str) -> Result<Self, Error> {
+        let config = CLIENT_CONFIG.get_or_init(client_config).clone()?;
+        Self::connect_with(tcp, host, config)
+    }
+
+    fn connect_with(mut tcp: TcpStream, host: &str, config: Arc<ClientConfig>) -> Result<Self, Error> {
+        let name = ServerName::try_from(host.to_string()).map_err(|_| Error(format!("invalid host name '{}'", host)))?;
+        let mut connection = ClientConnection::new(config, name)?;
+        handshake(&mut connection, &mut tcp)?;
+        Ok(Self { stream: Stream::Client(StreamOwned::new(connection, tcp)) })
+    }
+
+    // The host name the client asked for with SNI, if it sent one.
+    pub fn server_name(&self) -> Option<String> {
+        match &self.stream {
+            Stream::Server(stream) => stream.conn.server_name().map(str::to_string),
+            Stream::Client(_) => None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        match &self.stream {
+            Stream::Client(stream) => stream.get_ref(),
+            Stream::Server(stream) => stream.get_ref(),
+        }
+    }
+}
+
+// The server side: a certificate and key, loaded once and used for every
+// connection accepted.
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    pub fn new(certificate: &Path, key: &Path) -> Result<Self, Error> {
+        let chain = CertificateDer::pem_file_iter(certificate)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|error| Error(format!("{}: {}", certificate.display(), error)))?;
+        if chain.is_empty() {
+            return Err(Error(format!("{}: no certificates", certificate.display())));
+        }
+        let key_der = PrivateKeyDer::from_pem_file(key).map_err(|error| Error(format!("{}: {}", key.display(), error)))?;
+        // Also checks that the key is the certificate's.
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(chain, key_der)?;
+        Ok(Self { config: Arc::new(config) })
+    }
+
+    // Runs the server handshake on a freshly accepted connection.
+    pub fn accept(&self, mut tcp: TcpStream) -> Result<TlsStream, Error> {
+        let mut connection = ServerConnection::new(self.config.clone())?;
+        handshake(&mut connection, &mut tcp)?;
+        Ok(TlsStream { stream: Stream::Server(StreamOwned::new(connection, tcp)) })
+    }
+}
+
+fn client_config() -> Result<Arc<ClientConfig>, Error> {
+    let native = rustls_native_certs::load_native_certs();
+    for error in &native.errors {
+        log!(Server, Warn, "Unable to read a trusted certificate: {}", error);
+    }
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        return Err(Error(String::from("no trusted certificates found on this system")));
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+// On the blocking socket the connection starts on, so a failure is reported
+// here rather than by the first read or write.
+fn handshake<T>(connection: &mut ConnectionCommon<T>, tcp: &mut TcpStream) -> Result<(), Error> {
+    while connection.is_handshaking() {
+        connection.complete_io(tcp).map_err(|error| Error(format!("handshake: {}", error)))?;
+    }
+    Ok(())
+}
+
+// On a non-blocking socket, a read or write that has to wait for the network
+// is WouldBlock, as for a plain socket.
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.stream {
+            Stream::Client(stream) => stream.read(buf),
+            Stream::Server(stream) => stream.read(buf),
+        };
+        match read {
+            // Plenty of peers just close the socket without a close_notify.
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            read => read,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stream {
+            Stream::Client(stream) => stream.write(buf),
+            Stream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Stream::Client(stream) => stream.flush(),
+            Stream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+// Says goodbye to the peer, as far as the socket will take it without waiting.
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        match &mut self.stream {
+            Stream::Client(stream) => close_notify(&mut stream.conn, &mut stream.sock),
+            Stream::Server(stream) => close_notify(&mut stream.conn, &mut stream.sock),
+        }
+    }
+}
+
+fn close_notify<T>(connection: &mut ConnectionCommon<T>, tcp: &mut TcpStream) {
+    connection.send_close_notify();
+    while connection.wants_write() {
+        if connection.write_tls(tcp).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    // A self-signed certificate for the given names, written out as PEM.
+    pub(crate) struct TestCertificate {
+        pub(crate) certificate: PathBuf,
+        pub(crate) key: PathBuf,
+        der: CertificateDer<'static>,
+    }
+
+    impl TestCertificate {
+        pub(crate) fn new(name: &str, hosts: &[&str]) -> Self {
+            let generated = rcgen::generate_simple_self_signed(hosts.iter().map(|host| host.to_string()).collect::<Vec<_>>()).unwrap();
+            let base = std::env::temp_dir().join(format!("triserver-tls-{}-{}", name, std::process::id()));
+            let certificate = base.with_extension("crt");
+            let key = base.with_extension("key");
+            fs::write(&certificate, generated.cert.pem()).unwrap();
+            fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+            Self { certificate, key, der: generated.cert.der().clone() }
+        }
+
+        // A client that trusts only this certificate.
+        pub(crate) fn client(&self) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.der.clone()).unwrap();
+            Arc::new(ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth())
+        }
+
+        pub(crate) fn connect(&self, tcp: TcpStream, host: &str) -> Result<TlsStream, Error> {
+            TlsStream::connect_with(tcp, host, self.client())
+        }
+    }
+
+    impl Drop for TestCertificate {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.certificate);
+            let _ = fs::remove_file(&self.key);
+        }
+    }
+
+    // Accepts one connection on a thread and echoes a line back on it,
+    // returning the host name the client asked for.
+    fn echo_server(acceptor: TlsAcceptor) -> (std::net::SocketAddr, thread::JoinHandle<Result<Option<String>, Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut tls = acceptor.accept(listener.accept().unwrap().0)?;
+            let mut line = [0u8; 5];
+            tls.read_exact(&mut line).unwrap();
+            tls.write_all(&line).unwrap();
+            tls.flush().unwrap();
+            Ok(tls.server_name())
+        });
+        (address, server)
+    }
+
+    #[test]
+    fn shakes_hands_and_carries_data_both_ways() {
+        let test = TestCertificate::new("handshake", &["bbs.example"]);
+        let (address, server) = echo_server(TlsAcceptor::new(&test.certificate, &test.key).unwrap());
+        let mut client = test.connect(TcpStream::connect(address).unwrap(), "bbs.example").unwrap();
+        client.write_all(b"HELLO").unwrap();
+        let mut echoed = [0u8; 5];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"HELLO");
+        assert_eq!(server.join().unwrap().unwrap().as_deref(), Some("bbs.example"));
+        // A close without close_notify reads as the end.
+        assert_eq!(client.read(&mut echoed).unwrap(), 0);
+    }
+
+    #[test]
+    fn refuses_a_certificate_for_another_host() {
+        let test = TestCertificate::new("wrong-host", &["bbs.example"]);
+        let (address, server) = echo_server(TlsAcceptor::new(&test.certificate, &test.key).unwrap());
+        let error = test.connect(TcpStream::connect(address).unwrap(), "other.example").err().unwrap();
+        assert!(error.0.contains("certificate"), "{}", error);
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn refuses_a_key_that_isnt_the_certificates() {
+        let one = TestCertificate::new("mismatch-one", &["bbs.example"]);
+        let other = TestCertificate::new("mismatch-other", &["bbs.example"]);
+        assert!(TlsAcceptor::new(&one.certificate, &other.key).is_err());
+        assert!(TlsAcceptor::new(&one.certificate, &PathBuf::from("/nonexistent/key.pem")).is_err());
+        assert!(TlsAcceptor::new(&one.key, &one.key).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+// `TriServer --tui`: the server in the foreground with a sysop console on
+// the terminal in place of a scroll of log lines. It shows the sessions
+// (node, address, terminal, idle time and bytes each way), how the backends
+// are doing and the latest log lines, redrawn twice a second, and keys kick,
+// spy on and broadcast to callers. Quitting the console shuts the server
+// down, as `shutdown 0` would.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IsTerminal, Stdout, Write};
+use std::iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, TryRecvError};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::cursor::{Hide, MoveTo, Show};
+use ratatui::crossterm::event::{self as terminal_event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::style::{Attribute, Print, SetAttribute};
+use ratatui::crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::crossterm::execute;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Cell, Paragraph, Row, Table, TableState};
+use ratatui::Terminal;
+use uuid::Uuid;
+
+use crate::codec::cp437_to_utf8;
+use crate::config::BackendConfig;
+use crate::events::Event;
+use crate::faults::BackendFaults;
+use crate::queries::ManagerStats;
+use crate::span::Span;
+use crate::{log, version, ClientConnection, ServerContext, SessionControl};
+
+const REFRESH: Duration = Duration::from_millis(500);
+// While spying, the caller's output is shown as it comes.
+const SPY_REFRESH: Duration = Duration::from_millis(50);
+const LOG_LINES: usize = 1000;
+// How long the result of a kick or broadcast stays in the footer.
+const STATUS_TIME: Duration = Duration::from_secs(5);
+const HELP: &str = "Up/Down pick a session   k kick   s spy   b broadcast   q quit";
+
+// The running console; closing it gives the terminal back.
+pub struct Console {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Console {
+    pub fn close(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+// Takes over the terminal, which must be one, and starts drawing.
+pub fn launch(context: ServerContext) -> io::Result<Console> {
+    let mut terminal = RawTerminal::enter()?;
+    let (log_tx, log_rx) = unbounded();
+    log::capture(Some(log_tx));
+    let (key_tx, key_rx) = unbounded();
+    let _ = thread::spawn(move || {
+        while let Ok(event) = terminal_event::read() {
+            if let Some(key) = key(event) {
+                if key_tx.send(key).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let events = context.events.subscribe();
+    let thread = thread::spawn(move || {
+        let mut screen = Screen { context, mode: Mode::Sessions, selected: None, traffic: HashMap::new(), log: VecDeque::new(),
+                                  status: None, clear: false };
+        while !stopped.load(Ordering::Relaxed) {
+            let refresh = if matches!(screen.mode, Mode::Spying { .. }) { SPY_REFRESH } else { REFRESH };
+            let pressed: Vec<Key> = match key_rx.recv_timeout(refresh) {
+                Ok(pressed) => iter::once(pressed).chain(key_rx.try_iter()).collect(),
+                Err(RecvTimeoutError::Timeout) => Vec::new(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            for line in log_rx.try_iter() {
+                if screen.log.len() == LOG_LINES {
+                    screen.log.pop_front();
+                }
+                screen.log.push_back(line);
+            }
+            screen.count(events.try_iter());
+            let mut quit = false;
+            for key in pressed {
+                quit |= screen.press(key);
+            }
+            if quit {
+                break;
+            }
+            if screen.draw(&mut terminal.terminal).is_err() {
+                break;
+            }
+        }
+        drop(terminal);
+        log::capture(None);
+    });
+    Ok(Console { stop, thread })
+}
+
+// Puts the terminal back as it was when dropped.
+struct RawTerminal {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Err(io::Error::other("stdin and stdout must be a terminal"));
+        }
+        terminal::enable_raw_mode()?;
+        // The alternate screen, so the shell's scrollback is there again afterwards.
+        let mut stdout = io::stdout();
+        if let Err(error) = execute!(stdout, EnterAlternateScreen, Hide) {
+            let _ = terminal::disable_raw_mode();
+            return Err(error);
+        }
+        Ok(Self { terminal: Terminal::new(CrosstermBackend::new(stdout))? })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(self.terminal.backend_mut(), SetAttribute(Attribute::Reset), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    Enter,
+    Escape,
+    Backspace,
+    // Ctrl-C, which raw mode no longer turns into a signal.
+    Interrupt,
+    Char(char),
+}
+
+// The key pressed, for the ones the console uses.
+fn key(event: terminal_event::Event) -> Option<Key> {
+    let terminal_event::Event::Key(KeyEvent { code, modifiers, kind: KeyEventKind::Press, .. }) = event else {
+        return None;
+    };
+    Some(match code {
+        KeyCode::Up => Key::Up,
+        KeyCode::Down => Key::Down,
+        KeyCode::Enter => Key::Enter,
+        KeyCode::Esc => Key::Escape,
+        KeyCode::Backspace => Key::Backspace,
+        KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => Key::Interrupt,
+        KeyCode::Char(c) if !modifiers.contains(KeyModifiers::CONTROL) => Key::Char(c),
+        _ => return None,
+    })
+}
+
+enum Mode {
+    Sessions,
+    // Asking before something that can't be taken back.
+    Confirm { question: String, action: Action },
+    // Typing a message for every caller.
+    Broadcast(String),
+    Spying { node: u32, output: Receiver<Vec<u8>> },
+}
+
+enum Action {
+    Kick(Uuid),
+    Quit,
+}
+
+// Bytes relayed for a session so far, from the event bus.
+struct Traffic {
+    to_backend: u64,
+    to_client: u64,
+    last: Instant,
+}
+
+struct Screen {
+    context: ServerContext,
+    mode: Mode,
+    selected: Option<Uuid>,
+    traffic: HashMap<Uuid, Traffic>,
+    log: VecDeque<String>,
+    status: Option<(String, Instant)>,
+    // Spying wrote to the terminal behind ratatui's back, so the next frame
+    // is drawn in full.
+    clear: bool,
+}
+
+impl Screen {
+    fn count(&mut self, events: impl Iterator<Item = Event>) {
+        for event in events {
+            match event {
+                Event::BytesRelayed { client_id, to_backend, to_client } => {
+                    let traffic = self.traffic.entry(client_id).or_insert(Traffic { to_backend: 0, to_client: 0, last: Instant::now() });
+                    traffic.to_backend += to_backend;
+                    traffic.to_client += to_client;
+                    traffic.last = Instant::now();
+                }
+                Event::Closed { session, .. } => {
+                    self.traffic.remove(&session.client_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The sessions in node order.
+    fn sessions(&self) -> Vec<ClientConnection> {
+        let mut sessions = self.context.clients.values();
+        sessions.sort_by_key(|client| client.node);
+        sessions
+    }
+
+    fn selected(&self) -> Option<ClientConnection> {
+        let sessions = self.sessions();
+        let selected = self.selected.and_then(|id| sessions.iter().find(|client| client.client_id == id));
+        selected.or(sessions.first()).cloned()
+    }
+
+    fn report(&mut self, status: String) {
+        self.status = Some((status, Instant::now()));
+    }
+
+    // True once the sysop has said to quit.
+    fn press(&mut self, key: Key) -> bool {
+        match std::mem::replace(&mut self.mode, Mode::Sessions) {
+            Mode::Sessions => self.press_on_sessions(key),
+            Mode::Confirm { question, action } => match key {
+                Key::Char('y' | 'Y') => match action {
+                    Action::Kick(client_id) => self.kick(client_id),
+                    Action::Quit => {
+                        log!(Admin, Info, "Shutdown from the console");
+                        self.context.shutdown.schedule(Duration::ZERO);
+                        return true;
+                    }
+                },
+                Key::Char('n' | 'N') | Key::Escape | Key::Interrupt => {}
+                _ => self.mode = Mode::Confirm { question, action },
+            },
+            Mode::Broadcast(mut message) => match key {
+                Key::Enter if !message.trim().is_empty() => self.broadcast(message.trim()),
+                Key::Enter | Key::Escape | Key::Interrupt => {}
+                Key::Backspace => {
+                    message.pop();
+                    self.mode = Mode::Broadcast(message);
+                }
+                Key::Char(c) => {
+                    message.push(c);
+                    self.mode = Mode::Broadcast(message);
+                }
+                _ => self.mode = Mode::Broadcast(message),
+            },
+            // Dropping the receiver ends the spying.
+            Mode::Spying { node, output } => match key {
+                Key::Escape | Key::Interrupt => self.clear = true,
+                _ => self.mode = Mode::Spying { node, output },
+            },
+        }
+        false
+    }
+
+    fn press_on_sessions(&mut self, key: Key) {
+        match key {
+            Key::Up | Key::Down => {
+                let sessions = self.sessions();
+                let at = self.selected.and_then(|id| sessions.iter().position(|client| client.client_id == id)).unwrap_or(0);
+                let at = if key == Key::Up { at.saturating_sub(1) } else { (at + 1).min(sessions.len().saturating_sub(1)) };
+                self.selected = sessions.get(at).map(|client| client.client_id);
+            }
+            Key::Char('k') => match self.selected() {
+                Some(client) => {
+                    let question = format!("Kick node {} ({})?", client.node, client.ip_addr);
+                    self.mode = Mode::Confirm { question, action: Action::Kick(client.client_id) };
+                }
+                None => self.report(String::from("There's no one to kick.")),
+            },
+            Key::Char('s') => match self.selected() {
+                Some(client) => {
+                    let (viewer, output) = unbounded();
+                    if client.control.send(SessionControl::Spy { viewer }).is_ok() {
+                        self.mode = Mode::Spying { node: client.node, output };
+                        let _ = execute!(io::stdout(), Clear(ClearType::All), MoveTo(0, 0), SetAttribute(Attribute::Reverse),
+                                         Print(format!(" Watching node {} ({}); Esc to go back ", client.node, client.ip_addr)),
+                                         SetAttribute(Attribute::Reset), Print("\r\n"));
+                    }
+                }
+                None => self.report(String::from("There's no one to spy on.")),
+            },
+            Key::Char('b') => self.mode = Mode::Broadcast(String::new()),
+            Key::Char('q') | Key::Interrupt => {
+                self.mode = Mode::Confirm { question: String::from("Shut the server down?"), action: Action::Quit };
+            }
+            _ => {}
+        }
+    }
+
+    fn kick(&mut self, client_id: Uuid) {
+        let status = match self.context.manager.disconnect(client_id, "You have been disconnected by the sysop.") {
+            Ok(Some(client)) => {
+                log!(Admin, Info, span = client.span(), "kicked from the console");
+                format!("Kicked node {}.", client.node)
+            }
+            Ok(None) => String::from("That caller has already gone."),
+            Err(error) => error,
+        };
+        self.report(status);
+    }
+
+    fn broadcast(&mut self, message: &str) {
+        let status = match self.context.manager.broadcast(message) {
+            Ok(sessions) => {
+                log!(Admin, Info, "Broadcast to {} session(s) from the console: {}", sessions, message);
+                format!("Sent to {} session(s).", sessions)
+            }
+            Err(error) => error,
+        };
+        self.report(status);
+    }
+
+    fn draw(&mut self, terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> io::Result<()> {
+        if let Mode::Spying { node, output } = &self.mode {
+            let mut stdout = io::stdout().lock();
+            loop {
+                match output.try_recv() {
+                    Ok(data) => {
+                        let text = std::str::from_utf8(&data).map_or_else(|_| cp437_to_utf8(&data), str::to_string);
+                        stdout.write_all(text.as_bytes())?;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        let status = format!("Node {} has hung up.", node);
+                        self.mode = Mode::Sessions;
+                        self.clear = true;
+                        self.report(status);
+                        break;
+                    }
+                }
+            }
+            stdout.flush()?;
+            if matches!(self.mode, Mode::Spying { .. }) {
+                return Ok(());
+            }
+        }
+        if std::mem::take(&mut self.clear) {
+            terminal.clear()?;
+        }
+
+        let sessions = self.sessions();
+        let selected = self.selected().map(|client| client.client_id);
+        let backends = self.context.live.current().backends.clone();
+        let health = self.context.health.backends();
+
+        let title = title(&self.context.manager.stats(), self.context.started.elapsed());
+
+        let now = Instant::now();
+        let session_rows: Vec<Row> = sessions.iter()
+            .map(|client| row(session_row(client, self.traffic.get(&client.client_id), now), &[0, 7, 8, 9]))
+            .collect();
+        let sessions_table = Table::new(session_rows, [4, 8, 24, 14, 14, 12, 11, 6, 8, 8].map(Constraint::Length))
+            .header(Row::new([right("node"), "session".into(), "address".into(), "terminal".into(), "backend".into(), "user".into(),
+                              "state".into(), right("idle"), right("in"), right("out")]).bold())
+            .row_highlight_style(Style::new().reversed());
+        let mut sessions_state = TableState::default().with_selected(sessions.iter().position(|client| Some(client.client_id) == selected));
+
+        let backend_rows: Vec<Row> = backends.iter().map(|backend| {
+            let healthy = health.iter().find(|status| status.name == backend.name).and_then(|status| status.healthy);
+            let on = sessions.iter().filter(|client| client.backend.as_deref() == Some(&backend.name[..])).count();
+            row(backend_row(backend, healthy, on, self.context.faults.backend(&backend.name)), &[3, 4, 5, 6])
+        }).collect();
+        let backends_table = Table::new(backend_rows, [16, 28, 6, 8, 8, 8, 6].map(Constraint::Length).into_iter().chain([Constraint::Fill(1)]))
+            .header(Row::new(["backend".into(), "address".into(), "health".into(), right("sessions"), right("failures"), right("timeouts"),
+                              right("drops"), "last fault".into()]).bold());
+
+        if self.status.as_ref().is_some_and(|(_, at)| at.elapsed() > STATUS_TIME) {
+            self.status = None;
+        }
+        let footer = footer(&self.mode, self.status.as_ref().map(|(status, _)| status.as_str()));
+
+        let log = &self.log;
+        terminal.draw(|frame| {
+            // The log gets whatever the tables leave, but the sessions no more than half.
+            let fixed = 5 + backends.len();
+            let session_height = sessions.len().max(1).min((frame.area().height as usize).saturating_sub(fixed) / 2).max(1);
+            let [title_area, sessions_area, backends_area, log_heading_area, log_area, footer_area] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Length(session_height as u16 + 1),
+                Constraint::Length(backends.len() as u16 + 1),
+                Constraint::Length(1),
+                Constraint::Fill(1),
+                Constraint::Length(1),
+            ]).areas(frame.area());
+
+            // Bars run the width of the screen.
+            frame.render_widget(Paragraph::new(title).reversed(), title_area);
+            frame.render_stateful_widget(sessions_table, sessions_area, &mut sessions_state);
+            if sessions.is_empty() {
+                let [_, empty_area] = Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(sessions_area);
+                frame.render_widget(Paragraph::new("   No one is connected."), empty_area);
+            }
+            frame.render_widget(backends_table, backends_area);
+            frame.render_widget(Paragraph::new("log").bold(), log_heading_area);
+            let skipped = log.len().saturating_sub(log_area.height as usize);
+            frame.render_widget(Paragraph::new(log.iter().skip(skipped).map(|line| Line::from(line.as_str())).collect::<Vec<_>>()), log_area);
+            frame.render_widget(Paragraph::new(footer).reversed(), footer_area);
+        })?;
+        Ok(())
+    }
+}
+
+// A cell with its text to the right, for numbers.
+fn right<'a>(text: impl Into<String>) -> Cell<'a> {
+    Cell::from(Line::from(text.into()).right_aligned())
+}
+
+// A table row, with the columns listed in `numbers` to the right.
+fn row<'a, const N: usize>(columns: [String; N], numbers: &[usize]) -> Row<'a> {
+    Row::new(columns.into_iter().enumerate().map(|(i, text)| if numbers.contains(&i) { right(text) } else { Cell::from(text) }))
+}
+
+fn title(stats: &Result<ManagerStats, String>, uptime: Duration) -> String {
+    match stats {
+        Ok(stats) => format!(" {} | up {} | {} session(s) | {} accepted, {} refused", version::describe(), describe_duration(uptime),
+                             stats.sessions, stats.accepted, stats.refused),
+        Err(error) => format!(" {} | {}", version::describe(), error),
+    }
+}
+
+// Node, session, address, terminal, backend, user, state, idle, in and out.
+fn session_row(client: &ClientConnection, traffic: Option<&Traffic>, now: Instant) -> [String; 10] {
+    let idle = now.saturating_duration_since(traffic.map_or(client.connected_at, |traffic| traffic.last));
+    [client.node.to_string(), Span::short_id(client.client_id), client.ip_addr.to_string(), describe_terminal(client),
+     client.backend.clone().unwrap_or_else(|| String::from("-")), client.username.clone().unwrap_or_else(|| String::from("-")),
+     client.state.to_string(), describe_duration(idle), describe_bytes(traffic.map_or(0, |traffic| traffic.to_backend)),
+     describe_bytes(traffic.map_or(0, |traffic| traffic.to_client))]
+}
+
+// Name, address, health, sessions, failures, timeouts, drops and last fault.
+fn backend_row(backend: &BackendConfig, healthy: Option<bool>, sessions: usize, faults: BackendFaults) -> [String; 8] {
+    let health = match healthy {
+        Some(true) => "up",
+        Some(false) => "down",
+        None => "-",
+    };
+    [backend.name.clone(), backend.address(), String::from(health), sessions.to_string(), faults.connect_failures.to_string(),
+     faults.timeouts.to_string(), faults.drops.to_string(), faults.last.map(|(detail, _)| detail).unwrap_or_default()]
+}
+
+fn footer(mode: &Mode, status: Option<&str>) -> String {
+    match (mode, status) {
+        (Mode::Confirm { question, .. }, _) => format!("{} (y/n)", question),
+        (Mode::Broadcast(message), _) => format!("Broadcast (Enter to send, Esc to cancel): {}_", message),
+        (_, Some(status)) => status.to_string(),
+        _ => String::from(HELP),
+    }
+}
+
+fn describe_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+fn describe_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => bytes.to_string(),
+        1024..1048576 => format!("{:.1}K", bytes as f64 / 1024.0),
+        1048576..1073741824 => format!("{:.1}M", bytes as f64 / 1048576.0),
+        _ => format!("{:.1}G", bytes as f64 / 1073741824.0),
+    }
+}
+
+// The TTYPE and what the probe made of it, whichever were found out.
+fn describe_terminal(client: &ClientConnection) -> String {
+    match (client.terminal.as_deref(), client.terminal_class) {
+        (Some(terminal), Some(class)) => format!("{}/{}", terminal, class.name()),
+        (Some(terminal), None) => terminal.to_string(),
+        (None, Some(class)) => class.name().to_string(),
+        (None, None) => String::from("-"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TerminalClass;
+    use crate::harness;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn describes_durations_and_sizes() {
+        assert_eq!(describe_duration(Duration::from_millis(59_999)), "59s");
+        assert_eq!(describe_duration(Duration::from_secs(65)), "1m05s");
+        assert_eq!(describe_duration(Duration::from_secs(3 * 3600 + 7 * 60 + 30)), "3h07m");
+        assert_eq!(describe_bytes(1023), "1023");
+        assert_eq!(describe_bytes(1536), "1.5K");
+        assert_eq!(describe_bytes(5 * 1048576), "5.0M");
+        assert_eq!(describe_bytes(3 * 1073741824 / 2), "1.5G");
+    }
+
+    #[test]
+    fn shows_a_session_with_what_is_known_of_it() {
+        let (_, mut client) = harness::idle_connection(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 9)));
+        let now = client.connected_at + Duration::from_secs(90);
+        let row = session_row(&client, None, now);
+        assert_eq!(row[0], "1");
+        assert_eq!(row[1], Span::short_id(client.client_id));
+        assert_eq!(row[2..], ["203.0.113.9", "-", "-", "-", "active", "1m30s", "0", "0"]);
+
+        client.terminal = Some(String::from("ANSI"));
+        client.terminal_class = Some(TerminalClass::Ansi);
+        client.backend = Some(String::from("main"));
+        client.username = Some(String::from("sysop"));
+        let traffic = Traffic { to_backend: 12, to_client: 4096, last: client.connected_at + Duration::from_secs(80) };
+        assert_eq!(session_row(&client, Some(&traffic), now)[3..], ["ANSI/ansi", "main", "sysop", "active", "10s", "12", "4.0K"]);
+        client.terminal = None;
+        assert_eq!(session_row(&client, Some(&traffic), now)[3], "ansi");
+    }
+
+    #[test]
+    fn shows_a_backend_with_its_health_and_faults() {
+        let config = harness::config("127.0.0.1:2323".parse().unwrap());
+        let backend = &config.backends[0];
+        assert_eq!(backend_row(backend, None, 0, BackendFaults::default()), ["test", "127.0.0.1:2323", "-", "0", "0", "0", "0", ""]);
+        let faults = BackendFaults { connect_failures: 3, timeouts: 1, drops: 2, last: Some((String::from("connection refused"), 0)) };
+        assert_eq!(backend_row(backend, Some(false), 4, faults), ["test", "127.0.0.1:2323", "down", "4", "3", "1", "2", "connection refused"]);
+        assert_eq!(backend_row(backend, Some(true), 1, BackendFaults::default())[2], "up");
+    }
+
+    #[test]
+    fn titles_and_footers() {
+        let stats = ManagerStats { sessions: 2, accepted: 10, refused: 1, ..ManagerStats::default() };
+        assert!(title(&Ok(stats), Duration::from_secs(3700)).ends_with(" | up 1h01m | 2 session(s) | 10 accepted, 1 refused"));
+        assert!(title(&Err(String::from("no answer")), Duration::ZERO).ends_with(" | no answer"));
+
+        assert_eq!(footer(&Mode::Sessions, None), HELP);
+        assert_eq!(footer(&Mode::Sessions, Some("Kicked node 3.")), "Kicked node 3.");
+        let confirm = Mode::Confirm { question: String::from("Kick node 3 (203.0.113.9)?"), action: Action::Quit };
+        assert_eq!(footer(&confirm, Some("Kicked node 2.")), "Kick node 3 (203.0.113.9)? (y/n)");
+        assert_eq!(footer(&Mode::Broadcast(String::from("brb")), None), "Broadcast (Enter to send, Esc to cancel): brb_");
+    }
+}
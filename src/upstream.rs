@@ -0,0 +1,78 @@
+// A session's connection to its backend: a TCP connection, a local socket or
+// pipe, a local serial device, or an ssh client on a pseudo-terminal.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+use crate::chaos::Segmented;
+use crate::local::LocalSocket;
+use crate::serial::SerialPort;
+use crate::ssh::SshSession;
+
+pub enum Upstream {
+    Tcp(TcpStream),
+    Local(LocalSocket),
+    Serial(SerialPort),
+    Ssh(SshSession),
+}
+
+impl Upstream {
+    // Serial ports and SSH sessions have no half-close, so only shutting down
+    // both ways does anything to one: it is closed.
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match (self, how) {
+            (Upstream::Tcp(stream), how) => stream.shutdown(how),
+            (Upstream::Local(socket), how) => socket.shutdown(how),
+            (Upstream::Serial(_), Shutdown::Read | Shutdown::Write) => Ok(()),
+            (Upstream::Serial(port), Shutdown::Both) => {
+                port.close();
+                Ok(())
+            }
+            (Upstream::Ssh(_), Shutdown::Read | Shutdown::Write) => Ok(()),
+            (Upstream::Ssh(session), Shutdown::Both) => {
+                session.close();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for Upstream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Upstream::Tcp(stream) => stream.read(buffer),
+            Upstream::Local(socket) => socket.read(buffer),
+            Upstream::Serial(port) => port.read(buffer),
+            Upstream::Ssh(session) => session.read(buffer),
+        }
+    }
+}
+
+impl Write for Upstream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Upstream::Tcp(stream) => stream.write(data),
+            Upstream::Local(socket) => socket.write(data),
+            Upstream::Serial(port) => port.write(data),
+            Upstream::Ssh(session) => session.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Upstream::Tcp(stream) => stream.flush(),
+            Upstream::Local(socket) => socket.flush(),
+            Upstream::Serial(port) => port.flush(),
+            Upstream::Ssh(session) => session.flush(),
+        }
+    }
+}
+
+impl Segmented for Upstream {
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Upstream::Tcp(stream) => stream.set_nodelay(nodelay),
+            Upstream::Local(_) | Upstream::Serial(_) | Upstream::Ssh(_) => Ok(()),
+        }
+    }
+}
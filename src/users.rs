@@ -0,0 +1,485 @@
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rusqlite::{params, Connection, Error, OptionalExtension, Row};
+use uuid::Uuid;
+
+use crate::clock::{format_timestamp, local_midnight, unix_time};
+use crate::json::Object;
+use crate::log;
+use crate::sha256;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY COLLATE NOCASE,
+        password TEXT NOT NULL,
+        backend TEXT,
+        time_limit INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS calls (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL COLLATE NOCASE,
+        client_id TEXT NOT NULL,
+        ip_addr TEXT NOT NULL,
+        backend TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        duration INTEGER,
+        -- The run of the server the call is relayed by, to tell one still
+        -- going from one left open by a server that died.
+        run TEXT
+    );
+    CREATE INDEX IF NOT EXISTS calls_by_user ON calls (username, started_at);
+    -- Every server using the database, by the random token it takes on
+    -- starting, and when it last said it was still going.
+    CREATE TABLE IF NOT EXISTS runs (
+        token TEXT PRIMARY KEY,
+        seen_at INTEGER NOT NULL
+    );
+";
+
+// Rounds of the SHA-256 hashes stored before Argon2 was used.
+const LEGACY_HASH_ROUNDS: u32 = 10_000;
+// Checked against when there's no such user, so that takes as long as a
+// wrong password.
+static UNKNOWN_USER_PASSWORD: OnceLock<String> = OnceLock::new();
+// How often a server says it is still going, and how long after it last did
+// its open calls are taken to have been left by one that died.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const RUN_TIMEOUT: Duration = Duration::from_secs(90);
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub username: String,
+    pub backend: Option<String>,
+    // Minutes per day; None falls back to the store's default.
+    pub time_limit: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub id: i64,
+    pub username: String,
+    pub client_id: String,
+    pub ip_addr: String,
+    pub backend: String,
+    pub started_at: u64,
+    pub duration: Option<u64>,
+}
+
+// How `user export` writes the call history out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("expected one of csv, json; found '{}'", value)),
+        }
+    }
+}
+
+pub struct UserStore {
+    db: Mutex<Connection>,
+    default_time_limit: Option<u64>,
+    // This run's token, kept with each call it starts.
+    run: String,
+}
+
+impl UserStore {
+    pub fn open(path: &Path, default_time_limit: Option<u64>) -> Result<Self, Error> {
+        let db = Connection::open(path)?;
+        db.execute_batch(SCHEMA)?;
+        // Databases from before calls had a run.
+        let columns = db.prepare("PRAGMA table_info(calls)")?
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if !columns.iter().any(|column| column == "run") {
+            db.execute_batch("ALTER TABLE calls ADD COLUMN run TEXT")?;
+        }
+        let run = Uuid::new_v4().simple().to_string();
+        db.execute("INSERT INTO runs (token, seen_at) VALUES (?1, ?2)", params![run, unix_time() as i64])?;
+        close_dangling_calls(&db)?;
+        Ok(Self { db: Mutex::new(db), default_time_limit, run })
+    }
+
+    // Says this run is still going, and ends the calls of any that have
+    // stopped saying so since.
+    pub fn keep_alive(&self) -> Result<(), Error> {
+        let db = self.db.lock().unwrap();
+        db.execute("UPDATE runs SET seen_at = ?1 WHERE token = ?2", params![unix_time() as i64, self.run])?;
+        close_dangling_calls(&db)
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<Option<User>, Error> {
+        // The lock is let go before hashing, so one login's hashing doesn't
+        // hold up every other use of the store.
+        let found = self.db.lock().unwrap().query_row(
+            "SELECT username, password, backend, time_limit FROM users WHERE username = ?1",
+            [username],
+            |row| {
+                let user = User {
+                    username: row.get(0)?,
+                    backend: row.get(2)?,
+                    time_limit: row.get::<_, Option<i64>>(3)?.map(|minutes| minutes as u64),
+                };
+                Ok((user, row.get::<_, String>(1)?))
+            },
+        ).optional()?;
+        let Some((user, stored)) = found else {
+            // Hashed all the same, so an unknown name takes as long to turn
+            // away as a wrong password.
+            verify_password(password, UNKNOWN_USER_PASSWORD.get_or_init(|| hash_password("")));
+            return Ok(None);
+        };
+        if !verify_password(password, &stored) {
+            return Ok(None);
+        }
+        if !stored.starts_with("$argon2") {
+            // Now that the password is known, its old hash can be replaced.
+            self.db.lock().unwrap().execute("UPDATE users SET password = ?1 WHERE username = ?2",
+                                            params![hash_password(password), user.username])?;
+        }
+        Ok(Some(user))
+    }
+
+    pub fn add_user(&self, username: &str, password: &str, backend: Option<&str>, time_limit: Option<u64>) -> Result<(), Error> {
+        let password = hash_password(password);
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT INTO users (username, password, backend, time_limit) VALUES (?1, ?2, ?3, ?4)",
+            params![username, password, backend, time_limit.map(|minutes| minutes as i64)],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_user(&self, username: &str) -> Result<bool, Error> {
+        let db = self.db.lock().unwrap();
+        Ok(db.execute("DELETE FROM users WHERE username = ?1", [username])? > 0)
+    }
+
+    // Time the user may still spend online today, or None if unlimited.
+    pub fn time_remaining(&self, user: &User) -> Result<Option<Duration>, Error> {
+        let Some(limit) = user.time_limit.or(self.default_time_limit) else {
+            return Ok(None);
+        };
+        let now = unix_time();
+        let start_of_day = local_midnight(now);
+        let db = self.db.lock().unwrap();
+        // Calls still open, such as one held for resume, count up to now;
+        // those of a server that died were ended when the store was opened.
+        let used: i64 = db.query_row(
+            "SELECT COALESCE(SUM(COALESCE(duration, MAX(?3 - started_at, 0))), 0) FROM calls WHERE username = ?1 AND started_at >= ?2",
+            params![user.username, start_of_day as i64, now as i64],
+            |row| row.get(0),
+        )?;
+        let used = used as u64;
+        Ok(Some(Duration::from_secs((limit * 60).saturating_sub(used))))
+    }
+
+    pub fn record_call_start(&self, user: &User, client_id: Uuid, ip_addr: IpAddr, backend: &str) -> Result<i64, Error> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT INTO calls (username, client_id, ip_addr, backend, started_at, run) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![user.username, client_id.to_string(), ip_addr.to_string(), backend, unix_time() as i64, self.run],
+        )?;
+        Ok(db.last_insert_rowid())
+    }
+
+    pub fn record_call_end(&self, call_id: i64, duration: Duration) -> Result<(), Error> {
+        let db = self.db.lock().unwrap();
+        db.execute("UPDATE calls SET duration = ?1 WHERE id = ?2", params![duration.as_secs() as i64, call_id])?;
+        Ok(())
+    }
+
+    pub fn calls(&self, username: Option<&str>, limit: usize) -> Result<Vec<Call>, Error> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT id, username, client_id, ip_addr, backend, started_at, duration FROM calls
+             WHERE ?1 IS NULL OR username = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let calls = statement.query_map(params![username, limit as i64], call_from_row)?.collect();
+        calls
+    }
+
+    // Every call started at or after `since` and before `until`, oldest first.
+    pub fn calls_between(&self, since: Option<u64>, until: Option<u64>) -> Result<Vec<Call>, Error> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT id, username, client_id, ip_addr, backend, started_at, duration FROM calls
+             WHERE (?1 IS NULL OR started_at >= ?1) AND (?2 IS NULL OR started_at < ?2) ORDER BY id",
+        )?;
+        let calls = statement.query_map(params![since.map(|since| since as i64), until.map(|until| until as i64)], call_from_row)?.collect();
+        calls
+    }
+}
+
+fn call_from_row(row: &Row) -> Result<Call, Error> {
+    Ok(Call {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        client_id: row.get(2)?,
+        ip_addr: row.get(3)?,
+        backend: row.get(4)?,
+        started_at: row.get::<_, i64>(5)? as u64,
+        duration: row.get::<_, Option<i64>>(6)?.map(|secs| secs as u64),
+    })
+}
+
+// Writes calls out for a spreadsheet or a script: CSV with a header row, or a
+// JSON array of objects. Start times are UTC, both as text and Unix time; the
+// duration is in seconds, and empty (or null) for a call still online.
+pub fn export_calls(calls: &[Call], format: ExportFormat, out: &mut impl Write) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut *out);
+            writer.write_record(["id", "username", "client_id", "ip_addr", "backend", "started", "started_at", "duration"])?;
+            for call in calls {
+                writer.write_record([&call.id.to_string(), &call.username, &call.client_id, &call.ip_addr, &call.backend,
+                                     &format_timestamp(call.started_at), &call.started_at.to_string(),
+                                     &call.duration.map(|secs| secs.to_string()).unwrap_or_default()])?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Json => {
+            writeln!(out, "[")?;
+            for (i, call) in calls.iter().enumerate() {
+                let object = Object::new()
+                    .number("id", call.id as u64)
+                    .string("username", &call.username)
+                    .string("client_id", &call.client_id)
+                    .string("ip_addr", &call.ip_addr)
+                    .string("backend", &call.backend)
+                    .string("started", &format_timestamp(call.started_at))
+                    .number("started_at", call.started_at)
+                    .optional_number("duration", call.duration);
+                writeln!(out, "  {}{}", object.finish(), if i + 1 < calls.len() { "," } else { "" })?;
+            }
+            writeln!(out, "]")?;
+        }
+    }
+    out.flush()
+}
+
+// Keeps the store's run alive for as long as the server runs.
+pub fn launch_heartbeat(store: Arc<UserStore>) {
+    let _ = thread::spawn(move || loop {
+        sleep(HEARTBEAT_INTERVAL);
+        if let Err(error) = store.keep_alive() {
+            log!(Server, Warn, "Unable to update the user database: {}", error);
+        }
+    });
+}
+
+// Ends the calls of servers that died without ending them, by a crash, a
+// SIGKILL or a power cut, so they don't go on using up their user's time: a
+// run that hasn't said it is still going for RUN_TIMEOUT has died, wherever
+// it ran. Calls of other servers still running, such as [workers] or one
+// being upgraded from, are left alone. How long a dead one lasted isn't
+// known, so it's taken as 0.
+fn close_dangling_calls(db: &Connection) -> Result<(), Error> {
+    let given_up = (unix_time().saturating_sub(RUN_TIMEOUT.as_secs())) as i64;
+    db.execute("UPDATE calls SET duration = 0 WHERE duration IS NULL AND (run IS NULL OR run NOT IN (SELECT token FROM runs WHERE seen_at >= ?1))",
+               [given_up])?;
+    db.execute("DELETE FROM runs WHERE seen_at < ?1", [given_up])?;
+    Ok(())
+}
+
+// Stored as an Argon2id PHC string, which carries its own salt and
+// parameters.
+fn hash_password(password: &str) -> String {
+    Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+        .expect("the default Argon2 parameters take any password")
+        .to_string()
+}
+
+// Also takes the "<salt>$<hex digest>" hashes stored before Argon2, the
+// digest being SHA-256 iterated over salt + password.
+fn verify_password(password: &str, stored: &str) -> bool {
+    if let Ok(hash) = PasswordHash::new(stored) {
+        return Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+    }
+    let Some((salt, expected)) = stored.split_once('$') else {
+        return false;
+    };
+    let mut hash = sha256::digest(format!("{}{}", salt, password).as_bytes());
+    for _ in 1..LEGACY_HASH_ROUNDS {
+        hash = sha256::digest(&hash);
+    }
+    // Compare without short-circuiting on the first mismatch.
+    let actual = sha256::to_hex(&hash);
+    actual.len() == expected.len() && actual.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A database file of the test's own, removed when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("triserver-users-{}-{}.db", name, std::process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn caller() -> User {
+        User { username: String::from("sysop"), backend: None, time_limit: Some(60) }
+    }
+
+    #[test]
+    fn lets_in_only_a_known_user_with_their_password() {
+        let scratch = Scratch::new("authenticate");
+        let store = UserStore::open(&scratch.0, None).unwrap();
+        store.add_user("sysop", "secret", None, Some(60)).unwrap();
+        assert_eq!(store.authenticate("SYSOP", "secret").unwrap().map(|user| user.username), Some(String::from("sysop")));
+        assert!(store.authenticate("sysop", "guess").unwrap().is_none());
+        assert!(store.authenticate("nobody", "secret").unwrap().is_none());
+        assert!(store.authenticate("nobody", "").unwrap().is_none());
+    }
+
+    #[test]
+    fn takes_a_hash_from_before_argon2_and_replaces_it() {
+        let scratch = Scratch::new("legacy");
+        let store = UserStore::open(&scratch.0, None).unwrap();
+        let mut digest = sha256::digest(b"0123abcdsecret");
+        for _ in 1..LEGACY_HASH_ROUNDS {
+            digest = sha256::digest(&digest);
+        }
+        let legacy = format!("0123abcd${}", sha256::to_hex(&digest));
+        store.db.lock().unwrap().execute("INSERT INTO users (username, password) VALUES ('sysop', ?1)", [&legacy]).unwrap();
+        let stored = || -> String { store.db.lock().unwrap().query_row("SELECT password FROM users", [], |row| row.get(0)).unwrap() };
+        assert!(store.authenticate("sysop", "guess").unwrap().is_none());
+        assert_eq!(stored(), legacy);
+        assert!(store.authenticate("sysop", "secret").unwrap().is_some());
+        assert!(stored().starts_with("$argon2id$"));
+        assert!(store.authenticate("sysop", "secret").unwrap().is_some());
+        assert!(store.authenticate("sysop", "guess").unwrap().is_none());
+    }
+
+    #[test]
+    fn ends_calls_left_open_by_a_server_that_died() {
+        let scratch = Scratch::new("dangling");
+        let store = UserStore::open(&scratch.0, None).unwrap();
+        store.add_user("sysop", "secret", None, Some(60)).unwrap();
+        let ours = store.record_call_start(&caller(), Uuid::new_v4(), IpAddr::from([127, 0, 0, 1]), "bbs").unwrap();
+        let theirs = store.record_call_start(&caller(), Uuid::new_v4(), IpAddr::from([127, 0, 0, 1]), "bbs").unwrap();
+        let older = store.record_call_start(&caller(), Uuid::new_v4(), IpAddr::from([127, 0, 0, 1]), "bbs").unwrap();
+        {
+            // Started half an hour ago by a run that's gone, and by one
+            // from before calls had a run.
+            let db = store.db.lock().unwrap();
+            let earlier = (unix_time() - 30 * 60) as i64;
+            db.execute("UPDATE calls SET started_at = ?1, run = 'gone' WHERE id = ?2", [earlier, theirs]).unwrap();
+            db.execute("UPDATE calls SET started_at = ?1, run = NULL WHERE id = ?2", [earlier, older]).unwrap();
+        }
+        drop(store);
+
+        let store = UserStore::open(&scratch.0, None).unwrap();
+        let calls = store.calls(Some("sysop"), 10).unwrap();
+        let duration = |id| calls.iter().find(|call| call.id == id).unwrap().duration;
+        assert_eq!(duration(ours), None);
+        assert_eq!(duration(theirs), Some(0));
+        assert_eq!(duration(older), Some(0));
+        assert!(store.time_remaining(&caller()).unwrap().unwrap() > Duration::from_secs(59 * 60));
+    }
+
+    #[test]
+    fn ends_the_calls_of_a_run_that_stopped_keeping_alive() {
+        let scratch = Scratch::new("heartbeat");
+        let stopped = UserStore::open(&scratch.0, None).unwrap();
+        let running = UserStore::open(&scratch.0, None).unwrap();
+        stopped.add_user("sysop", "secret", None, Some(60)).unwrap();
+        let theirs = stopped.record_call_start(&caller(), Uuid::new_v4(), IpAddr::from([127, 0, 0, 1]), "bbs").unwrap();
+        let ours = running.record_call_start(&caller(), Uuid::new_v4(), IpAddr::from([127, 0, 0, 1]), "bbs").unwrap();
+        running.keep_alive().unwrap();
+        assert!(running.calls(Some("sysop"), 10).unwrap().iter().all(|call| call.duration.is_none()));
+
+        let last_seen = (unix_time() - RUN_TIMEOUT.as_secs() - 1) as i64;
+        stopped.db.lock().unwrap().execute("UPDATE runs SET seen_at = ?1 WHERE token = ?2", params![last_seen, stopped.run]).unwrap();
+        running.keep_alive().unwrap();
+        let calls = running.calls(Some("sysop"), 10).unwrap();
+        let duration = |id| calls.iter().find(|call| call.id == id).unwrap().duration;
+        assert_eq!(duration(theirs), Some(0));
+        assert_eq!(duration(ours), None);
+    }
+
+    fn finished_and_online() -> [Call; 2] {
+        [
+            Call { id: 1, username: String::from("sysop"), client_id: String::from("c1"), ip_addr: String::from("192.0.2.1"),
+                   backend: String::from("bbs"), started_at: 1_700_000_000, duration: Some(90) },
+            Call { id: 2, username: String::from("Bob \"the, builder\""), client_id: String::from("c2"), ip_addr: String::from("::1"),
+                   backend: String::from("door games"), started_at: 1_700_086_400, duration: None },
+        ]
+    }
+
+    fn export(format: ExportFormat, calls: &[Call]) -> String {
+        let mut out = Vec::new();
+        export_calls(calls, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn exports_calls_as_csv() {
+        assert_eq!(export(ExportFormat::Csv, &finished_and_online()), "\
+id,username,client_id,ip_addr,backend,started,started_at,duration
+1,sysop,c1,192.0.2.1,bbs,2023-11-14 22:13:20,1700000000,90
+2,\"Bob \"\"the, builder\"\"\",c2,::1,door games,2023-11-15 22:13:20,1700086400,
+");
+        assert_eq!(export(ExportFormat::Csv, &[]), "id,username,client_id,ip_addr,backend,started,started_at,duration\n");
+    }
+
+    #[test]
+    fn exports_calls_as_json() {
+        assert_eq!(export(ExportFormat::Json, &finished_and_online()), "\
+[
+  {\"id\":1,\"username\":\"sysop\",\"client_id\":\"c1\",\"ip_addr\":\"192.0.2.1\",\"backend\":\"bbs\",\"started\":\"2023-11-14 22:13:20\",\"started_at\":1700000000,\"duration\":90},
+  {\"id\":2,\"username\":\"Bob \\\"the, builder\\\"\",\"client_id\":\"c2\",\"ip_addr\":\"::1\",\"backend\":\"door games\",\"started\":\"2023-11-15 22:13:20\",\"started_at\":1700086400,\"duration\":null}
+]
+");
+        assert_eq!(export(ExportFormat::Json, &[]), "[\n]\n");
+    }
+
+    #[test]
+    fn picks_out_the_calls_between_two_times() {
+        let scratch = Scratch::new("between");
+        let store = UserStore::open(&scratch.0, None).unwrap();
+        for at in [100i64, 200, 300] {
+            let id = store.record_call_start(&caller(), Uuid::new_v4(), IpAddr::from([127, 0, 0, 1]), "bbs").unwrap();
+            store.db.lock().unwrap().execute("UPDATE calls SET started_at = ?1 WHERE id = ?2", [at, id]).unwrap();
+        }
+        let between = |since, until| -> Vec<i64> {
+            store.calls_between(since, until).unwrap().iter().map(|call| call.started_at as i64).collect()
+        };
+        assert_eq!(between(None, None), [100, 200, 300]);
+        assert_eq!(between(Some(200), None), [200, 300]);
+        assert_eq!(between(None, Some(200)), [100]);
+        assert_eq!(between(Some(150), Some(300)), [200]);
+    }
+}
@@ -0,0 +1,13 @@
+use crate::clock::format_timestamp;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("TRISERVER_GIT_HASH");
+
+pub fn build_time() -> u64 {
+    env!("TRISERVER_BUILD_TIME").parse().unwrap_or(0)
+}
+
+// "TriServer 0.1.0 (3f2a9c1d7e4b, built 2026-10-14 12:00:00 UTC)"
+pub fn describe() -> String {
+    format!("TriServer {} ({}, built {} UTC)", VERSION, GIT_HASH, format_timestamp(build_time()))
+}
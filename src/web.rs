@@ -0,0 +1,176 @@
+// Small HTTP listener for health checks from container orchestrators and
+// load balancers, plus a JSON description of the running build and config
+// and metrics for Prometheus to scrape.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::format_timestamp;
+use crate::config::HttpConfig;
+use crate::handover;
+use crate::json::Object;
+use crate::log;
+use crate::metrics;
+use crate::version;
+use crate::ServerContext;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn text(status: u16, body: impl Into<String>) -> Self {
+        Self { status, content_type: "text/plain; charset=utf-8", body: body.into() }
+    }
+
+    fn json(status: u16, body: Object) -> Self {
+        Self { status, content_type: "application/json", body: body.finish() }
+    }
+
+    fn metrics(body: String) -> Self {
+        Self { status: 200, content_type: metrics::CONTENT_TYPE, body }
+    }
+}
+
+pub fn launch_http_server(config: &HttpConfig, context: ServerContext) {
+    let listener = match handover::bind("http", &config.address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            log!(Server, Error, "Unable to bind HTTP interface on {}: {}", config.address, error);
+            return;
+        }
+    };
+    log!(Server, Info, "HTTP Interface Listening on: {}", config.address);
+    let _ = thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let context = context.clone();
+            let _ = thread::spawn(move || {
+                let _ = handle_request(stream, &context);
+            });
+        }
+    });
+}
+
+fn handle_request(stream: TcpStream, context: &ServerContext) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are not used, but must be read before replying.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    let reply = match method {
+        "GET" | "HEAD" => route(path, context),
+        _ => Reply::text(405, "method not allowed\n"),
+    };
+
+    write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+           reply.status, reason(reply.status), reply.content_type, reply.body.len())?;
+    if method != "HEAD" {
+        writer.write_all(reply.body.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn route(path: &str, context: &ServerContext) -> Reply {
+    match path {
+        "/healthz" => Reply::text(200, "ok\n"),
+        "/readyz" => readiness(context),
+        "/info" => info(context),
+        "/metrics" => Reply::metrics(metrics::render(context)),
+        _ => Reply::text(404, "not found\n"),
+    }
+}
+
+fn readiness(context: &ServerContext) -> Reply {
+    let health = &context.health;
+    let mut problems = Vec::new();
+    if !health.is_listening() {
+        problems.push(String::from("telnet listener is not bound"));
+    }
+    if !health.any_backend_healthy() {
+        for backend in health.backends() {
+            match (backend.healthy, backend.last_error) {
+                (None, _) => problems.push(format!("backend {} has not been checked yet", backend.name)),
+                (_, Some(error)) => problems.push(format!("backend {} is unreachable: {}", backend.name, error)),
+                (_, None) => problems.push(format!("backend {} is unreachable", backend.name)),
+            }
+        }
+    }
+    if problems.is_empty() {
+        Reply::text(200, "ready\n")
+    } else {
+        Reply::text(503, problems.join("\n") + "\n")
+    }
+}
+
+fn info(context: &ServerContext) -> Reply {
+    let config = &context.live.current();
+    let statuses = context.health.backends();
+    let backends = config.backends.iter().map(|backend| {
+        let healthy = statuses.iter().find(|status| status.name == backend.name).and_then(|status| status.healthy);
+        let object = match (&backend.serial, &backend.socket) {
+            (Some(serial), _) => Object::new().string("name", &backend.name).string("device", &serial.device.display().to_string()),
+            (_, Some(socket)) => Object::new().string("name", &backend.name).string("socket", &socket.display().to_string()),
+            _ => Object::new().string("name", &backend.name).string("host", &backend.host).number("port", backend.port.into()),
+        };
+        match healthy {
+            Some(healthy) => object.boolean("healthy", healthy),
+            None => object.optional_string("healthy", None),
+        }
+    }).collect();
+    let pools = config.pools.iter().map(|pool| {
+        let members = pool.members.iter()
+            .map(|member| Object::new().string("backend", &member.backend).number("weight", member.weight))
+            .collect();
+        Object::new().string("name", &pool.name).string("policy", pool.policy.name()).array("members", members)
+    }).collect();
+    let features = Object::new()
+        .boolean("users", config.users.is_some())
+        .boolean("resume", config.resume.is_some())
+        .boolean("honeypot", config.honeypot.is_some())
+        .boolean("autoban", config.autoban.is_some())
+        .boolean("admin", config.admin.is_some())
+        .boolean("hooks", config.hooks.is_some())
+        .boolean("webhook", config.webhook.is_some())
+        .boolean("chat", config.chat.is_some())
+        .boolean("http", config.http.is_some())
+        .boolean("metrics_push", config.metrics_push.is_some())
+        .boolean("finger", config.finger.is_some())
+        .boolean("tls", config.tls.is_some());
+    Reply::json(200, Object::new()
+        .string("version", version::VERSION)
+        .string("git_hash", version::GIT_HASH)
+        .string("build_time", &format!("{} UTC", format_timestamp(version::build_time())))
+        .number("uptime", context.started.elapsed().as_secs())
+        .object("server", Object::new()
+            .optional_string("address", config.server.address.as_deref())
+            .number("port", config.server.port.into()))
+        .array("backends", backends)
+        .array("pools", pools)
+        .object("features", features))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
@@ -0,0 +1,165 @@
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::clock::unix_time;
+use crate::config::{WebhookConfig, WebhookEvent};
+use crate::events::{Event, EventBus};
+use crate::http;
+use crate::json::Object;
+use crate::log;
+use crate::middleware::SessionInfo;
+use crate::sha256;
+use crate::span::Span;
+use crate::version;
+
+// Further attempts wait this long, then twice as long each time.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+// Posts a JSON payload for each selected event from a background thread, so
+// a slow endpoint never holds up a session.
+pub fn launch_webhooks(config: &WebhookConfig, events: &EventBus) {
+    let config = config.clone();
+    let receiver = events.subscribe();
+    let _ = thread::spawn(move || {
+        for event in receiver {
+            if let Some((kind, body)) = message(&config, &event) {
+                deliver(&config, kind, &body);
+            }
+        }
+    });
+}
+
+// The body to post for an event, if it's one of those selected.
+fn message(config: &WebhookConfig, event: &Event) -> Option<(WebhookEvent, String)> {
+    let (kind, payload) = payload(event).filter(|(kind, _)| config.events.contains(kind))?;
+    let body = Object::new()
+        .string("event", kind.name())
+        .number("timestamp", unix_time())
+        .object("data", payload)
+        .finish();
+    Some((kind, body))
+}
+
+fn payload(event: &Event) -> Option<(WebhookEvent, Object)> {
+    match event {
+        Event::Connected(session) => Some((WebhookEvent::Connect, session_payload(session))),
+        Event::Closed { session, duration } => {
+            Some((WebhookEvent::Disconnect, session_payload(session).number("duration", duration.as_secs())))
+        }
+        Event::Banned(ban) => Some((WebhookEvent::Ban, Object::new()
+            .string("ip", &ban.ip_addr.to_string())
+            .string("reason", &ban.reason)
+            .number("duration", ban.remaining().as_secs())
+            .number("strikes", ban.strikes as u64))),
+        Event::Daily(summary) => Some((WebhookEvent::Daily, summary.fields(Object::new()))),
+        _ => None,
+    }
+}
+
+fn session_payload(session: &SessionInfo) -> Object {
+    Object::new()
+        .string("client_id", &session.client_id.to_string())
+        .string("session", &Span::short_id(session.client_id))
+        .string("listener", session.listener)
+        .string("ip", &session.ip_addr.to_string())
+        .string("backend", &session.backend)
+        .optional_string("user", session.user.as_ref().map(|user| user.username.as_str()))
+}
+
+fn headers(config: &WebhookConfig, kind: WebhookEvent, body: &str) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("Content-Type", String::from("application/json")),
+        ("User-Agent", format!("TriServer/{}", version::VERSION)),
+        ("X-TriServer-Event", kind.name().to_string()),
+    ];
+    if let Some(secret) = &config.secret {
+        let signature = sha256::hmac(secret.as_bytes(), body.as_bytes());
+        headers.push(("X-TriServer-Signature", format!("sha256={}", sha256::to_hex(&signature))));
+    }
+    headers
+}
+
+// How long to wait before each of `retries` further attempts.
+fn retry_delays(retries: u32) -> impl Iterator<Item = Duration> {
+    (0..retries).map(|retry| FIRST_RETRY.saturating_mul(2u32.saturating_pow(retry)))
+}
+
+fn deliver(config: &WebhookConfig, kind: WebhookEvent, body: &str) {
+    let headers = headers(config, kind, body);
+    let mut delays = retry_delays(config.retries);
+    loop {
+        match http::post(&config.url, &headers, body.as_bytes(), config.timeout) {
+            Ok(response) if response.is_success() => return,
+            Ok(response) => log!(Server, Info, "Webhook {} returned {} for {} event", config.url, response.status, kind.name()),
+            Err(error) => log!(Server, Warn, "Webhook {} failed for {} event: {}", config.url, kind.name(), error),
+        }
+        match delays.next() {
+            Some(delay) => sleep(delay),
+            None => break,
+        }
+    }
+    log!(Server, Warn, "Giving up on {} event after {} attempts", kind.name(), config.retries as u64 + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use uuid::Uuid;
+
+    fn config(secret: Option<&str>, events: &[WebhookEvent]) -> WebhookConfig {
+        WebhookConfig { url: "http://127.0.0.1:9/hook".parse().unwrap(), secret: secret.map(str::to_string), events: events.to_vec(),
+                        retries: 3, timeout: Duration::from_secs(1) }
+    }
+
+    fn session() -> SessionInfo {
+        SessionInfo { client_id: Uuid::nil(), listener: "telnet", ip_addr: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 9)),
+                      backend: String::from("main"), user: None, encoding: None, terminal_class: None, utf8: false, charset: None, rip: false,
+                      syncterm: false }
+    }
+
+    fn header<'a>(headers: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn signs_the_body_with_the_secret() {
+        // RFC 4231 test case 2.
+        let signed = headers(&config(Some("Jefe"), &[]), WebhookEvent::Ban, "what do ya want for nothing?");
+        assert_eq!(header(&signed, "X-TriServer-Signature"),
+                   Some("sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+        assert_eq!(header(&signed, "X-TriServer-Event"), Some("ban"));
+
+        let unsigned = headers(&config(None, &[]), WebhookEvent::Ban, "what do ya want for nothing?");
+        assert_eq!(header(&unsigned, "X-TriServer-Signature"), None);
+    }
+
+    #[test]
+    fn waits_twice_as_long_before_each_retry() {
+        let secs = |retries| retry_delays(retries).map(|delay| delay.as_secs()).collect::<Vec<_>>();
+        assert_eq!(secs(0), Vec::<u64>::new());
+        assert_eq!(secs(3), [1, 2, 4]);
+        assert_eq!(secs(5), [1, 2, 4, 8, 16]);
+        // Far enough out, the doubling stops rather than overflowing.
+        let delays: Vec<_> = retry_delays(40).collect();
+        assert_eq!(delays.len(), 40);
+        assert_eq!(delays[32], delays[39]);
+    }
+
+    #[test]
+    fn posts_only_the_events_selected() {
+        let connect = Event::Connected(session());
+        let closed = Event::Closed { session: session(), duration: Duration::from_secs(75) };
+        let config = config(None, &[WebhookEvent::Disconnect]);
+        assert!(message(&config, &connect).is_none());
+        assert!(message(&config, &Event::Drained { backend: String::from("main") }).is_none());
+
+        let (kind, body) = message(&config, &closed).unwrap();
+        assert_eq!(kind, WebhookEvent::Disconnect);
+        assert!(body.starts_with(r#"{"event":"disconnect","timestamp":"#), "{}", body);
+        assert!(body.contains(r#""ip":"203.0.113.9""#), "{}", body);
+        assert!(body.contains(r#""backend":"main""#), "{}", body);
+        assert!(body.contains(r#""duration":75"#), "{}", body);
+    }
+}
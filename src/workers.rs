@@ -0,0 +1,239 @@
+// Multi-process mode for many-core hosts. A supervisor starts `workers`
+// copies of the server. Each copy binds the telnet port with SO_REUSEPORT, so
+// the kernel spreads callers across them. Each worker has its own client
+// manager, and they share nothing but the stats file. Worker 0 also runs the
+// admin and HTTP interfaces.
+//
+// Stats file layout: one fixed-width line per worker, at offset
+// worker * STATS_LINE, rewritten every STATS_INTERVAL.
+
+use std::ffi::{c_int, c_void};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::clock::unix_time;
+use crate::events::{Event, EventBus};
+use crate::log;
+use crate::systemd::{self, Watchdog};
+use crate::SharedClientMap;
+
+const STATS_LINE: usize = 96;
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(200);
+// A worker that dies sooner than this after starting is restarted only after
+// the same delay, so a crash loop doesn't spin.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const LISTEN_BACKLOG: c_int = 128;
+
+const AF_INET: c_int = 2;
+const AF_INET6: c_int = 10;
+const SOCK_STREAM: c_int = 1;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+const SOL_SOCKET: c_int = 1;
+const SO_REUSEADDR: c_int = 2;
+const SO_REUSEPORT: c_int = 15;
+const PR_SET_PDEATHSIG: c_int = 1;
+const SIGTERM: c_int = 15;
+const SIGINT: c_int = 2;
+
+#[repr(C)]
+struct SockaddrIn {
+    family: u16,
+    port: u16,
+    addr: [u8; 4],
+    zero: [u8; 8],
+}
+
+#[repr(C)]
+struct SockaddrIn6 {
+    family: u16,
+    port: u16,
+    flowinfo: u32,
+    addr: [u8; 16],
+    scope_id: u32,
+}
+
+extern "C" {
+    fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+    fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, length: u32) -> c_int;
+    fn bind(fd: c_int, address: *const c_void, length: u32) -> c_int;
+    fn listen(fd: c_int, backlog: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn prctl(option: c_int, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> c_int;
+    fn signal(signal: c_int, handler: usize) -> usize;
+    fn kill(pid: c_int, signal: c_int) -> c_int;
+}
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_signal: c_int) {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+// Runs in the supervisor until SIGTERM or SIGINT, keeping `count` workers
+// alive. They are stopped with SIGTERM on the way out.
+pub fn supervise(count: u32, stats_file: &Path) {
+    let on_stop = on_stop_signal as extern "C" fn(c_int) as usize;
+    unsafe {
+        signal(SIGTERM, on_stop);
+        signal(SIGINT, on_stop);
+    }
+    // Start from an empty file so lines from a larger earlier run don't linger.
+    if let Err(error) = File::create(stats_file) {
+        log!(Server, Warn, "Unable to create stats file {}: {}", stats_file.display(), error);
+    }
+
+    let mut workers: Vec<Option<(Child, Instant)>> = (0..count).map(|_| None).collect();
+    let mut watchdog = Watchdog::from_env();
+    let mut ready = false;
+    while !STOPPING.load(Ordering::SeqCst) {
+        for (i, worker) in workers.iter_mut().enumerate() {
+            if let Some((child, started)) = worker {
+                match child.try_wait() {
+                    Ok(None) => continue,
+                    Ok(Some(status)) => {
+                        log!(Server, Warn, "Worker {} (PID {}) exited with {}", i, child.id(), status);
+                        if started.elapsed() < RESTART_BACKOFF {
+                            sleep(RESTART_BACKOFF);
+                        }
+                    }
+                    Err(error) => log!(Server, Warn, "Unable to check on worker {}: {}", i, error),
+                }
+            }
+            *worker = spawn_worker(i as u32).map(|child| (child, Instant::now()));
+        }
+        if !ready {
+            ready = true;
+            systemd::notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+        }
+        if let Some(watchdog) = watchdog.as_mut() {
+            watchdog.tick();
+        }
+        sleep(SUPERVISE_INTERVAL);
+    }
+
+    log!(Server, Info, "Stopping {} worker(s)", count);
+    systemd::notify("STOPPING=1");
+    for (child, _) in workers.iter_mut().flatten() {
+        unsafe { kill(child.id() as c_int, SIGTERM) };
+    }
+    for (child, _) in workers.iter_mut().flatten() {
+        let _ = child.wait();
+    }
+}
+
+fn spawn_worker(index: u32) -> Option<Child> {
+    let executable = match std::env::current_exe() {
+        Ok(executable) => executable,
+        Err(error) => {
+            log!(Server, Error, "Unable to start worker {}: {}", index, error);
+            return None;
+        }
+    };
+    let args = std::env::args().skip(1).filter(|arg| arg != "--daemon" && arg != "-d");
+    // Only the supervisor talks to systemd.
+    match Command::new(executable).args(args).arg("--worker").arg(index.to_string()).env_remove("NOTIFY_SOCKET").spawn() {
+        Ok(child) => {
+            log!(Server, Info, "Started worker {} as PID {}", index, child.id());
+            Some(child)
+        }
+        Err(error) => {
+            log!(Server, Error, "Unable to start worker {}: {}", index, error);
+            None
+        }
+    }
+}
+
+// Called first thing in a worker so it doesn't outlive its supervisor.
+pub fn exit_with_supervisor() {
+    unsafe { prctl(PR_SET_PDEATHSIG, SIGTERM as u64, 0, 0, 0) };
+}
+
+pub fn bind_shared(address: &str) -> io::Result<TcpListener> {
+    let address: SocketAddr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+    let family = if address.is_ipv4() { AF_INET } else { AF_INET6 };
+    let fd = unsafe { socket(family, SOCK_STREAM | SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = unsafe { configure(fd, &address) };
+    if let Err(error) = result {
+        unsafe { close(fd) };
+        return Err(error);
+    }
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+unsafe fn configure(fd: c_int, address: &SocketAddr) -> io::Result<()> {
+    let on: c_int = 1;
+    for option in [SO_REUSEADDR, SO_REUSEPORT] {
+        if setsockopt(fd, SOL_SOCKET, option, &on as *const c_int as *const c_void, 4) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let rc = match address {
+        SocketAddr::V4(v4) => {
+            let sockaddr = SockaddrIn { family: AF_INET as u16, port: v4.port().to_be(), addr: v4.ip().octets(), zero: [0; 8] };
+            bind(fd, &sockaddr as *const SockaddrIn as *const c_void, std::mem::size_of::<SockaddrIn>() as u32)
+        }
+        SocketAddr::V6(v6) => {
+            let sockaddr = SockaddrIn6 {
+                family: AF_INET6 as u16,
+                port: v6.port().to_be(),
+                flowinfo: v6.flowinfo(),
+                addr: v6.ip().octets(),
+                scope_id: v6.scope_id(),
+            };
+            bind(fd, &sockaddr as *const SockaddrIn6 as *const c_void, std::mem::size_of::<SockaddrIn6>() as u32)
+        }
+    };
+    if rc != 0 || listen(fd, LISTEN_BACKLOG) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Keeps this worker's line in the stats file current.
+pub fn launch_stats_writer(path: PathBuf, index: u32, clients: SharedClientMap, events: &EventBus) {
+    let receiver = events.subscribe();
+    let _ = thread::spawn(move || {
+        let file = match OpenOptions::new().write(true).create(true).truncate(false).open(&path) {
+            Ok(file) => file,
+            Err(error) => {
+                log!(Server, Warn, "Unable to open stats file {}: {}", path.display(), error);
+                return;
+            }
+        };
+        let mut connects = 0u64;
+        loop {
+            connects += receiver.try_iter().filter(|event| matches!(event, Event::Connected(_))).count() as u64;
+            let line = format!("worker {:<3} pid {:<8} sessions {:<6} connects {:<10} updated {}",
+                               index, std::process::id(), clients.len(), connects, unix_time());
+            let mut record = format!("{:<width$}", line, width = STATS_LINE - 1);
+            record.truncate(STATS_LINE - 1);
+            record.push('\n');
+            if let Err(error) = file.write_at(record.as_bytes(), index as u64 * STATS_LINE as u64) {
+                log!(Server, Warn, "Unable to write stats file {}: {}", path.display(), error);
+            }
+            sleep(STATS_INTERVAL);
+        }
+    });
+}
+
+pub fn read_stats(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|contents| contents.lines().map(|line| line.trim_end().to_string()).filter(|line| !line.is_empty()).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,151 @@
+// The limits on what a caller or backend may send, and on callers per address.
+
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use triserver::codec::{IAC, SB, SE, WONT};
+use triserver::config::{AutobanConfig, Config, DuplicatePolicy, FloodAction, FloodConfig, NegotiationConfig};
+use triserver::harness::{self, ScriptedBackend, Step, TestClient, TestServer};
+
+const ECHO: u8 = 1;
+const TTYPE: u8 = 24;
+
+fn negotiation_limits(config: &mut Config) {
+    config.negotiation = Some(NegotiationConfig { max_subnegotiation: 16, client_rate: 100, backend_rate: 100 });
+}
+
+// Telnet commands that only refuse an option, `count` of them in one write.
+fn refusals(count: usize) -> Vec<u8> {
+    [IAC, WONT, ECHO].repeat(count)
+}
+
+#[test]
+fn disconnects_a_caller_flooding_input() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    config.flood = Some(FloodConfig { bytes_per_second: 100, lines_per_second: 0, action: FloodAction::Disconnect });
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::send([b'x'; 200])])?;
+    let rest = client.expect_closed()?;
+    assert!(String::from_utf8_lossy(&rest).contains("Too much input, disconnecting."));
+    Ok(())
+}
+
+#[test]
+fn warns_a_caller_flooding_input() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::expect("ok"), Step::send("bye")])?;
+    let mut config = harness::config(backend.address());
+    config.flood = Some(FloodConfig { bytes_per_second: 100, lines_per_second: 0, action: FloodAction::Warn });
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::send([b'x'; 200]), Step::expect("You are typing too fast")])?;
+    // Once the window has passed, input reaches the backend again.
+    client.run(&[Step::Sleep(Duration::from_millis(1100)), Step::send("ok"), Step::expect("bye")])?;
+    Ok(())
+}
+
+#[test]
+fn disconnects_a_caller_flooding_negotiation() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    negotiation_limits(&mut config);
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::Send(refusals(200))])?;
+    let rest = client.expect_closed()?;
+    assert!(String::from_utf8_lossy(&rest).contains("Too much negotiation, disconnecting."));
+    Ok(())
+}
+
+#[test]
+fn lets_a_caller_negotiate_within_the_rate() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::expect("ok"), Step::send("bye")])?;
+    let mut config = harness::config(backend.address());
+    negotiation_limits(&mut config);
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::Send(refusals(40)), Step::send("ok"), Step::expect("bye")])?;
+    Ok(())
+}
+
+#[test]
+fn disconnects_a_caller_sending_an_oversized_subnegotiation() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    negotiation_limits(&mut config);
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    let mut oversized = vec![IAC, SB, TTYPE, 0];
+    oversized.extend_from_slice(&[b'x'; 64]);
+    oversized.extend_from_slice(&[IAC, SE]);
+    client.run(&[Step::expect("Welcome"), Step::Send(oversized)])?;
+    let rest = client.expect_closed()?;
+    assert!(String::from_utf8_lossy(&rest).contains("Too much negotiation, disconnecting."));
+    Ok(())
+}
+
+#[test]
+fn hangs_up_on_a_backend_flooding_negotiation() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::Send(refusals(500)), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    negotiation_limits(&mut config);
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    let rest = client.expect_closed()?;
+    assert!(String::from_utf8_lossy(&rest).contains("The connection to test was closed."));
+    Ok(())
+}
+
+#[test]
+fn rejects_a_second_caller_from_the_same_address() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    config.server.duplicate_ip = DuplicatePolicy::Reject;
+    let server = TestServer::start(config)?;
+    let mut first = TestClient::connect(server.address())?;
+    first.expect("Welcome")?;
+    let mut second = TestClient::connect(server.address())?;
+    let rest = second.expect_closed()?;
+    assert_eq!(String::from_utf8_lossy(&rest), "Only one connection per address is allowed.\r\n");
+    assert_eq!(server.sessions(), 1);
+    Ok(())
+}
+
+#[test]
+fn bans_an_address_that_keeps_reconnecting() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    config.autoban = Some(AutobanConfig { reconnects: 3, ..AutobanConfig::default() });
+    let server = TestServer::start(config)?;
+    for _ in 0..2 {
+        let mut client = TestClient::connect(server.address())?;
+        client.run(&[Step::expect("Welcome"), Step::Close])?;
+    }
+    // The third connection within the window is the one that earns the ban.
+    let mut third = TestClient::connect(server.address())?;
+    assert_eq!(String::from_utf8_lossy(&third.expect_closed()?), "Too many connections, please try again later.\r\n");
+    let mut banned = TestClient::connect(server.address())?;
+    let rest = banned.expect_closed()?;
+    assert!(String::from_utf8_lossy(&rest).starts_with("You are temporarily banned"));
+    Ok(())
+}
+
+#[test]
+fn refuses_a_trusted_proxy_past_the_headers_being_read() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    config.proxy_protocol = Config::parse("[proxy_protocol]\ntrusted = [\"127.0.0.1\"]\n")?.proxy_protocol;
+    let server = TestServer::start(config)?;
+    // Connections that never send their header, each holding a reader.
+    let silent = (0..64).map(|_| TestClient::connect(server.address())).collect::<Result<Vec<_>, _>>()?;
+    let mut refused = TestClient::connect(server.address())?;
+    assert_eq!(String::from_utf8_lossy(&refused.expect_closed()?), "Too many connections, please try again later.\r\n");
+    // Their readers are given back when they hang up.
+    drop(silent);
+    thread::sleep(Duration::from_millis(500));
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::send("PROXY TCP4 192.0.2.1 127.0.0.1 4000 23\r\n"), Step::expect("Welcome")])?;
+    Ok(())
+}
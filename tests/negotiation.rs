@@ -0,0 +1,86 @@
+// How the proxy answers a backend's telnet negotiation on the caller's behalf.
+
+use std::error::Error;
+
+use triserver::codec::{DO, DONT, IAC, SB, SE, WILL, WONT};
+use triserver::config::{BackendConfig, OptionPolicy};
+use triserver::harness::{self, ScriptedBackend, Step, TestClient, TestServer, STEP_TIMEOUT};
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const SNDLOC: u8 = 23;
+const TTYPE: u8 = 24;
+
+// Runs `script` as the backend for one caller, who stays on until it has
+// finished. `configure` adjusts the backend's settings first.
+fn negotiate(configure: impl FnOnce(&mut BackendConfig), mut script: Vec<Step>) -> Result<(), Box<dyn Error>> {
+    script.push(Step::send("done"));
+    let backend = ScriptedBackend::start(script)?;
+    let mut config = harness::config(backend.address());
+    configure(&mut config.backends[0]);
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.expect("done")?;
+    backend.finished(STEP_TIMEOUT)?;
+    Ok(())
+}
+
+#[test]
+fn answers_the_usual_bbs_requests() -> Result<(), Box<dyn Error>> {
+    negotiate(|_| {}, vec![
+        Step::send([IAC, WILL, ECHO, IAC, WILL, SUPPRESS_GO_AHEAD]),
+        Step::expect([IAC, DO, ECHO]),
+        Step::expect([IAC, DO, SUPPRESS_GO_AHEAD]),
+    ])
+}
+
+#[test]
+fn reports_a_terminal_type() -> Result<(), Box<dyn Error>> {
+    negotiate(|_| {}, vec![
+        Step::send([IAC, DO, TTYPE]),
+        Step::expect([IAC, WILL, TTYPE]),
+        Step::send([IAC, SB, TTYPE, 1, IAC, SE]),
+        Step::expect([IAC, SB, TTYPE, 0]),
+        Step::expect([IAC, SE]),
+    ])
+}
+
+#[test]
+fn sends_the_caller_location() -> Result<(), Box<dyn Error>> {
+    let mut expected = vec![IAC, SB, SNDLOC];
+    expected.extend_from_slice(b"127.0.0.1");
+    expected.extend_from_slice(&[IAC, SE]);
+    negotiate(|_| {}, vec![Step::send([IAC, DO, SNDLOC]), Step::expect([IAC, WILL, SNDLOC]), Step::Expect(expected)])
+}
+
+#[test]
+fn follows_the_backend_option_policy() -> Result<(), Box<dyn Error>> {
+    let refuse = |backend: &mut BackendConfig| {
+        backend.options.insert(ECHO, OptionPolicy::Refuse);
+        backend.options.insert(SNDLOC, OptionPolicy::Refuse);
+    };
+    negotiate(refuse, vec![
+        Step::send([IAC, WILL, ECHO]),
+        Step::expect([IAC, DONT, ECHO]),
+        Step::send([IAC, DO, SNDLOC]),
+        Step::expect([IAC, WONT, SNDLOC]),
+    ])
+}
+
+#[test]
+fn asks_for_forced_options_on_connecting() -> Result<(), Box<dyn Error>> {
+    let force = |backend: &mut BackendConfig| {
+        backend.options.insert(SUPPRESS_GO_AHEAD, OptionPolicy::Force);
+    };
+    negotiate(force, vec![Step::expect([IAC, WILL, SUPPRESS_GO_AHEAD, IAC, DO, SUPPRESS_GO_AHEAD])])
+}
+
+#[test]
+fn keeps_negotiation_from_the_caller() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send([IAC, WILL, ECHO]), Step::send("Welcome")])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut client = TestClient::connect(server.address())?;
+    let received = client.expect("Welcome")?;
+    assert_eq!(received, b"Welcome");
+    Ok(())
+}
@@ -0,0 +1,84 @@
+// Filter plugins from a [plugins] directory, changing and ending sessions.
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use triserver::config::PluginsConfig;
+use triserver::harness::{self, ScriptedBackend, Step, TestClient, TestServer, STEP_TIMEOUT};
+
+// Upper-cases what the caller types, and ends the session on input starting
+// with a !.
+const SHOUTING_PLUGIN: &str = r#"(module
+  (import "triserver" "replace" (func $replace (param i32 i32)))
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "on_client_data") (param $ptr i32) (param $len i32) (result i32)
+    (local $i i32) (local $byte i32)
+    (if (i32.and (i32.eqz (i32.eqz (local.get $len)))
+                 (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 33)))
+      (then (return (i32.const 1))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (local.set $byte (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+        (i32.store8 (i32.add (local.get $ptr) (local.get $i))
+          (select (i32.sub (local.get $byte) (i32.const 32)) (local.get $byte)
+                  (i32.lt_u (i32.sub (local.get $byte) (i32.const 97)) (i32.const 26))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (call $replace (local.get $ptr) (local.get $len))
+    (i32.const 0)))"#;
+
+// A plugin directory, removed again when the test is done.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("triserver-plugins-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        fs::write(path.join("shout.wasm"), wat::parse_str(SHOUTING_PLUGIN)?)?;
+        Ok(Self(path))
+    }
+
+    fn config(&self) -> PluginsConfig {
+        PluginsConfig { directory: self.0.clone(), fuel: 100_000, memory: 64 }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn passes_on_what_a_plugin_changed() -> Result<(), Box<dyn Error>> {
+    let plugins = Scratch::new("change")?;
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome\r\n"), Step::expect("HELLO, BBS"), Step::send("bye")])?;
+    let mut config = harness::config(backend.address());
+    config.plugins = Some(plugins.config());
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::send("hello, bbs"), Step::expect("bye")])?;
+    backend.finished(STEP_TIMEOUT)?;
+    server.stop();
+    Ok(())
+}
+
+#[test]
+fn ends_the_session_when_a_plugin_says_so() -> Result<(), Box<dyn Error>> {
+    let plugins = Scratch::new("veto")?;
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome\r\n"), Step::ExpectClosed])?;
+    let mut config = harness::config(backend.address());
+    config.plugins = Some(plugins.config());
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::send("!quit")])?;
+    let rest = client.expect_closed()?;
+    assert!(String::from_utf8_lossy(&rest).contains("Disconnected by a filter."));
+    backend.finished(STEP_TIMEOUT)?;
+    server.wait_for_sessions(0)?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+// Bytes relayed between callers and a backend, and what each side sees when
+// the other hangs up.
+
+use std::error::Error;
+
+use triserver::harness::{self, ScriptedBackend, Step, TestClient, TestServer, STEP_TIMEOUT};
+
+#[test]
+fn relays_both_ways() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome\r\n"), Step::expect("hi"), Step::send("bye")])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::send("hi"), Step::expect("bye")])?;
+    backend.finished(STEP_TIMEOUT)?;
+    server.stop();
+    Ok(())
+}
+
+#[test]
+fn backend_hanging_up_drops_the_caller() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Goodbye\r\n"), Step::Close])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut client = TestClient::connect(server.address())?;
+    client.expect("Goodbye\r\n")?;
+    let rest = client.expect_closed()?;
+    assert_eq!(String::from_utf8_lossy(&rest).trim(), "NO CARRIER");
+    server.wait_for_sessions(0)?;
+    Ok(())
+}
+
+#[test]
+fn caller_hanging_up_closes_the_backend() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome\r\n"), Step::expect("bye"), Step::ExpectClosed])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect("Welcome"), Step::send("bye"), Step::Close])?;
+    backend.finished(STEP_TIMEOUT)?;
+    server.wait_for_sessions(0)?;
+    Ok(())
+}
+
+#[test]
+fn keeps_callers_apart() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Name? "), Step::expect("\r"), Step::send("Hello\r\n"), Step::ExpectClosed])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut first = TestClient::connect(server.address())?;
+    let mut second = TestClient::connect(server.address())?;
+    first.expect("Name? ")?;
+    second.expect("Name? ")?;
+    server.wait_for_sessions(2)?;
+    first.run(&[Step::send("one\r"), Step::expect("Hello")])?;
+    second.run(&[Step::send("two\r"), Step::expect("Hello")])?;
+    first.run(&[Step::Close])?;
+    second.run(&[Step::Close])?;
+    backend.finished(STEP_TIMEOUT)?;
+    backend.finished(STEP_TIMEOUT)?;
+    Ok(())
+}
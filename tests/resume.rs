@@ -0,0 +1,156 @@
+// Picking a dropped session back up with its resume code.
+
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use triserver::config::{AutobanConfig, Config, DuplicatePolicy, ResumeConfig};
+use triserver::harness::{self, ScriptedBackend, Step, TestClient, TestServer, STEP_TIMEOUT};
+
+const PROMPT: &str = "type your resume code: ";
+
+fn resumable(config: &mut Config) {
+    config.resume = Some(ResumeConfig { grace_period: 30, prompt_timeout: 3, replay_buffer: 64 });
+}
+
+// Starts a new session and returns the resume code it was given.
+fn start_session(client: &mut TestClient) -> Result<String, Box<dyn Error>> {
+    client.run(&[Step::expect(PROMPT), Step::send("\r\n"), Step::expect("Your resume code is ")])?;
+    let code = client.expect(". ")?;
+    Ok(String::from_utf8_lossy(&code).trim_end_matches(". ").to_string())
+}
+
+fn resume_from_the_same_address(policy: DuplicatePolicy) -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::expect("back"), Step::send("Hello again")])?;
+    let mut config = harness::config(backend.address());
+    config.server.duplicate_ip = policy;
+    resumable(&mut config);
+    let server = TestServer::start(config)?;
+
+    let mut first = TestClient::connect(server.address())?;
+    let code = start_session(&mut first)?;
+    first.run(&[Step::expect("Welcome"), Step::Close])?;
+    server.wait_for_held(1)?;
+
+    // The held session is still in the client map from this address, but
+    // mustn't count as a duplicate of the caller coming back for it.
+    let mut second = TestClient::connect(server.address())?;
+    second.run(&[Step::expect(PROMPT), Step::send(format!("{}\r\n", code)), Step::expect("Session resumed.")])?;
+    server.wait_for_held(0)?;
+    assert_eq!(server.sessions(), 1);
+    second.run(&[Step::send("back"), Step::expect("Hello again")])?;
+    backend.finished(STEP_TIMEOUT)?;
+    Ok(())
+}
+
+#[test]
+fn resumes_with_duplicates_kicked() -> Result<(), Box<dyn Error>> {
+    resume_from_the_same_address(DuplicatePolicy::Kick)
+}
+
+#[test]
+fn resumes_with_duplicates_rejected() -> Result<(), Box<dyn Error>> {
+    resume_from_the_same_address(DuplicatePolicy::Reject)
+}
+
+#[test]
+fn starts_afresh_on_an_unknown_code() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome")])?;
+    let mut config = harness::config(backend.address());
+    resumable(&mut config);
+    let server = TestServer::start(config)?;
+    let mut client = TestClient::connect(server.address())?;
+    client.run(&[Step::expect(PROMPT), Step::send("AAAA-BBBB\r\n"), Step::expect("starting a new session"), Step::expect("Welcome")])?;
+    Ok(())
+}
+
+#[test]
+fn bans_an_address_guessing_codes() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome")])?;
+    let mut config = harness::config(backend.address());
+    resumable(&mut config);
+    config.autoban = Some(AutobanConfig { failed_logins: 2, ..AutobanConfig::default() });
+    let server = TestServer::start(config)?;
+
+    let mut first = TestClient::connect(server.address())?;
+    first.run(&[Step::expect(PROMPT), Step::send("AAAA-BBBB\r\n"), Step::expect("starting a new session"), Step::Close])?;
+    let mut second = TestClient::connect(server.address())?;
+    second.run(&[Step::expect(PROMPT), Step::send("CCCC-DDDD\r\n")])?;
+    assert!(String::from_utf8_lossy(&second.expect_closed()?).contains("Unknown or expired resume code.\r\n"));
+    let mut third = TestClient::connect(server.address())?;
+    assert!(String::from_utf8_lossy(&third.expect_closed()?).starts_with("You are temporarily banned"));
+    Ok(())
+}
+
+#[test]
+fn holds_a_session_whose_caller_reset() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::expect("back"), Step::send("Hello again")])?;
+    let mut config = harness::config(backend.address());
+    resumable(&mut config);
+    let server = TestServer::start(config)?;
+
+    let mut first = TestClient::connect(server.address())?;
+    let code = start_session(&mut first)?;
+    first.run(&[Step::expect("Welcome")])?;
+    first.reset()?;
+    server.wait_for_held(1)?;
+
+    let mut second = TestClient::connect(server.address())?;
+    second.run(&[Step::expect(PROMPT), Step::send(format!("{}\r\n", code)), Step::expect("Session resumed."), Step::send("back"),
+                 Step::expect("Hello again")])?;
+    backend.finished(STEP_TIMEOUT)?;
+    Ok(())
+}
+
+#[test]
+fn holds_a_session_whose_caller_stops_reading() -> Result<(), Box<dyn Error>> {
+    // More than the caller's buffers and server.pending_output take, so writing to them fails.
+    let flood = vec![b'x'; 4 * 1024 * 1024];
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::Send(flood), Step::send("While you were out"), Step::expect("back"),
+                                              Step::send("Hello again")])?;
+    let mut config = harness::config(backend.address());
+    config.server.pending_output = 16;
+    resumable(&mut config);
+    let server = TestServer::start(config)?;
+
+    let mut first = TestClient::connect(server.address())?;
+    first.shrink_receive_buffer()?;
+    let code = start_session(&mut first)?;
+    server.wait_for_held(1)?;
+
+    let mut second = TestClient::connect(server.address())?;
+    second.run(&[Step::expect(PROMPT), Step::send(format!("{}\r\n", code)), Step::expect("Session resumed."),
+                 Step::expect("While you were out"), Step::send("back"), Step::expect("Hello again")])?;
+    backend.finished(STEP_TIMEOUT)?;
+    Ok(())
+}
+
+#[test]
+fn resumes_or_starts_afresh_at_the_end_of_the_grace_period() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::expect("back"), Step::send("Hello again")])?;
+    // Either the caller gets the session or it has expired; never a hang-up.
+    for after in [975, 1000, 1025] {
+        let mut config = harness::config(backend.address());
+        config.resume = Some(ResumeConfig { grace_period: 1, prompt_timeout: 3, replay_buffer: 64 });
+        let server = TestServer::start(config)?;
+
+        let mut first = TestClient::connect(server.address())?;
+        let code = start_session(&mut first)?;
+        first.run(&[Step::expect("Welcome"), Step::Close])?;
+        server.wait_for_held(1)?;
+        let held_at = Instant::now();
+
+        let mut second = TestClient::connect(server.address())?;
+        second.run(&[Step::expect(PROMPT)])?;
+        thread::sleep((held_at + Duration::from_millis(after)).saturating_duration_since(Instant::now()));
+        second.send(format!("{}\r\n", code))?;
+        let reply = String::from_utf8_lossy(&second.expect(".\r\n")?).into_owned();
+        if reply.contains("Session resumed.") {
+            second.run(&[Step::send("back"), Step::expect("Hello again")])?;
+        } else {
+            assert!(reply.contains("Unknown or expired resume code, starting a new session."), "{:?}", reply);
+            second.run(&[Step::expect("Welcome")])?;
+        }
+    }
+    Ok(())
+}
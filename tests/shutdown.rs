@@ -0,0 +1,53 @@
+// Stopping the server, which disconnects every session as a real shutdown does.
+
+use std::error::Error;
+use std::time::Instant;
+
+use triserver::harness::{self, ScriptedBackend, Step, TestClient, TestServer, STEP_TIMEOUT};
+
+#[test]
+fn disconnects_every_session() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let mut client = TestClient::connect(server.address())?;
+        client.expect("Welcome")?;
+        clients.push(client);
+    }
+    server.wait_for_sessions(3)?;
+    server.stop();
+    for client in &mut clients {
+        let rest = client.expect_closed()?;
+        assert!(String::from_utf8_lossy(&rest).contains("The server is shutting down."));
+    }
+    for _ in 0..3 {
+        backend.finished(STEP_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn stops_at_once_when_idle() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(Vec::new())?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let address = server.address();
+    let started = Instant::now();
+    server.stop();
+    assert!(started.elapsed() < STEP_TIMEOUT);
+    assert!(TestClient::connect(address).is_err());
+    Ok(())
+}
+
+#[test]
+fn stops_when_dropped() -> Result<(), Box<dyn Error>> {
+    let backend = ScriptedBackend::start(vec![Step::send("Welcome"), Step::ExpectClosed])?;
+    let server = TestServer::start(harness::config(backend.address()))?;
+    let mut client = TestClient::connect(server.address())?;
+    client.expect("Welcome")?;
+    server.wait_for_sessions(1)?;
+    drop(server);
+    client.expect_closed()?;
+    backend.finished(STEP_TIMEOUT)?;
+    Ok(())
+}